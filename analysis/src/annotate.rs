@@ -0,0 +1,221 @@
+use std::fmt::Write as _;
+
+use fast_tak::takparse::{Color, Move};
+use takzero::{
+    network::net6_simhash::{Env, Net, HALF_KOMI, N},
+    ptn::PtnGame,
+    search::{env::Environment, eval::Eval, node::Node, DISCOUNT_FACTOR},
+    winrate::expected_score,
+};
+
+/// Minimum loss in value (between -1 and 1) for each annotation.
+#[derive(Debug, Clone, Copy)]
+pub struct Thresholds {
+    pub inaccuracy: f32,
+    pub mistake: f32,
+    pub blunder: f32,
+}
+
+impl Default for Thresholds {
+    fn default() -> Self {
+        Self {
+            inaccuracy: 0.1,
+            mistake: 0.2,
+            blunder: 0.4,
+        }
+    }
+}
+
+impl Thresholds {
+    pub fn annotation(&self, loss: f32) -> Option<&'static str> {
+        if loss >= self.blunder {
+            Some("??")
+        } else if loss >= self.mistake {
+            Some("?")
+        } else if loss >= self.inaccuracy {
+            Some("?!")
+        } else {
+            None
+        }
+    }
+}
+
+/// Search a position with a fixed budget and return its value (for the
+/// player to move) and the best move, if the game is not over.
+fn evaluate(agent: &Net, env: &Env, visits: u32, beta: f32) -> (f32, Option<Move>) {
+    if let Some(terminal) = env.terminal() {
+        return (f32::from(Eval::from(terminal)), None);
+    }
+    let mut node = Node::default();
+    for _ in 0..visits {
+        node.simulate_simple(agent, env.clone(), beta, DISCOUNT_FACTOR);
+    }
+    (f32::from(node.evaluation()), Some(node.select_best_action()))
+}
+
+/// A game with annotations, and the value lost by each move.
+pub struct AnnotatedGame {
+    pub ptn: String,
+    /// Names from the `Player1` and `Player2` tags.
+    pub players: [Option<String>; 2],
+    /// Value lost by each move of white and of black.
+    pub losses: [Vec<f32>; 2],
+}
+
+/// Value (between -1 and 1) lost by the move after the position at `ply`.
+/// The value after the move is from the perspective of the opponent.
+fn move_loss(values: &[f32], ply: usize) -> f32 {
+    (values[ply] + values[ply + 1]).max(0.0)
+}
+
+const fn opponent(color: Color) -> Color {
+    match color {
+        Color::White => Color::Black,
+        Color::Black => Color::White,
+    }
+}
+
+/// Value of a tag like `[Player1 "name"]`.
+fn tag_value(tags: &[&str], name: &str) -> Option<String> {
+    tags.iter().find_map(|tag| {
+        let rest = tag.strip_prefix('[')?.strip_prefix(name)?;
+        let value = rest.trim().strip_suffix(']')?.trim().trim_matches('"');
+        Some(value.to_string())
+    })
+}
+
+/// Evaluate every position of a game and write it as PTN, marking moves
+/// which lose value with `?!`, `?`, or `??`.
+pub fn annotate(
+    agent: &Net,
+    ptn: &str,
+    visits: u32,
+    beta: f32,
+    thresholds: Thresholds,
+) -> Result<AnnotatedGame, takzero::ptn::ImportPtnError> {
+    let game: PtnGame<N, HALF_KOMI> = ptn.parse()?;
+    let mut envs: Vec<_> = game.replay.states().collect();
+    let mut last = game.replay.env.clone();
+    for &action in &game.replay.actions {
+        last.step(action);
+    }
+    envs.push(last);
+
+    let (values, best): (Vec<_>, Vec<_>) = envs
+        .iter()
+        .enumerate()
+        .map(|(ply, env)| {
+            log::info!("evaluating ply {ply}/{}", envs.len() - 1);
+            evaluate(agent, env, visits, beta)
+        })
+        .unzip();
+
+    let tags: Vec<_> = ptn
+        .lines()
+        .map(str::trim)
+        .filter(|line| line.starts_with('['))
+        .collect();
+    let mut losses = [Vec::new(), Vec::new()];
+    let mut color = game.replay.env.to_move;
+    for ply in 0..game.replay.actions.len() {
+        losses[usize::from(color == Color::Black)].push(move_loss(&values, ply));
+        color = opponent(color);
+    }
+    Ok(AnnotatedGame {
+        ptn: annotated_ptn(
+            &tags,
+            &game.replay.env,
+            &game.replay.actions.iter().copied().collect::<Vec<_>>(),
+            &values,
+            &best,
+            thresholds,
+        ),
+        players: [tag_value(&tags, "Player1"), tag_value(&tags, "Player2")],
+        losses,
+    })
+}
+
+/// Write the annotated game. `values` has one more entry than `actions`,
+/// for the final position.
+fn annotated_ptn(
+    tags: &[&str],
+    start: &Env,
+    actions: &[Move],
+    values: &[f32],
+    best: &[Option<Move>],
+    thresholds: Thresholds,
+) -> String {
+    let mut out = String::new();
+    for tag in tags {
+        writeln!(out, "{tag}").unwrap();
+    }
+    writeln!(out).unwrap();
+
+    let mut color = start.to_move;
+    let mut move_number = usize::from(start.ply) / 2 + 1;
+    if color == Color::Black {
+        write!(out, "{move_number}. --").unwrap();
+    }
+    for (i, action) in actions.iter().enumerate() {
+        if color == Color::White {
+            if i > 0 {
+                writeln!(out).unwrap();
+            }
+            write!(out, "{move_number}. {action}").unwrap();
+        } else {
+            write!(out, " {action}").unwrap();
+            move_number += 1;
+        }
+
+        let (value, played) = (values[i], -values[i + 1]);
+        let loss = move_loss(values, i);
+        if let Some(annotation) = thresholds.annotation(loss) {
+            write!(out, "{annotation}").unwrap();
+            if let Some(best) = best[i] {
+                let (score, played_score) = (expected_score(value), expected_score(played));
+                write!(
+                    out,
+                    " {{best: {best}, {value:+.2} ({:.1}%) instead of {played:+.2} ({:.1}%)}}",
+                    100.0 * score,
+                    100.0 * played_score
+                )
+                .unwrap();
+            }
+        }
+        color = opponent(color);
+    }
+    writeln!(out).unwrap();
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use fast_tak::takparse::Move;
+    use takzero::network::net6_simhash::Env;
+
+    use super::{annotated_ptn, Thresholds};
+
+    #[test]
+    fn marks_bad_moves() {
+        let actions: Vec<Move> = ["a1", "f6", "b2", "e5"]
+            .into_iter()
+            .map(|s| s.parse().unwrap())
+            .collect();
+        let best = vec![Some("c3".parse().unwrap()); 5];
+        // Values alternate perspective, so a good move keeps the sum near zero.
+        let values = [0.1, -0.1, 0.25, 0.3, -0.4];
+        let ptn = annotated_ptn(
+            &["[Size \"6\"]"],
+            &Env::default(),
+            &actions,
+            &values,
+            &best,
+            Thresholds::default(),
+        );
+        assert_eq!(
+            ptn,
+            "[Size \"6\"]\n\n1. a1 f6?! {best: c3, -0.10 (45.0%) instead of -0.25 (37.5%)}\n2. \
+             b2?? {best: c3, +0.25 (62.5%) instead of -0.30 (35.0%)} e5\n"
+        );
+    }
+}
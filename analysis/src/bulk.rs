@@ -0,0 +1,119 @@
+use std::{collections::BTreeMap, path::Path};
+
+use takzero::network::net6_simhash::Net;
+
+use crate::annotate::{annotate, Thresholds};
+
+/// Aggregate statistics of one player over many games.
+#[derive(Debug, Default)]
+pub struct PlayerStats {
+    pub games: usize,
+    pub moves: usize,
+    pub total_loss: f32,
+    pub inaccuracies: usize,
+    pub mistakes: usize,
+    pub blunders: usize,
+}
+
+impl PlayerStats {
+    fn add(&mut self, losses: &[f32], thresholds: Thresholds) {
+        self.games += 1;
+        self.moves += losses.len();
+        for &loss in losses {
+            self.total_loss += loss;
+            match thresholds.annotation(loss) {
+                Some("??") => self.blunders += 1,
+                Some("?") => self.mistakes += 1,
+                Some(_) => self.inaccuracies += 1,
+                None => {}
+            }
+        }
+    }
+
+    /// Average value lost per move, scaled by 100 like centipawns.
+    #[must_use]
+    pub fn average_loss(&self) -> f32 {
+        100.0 * self.total_loss / self.moves.max(1) as f32
+    }
+}
+
+/// Annotate every `.ptn` file in `input` and write the annotated copies with
+/// the same name to `output`. Games which cannot be read are skipped.
+/// Returns statistics by player name (from the `Player1` and `Player2` tags).
+pub fn annotate_directory(
+    agent: &Net,
+    input: &Path,
+    output: &Path,
+    visits: u32,
+    beta: f32,
+    thresholds: Thresholds,
+) -> std::io::Result<BTreeMap<String, PlayerStats>> {
+    std::fs::create_dir_all(output)?;
+    let mut paths: Vec<_> = std::fs::read_dir(input)?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<Result<_, _>>()?;
+    paths.retain(|path| path.extension().is_some_and(|extension| extension == "ptn"));
+    paths.sort();
+
+    let mut stats: BTreeMap<String, PlayerStats> = BTreeMap::new();
+    for (i, path) in paths.iter().enumerate() {
+        log::info!("annotating {} ({}/{})", path.display(), i + 1, paths.len());
+        let ptn = std::fs::read_to_string(path)?;
+        let game = match annotate(agent, &ptn, visits, beta, thresholds) {
+            Ok(game) => game,
+            Err(err) => {
+                log::warn!("skipping {}: {err}", path.display());
+                continue;
+            }
+        };
+        std::fs::write(output.join(path.file_name().unwrap_or_default()), &game.ptn)?;
+        for ((player, losses), default) in game
+            .players
+            .into_iter()
+            .zip(&game.losses)
+            .zip(["White", "Black"])
+        {
+            stats
+                .entry(player.unwrap_or_else(|| default.to_string()))
+                .or_default()
+                .add(losses, thresholds);
+        }
+    }
+    Ok(stats)
+}
+
+pub fn print_stats(stats: &BTreeMap<String, PlayerStats>) {
+    println!(
+        "{:<24} {:>6} {:>6} {:>8} {:>6} {:>6} {:>6}",
+        "player", "games", "moves", "avg loss", "?!", "?", "??"
+    );
+    for (player, stats) in stats {
+        println!(
+            "{:<24} {:>6} {:>6} {:>8.1} {:>6} {:>6} {:>6}",
+            player,
+            stats.games,
+            stats.moves,
+            stats.average_loss(),
+            stats.inaccuracies,
+            stats.mistakes,
+            stats.blunders
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::PlayerStats;
+    use crate::annotate::Thresholds;
+
+    #[test]
+    fn counts_annotations() {
+        let mut stats = PlayerStats::default();
+        stats.add(&[0.0, 0.15, 0.25, 0.5], Thresholds::default());
+        stats.add(&[0.1], Thresholds::default());
+        assert_eq!(stats.games, 2);
+        assert_eq!(stats.moves, 5);
+        assert_eq!((stats.inaccuracies, stats.mistakes, stats.blunders), (2, 1, 1));
+        assert!((stats.average_loss() - 20.0).abs() < 1e-4);
+    }
+}
@@ -0,0 +1,282 @@
+use std::{
+    io::{BufRead, Write as _},
+    path::PathBuf,
+    time::{Duration, Instant},
+};
+
+use clap::Parser;
+use fast_tak::takparse::{Move, Tps};
+use rand::prelude::*;
+use takzero::{
+    network::{
+        checkpoint,
+        net6_simhash::{Env, Net},
+        repr::game_to_tensor,
+        HashNetwork,
+        Network,
+    },
+    search::{
+        env::Environment,
+        node::{
+            batched::BatchedMCTS,
+            debug::TreeRender,
+            progress::{ProgressReporter, ReportInterval},
+            Node,
+        },
+        DISCOUNT_FACTOR,
+    },
+};
+use tch::Device;
+
+mod annotate;
+mod bulk;
+
+const DEVICE: Device = Device::Cuda(0);
+const BETA: f32 = 0.0;
+// const BATCH_SIZE: usize = 128;
+
+#[derive(Parser, Debug)]
+pub struct Args {
+    /// Path to model to load, or a directory to load its latest model.
+    #[arg(long)]
+    model_path: PathBuf,
+    /// Run an example game
+    #[arg(long)]
+    example: bool,
+    /// Starting position written as TPS
+    #[arg(long)]
+    tps: Option<Tps>,
+    /// Annotate the moves of a PTN game with `?!`, `?`, and `??`
+    #[arg(long)]
+    annotate: Option<PathBuf>,
+    /// Annotate every PTN file in a directory and report statistics per player
+    #[arg(long, conflicts_with = "annotate")]
+    annotate_dir: Option<PathBuf>,
+    /// Where to write the annotated game (standard output by default), or the
+    /// directory for annotated copies with `--annotate-dir` (`annotated` by default)
+    #[arg(long)]
+    output: Option<PathBuf>,
+    /// Simulations per position when annotating
+    #[arg(long, default_value_t = 800)]
+    visits: u32,
+    /// How often to print progress while simulating interactively
+    #[arg(long, default_value_t = 1000)]
+    info_milliseconds: u64,
+    /// Depth of the search tree printed after searching
+    #[arg(long, default_value_t = 1)]
+    tree_depth: usize,
+    /// Children printed per node of the search tree (all by default)
+    #[arg(long)]
+    tree_top: Option<usize>,
+}
+
+// #[allow(unused)]
+// fn gather_policy_data(agent: &Net, rng: &mut impl Rng) {
+//     let file = OpenOptions::new().read(true).open("replays.txt").unwrap();
+//     let env_batches = BufReader::new(file)
+//         .lines()
+//         .filter_map(|line| line.ok()?.parse::<Replay<Env>>().ok())
+//         .choose_multiple(rng, 1024)
+//         .into_iter()
+//         .map(|mut replay| {
+//             replay.advance(rng.gen_range(0..replay.len()));
+//             replay.env
+//         })
+//         .array_chunks::<BATCH_SIZE>()
+//         .collect::<Vec<_>>();
+
+//     let mut line = String::new();
+//     for envs in env_batches {
+//         let mut batched_mcts = BatchedMCTS::from_envs(envs);
+
+//         // for _ in 0..800 {
+//         //     batched_mcts.simulate(&agent, &[BETA; 128]);
+//         // }
+//         batched_mcts.gumbel_sequential_halving(agent, &[BETA; 128], 64, 768,
+// rng);
+
+//         for (node, _) in batched_mcts.nodes_and_envs() {
+//             line.clear();
+//             node.children.iter().for_each(|(a, child)| {
+//                 write!(
+//                     &mut line,
+//                     "{a}:{}:{}:{}:{},",
+//                     child.visit_count, child.evaluation, child.std_dev,
+// child.logit                 )
+//                 .unwrap();
+//             });
+//             println!("{line}");
+//         }
+//     }
+// }
+
+/// Run the analysis with the given arguments. The caller initializes logging.
+///
+/// # Panics
+///
+/// Panics if the model or the games cannot be loaded.
+pub fn run(args: Args) {
+    let model_path = checkpoint::resolve(&args.model_path).expect("Model path should exist");
+    let agent = Net::load_partial(model_path, DEVICE).unwrap();
+    let mut rng = StdRng::seed_from_u64(123);
+
+    if let Some(path) = args.annotate {
+        let ptn = std::fs::read_to_string(path).expect("PTN file should be readable");
+        let annotated = annotate::annotate(
+            &agent,
+            &ptn,
+            args.visits,
+            BETA,
+            annotate::Thresholds::default(),
+        )
+        .expect("PTN should be a valid game for this network")
+        .ptn;
+        if let Some(output) = args.output {
+            std::fs::write(output, annotated).expect("output should be writable");
+        } else {
+            print!("{annotated}");
+        }
+        return;
+    }
+
+    if let Some(input) = args.annotate_dir {
+        let output = args.output.unwrap_or_else(|| PathBuf::from("annotated"));
+        let stats = bulk::annotate_directory(
+            &agent,
+            &input,
+            &output,
+            args.visits,
+            BETA,
+            annotate::Thresholds::default(),
+        )
+        .expect("PTN files should be readable and annotated copies writable");
+        bulk::print_stats(&stats);
+        return;
+    }
+
+    let mut env = args.tps.map(Env::from).unwrap_or_default();
+    let mut node = Node::default();
+    let tree = TreeRender {
+        max_depth: args.tree_depth,
+        top_k: args.tree_top,
+        // Deeper nodes are only worth showing once they were visited.
+        min_visits: u32::from(args.tree_depth > 1),
+        ..TreeRender::default()
+    };
+    if args.example {
+        while env.terminal().is_none() {
+            println!("tps: {}", Tps::from(env.clone()));
+            // for _ in 0..visits {
+            //     node.simulate_simple(&agent, env.clone(), BETA);
+            // }
+            let mut batched_mcts = BatchedMCTS::from_envs([env.clone()]);
+            let (bm_node, _) = batched_mcts.nodes_and_envs_mut().next().unwrap();
+            std::mem::swap(bm_node, &mut node);
+            batched_mcts.gumbel_sequential_halving(
+                &agent,
+                &[BETA],
+                DISCOUNT_FACTOR,
+                64,
+                768,
+                &mut rng,
+            );
+            let (bm_node, _) = batched_mcts.nodes_and_envs_mut().next().unwrap();
+            std::mem::swap(bm_node, &mut node);
+            println!("{}", node.render(&tree));
+
+            // Print raw network output.
+            let xs = tch::Tensor::concat(
+                &node
+                    .children()
+                    .iter()
+                    .map(|(a, _)| {
+                        let mut clone = env.clone();
+                        clone.step(a);
+                        game_to_tensor(&clone, tch::Device::Cpu)
+                    })
+                    .collect::<Vec<_>>(),
+                0,
+            )
+            .to(DEVICE);
+            let local_unc: Vec<f32> = agent.forward_hash(&xs).try_into().unwrap();
+            let (_policy, value, ube) = agent.forward_t(&xs, false);
+            let value_out: Vec<Vec<f32>> = value.try_into().unwrap();
+            let ube_out: Vec<Vec<f32>> = ube.exp().try_into().unwrap();
+
+            println!("network output:");
+            println!("[action]  [value]  [local]  [ ube ]");
+            let mut network_output = local_unc
+                .into_iter()
+                .zip(value_out)
+                .zip(ube_out)
+                .zip(node.children().iter())
+                .collect::<Vec<_>>();
+            network_output.sort_by_key(|(_, (_, n))| n.visit_count());
+            network_output.reverse();
+            for (((local, value), ube), (action, _)) in network_output {
+                println!(
+                    "{: ^8}  {:+.4}  {local:+.4}  {:+.4}",
+                    action.to_string(),
+                    value[0],
+                    ube[0]
+                );
+            }
+            println!();
+
+            let action = node.select_best_action();
+            println!(">>> {action}");
+            node.descend(&action);
+            env.step(action);
+        }
+        return;
+    }
+
+    let mut input = String::new();
+    loop {
+        input.clear();
+        println!("tps: {}", Tps::from(env.clone()));
+        print!(">>> ");
+        std::io::stdout().flush().unwrap();
+        std::io::stdin().lock().read_line(&mut input).unwrap();
+        let trim = input.trim();
+        if let Ok(mov) = trim.parse::<Move>() {
+            match env.play(mov) {
+                Ok(()) => {}
+                Err(e) => {
+                    eprintln!("{e}");
+                    continue;
+                }
+            }
+            node.descend(&mov);
+        } else if let Ok(visits) = trim.parse::<u32>() {
+            // Plain MCTS with progress reports along the way.
+            println!("simulating {visits} visits");
+            let interval = Duration::from_millis(args.info_milliseconds);
+            let mut reporter = ProgressReporter::new(ReportInterval::Time(interval));
+            let start = Instant::now();
+            for simulation in 1..=visits {
+                node.simulate_simple(&agent, env.clone(), BETA, DISCOUNT_FACTOR);
+                let elapsed = start.elapsed();
+                if reporter.should_report(simulation, elapsed) {
+                    println!("{}", node.progress(elapsed));
+                }
+            }
+            println!("{}", node.progress(start.elapsed()));
+        } else {
+            let mut batched_mcts = BatchedMCTS::from_envs([env.clone()]);
+            let (bm_node, _) = batched_mcts.nodes_and_envs_mut().next().unwrap();
+            std::mem::swap(bm_node, &mut node);
+            batched_mcts.gumbel_sequential_halving(
+                &agent,
+                &[BETA],
+                DISCOUNT_FACTOR,
+                64,
+                768,
+                &mut rng,
+            );
+            let (bm_node, _) = batched_mcts.nodes_and_envs_mut().next().unwrap();
+            std::mem::swap(bm_node, &mut node);
+        }
+        println!("{}", node.render(&tree));
+    }
+}
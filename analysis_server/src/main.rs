@@ -0,0 +1,213 @@
+use std::{
+    net::SocketAddr,
+    num::NonZeroUsize,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+};
+
+use axum::{
+    extract::{Query, State},
+    http::{header, StatusCode},
+    response::IntoResponse,
+    routing::get,
+    Json,
+    Router,
+};
+use clap::Parser;
+use fast_tak::takparse::Tps;
+use lru::LruCache;
+use serde::{Deserialize, Serialize};
+use takzero::{
+    network::{
+        checkpoint,
+        net6_simhash::{Env, Net, N},
+        Network,
+    },
+    search::{node::Node, DISCOUNT_FACTOR},
+};
+use tch::Device;
+use tokio::sync::{mpsc, oneshot};
+
+const DEVICE: Device = Device::Cuda(0);
+const QUEUE_SIZE: usize = 64;
+
+#[derive(Parser, Debug)]
+struct Args {
+    /// Path to the model
+    #[arg(long)]
+    model_path: PathBuf,
+    /// Address to serve on
+    #[arg(long, default_value = "0.0.0.0:8080")]
+    address: SocketAddr,
+    /// Number of analyses to keep in the cache
+    #[arg(long, default_value_t = NonZeroUsize::new(4096).unwrap())]
+    cache_size: NonZeroUsize,
+    /// Visits used when the request does not specify them
+    #[arg(long, default_value_t = 800)]
+    default_visits: u32,
+    /// Maximum visits a request may ask for
+    #[arg(long, default_value_t = 100_000)]
+    max_visits: u32,
+    /// Exploration bonus for the search
+    #[arg(long, default_value_t = 0.0)]
+    beta: f32,
+}
+
+#[derive(Deserialize, Debug)]
+struct AnalyzeQuery {
+    tps: String,
+    visits: Option<u32>,
+    /// Number of moves to include in the policy
+    top: Option<usize>,
+}
+
+#[derive(Serialize, Debug, Clone)]
+struct MoveInfo {
+    #[serde(rename = "move")]
+    action: String,
+    /// Prior probability from the network.
+    probability: f32,
+    visits: u32,
+}
+
+#[derive(Serialize, Debug, Clone)]
+struct Analysis {
+    tps: String,
+    visits: u32,
+    /// `None` if the game is over.
+    best_move: Option<String>,
+    principal_variation: Vec<String>,
+    /// Value for the player to move, between -1 and 1.
+    value: f32,
+    /// Evaluation as printed by the engine, for example `Win(3)`.
+    evaluation: String,
+    /// Moves ordered by prior probability.
+    policy: Vec<MoveInfo>,
+}
+
+/// A position to search and where to send the result.
+struct Job {
+    env: Env,
+    visits: u32,
+    respond: oneshot::Sender<Analysis>,
+}
+
+struct AppState {
+    jobs: mpsc::Sender<Job>,
+    cache: Mutex<LruCache<(String, u32), Arc<Analysis>>>,
+    default_visits: u32,
+    max_visits: u32,
+}
+
+type Error = (StatusCode, String);
+
+async fn analyze(
+    State(state): State<Arc<AppState>>,
+    Query(query): Query<AnalyzeQuery>,
+) -> Result<impl IntoResponse, Error> {
+    let tps: Tps = query.tps.parse().map_err(|err| {
+        (StatusCode::BAD_REQUEST, format!("invalid TPS `{}`: {err}", query.tps))
+    })?;
+    if tps.size() != N {
+        return Err((StatusCode::BAD_REQUEST, format!("only size {N} is supported")));
+    }
+    let visits = query.visits.unwrap_or(state.default_visits);
+    if visits == 0 || visits > state.max_visits {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            format!("visits should be between 1 and {}", state.max_visits),
+        ));
+    }
+
+    // Normalize the TPS so that equivalent requests share a cache entry.
+    let env = Env::from(tps);
+    let key = (Tps::from(env.clone()).to_string(), visits);
+    let cached = state.cache.lock().unwrap().get(&key).cloned();
+    let analysis = if let Some(analysis) = cached {
+        analysis
+    } else {
+        let (respond, response) = oneshot::channel();
+        state
+            .jobs
+            .send(Job { env, visits, respond })
+            .await
+            .map_err(|_| {
+                (StatusCode::SERVICE_UNAVAILABLE, "the search worker has stopped".into())
+            })?;
+        let analysis = Arc::new(response.await.map_err(|_| {
+            (StatusCode::INTERNAL_SERVER_ERROR, "the search worker dropped the request".into())
+        })?);
+        state.cache.lock().unwrap().put(key, analysis.clone());
+        analysis
+    };
+
+    let mut analysis = Analysis::clone(&analysis);
+    if let Some(top) = query.top {
+        analysis.policy.truncate(top);
+    }
+    // Allow analysis boards hosted elsewhere to query the server.
+    Ok(([(header::ACCESS_CONTROL_ALLOW_ORIGIN, "*")], Json(analysis)))
+}
+
+fn search(net: &Net, env: Env, visits: u32, beta: f32) -> Analysis {
+    let mut node = Node::default();
+    for _ in 0..visits {
+        node.simulate_simple(net, env.clone(), beta, DISCOUNT_FACTOR);
+    }
+
+    let mut policy: Vec<_> = node
+        .children()
+        .iter()
+        .map(|(action, child)| MoveInfo {
+            action: action.to_string(),
+            probability: child.probability().into_inner(),
+            visits: child.visit_count(),
+        })
+        .collect();
+    policy.sort_by(|a, b| b.probability.total_cmp(&a.probability));
+    Analysis {
+        tps: Tps::from(env).to_string(),
+        visits,
+        best_move: (!node.children().is_empty()).then(|| node.select_best_action().to_string()),
+        principal_variation: node.principal_variation().map(|a| a.to_string()).collect(),
+        value: f32::from(node.evaluation()),
+        evaluation: node.evaluation().to_string(),
+        policy,
+    }
+}
+
+/// Run searches one at a time on the GPU.
+fn run_worker(net: &Net, mut jobs: mpsc::Receiver<Job>, beta: f32) {
+    while let Some(Job { env, visits, respond }) = jobs.blocking_recv() {
+        log::debug!("searching {visits} visits");
+        // The client might have gone away in the meantime.
+        respond.send(search(net, env, visits, beta)).ok();
+    }
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    takzero::logging::init();
+    let args = Args::parse();
+    checkpoint::configure_repr(&args.model_path)?;
+    let net = Net::load_partial(&args.model_path, DEVICE)?;
+
+    let (jobs, receiver) = mpsc::channel(QUEUE_SIZE);
+    let beta = args.beta;
+    std::thread::spawn(move || tch::no_grad(|| run_worker(&net, receiver, beta)));
+
+    let state = Arc::new(AppState {
+        jobs,
+        cache: Mutex::new(LruCache::new(args.cache_size)),
+        default_visits: args.default_visits,
+        max_visits: args.max_visits,
+    });
+    let app = Router::new()
+        .route("/analyze", get(analyze))
+        .with_state(state);
+
+    log::info!("serving on {}", args.address);
+    let listener = tokio::net::TcpListener::bind(args.address).await?;
+    axum::serve(listener, app).await?;
+    Ok(())
+}
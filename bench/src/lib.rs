@@ -0,0 +1,383 @@
+use std::{
+    cmp::Reverse,
+    env,
+    hint::black_box,
+    io,
+    path::PathBuf,
+    process::{self, Command, Stdio},
+    time::{Duration, Instant},
+};
+
+use clap::Parser;
+use fast_tak::{Game, Reserves};
+use ordered_float::NotNan;
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use rayon::prelude::*;
+use takzero::{
+    batch_size::gpu_memory_of,
+    network::{
+        checkpoint,
+        net6_simhash::{Env, Net, N},
+        repr::{game_to_tensor, games_to_input, input_channels},
+        staging::Staging,
+        CheckpointNetwork,
+        HashNetwork,
+        Network,
+    },
+    positions::{random_position, Constraints},
+    search::{
+        agent::Agent,
+        env::{ActionIndex, Environment},
+        node::{policy::sigma_select, Node},
+        DISCOUNT_FACTOR,
+    },
+};
+use tch::{Device, Kind, TchError, Tensor};
+
+#[derive(Parser, Debug)]
+pub struct Args {
+    /// Model to benchmark, or a directory to benchmark its latest model
+    /// (randomly initialized if not given)
+    #[arg(long)]
+    model_path: Option<PathBuf>,
+    /// Run the network on the CPU
+    #[arg(long)]
+    cpu: bool,
+    /// Seconds to spend on each measurement
+    #[arg(long, default_value_t = 3.0)]
+    seconds: f64,
+    /// Batch sizes for network evaluation
+    #[arg(long, value_delimiter = ',', default_value = "1,8,32,128,512")]
+    batch_sizes: Vec<usize>,
+    /// Plies from the start of the random benchmark positions
+    #[arg(long, default_value_t = 14)]
+    plies: u16,
+    /// Batch size of the training step benchmark
+    #[arg(long, default_value_t = 128)]
+    train_batch_size: usize,
+    /// Segments of recomputed residual blocks to compare training steps
+    /// with, where 0 keeps every activation (speeds are relative to the
+    /// first)
+    #[arg(long, value_delimiter = ',', default_value = "0,1,4")]
+    checkpoint_segments: Vec<usize>,
+    /// Only measure training steps with this many segments, and print the
+    /// steps per second and the peak GPU memory in bytes. Every setting is
+    /// measured in a process of its own this way, because `LibTorch` keeps
+    /// the memory it allocated for the next setting.
+    #[arg(long, hide = true)]
+    training_segments: Option<usize>,
+    #[arg(long, default_value_t = 0)]
+    seed: u64,
+}
+
+/// Run `f` repeatedly for about `duration` and return the rate of the work
+/// it reports doing per call.
+fn throughput(duration: Duration, mut f: impl FnMut() -> usize) -> f64 {
+    // Warm up (for example CUDA kernels and allocations).
+    f();
+    let start = Instant::now();
+    let mut work = 0;
+    while start.elapsed() < duration {
+        work += f();
+    }
+    work as f64 / start.elapsed().as_secs_f64()
+}
+
+/// # Panics
+///
+/// Panics if no position is found for the number of plies.
+fn positions<const N: usize, const HALF_KOMI: i8>(
+    count: usize,
+    plies: u16,
+    rng: &mut StdRng,
+) -> Vec<Game<N, HALF_KOMI>>
+where
+    Reserves<N>: Default,
+{
+    let constraints = Constraints {
+        plies: plies..=plies,
+        // Search speed does not depend much on who is winning.
+        visits: 0,
+        ..Constraints::default()
+    };
+    (0..count)
+        .map(|_| {
+            random_position(rng, &constraints, 1_000)
+                .unwrap_or_else(|| panic!("no position with {plies} plies was found"))
+        })
+        .collect()
+}
+
+/// Wait for the work queued on the device.
+fn synchronize(device: Device) {
+    if let Device::Cuda(index) = device {
+        tch::Cuda::synchronize(index as i64);
+    }
+}
+
+/// An agent which values every position at 0 and prefers no action, so
+/// that the search benchmark does not measure the heuristic value of
+/// [`Dummy`](takzero::search::agent::dummy::Dummy).
+struct Uniform;
+
+impl<E: Environment> Agent<E> for Uniform {
+    fn policy_value_uncertainty(
+        &self,
+        env_batch: &[E],
+        actions_batch: &[Vec<E::Action>],
+    ) -> impl Iterator<Item = (Vec<(E::Action, NotNan<f32>)>, f32, f32)> {
+        debug_assert_eq!(env_batch.len(), actions_batch.len());
+        actions_batch.iter().map(|actions| {
+            (
+                actions
+                    .iter()
+                    .map(|a| (a.clone(), NotNan::new(1.0).unwrap()))
+                    .collect(),
+                0.0,
+                0.0,
+            )
+        })
+    }
+}
+
+/// Simulations per second from a single root.
+fn search_throughput<E: ActionIndex, A: Agent<E>>(
+    agent: &A,
+    env: &E,
+    duration: Duration,
+) -> f64 {
+    let mut node = Node::default();
+    throughput(duration, || {
+        node.simulate_simple(agent, env.clone(), 0.0, DISCOUNT_FACTOR);
+        1
+    })
+}
+
+fn bench_size<const N: usize, const HALF_KOMI: i8>(args: &Args, rng: &mut StdRng)
+where
+    Reserves<N>: Default,
+{
+    let duration = Duration::from_secs_f64(args.seconds);
+    let env = positions::<N, HALF_KOMI>(1, args.plies, rng).remove(0);
+    let simulations = search_throughput(&Uniform, &env, duration);
+    let envs = positions::<N, HALF_KOMI>(256, args.plies, rng);
+    let encodings = throughput(duration, || {
+        for env in &envs {
+            black_box(game_to_tensor(env, Device::Cpu));
+        }
+        envs.len()
+    });
+    println!("{N}x{N}: dummy search {simulations:.0} sims/s, encoding {encodings:.0} positions/s");
+}
+
+/// The network to benchmark, or `None` after logging why it could not be
+/// loaded.
+fn load_net(args: &Args, device: Device) -> Option<Net> {
+    let Some(path) = &args.model_path else {
+        return Some(Net::new(device, Some(args.seed as i64)));
+    };
+    checkpoint::resolve(path)
+        .map_err(TchError::from)
+        .and_then(|path| Net::load_partial(path, device))
+        .inspect_err(|err| log::error!("could not load {}: {err}", path.display()))
+        .ok()
+}
+
+/// Training steps per second with `segments` segments of recomputed
+/// residual blocks, or none, and the peak GPU memory of this process.
+fn training_steps(
+    args: &Args,
+    net: &Net,
+    device: Device,
+    segments: usize,
+    rng: &mut StdRng,
+) -> (f64, Option<u64>) {
+    let envs: Vec<Env> = positions(args.train_batch_size, args.plies, rng);
+    let shape = [
+        args.train_batch_size as i64,
+        input_channels::<N>() as i64,
+        N as i64,
+        N as i64,
+    ];
+    let xs = Tensor::from_slice(&games_to_input(&envs)).view(shape).to(device);
+    let loss = |(policy, value, _): (Tensor, Tensor, Tensor)| {
+        policy.square().mean(Kind::Float) + value.square().mean(Kind::Float)
+    };
+    let steps = throughput(Duration::from_secs_f64(args.seconds), || {
+        if segments == 0 {
+            loss(net.forward_t(&xs, true)).backward();
+        } else {
+            let (output, checkpoints) = net.forward_checkpointed(&xs, segments);
+            loss(output).backward();
+            net.backward_checkpointed(checkpoints);
+        }
+        synchronize(device);
+        1
+    });
+    // `LibTorch` keeps the memory it allocated, so what the process holds
+    // now is its peak.
+    let memory = match device {
+        Device::Cuda(_) => gpu_memory_of(process::id()),
+        _ => None,
+    };
+    (steps, memory)
+}
+
+/// Measure training steps with `segments` segments in a new process with
+/// the arguments of this one, whose last `--training-segments` wins.
+fn training_steps_in_process(segments: usize) -> io::Result<(f64, Option<u64>)> {
+    let output = Command::new(env::current_exe()?)
+        .args(env::args_os().skip(1))
+        .args(["--training-segments", &segments.to_string()])
+        .stderr(Stdio::inherit())
+        .output()?;
+    if !output.status.success() {
+        return Err(io::Error::other(format!("the measurement failed: {}", output.status)));
+    }
+    let output = String::from_utf8_lossy(&output.stdout);
+    let mut words = output.split_whitespace();
+    let steps = words
+        .next()
+        .and_then(|steps| steps.parse().ok())
+        .ok_or_else(|| io::Error::other(format!("unexpected measurement: {output}")))?;
+    Ok((steps, words.next().and_then(|memory| memory.parse().ok())))
+}
+
+/// Roots in a batch of self-play, and actions sampled at each.
+const HALVING_BATCH_SIZE: usize = 128;
+const HALVING_SAMPLED_ACTIONS: usize = 64;
+
+/// Batches per second of the first halving of sequential halving, in which
+/// the actions of every root are sorted by their score, across the roots in
+/// parallel like the search does, and in sequence.
+fn halving_throughput(duration: Duration, rng: &mut StdRng) -> (f64, f64) {
+    let mut score = || NotNan::new(rng.gen::<f32>()).expect("random numbers are not NaN");
+    let sets: Vec<Vec<_>> = (0..HALVING_BATCH_SIZE)
+        .map(|_| (0..HALVING_SAMPLED_ACTIONS).map(|_| (score(), score(), score())).collect())
+        .collect();
+    let halve = |set: &mut Vec<(NotNan<f32>, NotNan<f32>, NotNan<f32>)>| {
+        set.sort_by_key(|&(logit, q, std_dev)| Reverse(logit + sigma_select(q, std_dev, 0.0, 1.0)));
+        set.truncate(HALVING_SAMPLED_ACTIONS / 2);
+    };
+    let parallel = throughput(duration, || {
+        let mut sets = sets.clone();
+        sets.par_iter_mut().for_each(halve);
+        black_box(sets);
+        1
+    });
+    let sequential = throughput(duration, || {
+        let mut sets = sets.clone();
+        sets.iter_mut().for_each(halve);
+        black_box(sets);
+        1
+    });
+    (parallel, sequential)
+}
+
+/// Run the benchmarks with the given arguments. The caller initializes
+/// logging. A model which cannot be loaded is logged as an error.
+pub fn run(args: Args) {
+    let mut rng = StdRng::seed_from_u64(args.seed);
+    if let Some(segments) = args.training_segments {
+        let device = if args.cpu { Device::Cpu } else { Device::cuda_if_available() };
+        if let Some(net) = load_net(&args, device) {
+            let (steps, memory) = training_steps(&args, &net, device, segments, &mut rng);
+            println!("{steps} {}", memory.map_or_else(|| "-".to_string(), |m| m.to_string()));
+        }
+        return;
+    }
+
+    println!("# search with the dummy agent and input encoding");
+    bench_size::<3, 0>(&args, &mut rng);
+    bench_size::<4, 0>(&args, &mut rng);
+    bench_size::<5, 4>(&args, &mut rng);
+    bench_size::<6, 4>(&args, &mut rng);
+    bench_size::<7, 4>(&args, &mut rng);
+    bench_size::<8, 4>(&args, &mut rng);
+
+    println!(
+        "# first halving of {HALVING_BATCH_SIZE} roots with {HALVING_SAMPLED_ACTIONS} sampled \
+         actions"
+    );
+    let (parallel, sequential) =
+        halving_throughput(Duration::from_secs_f64(args.seconds), &mut rng);
+    println!("{parallel:.0} batches/s in parallel, {sequential:.0} batches/s in sequence");
+
+    let device = if args.cpu { Device::Cpu } else { Device::cuda_if_available() };
+    let Some(net) = load_net(&args, device) else {
+        return;
+    };
+    let duration = Duration::from_secs_f64(args.seconds);
+    tch::no_grad(|| {
+        println!("# network on {device:?}");
+        let env: Env = positions(1, args.plies, &mut rng).remove(0);
+        let simulations = search_throughput(&net, &env, duration);
+        println!("search {simulations:.0} sims/s");
+
+        for &batch_size in &args.batch_sizes {
+            let envs: Vec<Env> = positions(batch_size, args.plies, &mut rng);
+            let actions: Vec<_> = envs
+                .iter()
+                .map(|env| {
+                    let mut actions = Vec::new();
+                    env.populate_actions(&mut actions);
+                    actions
+                })
+                .collect();
+            let evaluations = throughput(duration, || {
+                net.policy_value_uncertainty(&envs, &actions)
+                    .map(black_box)
+                    .count()
+            });
+            println!("batch {batch_size:>4}: {evaluations:.0} evaluations/s");
+        }
+
+        // Staged uploads encode into pinned memory, plain ones copy the
+        // encoded input into a tensor before sending it.
+        println!("# input uploads to {device:?}");
+        let mut staging = Staging::new(device);
+        for &batch_size in &args.batch_sizes {
+            let envs: Vec<Env> = positions(batch_size, args.plies, &mut rng);
+            let shape = [batch_size as i64, input_channels::<N>() as i64, N as i64, N as i64];
+            let plain = throughput(duration, || {
+                let xs = Tensor::from_slice(&games_to_input(&envs)).view(shape).to(device);
+                synchronize(device);
+                black_box(xs);
+                batch_size
+            });
+            let staged = throughput(duration, || {
+                let xs = staging.upload(&envs);
+                synchronize(device);
+                black_box(xs);
+                batch_size
+            });
+            println!(
+                "batch {batch_size:>4}: {plain:.0} positions/s plain, {staged:.0} positions/s \
+                 staged"
+            );
+        }
+    });
+
+    // Every setting runs in a process of its own, so that its peak memory
+    // is not that of another setting.
+    println!("# training steps of batch {}", args.train_batch_size);
+    let mut baseline = None;
+    for &segments in &args.checkpoint_segments {
+        let (steps, memory) = match training_steps_in_process(segments) {
+            Ok(measured) => measured,
+            Err(err) => {
+                log::error!("could not measure training steps with {segments} segments: {err}");
+                continue;
+            }
+        };
+        let baseline = *baseline.get_or_insert(steps);
+        let memory = memory.map_or_else(
+            || "unknown peak GPU memory".to_string(),
+            |bytes| format!("{:.0} MiB peak GPU memory", bytes as f64 / f64::from(1 << 20)),
+        );
+        println!(
+            "segments {segments:>2}: {steps:.1} steps/s ({:.2}x), {memory}",
+            steps / baseline
+        );
+    }
+}
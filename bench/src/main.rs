@@ -0,0 +1,6 @@
+use clap::Parser;
+
+fn main() {
+    takzero::logging::init();
+    bench::run(bench::Args::parse());
+}
@@ -0,0 +1,139 @@
+//! The `takzero` command, which runs the parts of a training run as
+//! subcommands, like `takzero selfplay --directory runs/main`.
+//!
+//! Every subcommand takes the options of the binary it replaces. They share
+//! logging (`--log-format` and `--log-filter`), a configuration file with
+//! the options of a run (`--config run.conf`, see [`takzero::config`]), and
+//! how checkpoints are verified and found (see
+//! [`takzero::network::checkpoint`]).
+
+use std::{ffi::OsString, path::PathBuf, process::ExitCode};
+
+use clap::{CommandFactory, FromArgMatches, Parser, Subcommand};
+use takzero::{config::Config, logging};
+
+#[derive(Parser, Debug)]
+#[command(name = "takzero", version, about)]
+struct Cli {
+    /// Configuration file with options for the subcommands. Options on the
+    /// command line take precedence.
+    #[arg(long)]
+    config: Option<PathBuf>,
+    /// Format of log lines, `text` or `json`. Defaults to `TAKZERO_LOG_FORMAT`.
+    #[arg(long)]
+    log_format: Option<logging::Format>,
+    /// Log filter like `info,takzero=debug`. Defaults to `RUST_LOG`.
+    #[arg(long)]
+    log_filter: Option<String>,
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Play games against itself to generate replays and targets.
+    Selfplay(selfplay::Args),
+    /// Train new models on the targets of self-play and reanalysis.
+    Train(learn::Args),
+    /// Compute fresh targets from old replays.
+    Reanalyze(reanalyze::Args),
+    /// Play models against each other.
+    Evaluate(evaluation::Args),
+    /// Analyze positions and annotate games.
+    Analyze(analysis::Args),
+    /// Speak TEI on standard input and output.
+    Tei,
+    /// Measure search, network, and encoding throughput.
+    Bench(bench::Args),
+}
+
+fn main() -> ExitCode {
+    let mut args: Vec<OsString> = std::env::args_os().collect();
+    if let Err(err) = apply_config(&mut args) {
+        eprintln!("{err}");
+        return ExitCode::FAILURE;
+    }
+    let matches = command().get_matches_from(args);
+    let cli = Cli::from_arg_matches(&matches).unwrap_or_else(|err| err.exit());
+    logging::init_with(cli.log_format, cli.log_filter.as_deref());
+
+    match cli.command {
+        Command::Selfplay(args) => selfplay::run(args),
+        Command::Train(args) => learn::run(args),
+        Command::Reanalyze(args) => reanalyze::run(args),
+        Command::Evaluate(args) => evaluation::run(args),
+        Command::Analyze(args) => analysis::run(args),
+        Command::Tei => tei::run(),
+        Command::Bench(args) => bench::run(args),
+    }
+    ExitCode::SUCCESS
+}
+
+/// The command line interface, in which options given twice take the last
+/// value, so that the command line overrides the configuration file.
+fn command() -> clap::Command {
+    let mut command = Cli::command();
+    let names: Vec<_> = command
+        .get_subcommands()
+        .map(|subcommand| subcommand.get_name().to_string())
+        .collect();
+    for name in names {
+        command = command.mut_subcommand(name, |subcommand| subcommand.args_override_self(true));
+    }
+    command
+}
+
+/// Insert the options of the configuration file, if one is given, right
+/// after the name of the subcommand.
+fn apply_config(args: &mut Vec<OsString>) -> Result<(), takzero::config::ConfigError> {
+    // The subcommand may be missing options which the configuration has,
+    // so the command line is only parsed far enough to find both.
+    let Ok(matches) = command().ignore_errors(true).try_get_matches_from(args.iter()) else {
+        return Ok(());
+    };
+    let (Some(path), Some(name)) = (matches.get_one::<PathBuf>("config"), matches.subcommand_name())
+    else {
+        return Ok(());
+    };
+    let config = Config::load(path)?;
+    let command = command();
+    let Some(subcommand) = command.find_subcommand(name) else {
+        return Ok(());
+    };
+    let has_option = |key: &str| {
+        subcommand
+            .get_arguments()
+            .any(|arg| arg.get_long() == Some(key))
+    };
+    let position = subcommand_position(args, &matches, &command);
+    let options = config.arguments(name, has_option).into_iter().map(OsString::from);
+    args.splice(position + 1..position + 1, options);
+    Ok(())
+}
+
+/// The position in `args` of the subcommand, which follows the value of the
+/// last option before it. Searching for the name instead would stop at an
+/// option value equal to it, like in `--log-filter bench bench`.
+fn subcommand_position(
+    args: &[OsString],
+    matches: &clap::ArgMatches,
+    command: &clap::Command,
+) -> usize {
+    let last = command
+        .get_arguments()
+        .filter_map(|arg| matches.indices_of(arg.get_id().as_str()))
+        .flatten()
+        .max()
+        .unwrap_or(0);
+    // Clap counts an option and its value as two indices even when they
+    // are written as one argument, like `--log-filter=info`.
+    let mut index = 0;
+    for (position, arg) in args.iter().enumerate().skip(1) {
+        if index >= last {
+            return position;
+        }
+        let joined = arg.to_str().is_some_and(|arg| arg.starts_with("--") && arg.contains('='));
+        index += if joined { 2 } else { 1 };
+    }
+    args.len()
+}
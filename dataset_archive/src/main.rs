@@ -0,0 +1,69 @@
+//! Pack target shards into a single compressed archive with checksums, and
+//! verify or unpack it on the other side.
+
+use std::{fs::File, io::BufWriter, path::PathBuf};
+
+use clap::Parser;
+use takzero::dataset::{DatasetError, DatasetReader, DatasetWriter};
+
+#[derive(Parser, Debug)]
+struct Args {
+    /// Archive to create, or to verify and unpack
+    archive: PathBuf,
+    /// Shards to pack into a new archive
+    #[arg(long, num_args = 1..)]
+    pack: Vec<PathBuf>,
+    /// Verify the archive and unpack its shards into this directory
+    #[arg(long, conflicts_with = "pack")]
+    unpack: Option<PathBuf>,
+}
+
+fn main() {
+    takzero::logging::init();
+    let args = Args::parse();
+    let result = if args.pack.is_empty() {
+        verify(&args)
+    } else {
+        pack(&args)
+    };
+    if let Err(err) = result {
+        log::error!("{err}");
+        std::process::exit(1);
+    }
+}
+
+fn pack(args: &Args) -> Result<(), DatasetError> {
+    let mut writer = DatasetWriter::create(&args.archive)?;
+    for path in &args.pack {
+        let entry = writer.add_file(path)?;
+        log::info!(
+            "packed {} ({} bytes, {} compressed)",
+            entry.name,
+            entry.bytes,
+            entry.compressed
+        );
+    }
+    let entries = writer.finish()?;
+    log::info!("wrote {} shards to {}", entries.len(), args.archive.display());
+    Ok(())
+}
+
+/// Check every shard, unpacking them if asked to.
+fn verify(args: &Args) -> Result<(), DatasetError> {
+    let reader = DatasetReader::open(&args.archive)?;
+    if let Some(directory) = &args.unpack {
+        std::fs::create_dir_all(directory)?;
+    }
+    for entry in reader.entries() {
+        match &args.unpack {
+            Some(directory) => {
+                let mut file = BufWriter::new(File::create(directory.join(&entry.name))?);
+                reader.extract(&entry.name, &mut file)?;
+            }
+            None => reader.extract(&entry.name, &mut std::io::sink())?,
+        }
+        log::info!("{} is intact ({} bytes)", entry.name, entry.bytes);
+    }
+    log::info!("all {} shards are intact", reader.entries().len());
+    Ok(())
+}
@@ -0,0 +1,89 @@
+//! Fill the UBE of targets with the disagreement of a trained ensemble, so
+//! that the ensemble and RND can be compared as uncertainty targets on the
+//! same data.
+
+use std::{io::Write, path::PathBuf};
+
+use clap::{Parser, ValueEnum};
+use takzero::{
+    header::{self, FileKind, Header},
+    network::{
+        net4_ensemble::{Net, HALF_KOMI, N},
+        repr::game_to_tensor,
+        Network,
+    },
+    target::{get_targets, Target},
+};
+use tch::{Device, Tensor};
+
+const BATCH_SIZE: usize = 1024;
+
+/// Where the uncertainty of the written targets comes from.
+#[derive(Clone, Copy, Debug, ValueEnum)]
+enum Uncertainty {
+    /// Keep the UBE of the input targets, which comes from RND.
+    Rnd,
+    /// Replace the UBE with the ensemble disagreement.
+    Ensemble,
+    /// Take the larger of the two, like the ensemble agent does in search.
+    Max,
+}
+
+#[derive(Parser, Debug)]
+struct Args {
+    /// Targets to relabel
+    input: PathBuf,
+    /// File to append the relabeled targets to
+    output: PathBuf,
+    /// Checkpoint of the ensemble network
+    #[arg(long)]
+    model: PathBuf,
+    #[arg(long, value_enum, default_value_t = Uncertainty::Ensemble)]
+    uncertainty: Uncertainty,
+}
+
+fn main() {
+    env_logger::init();
+    let args = Args::parse();
+    let device = Device::cuda_if_available();
+    let net = Net::load(&args.model, device).expect("the model should load");
+
+    let mut targets = get_targets::<N, HALF_KOMI>(&args.input).expect("the targets should open");
+    let header = Header::new::<N, HALF_KOMI>(FileKind::Targets, None);
+    let mut output = header::open_append(&args.output, &header).expect("the output should open");
+
+    let mut batch: Vec<Target<_>> = Vec::with_capacity(BATCH_SIZE);
+    let mut written = 0;
+    loop {
+        batch.extend(targets.by_ref().take(BATCH_SIZE));
+        if batch.is_empty() {
+            break;
+        }
+        if !matches!(args.uncertainty, Uncertainty::Rnd) {
+            let xs = Tensor::cat(
+                &batch
+                    .iter()
+                    .map(|target| game_to_tensor(&target.env, Device::Cpu))
+                    .collect::<Vec<_>>(),
+                0,
+            )
+            .to(device);
+            let disagreement: Vec<f32> = net
+                .ensemble_disagreement(&xs)
+                .view([-1])
+                .try_into()
+                .unwrap();
+            for (target, disagreement) in batch.iter_mut().zip(disagreement) {
+                target.ube = match args.uncertainty {
+                    Uncertainty::Max => target.ube.max(disagreement),
+                    Uncertainty::Rnd | Uncertainty::Ensemble => disagreement,
+                };
+            }
+        }
+        for target in batch.drain(..) {
+            write!(output, "{target}").expect("the output should be writable");
+            written += 1;
+        }
+    }
+    log::info!("wrote {written} targets, skipped {}", targets.skipped());
+}
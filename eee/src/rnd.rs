@@ -9,7 +9,7 @@ use rand::{
 use takzero::{
     network::{
         net4_rnd::{Env, MAXIMUM_VARIANCE, N},
-        repr::{game_to_tensor, input_channels},
+        repr::{game_to_tensor, input_channels, stack_size},
         residual::{ResidualBlock, SmallBlock},
     },
     search::env::Environment,
@@ -165,6 +165,10 @@ fn main() {
     let (random_late_batch, random_late_tensor) = reference_envs(60, &mut actions, &mut rng);
 
     let (_, impossible_early_tensor) = reference_envs(8, &mut actions, &mut rng);
+    // Shuffle the first planes of the player to move, the type of the top
+    // pieces and the stones right below them, so that pieces end up where
+    // they cannot be. The planes after them keep their place.
+    assert!(stack_size::<N>() >= 8, "the stacks should be encoded 5 pieces deep");
     let impossible_early_tensor = impossible_early_tensor.index_select(
         1,
         &Tensor::from_slice(
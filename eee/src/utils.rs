@@ -3,7 +3,7 @@ use rand::{seq::IteratorRandom, Rng};
 use takzero::{
     network::{
         net4_lcghash::{Env, N},
-        repr::{game_to_tensor, input_channels},
+        repr::{game_to_tensor, input_channels, stack_size},
     },
     search::env::Environment,
 };
@@ -76,6 +76,10 @@ pub fn reference_batches(
     let (random_late_batch, random_late_tensor) = reference_envs(60, &mut actions, rng, batch_size);
 
     let (_, impossible_early_tensor) = reference_envs(8, &mut actions, rng, batch_size);
+    // Shuffle the first planes of the player to move, the type of the top
+    // pieces and the stones right below them, so that pieces end up where
+    // they cannot be. The planes after them keep their place.
+    assert!(stack_size::<N>() >= 8, "the stacks should be encoded 5 pieces deep");
     let impossible_early_tensor = impossible_early_tensor.index_select(
         1,
         &Tensor::from_slice(
@@ -0,0 +1,220 @@
+//! Check the environment against a naive reference implementation of the
+//! rules over many random games: the legal moves, the outcome, and the TPS of
+//! every position, and that the TPS survives a round trip. A bug in any of
+//! these silently poisons the training data, so this is worth running after
+//! upgrading `fast-tak`.
+
+mod reference;
+
+use std::collections::BTreeSet;
+
+use clap::Parser;
+use fast_tak::{
+    takparse::{Move, Tps},
+    Game,
+    Reserves,
+};
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha8Rng;
+use reference::Board;
+use takzero::{
+    ptn::{ninja_url, to_ptn, Outcome},
+    search::env::{perft, Environment},
+    target::Replay,
+};
+
+/// Games are cut off after this many plies (random games rarely get there).
+const MAX_PLIES: usize = 2000;
+
+#[derive(Parser, Debug)]
+struct Args {
+    /// Number of random games to play
+    #[arg(long, default_value_t = 10_000)]
+    games: usize,
+    #[arg(long, default_value_t = 6)]
+    size: usize,
+    #[arg(long, default_value_t = 4)]
+    half_komi: i8,
+    #[arg(long, default_value_t = 0)]
+    seed: u64,
+    /// Number of mismatches to print in detail
+    #[arg(long, default_value_t = 5)]
+    show: usize,
+    /// Also count the positions up to this many plies from the start and
+    /// compare them with the reference counts
+    #[arg(long)]
+    perft: Option<usize>,
+}
+
+macro_rules! dispatch {
+    ($args:expr; $($size:literal),*) => {
+        match ($args.size, $args.half_komi) {
+            $(
+                ($size, 0) => check::<$size, 0>(&$args),
+                ($size, 4) => check::<$size, 4>(&$args),
+            )*
+            (size, half_komi) => {
+                log::error!("size {size} with half komi {half_komi} is not supported");
+                return;
+            }
+        }
+    };
+}
+
+#[derive(Debug)]
+struct Mismatch {
+    kind: &'static str,
+    detail: String,
+    /// Link to the game up to the position where the implementations disagree.
+    url: String,
+}
+
+#[derive(Debug, Default)]
+struct Report {
+    games: usize,
+    positions: usize,
+    /// Draws which the reference does not know about (for example by
+    /// repetition), so the rest of the game is not checked.
+    unchecked_draws: usize,
+    mismatches: Vec<Mismatch>,
+}
+
+fn main() {
+    takzero::logging::init();
+    let args = Args::parse();
+    let report = dispatch!(args; 3, 4, 5, 6, 7, 8);
+
+    println!(
+        "checked {} positions in {} games ({} ended in draws the reference does not model)",
+        report.positions, report.games, report.unchecked_draws
+    );
+    for kind in ["tps", "tps round trip", "outcome", "moves", "perft"] {
+        let count = report.mismatches.iter().filter(|m| m.kind == kind).count();
+        println!("{kind} mismatches: {count}");
+    }
+    for mismatch in report.mismatches.iter().take(args.show) {
+        println!("\n{}: {}\n{}", mismatch.kind, mismatch.detail, mismatch.url);
+    }
+    if !report.mismatches.is_empty() {
+        std::process::exit(1);
+    }
+}
+
+fn check<const N: usize, const HALF_KOMI: i8>(args: &Args) -> Report
+where
+    Reserves<N>: Default,
+{
+    let mut rng = ChaCha8Rng::seed_from_u64(args.seed);
+    let mut report = Report::default();
+    let mut actions = Vec::new();
+    for _ in 0..args.games {
+        let mut env = Game::<N, HALF_KOMI>::default();
+        let mut reference = Board::new(N, HALF_KOMI);
+        let mut replay = Replay::new(env.clone());
+        report.games += 1;
+
+        while replay.len() < MAX_PLIES {
+            report.positions += 1;
+            let mismatch = |kind, detail| Mismatch {
+                kind,
+                detail,
+                url: ninja_url(&to_ptn(&replay, &[], None)),
+            };
+
+            let tps = Tps::from(env.clone()).to_string();
+            if tps != reference.tps() {
+                let detail = format!("{tps} but the reference has {}", reference.tps());
+                report.mismatches.push(mismatch("tps", detail));
+                break;
+            }
+            let round_trip = tps
+                .parse::<Tps>()
+                .map(|parsed| Tps::from(Game::<N, HALF_KOMI>::from(parsed)).to_string());
+            if !matches!(&round_trip, Ok(round_trip) if *round_trip == tps) {
+                let detail = format!("{tps} became {round_trip:?}");
+                report.mismatches.push(mismatch("tps round trip", detail));
+                break;
+            }
+
+            let outcome = env
+                .terminal()
+                .map(|terminal| Outcome::from_terminal(terminal, env.to_move));
+            let expected = reference.outcome();
+            if outcome != expected {
+                if outcome == Some(Outcome::Draw) && expected.is_none() {
+                    report.unchecked_draws += 1;
+                } else {
+                    let detail = format!("{tps}: {outcome:?} but the reference has {expected:?}");
+                    report.mismatches.push(mismatch("outcome", detail));
+                }
+                break;
+            }
+            if outcome.is_some() {
+                break;
+            }
+
+            actions.clear();
+            env.populate_actions(&mut actions);
+            let reference_moves = reference.moves();
+            let parsed: Result<Vec<Move>, _> =
+                reference_moves.iter().map(|m| m.ptn().parse()).collect();
+            let Ok(parsed) = parsed else {
+                let detail = format!("{tps}: the reference generated a move which is not PTN");
+                report.mismatches.push(mismatch("moves", detail));
+                break;
+            };
+            let ours: BTreeSet<_> = actions.iter().map(ToString::to_string).collect();
+            let theirs: BTreeSet<_> = parsed.iter().map(ToString::to_string).collect();
+            if ours != theirs || actions.len() != parsed.len() {
+                let detail = format!(
+                    "{tps}: missing {:?}, extra {:?}, {} moves but the reference has {}",
+                    theirs.difference(&ours).collect::<Vec<_>>(),
+                    ours.difference(&theirs).collect::<Vec<_>>(),
+                    actions.len(),
+                    parsed.len()
+                );
+                report.mismatches.push(mismatch("moves", detail));
+                break;
+            }
+
+            let index = rng.gen_range(0..parsed.len());
+            let action = parsed[index];
+            env.step(action);
+            reference.play(&reference_moves[index]);
+            replay.push(action);
+        }
+    }
+
+    let counts = perft::reference_counts(N).unwrap_or_default();
+    for (depth, &expected) in counts.iter().enumerate().take(args.perft.map_or(0, |d| d + 1)) {
+        let count = perft::perft(&Game::<N, HALF_KOMI>::default(), depth);
+        if count != expected {
+            report.mismatches.push(Mismatch {
+                kind: "perft",
+                detail: format!("{count} positions after {depth} plies instead of {expected}"),
+                url: String::new(),
+            });
+        }
+    }
+    report
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{check, Args};
+
+    #[test]
+    fn agrees_with_reference() {
+        let args = Args {
+            games: 20,
+            size: 5,
+            half_komi: 4,
+            seed: 1,
+            show: 0,
+            perft: Some(3),
+        };
+        let report = check::<5, 4>(&args);
+        assert_eq!(report.games, 20);
+        assert!(report.mismatches.is_empty(), "{:?}", report.mismatches);
+    }
+}
@@ -0,0 +1,431 @@
+//! A deliberately naive implementation of the rules of Tak, written to be
+//! obviously correct rather than fast, which `fast-tak` is checked against.
+//! It shares nothing with `fast-tak` except the `Color` and `Piece` enums.
+
+use std::collections::VecDeque;
+
+use fast_tak::takparse::{Color, Piece};
+use takzero::ptn::Outcome;
+
+/// Symbol, column offset, and row offset of each direction.
+const DIRECTIONS: [(char, isize, isize); 4] =
+    [('+', 0, 1), ('-', 0, -1), ('>', 1, 0), ('<', -1, 0)];
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ReferenceMove {
+    Place {
+        column: usize,
+        row: usize,
+        piece: Piece,
+    },
+    Spread {
+        column: usize,
+        row: usize,
+        /// Index into [`DIRECTIONS`].
+        direction: usize,
+        drops: Vec<usize>,
+    },
+}
+
+fn square_name(column: usize, row: usize) -> String {
+    format!("{}{}", char::from(b'a' + column as u8), row + 1)
+}
+
+const fn opponent(color: Color) -> Color {
+    match color {
+        Color::White => Color::Black,
+        Color::Black => Color::White,
+    }
+}
+
+/// A run of empty squares in TPS.
+fn empty_squares(count: usize) -> String {
+    if count == 1 {
+        "x".to_string()
+    } else {
+        format!("x{count}")
+    }
+}
+
+const fn player(color: Color) -> usize {
+    match color {
+        Color::White => 0,
+        Color::Black => 1,
+    }
+}
+
+impl ReferenceMove {
+    /// The move in PTN, with the carry and drops written out.
+    #[must_use]
+    pub fn ptn(&self) -> String {
+        match self {
+            Self::Place { column, row, piece } => {
+                let prefix = match piece {
+                    Piece::Flat => "",
+                    Piece::Wall => "S",
+                    Piece::Cap => "C",
+                };
+                format!("{prefix}{}", square_name(*column, *row))
+            }
+            Self::Spread {
+                column,
+                row,
+                direction,
+                drops,
+            } => {
+                let carry: usize = drops.iter().sum();
+                let drops: String = drops.iter().map(ToString::to_string).collect();
+                format!(
+                    "{carry}{}{}{drops}",
+                    square_name(*column, *row),
+                    DIRECTIONS[*direction].0
+                )
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Board {
+    size: usize,
+    half_komi: i8,
+    /// Stacks from bottom to top, indexed by row (rank 1 first) and column.
+    stacks: Vec<Vec<Vec<(Piece, Color)>>>,
+    /// Stones and capstones left for white and black.
+    reserves: [(usize, usize); 2],
+    to_move: Color,
+    ply: usize,
+}
+
+impl Board {
+    /// The empty board with the standard number of pieces.
+    ///
+    /// # Panics
+    ///
+    /// Panics for sizes other than 3 to 8.
+    #[must_use]
+    pub fn new(size: usize, half_komi: i8) -> Self {
+        let reserves = match size {
+            3 => (10, 0),
+            4 => (15, 0),
+            5 => (21, 1),
+            6 => (30, 1),
+            7 => (40, 2),
+            8 => (50, 2),
+            _ => panic!("there are no standard reserves for size {size}"),
+        };
+        Self {
+            size,
+            half_komi,
+            stacks: vec![vec![Vec::new(); size]; size],
+            reserves: [reserves; 2],
+            to_move: Color::White,
+            ply: 0,
+        }
+    }
+
+    fn top(&self, column: usize, row: usize) -> Option<(Piece, Color)> {
+        self.stacks[row][column].last().copied()
+    }
+
+    /// The square `distance` steps away in a direction, if it is on the board.
+    fn step(
+        &self,
+        column: usize,
+        row: usize,
+        direction: usize,
+        distance: usize,
+    ) -> Option<(usize, usize)> {
+        let (_, dc, dr) = DIRECTIONS[direction];
+        let column = column.checked_add_signed(dc * distance as isize)?;
+        let row = row.checked_add_signed(dr * distance as isize)?;
+        (column < self.size && row < self.size).then_some((column, row))
+    }
+
+    /// All legal moves.
+    #[must_use]
+    pub fn moves(&self) -> Vec<ReferenceMove> {
+        let mut moves = Vec::new();
+        let (stones, caps) = self.reserves[player(self.to_move)];
+        for row in 0..self.size {
+            for column in 0..self.size {
+                if !self.stacks[row][column].is_empty() {
+                    continue;
+                }
+                let mut pieces = Vec::new();
+                if self.ply < 2 {
+                    // In the first turn, each player places a flat of the opponent.
+                    pieces.push(Piece::Flat);
+                } else {
+                    if stones > 0 {
+                        pieces.extend([Piece::Flat, Piece::Wall]);
+                    }
+                    if caps > 0 {
+                        pieces.push(Piece::Cap);
+                    }
+                }
+                for piece in pieces {
+                    moves.push(ReferenceMove::Place { column, row, piece });
+                }
+            }
+        }
+        if self.ply < 2 {
+            return moves;
+        }
+        for row in 0..self.size {
+            for column in 0..self.size {
+                let Some((piece, color)) = self.top(column, row) else {
+                    continue;
+                };
+                if color != self.to_move {
+                    continue;
+                }
+                let height = self.stacks[row][column].len();
+                for carry in 1..=height.min(self.size) {
+                    for direction in 0..DIRECTIONS.len() {
+                        self.spreads(
+                            (column, row, direction),
+                            carry,
+                            piece == Piece::Cap,
+                            &mut Vec::new(),
+                            &mut moves,
+                        );
+                    }
+                }
+            }
+        }
+        moves
+    }
+
+    /// Every way to drop the `remaining` pieces after the `drops` so far.
+    fn spreads(
+        &self,
+        (column, row, direction): (usize, usize, usize),
+        remaining: usize,
+        cap: bool,
+        drops: &mut Vec<usize>,
+        moves: &mut Vec<ReferenceMove>,
+    ) {
+        let Some((next_column, next_row)) = self.step(column, row, direction, drops.len() + 1)
+        else {
+            return;
+        };
+        let spread = |drops: &Vec<usize>| ReferenceMove::Spread {
+            column,
+            row,
+            direction,
+            drops: drops.clone(),
+        };
+        match self.top(next_column, next_row) {
+            Some((Piece::Cap, _)) => return,
+            Some((Piece::Wall, _)) => {
+                // Only a capstone on its own can flatten a wall.
+                if cap && remaining == 1 {
+                    drops.push(1);
+                    moves.push(spread(drops));
+                    drops.pop();
+                }
+                return;
+            }
+            _ => {}
+        }
+        for drop in 1..=remaining {
+            drops.push(drop);
+            if drop == remaining {
+                moves.push(spread(drops));
+            } else {
+                self.spreads((column, row, direction), remaining - drop, cap, drops, moves);
+            }
+            drops.pop();
+        }
+    }
+
+    /// Play a move, which must be legal.
+    pub fn play(&mut self, m: &ReferenceMove) {
+        match m {
+            ReferenceMove::Place { column, row, piece } => {
+                let color = if self.ply < 2 {
+                    opponent(self.to_move)
+                } else {
+                    self.to_move
+                };
+                let reserves = &mut self.reserves[player(color)];
+                if *piece == Piece::Cap {
+                    reserves.1 -= 1;
+                } else {
+                    reserves.0 -= 1;
+                }
+                self.stacks[*row][*column].push((*piece, color));
+            }
+            ReferenceMove::Spread {
+                column,
+                row,
+                direction,
+                drops,
+            } => {
+                let carry: usize = drops.iter().sum();
+                let stack = &mut self.stacks[*row][*column];
+                let mut carried: VecDeque<_> = stack.split_off(stack.len() - carry).into();
+                for (i, drop) in drops.iter().enumerate() {
+                    let (c, r) = self
+                        .step(*column, *row, *direction, i + 1)
+                        .expect("the spread should stay on the board");
+                    let target = &mut self.stacks[r][c];
+                    if let Some((piece @ Piece::Wall, _)) = target.last_mut() {
+                        *piece = Piece::Flat;
+                    }
+                    target.extend(carried.drain(..drop));
+                }
+            }
+        }
+        self.ply += 1;
+        self.to_move = opponent(self.to_move);
+    }
+
+    fn is_road(&self, column: usize, row: usize, color: Color) -> bool {
+        matches!(self.top(column, row), Some((Piece::Flat | Piece::Cap, c)) if c == color)
+    }
+
+    /// Whether the player has a road between opposite edges.
+    fn has_road(&self, color: Color) -> bool {
+        // Search from the left edge to the right, and from the bottom to the top.
+        [false, true].into_iter().any(|vertical| {
+            let at = |i: usize, j: usize| if vertical { (j, i) } else { (i, j) };
+            let mut seen = vec![vec![false; self.size]; self.size];
+            let mut queue: VecDeque<_> = (0..self.size)
+                .map(|j| at(0, j))
+                .filter(|&(column, row)| self.is_road(column, row, color))
+                .collect();
+            while let Some((column, row)) = queue.pop_front() {
+                if seen[row][column] {
+                    continue;
+                }
+                seen[row][column] = true;
+                let (i, _) = if vertical { (row, column) } else { (column, row) };
+                if i == self.size - 1 {
+                    return true;
+                }
+                for direction in 0..DIRECTIONS.len() {
+                    if let Some((c, r)) = self.step(column, row, direction, 1) {
+                        if self.is_road(c, r, color) {
+                            queue.push_back((c, r));
+                        }
+                    }
+                }
+            }
+            false
+        })
+    }
+
+    /// The outcome if the game is over.
+    #[must_use]
+    pub fn outcome(&self) -> Option<Outcome> {
+        let win = |color| match color {
+            Color::White => Outcome::WhiteWin,
+            Color::Black => Outcome::BlackWin,
+        };
+        // The player who made a road for both players wins.
+        let mover = opponent(self.to_move);
+        if self.has_road(mover) {
+            return Some(win(mover));
+        }
+        if self.has_road(self.to_move) {
+            return Some(win(self.to_move));
+        }
+
+        let full = self.stacks.iter().flatten().all(|stack| !stack.is_empty());
+        let out_of_pieces = self.reserves.contains(&(0, 0));
+        if !full && !out_of_pieces {
+            return None;
+        }
+        let mut flats = [0; 2];
+        for stack in self.stacks.iter().flatten() {
+            if let Some(&(Piece::Flat, color)) = stack.last() {
+                flats[player(color)] += 2;
+            }
+        }
+        let black = flats[1] + i32::from(self.half_komi);
+        Some(match flats[0].cmp(&black) {
+            std::cmp::Ordering::Greater => Outcome::WhiteWin,
+            std::cmp::Ordering::Less => Outcome::BlackWin,
+            std::cmp::Ordering::Equal => Outcome::Draw,
+        })
+    }
+
+    /// The position as TPS.
+    #[must_use]
+    pub fn tps(&self) -> String {
+        let rows: Vec<String> = self
+            .stacks
+            .iter()
+            .rev()
+            .map(|row| {
+                let mut squares = Vec::new();
+                let mut empty = 0;
+                for stack in row {
+                    if stack.is_empty() {
+                        empty += 1;
+                        continue;
+                    }
+                    if empty > 0 {
+                        squares.push(empty_squares(empty));
+                        empty = 0;
+                    }
+                    let mut square: String = stack
+                        .iter()
+                        .map(|(_, color)| if *color == Color::White { '1' } else { '2' })
+                        .collect();
+                    match stack.last() {
+                        Some((Piece::Wall, _)) => square.push('S'),
+                        Some((Piece::Cap, _)) => square.push('C'),
+                        _ => {}
+                    }
+                    squares.push(square);
+                }
+                if empty > 0 {
+                    squares.push(empty_squares(empty));
+                }
+                squares.join(",")
+            })
+            .collect();
+        let color = if self.to_move == Color::White { 1 } else { 2 };
+        format!("{} {color} {}", rows.join("/"), self.ply / 2 + 1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use takzero::ptn::Outcome;
+
+    use super::Board;
+
+    fn play(board: &mut Board, ptn: &str) {
+        let m = board
+            .moves()
+            .into_iter()
+            .find(|m| m.ptn() == ptn)
+            .unwrap_or_else(|| panic!("{ptn} should be legal"));
+        board.play(&m);
+    }
+
+    #[test]
+    fn rules() {
+        let mut board = Board::new(3, 0);
+        assert_eq!(board.moves().len(), 9);
+        for ptn in ["c3", "a1", "b1", "b3"] {
+            play(&mut board, ptn);
+        }
+        assert_eq!(board.tps(), "x,2,2/x3/1,1,x 1 3");
+        assert_eq!(board.outcome(), None);
+        // Flats and walls on the 5 empty squares, and 5 ways to move a flat.
+        assert_eq!(board.moves().len(), 15);
+        play(&mut board, "1b1+1");
+        assert_eq!(board.tps(), "x,2,2/x,1,x/1,x2 2 3");
+        play(&mut board, "Sc2");
+        // Only a capstone can move onto a wall.
+        assert!(board.moves().iter().all(|m| m.ptn() != "1b2>1"));
+        play(&mut board, "c1");
+        play(&mut board, "a3");
+        assert_eq!(board.outcome(), Some(Outcome::BlackWin));
+    }
+}
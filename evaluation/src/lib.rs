@@ -0,0 +1,573 @@
+#![warn(clippy::pedantic, clippy::style)]
+
+use std::{
+    array,
+    fs::{read_dir, OpenOptions},
+    io::{BufRead, BufReader},
+    path::PathBuf,
+    time::{Duration, Instant},
+};
+
+use clap::Parser;
+use fast_tak::takparse::Tps;
+use rand::{prelude::*, rngs::StdRng, seq::SliceRandom, Rng, SeedableRng};
+use takzero::{
+    curriculum::Curriculum,
+    metrics::{self, REGISTRY},
+    network::{
+        repr::{self, InputRepr},
+        Network,
+    },
+    ptn::{ninja_url, to_ptn},
+    search::{
+        agent::{symmetric::Symmetric, Agent},
+        env::{Environment, Terminal},
+        node::{batched::BatchedMCTS, Node},
+        DISCOUNT_FACTOR,
+    },
+    time_manager::{TimeControl, TimeManager},
+    winrate::elo_from_score,
+};
+use tch::Device;
+// The board size is 4, or 6 for the last stage of a board-size curriculum.
+#[cfg(not(feature = "board6"))]
+use takzero::network::net4_simhash::{Env, Net, N};
+#[cfg(feature = "board6")]
+use takzero::network::net6_simhash::{Env, Net, N};
+
+const DEVICE: Device = Device::Cuda(0);
+
+const BATCH_SIZE: usize = 64;
+const MAX_MOVES: usize = 200;
+const SAMPLED_ACTIONS: usize = 64;
+const SEARCH_BUDGET: u32 = 768;
+
+// Curriculum, over the board sizes which self-play can be built for.
+const CURRICULUM_BOARD_SIZES: [usize; 2] = [4, 6];
+const CURRICULUM_WINDOW: usize = 5;
+const CURRICULUM_MIN_GAIN: f64 = 20.0;
+const CURRICULUM_MIX_OBSERVATIONS: usize = 10;
+
+#[derive(Parser, Debug)]
+pub struct Args {
+    /// Path to models
+    #[arg(long)]
+    model_path: PathBuf,
+    /// How many models to skip when creating match-ups
+    #[arg(long, default_value_t = 1)]
+    step: usize,
+    /// Path to starting positions
+    #[arg(long)]
+    opening_book: Option<PathBuf>,
+    /// Only evaluate the newest checkpoint against the previous one and
+    /// report the Elo gain to the board-size curriculum in this file, while
+    /// the curriculum is on the board size of this evaluation.
+    #[arg(long)]
+    curriculum: Option<PathBuf>,
+    /// Address to serve Prometheus metrics on, for example `0.0.0.0:9100`.
+    #[arg(long)]
+    metrics_address: Option<String>,
+    /// Play with a clock instead of a fixed search budget,
+    /// for example `60+0.5` (in seconds).
+    #[arg(long)]
+    time_control: Option<TimeControl>,
+    /// Average the predictions of both networks over the 8 symmetries of
+    /// every position, which takes 8 times the network evaluations.
+    #[arg(long)]
+    symmetric: bool,
+    /// Encoding of positions as network inputs, like `stack-depth=6`, which
+    /// has to be the one the models were trained with.
+    #[arg(long, default_value_t)]
+    input_repr: InputRepr,
+}
+
+// #[allow(unused)]
+// fn compare_mid_big(
+//     path_1: impl AsRef<std::path::Path>,
+//     path_2: impl AsRef<std::path::Path>,
+//     games: &[Env],
+//     rng: &mut impl Rng,
+// ) -> Evaluation {
+//     let big_1 = path_1
+//         .as_ref()
+//         .file_name()
+//         .unwrap()
+//         .to_string_lossy()
+//         .split_once('_')
+//         .is_some_and(|(f, _)| f == "big");
+//     let big_2 = path_2
+//         .as_ref()
+//         .file_name()
+//         .unwrap()
+//         .to_string_lossy()
+//         .split_once('_')
+//         .is_some_and(|(f, _)| f == "big");
+
+//     match (big_1, big_2) {
+//         (true, true) => {
+//             let a = net4_big::Net::load(path_1, DEVICE).unwrap();
+//             let b = net4_big::Net::load(path_2, DEVICE).unwrap();
+//             compete(&a, &b, games, rng)
+//         }
+//         (true, false) => {
+//             let a = net4_big::Net::load(path_1, DEVICE).unwrap();
+//             let b = net4_rnd::Net::load(path_2, DEVICE).unwrap();
+//             compete(&a, &b, games, rng)
+//         }
+//         (false, true) => {
+//             let a = net4_rnd::Net::load(path_1, DEVICE).unwrap();
+//             let b = net4_big::Net::load(path_2, DEVICE).unwrap();
+//             compete(&a, &b, games, rng)
+//         }
+//         (false, false) => {
+//             let a = net4_rnd::Net::load(path_1, DEVICE).unwrap();
+//             let b = net4_rnd::Net::load(path_2, DEVICE).unwrap();
+//             compete(&a, &b, games, rng)
+//         }
+//     }
+// }
+
+// fn negative_beta_range_test() {
+//     const BETA: [f32; 7] = [0.25, 0.0, -0.01, -0.1, -0.25, -0.5, -1.0];
+//     log::info!("negative beta experiments with BETA = {BETA:?}");
+
+//     let args = Args::parse();
+//     let seed: u64 = thread_rng().gen();
+//     log::info!("seed: {seed}");
+//     let mut rng = StdRng::seed_from_u64(seed);
+
+//     let net = Net::load(args.model_path, DEVICE).expect("model path should be
+// valid");
+
+//     loop {
+//         let mut betas = BETA.choose_multiple(&mut rng, 2);
+//         let beta_1 = *betas.next().expect("Exactly two betas should be
+// chosen");         let beta_2 = *betas.next().expect("Exactly two betas should
+// be chosen");
+
+//         let mut actions = Vec::new();
+//         let games: [Env; BATCH_SIZE] = array::from_fn(|_| {
+//             let steps = rng.gen_range(2..=3);
+//             Env::new_opening_with_random_steps(&mut rng, &mut actions, steps)
+//         });
+
+//         let a_as_white = compete(&net, &net, beta_1, beta_2, &games, &mut
+// rng);         log::info!(
+//             "{beta_1} vs. {beta_2}: {a_as_white:?} {:.1}%",
+//             a_as_white.win_rate() * 100.0
+//         );
+//         let b_as_white = compete(&net, &net, beta_2, beta_1, &games, &mut
+// rng);         log::info!(
+//             "{beta_2} vs. {beta_1}: {b_as_white:?} {:.1}%",
+//             b_as_white.win_rate() * 100.0
+//         );
+//     }
+// }
+
+/// Run the evaluation with the given arguments. The caller initializes logging.
+///
+/// # Panics
+///
+/// Panics if the models or the opening book cannot be loaded.
+pub fn run(args: Args) {
+    log::info!("Begin.");
+    repr::configure(args.input_repr).expect("The input encoding should only be set once");
+    tch::no_grad(|| real_main(args));
+}
+
+#[allow(unused)]
+fn real_main(args: Args) {
+    let seed: u64 = thread_rng().gen();
+    log::info!("seed: {seed}");
+    let mut rng = StdRng::seed_from_u64(seed);
+
+    if let Some(address) = &args.metrics_address {
+        metrics::serve(address).expect("Metrics address should be valid");
+    }
+
+    let opening_book: Option<Vec<Env>> = args.opening_book.map(|path| {
+        let mut file = OpenOptions::new()
+            .read(true)
+            .open(path)
+            .expect("Path to opening book should be valid");
+        BufReader::new(file)
+            .lines()
+            .map(|line| {
+                line.expect("Line should be fine to read")
+                    .parse()
+                    .map(|tps: Tps| tps.into())
+            })
+            .collect::<Result<_, _>>()
+            .expect("Opening book should be valid TPS, one per line")
+    });
+    let mut last_observed = None;
+
+    loop {
+        if let Some(path) = &args.curriculum {
+            let board_size = load_curriculum(path).current_board_size();
+            if board_size != N {
+                let time = std::time::Duration::from_secs(600);
+                log::info!("Curriculum is on board size {board_size}. Sleeping for {time:?}.");
+                std::thread::sleep(time);
+                continue;
+            }
+        }
+
+        let mut paths: Vec<_> = read_dir(&args.model_path)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .filter(|path| {
+                path.extension().is_some_and(|ext| ext == "ot")
+                    && path.file_stem().is_some_and(|stem| stem != "model_latest")
+            })
+            .collect();
+        paths.sort();
+        let paths: Vec<_> = paths.into_iter().step_by(args.step).collect();
+        if paths.len() < 2 {
+            let time = std::time::Duration::from_secs(600);
+            log::info!("Too few models. Sleeping for {time:?}.");
+            std::thread::sleep(time);
+            continue;
+        }
+
+        let (path_a, path_b) = if args.curriculum.is_some() {
+            // Newest checkpoint against the one before it.
+            let [.., previous, newest] = paths.as_slice() else {
+                unreachable!("there are at least two paths");
+            };
+            if last_observed.as_ref() == Some(newest) {
+                let time = std::time::Duration::from_secs(600);
+                log::info!("No new checkpoint for the curriculum. Sleeping for {time:?}.");
+                std::thread::sleep(time);
+                continue;
+            }
+            (newest, previous)
+        } else {
+            let mut match_up = paths.choose_multiple(&mut rng, 2);
+            (match_up.next().unwrap(), match_up.next().unwrap())
+        };
+
+        let Ok(a) = Net::load_partial(path_a, DEVICE) else {
+            log::warn!("Cannot load {}", path_a.display());
+            continue;
+        };
+        let Ok(b) = Net::load_partial(path_b, DEVICE) else {
+            log::warn!("Cannot load {}", path_b.display());
+            continue;
+        };
+        let name_a = path_a.file_name().unwrap().to_string_lossy().to_string();
+        let name_b = path_b.file_name().unwrap().to_string_lossy().to_string();
+
+        let games: [Env; BATCH_SIZE] = if let Some(openings) = &opening_book {
+            openings
+                .choose_multiple(&mut rng, BATCH_SIZE)
+                .cloned()
+                .collect::<Vec<_>>()
+                .try_into()
+                .expect("There should be enough games in the opening book to form a unique batch")
+        } else {
+            let mut actions = Vec::new();
+
+            array::from_fn(|_| {
+                let steps = rng.gen_range(2..=3);
+                Env::new_opening_with_random_steps(&mut rng, &mut actions, steps)
+            })
+        };
+
+        let play = |white: &Net, black: &Net, rng: &mut StdRng| {
+            if args.symmetric {
+                let (white, black) = (Symmetric(white), Symmetric(black));
+                compete(&white, &black, 0.0, 0.0, &games, args.time_control, rng)
+            } else {
+                compete(white, black, 0.0, 0.0, &games, args.time_control, rng)
+            }
+        };
+        let a_as_white = play(&a, &b, &mut rng);
+        // let a_as_white = compare_mid_big(path_a, path_b, &games, &mut rng);
+        log::info!(
+            "{name_a} vs. {name_b}: {a_as_white:?} {:.1}%",
+            a_as_white.win_rate() * 100.0
+        );
+        let b_as_white = play(&b, &a, &mut rng);
+        // let b_as_white = compare_mid_big(path_b, path_a, &games, &mut rng);
+        log::info!(
+            "{name_b} vs. {name_a}: {b_as_white:?} {:.1}%",
+            b_as_white.win_rate() * 100.0
+        );
+
+        for evaluation in [&a_as_white, &b_as_white] {
+            REGISTRY.set_gauge(
+                "takzero_evaluation_white_win_rate",
+                "Win rate of white in the latest evaluation match.",
+                evaluation.win_rate(),
+            );
+            REGISTRY.inc_counter(
+                "takzero_evaluation_white_wins_total",
+                "Evaluation games won by white.",
+                f64::from(evaluation.wins),
+            );
+            REGISTRY.inc_counter(
+                "takzero_evaluation_white_losses_total",
+                "Evaluation games lost by white.",
+                f64::from(evaluation.losses),
+            );
+            REGISTRY.inc_counter(
+                "takzero_evaluation_draws_total",
+                "Evaluation games drawn.",
+                f64::from(evaluation.draws),
+            );
+        }
+
+        if let Some(path) = &args.curriculum {
+            let games = a_as_white.games() + b_as_white.games();
+            let score = f64::from(
+                2 * (a_as_white.wins + b_as_white.losses) + a_as_white.draws + b_as_white.draws,
+            ) / f64::from(2 * games);
+            update_curriculum(path, elo_from_score(score));
+            last_observed = Some(path_a.clone());
+        }
+    }
+}
+
+/// Load the curriculum state, or start a new one if it does not exist yet.
+fn load_curriculum(path: &std::path::Path) -> Curriculum {
+    Curriculum::load(path).unwrap_or_else(|err| {
+        log::warn!("Could not load curriculum ({err}), starting a new one.");
+        Curriculum::new(
+            CURRICULUM_BOARD_SIZES.to_vec(),
+            CURRICULUM_WINDOW,
+            CURRICULUM_MIN_GAIN,
+            CURRICULUM_MIX_OBSERVATIONS,
+        )
+    })
+}
+
+/// Add the Elo gain of the newest checkpoint on this board size to the
+/// curriculum state, unless it moved on to another board size meanwhile.
+fn update_curriculum(path: &std::path::Path, elo_gain: f64) {
+    let mut curriculum = load_curriculum(path);
+    if curriculum.current_board_size() != N {
+        log::info!("Curriculum moved on to board size {}.", curriculum.current_board_size());
+        return;
+    }
+    let elo = curriculum.latest_elo() + elo_gain;
+    if curriculum.observe(elo) {
+        log::info!(
+            "Curriculum advanced to board size {}.",
+            curriculum.current_board_size()
+        );
+    }
+    log::info!("Curriculum Elo: {elo:.1} (gain {elo_gain:+.1})");
+    if let Err(err) = curriculum.save(path) {
+        log::error!("Could not save curriculum: {err}");
+    }
+}
+/// Pit two networks against each other in the given games. Evaluation is from
+/// the perspective of white.
+#[allow(dead_code)]
+fn compete<W, B>(
+    white: &W,
+    black: &B,
+    white_beta: f32,
+    black_beta: f32,
+    games: &[Env],
+    time_control: Option<TimeControl>,
+    rng: &mut impl Rng,
+) -> Evaluation
+where
+    W: Agent<Env>,
+    B: Agent<Env>,
+{
+    let mut evaluation = Evaluation::default();
+
+    let mut white_mcts = BatchedMCTS::from_envs(games.to_owned().try_into().unwrap());
+    let mut black_mcts = BatchedMCTS::from_envs(games.to_owned().try_into().unwrap());
+    let white_beta = [white_beta; BATCH_SIZE];
+    let black_beta = [black_beta; BATCH_SIZE];
+
+    let mut done = [false; BATCH_SIZE];
+
+    let time_manager = TimeManager::default();
+    let mut clocks = time_control.map(|time_control| [Clock::new(time_control); 2]);
+
+    'outer: for _ in 0..MAX_MOVES {
+        for is_white in [true, false] {
+            // Check if all games are done.
+            if done.iter().all(|x| *x) {
+                break 'outer;
+            }
+
+            // Perform search as the current agent.
+            let (current, other) = if is_white {
+                (&mut white_mcts, &mut black_mcts)
+            } else {
+                (&mut black_mcts, &mut white_mcts)
+            };
+            let clock = clocks
+                .as_mut()
+                .map(|clocks| &mut clocks[usize::from(!is_white)]);
+            let search_budget = clock
+                .as_ref()
+                .map_or(SEARCH_BUDGET, |clock| clock.search_budget(&time_manager));
+            let start = Instant::now();
+            let top_actions: [_; BATCH_SIZE] = if is_white {
+                current.gumbel_sequential_halving(
+                    white,
+                    &white_beta,
+                    DISCOUNT_FACTOR,
+                    SAMPLED_ACTIONS,
+                    search_budget,
+                    rng,
+                )
+            } else {
+                current.gumbel_sequential_halving(
+                    black,
+                    &black_beta,
+                    DISCOUNT_FACTOR,
+                    SAMPLED_ACTIONS,
+                    search_budget,
+                    rng,
+                )
+            };
+            if let Some(clock) = clock {
+                if !clock.charge(start.elapsed(), search_budget) {
+                    // All unfinished games are lost on time.
+                    let unfinished = done.iter().filter(|done| !**done).count() as u32;
+                    log::info!("{unfinished} games lost on time (white: {is_white})");
+                    if is_white {
+                        evaluation.losses += unfinished;
+                    } else {
+                        evaluation.wins += unfinished;
+                    }
+                    break 'outer;
+                }
+            }
+
+            // Pick the top actions and take a step.
+            current.step(&top_actions);
+            other.step(&top_actions);
+
+            // Collect terminals and replays.
+            let (terminals, replays): (Vec<_>, Vec<_>) = current
+                .restart_terminal_envs(&mut thread_rng())
+                .zip(&mut done)
+                .filter_map(|(x, done)| if *done { None } else { Some((x?, done)) })
+                .map(|(t, done)| {
+                    *done = true;
+                    t
+                })
+                .unzip();
+            // Also reset other's nodes and envs.
+            other
+                .nodes_and_envs_mut()
+                .zip(current.nodes_and_envs())
+                .zip(&done)
+                .filter(|(_, done)| **done)
+                .for_each(|(((node, other_env), (_, current_env)), _)| {
+                    *node = Node::default();
+                    *other_env = current_env.clone();
+                });
+
+            for replay in replays {
+                log::debug!(
+                    "{} {}",
+                    replay.to_string().trim_end(),
+                    ninja_url(&to_ptn(&replay, &[], None))
+                );
+            }
+
+            // Update evaluation results.
+            for terminal in terminals {
+                // This may seem opposite of what is should be.
+                // That is because we are looking at the terminal after a move was made, so a
+                // loss for the "current player" is actually a win for the one who just played
+                match (terminal, is_white) {
+                    (Terminal::Loss, true) | (Terminal::Win, false) => evaluation.wins += 1,
+                    (Terminal::Win, true) | (Terminal::Loss, false) => evaluation.losses += 1,
+                    (Terminal::Draw, _) => evaluation.draws += 1,
+                }
+            }
+        }
+    }
+    evaluation
+}
+
+/// Clock of one side in a timed match. All games in a batch are played in
+/// lockstep, so they share a clock.
+#[derive(Debug, Clone, Copy)]
+struct Clock {
+    remaining: Duration,
+    increment: Duration,
+    /// Measured speed, used to turn time into a search budget.
+    simulations_per_second: Option<f64>,
+}
+
+impl Clock {
+    const fn new(time_control: TimeControl) -> Self {
+        Self {
+            remaining: time_control.initial,
+            increment: time_control.increment,
+            simulations_per_second: None,
+        }
+    }
+
+    /// Search budget for the next move. The first move uses the smallest
+    /// possible budget to measure the speed.
+    #[allow(clippy::cast_sign_loss)]
+    fn search_budget(&self, time_manager: &TimeManager) -> u32 {
+        let unit = SAMPLED_ACTIONS.ilog2() * SAMPLED_ACTIONS as u32;
+        let Some(simulations_per_second) = self.simulations_per_second else {
+            return unit;
+        };
+        let time = time_manager
+            .start::<()>(self.remaining, self.increment, false)
+            .budget();
+        let simulations = (simulations_per_second * time.as_secs_f64()) as u32;
+        (simulations / unit).max(1) * unit
+    }
+
+    /// Charge the time spent on a move. Returns `false` if the flag fell.
+    fn charge(&mut self, elapsed: Duration, search_budget: u32) -> bool {
+        self.simulations_per_second =
+            Some(f64::from(search_budget) / elapsed.as_secs_f64().max(f64::EPSILON));
+        let flagged = elapsed > self.remaining;
+        self.remaining = self.remaining.saturating_sub(elapsed) + self.increment;
+        !flagged
+    }
+}
+
+use std::{iter::Sum, ops::AddAssign};
+
+#[derive(Debug, Default)]
+pub struct Evaluation {
+    pub wins: u32,
+    pub losses: u32,
+    pub draws: u32,
+}
+
+impl AddAssign for Evaluation {
+    fn add_assign(&mut self, rhs: Self) {
+        self.wins += rhs.wins;
+        self.losses += rhs.losses;
+        self.draws += rhs.draws;
+    }
+}
+
+impl Sum for Evaluation {
+    fn sum<I: Iterator<Item = Self>>(iter: I) -> Self {
+        iter.fold(Self::default(), |mut a, b| {
+            a += b;
+            a
+        })
+    }
+}
+
+impl Evaluation {
+    const fn games(&self) -> u32 {
+        self.wins + self.losses + self.draws
+    }
+
+    fn win_rate(&self) -> f64 {
+        f64::from(self.wins) / f64::from(self.games())
+    }
+}
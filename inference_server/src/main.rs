@@ -0,0 +1,183 @@
+use std::{net::SocketAddr, path::PathBuf};
+
+use clap::Parser;
+use fast_tak::takparse::Tps;
+use proto::{
+    inference_server::{Inference, InferenceServer},
+    ActionProbability,
+    EvaluateRequest,
+    EvaluateResponse,
+    Evaluation,
+};
+use takzero::{
+    network::{
+        checkpoint,
+        net6_simhash::{Env, Net, N},
+        Network,
+    },
+    search::{agent::Agent, env::Environment, eval::Eval, node::policy::softmax},
+};
+use tch::Device;
+use tokio::sync::{mpsc, oneshot};
+use tonic::{transport::Server, Request, Response, Status};
+
+mod proto {
+    tonic::include_proto!("takzero.inference");
+}
+
+const DEVICE: Device = Device::Cuda(0);
+const QUEUE_SIZE: usize = 1024;
+
+#[derive(Parser, Debug)]
+struct Args {
+    /// Path to the model
+    #[arg(long)]
+    model_path: PathBuf,
+    /// Address to serve on
+    #[arg(long, default_value = "0.0.0.0:50051")]
+    address: SocketAddr,
+    /// Maximum number of positions in one forward pass
+    #[arg(long, default_value_t = 256)]
+    max_batch_size: usize,
+}
+
+/// Positions from one request and where to send their evaluations.
+struct Job {
+    envs: Vec<Env>,
+    respond: oneshot::Sender<Vec<Evaluation>>,
+}
+
+struct Service {
+    jobs: mpsc::Sender<Job>,
+}
+
+#[tonic::async_trait]
+impl Inference for Service {
+    async fn evaluate(
+        &self,
+        request: Request<EvaluateRequest>,
+    ) -> Result<Response<EvaluateResponse>, Status> {
+        let envs = request
+            .into_inner()
+            .tps
+            .iter()
+            .map(|tps| parse_env(tps).map_err(Status::invalid_argument))
+            .collect::<Result<_, _>>()?;
+        let (respond, response) = oneshot::channel();
+        self.jobs
+            .send(Job { envs, respond })
+            .await
+            .map_err(|_| Status::unavailable("the inference worker has stopped"))?;
+        let evaluations = response
+            .await
+            .map_err(|_| Status::internal("the inference worker dropped the request"))?;
+        Ok(Response::new(EvaluateResponse { evaluations }))
+    }
+}
+
+fn parse_env(tps: &str) -> Result<Env, String> {
+    let tps: Tps = tps
+        .parse()
+        .map_err(|err| format!("invalid TPS `{tps}`: {err}"))?;
+    if tps.size() != N {
+        return Err(format!("only size {N} is supported"));
+    }
+    Ok(tps.into())
+}
+
+/// Evaluate positions with the network. Finished games get the value of
+/// their result and an empty policy.
+fn evaluate(net: &Net, envs: &[&Env]) -> Vec<Evaluation> {
+    let (ongoing, actions): (Vec<_>, Vec<_>) = envs
+        .iter()
+        .filter(|env| env.terminal().is_none())
+        .map(|env| {
+            let mut actions = Vec::new();
+            env.populate_actions(&mut actions);
+            ((*env).clone(), actions)
+        })
+        .unzip();
+    let mut outputs = if ongoing.is_empty() {
+        Vec::new()
+    } else {
+        net.policy_value_uncertainty(&ongoing, &actions).collect()
+    }
+    .into_iter();
+
+    envs.iter()
+        .map(|env| {
+            if let Some(terminal) = env.terminal() {
+                return Evaluation {
+                    policy: Vec::new(),
+                    value: f32::from(Eval::from(terminal)),
+                    uncertainty: 0.0,
+                };
+            }
+            let (policy, value, uncertainty) = outputs
+                .next()
+                .expect("there should be an output for every ongoing game");
+            let probabilities = softmax(policy.iter().map(|(_, logit)| *logit));
+            Evaluation {
+                policy: policy
+                    .iter()
+                    .zip(probabilities)
+                    .map(|((action, _), probability)| ActionProbability {
+                        action: action.to_string(),
+                        probability: probability.into_inner(),
+                    })
+                    .collect(),
+                value,
+                uncertainty,
+            }
+        })
+        .collect()
+}
+
+/// Evaluate jobs on the GPU. Jobs which arrive while the network is busy are
+/// batched together, so concurrent clients share forward passes.
+fn run_worker(net: &Net, mut jobs: mpsc::Receiver<Job>, max_batch_size: usize) {
+    let mut batch = Vec::new();
+    while let Some(job) = jobs.blocking_recv() {
+        let mut positions = job.envs.len();
+        batch.push(job);
+        while positions < max_batch_size {
+            let Ok(job) = jobs.try_recv() else {
+                break;
+            };
+            positions += job.envs.len();
+            batch.push(job);
+        }
+
+        let envs: Vec<_> = batch.iter().flat_map(|job| &job.envs).collect();
+        let mut evaluations = envs
+            .chunks(max_batch_size)
+            .flat_map(|chunk| evaluate(net, chunk))
+            .collect::<Vec<_>>()
+            .into_iter();
+        log::debug!("evaluated {} positions from {} requests", envs.len(), batch.len());
+        for job in batch.drain(..) {
+            let evaluations = evaluations.by_ref().take(job.envs.len()).collect();
+            // The client might have gone away in the meantime.
+            job.respond.send(evaluations).ok();
+        }
+    }
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    takzero::logging::init();
+    let args = Args::parse();
+    checkpoint::configure_repr(&args.model_path)?;
+    let net = Net::load_partial(&args.model_path, DEVICE)?;
+
+    let (jobs, receiver) = mpsc::channel(QUEUE_SIZE);
+    let max_batch_size = args.max_batch_size;
+    std::thread::spawn(move || tch::no_grad(|| run_worker(&net, receiver, max_batch_size)));
+
+    log::info!("serving on {}", args.address);
+    Server::builder()
+        .add_service(InferenceServer::new(Service { jobs }))
+        .serve(args.address)
+        .await?;
+    Ok(())
+}
@@ -0,0 +1,1219 @@
+use std::{
+    cmp::Reverse,
+    collections::{HashMap, HashSet},
+    fmt,
+    fs::{File, OpenOptions},
+    io::{BufRead, BufReader, Read, Seek, Write},
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+
+use clap::Parser;
+use fast_tak::takparse::Tps;
+use ordered_float::NotNan;
+use rand::prelude::*;
+use rand_chacha::ChaCha12Rng;
+use takzero::{
+    affinity::CoreSet,
+    audit::{read_manifest, Audit, ReadManifestError, Record},
+    control::{self, Command, CONTROL},
+    curriculum::Curriculum,
+    dashboard::{self, DASHBOARD},
+    logging,
+    metrics::{self, REGISTRY},
+    network::{
+        amp::{MixedPrecision, Precision},
+        checkpoint::{self, CheckpointError},
+        repr::{
+            self,
+            game_to_input,
+            input_channels,
+            move_mask,
+            output_size,
+            policy_tensor,
+            InputRepr,
+        },
+        CheckpointNetwork,
+        HashNetwork,
+        Network,
+    },
+    phase::{PhaseSampler, PhaseSplit, Proportions},
+    search::{agent::Agent, env::Environment, eval::Eval},
+    seen::{target_key, SeenFilter},
+    shards::Manifest,
+    storage::{self, ObjectStore},
+    target::{get_targets, Augment, Replay, Target},
+};
+use tch::{
+    nn::{Adam, Optimizer, OptimizerConfig},
+    Device,
+    Kind,
+    TchError,
+    Tensor,
+};
+use thiserror::Error;
+// The board size is 6, or 4 for the first stage of a board-size curriculum.
+#[cfg(feature = "board4")]
+use takzero::network::net4_simhash::{Env, Net, HALF_KOMI, MAXIMUM_VARIANCE, N};
+#[cfg(not(feature = "board4"))]
+use takzero::network::net6_simhash::{Env, Net, HALF_KOMI, MAXIMUM_VARIANCE, N};
+
+// use crate::rnd_normalization::{reference_games, update_rnd};
+// mod rnd_normalization;
+
+// The environment to learn.
+#[rustfmt::skip] #[allow(dead_code)]
+const fn assert_env<E: Environment>() where Target<E>: Augment + fmt::Display {}
+const _: () = assert_env::<Env>();
+
+// The network architecture.
+#[rustfmt::skip] #[allow(dead_code)] const fn assert_net<NET: Network + Agent<Env>>() {}
+const _: () = assert_net::<Net>();
+
+const DEVICE: Device = Device::Cuda(0);
+const BATCH_SIZE: usize = 128;
+const STEPS_PER_SAVE: usize = 100;
+const STEPS_PER_CHECKPOINT: usize = 50_000;
+const LEARNING_RATE: f64 = 1e-4;
+
+// Pre-training
+const INITIAL_RANDOM_TARGETS: usize = BATCH_SIZE * 2_000;
+const PRE_TRAINING_STEPS: usize = 1_000;
+const _: () = assert!(INITIAL_RANDOM_TARGETS >= PRE_TRAINING_STEPS * BATCH_SIZE);
+
+// Restarting
+/// Targets which are shuffled together when resuming from restart targets,
+/// so that the file does not have to fit into memory.
+const RESTART_SHUFFLE_WINDOW: usize = 1 << 20;
+
+// Buffers
+const STEPS_BEFORE_REANALYZE: usize = 5000;
+const MIN_SELFPLAY_BUFFER_LEN: usize = 10_000;
+const _: () = assert!(MIN_SELFPLAY_BUFFER_LEN >= BATCH_SIZE);
+const MIN_REANALYZE_BUFFER_LEN: usize = 2_000;
+const _: () = assert!(MIN_REANALYZE_BUFFER_LEN >= BATCH_SIZE);
+const SELFPLAY_TARGET_FORCED_USES: u32 = 4;
+const REANALYZE_TARGET_FORCED_USES: u32 = 4;
+const MIN_TIME_BETWEEN_BUFFER_READS: Duration = Duration::from_secs(10);
+/// Size of the filter which counts uses of the same position and policy.
+const SEEN_FILTER_COUNTERS: usize = 1 << 24;
+const SEEN_FILTER_HASHES: u32 = 4;
+const SLEEP_WHEN_NOT_ENOUGH_TARGETS: Duration = Duration::from_secs(30);
+
+// Control
+/// How often to check for commands while paused.
+const PAUSED_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+// Dashboard
+/// Bytes read from the end of the replay file to find the recent games.
+const RECENT_REPLAYS_BYTES: u64 = 1 << 20;
+
+// Target
+const MINIMUM_UBE_TARGET: f64 = -10.0;
+
+#[derive(Parser, Debug)]
+pub struct Args {
+    /// Directory where to find targets
+    /// and also where to save models.
+    #[arg(long)]
+    directory: PathBuf,
+    /// Targets to use for resuming after restart.
+    #[arg(long)]
+    restart_targets: Option<PathBuf>,
+    /// Address to serve Prometheus metrics on, for example `0.0.0.0:9100`.
+    #[arg(long)]
+    metrics_address: Option<String>,
+    /// Address to serve the training dashboard on, for example `0.0.0.0:8000`.
+    #[arg(long)]
+    dashboard_address: Option<String>,
+    /// Address to serve the control endpoint on, for example
+    /// `127.0.0.1:8002`, to pause the run, save the model, or change the
+    /// learning rate and self-play temperature while it runs.
+    #[arg(long)]
+    control_address: Option<String>,
+    /// Curriculum state written by `evaluation --curriculum`, whose Elo
+    /// history the dashboard shows.
+    #[arg(long)]
+    curriculum: Option<PathBuf>,
+    /// Object store to pull target shards from and push models to,
+    /// for example `s3://bucket/run`. Without it, the directory is shared.
+    #[arg(long)]
+    storage: Option<String>,
+    /// Train on the same position with the same policy at most this many
+    /// times. Uses are counted across restarts in `seen_targets.bin`.
+    #[arg(long)]
+    max_target_uses: Option<u8>,
+    /// Draw openings, middlegames, and endgames into batches in these
+    /// proportions, for example `0.3,0.4,0.3`, instead of uniformly.
+    #[arg(long)]
+    phase_proportions: Option<Proportions>,
+    /// Where the middlegame and endgame start for `--phase-proportions`,
+    /// by ply (`ply:10,40`) or by the fraction of stones left in reserve
+    /// (`reserves:0.8,0.3`).
+    #[arg(long, default_value = "ply:10,40")]
+    phase_split: PhaseSplit,
+    /// Manifest in which to record the seed, and the targets and loss of
+    /// every training step, so that steps can be reproduced with
+    /// `--reproduce-step`. The weights before every step are also kept
+    /// numbered, which takes one model of disk space per step, and CUDA
+    /// only uses deterministic kernels.
+    #[arg(long)]
+    audit: Option<PathBuf>,
+    /// Execute this audited training step again from the weights kept in
+    /// `audit/` right before it and compare the loss, instead of training.
+    #[arg(long, requires = "audit")]
+    reproduce_step: Option<usize>,
+    /// Cores to load targets and train on, like `32-63`, or a NUMA node,
+    /// like `node:1`. The threads of `LibTorch` stay on them too.
+    #[arg(long)]
+    cores: Option<CoreSet>,
+    /// Precision of the forward and backward pass: `fp32`, or `fp16` or
+    /// `bf16` with the weights kept in `fp32`. Audited runs only train in
+    /// `fp32`, which reproduced steps use too.
+    #[arg(long, default_value = "fp32")]
+    precision: Precision,
+    /// Recompute the activations of the residual blocks in the backward
+    /// pass instead of keeping them, in this many segments, so that larger
+    /// networks fit into GPU memory at the cost of another forward pass of
+    /// the blocks, or keep them with 0. Defaults to the setting of the
+    /// architecture. `bench --checkpoint-segments` measures the trade-off.
+    #[arg(long)]
+    checkpoint_segments: Option<usize>,
+    /// Encoding of positions as network inputs, like `stack-depth=6`, which
+    /// the workers and the trainer of a run have to agree on. Checkpoints
+    /// record it and only load into runs with the same encoding.
+    #[arg(long, default_value_t)]
+    input_repr: InputRepr,
+}
+
+struct TargetWithContext {
+    /// The target.
+    target: Target<Env>,
+    /// How many uses are available until you cannot use this target.
+    forced_uses: u32,
+    /// The model steps at the time of loading this target.
+    model_steps: usize,
+}
+
+impl TargetWithContext {
+    fn reuse(mut self) -> Option<Self> {
+        if self.forced_uses > 1 {
+            self.forced_uses -= 1;
+            Some(self)
+        } else {
+            None
+        }
+    }
+}
+
+/// Limit on how often the same position with the same policy is trained on,
+/// also when it is generated again later.
+struct SeenTargets {
+    filter: SeenFilter,
+    limit: u8,
+}
+
+impl SeenTargets {
+    /// Count a use of the target and return whether it can be used again.
+    fn record(&mut self, target: &Target<Env>) -> bool {
+        self.filter.insert(target_key(target)) < self.limit
+    }
+
+    /// Drop the targets from `start` on which reached the limit already.
+    fn drop_exhausted(&self, buffer: &mut Vec<TargetWithContext>, start: usize) {
+        let fresh = buffer.split_off(start);
+        buffer.extend(
+            fresh
+                .into_iter()
+                .filter(|t| self.filter.count(target_key(&t.target)) < self.limit),
+        );
+    }
+}
+
+/// Run training with the given arguments. The caller initializes logging.
+///
+/// # Panics
+///
+/// Panics if the directory, the models, or the targets cannot be used, or if
+/// an audited run is to train in mixed precision.
+#[allow(clippy::too_many_lines)]
+pub fn run(args: Args) {
+    assert!(
+        args.audit.is_none() || args.precision == Precision::Fp32,
+        "Audited runs should train in fp32, since reproduced steps recompute in fp32"
+    );
+    logging::set_worker("learn");
+    repr::configure(args.input_repr).expect("The input encoding should only be set once");
+    if let Some(cores) = &args.cores {
+        cores
+            .install_global("learn")
+            .expect("Training threads should only be started once");
+    }
+    if args.audit.is_some() {
+        use_deterministic_kernels();
+    }
+    if let (Some(step), Some(manifest)) = (args.reproduce_step, &args.audit) {
+        if let Err(err) = reproduce_step(manifest, &args.directory, step) {
+            log::error!("Could not reproduce step {step}: {err}");
+        }
+        return;
+    }
+    let store = args
+        .storage
+        .as_deref()
+        .map(|url| storage::open(url).expect("Storage URL should be valid"));
+    let mut pulled_shards = HashSet::new();
+
+    let seed: u64 = rand::thread_rng().gen();
+    log::info!("seed = {seed}");
+    let mut rng = rand::rngs::StdRng::seed_from_u64(seed);
+
+    if let Some(address) = &args.metrics_address {
+        metrics::serve(address).expect("Metrics address should be valid");
+    }
+    if let Some(address) = &args.dashboard_address {
+        dashboard::serve(address).expect("Dashboard address should be valid");
+    }
+    if let Some(address) = &args.control_address {
+        control::serve(address).expect("Control address should be valid");
+    }
+
+    let latest = checkpoint::latest_numbered(&args.directory).expect("Could not read directory");
+    let (mut net, mut starting_steps) = if let Some((resume_steps, path)) = latest {
+        log::info!("Resuming with model at {}", path.display());
+        (
+            checkpoint::load(&path, DEVICE).expect("Could not load network model"),
+            resume_steps,
+        )
+    } else {
+        // Initialize a network.
+        log::info!("Initializing a network model");
+        let net = Net::new(DEVICE, Some(rng.gen()));
+        checkpoint::save(&net, &args.directory.join("model_0000000.ot")).unwrap();
+        (net, 0)
+    };
+
+    let mut amp = MixedPrecision::new(&net, args.precision);
+    let segments = args
+        .checkpoint_segments
+        .map_or(Net::CHECKPOINT_SEGMENTS, |segments| (segments > 0).then_some(segments));
+    let mut opt = Adam::default().build(net.vs_mut(), LEARNING_RATE).unwrap();
+    // The optimizer state is not saved, so a resumed run cannot be replayed.
+    let mut audit = args.audit.as_ref().map(|path| {
+        std::fs::create_dir_all(audit_weights_path(&args.directory, 0).parent().unwrap())
+            .expect("Audit weights directory should be writable");
+        Audit::open(path, "learn", seed, starting_steps > 0)
+            .expect("Audit manifest should be writable")
+    });
+    // Load RND reference games.
+    // let (early_reference, late_reference) = reference_games(DEVICE, &mut rng);
+
+    if let Some(target_file) = &args.restart_targets {
+        // Resuming after restarting.
+        let mut targets = get_targets::<N, HALF_KOMI>(target_file).unwrap().lossy();
+        let mut window = Vec::with_capacity(RESTART_SHUFFLE_WINDOW);
+        loop {
+            window.clear();
+            window.extend(targets.by_ref().take(RESTART_SHUFFLE_WINDOW));
+            if window.is_empty() {
+                break;
+            }
+            window.shuffle(&mut rng);
+            for batch in window.chunks_exact(BATCH_SIZE) {
+                DASHBOARD.set_step(starting_steps);
+                let tensors = create_input_and_target_tensors(batch.iter(), &mut rng);
+                compute_loss_and_take_step(
+                    &mut net,
+                    &mut opt,
+                    amp.as_mut(),
+                    segments,
+                    tensors,
+                    // &early_reference,
+                    // &late_reference,
+                    false,
+                );
+                starting_steps += 1;
+            }
+        }
+        if targets.skipped() > 0 {
+            log::warn!("Skipped {} restart targets which did not parse.", targets.skipped());
+            for err in targets.errors().iter().take(10) {
+                log::warn!("{err}");
+            }
+        }
+        checkpoint::save(
+            &net,
+            &args.directory.join(format!("model_{starting_steps:0>7}.ot")),
+        )
+        .unwrap();
+    } else if starting_steps == 0 {
+        // Pre-training.
+        pre_training(
+            &mut net,
+            &mut opt,
+            amp.as_mut(),
+            segments,
+            &mut rng,
+            &args.directory,
+            // &early_reference,
+            // &late_reference,
+        );
+        starting_steps += PRE_TRAINING_STEPS;
+        checkpoint::save(
+            &net,
+            &args.directory.join(format!("model_{starting_steps:0>7}.ot")),
+        )
+        .unwrap();
+    }
+
+    save_latest_model(&net, &args.directory, starting_steps, store.as_deref());
+
+    // Initialize buffers.
+    let mut exploitation_buffer: Vec<TargetWithContext> =
+        Vec::with_capacity(2 * MIN_SELFPLAY_BUFFER_LEN);
+    let mut exploitation_targets_seek = 0;
+    let mut reanalyze_buffer: Vec<TargetWithContext> = Vec::new();
+    let mut reanalyze_targets_seek = 0;
+    let seen_path = args.directory.join("seen_targets.bin");
+    let mut seen = args.max_target_uses.map(|limit| SeenTargets {
+        filter: SeenFilter::load(&seen_path).unwrap_or_else(|err| {
+            log::info!("Starting a new seen filter: {err}");
+            SeenFilter::new(SEEN_FILTER_COUNTERS, SEEN_FILTER_HASHES)
+        }),
+        limit,
+    });
+    // One sampler for each buffer, which keeps the phases of its targets.
+    let mut samplers = args.phase_proportions.map(|proportions| {
+        std::array::from_fn::<_, 2, _>(|_| PhaseSampler::new(args.phase_split, proportions))
+    });
+
+    // Main training loop.
+    let mut last_loaded = Instant::now();
+    for model_steps in (starting_steps + 1).. {
+        logging::set_generation(model_steps);
+        DASHBOARD.set_step(model_steps);
+        let save_now = apply_commands(
+            &mut opt,
+            &args.directory,
+            store.as_deref(),
+            audit.as_mut(),
+            model_steps,
+            || {
+                // Nothing was trained in this step yet.
+                log::info!("Saving model while paused.");
+                save_latest_model(&net, &args.directory, model_steps - 1, store.as_deref());
+            },
+        );
+        let using_reanalyze =
+            args.restart_targets.is_some() || model_steps >= STEPS_BEFORE_REANALYZE;
+
+        // Make sure there are enough targets before sampling a batch.
+        loop {
+            if last_loaded.elapsed() >= MIN_TIME_BETWEEN_BUFFER_READS {
+                if let Some(store) = &store {
+                    pull_target_shards(store.as_ref(), &args.directory, &mut pulled_shards);
+                }
+                let lengths = (exploitation_buffer.len(), reanalyze_buffer.len());
+                fill_buffers(
+                    &mut exploitation_buffer,
+                    &mut exploitation_targets_seek,
+                    &mut reanalyze_buffer,
+                    &mut reanalyze_targets_seek,
+                    &args.directory,
+                    model_steps,
+                    using_reanalyze,
+                );
+                if let Some(seen) = &seen {
+                    seen.drop_exhausted(&mut exploitation_buffer, lengths.0);
+                    seen.drop_exhausted(&mut reanalyze_buffer, lengths.1);
+                }
+                last_loaded = Instant::now();
+                REGISTRY.set_gauge(
+                    "takzero_exploitation_buffer_size",
+                    "Targets in the exploitation (selfplay) buffer.",
+                    exploitation_buffer.len() as f64,
+                );
+                REGISTRY.set_gauge(
+                    "takzero_reanalyze_buffer_size",
+                    "Targets in the reanalyze buffer.",
+                    reanalyze_buffer.len() as f64,
+                );
+                if args.dashboard_address.is_some() {
+                    update_dashboard(
+                        &args.directory,
+                        args.curriculum.as_deref(),
+                        exploitation_buffer.len(),
+                        reanalyze_buffer.len(),
+                    );
+                }
+                // Write buffer sizes to file for synchronization.
+                if let Ok(mut file) = OpenOptions::new()
+                    .write(true)
+                    .truncate(true)
+                    .create(true)
+                    .open(args.directory.join("buffer_lengths.txt"))
+                {
+                    if let Err(err) = file.write_fmt(format_args!(
+                        "{},{},{}",
+                        exploitation_buffer.len(),
+                        reanalyze_buffer.len(),
+                        exploitation_buffer.len() + reanalyze_buffer.len(),
+                    )) {
+                        log::error!("Writing buffer sizes to file: {err}");
+                    }
+                }
+                if let Some(store) = &store {
+                    push_file(store.as_ref(), &args.directory, "buffer_lengths.txt");
+                }
+            }
+
+            // Create a batch and take a step if there are enough targets.
+            let enough_exploitation_targets = exploitation_buffer.len() >= MIN_SELFPLAY_BUFFER_LEN;
+            let enough_reanalyze_targets =
+                !using_reanalyze || reanalyze_buffer.len() >= MIN_REANALYZE_BUFFER_LEN;
+            if enough_exploitation_targets && enough_reanalyze_targets {
+                break;
+            }
+
+            log::info!(
+                "Not enough targets, waiting {SLEEP_WHEN_NOT_ENOUGH_TARGETS:?}. \
+                 exploitation_buffer={} reanalyze_buffer={}",
+                exploitation_buffer.len(),
+                reanalyze_buffer.len()
+            );
+            std::thread::sleep(SLEEP_WHEN_NOT_ENOUGH_TARGETS);
+        }
+
+        if audit.is_some() {
+            // The weights before the step, from which it can be reproduced.
+            let path = audit_weights_path(&args.directory, model_steps);
+            if let Err(err) = checkpoint::save(&net, &path) {
+                log::error!("Could not keep the weights before step {model_steps}: {err}");
+            }
+        }
+        let (tensors, keys) = create_batch(
+            using_reanalyze,
+            &mut exploitation_buffer,
+            &mut reanalyze_buffer,
+            seen.as_mut(),
+            samplers.as_mut(),
+            &mut rng,
+            &mut augmentation_rng(seed, model_steps),
+        );
+        let loss = compute_loss_and_take_step(
+            &mut net,
+            &mut opt,
+            amp.as_mut(),
+            segments,
+            tensors,
+            // &early_reference,
+            // &late_reference,
+            true,
+        );
+        if let Some(audit) = &mut audit {
+            let record = Record::Batch {
+                step: model_steps,
+                loss: loss.unwrap_or(f64::NAN),
+                keys,
+            };
+            if let Err(err) = audit.record(&record) {
+                log::error!("Could not write to the audit manifest: {err}");
+            }
+        }
+        REGISTRY.set_gauge(
+            "takzero_training_steps",
+            "Training steps taken by the model.",
+            model_steps as f64,
+        );
+
+        // Save latest model.
+        if model_steps % STEPS_PER_SAVE == 0 || save_now {
+            log::info!(
+                "Saving model. exploitation_buffer={} reanalyze_buffer={}",
+                exploitation_buffer.len(),
+                reanalyze_buffer.len()
+            );
+            save_latest_model(&net, &args.directory, model_steps, store.as_deref());
+            if let Some(seen) = &seen {
+                if let Err(err) = seen.filter.save(&seen_path) {
+                    log::error!("Could not save the seen filter: {err}");
+                }
+            }
+        }
+
+        // Save checkpoint.
+        if model_steps % STEPS_PER_CHECKPOINT == 0 {
+            let name = format!("model_{model_steps:0>7}.ot");
+            checkpoint::save(&net, &args.directory.join(&name)).unwrap();
+            if let Some(store) = &store {
+                push_file(store.as_ref(), &args.directory, &name);
+                push_file(store.as_ref(), &args.directory, &format!("{name}.repr"));
+            }
+            // I don't know if this helps or hurts or does nothing.
+            opt.zero_grad();
+        }
+    }
+}
+
+/// Apply the commands from the control endpoint before the step, and wait
+/// while training is paused. A model which should be saved while paused is
+/// saved with `save` right away. Returns whether the model should be saved
+/// after this step.
+fn apply_commands(
+    opt: &mut Optimizer,
+    directory: &Path,
+    store: Option<&dyn ObjectStore>,
+    mut audit: Option<&mut Audit>,
+    step: usize,
+    mut save: impl FnMut(),
+) -> bool {
+    let mut save_now = false;
+    loop {
+        for command in CONTROL.take_commands() {
+            match command {
+                Command::Pause => log::info!("Training paused."),
+                Command::Resume => log::info!("Training resumed."),
+                Command::Save => save_now = true,
+                Command::LearningRate(learning_rate) => {
+                    log::info!("Learning rate set to {learning_rate}.");
+                    opt.set_lr(learning_rate);
+                }
+                Command::Temperature(temperature) => {
+                    set_selfplay_temperature(directory, temperature, store);
+                    if let Some(audit) = audit.as_deref_mut() {
+                        let record = Record::Temperature { step, temperature };
+                        if let Err(err) = audit.record(&record) {
+                            log::error!("Could not write to the audit manifest: {err}");
+                        }
+                    }
+                }
+                // The log filter is replaced by the endpoint itself.
+                Command::LogFilter(_) => {}
+            }
+        }
+        if !CONTROL.settings().paused {
+            return save_now;
+        }
+        if std::mem::take(&mut save_now) {
+            save();
+        }
+        std::thread::sleep(PAUSED_POLL_INTERVAL);
+    }
+}
+
+/// Write the temperature for the opening moves of self-play to
+/// `selfplay_temperature.txt`, which workers read when they load a model.
+fn set_selfplay_temperature(
+    directory: &Path,
+    temperature: Option<f32>,
+    store: Option<&dyn ObjectStore>,
+) {
+    let content = temperature.map_or_else(|| "default".to_string(), |t| t.to_string());
+    if let Err(err) = std::fs::write(directory.join("selfplay_temperature.txt"), &content) {
+        log::error!("Could not write the self-play temperature: {err}");
+        return;
+    }
+    log::info!("Self-play temperature set to {content}.");
+    if let Some(store) = store {
+        push_file(store, directory, "selfplay_temperature.txt");
+    }
+}
+
+/// Record buffer sizes, and read the Elo history from the curriculum and
+/// the most recent selfplay games from the directory for the dashboard.
+fn update_dashboard(
+    directory: &Path,
+    curriculum: Option<&Path>,
+    exploitation_buffer_len: usize,
+    reanalyze_buffer_len: usize,
+) {
+    DASHBOARD.record_buffer("exploitation", exploitation_buffer_len);
+    DASHBOARD.record_buffer("reanalyze", reanalyze_buffer_len);
+    if let Some(Ok(curriculum)) = curriculum.map(Curriculum::load) {
+        DASHBOARD.set_elo(curriculum.history());
+    }
+    let replays = Manifest::load(directory, "replays").and_then(|manifest| {
+        manifest
+            .paths(directory, "replays")
+            .last()
+            .map_or_else(|| Ok(Vec::new()), |path| recent_replays(&path))
+    });
+    match replays {
+        Ok(replays) => DASHBOARD.set_games(replays.iter().map(dashboard_game).collect()),
+        Err(err) => log::debug!("Could not read recent replays: {err}"),
+    }
+}
+
+/// Replays from the end of a replay shard.
+fn recent_replays(path: &Path) -> std::io::Result<Vec<Replay<Env>>> {
+    let mut file = OpenOptions::new().read(true).open(path)?;
+    let start = file.metadata()?.len().saturating_sub(RECENT_REPLAYS_BYTES);
+    file.seek(std::io::SeekFrom::Start(start))?;
+    let mut content = Vec::new();
+    file.read_to_end(&mut content)?;
+    let content = String::from_utf8_lossy(&content);
+    // The first line is only partially read when starting in the middle.
+    let skip = usize::from(start > 0);
+    Ok(content
+        .lines()
+        .skip(skip)
+        .filter_map(|line| line.parse().ok())
+        .collect())
+}
+
+fn dashboard_game(replay: &Replay<Env>) -> dashboard::Game {
+    let mut env = replay.env.clone();
+    let mut positions = vec![Tps::from(env.clone()).to_string()];
+    for &action in &replay.actions {
+        env.step(action);
+        positions.push(Tps::from(env.clone()).to_string());
+    }
+    dashboard::Game {
+        moves: replay.actions.iter().map(ToString::to_string).collect(),
+        positions,
+    }
+}
+
+/// Save the model as `model_latest.ot` and record its number of training
+/// steps in `model_latest_steps.txt`, so that workers can tag their logs with
+/// the generation they are using.
+///
+/// The model is only published if it passes the checks of
+/// [`checkpoint::publish`], otherwise workers keep the previous one.
+fn save_latest_model(
+    net: &Net,
+    directory: &Path,
+    model_steps: usize,
+    store: Option<&dyn ObjectStore>,
+) {
+    let path = directory.join("model_latest.ot");
+    if let Err(err) = checkpoint::publish::<Env, Net>(net, &path) {
+        checkpoint::report_failure(&path, &err);
+        return;
+    }
+    if let Err(err) = std::fs::write(
+        directory.join("model_latest_steps.txt"),
+        model_steps.to_string(),
+    ) {
+        log::error!("Could not write model steps to file: {err}");
+    }
+    if let Some(store) = store {
+        // The previous model goes first and the steps go last, so workers
+        // only pull models which were pushed completely.
+        for name in storage::PREVIOUS_MODEL_FILES {
+            if directory.join(name).exists() {
+                push_file(store, directory, name);
+            }
+        }
+        for name in storage::MODEL_FILES {
+            if let Err(err) = store.put_file(name, &directory.join(name)) {
+                log::error!("Could not push {name}: {err}");
+                break;
+            }
+        }
+    }
+}
+
+/// Upload a file from the directory to the object store.
+fn push_file(store: &dyn ObjectStore, directory: &Path, name: &str) {
+    if let Err(err) = store.put_file(name, &directory.join(name)) {
+        log::error!("Could not push {name}: {err}");
+    }
+}
+
+/// Append target shards which have not been seen yet to the local target
+/// files, so that they are read like targets written to a shared directory.
+fn pull_target_shards(store: &dyn ObjectStore, directory: &Path, pulled: &mut HashSet<String>) {
+    for prefix in ["targets-selfplay", "targets-reanalyze"] {
+        let path = directory.join(format!("{prefix}.txt"));
+        let result = storage::pull_new_shards(store, prefix, pulled, |data| {
+            OpenOptions::new()
+                .append(true)
+                .create(true)
+                .open(&path)?
+                .write_all(data)
+        });
+        if let Err(err) = result {
+            log::error!("Could not list {prefix} shards: {err}");
+        }
+    }
+}
+
+/// Add targets to the buffer from the given file, skipping the targets that
+/// have already been read.
+fn fill_buffer_with_targets(
+    buffer: &mut Vec<TargetWithContext>,
+    seek: &mut u64,
+    file_path: &Path,
+    forced_uses: u32,
+    model_steps: usize,
+) -> std::io::Result<()> {
+    let mut reader = BufReader::new(OpenOptions::new().read(true).open(file_path)?);
+    reader
+        .seek(std::io::SeekFrom::Start(*seek))
+        .expect("Target file should not get shorter.");
+    buffer.extend(
+        reader
+            .by_ref()
+            .lines()
+            .filter_map(|line| line.unwrap().parse().ok())
+            .map(|target| TargetWithContext {
+                target,
+                forced_uses,
+                model_steps,
+            }),
+    );
+    *seek = reader
+        .stream_position()
+        .expect("Target file should not get shorter.");
+    Ok(())
+}
+
+struct Tensors {
+    input: Tensor,
+    mask: Tensor,
+    target_value: Tensor,
+    target_policy: Tensor,
+    target_ube: Tensor,
+    /// Sample weight of each target in the loss.
+    weight: Tensor,
+}
+
+fn create_input_and_target_tensors<'a>(
+    batch: impl Iterator<Item = &'a Target<Env>>,
+    rng: &mut impl Rng,
+) -> Tensors {
+    // Create input tensors.
+    let repr = repr::input_repr();
+    let mut inputs = Vec::with_capacity(BATCH_SIZE * repr.channels::<N>() * N * N);
+    let mut policy_targets = Vec::with_capacity(BATCH_SIZE);
+    let mut masks = Vec::with_capacity(BATCH_SIZE);
+    let mut value_targets = Vec::with_capacity(BATCH_SIZE);
+    let mut ube_targets = Vec::with_capacity(BATCH_SIZE);
+    let mut weights = Vec::with_capacity(BATCH_SIZE);
+    for target in batch {
+        let target = target.augment(rng);
+        let mut input = game_to_input(&target.env);
+        repr.encode_move_limit::<N>(&mut input, target.move_limit.unwrap_or_default());
+        inputs.extend(input);
+        policy_targets.push(policy_tensor::<N>(&target.policy, DEVICE));
+        masks.push(move_mask::<N>(
+            &target.policy.iter().map(|(m, _)| *m).collect::<Vec<_>>(),
+            DEVICE,
+        ));
+        value_targets.push(target.value);
+        ube_targets.push(target.ube);
+        weights.push(target.weight.unwrap_or(1.0));
+    }
+
+    // Get network output.
+    let input = Tensor::from_slice(&inputs)
+        .view([-1, input_channels::<N>() as i64, N as i64, N as i64])
+        .to(DEVICE);
+    let mask = Tensor::cat(&masks, 0).to(DEVICE);
+    // Get the target.
+    let target_policy = Tensor::stack(&policy_targets, 0)
+        .view([BATCH_SIZE as i64, output_size::<N>() as i64])
+        .to(DEVICE);
+    let target_value = Tensor::from_slice(&value_targets).unsqueeze(1).to(DEVICE);
+    let target_ube = Tensor::from_slice(&ube_targets)
+        .unsqueeze(1)
+        .to(DEVICE)
+        .log()
+        .clamp(MINIMUM_UBE_TARGET, MAXIMUM_VARIANCE.ln());
+    let weight = Tensor::from_slice(&weights).unsqueeze(1).to(DEVICE);
+
+    Tensors {
+        input,
+        mask,
+        target_value,
+        target_policy,
+        target_ube,
+        weight,
+    }
+}
+
+/// Returns the total loss before the step.
+fn compute_loss_and_take_step(
+    net: &mut Net,
+    opt: &mut Optimizer,
+    amp: Option<&mut MixedPrecision<Net>>,
+    segments: Option<usize>,
+    tensors: Tensors,
+    // early_reference: &Tensor,
+    // late_reference: &Tensor,
+    train_ube: bool,
+) -> Option<f64> {
+    // Get network output.
+    let network = amp.as_ref().map_or(&*net, |amp| amp.network());
+    let input = amp
+        .as_ref()
+        .map_or_else(|| tensors.input.shallow_clone(), |amp| amp.input(&tensors.input));
+    let ((policy, network_value, network_ube), checkpoints) = match segments {
+        Some(segments) => {
+            let (output, checkpoints) = network.forward_checkpointed(&input, segments);
+            (output, Some(checkpoints))
+        }
+        None => (network.forward_t(&input, true), None),
+    };
+    // The loss is computed in single precision.
+    let (policy, network_value, network_ube) = (
+        policy.to_kind(Kind::Float),
+        network_value.to_kind(Kind::Float),
+        network_ube.to_kind(Kind::Float),
+    );
+    let log_softmax_network_policy = policy
+        .masked_fill(&tensors.mask, f64::from(f32::MIN))
+        .view([-1, output_size::<N>() as i64])
+        .log_softmax(1, Kind::Float);
+
+    // Calculate loss, weighing each target by its sample weight.
+    let loss_policy = -((log_softmax_network_policy * &tensors.target_policy)
+        .sum_dim_intlist(1, true, None)
+        * &tensors.weight)
+        .sum(Kind::Float)
+        / i64::try_from(BATCH_SIZE).unwrap();
+    let loss_value = ((tensors.target_value - network_value).square() * &tensors.weight)
+        .mean(Kind::Float);
+    let loss_ube = if train_ube {
+        ((tensors.target_ube - network_ube).square() * &tensors.weight).mean(Kind::Float)
+    } else {
+        // We don't want to train UBE in pre-training.
+        Tensor::zeros_like(&loss_value)
+    };
+    // let loss_rnd = net.forward_rnd(&tensors.input, true).mean(Kind::Float);
+    let loss = &loss_policy + &loss_value + &loss_ube; // + &loss_rnd;
+    #[rustfmt::skip]
+    log::info!(
+        "loss = {loss:?}\n\
+         loss_policy = {loss_policy:?}\n\
+         loss_value = {loss_value:?}\n\
+         loss_ube = {loss_ube:?}"
+    );
+    // loss_rnd = {loss_rnd:?}"
+    for (name, help, short_name, tensor) in [
+        ("takzero_loss", "Total training loss.", "total", &loss),
+        ("takzero_loss_policy", "Policy training loss.", "policy", &loss_policy),
+        ("takzero_loss_value", "Value training loss.", "value", &loss_value),
+        ("takzero_loss_ube", "UBE training loss.", "ube", &loss_ube),
+    ] {
+        if let Ok(value) = f64::try_from(tensor) {
+            REGISTRY.set_gauge(name, help, value);
+            DASHBOARD.record_loss(short_name, value);
+        }
+    }
+
+    // Update network RND min and max for normalization.
+    // update_rnd(net, early_reference, late_reference);
+
+    // Update hash counts
+    net.update_counts(&tensors.input);
+
+    // Take step.
+    match amp {
+        Some(amp) => {
+            amp.backward_step_with(opt, &loss, |copy, loss| {
+                loss.backward();
+                if let Some(checkpoints) = checkpoints {
+                    copy.backward_checkpointed(checkpoints);
+                }
+            });
+            REGISTRY.set_gauge(
+                "takzero_loss_scale",
+                "Scale of the loss in mixed precision training.",
+                amp.scaler().scale(),
+            );
+        }
+        None => {
+            opt.zero_grad();
+            loss.backward();
+            if let Some(checkpoints) = checkpoints {
+                net.backward_checkpointed(checkpoints);
+            }
+            opt.step();
+        }
+    }
+    f64::try_from(&loss).ok()
+}
+
+fn pre_training(
+    net: &mut Net,
+    opt: &mut Optimizer,
+    mut amp: Option<&mut MixedPrecision<Net>>,
+    segments: Option<usize>,
+    rng: &mut impl Rng,
+    directory: &Path,
+    // early_reference: &Tensor,
+    // late_reference: &Tensor,
+) {
+    log::info!("Pre-training");
+    let mut actions = Vec::new();
+    let mut states = Vec::new();
+    let mut buffer = Vec::with_capacity(INITIAL_RANDOM_TARGETS);
+    while buffer.len() < INITIAL_RANDOM_TARGETS {
+        let mut game = Env::new_opening(rng, &mut actions);
+        // Play game until the end.
+        while game.terminal().is_none() {
+            states.push(game.clone());
+            game.populate_actions(&mut actions);
+            let action = actions.drain(..).choose(rng).unwrap();
+            game.step(action);
+        }
+        // Create targets from the random game.
+        let mut value = Eval::from(game.terminal().unwrap());
+        for env in states.drain(..).rev() {
+            env.populate_actions(&mut actions);
+            // Uniform policy.
+            let p = NotNan::new(1.0 / actions.len() as f32)
+                .expect("there should always be at least one action");
+            let policy = actions.drain(..).map(|a| (a, p)).collect();
+            // Value is the discounted end of the game.
+            value = value.negate();
+            buffer.push(Target {
+                env,
+                policy,
+                value: f32::from(value),
+                ube: MAXIMUM_VARIANCE as f32 - f32::EPSILON,
+                weight: None,
+                move_limit: None,
+                model_steps: None,
+            });
+        }
+    }
+    buffer.shuffle(rng);
+    // Save initial targets for inspection.
+    let content: String = buffer.iter().map(ToString::to_string).collect();
+    OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(directory.join("targets-initial.txt"))
+        .unwrap()
+        .write_all(content.as_bytes())
+        .unwrap();
+
+    for (step, batch) in buffer
+        .chunks_exact(BATCH_SIZE)
+        .take(PRE_TRAINING_STEPS)
+        .enumerate()
+    {
+        DASHBOARD.set_step(step);
+        let tensors = create_input_and_target_tensors(batch.iter(), rng);
+        compute_loss_and_take_step(
+            net,
+            opt,
+            amp.as_deref_mut(),
+            segments,
+            tensors, // early_reference, late_reference,
+            false,
+        );
+    }
+}
+
+fn create_batch(
+    using_reanalyze: bool,
+    exploitation_buffer: &mut Vec<TargetWithContext>,
+    reanalyze_buffer: &mut Vec<TargetWithContext>,
+    mut seen: Option<&mut SeenTargets>,
+    samplers: Option<&mut [PhaseSampler; 2]>,
+    rng: &mut impl Rng,
+    augmentation_rng: &mut impl Rng,
+) -> (Tensors, Vec<u64>) {
+    // Phase samplers draw at random and keep the order of the buffers.
+    if samplers.is_none() {
+        // TODO: Can we avoid doing an O(n) operation here?
+        // Ideally we would like to sample without replacement,
+        // Then swap_remove those targets which have forced_uses == 0.
+        exploitation_buffer.shuffle(rng);
+        reanalyze_buffer.shuffle(rng);
+    }
+
+    // Targets which reached the limit of uses are not reused.
+    let mut record_uses = |batch: &mut [TargetWithContext]| {
+        if let Some(seen) = &mut seen {
+            for t in batch.iter_mut().filter(|t| !seen.record(&t.target)) {
+                t.forced_uses = 0;
+            }
+        }
+    };
+    // Takes from the end of the shuffled buffer, or at random by phase.
+    let [mut exploitation_sampler, mut reanalyze_sampler] =
+        samplers.map_or([None, None], |samplers| samplers.each_mut().map(Some));
+    let mut take = |buffer: &mut Vec<TargetWithContext>,
+                    sampler: Option<&mut PhaseSampler>,
+                    n: usize| match sampler {
+        Some(sampler) => sampler.draw(buffer, n, rng, |t| &t.target.env),
+        None => buffer.drain(buffer.len() - n..).collect(),
+    };
+
+    if using_reanalyze {
+        let mut batch = take(exploitation_buffer, exploitation_sampler.take(), BATCH_SIZE / 2);
+        batch.extend(take(reanalyze_buffer, reanalyze_sampler.take(), BATCH_SIZE / 2));
+        let keys = batch.iter().map(|t| target_key(&t.target)).collect();
+        let tensors =
+            create_input_and_target_tensors(batch.iter().map(|t| &t.target), augmentation_rng);
+        record_uses(&mut batch);
+        let mut iter = batch.into_iter();
+        exploitation_buffer.extend(
+            iter.by_ref()
+                .take(BATCH_SIZE / 2)
+                .filter_map(TargetWithContext::reuse),
+        );
+        reanalyze_buffer.extend(iter.filter_map(TargetWithContext::reuse));
+        return (tensors, keys);
+    }
+
+    let mut batch = take(exploitation_buffer, exploitation_sampler, BATCH_SIZE);
+    let keys = batch.iter().map(|t| target_key(&t.target)).collect();
+    let tensors =
+        create_input_and_target_tensors(batch.iter().map(|t| &t.target), augmentation_rng);
+    record_uses(&mut batch);
+    exploitation_buffer.extend(batch.into_iter().filter_map(TargetWithContext::reuse));
+    (tensors, keys)
+}
+
+/// Keep cuDNN and cuBLAS from choosing kernels whose results depend on
+/// timing, so that audited steps can be reproduced bit for bit. cuBLAS reads
+/// its workspace configuration when it is first used, so this has to run
+/// before any work on the GPU.
+fn use_deterministic_kernels() {
+    std::env::set_var("CUBLAS_WORKSPACE_CONFIG", ":4096:8");
+    tch::Cuda::cudnn_set_benchmark(false);
+    tch::Cuda::set_user_enabled_cudnn(false);
+}
+
+/// Where audited runs keep the weights from right before a training step.
+/// They are apart from the checkpoints, so that evaluation, the curriculum
+/// and resuming do not take them for models.
+fn audit_weights_path(directory: &Path, step: usize) -> PathBuf {
+    directory.join("audit").join(format!("weights_{step:0>7}.ot"))
+}
+
+/// The random number generator which augments the batch of a training step.
+/// Every step has its own stream, so that a step can be reproduced without
+/// the ones before it. Sampling uses stream 0 of the same seed.
+fn augmentation_rng(seed: u64, step: usize) -> ChaCha12Rng {
+    let mut rng = ChaCha12Rng::seed_from_u64(seed);
+    rng.set_stream(step as u64);
+    rng
+}
+
+#[derive(Debug, Error)]
+enum ReproduceError {
+    #[error("{0}")]
+    Manifest(#[from] ReadManifestError),
+    #[error("step {0} is not in a learn run of the manifest")]
+    UnknownStep(usize),
+    #[error("target {0:016x} of the batch is not in the target files")]
+    MissingTarget(u64),
+    #[error("the weights before the step are not kept in {0}")]
+    MissingWeights(PathBuf),
+    #[error("{0}")]
+    Torch(#[from] TchError),
+    #[error("{0}")]
+    Checkpoint(#[from] CheckpointError),
+}
+
+/// Execute an audited training step again: draw the same targets from the
+/// target files, augment them the same way, and compute the loss of the
+/// weights which audited runs keep from right before the step.
+fn reproduce_step(manifest: &Path, directory: &Path, step: usize) -> Result<(), ReproduceError> {
+    let (seed, loss, keys) = read_manifest(manifest)?
+        .into_iter()
+        .rev()
+        .filter(|run| run.kind == "learn")
+        .find_map(|run| {
+            let seed = run.seed;
+            run.records.into_iter().find_map(|record| match record {
+                Record::Batch { step: s, loss, keys } if s == step => Some((seed, loss, keys)),
+                _ => None,
+            })
+        })
+        .ok_or(ReproduceError::UnknownStep(step))?;
+
+    let wanted: HashSet<_> = keys.iter().copied().collect();
+    let mut targets = HashMap::with_capacity(wanted.len());
+    for name in ["targets-selfplay.txt", "targets-reanalyze.txt"] {
+        let Ok(file) = File::open(directory.join(name)) else {
+            continue;
+        };
+        for target in BufReader::new(file)
+            .lines()
+            .map_while(Result::ok)
+            .filter_map(|line| line.parse::<Target<Env>>().ok())
+        {
+            let key = target_key(&target);
+            if wanted.contains(&key) {
+                targets.entry(key).or_insert(target);
+            }
+        }
+    }
+    let batch = keys
+        .iter()
+        .map(|key| targets.get(key).ok_or(ReproduceError::MissingTarget(*key)))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let path = audit_weights_path(directory, step);
+    if !path.exists() {
+        return Err(ReproduceError::MissingWeights(path));
+    }
+    let mut net: Net = checkpoint::load(&path, DEVICE)?;
+    let mut opt = Adam::default().build(net.vs_mut(), LEARNING_RATE)?;
+    let tensors =
+        create_input_and_target_tensors(batch.into_iter(), &mut augmentation_rng(seed, step));
+    match compute_loss_and_take_step(&mut net, &mut opt, None, None, tensors, true) {
+        Some(reproduced) if reproduced.to_bits() == loss.to_bits() => {
+            log::info!("Step {step} was reproduced exactly, with loss {loss}.");
+        }
+        reproduced => log::warn!("Step {step} has loss {reproduced:?} instead of {loss}."),
+    }
+    Ok(())
+}
+
+#[allow(unused)]
+fn truncate_buffer_if_needed(buffer: &mut Vec<TargetWithContext>, max_length: usize, name: &str) {
+    if buffer.len() > max_length {
+        log::info!(
+            "Truncating {name} buffer because it is too big. {}",
+            buffer.len()
+        );
+        buffer.sort_unstable_by_key(|t| Reverse((t.model_steps, t.forced_uses)));
+        buffer.truncate(max_length);
+    }
+}
+
+fn fill_buffers(
+    exploitation_buffer: &mut Vec<TargetWithContext>,
+    exploitation_targets_seek: &mut u64,
+    reanalyze_buffer: &mut Vec<TargetWithContext>,
+    reanalyze_targets_seek: &mut u64,
+    directory: &Path,
+    model_steps: usize,
+    using_reanalyze: bool,
+) {
+    let start = Instant::now();
+
+    if let Err(error) = fill_buffer_with_targets(
+        exploitation_buffer,
+        exploitation_targets_seek,
+        &directory.join("targets-selfplay.txt"),
+        SELFPLAY_TARGET_FORCED_USES,
+        model_steps,
+    ) {
+        log::error!("Cannot read selfplay targets: {error}");
+    }
+
+    if using_reanalyze {
+        if let Err(error) = fill_buffer_with_targets(
+            reanalyze_buffer,
+            reanalyze_targets_seek,
+            &directory.join("targets-reanalyze.txt"),
+            REANALYZE_TARGET_FORCED_USES,
+            model_steps,
+        ) {
+            log::error!("Cannot read reanalyze targets: {error}");
+        }
+    }
+
+    log::debug!("It took {:?} to add targets to buffer.", start.elapsed());
+}
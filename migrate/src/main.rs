@@ -0,0 +1,372 @@
+//! Upgrade replay and target files to the current format version, so that
+//! the history of a long-lived run can still be read after the format
+//! changes.
+//!
+//! Every line is parsed with the current parsers, which still accept the
+//! older formats, and written back in the current format under a current
+//! header. Fields which older versions did not have are re-derived where
+//! possible: the komi from the header or `--half-komi`, the generation of
+//! replays from the header or `--generation`, adjudicated results from the
+//! moves, and the creation time of files without a header from their
+//! modification time. Lines which do not parse are left out and kept in a
+//! `.rejected` file next to the output, and a file with such lines is not
+//! replaced: it has to be migrated into `--output-dir`. Gzipped files stay
+//! gzipped, and files with a checksum get a new one.
+
+use std::{
+    ffi::OsString,
+    fs::File,
+    io::{self, BufRead, BufWriter, Read, Write},
+    path::{Path, PathBuf},
+    time::UNIX_EPOCH,
+};
+
+use clap::{Parser, ValueEnum};
+use flate2::{write::GzEncoder, Compression};
+use takzero::{
+    any::{AnyParseError, AnyReplay, AnyTarget},
+    checksum,
+    header::{read_any_header, FileKind, Header, HeaderError, VERSION},
+    reader::{self, GZIP_MAGIC},
+};
+
+/// Lines which do not parse that are logged per file.
+const LOGGED_ERRORS: usize = 10;
+
+#[derive(Clone, Copy, Debug, ValueEnum)]
+enum Kind {
+    Replays,
+    Targets,
+}
+
+impl From<Kind> for FileKind {
+    fn from(kind: Kind) -> Self {
+        match kind {
+            Kind::Replays => Self::Replays,
+            Kind::Targets => Self::Targets,
+        }
+    }
+}
+
+#[derive(Parser, Debug)]
+struct Args {
+    /// Replay or target files to migrate
+    #[arg(required = true)]
+    input: Vec<PathBuf>,
+    /// Directory to write the migrated files to, under the same names.
+    /// Without it, the files are replaced.
+    #[arg(long)]
+    output_dir: Option<PathBuf>,
+    /// Kind of files without a header. Without it, lines starting with `[`
+    /// are taken to be replays.
+    #[arg(long, value_enum)]
+    kind: Option<Kind>,
+    /// Half komi of files without a header, and of their replays without a
+    /// `Komi` tag
+    #[arg(long, default_value_t = 4)]
+    half_komi: i8,
+    /// Generation of files whose header does not record one
+    #[arg(long)]
+    generation: Option<usize>,
+}
+
+/// What is known about a file before its lines are migrated.
+struct Source {
+    header: Option<Header>,
+    kind: FileKind,
+    half_komi: i8,
+    generation: Option<usize>,
+}
+
+impl Source {
+    /// The line in the current format, with its size and half komi.
+    fn migrate_line(&self, line: &str) -> Result<(String, usize, i8), AnyParseError> {
+        match (self.kind, &self.header) {
+            (FileKind::Replays, header) => {
+                let mut replay = match header {
+                    Some(header) => AnyReplay::parse(line, header.size, header.half_komi)?,
+                    None => AnyReplay::detect(line, self.half_komi)?,
+                };
+                if let Some(generation) = self.generation {
+                    replay.fill_generation(generation);
+                }
+                Ok((replay.to_string(), replay.size(), replay.half_komi()))
+            }
+            (FileKind::Targets, Some(header)) => {
+                let target = AnyTarget::parse(line, header.size, header.half_komi)?;
+                Ok((target.to_string(), target.size(), target.half_komi()))
+            }
+            (FileKind::Targets, None) => {
+                let target = AnyTarget::detect(line, self.half_komi)?;
+                Ok((target.to_string(), target.size(), target.half_komi()))
+            }
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct Report {
+    from_version: u32,
+    lines: usize,
+    /// Lines which do not parse.
+    rejected: Vec<String>,
+}
+
+fn main() {
+    takzero::logging::init();
+    let args = Args::parse();
+    let mut failed = false;
+    for input in &args.input {
+        let output = match &args.output_dir {
+            Some(directory) => directory.join(input.file_name().unwrap_or_default()),
+            None => input.clone(),
+        };
+        match migrate(input, &output, &args) {
+            Ok(report) => log::info!(
+                "{}: migrated {} lines from version {} to {VERSION}, rejected {}",
+                input.display(),
+                report.lines,
+                report.from_version,
+                report.rejected.len()
+            ),
+            Err(err) => {
+                log::error!("{}: {err}", input.display());
+                failed = true;
+            }
+        }
+    }
+    if failed {
+        std::process::exit(1);
+    }
+}
+
+/// `path` with `suffix` appended to its file name.
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = path.file_name().map(OsString::from).unwrap_or_default();
+    name.push(suffix);
+    path.with_file_name(name)
+}
+
+/// Whether both paths are the same existing file.
+fn same_file(a: &Path, b: &Path) -> bool {
+    a.canonicalize()
+        .is_ok_and(|a| b.canonicalize().is_ok_and(|b| a == b))
+}
+
+/// Migrate one file. The output is written next to its final path first and
+/// renamed at the end, so the input is only replaced once everything worked.
+/// Lines which do not parse are written to a `.rejected` file next to the
+/// output, and the input is not replaced if there are any.
+fn migrate(input: &Path, output: &Path, args: &Args) -> io::Result<Report> {
+    let checked = checksum::verify(input)?;
+    let mut gzipped = [0; 2];
+    let gzipped = File::open(input)?.read(&mut gzipped)? == 2 && gzipped == GZIP_MAGIC;
+
+    let mut reader = reader::open(input)?;
+    let header = read_any_header(&mut reader)?;
+    let from_version = header.as_ref().map_or(0, |header| header.version);
+    if from_version > VERSION {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            HeaderError::NewerVersion(from_version),
+        ));
+    }
+    let kind = match (&header, args.kind) {
+        (Some(header), _) => header.kind,
+        (None, Some(kind)) => kind.into(),
+        (None, None) if reader.fill_buf()?.starts_with(b"[") => FileKind::Replays,
+        (None, None) => FileKind::Targets,
+    };
+    let source = Source {
+        kind,
+        half_komi: args.half_komi,
+        generation: header.as_ref().and_then(|h| h.generation).or(args.generation),
+        header,
+    };
+
+    let new_header = match &source.header {
+        Some(header) => Header {
+            version: VERSION,
+            generation: source.generation,
+            ..header.clone()
+        },
+        None => {
+            // Without a header, the size and komi come from the first line
+            // which parses.
+            let Some((_, size, half_komi)) = reader::open(input)?
+                .lines()
+                .map_while(Result::ok)
+                .find_map(|line| source.migrate_line(&line).ok())
+            else {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "no line parses"));
+            };
+            let created = std::fs::metadata(input)?
+                .modified()?
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |duration| duration.as_secs());
+            Header {
+                kind,
+                version: VERSION,
+                size,
+                half_komi,
+                generation: source.generation,
+                created,
+            }
+        }
+    };
+
+    let temporary = with_suffix(output, ".migrating");
+    let mut file = BufWriter::new(File::create(&temporary)?);
+    let result = if gzipped {
+        let mut encoder = GzEncoder::new(file, Compression::default());
+        write_lines(reader, &mut encoder, &source, &new_header)
+            .and_then(|report| encoder.finish()?.flush().map(|()| report))
+    } else {
+        write_lines(reader, &mut file, &source, &new_header)
+            .and_then(|report| file.flush().map(|()| report))
+    };
+    let report = match result {
+        Ok(report) => report,
+        Err(err) => {
+            std::fs::remove_file(&temporary).ok();
+            return Err(err);
+        }
+    };
+    if !report.rejected.is_empty() {
+        let rejected = with_suffix(output, ".rejected");
+        let mut lines = report.rejected.join("\n");
+        lines.push('\n');
+        std::fs::write(&rejected, lines)?;
+        if same_file(input, output) {
+            std::fs::remove_file(&temporary).ok();
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "{} lines do not parse, which are kept in {}; migrate the file into \
+                     --output-dir instead of replacing it",
+                    report.rejected.len(),
+                    rejected.display()
+                ),
+            ));
+        }
+    }
+    std::fs::rename(&temporary, output)?;
+    if checked {
+        checksum::write_sidecar(output)?;
+    }
+    Ok(Report {
+        from_version,
+        ..report
+    })
+}
+
+/// Write the header and every line in the current format. All lines must
+/// be for the size and komi of the header.
+fn write_lines(
+    reader: impl BufRead,
+    writer: &mut impl Write,
+    source: &Source,
+    header: &Header,
+) -> io::Result<Report> {
+    write!(writer, "{header}")?;
+    let mut report = Report::default();
+    let first_line = 1 + usize::from(source.header.is_some());
+    for (number, line) in reader.lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        match source.migrate_line(&line) {
+            Ok((migrated, size, half_komi)) => {
+                if (size, half_komi) != (header.size, header.half_komi) {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!(
+                            "line {} is for size {size} with half komi {half_komi}, but the file \
+                             is for size {} with half komi {}",
+                            number + first_line,
+                            header.size,
+                            header.half_komi
+                        ),
+                    ));
+                }
+                write!(writer, "{migrated}")?;
+                report.lines += 1;
+            }
+            Err(err) => {
+                if report.rejected.len() < LOGGED_ERRORS {
+                    log::warn!("line {}: {err}", number + first_line);
+                }
+                report.rejected.push(line);
+            }
+        }
+    }
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::Parser;
+    use takzero::{
+        checksum,
+        header::{FileKind, Header, VERSION},
+    };
+
+    use super::{migrate, Args};
+
+    #[test]
+    fn upgrade_headerless_replays() {
+        let directory =
+            std::env::temp_dir().join(format!("takzero-migrate-{}", std::process::id()));
+        std::fs::create_dir_all(&directory).unwrap();
+        let input = directory.join("replays.txt");
+        std::fs::write(
+            &input,
+            "[TPS \"x5/x5/x5/x5/x5 1 1\"] a1 e5\nnot a replay\n\n[TPS \"x5/x5/x5/x5/x5 1 1\"] b2\n",
+        )
+        .unwrap();
+        checksum::write_sidecar(&input).unwrap();
+
+        let args = Args::parse_from(["migrate", "replays.txt", "--generation", "7"]);
+        let args = Args { half_komi: 0, ..args };
+        // The line which does not parse keeps the file from being replaced.
+        let original = std::fs::read_to_string(&input).unwrap();
+        assert!(migrate(&input, &input, &args).is_err());
+        assert_eq!(std::fs::read_to_string(&input).unwrap(), original);
+        let rejected = directory.join("replays.txt.rejected");
+        assert_eq!(std::fs::read_to_string(&rejected).unwrap(), "not a replay\n");
+        std::fs::remove_file(&rejected).unwrap();
+
+        let output = directory.join("migrated").join("replays.txt");
+        std::fs::create_dir_all(output.parent().unwrap()).unwrap();
+        let report = migrate(&input, &output, &args).unwrap();
+        assert_eq!((report.from_version, report.lines), (0, 2));
+        assert_eq!(report.rejected, ["not a replay"]);
+        assert!(checksum::verify(&output).unwrap());
+        assert_eq!(
+            std::fs::read_to_string(directory.join("migrated").join("replays.txt.rejected"))
+                .unwrap(),
+            "not a replay\n"
+        );
+        let input = output;
+
+        let content = std::fs::read_to_string(&input).unwrap();
+        let mut lines = content.lines();
+        let header: Header = lines.next().unwrap().parse().unwrap();
+        assert_eq!(
+            (header.kind, header.version, header.size, header.half_komi, header.generation),
+            (FileKind::Replays, VERSION, 5, 0, Some(7))
+        );
+        assert_eq!(
+            lines.next(),
+            Some("[TPS \"x5/x5/x5/x5/x5 1 1\"] [Komi \"0\"] [Generation \"7\"] a1 e5")
+        );
+        assert_eq!(lines.count(), 1);
+
+        // Migrating again changes nothing.
+        let report = migrate(&input, &input, &args).unwrap();
+        assert_eq!((report.from_version, report.lines), (VERSION, 2));
+        assert_eq!(std::fs::read_to_string(&input).unwrap(), content);
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+}
@@ -0,0 +1,142 @@
+//! Just enough TPS to draw a board: the top of every stack and its height.
+
+use std::fmt;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Color {
+    White,
+    Black,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kind {
+    Flat,
+    Wall,
+    Cap,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Stack {
+    pub top: Color,
+    pub kind: Kind,
+    pub height: usize,
+}
+
+impl fmt::Display for Stack {
+    /// The height if the stack is taller than one, then `w` or `b` for the
+    /// color on top, and `S` or `C` for walls and capstones.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.height > 1 {
+            write!(f, "{}", self.height)?;
+        }
+        f.write_str(match self.top {
+            Color::White => "w",
+            Color::Black => "b",
+        })?;
+        f.write_str(match self.kind {
+            Kind::Flat => "",
+            Kind::Wall => "S",
+            Kind::Cap => "C",
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Board {
+    /// Rows from the top of the board, as TPS writes them.
+    pub rows: Vec<Vec<Option<Stack>>>,
+    pub to_move: Color,
+}
+
+impl Board {
+    /// Parse a TPS string like `x3/x,2,x/1S,x2 1 3`.
+    /// Returns `None` if it is malformed.
+    #[must_use]
+    pub fn parse(tps: &str) -> Option<Self> {
+        let mut fields = tps.split_whitespace();
+        let rows = fields
+            .next()?
+            .split('/')
+            .map(parse_row)
+            .collect::<Option<Vec<_>>>()?;
+        let size = rows.len();
+        if rows.iter().any(|row| row.len() != size) {
+            return None;
+        }
+        let to_move = match fields.next()? {
+            "1" => Color::White,
+            "2" => Color::Black,
+            _ => return None,
+        };
+        Some(Self { rows, to_move })
+    }
+
+    /// The board as lines of text, with squares `width` characters wide.
+    #[must_use]
+    pub fn lines(&self, width: usize) -> Vec<String> {
+        self.rows
+            .iter()
+            .map(|row| {
+                row.iter()
+                    .map(|square| {
+                        square.map_or_else(
+                            || format!("{:<width$}", "."),
+                            |stack| format!("{:<width$}", stack.to_string()),
+                        )
+                    })
+                    .collect()
+            })
+            .collect()
+    }
+}
+
+fn parse_row(row: &str) -> Option<Vec<Option<Stack>>> {
+    let mut squares = Vec::new();
+    for square in row.split(',') {
+        if let Some(empty) = square.strip_prefix('x') {
+            let count = if empty.is_empty() { 1 } else { empty.parse().ok()? };
+            squares.extend(std::iter::repeat(None).take(count));
+            continue;
+        }
+        let (pieces, kind) = match square.as_bytes().last()? {
+            b'S' => (&square[..square.len() - 1], Kind::Wall),
+            b'C' => (&square[..square.len() - 1], Kind::Cap),
+            _ => (square, Kind::Flat),
+        };
+        if pieces.is_empty() || !pieces.bytes().all(|piece| matches!(piece, b'1' | b'2')) {
+            return None;
+        }
+        let top = if pieces.ends_with('1') { Color::White } else { Color::Black };
+        squares.push(Some(Stack {
+            top,
+            kind,
+            height: pieces.len(),
+        }));
+    }
+    Some(squares)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Board, Color, Kind, Stack};
+
+    #[test]
+    fn parse_tps() {
+        let board = Board::parse("x3/x,221C,x/1S,x2 2 4").unwrap();
+        assert_eq!(board.to_move, Color::Black);
+        assert_eq!(board.rows[0], [None, None, None]);
+        assert_eq!(
+            board.rows[1][1],
+            Some(Stack {
+                top: Color::White,
+                kind: Kind::Cap,
+                height: 3,
+            })
+        );
+        assert_eq!(board.lines(4), [".   .   .   ", ".   3wC .   ", "wS  .   .   "]);
+
+        for malformed in ["x3/x3 1 1", "x2/x,3 1 1", "x2/x2 3 1", "x2/x2", "x2/xa,x 1 1"] {
+            assert_eq!(Board::parse(malformed), None, "{malformed}");
+        }
+    }
+}
@@ -0,0 +1,74 @@
+//! A terminal view of a training run, for when the web dashboard is out of
+//! reach: live self-play boards from the spectator of `selfplay`, and loss
+//! curves, buffer lengths, and the Elo history from the dashboard of `learn`.
+
+mod board;
+mod source;
+mod ui;
+
+use std::{
+    io,
+    sync::{Arc, Mutex},
+    thread,
+    time::Duration,
+};
+
+use clap::Parser;
+use ratatui::crossterm::event::{self, Event, KeyCode, KeyEventKind};
+use source::State;
+
+#[derive(Parser, Debug)]
+struct Args {
+    /// Address of the dashboard of `learn`, for example `localhost:8000`.
+    #[arg(long)]
+    dashboard: Option<String>,
+    /// Address of the spectator of `selfplay`, for example `localhost:8001`.
+    #[arg(long)]
+    spectator: Option<String>,
+    /// Seconds between reads of the dashboard.
+    #[arg(long, default_value_t = 5)]
+    interval: u64,
+    /// Buffer length at which self-play and reanalysis pause.
+    #[arg(long, default_value_t = 32_000)]
+    buffer_capacity: usize,
+}
+
+/// Time between redraws.
+const FRAME: Duration = Duration::from_millis(250);
+
+fn main() -> io::Result<()> {
+    let args = Args::parse();
+    let state = Arc::new(Mutex::new(State::default()));
+    if let Some(address) = args.dashboard {
+        let state = state.clone();
+        let interval = Duration::from_secs(args.interval);
+        thread::spawn(move || source::poll_dashboard(&address, interval, &state));
+    }
+    if let Some(address) = args.spectator {
+        let state = state.clone();
+        thread::spawn(move || source::follow_spectator(&address, &state));
+    }
+
+    let mut terminal = ratatui::init();
+    let result = loop {
+        let drawn = terminal.draw(|frame| {
+            let state = state.lock().expect("monitor lock should not be poisoned");
+            ui::draw(frame, &state, args.buffer_capacity);
+        });
+        if let Err(err) = drawn {
+            break Err(err);
+        }
+        match event::poll(FRAME).and_then(|ready| ready.then(event::read).transpose()) {
+            Ok(Some(Event::Key(key)))
+                if key.kind == KeyEventKind::Press
+                    && matches!(key.code, KeyCode::Char('q') | KeyCode::Esc) =>
+            {
+                break Ok(());
+            }
+            Ok(_) => {}
+            Err(err) => break Err(err),
+        }
+    };
+    ratatui::restore();
+    result
+}
@@ -0,0 +1,286 @@
+//! Reading the dashboard of `learn` and the live games of `selfplay`, see
+//! `takzero::dashboard` and `takzero::spectator`.
+
+use std::{
+    collections::BTreeMap,
+    io::{self, BufRead, BufReader, Read, Write},
+    net::TcpStream,
+    sync::{Arc, Mutex},
+    thread,
+    time::Duration,
+};
+
+use serde_json::Value;
+use thiserror::Error;
+
+/// Requests which take longer than this fail, so that a stuck server shows
+/// up as an error instead of a frozen screen.
+const TIMEOUT: Duration = Duration::from_secs(5);
+/// Time to wait before connecting to the spectator again.
+const RECONNECT: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Error)]
+pub enum SourceError {
+    #[error("{0}")]
+    Io(#[from] io::Error),
+    #[error("{0}")]
+    Json(#[from] serde_json::Error),
+    #[error("unexpected response `{0}`")]
+    Status(String),
+    #[error("malformed data: {0}")]
+    Format(&'static str),
+}
+
+/// What the monitor knows about the run, shared between the threads which
+/// read it and the one which draws it.
+#[derive(Debug, Default)]
+pub struct State {
+    pub dashboard: Option<Dashboard>,
+    pub dashboard_error: Option<String>,
+    pub games: LiveGames,
+    pub spectator_error: Option<String>,
+}
+
+pub type Shared = Arc<Mutex<State>>;
+
+fn lock(state: &Shared) -> std::sync::MutexGuard<'_, State> {
+    state.lock().expect("monitor lock should not be poisoned")
+}
+
+/// A snapshot of the training dashboard.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Dashboard {
+    pub step: u64,
+    /// The values of every loss, oldest first.
+    pub losses: Vec<(String, Vec<f64>)>,
+    /// The lengths of every target buffer, oldest first.
+    pub buffers: Vec<(String, Vec<f64>)>,
+    /// The Elo of the evaluated checkpoints, oldest first.
+    pub elo: Vec<f64>,
+}
+
+impl Dashboard {
+    /// Parse the JSON which the dashboard serves at `/data`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the JSON is malformed.
+    pub fn parse(json: &str) -> Result<Self, SourceError> {
+        let data: Value = serde_json::from_str(json)?;
+        let step = data["step"]
+            .as_u64()
+            .ok_or(SourceError::Format("dashboard without a step"))?;
+        Ok(Self {
+            step,
+            losses: series(&data["losses"])?,
+            buffers: series(&data["buffers"])?,
+            elo: data["elo"]
+                .as_array()
+                .ok_or(SourceError::Format("dashboard without Elo"))?
+                .iter()
+                .filter_map(Value::as_f64)
+                .collect(),
+        })
+    }
+}
+
+/// Parse series of `[step, value]` points. Missing values are skipped.
+fn series(value: &Value) -> Result<Vec<(String, Vec<f64>)>, SourceError> {
+    let series = value
+        .as_object()
+        .ok_or(SourceError::Format("series should be an object"))?;
+    series
+        .iter()
+        .map(|(name, points)| {
+            let points = points
+                .as_array()
+                .ok_or(SourceError::Format("series should be arrays of points"))?;
+            let values = points.iter().filter_map(|point| point[1].as_f64()).collect();
+            Ok((name.clone(), values))
+        })
+        .collect()
+}
+
+/// A game which self-play is playing right now.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct LiveGame {
+    pub ply: usize,
+    pub last_move: Option<String>,
+    /// The root value of the last search, from white's perspective.
+    pub value: Option<f64>,
+    pub tps: String,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct LiveGames {
+    pub games: BTreeMap<u64, LiveGame>,
+    /// How often every result was seen since the monitor started.
+    pub results: BTreeMap<String, usize>,
+}
+
+impl LiveGames {
+    /// Apply an event of the spectator stream.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the event is malformed.
+    pub fn apply(&mut self, event: &str) -> Result<(), SourceError> {
+        let event: Value = serde_json::from_str(event)?;
+        let id = event["game"]
+            .as_u64()
+            .ok_or(SourceError::Format("event without a game"))?;
+        let tps = || {
+            event["tps"]
+                .as_str()
+                .map(ToString::to_string)
+                .ok_or(SourceError::Format("event without a position"))
+        };
+        match event["type"].as_str() {
+            Some("game") => {
+                let moves = event["moves"].as_array().map(Vec::as_slice).unwrap_or_default();
+                let values = event["values"].as_array().map(Vec::as_slice).unwrap_or_default();
+                self.games.insert(id, LiveGame {
+                    ply: moves.len(),
+                    last_move: moves.last().and_then(Value::as_str).map(ToString::to_string),
+                    value: values.last().and_then(Value::as_f64),
+                    tps: tps()?,
+                });
+            }
+            Some("move") => {
+                let game = self.games.entry(id).or_default();
+                game.ply = event["ply"]
+                    .as_u64()
+                    .and_then(|ply| usize::try_from(ply).ok())
+                    .unwrap_or(game.ply + 1);
+                game.last_move = event["move"].as_str().map(ToString::to_string);
+                game.value = event["value"].as_f64();
+                game.tps = tps()?;
+            }
+            Some("end") => {
+                self.games.remove(&id);
+                let result = event["result"].as_str().unwrap_or("?").to_string();
+                *self.results.entry(result).or_default() += 1;
+            }
+            _ => return Err(SourceError::Format("unknown event")),
+        }
+        Ok(())
+    }
+}
+
+/// Get a page from an HTTP server like the dashboard, which closes the
+/// connection after answering.
+fn get(address: &str, path: &str) -> Result<String, SourceError> {
+    let mut stream = TcpStream::connect(address)?;
+    stream.set_read_timeout(Some(TIMEOUT))?;
+    write!(stream, "GET {path} HTTP/1.1\r\nHost: {address}\r\nConnection: close\r\n\r\n")?;
+    let mut response = String::new();
+    stream.read_to_string(&mut response)?;
+    let (head, body) = response
+        .split_once("\r\n\r\n")
+        .ok_or(SourceError::Format("response without a body"))?;
+    let status = head.lines().next().unwrap_or_default();
+    if status.split_whitespace().nth(1) != Some("200") {
+        return Err(SourceError::Status(status.to_string()));
+    }
+    Ok(body.to_string())
+}
+
+/// Read the dashboard every `interval`, forever.
+pub fn poll_dashboard(address: &str, interval: Duration, state: &Shared) {
+    loop {
+        let dashboard = get(address, "/data").and_then(|json| Dashboard::parse(&json));
+        let mut state = lock(state);
+        match dashboard {
+            Ok(dashboard) => {
+                state.dashboard = Some(dashboard);
+                state.dashboard_error = None;
+            }
+            Err(err) => state.dashboard_error = Some(err.to_string()),
+        }
+        drop(state);
+        thread::sleep(interval);
+    }
+}
+
+/// Follow the live games of the spectator, reconnecting when the stream
+/// ends, forever.
+pub fn follow_spectator(address: &str, state: &Shared) {
+    loop {
+        let err = match stream_events(address, state) {
+            Ok(()) => "the stream ended".to_string(),
+            Err(err) => err.to_string(),
+        };
+        lock(state).spectator_error = Some(err);
+        thread::sleep(RECONNECT);
+    }
+}
+
+fn stream_events(address: &str, state: &Shared) -> Result<(), SourceError> {
+    let mut stream = TcpStream::connect(address)?;
+    // The spectator sends a comment at least every 15 seconds.
+    stream.set_read_timeout(Some(TIMEOUT + Duration::from_secs(15)))?;
+    write!(stream, "GET /events HTTP/1.1\r\nHost: {address}\r\n\r\n")?;
+    let mut lines = BufReader::new(stream).lines();
+    let status = lines
+        .next()
+        .ok_or(SourceError::Format("empty response"))??;
+    if status.split_whitespace().nth(1) != Some("200") {
+        return Err(SourceError::Status(status));
+    }
+    // Skip the headers.
+    for line in lines.by_ref() {
+        if line?.is_empty() {
+            break;
+        }
+    }
+
+    // The stream starts with every running game.
+    let mut state_guard = lock(state);
+    state_guard.games.games.clear();
+    state_guard.spectator_error = None;
+    drop(state_guard);
+    for line in lines {
+        let line = line?;
+        if let Some(event) = line.strip_prefix("data: ") {
+            lock(state).games.apply(event)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Dashboard, LiveGames};
+
+    #[test]
+    fn parse_dashboard() {
+        let json = "{\"step\":1000,\"losses\":{\"value\":[[0,0.5],[2,null],[4,0.25]]},\
+                    \"buffers\":{\"selfplay\":[[1000,10]]},\"elo\":[0,12.5],\"games\":[]}";
+        assert_eq!(Dashboard::parse(json).unwrap(), Dashboard {
+            step: 1000,
+            losses: vec![("value".into(), vec![0.5, 0.25])],
+            buffers: vec![("selfplay".into(), vec![10.0])],
+            elo: vec![0.0, 12.5],
+        });
+        assert!(Dashboard::parse("{\"losses\":{}}").is_err());
+    }
+
+    #[test]
+    fn apply_events() {
+        let mut games = LiveGames::default();
+        for event in [
+            "{\"type\":\"game\",\"game\":3,\"moves\":[\"a1\"],\"values\":[0.1],\"tps\":\"t1\"}",
+            "{\"type\":\"move\",\"game\":3,\"ply\":2,\"move\":\"c3\",\"value\":0,\"tps\":\"t2\"}",
+            "{\"type\":\"move\",\"game\":4,\"move\":\"a1\",\"value\":null,\"tps\":\"t3\"}",
+            "{\"type\":\"end\",\"game\":4,\"result\":\"R-0\"}",
+        ] {
+            games.apply(event).unwrap();
+        }
+        assert_eq!(games.games.len(), 1);
+        let game = &games.games[&3];
+        assert_eq!((game.ply, game.last_move.as_deref()), (2, Some("c3")));
+        assert_eq!((game.value, game.tps.as_str()), (Some(0.0), "t2"));
+        assert_eq!(games.results["R-0"], 1);
+        assert!(games.apply("{\"type\":\"chat\",\"game\":1}").is_err());
+    }
+}
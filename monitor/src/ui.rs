@@ -0,0 +1,208 @@
+//! Drawing the state of the run.
+
+use ratatui::{
+    layout::{Constraint, Layout, Rect},
+    style::{Color, Style, Stylize},
+    text::Line,
+    widgets::{Block, Gauge, Paragraph, Sparkline},
+    Frame,
+};
+
+use crate::{
+    board::{Board, Color as Side},
+    source::{LiveGame, State},
+};
+
+/// Width of a square of a board.
+const SQUARE_WIDTH: usize = 4;
+
+pub fn draw(frame: &mut Frame, state: &State, buffer_capacity: usize) {
+    let [header, top, games] = Layout::vertical([
+        Constraint::Length(1),
+        Constraint::Percentage(45),
+        Constraint::Fill(1),
+    ])
+    .areas(frame.area());
+    let [losses, side] =
+        Layout::horizontal([Constraint::Percentage(60), Constraint::Fill(1)]).areas(top);
+    let [buffers, evaluation] =
+        Layout::vertical([Constraint::Percentage(50), Constraint::Fill(1)]).areas(side);
+
+    draw_header(frame, header, state);
+    draw_losses(frame, losses, state);
+    draw_buffers(frame, buffers, state, buffer_capacity);
+    draw_evaluation(frame, evaluation, state);
+    draw_games(frame, games, state);
+}
+
+fn draw_header(frame: &mut Frame, area: Rect, state: &State) {
+    let step = state
+        .dashboard
+        .as_ref()
+        .map_or_else(|| "-".to_string(), |dashboard| dashboard.step.to_string());
+    let status = |name: &str, error: Option<&String>| match error {
+        Some(err) => format!("{name}: {err}").red(),
+        None => format!("{name}: ok").green(),
+    };
+    let line = Line::from(vec![
+        format!("step {step}  ").bold(),
+        status("dashboard", state.dashboard_error.as_ref()),
+        "  ".into(),
+        status("spectator", state.spectator_error.as_ref()),
+        "  (q to quit)".dark_gray(),
+    ]);
+    frame.render_widget(line, area);
+}
+
+/// Scale values to the range of a sparkline, with the minimum at the bottom.
+#[allow(clippy::cast_sign_loss, clippy::cast_possible_truncation)]
+fn scale(values: &[f64]) -> Vec<u64> {
+    let min = values.iter().copied().fold(f64::INFINITY, f64::min);
+    let max = values.iter().copied().fold(f64::NEG_INFINITY, f64::max);
+    let range = (max - min).max(f64::EPSILON);
+    values
+        .iter()
+        .map(|value| ((value - min) / range * 99.0) as u64 + 1)
+        .collect()
+}
+
+/// The last `width` values, which fit into a sparkline of that width.
+fn tail(values: &[f64], width: u16) -> &[f64] {
+    &values[values.len().saturating_sub(width.into())..]
+}
+
+fn draw_losses(frame: &mut Frame, area: Rect, state: &State) {
+    let block = Block::bordered().title("Losses");
+    let inner = block.inner(area);
+    frame.render_widget(block, area);
+    let Some(dashboard) = &state.dashboard else {
+        return;
+    };
+    let rows = Layout::vertical(vec![Constraint::Fill(1); dashboard.losses.len()]).split(inner);
+    for ((name, values), &row) in dashboard.losses.iter().zip(rows.iter()) {
+        let [label, sparkline] =
+            Layout::horizontal([Constraint::Length(24), Constraint::Fill(1)]).areas(row);
+        let last = values.last().map_or_else(|| "-".to_string(), |x| format!("{x:.4}"));
+        frame.render_widget(Paragraph::new(format!("{name} {last}")), label);
+        let data = scale(tail(values, sparkline.width));
+        frame.render_widget(
+            Sparkline::default().data(&data).style(Style::new().fg(Color::Cyan)),
+            sparkline,
+        );
+    }
+}
+
+fn draw_buffers(frame: &mut Frame, area: Rect, state: &State, capacity: usize) {
+    let block = Block::bordered().title("Buffers");
+    let inner = block.inner(area);
+    frame.render_widget(block, area);
+    let Some(dashboard) = &state.dashboard else {
+        return;
+    };
+    let rows = Layout::vertical(vec![Constraint::Length(1); dashboard.buffers.len()]).split(inner);
+    for ((name, values), &row) in dashboard.buffers.iter().zip(rows.iter()) {
+        let length = values.last().copied().unwrap_or_default();
+        #[allow(clippy::cast_precision_loss)]
+        let ratio = (length / capacity as f64).clamp(0.0, 1.0);
+        // Workers pause when their buffer is full.
+        let color = if ratio >= 1.0 { Color::Red } else { Color::Green };
+        frame.render_widget(
+            Gauge::default()
+                .ratio(ratio)
+                .label(format!("{name} {length}/{capacity}"))
+                .gauge_style(Style::new().fg(color)),
+            row,
+        );
+    }
+}
+
+fn draw_evaluation(frame: &mut Frame, area: Rect, state: &State) {
+    let block = Block::bordered().title("Evaluation (Elo)");
+    let inner = block.inner(area);
+    frame.render_widget(block, area);
+    let elo = state
+        .dashboard
+        .as_ref()
+        .map(|dashboard| dashboard.elo.as_slice())
+        .unwrap_or_default();
+    let [summary, sparkline] =
+        Layout::vertical([Constraint::Length(1), Constraint::Fill(1)]).areas(inner);
+    let text = match elo {
+        [] => "no evaluations yet".to_string(),
+        [last] => format!("{last:+.1}"),
+        [.., previous, last] => format!(
+            "{last:+.1} after {} checkpoints, last gain {:+.1}",
+            elo.len(),
+            last - previous
+        ),
+    };
+    frame.render_widget(Paragraph::new(text), summary);
+    let data = scale(tail(elo, sparkline.width));
+    frame.render_widget(
+        Sparkline::default().data(&data).style(Style::new().fg(Color::Yellow)),
+        sparkline,
+    );
+}
+
+fn draw_games(frame: &mut Frame, area: Rect, state: &State) {
+    let results: Vec<_> = state
+        .games
+        .results
+        .iter()
+        .map(|(result, count)| format!("{result} {count}"))
+        .collect();
+    let block = Block::bordered().title(format!(
+        "Self-play ({} running, finished: {})",
+        state.games.games.len(),
+        if results.is_empty() { "none".to_string() } else { results.join(", ") },
+    ));
+    let inner = block.inner(area);
+    frame.render_widget(block, area);
+
+    let Some(size) = state
+        .games
+        .games
+        .values()
+        .find_map(|game| Board::parse(&game.tps))
+        .map(|board| board.rows.len())
+    else {
+        return;
+    };
+    // A board with its title and a column of space.
+    let width = u16::try_from(size * SQUARE_WIDTH + 1).unwrap_or(u16::MAX);
+    let height = u16::try_from(size + 1).unwrap_or(u16::MAX);
+    let columns = (inner.width / width).max(1);
+    let games: Vec<_> = state.games.games.iter().collect();
+    let rows = (0..inner.height / height).zip(games.chunks(columns.into()));
+    for (row, games) in rows {
+        for (column, (id, game)) in (0..).zip(games) {
+            let area = Rect {
+                x: inner.x + column * width,
+                y: inner.y + row * height,
+                width: width.min(inner.width),
+                height,
+            };
+            draw_game(frame, area.intersection(inner), **id, game);
+        }
+    }
+}
+
+fn draw_game(frame: &mut Frame, area: Rect, id: u64, game: &LiveGame) {
+    let value = game
+        .value
+        .map_or_else(|| "-".to_string(), |value| format!("{value:+.2}"));
+    let last_move = game.last_move.as_deref().unwrap_or("-");
+    let Some(board) = Board::parse(&game.tps) else {
+        let lines = vec![Line::from(format!("#{id}")), Line::from("malformed TPS".red())];
+        frame.render_widget(Paragraph::new(lines), area);
+        return;
+    };
+    let to_move = match board.to_move {
+        Side::White => "w",
+        Side::Black => "b",
+    };
+    let title = format!("#{id} {} {last_move} {to_move} {value}", game.ply);
+    let mut lines = vec![Line::from(title.bold())];
+    lines.extend(board.lines(SQUARE_WIDTH).into_iter().map(Line::from));
+    frame.render_widget(Paragraph::new(lines), area);
+}
@@ -0,0 +1,222 @@
+//! Export targets encoded like `learn` encodes them to NumPy `.npz` files,
+//! so that prototypes in PyTorch or JAX train on the same data and inputs.
+//!
+//! Every file holds up to `--chunk` targets (`targets-00000.npz`,
+//! `targets-00001.npz`, ...) as these arrays, where `B` is the number of
+//! targets, `C` the number of input channels, and `M` the size of the
+//! policy output:
+//!
+//! | array    | shape          | type    | meaning                                              |
+//! |----------|----------------|---------|------------------------------------------------------|
+//! | `input`  | `[B, C, N, N]` | float32 | input planes from `repr::game_to_input`              |
+//! | `policy` | `[B, M]`       | float32 | search policy, indexed by `repr::move_index`         |
+//! | `mask`   | `[B, M]`       | bool    | true for illegal moves, like `repr::move_mask`       |
+//! | `value`  | `[B]`          | float32 | value target for the player to move                  |
+//! | `ube`    | `[B]`          | float32 | uncertainty target (`learn` trains on its logarithm) |
+//! | `weight` | `[B]`          | float32 | sample weight, 1 unless the target has another       |
+//!
+//! With `--augment`, every target is put through a random symmetry first,
+//! as `learn` does.
+
+use std::path::{Path, PathBuf};
+
+use clap::Parser;
+use fast_tak::{Game, Reserves};
+use rand::{rngs::StdRng, SeedableRng};
+use takzero::{
+    network::repr::{game_to_input, input_channels, move_index, output_size},
+    target::{get_targets, Augment, Target},
+};
+use tch::{TchError, Tensor};
+
+#[derive(Parser, Debug)]
+struct Args {
+    /// Target files to export
+    #[arg(required = true)]
+    input: Vec<PathBuf>,
+    /// Directory to write the `.npz` files to
+    #[arg(long)]
+    output: PathBuf,
+    #[arg(long, default_value_t = 6)]
+    size: usize,
+    #[arg(long, default_value_t = 4)]
+    half_komi: i8,
+    /// Number of targets per file
+    #[arg(long, default_value_t = 65_536)]
+    chunk: usize,
+    /// Apply a random symmetry to every target
+    #[arg(long)]
+    augment: bool,
+    /// Seed for `--augment`
+    #[arg(long, default_value_t = 0)]
+    seed: u64,
+}
+
+macro_rules! dispatch {
+    ($args:expr; $($size:literal),*) => {
+        match ($args.size, $args.half_komi) {
+            $(
+                ($size, 0) => export::<$size, 0>(&$args),
+                ($size, 4) => export::<$size, 4>(&$args),
+            )*
+            (size, half_komi) => {
+                log::error!("size {size} with half komi {half_komi} is not supported");
+                Ok(())
+            }
+        }
+    };
+}
+
+fn main() {
+    takzero::logging::init();
+    let args = Args::parse();
+    if let Err(err) = dispatch!(args; 3, 4, 5, 6, 7, 8) {
+        log::error!("{err}");
+    }
+}
+
+/// Arrays of the file which is being built, flattened.
+#[derive(Default)]
+struct Arrays {
+    input: Vec<f32>,
+    policy: Vec<f32>,
+    mask: Vec<bool>,
+    value: Vec<f32>,
+    ube: Vec<f32>,
+    weight: Vec<f32>,
+}
+
+impl Arrays {
+    fn len(&self) -> usize {
+        self.value.len()
+    }
+
+    fn push<const N: usize, const HALF_KOMI: i8>(&mut self, target: &Target<Game<N, HALF_KOMI>>)
+    where
+        Reserves<N>: Default,
+    {
+        self.input.extend(game_to_input(&target.env));
+        let start = self.policy.len();
+        self.policy.resize(start + output_size::<N>(), 0.0);
+        self.mask.resize(start + output_size::<N>(), true);
+        for (action, p) in target.policy.iter() {
+            let index = start + move_index::<N>(action);
+            self.policy[index] = p.into_inner();
+            self.mask[index] = false;
+        }
+        self.value.push(target.value);
+        self.ube.push(target.ube);
+        self.weight.push(target.weight.unwrap_or(1.0));
+    }
+
+    /// Write the arrays to a file and start over.
+    fn write<const N: usize>(&mut self, path: &Path) -> Result<(), TchError> {
+        let batch = self.len() as i64;
+        let input = Tensor::from_slice(&self.input).reshape([
+            batch,
+            input_channels::<N>() as i64,
+            N as i64,
+            N as i64,
+        ]);
+        let policy = Tensor::from_slice(&self.policy).reshape([batch, output_size::<N>() as i64]);
+        let mask = Tensor::from_slice(&self.mask).reshape([batch, output_size::<N>() as i64]);
+        Tensor::write_npz(
+            &[
+                ("input", input),
+                ("policy", policy),
+                ("mask", mask),
+                ("value", Tensor::from_slice(&self.value)),
+                ("ube", Tensor::from_slice(&self.ube)),
+                ("weight", Tensor::from_slice(&self.weight)),
+            ],
+            path,
+        )?;
+        *self = Self::default();
+        Ok(())
+    }
+}
+
+fn export<const N: usize, const HALF_KOMI: i8>(
+    args: &Args,
+) -> Result<(), Box<dyn std::error::Error>>
+where
+    Reserves<N>: Default,
+{
+    std::fs::create_dir_all(&args.output)?;
+    let mut rng = StdRng::seed_from_u64(args.seed);
+    let mut arrays = Arrays::default();
+    let (mut files, mut total) = (0, 0);
+    let mut write = |arrays: &mut Arrays| {
+        let path = args.output.join(format!("targets-{files:05}.npz"));
+        total += arrays.len();
+        files += 1;
+        arrays.write::<N>(&path)
+    };
+    for path in &args.input {
+        for target in get_targets::<N, HALF_KOMI>(path)? {
+            if args.augment {
+                arrays.push(&target.augment(&mut rng));
+            } else {
+                arrays.push(&target);
+            }
+            if arrays.len() >= args.chunk {
+                write(&mut arrays)?;
+            }
+        }
+    }
+    if !arrays.value.is_empty() {
+        write(&mut arrays)?;
+    }
+    log::info!("exported {total} targets to {files} files in {}", args.output.display());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use fast_tak::Game;
+    use ordered_float::NotNan;
+    use takzero::{
+        network::repr::{input_channels, input_size, move_index, output_size},
+        search::env::Environment,
+        target::Target,
+    };
+    use tch::{Kind, Tensor};
+
+    use super::Arrays;
+
+    #[test]
+    fn encode_and_write() {
+        let env = Game::<4, 0>::default();
+        let mut actions = Vec::new();
+        env.populate_actions(&mut actions);
+        let target = Target {
+            policy: actions.iter().map(|a| (*a, NotNan::new(0.25).unwrap())).collect(),
+            env,
+            value: 0.5,
+            ube: 0.125,
+            weight: None,
+            move_limit: None,
+            model_steps: None,
+        };
+        let mut arrays = Arrays::default();
+        arrays.push(&target);
+        arrays.push(&target);
+        assert_eq!(arrays.len(), 2);
+        assert_eq!(arrays.input.len(), 2 * input_size::<4>());
+        let index = output_size::<4>() + move_index::<4>(&actions[0]);
+        assert!((arrays.policy[index] - 0.25).abs() < f32::EPSILON);
+        assert!(!arrays.mask[index]);
+        assert_eq!(arrays.mask.iter().filter(|illegal| !**illegal).count(), 2 * actions.len());
+
+        let path = std::env::temp_dir().join(format!("takzero-npz-{}.npz", std::process::id()));
+        arrays.write::<4>(&path).unwrap();
+        assert_eq!(arrays.len(), 0);
+        let read = Tensor::read_npz(&path).unwrap();
+        std::fs::remove_file(path).unwrap();
+        let array = |name: &str| &read.iter().find(|(n, _)| n == name).unwrap().1;
+        assert_eq!(array("input").size(), [2, input_channels::<4>() as i64, 4, 4]);
+        assert_eq!(array("policy").size(), [2, output_size::<4>() as i64]);
+        assert_eq!(array("mask").kind(), Kind::Bool);
+        assert!((array("weight").sum(Kind::Float).double_value(&[]) - 2.0).abs() < 1e-6);
+    }
+}
@@ -0,0 +1,241 @@
+//! Export targets to Parquet for analysis with pandas, polars, or DuckDB.
+//!
+//! Every row is one target with these columns:
+//!
+//! | column      | type            | meaning                                                  |
+//! |-------------|-----------------|----------------------------------------------------------|
+//! | `tps`       | string          | position                                                 |
+//! | `ply`       | uint16          | plies played since the start of the game                 |
+//! | `white`     | bool            | whether white is to move                                 |
+//! | `value`     | float32         | value target, from the perspective of the player to move |
+//! | `ube`       | float32         | uncertainty target                                       |
+//! | `weight`    | float32         | sample weight, 1 unless the target has another           |
+//! | `moves`     | list of string  | legal moves in PTN                                       |
+//! | `policy`    | list of float32 | search policy for each of `moves`                        |
+//! | `best_move` | string          | move with the highest search policy                      |
+//! | `entropy`   | float32         | entropy of the search policy in nats                     |
+//! | `features`  | list of float32 | position features, named in `Features::NAMES`            |
+//!
+//! Targets are read lazily and written in row groups, so files larger than
+//! memory can be exported.
+
+use std::{fs::File, path::PathBuf, sync::Arc};
+
+use arrow_array::{
+    builder::{BooleanBuilder, Float32Builder, ListBuilder, StringBuilder, UInt16Builder},
+    ArrayRef,
+    RecordBatch,
+};
+use arrow_schema::{ArrowError, DataType, Field, Schema, SchemaRef};
+use clap::Parser;
+use fast_tak::{
+    takparse::{Color, Tps},
+    Game,
+    Reserves,
+};
+use parquet::{arrow::ArrowWriter, basic::Compression, file::properties::WriterProperties};
+use takzero::{
+    features::Features,
+    target::{get_targets, Target},
+};
+
+#[derive(Parser, Debug)]
+struct Args {
+    /// Target files to export
+    #[arg(required = true)]
+    input: Vec<PathBuf>,
+    /// Parquet file to write
+    #[arg(long)]
+    output: PathBuf,
+    #[arg(long, default_value_t = 6)]
+    size: usize,
+    #[arg(long, default_value_t = 4)]
+    half_komi: i8,
+    /// Number of targets per row group
+    #[arg(long, default_value_t = 65_536)]
+    row_group: usize,
+}
+
+macro_rules! dispatch {
+    ($args:expr; $($size:literal),*) => {
+        match ($args.size, $args.half_komi) {
+            $(
+                ($size, 0) => export::<$size, 0>(&$args),
+                ($size, 4) => export::<$size, 4>(&$args),
+            )*
+            (size, half_komi) => {
+                log::error!("size {size} with half komi {half_komi} is not supported");
+                Ok(())
+            }
+        }
+    };
+}
+
+fn main() {
+    takzero::logging::init();
+    let args = Args::parse();
+    if let Err(err) = dispatch!(args; 3, 4, 5, 6, 7, 8) {
+        log::error!("{err}");
+    }
+}
+
+fn schema() -> SchemaRef {
+    let list = |item| DataType::List(Arc::new(Field::new("item", item, true)));
+    Arc::new(Schema::new(vec![
+        Field::new("tps", DataType::Utf8, false),
+        Field::new("ply", DataType::UInt16, false),
+        Field::new("white", DataType::Boolean, false),
+        Field::new("value", DataType::Float32, false),
+        Field::new("ube", DataType::Float32, false),
+        Field::new("weight", DataType::Float32, false),
+        Field::new("moves", list(DataType::Utf8), false),
+        Field::new("policy", list(DataType::Float32), false),
+        Field::new("best_move", DataType::Utf8, true),
+        Field::new("entropy", DataType::Float32, false),
+        Field::new("features", list(DataType::Float32), false),
+    ]))
+}
+
+/// Columns of the row group which is being built.
+#[derive(Default)]
+struct Columns {
+    tps: StringBuilder,
+    ply: UInt16Builder,
+    white: BooleanBuilder,
+    value: Float32Builder,
+    ube: Float32Builder,
+    weight: Float32Builder,
+    moves: ListBuilder<StringBuilder>,
+    policy: ListBuilder<Float32Builder>,
+    best_move: StringBuilder,
+    entropy: Float32Builder,
+    features: ListBuilder<Float32Builder>,
+    rows: usize,
+}
+
+impl Columns {
+    fn push<const N: usize, const HALF_KOMI: i8>(&mut self, target: &Target<Game<N, HALF_KOMI>>)
+    where
+        Reserves<N>: Default,
+    {
+        self.tps.append_value(Tps::from(target.env.clone()).to_string());
+        self.ply.append_value(target.env.ply);
+        self.white.append_value(target.env.to_move == Color::White);
+        self.value.append_value(target.value);
+        self.ube.append_value(target.ube);
+        self.weight.append_value(target.weight.unwrap_or(1.0));
+        for (action, p) in target.policy.iter() {
+            self.moves.values().append_value(action.to_string());
+            self.policy.values().append_value(p.into_inner());
+        }
+        self.moves.append(true);
+        self.policy.append(true);
+        self.best_move.append_option(
+            target
+                .policy
+                .iter()
+                .max_by_key(|(_, p)| *p)
+                .map(|(action, _)| action.to_string()),
+        );
+        let entropy: f32 = target
+            .policy
+            .iter()
+            .map(|(_, p)| p.into_inner())
+            .filter(|p| *p > 0.0)
+            .map(|p| -p * p.ln())
+            .sum();
+        self.entropy.append_value(entropy);
+        self.features
+            .values()
+            .append_slice(&Features::new(&target.env).to_vec());
+        self.features.append(true);
+        self.rows += 1;
+    }
+
+    /// Take the rows built so far as a record batch.
+    fn finish(&mut self, schema: SchemaRef) -> Result<RecordBatch, ArrowError> {
+        let columns: Vec<ArrayRef> = vec![
+            Arc::new(self.tps.finish()),
+            Arc::new(self.ply.finish()),
+            Arc::new(self.white.finish()),
+            Arc::new(self.value.finish()),
+            Arc::new(self.ube.finish()),
+            Arc::new(self.weight.finish()),
+            Arc::new(self.moves.finish()),
+            Arc::new(self.policy.finish()),
+            Arc::new(self.best_move.finish()),
+            Arc::new(self.entropy.finish()),
+            Arc::new(self.features.finish()),
+        ];
+        self.rows = 0;
+        RecordBatch::try_new(schema, columns)
+    }
+}
+
+fn export<const N: usize, const HALF_KOMI: i8>(
+    args: &Args,
+) -> Result<(), Box<dyn std::error::Error>>
+where
+    Reserves<N>: Default,
+{
+    let schema = schema();
+    let properties = WriterProperties::builder()
+        .set_compression(Compression::SNAPPY)
+        .set_max_row_group_size(args.row_group)
+        .build();
+    let file = File::create(&args.output)?;
+    let mut writer = ArrowWriter::try_new(file, schema.clone(), Some(properties))?;
+
+    let mut columns = Columns::default();
+    let mut total = 0;
+    for path in &args.input {
+        for target in get_targets::<N, HALF_KOMI>(path)? {
+            columns.push(&target);
+            if columns.rows >= args.row_group {
+                total += columns.rows;
+                writer.write(&columns.finish(schema.clone())?)?;
+            }
+        }
+    }
+    total += columns.rows;
+    writer.write(&columns.finish(schema)?)?;
+    writer.close()?;
+    log::info!("exported {total} targets to {}", args.output.display());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use fast_tak::Game;
+    use ordered_float::NotNan;
+    use takzero::{search::env::Environment, target::Target};
+
+    use super::{schema, Columns};
+
+    #[test]
+    fn columns_match_schema() {
+        let env = Game::<3, 0>::default();
+        let mut actions = Vec::new();
+        env.populate_actions(&mut actions);
+        let target = Target {
+            policy: actions
+                .iter()
+                .enumerate()
+                .map(|(i, a)| (*a, NotNan::new(if i == 0 { 1.0 } else { 0.0 }).unwrap()))
+                .collect(),
+            env,
+            value: 0.5,
+            ube: 0.0,
+            weight: None,
+            move_limit: None,
+            model_steps: None,
+        };
+
+        let mut columns = Columns::default();
+        columns.push(&target);
+        columns.push(&target);
+        let batch = columns.finish(schema()).unwrap();
+        assert_eq!(batch.num_rows(), 2);
+        assert_eq!(columns.rows, 0);
+    }
+}
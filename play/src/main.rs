@@ -0,0 +1,259 @@
+use std::{
+    fmt::Write as _,
+    io::{BufRead, Write as _},
+    path::PathBuf,
+};
+
+use clap::{Parser, ValueEnum};
+use fast_tak::{
+    takparse::{Color, Move, Tps},
+    Game,
+    Reserves,
+};
+use takzero::{
+    network::{net4_simhash, net6_simhash, Network},
+    ptn::{ninja_url, to_ptn, Outcome},
+    search::{
+        agent::{simple::Simple, Agent},
+        env::Environment,
+        node::Node,
+        DISCOUNT_FACTOR,
+    },
+    target::Replay,
+};
+use tch::Device;
+
+const BETA: f32 = 0.0;
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+enum Side {
+    White,
+    Black,
+}
+
+#[derive(Parser, Debug)]
+struct Args {
+    /// Checkpoint to play against (a simple heuristic plays without one)
+    #[arg(long)]
+    model_path: Option<PathBuf>,
+    /// Board size (checkpoints exist for 4x4 and 6x6)
+    #[arg(long, default_value_t = 6)]
+    size: usize,
+    /// Half komi of the game
+    #[arg(long, default_value_t = 4)]
+    half_komi: i8,
+    /// Color you play
+    #[arg(long, value_enum, default_value_t = Side::White)]
+    color: Side,
+    /// Simulations per engine move
+    #[arg(long, default_value_t = 800)]
+    visits: u32,
+    /// Starting position written as TPS
+    #[arg(long)]
+    tps: Option<Tps>,
+    /// Run the network on the CPU
+    #[arg(long)]
+    cpu: bool,
+}
+
+macro_rules! with_network {
+    ($args:expr, $path:expr, $device:expr; $($net:ident),*) => {
+        match ($args.size, $args.half_komi) {
+            $(
+                ($net::N, $net::HALF_KOMI) => match $net::Net::load_partial($path, $device) {
+                    Ok(net) => {
+                        tch::no_grad(|| play::<{ $net::N }, { $net::HALF_KOMI }, _>(&$args, &net));
+                    }
+                    Err(err) => log::error!("could not load {}: {err}", $path.display()),
+                },
+            )*
+            (size, half_komi) => {
+                log::error!("there is no network for size {size} with half komi {half_komi}");
+            }
+        }
+    };
+}
+
+macro_rules! with_heuristic {
+    ($args:expr; $($size:literal),*) => {
+        match ($args.size, $args.half_komi) {
+            $(
+                ($size, 0) => play::<$size, 0, _>(&$args, &Simple),
+                ($size, 4) => play::<$size, 4, _>(&$args, &Simple),
+            )*
+            (size, half_komi) => {
+                log::error!("size {size} with half komi {half_komi} is not supported");
+            }
+        }
+    };
+}
+
+fn main() {
+    takzero::logging::init();
+    let args = Args::parse();
+    if let Some(tps) = &args.tps {
+        if tps.size() != args.size {
+            log::error!("the TPS is for size {}, but the size is {}", tps.size(), args.size);
+            return;
+        }
+    }
+
+    let device = if args.cpu { Device::Cpu } else { Device::cuda_if_available() };
+    match &args.model_path {
+        Some(path) => with_network!(args, path, device; net4_simhash, net6_simhash),
+        None => with_heuristic!(args; 3, 4, 5, 6, 7, 8),
+    }
+}
+
+fn play<const N: usize, const HALF_KOMI: i8, A: Agent<Game<N, HALF_KOMI>>>(args: &Args, agent: &A)
+where
+    Reserves<N>: Default,
+{
+    let start: Game<N, HALF_KOMI> = args.tps.clone().map(Game::from).unwrap_or_default();
+    let human = match args.color {
+        Side::White => Color::White,
+        Side::Black => Color::Black,
+    };
+    let mut env = start.clone();
+    let mut moves = Vec::new();
+    let mut node = Node::default();
+    let mut input = String::new();
+
+    println!("enter moves in PTN, `undo` to take back your last move, or `quit`");
+    loop {
+        println!("{}", render(&Tps::from(env.clone())));
+        let terminal = env.terminal();
+        let game_over = terminal.is_some();
+        if let Some(terminal) = terminal {
+            let outcome = Outcome::from_terminal(terminal, env.to_move);
+            println!("game over: {}", outcome.result_with_reason(env.terminal_reason()));
+            let mut replay = Replay::new(start.clone());
+            for &action in &moves {
+                replay.push(action);
+            }
+            println!("{}", ninja_url(&to_ptn(&replay, &[], Some(outcome))));
+        } else if env.to_move != human {
+            for _ in 0..args.visits {
+                node.simulate_simple(agent, env.clone(), BETA, DISCOUNT_FACTOR);
+                if node.evaluation().is_known() {
+                    break;
+                }
+            }
+            let action = node.select_best_action();
+            let principal_variation: Vec<_> =
+                node.principal_variation().map(|a| a.to_string()).collect();
+            println!(
+                "engine plays {action} (value {}, pv {})",
+                node.evaluation(),
+                principal_variation.join(" ")
+            );
+            node.descend(&action);
+            env.step(action);
+            moves.push(action);
+            continue;
+        }
+
+        input.clear();
+        print!(">>> ");
+        std::io::stdout().flush().unwrap();
+        if std::io::stdin().lock().read_line(&mut input).unwrap() == 0 {
+            return;
+        }
+        match input.trim() {
+            "" => {}
+            "quit" | "exit" => return,
+            "undo" => {
+                if moves.pop().is_none() {
+                    println!("nothing to undo");
+                    continue;
+                }
+                // Also take back the engine's reply, so that it is your turn again.
+                env = replayed(&start, &moves);
+                while env.to_move != human && moves.pop().is_some() {
+                    env = replayed(&start, &moves);
+                }
+                node = Node::default();
+            }
+            _ if game_over => println!("the game is over, `undo` or `quit`"),
+            text => match text.parse::<Move>() {
+                Ok(action) => match env.check(&action) {
+                    Ok(()) => {
+                        env.step(action);
+                        node.descend(&action);
+                        moves.push(action);
+                    }
+                    Err(err) => println!("illegal move: {err}"),
+                },
+                Err(err) => println!("could not parse `{text}`: {err}"),
+            },
+        }
+    }
+}
+
+/// The position after playing `moves` from `start`.
+fn replayed<const N: usize, const HALF_KOMI: i8>(
+    start: &Game<N, HALF_KOMI>,
+    moves: &[Move],
+) -> Game<N, HALF_KOMI>
+where
+    Reserves<N>: Default,
+{
+    let mut env = start.clone();
+    for &action in moves {
+        env.step(action);
+    }
+    env
+}
+
+/// Draw the board of a position, with stacks written bottom to top as in TPS.
+fn render(tps: &Tps) -> String {
+    let text = tps.to_string();
+    let board = text.split_whitespace().next().unwrap_or_default();
+    let rows: Vec<Vec<String>> = board
+        .split('/')
+        .map(|row| {
+            row.split(',')
+                .flat_map(|square| match square.strip_prefix('x') {
+                    Some(count) => vec![".".to_string(); count.parse().unwrap_or(1)],
+                    None => vec![square.to_string()],
+                })
+                .collect()
+        })
+        .collect();
+    let width = rows.iter().flatten().map(String::len).max().unwrap_or(1);
+
+    let mut out = String::new();
+    let mut line = String::new();
+    for (row, rank) in rows.iter().zip((1..=rows.len()).rev()) {
+        line.clear();
+        write!(line, "{rank} ").unwrap();
+        for square in row {
+            write!(line, " {square:<width$}").unwrap();
+        }
+        writeln!(out, "{}", line.trim_end()).unwrap();
+    }
+    line.clear();
+    line.push_str("  ");
+    for file in (b'a'..).take(rows.len()) {
+        write!(line, " {:<width$}", char::from(file)).unwrap();
+    }
+    write!(out, "{}\ntps: {text}", line.trim_end()).unwrap();
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::render;
+
+    #[test]
+    fn render_board() {
+        let tps = "x2,12S/x,1,x/2C,x2 1 4".parse().unwrap();
+        let expected = "\
+3  .   .   12S
+2  .   1   .
+1  2C  .   .
+   a   b   c
+tps: x2,12S/x,1,x/2C,x2 1 4";
+        assert_eq!(render(&tps), expected);
+    }
+}
@@ -0,0 +1,293 @@
+use std::{
+    io::{BufRead, BufReader, Write},
+    net::TcpStream,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use clap::Parser;
+use fast_tak::takparse::{Color, Move};
+use protocol::{format_move, GameStart, Message, Seek};
+use takzero::{
+    network::{
+        net6_simhash::{Env, Net, HALF_KOMI, N},
+        Network,
+    },
+    search::{env::Environment, node::Node, DISCOUNT_FACTOR},
+    time_manager::{TimeControl, TimeManager},
+};
+
+mod protocol;
+
+const PING_INTERVAL: Duration = Duration::from_secs(30);
+
+#[derive(Parser, Debug)]
+struct Args {
+    /// Path to the model
+    #[arg(long)]
+    model: PathBuf,
+    /// Address of the PlayTak server
+    #[arg(long, default_value = "playtak.com:10000")]
+    address: String,
+    /// Bot account name
+    #[arg(long)]
+    user: String,
+    /// Bot account password (defaults to the `PLAYTAK_PASSWORD` variable)
+    #[arg(long)]
+    password: Option<String>,
+    /// Post a seek whenever the bot is not playing
+    #[arg(long)]
+    seek: bool,
+    /// Time control of the posted seek, for example `900+10` (in seconds)
+    #[arg(long, default_value = "900+10")]
+    time_control: TimeControl,
+    /// Accept seeks by other players for board size 6 with the right komi
+    #[arg(long)]
+    accept: bool,
+    #[arg(long, default_value_t = 0.0)]
+    beta: f32,
+}
+
+struct Game {
+    id: u32,
+    color: Color,
+    env: Env,
+    node: Node<Env>,
+    white_time: Duration,
+    black_time: Duration,
+    increment: Duration,
+}
+
+fn main() {
+    takzero::logging::init();
+    let args = Args::parse();
+    let Some(password) = args
+        .password
+        .clone()
+        .or_else(|| std::env::var("PLAYTAK_PASSWORD").ok())
+    else {
+        log::error!("password must be given with --password or PLAYTAK_PASSWORD");
+        return;
+    };
+
+    let net = match Net::load_partial(&args.model, tch::Device::Cuda(0)) {
+        Ok(net) => net,
+        Err(err) => {
+            log::error!("failed to load model: {err}");
+            return;
+        }
+    };
+
+    let stream = match TcpStream::connect(&args.address) {
+        Ok(stream) => stream,
+        Err(err) => {
+            log::error!("could not connect to {}: {err}", args.address);
+            return;
+        }
+    };
+    let reader = BufReader::new(stream.try_clone().expect("stream should be cloneable"));
+    let writer = Arc::new(Mutex::new(stream));
+
+    // Keep the connection alive, even while searching.
+    let ping_writer = writer.clone();
+    std::thread::spawn(move || loop {
+        std::thread::sleep(PING_INTERVAL);
+        if send(&ping_writer, "PING").is_err() {
+            break;
+        }
+    });
+
+    if let Err(err) = send(&writer, "Client TakZero") {
+        log::error!("could not send message: {err}");
+        return;
+    }
+    let mut game: Option<Game> = None;
+    // Increment of the game which is about to start.
+    let mut pending_increment = args.time_control.increment;
+
+    for line in reader.lines() {
+        let line = match line {
+            Ok(line) => line,
+            Err(err) => {
+                log::error!("connection lost: {err}");
+                return;
+            }
+        };
+        let message = match line.trim().parse() {
+            Ok(message) => message,
+            Err(err) => {
+                log::warn!("could not parse `{line}`: {err}");
+                continue;
+            }
+        };
+
+        let response = match message {
+            Message::LoginPrompt => Some(format!("Login {} {password}", args.user)),
+            Message::LoggedIn(name) => {
+                log::info!("logged in as {name}");
+                args.seek.then(|| seek(&args.time_control))
+            }
+            Message::LoginFailed => {
+                log::error!("login failed");
+                return;
+            }
+            Message::SeekNew(Seek {
+                id,
+                name,
+                size,
+                increment,
+                half_komi,
+                ..
+            }) if args.accept
+                && game.is_none()
+                && size == N
+                && half_komi == HALF_KOMI
+                && name != args.user =>
+            {
+                log::info!("accepting seek {id} by {name}");
+                pending_increment = increment;
+                Some(format!("Accept {id}"))
+            }
+            Message::GameStart(GameStart {
+                id,
+                size,
+                white,
+                black,
+                color,
+                time,
+                half_komi,
+            }) => {
+                log::info!("game {id} started: {white} vs. {black}");
+                if size != N || half_komi != HALF_KOMI {
+                    log::error!("size {size} with half komi {half_komi} is not supported");
+                    Some(format!("Game#{id} Resign"))
+                } else {
+                    let mut new_game = Game {
+                        id,
+                        color,
+                        env: Env::default(),
+                        node: Node::default(),
+                        white_time: time,
+                        black_time: time,
+                        increment: pending_increment,
+                    };
+                    pending_increment = args.time_control.increment;
+                    let response = if color == Color::White {
+                        think(&net, &mut new_game, &args)
+                    } else {
+                        None
+                    };
+                    game = Some(new_game);
+                    response
+                }
+            }
+            Message::Move { game: id, the_move } => match &mut game {
+                Some(game) if game.id == id => match game.env.play(the_move) {
+                    Ok(()) => {
+                        game.node.descend(&the_move);
+                        if game.env.terminal().is_some() {
+                            // The server will follow up with the result.
+                            log::info!("game {id} ended with {the_move}");
+                            None
+                        } else if game.env.to_move == game.color {
+                            think(&net, game, &args)
+                        } else {
+                            None
+                        }
+                    }
+                    Err(err) => {
+                        // The game no longer matches the one on the server, so
+                        // no move of ours would be right.
+                        log::error!("could not play move {the_move}: {err}, resigning");
+                        Some(format!("Game#{id} Resign"))
+                    }
+                },
+                _ => None,
+            },
+            Message::Time {
+                game: id,
+                white,
+                black,
+            } => {
+                if let Some(game) = game.as_mut().filter(|game| game.id == id) {
+                    game.white_time = white;
+                    game.black_time = black;
+                }
+                None
+            }
+            Message::Over { game: id, result }
+                if game.as_ref().is_some_and(|game| game.id == id) =>
+            {
+                log::info!("game {id} is over: {result}");
+                game = None;
+                args.seek.then(|| seek(&args.time_control))
+            }
+            Message::Abandoned { game: id } if game.as_ref().is_some_and(|game| game.id == id) => {
+                log::info!("game {id} was abandoned");
+                game = None;
+                args.seek.then(|| seek(&args.time_control))
+            }
+            _ => None,
+        };
+
+        if let Some(response) = response {
+            if let Err(err) = send(&writer, &response) {
+                log::error!("could not send message: {err}");
+                return;
+            }
+        }
+    }
+}
+
+/// Search the current position under the game clock and return the message
+/// with the chosen move, or `None` if there is no move to choose.
+fn think(net: &Net, game: &mut Game, args: &Args) -> Option<String> {
+    let remaining = match game.color {
+        Color::White => game.white_time,
+        Color::Black => game.black_time,
+    };
+    let time_manager = TimeManager::default();
+    let mut timer = None;
+    let mut visits = 0;
+    loop {
+        game.node.simulate_simple(net, game.env.clone(), args.beta, DISCOUNT_FACTOR);
+        visits += 1;
+        let timer = timer.get_or_insert_with(|| {
+            let forced = game.node.children().len() == 1 || game.node.evaluation().is_known();
+            time_manager.start(remaining, game.increment, forced)
+        });
+        match game.node.try_select_best_action() {
+            Ok(best) => timer.observe_best(best),
+            Err(err) => {
+                log::error!("could not search game {}: {err}", game.id);
+                return None;
+            }
+        }
+        if timer.should_stop() || game.node.evaluation().is_known() {
+            break;
+        }
+    }
+
+    let the_move: Move = game.node.try_select_best_action().ok()?;
+    log::info!("playing {the_move} after {visits} visits, eval {}", game.node.evaluation());
+    game.env
+        .play(the_move)
+        .expect("the searched move should be legal");
+    game.node.descend(&the_move);
+    Some(format!("Game#{} {}", game.id, format_move(the_move)))
+}
+
+fn seek(time_control: &TimeControl) -> String {
+    format!(
+        "Seek {N} {} {} A {HALF_KOMI} 30 1 0 0",
+        time_control.initial.as_secs(),
+        time_control.increment.as_secs()
+    )
+}
+
+fn send(writer: &Mutex<TcpStream>, message: &str) -> std::io::Result<()> {
+    log::debug!("> {message}");
+    let mut stream = writer.lock().expect("writer lock should not be poisoned");
+    writeln!(stream, "{message}")
+}
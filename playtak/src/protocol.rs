@@ -0,0 +1,268 @@
+// Only the parts of the PlayTak server protocol which a bot needs.
+
+use std::{num::ParseIntError, str::FromStr, time::Duration};
+
+use fast_tak::takparse::{Color, Direction, Move, MoveKind, Piece, Square};
+use takzero::import::{parse_playtak_move, ParsePlayTakMoveError};
+use thiserror::Error;
+
+/// A message from the server.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Message {
+    /// The server wants us to log in.
+    LoginPrompt,
+    LoggedIn(String),
+    LoginFailed,
+    SeekNew(Seek),
+    SeekRemove(u32),
+    GameStart(GameStart),
+    Move { game: u32, the_move: Move },
+    Time { game: u32, white: Duration, black: Duration },
+    Over { game: u32, result: String },
+    Abandoned { game: u32 },
+    Other,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Seek {
+    pub id: u32,
+    pub name: String,
+    pub size: usize,
+    pub time: Duration,
+    pub increment: Duration,
+    /// The color the seeker wants to play, if any.
+    pub color: Option<Color>,
+    pub half_komi: i8,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GameStart {
+    pub id: u32,
+    pub size: usize,
+    pub white: String,
+    pub black: String,
+    /// The color we are playing.
+    pub color: Color,
+    pub time: Duration,
+    pub half_komi: i8,
+}
+
+#[derive(Debug, Error)]
+pub enum ParseMessageError {
+    #[error("message `{0}` is missing a field")]
+    MissingField(String),
+    #[error("could not parse number: {0}")]
+    ParseInt(#[from] ParseIntError),
+    #[error("could not parse move: {0}")]
+    ParseMove(#[from] ParsePlayTakMoveError),
+    #[error("unknown color `{0}`")]
+    Color(String),
+}
+
+impl FromStr for Message {
+    type Err = ParseMessageError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut words = s.split_whitespace();
+        let mut next = || {
+            words
+                .next()
+                .ok_or_else(|| ParseMessageError::MissingField(s.to_string()))
+        };
+        Ok(match next()? {
+            "Login" if s.starts_with("Login or Register") => Self::LoginPrompt,
+            "Welcome!" => Self::LoginPrompt,
+            "Welcome" => Self::LoggedIn(next()?.trim_end_matches('!').to_string()),
+            "Authentication" => Self::LoginFailed,
+            "Seek" => match next()? {
+                "new" => Self::SeekNew(Seek {
+                    id: next()?.parse()?,
+                    name: next()?.to_string(),
+                    size: next()?.parse()?,
+                    time: Duration::from_secs(next()?.parse()?),
+                    increment: Duration::from_secs(next()?.parse()?),
+                    color: match next()? {
+                        "W" => Some(Color::White),
+                        "B" => Some(Color::Black),
+                        _ => None,
+                    },
+                    half_komi: next().map_or(Ok(0), str::parse)?,
+                }),
+                "remove" => Self::SeekRemove(next()?.parse()?),
+                _ => Self::Other,
+            },
+            "Game" if s.starts_with("Game Start") => {
+                next()?;
+                let id = next()?.parse()?;
+                let size = next()?.parse()?;
+                let white = next()?.to_string();
+                next()?; // vs
+                let black = next()?.to_string();
+                let color = match next()? {
+                    "white" => Color::White,
+                    "black" => Color::Black,
+                    color => return Err(ParseMessageError::Color(color.to_string())),
+                };
+                Self::GameStart(GameStart {
+                    id,
+                    size,
+                    white,
+                    black,
+                    color,
+                    time: Duration::from_secs(next()?.parse()?),
+                    half_komi: next().map_or(Ok(0), str::parse)?,
+                })
+            }
+            word if word.starts_with("Game#") => {
+                let game = word.trim_start_matches("Game#").parse()?;
+                match next()? {
+                    "P" | "M" => Self::Move {
+                        game,
+                        the_move: parse_playtak_move(
+                            s.trim_start().split_once(' ').map_or("", |(_, rest)| rest),
+                        )?,
+                    },
+                    "Time" => Self::Time {
+                        game,
+                        white: Duration::from_secs(next()?.parse()?),
+                        black: Duration::from_secs(next()?.parse()?),
+                    },
+                    "Over" => Self::Over {
+                        game,
+                        result: next()?.to_string(),
+                    },
+                    "Abandoned" | "Abandoned." => Self::Abandoned { game },
+                    _ => Self::Other,
+                }
+            }
+            _ => Self::Other,
+        })
+    }
+}
+
+fn format_square(square: Square) -> String {
+    format!("{}{}", (b'A' + square.column()) as char, square.row() + 1)
+}
+
+/// Format a move for the server, like `P A1 C` or `M A1 A3 1 2`.
+#[must_use]
+pub fn format_move(the_move: Move) -> String {
+    let from = the_move.square();
+    match the_move.kind() {
+        MoveKind::Place(piece) => {
+            let piece = match piece {
+                Piece::Flat => "",
+                Piece::Wall => " W",
+                Piece::Cap => " C",
+            };
+            format!("P {}{piece}", format_square(from))
+        }
+        MoveKind::Spread(direction, _) => {
+            // The drop counts are whatever follows the direction in PTN.
+            let ptn = the_move.to_string();
+            let drops: Vec<u32> = ptn
+                .rsplit(['+', '-', '<', '>'])
+                .next()
+                .unwrap_or_default()
+                .chars()
+                .filter_map(|c| c.to_digit(10))
+                .collect();
+            let drops = if drops.is_empty() {
+                // A single drop of everything which was picked up.
+                let count = ptn.chars().next().and_then(|c| c.to_digit(10));
+                vec![count.unwrap_or(1)]
+            } else {
+                drops
+            };
+            let distance = drops.len() as u8;
+            let to = match direction {
+                Direction::Up => Square::new(from.column(), from.row() + distance),
+                Direction::Down => Square::new(from.column(), from.row() - distance),
+                Direction::Right => Square::new(from.column() + distance, from.row()),
+                Direction::Left => Square::new(from.column() - distance, from.row()),
+            };
+            let drops: Vec<_> = drops.iter().map(ToString::to_string).collect();
+            format!("M {} {} {}", format_square(from), format_square(to), drops.join(" "))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use fast_tak::takparse::{Color, Move};
+
+    use super::{format_move, GameStart, Message, Seek};
+
+    #[test]
+    fn moves_roundtrip() {
+        for (playtak, ptn) in [
+            ("P A1", "a1"),
+            ("P C3 W", "Sc3"),
+            ("P F6 C", "Cf6"),
+            ("M A1 A2 1", "a1+"),
+            ("M C3 A3 2 1", "3c3<21"),
+            ("M B4 B2 2 2", "4b4-22"),
+            ("M A1 B1 3", "3a1>"),
+        ] {
+            let message: Message = format!("Game#7 {playtak}").parse().unwrap();
+            let the_move: Move = ptn.parse().unwrap();
+            assert_eq!(message, Message::Move { game: 7, the_move });
+            assert_eq!(format_move(the_move), playtak);
+        }
+    }
+
+    #[test]
+    fn parse_messages() {
+        assert_eq!("Login or Register".parse::<Message>().unwrap(), Message::LoginPrompt);
+        assert_eq!(
+            "Welcome takzero!".parse::<Message>().unwrap(),
+            Message::LoggedIn("takzero".to_string())
+        );
+        assert_eq!(
+            "Seek new 12 alice 6 900 10 A 4 30 1 0 0"
+                .parse::<Message>()
+                .unwrap(),
+            Message::SeekNew(Seek {
+                id: 12,
+                name: "alice".to_string(),
+                size: 6,
+                time: Duration::from_secs(900),
+                increment: Duration::from_secs(10),
+                color: None,
+                half_komi: 4,
+            })
+        );
+        assert_eq!(
+            "Game Start 5 6 alice vs takzero black 900 4 30 1"
+                .parse::<Message>()
+                .unwrap(),
+            Message::GameStart(GameStart {
+                id: 5,
+                size: 6,
+                white: "alice".to_string(),
+                black: "takzero".to_string(),
+                color: Color::Black,
+                time: Duration::from_secs(900),
+                half_komi: 4,
+            })
+        );
+        assert_eq!(
+            "Game#5 Time 850 870".parse::<Message>().unwrap(),
+            Message::Time {
+                game: 5,
+                white: Duration::from_secs(850),
+                black: Duration::from_secs(870),
+            }
+        );
+        assert_eq!(
+            "Game#5 Over R-0".parse::<Message>().unwrap(),
+            Message::Over {
+                game: 5,
+                result: "R-0".to_string(),
+            }
+        );
+        assert_eq!("Shout <bob> hello".parse::<Message>().unwrap(), Message::Other);
+    }
+}
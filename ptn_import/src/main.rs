@@ -0,0 +1,286 @@
+use std::{
+    io::{BufWriter, Write},
+    path::{Path, PathBuf},
+};
+
+use clap::{Parser, ValueEnum};
+use fast_tak::Reserves;
+use sqlite::Value;
+use takzero::{
+    header::{self, FileKind, Header},
+    import::{taktician_target, PlayTakGame},
+    ptn::{split_games, PtnGame},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum Format {
+    /// PTN files or directories containing `.ptn` files
+    Ptn,
+    /// SQLite database exported by PlayTak
+    Playtak,
+    /// Taktician analysis dumps (TPS, best move, and score separated by tabs)
+    Taktician,
+}
+
+#[derive(Parser, Debug)]
+struct Args {
+    /// Files to import (and directories for PTN)
+    #[arg(required = true)]
+    input: Vec<PathBuf>,
+    #[arg(long, value_enum, default_value_t = Format::Ptn)]
+    format: Format,
+    /// File to append the replays to (Taktician dumps have no games)
+    #[arg(long, required_unless_present = "targets")]
+    replays: Option<PathBuf>,
+    /// File to append supervised targets to (one-hot policy, game outcome)
+    #[arg(long)]
+    targets: Option<PathBuf>,
+    /// Board size of the games to import, others are skipped
+    #[arg(long, default_value_t = 6)]
+    size: usize,
+    /// Half komi of the games to import, others are skipped
+    #[arg(long, default_value_t = 4)]
+    half_komi: i8,
+    /// Taktician score which is squashed to a value of tanh(1)
+    #[arg(long, default_value_t = 1000.0)]
+    taktician_scale: f32,
+    /// Sample weight of the imported targets in the loss, for example below 1
+    /// to down-weight human games
+    #[arg(long)]
+    weight: Option<f32>,
+}
+
+macro_rules! dispatch {
+    ($args:expr; $($size:literal),*) => {
+        match ($args.size, $args.half_komi) {
+            $(
+                ($size, 0) => import::<$size, 0>(&$args),
+                ($size, 4) => import::<$size, 4>(&$args),
+            )*
+            (size, half_komi) => {
+                log::error!("size {size} with half komi {half_komi} is not supported");
+                Ok(())
+            }
+        }
+    };
+}
+
+fn main() {
+    takzero::logging::init();
+    let args = Args::parse();
+    if let Err(err) = dispatch!(args; 3, 4, 5, 6, 7, 8) {
+        log::error!("{err}");
+    }
+}
+
+fn ptn_files(input: &[PathBuf]) -> std::io::Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    for path in input {
+        if path.is_dir() {
+            for entry in std::fs::read_dir(path)? {
+                let path = entry?.path();
+                if path.extension().is_some_and(|ext| ext == "ptn") {
+                    files.push(path);
+                }
+            }
+        } else {
+            files.push(path.clone());
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+fn append<const N: usize, const HALF_KOMI: i8>(
+    path: &Path,
+    kind: FileKind,
+) -> std::io::Result<BufWriter<std::fs::File>> {
+    let header = Header::new::<N, HALF_KOMI>(kind, None);
+    Ok(BufWriter::new(header::open_append(path, &header)?))
+}
+
+/// Where imported games and targets go.
+struct Output {
+    replays: Option<BufWriter<std::fs::File>>,
+    targets: Option<BufWriter<std::fs::File>>,
+    weight: Option<f32>,
+    imported: usize,
+    skipped: usize,
+    positions: usize,
+}
+
+impl Output {
+    fn game<const N: usize, const HALF_KOMI: i8>(
+        &mut self,
+        game: &PtnGame<N, HALF_KOMI>,
+    ) -> std::io::Result<()>
+    where
+        Reserves<N>: Default,
+    {
+        if let Some(replays) = &mut self.replays {
+            write!(replays, "{}", game.replay)?;
+        }
+        if let Some(targets) = &mut self.targets {
+            for mut target in game.targets().into_iter().flatten() {
+                target.weight = self.weight;
+                write!(targets, "{target}")?;
+                self.positions += 1;
+            }
+        }
+        self.imported += 1;
+        Ok(())
+    }
+}
+
+fn import<const N: usize, const HALF_KOMI: i8>(args: &Args) -> std::io::Result<()>
+where
+    Reserves<N>: Default,
+{
+    let mut output = Output {
+        replays: args
+            .replays
+            .as_deref()
+            .map(|path| append::<N, HALF_KOMI>(path, FileKind::Replays))
+            .transpose()?,
+        targets: args
+            .targets
+            .as_deref()
+            .map(|path| append::<N, HALF_KOMI>(path, FileKind::Targets))
+            .transpose()?,
+        weight: args.weight,
+        imported: 0,
+        skipped: 0,
+        positions: 0,
+    };
+    match args.format {
+        Format::Ptn => import_ptn::<N, HALF_KOMI>(args, &mut output)?,
+        Format::Playtak => {
+            for path in &args.input {
+                if let Err(err) = import_playtak::<N, HALF_KOMI>(path, &mut output) {
+                    log::error!("could not read {}: {err}", path.display());
+                }
+            }
+        }
+        Format::Taktician => import_taktician::<N, HALF_KOMI>(args, &mut output)?,
+    }
+
+    if let Some(mut replays) = output.replays {
+        replays.flush()?;
+    }
+    if let Some(mut targets) = output.targets {
+        targets.flush()?;
+    }
+    log::info!(
+        "imported {} games ({} targets), skipped {}",
+        output.imported,
+        output.positions,
+        output.skipped
+    );
+    Ok(())
+}
+
+fn import_ptn<const N: usize, const HALF_KOMI: i8>(
+    args: &Args,
+    output: &mut Output,
+) -> std::io::Result<()>
+where
+    Reserves<N>: Default,
+{
+    for path in ptn_files(&args.input)? {
+        let content = std::fs::read_to_string(&path)?;
+        for (index, game) in split_games(&content).enumerate() {
+            match game.parse::<PtnGame<N, HALF_KOMI>>() {
+                Ok(game) => output.game(&game)?,
+                Err(err) => {
+                    log::debug!("skipping game {index} in {}: {err}", path.display());
+                    output.skipped += 1;
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Import the games of one size from a PlayTak database.
+/// Games with a different komi or number of pieces are skipped.
+fn import_playtak<const N: usize, const HALF_KOMI: i8>(
+    path: &Path,
+    output: &mut Output,
+) -> Result<(), Box<dyn std::error::Error>>
+where
+    Reserves<N>: Default,
+{
+    let connection = sqlite::open(path)?;
+    let mut statement = connection.prepare(
+        "SELECT id, komi, pieces, capstones, notation, result FROM games
+        WHERE size = :size
+        ORDER BY id ASC",
+    )?;
+    statement.bind::<&[(_, Value)]>(&[(":size", (N as i64).into())])?;
+    for row in statement {
+        let row = row?;
+        let id = row.read::<i64, _>("id");
+        // Older games have no komi and a negative number of pieces for the defaults.
+        let pieces = row.read::<Option<i64>, _>("pieces").unwrap_or(-1);
+        let capstones = row.read::<Option<i64>, _>("capstones").unwrap_or(-1);
+        let game = PlayTakGame {
+            size: N,
+            half_komi: row
+                .read::<Option<i64>, _>("komi")
+                .unwrap_or_default()
+                .try_into()
+                .unwrap_or(i8::MAX),
+            reserves: (pieces >= 0 && capstones >= 0).then(|| {
+                (
+                    pieces.try_into().unwrap_or(u8::MAX),
+                    capstones.try_into().unwrap_or(u8::MAX),
+                )
+            }),
+            notation: row.read::<&str, _>("notation").to_string(),
+            result: row.read::<Option<&str>, _>("result").unwrap_or("0-0").to_string(),
+        };
+        match game.to_ptn_game::<N, HALF_KOMI>() {
+            Ok(game) => output.game(&game)?,
+            Err(err) => {
+                log::debug!("skipping game {id} in {}: {err}", path.display());
+                output.skipped += 1;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Import positions from Taktician analysis dumps as targets.
+fn import_taktician<const N: usize, const HALF_KOMI: i8>(
+    args: &Args,
+    output: &mut Output,
+) -> std::io::Result<()>
+where
+    Reserves<N>: Default,
+{
+    let Some(targets) = &mut output.targets else {
+        log::error!("Taktician dumps can only be imported as targets, use --targets");
+        return Ok(());
+    };
+    for path in &args.input {
+        let content = std::fs::read_to_string(path)?;
+        for (index, line) in content.lines().enumerate() {
+            if line.trim().is_empty() {
+                continue;
+            }
+            match taktician_target::<N, HALF_KOMI>(line, args.taktician_scale) {
+                Ok(mut target) => {
+                    target.weight = args.weight;
+                    write!(targets, "{target}")?;
+                    output.positions += 1;
+                }
+                Err(err) => {
+                    log::debug!("skipping line {} in {}: {err}", index + 1, path.display());
+                    output.skipped += 1;
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
@@ -9,7 +9,10 @@ use takzero::{
         net6_simhash::{Env, Net},
         Network,
     },
-    search::node::{batched::BatchedMCTS, Node},
+    search::{
+        node::{batched::BatchedMCTS, Node},
+        DISCOUNT_FACTOR,
+    },
 };
 use tch::Device;
 
@@ -36,7 +39,7 @@ const ZERO_BETA: [f32; BATCH_SIZE] = [0.0; BATCH_SIZE];
 const DEVICE: Device = Device::Cuda(0);
 
 fn main() {
-    env_logger::init();
+    takzero::logging::init();
     log::info!("Begin.");
     tch::no_grad(real_main);
 }
@@ -206,6 +209,7 @@ fn benchmark(
         batched_mcts.gumbel_sequential_halving(
             agent,
             &ZERO_BETA,
+            DISCOUNT_FACTOR,
             sampled_actions,
             search_budget,
             rng,
@@ -240,7 +244,7 @@ fn benchmark(
             batched_mcts
                 .nodes_and_envs()
                 .take(solution_batch.len())
-                .filter(|(node, _)| node.evaluation.is_win())
+                .filter(|(node, _)| node.evaluation().is_win())
                 .count()
         } else {
             // Count how many nodes have had all but one child solved as a win
@@ -249,11 +253,11 @@ fn benchmark(
                 .nodes_and_envs()
                 .take(solution_batch.len())
                 .filter(|(node, _)| {
-                    node.children
+                    node.children()
                         .iter()
-                        .filter(|(_, child)| child.evaluation.is_win())
+                        .filter(|(_, child)| child.evaluation().is_win())
                         .count()
-                        == node.children.len() - 1
+                        == node.children().len() - 1
                 })
                 .count()
         };
@@ -0,0 +1,414 @@
+use std::{
+    collections::HashSet,
+    fmt,
+    fs::{read_dir, OpenOptions},
+    io::{BufRead, BufReader, Write},
+    path::{Path, PathBuf},
+};
+
+use clap::Parser;
+use rand::prelude::*;
+use takzero::{
+    header::{self, FileKind, Header},
+    logging,
+    network::{
+        checkpoint::{self, CheckpointError},
+        net6_simhash::{Env, Net, HALF_KOMI, N},
+        repr::{self, InputRepr},
+        Network,
+    },
+    search::{
+        agent::Agent,
+        env::Environment,
+        node::{batched::BatchedMCTS, Node},
+        DISCOUNT_FACTOR,
+    },
+    shards::ShardCursor,
+    storage::{self, ObjectStore},
+    target::{Augment, Replay, Target},
+};
+use tch::{Device, TchError};
+use thiserror::Error;
+
+#[rustfmt::skip]
+#[allow(dead_code)] const fn assert_env<E: Environment>() where Target<E>: Augment + fmt::Display {}
+const _: () = assert_env::<Env>();
+
+// The network architecture.
+#[rustfmt::skip] #[allow(dead_code)] const fn assert_net<NET: Network + Agent<Env>>() {}
+const _: () = assert_net::<Net>();
+
+const DEVICE: Device = Device::Cuda(0);
+const BATCH_SIZE: usize = 128;
+const SAMPLED_ACTIONS: usize = 64;
+const SEARCH_BUDGET: u32 = 768;
+const ZERO_BETA: [f32; BATCH_SIZE] = [0.0; BATCH_SIZE];
+const MIN_POSITIONS: usize = 4000 * 128 / 4; // steps before reanalyze * batch size / forced uses
+const _: () = assert!(MIN_POSITIONS > BATCH_SIZE);
+const MAX_REANALYZE_BUFFER_LEN: usize = 32_000;
+
+#[cfg(feature = "exploration")]
+const MAX_EXPLORATION_BUFFER_SIZE: usize = 0;
+#[cfg(feature = "exploration")]
+const EXPLORATION_POSITIONS_IN_BATCH: usize = 0;
+#[cfg(feature = "exploration")]
+const _: () = assert!(EXPLORATION_POSITIONS_IN_BATCH <= BATCH_SIZE);
+
+const UBE_TARGET_BETA: f32 = 0.25;
+
+#[derive(Parser, Debug)]
+pub struct Args {
+    /// Directory where to find models
+    /// and also where to save targets.
+    #[arg(long)]
+    directory: PathBuf,
+    /// Name of this worker in the logs. Defaults to the process id.
+    #[arg(long)]
+    worker: Option<String>,
+    /// Encoding of positions as network inputs, like `stack-depth=6`, which
+    /// has to be the one the models were trained with.
+    #[arg(long, default_value_t)]
+    input_repr: InputRepr,
+    /// Object store to pull models and replays from and push targets to,
+    /// for example `s3://bucket/run`. Without it, the directory is shared.
+    #[arg(long)]
+    storage: Option<String>,
+}
+
+/// Run reanalysis with the given arguments. The caller initializes logging.
+///
+/// # Panics
+///
+/// Panics if the directory or the replays cannot be used.
+#[allow(clippy::too_many_lines)]
+pub fn run(args: Args) {
+    logging::set_worker_or_default(args.worker.as_deref(), "reanalyze");
+    repr::configure(args.input_repr).expect("The input encoding should only be set once");
+
+    let seed: u64 = rand::thread_rng().gen();
+    log::info!("seed = {seed}");
+    let mut rng = rand::rngs::StdRng::seed_from_u64(seed);
+
+    let mut net;
+    let mut batched_mcts = BatchedMCTS::<BATCH_SIZE, _>::new(&mut rng);
+    let mut position_buffer = Vec::new();
+    let mut replays_cursor = ShardCursor::default();
+    #[cfg(feature = "exploration")]
+    let mut exploration_buffer = Vec::new();
+    #[cfg(feature = "exploration")]
+    let mut exploration_replays_cursor = ShardCursor::default();
+    let store = args
+        .storage
+        .as_deref()
+        .map(|url| storage::open(url).expect("Storage URL should be valid"));
+    let mut pulled_shards = HashSet::new();
+
+    for batches in 0.. {
+        loop {
+            if let Some(store) = &store {
+                storage::pull_files(store.as_ref(), &args.directory, &["buffer_lengths.txt"]);
+            }
+            let reanalyze = match read_buffer_lengths(&args.directory) {
+                Ok((_, reanalyze)) => reanalyze,
+                Err(err) => {
+                    log::error!("Could not read buffer lengths: {err}");
+                    std::thread::sleep(std::time::Duration::from_secs(1));
+                    continue;
+                }
+            };
+            if reanalyze > MAX_REANALYZE_BUFFER_LEN {
+                std::thread::sleep(std::time::Duration::from_secs(1));
+                continue;
+            }
+            log::debug!("Checked that there more reanalyze targets are needed.");
+
+            if let Some(store) = &store {
+                if let Err(err) = storage::pull_model(store.as_ref(), &args.directory) {
+                    log::debug!("Could not pull the model: {err}");
+                }
+            }
+            let path = args.directory.join("model_latest.ot");
+            match checkpoint::load_verified::<Env, Net>(&path, DEVICE) {
+                Ok(new_net) => {
+                    net = new_net;
+                    if let Some(generation) = checkpoint::read_model_steps(&args.directory) {
+                        logging::set_generation(generation);
+                    }
+                    break;
+                }
+                Err(err) if err.is_corrupted() => {
+                    checkpoint::report_failure(&path, &err);
+                    if let Some(previous) = checkpoint::load_previous::<Env, Net>(&path, DEVICE) {
+                        net = previous;
+                    }
+                    break;
+                }
+                Err(CheckpointError::Torch(TchError::Torch(err))) => {
+                    log::warn!("Cannot load model (internal torch error): {err}, retrying.");
+                    std::thread::sleep(std::time::Duration::from_secs(1));
+                }
+                Err(err) => {
+                    log::error!("Cannot load model (some other reason): {err}, retrying.");
+                    std::thread::sleep(std::time::Duration::from_secs(1));
+                }
+            }
+        }
+
+        // Fill the position buffer.
+        if let Some(store) = &store {
+            pull_replay_shards(store.as_ref(), &mut position_buffer, &mut pulled_shards);
+        }
+        if let Err(err) = fill_buffer_with_positions_from_replays(
+            &mut position_buffer,
+            &mut replays_cursor,
+            &args.directory,
+            "replays",
+        ) {
+            log::error!("Cannot fill position buffer: {err}");
+        };
+
+        // Update exploration buffer.
+        #[cfg(feature = "exploration")]
+        {
+            if let Err(err) = fill_buffer_with_positions_from_replays(
+                &mut exploration_buffer,
+                &mut exploration_replays_cursor,
+                &args.directory,
+                "replays-exploration",
+            ) {
+                log::error!("Cannot fill recent buffer: {err}");
+            };
+            if exploration_buffer.len() > MAX_EXPLORATION_BUFFER_SIZE {
+                exploration_buffer
+                    .drain(..exploration_buffer.len() - MAX_EXPLORATION_BUFFER_SIZE)
+                    .for_each(drop);
+            }
+        }
+
+        if position_buffer.len() < MIN_POSITIONS {
+            let duration = std::time::Duration::from_secs(60);
+            log::info!(
+                "Not enough positions yet ({}), sleeping for {duration:?}",
+                position_buffer.len()
+            );
+            std::thread::sleep(duration);
+            continue;
+        }
+        log::debug!("Number of positions: {}", position_buffer.len());
+
+        // Sample a batch.
+        let mut batch = Vec::new();
+        #[cfg(feature = "exploration")]
+        batch.extend(
+            exploration_buffer
+                .choose_multiple(&mut rng, EXPLORATION_POSITIONS_IN_BATCH)
+                .cloned(),
+        );
+        batch.extend(
+            position_buffer
+                .choose_multiple(&mut rng, BATCH_SIZE - batch.len())
+                .cloned(),
+        );
+        batched_mcts
+            .nodes_and_envs_mut()
+            .zip(batch)
+            .for_each(|((node, env), replay_env)| {
+                *node = Node::default();
+                *env = replay_env;
+            });
+
+        // Perform search.
+        // for _ in 0..VISITS {
+        //     batched_mcts.simulate(&net, &ZERO_BETA);
+        // }
+        let selected = batched_mcts.gumbel_sequential_halving(
+            &net,
+            &ZERO_BETA,
+            DISCOUNT_FACTOR,
+            SAMPLED_ACTIONS,
+            SEARCH_BUDGET,
+            &mut rng,
+        );
+
+        // Create targets.
+        let model_steps = logging::context().generation;
+        let contents: String = batched_mcts
+            .nodes_and_envs()
+            .zip(selected)
+            .map(|((node, env), selected_action)| {
+                let value = if node.evaluation().is_known() {
+                    node.evaluation()
+                } else {
+                    node.children()
+                        .iter()
+                        .find(|(a, _)| *a == selected_action)
+                        .expect("all non-terminal nodes should have at least one child")
+                        .1
+                        .evaluation()
+                        .negate()
+                }
+                .into();
+                let policy = node
+                    .children()
+                    .iter()
+                    .map(|(a, _)| a)
+                    .zip(node.improved_policy(node.most_visited_count(), DISCOUNT_FACTOR))
+                    .collect(); // policy_target_from_proportional_visits(node);
+                let ube = node.ube_target(UBE_TARGET_BETA, DISCOUNT_FACTOR).into_inner();
+
+                // Log UBE statistics.
+                // let root = node.std_dev * node.std_dev;
+                // let max_std_dev = node
+                //     .children
+                //     .iter()
+                //     .map(|(_, child)| child.std_dev)
+                //     .max()
+                //     .unwrap_or_default();
+                // let max = max_std_dev * max_std_dev;
+                // log::debug!(
+                //     "[UBE STATS] ply: {}, bf: {}, root: {root:.5}, max: {max:.5}, target:
+                // {ube:.5}",     env.ply,
+                //     policy.len()
+                // );
+
+                Target {
+                    env: env.clone(),
+                    policy,
+                    value,
+                    ube,
+                    weight: None,
+                    move_limit: None,
+                    model_steps,
+                }
+                .to_string()
+            })
+            .collect();
+
+        // Save targets to file.
+        let header = Header::new::<N, HALF_KOMI>(FileKind::Targets, logging::context().generation);
+        if let Err(err) = header::open_append(args.directory.join("targets-reanalyze.txt"), &header)
+            .and_then(|mut file| file.write_all(contents.as_bytes()))
+        {
+            log::error!(
+                "Could not save targets to file [{err}], so here they are instead:\n{contents}"
+            );
+        } else {
+            log::info!("Saved targets to file.");
+        }
+        if let Some(store) = &store {
+            let worker = logging::context().worker.unwrap_or_default();
+            let key = format!("targets-reanalyze/{worker}-{batches:0>7}.txt");
+            if let Err(err) = storage::push_shard(store.as_ref(), &key, contents.as_bytes()) {
+                log::error!("Could not push {key}: {err}");
+            }
+        }
+    }
+}
+
+/// Get the path to the model file (ending with ".ot")
+/// which has the highest number of steps (number after '_')
+/// in the given directory.
+#[allow(unused)]
+fn get_model_path_with_most_steps(directory: &PathBuf) -> Option<(u32, PathBuf)> {
+    read_dir(directory)
+        .unwrap()
+        .filter_map(|res| res.ok().map(|entry| entry.path()))
+        .filter(|p| p.extension().is_some_and(|ext| ext == "ot"))
+        .filter_map(|p| {
+            Some((
+                p.file_stem()?
+                    .to_str()?
+                    .split_once('_')?
+                    .1
+                    .parse::<u32>()
+                    .ok()?,
+                p,
+            ))
+        })
+        .max_by_key(|(s, _)| *s)
+}
+
+/// Fill the buffer with new positions from the replay shards.
+fn fill_buffer_with_positions_from_replays(
+    buffer: &mut Vec<Env>,
+    cursor: &mut ShardCursor,
+    directory: &Path,
+    prefix: &str,
+) -> std::io::Result<()> {
+    cursor.read_new_lines(directory, prefix, |line| {
+        if let Ok(replay) = line.parse::<Replay<Env>>() {
+            buffer.extend(replay.states());
+        }
+    })
+}
+
+/// Add the positions of the replay shards which self-play pushed and which
+/// were not pulled yet to the buffer.
+fn pull_replay_shards(
+    store: &dyn ObjectStore,
+    buffer: &mut Vec<Env>,
+    pulled: &mut HashSet<String>,
+) {
+    let result = storage::pull_new_shards(store, "replays", pulled, |data| {
+        for line in String::from_utf8_lossy(data).lines() {
+            if let Ok(replay) = line.parse::<Replay<Env>>() {
+                buffer.extend(replay.states());
+            }
+        }
+        Ok(())
+    });
+    if let Err(err) = result {
+        log::error!("Could not list replay shards: {err}");
+    }
+}
+
+/// Sample a Vec of replays in the `directory`.
+#[allow(unused)]
+fn get_replays(directory: &Path, _model_steps: u32, rng: &mut impl Rng) -> Vec<Replay<Env>> {
+    read_dir(directory)
+        .unwrap()
+        .filter_map(|res| res.ok().map(|entry| entry.path()))
+        .filter(|p| p.extension().is_some_and(|ext| ext == "txt"))
+        .filter(|p| {
+            p.file_stem()
+                .and_then(|s| s.to_str()?.split_once('_'))
+                .is_some_and(|(before, _after)| before == "replays")
+        })
+        .filter_map(|p| {
+            Some(
+                BufReader::new(OpenOptions::new().read(true).open(p).ok()?)
+                    .lines()
+                    .filter_map(|line| line.unwrap().parse().ok()),
+            )
+        })
+        .flatten()
+        .choose_multiple(rng, BATCH_SIZE)
+}
+
+#[derive(Debug, Error)]
+enum ReadBufferLengthsError {
+    #[error("io: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("missing component")]
+    MissingComponent,
+    #[error("wrong checksum")]
+    WrongCheckSum,
+}
+
+fn read_buffer_lengths(directory: &Path) -> Result<(usize, usize), ReadBufferLengthsError> {
+    let buffer_lengths = std::fs::read_to_string(directory.join("buffer_lengths.txt"))?;
+    let mut nums = buffer_lengths.split(',').filter_map(|s| s.parse().ok());
+    let selfplay: usize = nums
+        .next()
+        .ok_or(ReadBufferLengthsError::MissingComponent)?;
+    let reanalyze: usize = nums
+        .next()
+        .ok_or(ReadBufferLengthsError::MissingComponent)?;
+    let checksum: usize = nums
+        .next()
+        .ok_or(ReadBufferLengthsError::MissingComponent)?;
+    if selfplay + reanalyze != checksum {
+        return Err(ReadBufferLengthsError::WrongCheckSum);
+    }
+    Ok((selfplay, reanalyze))
+}
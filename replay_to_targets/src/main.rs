@@ -0,0 +1,227 @@
+use std::{
+    io::{BufWriter, Write},
+    path::{Path, PathBuf},
+};
+
+use clap::Parser;
+use fast_tak::{Game, Reserves};
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha8Rng;
+use takzero::{
+    header::{self, FileKind, Header},
+    network::{net4_simhash, net6_simhash, Network},
+    search::{
+        agent::{simple::Simple, Agent},
+        env::Environment,
+        DISCOUNT_FACTOR,
+    },
+    target::{dedup_targets, get_replays, n_step_values, one_hot_targets, Target},
+};
+use tch::Device;
+
+#[derive(Parser, Debug)]
+struct Args {
+    /// File with one replay per line
+    replays: PathBuf,
+    /// File to append the training targets to
+    #[arg(long)]
+    train: PathBuf,
+    /// File to append the validation targets to
+    #[arg(long, requires = "validation_fraction")]
+    validation: Option<PathBuf>,
+    /// Fraction of games whose targets go to the validation file
+    #[arg(long, requires = "validation")]
+    validation_fraction: Option<f64>,
+    /// Bootstrap values from the network this many plies ahead
+    /// (the discounted game outcome is used by default)
+    #[arg(long, requires = "model_path")]
+    horizon: Option<usize>,
+    /// Discount per ply
+    #[arg(long, default_value_t = DISCOUNT_FACTOR)]
+    discount: f32,
+    /// Write every target under all 8 symmetries of the board
+    #[arg(long)]
+    symmetries: bool,
+    /// Merge targets whose positions are the same up to symmetry, averaging
+    /// their values and policies (keeps all targets in memory)
+    #[arg(long)]
+    dedup: bool,
+    /// Checkpoint used to bootstrap values
+    #[arg(long)]
+    model_path: Option<PathBuf>,
+    #[arg(long, default_value_t = 6)]
+    size: usize,
+    #[arg(long, default_value_t = 4)]
+    half_komi: i8,
+    /// Seed for the train/validation split, so that it can be reproduced
+    #[arg(long, default_value_t = 0)]
+    seed: u64,
+    /// Run the network on the CPU
+    #[arg(long)]
+    cpu: bool,
+}
+
+macro_rules! with_network {
+    ($args:expr, $path:expr, $device:expr; $($net:ident),*) => {
+        match ($args.size, $args.half_komi) {
+            $(
+                ($net::N, $net::HALF_KOMI) => match $net::Net::load_partial($path, $device) {
+                    Ok(net) => tch::no_grad(|| {
+                        convert::<{ $net::N }, { $net::HALF_KOMI }, _>(&$args, Some(&net))
+                    }),
+                    Err(err) => {
+                        log::error!("could not load {}: {err}", $path.display());
+                        Ok(())
+                    }
+                },
+            )*
+            (size, half_komi) => {
+                log::error!("there is no network for size {size} with half komi {half_komi}");
+                Ok(())
+            }
+        }
+    };
+}
+
+macro_rules! without_network {
+    ($args:expr; $($size:literal),*) => {
+        match ($args.size, $args.half_komi) {
+            $(
+                ($size, 0) => convert::<$size, 0, Simple>(&$args, None),
+                ($size, 4) => convert::<$size, 4, Simple>(&$args, None),
+            )*
+            (size, half_komi) => {
+                log::error!("size {size} with half komi {half_komi} is not supported");
+                Ok(())
+            }
+        }
+    };
+}
+
+fn main() {
+    takzero::logging::init();
+    let args = Args::parse();
+    let device = if args.cpu { Device::Cpu } else { Device::cuda_if_available() };
+    let result = match &args.model_path {
+        Some(path) => with_network!(args, path, device; net4_simhash, net6_simhash),
+        None => without_network!(args; 3, 4, 5, 6, 7, 8),
+    };
+    if let Err(err) = result {
+        log::error!("{err}");
+    }
+}
+
+fn append<const N: usize, const HALF_KOMI: i8>(
+    path: &Path,
+    kind: FileKind,
+) -> std::io::Result<BufWriter<std::fs::File>> {
+    let header = Header::new::<N, HALF_KOMI>(kind, None);
+    Ok(BufWriter::new(header::open_append(path, &header)?))
+}
+
+/// Write targets, optionally under all symmetries, and count them.
+fn write_targets<const N: usize, const HALF_KOMI: i8>(
+    file: &mut impl Write,
+    targets: impl IntoIterator<Item = Target<Game<N, HALF_KOMI>>>,
+    symmetries: bool,
+) -> std::io::Result<usize>
+where
+    Reserves<N>: Default,
+{
+    let mut count = 0;
+    for target in targets {
+        if symmetries {
+            for target in target.symmetries() {
+                write!(file, "{target}")?;
+                count += 1;
+            }
+        } else {
+            write!(file, "{target}")?;
+            count += 1;
+        }
+    }
+    Ok(count)
+}
+
+/// Stream the replays and write their targets. Games rather than positions
+/// are split between training and validation, so that positions of the same
+/// game do not end up on both sides.
+fn convert<const N: usize, const HALF_KOMI: i8, A: Agent<Game<N, HALF_KOMI>>>(
+    args: &Args,
+    agent: Option<&A>,
+) -> std::io::Result<()>
+where
+    Reserves<N>: Default,
+{
+    let mut train = append::<N, HALF_KOMI>(&args.train, FileKind::Targets)?;
+    let mut validation = args
+        .validation
+        .as_deref()
+        .map(|path| append::<N, HALF_KOMI>(path, FileKind::Targets))
+        .transpose()?;
+    let mut rng = ChaCha8Rng::seed_from_u64(args.seed);
+
+    let (mut games, mut train_targets, mut validation_targets) = (0, 0, 0);
+    // Targets kept back for deduplication.
+    let (mut train_kept, mut validation_kept) = (Vec::new(), Vec::new());
+    for replay in get_replays::<N, HALF_KOMI>(&args.replays)? {
+        let values = n_step_values(&replay, args.horizon, args.discount, |positions| {
+            let Some(agent) = agent else {
+                unreachable!("a horizon requires a model");
+            };
+            let actions: Vec<_> = positions
+                .iter()
+                .map(|env| {
+                    let mut actions = Vec::new();
+                    env.populate_actions(&mut actions);
+                    actions
+                })
+                .collect();
+            agent
+                .policy_value_uncertainty(positions, &actions)
+                .map(|(_, value, _)| value)
+                .collect()
+        });
+        if values.is_empty() {
+            continue;
+        }
+        games += 1;
+
+        let is_validation = args
+            .validation_fraction
+            .is_some_and(|fraction| rng.gen_bool(fraction.clamp(0.0, 1.0)));
+        let (file, count, kept) = match &mut validation {
+            Some(file) if is_validation => (file, &mut validation_targets, &mut validation_kept),
+            _ => (&mut train, &mut train_targets, &mut train_kept),
+        };
+        let targets = one_hot_targets(&replay, &values);
+        if args.dedup {
+            kept.extend(targets);
+        } else {
+            *count += write_targets(file, targets, args.symmetries)?;
+        }
+    }
+
+    if args.dedup {
+        let total = train_kept.len() + validation_kept.len();
+        let train_kept = dedup_targets(train_kept);
+        let validation_kept = dedup_targets(validation_kept);
+        log::info!(
+            "merged {total} targets into {} unique positions",
+            train_kept.len() + validation_kept.len()
+        );
+        train_targets = write_targets(&mut train, train_kept, args.symmetries)?;
+        if let Some(validation) = &mut validation {
+            validation_targets = write_targets(validation, validation_kept, args.symmetries)?;
+        }
+    }
+    train.flush()?;
+    if let Some(mut validation) = validation {
+        validation.flush()?;
+    }
+    log::info!(
+        "converted {games} games into {train_targets} training and {validation_targets} \
+         validation targets"
+    );
+    Ok(())
+}
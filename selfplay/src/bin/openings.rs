@@ -0,0 +1,52 @@
+//! Report how diverse the openings of each network generation are, from the
+//! games in the game archive, and how the most common openings scored.
+
+use std::path::PathBuf;
+
+use clap::Parser;
+use takzero::{
+    archive::GameArchive,
+    network::net6_simhash::{HALF_KOMI, N},
+    opening::diversity,
+};
+
+#[derive(Parser, Debug)]
+struct Args {
+    /// Game archive written by selfplay with `--archive`.
+    #[arg(long)]
+    archive: PathBuf,
+    /// Number of the most common openings to list for each generation.
+    #[arg(long, default_value_t = 3)]
+    top: usize,
+}
+
+fn main() {
+    takzero::logging::init();
+    let args = Args::parse();
+
+    let archive = GameArchive::open(&args.archive).expect("Game archive should be openable");
+    let report = archive
+        .opening_stats_by_generation::<N, HALF_KOMI>()
+        .expect("Game archive should be readable");
+
+    for (generation, openings) in report {
+        if openings.is_empty() {
+            continue;
+        }
+        println!(
+            "generation={generation} openings={} diversity={:.2}",
+            openings.len(),
+            diversity(openings.values().map(|stats| stats.games))
+        );
+        let mut common: Vec<_> = openings.into_iter().collect();
+        common.sort_by_key(|(_, stats)| std::cmp::Reverse(stats.games));
+        for (opening, stats) in common.into_iter().take(args.top) {
+            println!(
+                "    {opening}: games={} white_score={:.3} draws={}",
+                stats.games,
+                stats.white_score(),
+                stats.draws
+            );
+        }
+    }
+}
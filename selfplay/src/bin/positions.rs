@@ -0,0 +1,113 @@
+//! Query the positions indexed in the game archive: how often each
+//! generation reached a position (or material signature) and how it scored.
+
+use std::path::PathBuf;
+
+use clap::Parser;
+use fast_tak::{takparse::Tps, Game, Reserves};
+use takzero::{
+    archive::{material, GameArchive, PositionStats},
+    zobrist::zobrist,
+};
+
+#[derive(Parser, Debug)]
+struct Args {
+    /// Game archive written by selfplay with `--archive`.
+    #[arg(long)]
+    archive: PathBuf,
+    /// Position written as TPS.
+    #[arg(long, conflicts_with = "material")]
+    tps: Option<Tps>,
+    /// Material signature like `5F1S0C/4F0S1C` (flats, walls, and capstones
+    /// on top of stacks for white and then black).
+    #[arg(long)]
+    material: Option<String>,
+    /// Number of games which reached the position to list.
+    #[arg(long, default_value_t = 5)]
+    games: usize,
+    /// Index the positions of games of this size which were archived before
+    /// positions were indexed.
+    #[arg(long)]
+    index: Option<usize>,
+}
+
+/// Call `$f::<N, 0>` with the board size. Komi does not change positions.
+macro_rules! dispatch {
+    ($size:expr, $f:ident($arg:expr); $($n:literal),*) => {
+        match $size {
+            $($n => $f::<$n, 0>($arg),)*
+            size => {
+                log::error!("size {size} is not supported");
+                return;
+            }
+        }
+    };
+}
+
+fn main() {
+    takzero::logging::init();
+    let args = Args::parse();
+
+    let archive = GameArchive::open(&args.archive).expect("Game archive should be openable");
+    if let Some(size) = args.index {
+        let games = dispatch!(size, index(&archive); 3, 4, 5, 6, 7, 8);
+        log::info!("indexed the positions of {games} games");
+    }
+
+    if let Some(tps) = args.tps {
+        let (key, material) = dispatch!(tps.size(), describe(tps); 3, 4, 5, 6, 7, 8);
+        println!("key: {key:016x}");
+        println!("material: {material}");
+        print_stats(&archive.position_stats(key).expect("Game archive should be readable"));
+        for occurrence in archive
+            .occurrences(key, args.games)
+            .expect("Game archive should be readable")
+        {
+            let moves: Vec<_> = occurrence.moves.split_whitespace().collect();
+            let unknown = || "unknown".to_string();
+            println!(
+                "game {} (generation {}, half komi {}, result {}) at ply {}: [TPS \"{}\"] {}",
+                occurrence.game.map_or_else(unknown, |game| game.to_string()),
+                occurrence.generation,
+                occurrence.half_komi.map_or_else(unknown, |half_komi| half_komi.to_string()),
+                occurrence.result.as_deref().unwrap_or("none"),
+                occurrence.ply,
+                occurrence.start,
+                moves[..occurrence.ply.min(moves.len())].join(" ")
+            );
+        }
+    } else if let Some(material) = args.material {
+        print_stats(&archive.material_stats(&material).expect("Game archive should be readable"));
+    }
+}
+
+fn index<const N: usize, const HALF_KOMI: i8>(archive: &GameArchive) -> usize
+where
+    Reserves<N>: Default,
+{
+    archive
+        .index_positions::<N, HALF_KOMI>()
+        .expect("Game archive should be indexable")
+}
+
+fn describe<const N: usize, const HALF_KOMI: i8>(tps: Tps) -> (u64, String)
+where
+    Reserves<N>: Default,
+{
+    let game: Game<N, HALF_KOMI> = tps.into();
+    (zobrist(&game), material(&game))
+}
+
+fn print_stats(stats: &[PositionStats]) {
+    if stats.is_empty() {
+        println!("never reached");
+        return;
+    }
+    println!("{:>10} {:>11} {:>6} {:>9}", "generation", "occurrences", "games", "avg value");
+    for s in stats {
+        let value = s
+            .average_value
+            .map_or_else(|| "-".to_string(), |value| format!("{value:+.3}"));
+        println!("{:>10} {:>11} {:>6} {:>9}", s.generation, s.occurrences, s.games, value);
+    }
+}
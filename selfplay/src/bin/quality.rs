@@ -0,0 +1,67 @@
+//! Report how well the value targets made from the root values recorded in
+//! the game archive predicted the game outcomes, for each network generation.
+
+use std::path::PathBuf;
+
+use clap::Parser;
+use takzero::{
+    archive::GameArchive,
+    network::net6_simhash::{HALF_KOMI, N},
+    quality::ValueQuality,
+    search::DISCOUNT_FACTOR,
+};
+
+#[derive(Parser, Debug)]
+struct Args {
+    /// Game archive written by selfplay with `--archive`.
+    #[arg(long)]
+    archive: PathBuf,
+    /// Horizon of the value targets, as given to selfplay.
+    #[arg(long)]
+    horizon: Option<usize>,
+    /// Discount per ply of the value targets, as given to selfplay.
+    #[arg(long, default_value_t = DISCOUNT_FACTOR)]
+    discount: f32,
+    /// Also print the calibration curve for each generation.
+    #[arg(long)]
+    calibration: bool,
+}
+
+fn main() {
+    takzero::logging::init();
+    let args = Args::parse();
+
+    let archive = GameArchive::open(&args.archive).expect("Game archive should be openable");
+    let report = archive
+        .value_quality_by_generation::<N, HALF_KOMI>(args.horizon, args.discount)
+        .expect("Game archive should be readable");
+
+    let mut previous: Option<ValueQuality> = None;
+    for (generation, quality) in report {
+        if quality.positions == 0 {
+            continue;
+        }
+        println!("generation={generation} {quality}");
+        if let Some(previous) = &previous {
+            // Flag sudden regressions, which usually mean a bug in target generation.
+            if quality.mean_squared_error() > 1.5 * previous.mean_squared_error() {
+                log::warn!(
+                    "Value error jumped from {:.4} to {:.4} at generation {generation}",
+                    previous.mean_squared_error(),
+                    quality.mean_squared_error()
+                );
+            }
+        }
+        if args.calibration {
+            for bin in quality.bins.iter().filter(|bin| bin.count > 0) {
+                println!(
+                    "    predicted={:+.3} outcome={:+.3} count={}",
+                    bin.mean_predicted(),
+                    bin.mean_outcome(),
+                    bin.count
+                );
+            }
+        }
+        previous = Some(quality);
+    }
+}
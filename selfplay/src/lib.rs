@@ -0,0 +1,589 @@
+use std::{
+    fmt,
+    fs::read_dir,
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+use clap::Parser;
+use fast_tak::takparse::Move;
+use ordered_float::NotNan;
+use rand::prelude::*;
+use resume::SelfplayState;
+// The board size is 6, or 4 for the first stage of a board-size curriculum.
+#[cfg(feature = "board4")]
+use takzero::network::net4_simhash::{Env, Net, HALF_KOMI, N};
+#[cfg(not(feature = "board4"))]
+use takzero::network::net6_simhash::{Env, Net, HALF_KOMI, N};
+#[cfg(feature = "archive")]
+use takzero::archive::GameArchive;
+use takzero::{
+    affinity::CoreSet,
+    audit::{Audit, Record},
+    batch_size::{BatchSizeController, GpuMonitor},
+    curriculum::Curriculum,
+    header::{self, FileKind, Header},
+    logging,
+    metrics::{self, REGISTRY},
+    network::{
+        checkpoint::{self, CheckpointError},
+        repr::{self, InputRepr},
+        Network,
+    },
+    shards::{ExpiryPolicy, RotationPolicy, ShardWriter},
+    spectator,
+    storage::{self, ObjectStore},
+    search::{agent::Agent, env::Environment, DISCOUNT_FACTOR},
+    target::{Augment, Replay, RootStats, Target},
+    variant::Variant,
+};
+use tch::{Device, TchError};
+use thiserror::Error;
+use worker::{Played, SelfPlayBuilder};
+
+mod reproduce;
+mod resume;
+mod worker;
+
+#[rustfmt::skip]
+#[allow(dead_code)] const fn assert_env<E: Environment>() where Target<E>: Augment + fmt::Display {}
+const _: () = assert_env::<Env>();
+
+// The network architecture.
+#[rustfmt::skip] #[allow(dead_code)]
+const fn assert_net<NET: Network + Agent<Env> + Agent<Played>>() {}
+const _: () = assert_net::<Net>();
+
+const DEVICE: Device = Device::Cuda(0);
+/// Capacity of the batched search. The batch size controller starts at
+/// [`INITIAL_ACTIVE_ENVS`] and may grow up to this.
+const BATCH_SIZE: usize = 256;
+const INITIAL_ACTIVE_ENVS: usize = 128;
+const WEIGHTED_RANDOM_PLIES: u16 = 10;
+// const NOISE_ALPHA: f32 = 0.05;
+// const NOISE_RATIO: f32 = 0.2;
+const BETA: f32 = 0.25;
+const DUPLICATE_TEMPERATURE: f32 = 2.0;
+const MAX_SELFPLAY_BUFFER_LEN: usize = 32_000;
+const STEPS_BETWEEN_STATE_SAVES: usize = 10;
+const MIN_ACTIVE_ENVS: usize = 32;
+const ACTIVE_ENVS_STEP: usize = 16;
+const MAX_SEARCH_TIME: std::time::Duration = std::time::Duration::from_secs(30);
+const GPU_SAMPLE_INTERVAL: std::time::Duration = std::time::Duration::from_secs(30);
+/// Most visited actions whose visit counts are recorded in replays.
+const REPLAY_TOP_MOVES: usize = 4;
+/// Failed searches in a row after which the games are abandoned, and the
+/// pause after each failed search, which grows with every failure.
+const MAX_FAILED_SEARCHES: u32 = 3;
+const FAILED_SEARCH_BACKOFF: std::time::Duration = std::time::Duration::from_secs(5);
+
+const SAMPLED_ACTIONS: usize = 64;
+const SEARCH_BUDGET: u32 = 768;
+
+#[derive(Parser, Debug)]
+pub struct Args {
+    /// Directory where to find models
+    /// and also where to save targets.
+    #[arg(long)]
+    directory: PathBuf,
+    /// Address to serve Prometheus metrics on, for example `0.0.0.0:9100`.
+    #[arg(long)]
+    metrics_address: Option<String>,
+    /// Address to serve a live view of the games on, for example `0.0.0.0:8001`.
+    #[arg(long)]
+    spectator_address: Option<String>,
+    /// Name of this worker in the logs. Defaults to the process id.
+    #[arg(long)]
+    worker: Option<String>,
+    /// File in which to periodically save unfinished games.
+    /// If it exists on startup, those games are resumed.
+    #[arg(long)]
+    resume: Option<PathBuf>,
+    /// Object store to pull models from and push targets and replays to,
+    /// for example `s3://bucket/run`. Without it, the directory is shared.
+    #[arg(long)]
+    storage: Option<String>,
+    /// Games per replay shard before a new shard is started.
+    #[arg(long, default_value_t = RotationPolicy::default().max_games)]
+    replay_shard_games: usize,
+    /// Number of closed replay shards to keep. Older shards are expired.
+    /// All shards are kept by default.
+    #[arg(long)]
+    replay_shards_kept: Option<usize>,
+    /// Directory to move expired replay shards to instead of deleting them.
+    #[arg(long)]
+    replay_shard_archive: Option<PathBuf>,
+    /// Bootstrap value targets from the root value of the search this many
+    /// plies ahead. The discounted game outcome is used by default.
+    #[arg(long)]
+    horizon: Option<usize>,
+    /// Discount per ply for value targets.
+    #[arg(long, default_value_t = DISCOUNT_FACTOR)]
+    discount: f32,
+    /// Discount per ply for known results during search, so that faster
+    /// wins are preferred before they are proven.
+    #[arg(long, default_value_t = DISCOUNT_FACTOR)]
+    mate_discount: f32,
+    /// Manifest in which to record the seed, and the random stream position,
+    /// network generation, and finished games of every step, so that games
+    /// can be reproduced with `--reproduce-game`. Learn should be audited
+    /// too, so that it keeps the model of every generation.
+    #[arg(long)]
+    audit: Option<PathBuf>,
+    /// Play the audited run in which this game finished again, with the same
+    /// options, and print the replay of the game instead of playing normally.
+    #[arg(long, requires = "audit")]
+    reproduce_game: Option<u64>,
+    /// Cores to play on, like `0-31`, or a NUMA node, like `node:0`.
+    /// The search threads are pinned one to each core.
+    #[arg(long)]
+    cores: Option<CoreSet>,
+    /// Curriculum state written by `evaluation --curriculum`, shared by the
+    /// workers of every board size. Self-play only plays on this board size
+    /// as often as the curriculum wants.
+    #[arg(long)]
+    curriculum: Option<PathBuf>,
+    /// Encoding of positions as network inputs, like `stack-depth=6`, which
+    /// has to be the one the models were trained with.
+    #[arg(long, default_value_t)]
+    input_repr: InputRepr,
+    /// Draw games after this many plies. Networks only see how close a game
+    /// is to the limit with `--input-repr planes=extended`.
+    #[arg(long)]
+    max_plies: Option<u16>,
+    /// Draw games after this many plies in a row without a placement.
+    #[arg(long)]
+    max_reversible_plies: Option<u16>,
+    /// SQLite database in which to archive finished games.
+    #[cfg(feature = "archive")]
+    #[arg(long)]
+    archive: Option<PathBuf>,
+}
+
+/// Run self-play with the given arguments. The caller initializes logging.
+///
+/// # Panics
+///
+/// Panics if the directory, a model, or the state to resume from cannot be used.
+#[allow(clippy::too_many_lines)]
+pub fn run(args: Args) {
+    if let Some(cores) = &args.cores {
+        cores
+            .install_global("selfplay")
+            .expect("Self-play threads should only be started once");
+    }
+    repr::configure(args.input_repr).expect("The input encoding should only be set once");
+    let variant = Variant {
+        max_plies: args.max_plies,
+        max_reversible_plies: args.max_reversible_plies,
+        ..Variant::standard::<N>()
+    };
+    logging::set_worker_or_default(args.worker.as_deref(), "selfplay");
+
+    if let (Some(game_id), Some(manifest)) = (args.reproduce_game, &args.audit) {
+        let builder = SelfPlayBuilder::new(DEVICE)
+            .horizon(args.horizon)
+            .discount(args.discount)
+            .mate_discount(args.mate_discount)
+            .variant(variant);
+        match reproduce::reproduce_game(manifest, &args.directory, builder, game_id) {
+            Ok(replay) => print!("{replay}"),
+            Err(err) => log::error!("Could not reproduce game {game_id}: {err}"),
+        }
+        return;
+    }
+
+    let state = args
+        .resume
+        .as_deref()
+        .filter(|path| path.exists())
+        .and_then(|path| match SelfplayState::load(path) {
+            Ok(state) => Some(state),
+            Err(err) => {
+                log::error!("Could not load unfinished games, starting new ones: {err}");
+                None
+            }
+        });
+
+    let mut builder = SelfPlayBuilder::new(DEVICE)
+        .horizon(args.horizon)
+        .discount(args.discount)
+        .mate_discount(args.mate_discount)
+        .variant(variant);
+    let resumed = state.is_some();
+    if let Some(state) = state {
+        builder = builder.resume(state);
+    }
+    let mut selfplay = builder.build().expect("Self-play configuration should be valid");
+    log::info!("seed = {}", selfplay.seed());
+    let mut audit = args.audit.as_ref().map(|path| {
+        Audit::open(path, "selfplay", selfplay.seed(), resumed)
+            .expect("Audit manifest should be writable")
+    });
+
+    if let Some(address) = &args.metrics_address {
+        metrics::serve(address).expect("Metrics address should be valid");
+    }
+    if let Some(address) = &args.spectator_address {
+        spectator::serve(address).expect("Spectator address should be valid");
+    }
+
+    #[cfg(feature = "archive")]
+    let archive = args
+        .archive
+        .as_ref()
+        .map(|path| GameArchive::open(path).expect("Game archive should be openable"));
+
+    let store = args
+        .storage
+        .as_deref()
+        .map(|url| storage::open(url).expect("Storage URL should be valid"));
+
+    let rotation = RotationPolicy {
+        max_games: args.replay_shard_games,
+        ..RotationPolicy::default()
+    };
+    let expiry = ExpiryPolicy {
+        keep: args.replay_shards_kept,
+        archive: args.replay_shard_archive.clone(),
+    };
+    #[cfg(feature = "exploration")]
+    let exploration_writer =
+        ShardWriter::new(&args.directory, "replays-exploration", rotation, expiry.clone());
+    let replay_writer = ShardWriter::new(&args.directory, "replays", rotation, expiry);
+
+    let mut batch_size_controller =
+        BatchSizeController::new(MIN_ACTIVE_ENVS, BATCH_SIZE, ACTIVE_ENVS_STEP, MAX_SEARCH_TIME)
+            .starting_at(INITIAL_ACTIVE_ENVS);
+    let mut gpu_monitor = GpuMonitor::new(0, GPU_SAMPLE_INTERVAL);
+    selfplay.search.mcts.set_active(batch_size_controller.active());
+
+    let mut failed_searches = 0;
+    let mut recorded_temperature = None;
+    for steps in 0.. {
+        log::info!("Step: {steps}");
+        let start = std::time::Instant::now();
+        loop {
+            if let Some(store) = &store {
+                storage::pull_files(store.as_ref(), &args.directory, &["buffer_lengths.txt"]);
+            }
+            let exploitation = match read_buffer_lengths(&args.directory) {
+                Ok((exploitation, _)) => exploitation,
+                Err(err) => {
+                    log::error!("Could not read buffer lengths: {err}");
+                    std::thread::sleep(std::time::Duration::from_secs(1));
+                    continue;
+                }
+            };
+            if exploitation > MAX_SELFPLAY_BUFFER_LEN {
+                std::thread::sleep(std::time::Duration::from_secs(1));
+                continue;
+            }
+            log::debug!("Checked that there more selfplay targets are needed.");
+
+            // Only play on this board size as often as the curriculum wants.
+            if let Some(Ok(curriculum)) = args.curriculum.as_ref().map(Curriculum::load) {
+                if selfplay.search.rng.gen::<f32>() >= curriculum.share(N) {
+                    log::debug!("Curriculum is not on board size {N} right now.");
+                    std::thread::sleep(std::time::Duration::from_secs(1));
+                    continue;
+                }
+            }
+
+            if let Some(store) = &store {
+                if let Err(err) = storage::pull_model(store.as_ref(), &args.directory) {
+                    log::debug!("Could not pull the model: {err}");
+                }
+                storage::pull_files(store.as_ref(), &args.directory, &["selfplay_temperature.txt"]);
+            }
+            let path = args.directory.join("model_latest.ot");
+            match checkpoint::load_verified::<Env, Net>(&path, DEVICE) {
+                Ok(new_net) => {
+                    selfplay.search.agent = new_net;
+                    if let Some(generation) = checkpoint::read_model_steps(&args.directory) {
+                        logging::set_generation(generation);
+                    }
+                    selfplay.temperature = read_temperature(&args.directory);
+                    break;
+                }
+                Err(err) if err.is_corrupted() => {
+                    checkpoint::report_failure(&path, &err);
+                    if let Some(previous) = checkpoint::load_previous::<Env, Net>(&path, DEVICE) {
+                        selfplay.search.agent = previous;
+                    }
+                    break;
+                }
+                Err(CheckpointError::Torch(TchError::Torch(err))) => {
+                    log::warn!("Cannot load model (internal torch error): {err}, not retrying.");
+                    break;
+                }
+                Err(err) => {
+                    log::error!("Cannot load model (some other reason): {err}, retrying.");
+                    std::thread::sleep(std::time::Duration::from_secs(1));
+                }
+            }
+        }
+        log::debug!(
+            "Waiting until more targets are needed and loading model took {:?}.",
+            start.elapsed()
+        );
+
+        if let Some(audit) = &mut audit {
+            record_temperature(audit, steps, selfplay.temperature, &mut recorded_temperature);
+        }
+
+        let search_start = std::time::Instant::now();
+        let word_pos = selfplay.search.rng.get_word_pos();
+        let selected_actions = match selfplay.select_actions() {
+            Ok(selected_actions) => selected_actions,
+            Err(err) => {
+                // Searching the same trees again would likely fail the same way.
+                failed_searches += 1;
+                let pause = FAILED_SEARCH_BACKOFF * failed_searches;
+                if failed_searches < MAX_FAILED_SEARCHES {
+                    log::error!("Search failed: {err}, searching again from scratch in {pause:?}.");
+                    selfplay.search.mcts.reset_trees();
+                } else {
+                    log::error!("Search failed again: {err}, starting new games.");
+                    selfplay.abandon_games();
+                    failed_searches = 0;
+                }
+                std::thread::sleep(pause);
+                continue;
+            }
+        };
+        let search_time = search_start.elapsed();
+        failed_searches = 0;
+
+        let active = selfplay.search.mcts.active().filter(|active| *active).count();
+        selfplay.take_a_step(&selected_actions);
+        selfplay.spectate(&selected_actions);
+        let next_active =
+            batch_size_controller.observe(search_time, active, gpu_monitor.utilization());
+        selfplay.search.mcts.set_active(next_active);
+        REGISTRY.set_gauge(
+            "takzero_selfplay_active_envs",
+            "Environments being played concurrently.",
+            batch_size_controller.active() as f64,
+        );
+        REGISTRY.inc_counter(
+            "takzero_selfplay_plies_total",
+            "Plies played in selfplay.",
+            active as f64,
+        );
+        REGISTRY.set_gauge(
+            "takzero_selfplay_seconds_per_step",
+            "Time taken for one batched selfplay step, including waiting.",
+            start.elapsed().as_secs_f64(),
+        );
+        selfplay.restart_envs_and_complete_targets();
+        if let Some(audit) = &mut audit {
+            record_step(audit, steps, word_pos, next_active, &selfplay.complete_replays);
+        }
+
+        if let Some(store) = &store {
+            push_shards(store.as_ref(), steps, &selfplay.targets, &selfplay.complete_replays);
+        }
+        if !selfplay.targets.is_empty() {
+            REGISTRY.inc_counter(
+                "takzero_selfplay_targets_total",
+                "Targets produced by selfplay.",
+                selfplay.targets.len() as f64,
+            );
+            save_targets_to_file(&mut selfplay.targets, &args.directory);
+        }
+        #[cfg(feature = "archive")]
+        for game in selfplay.archived_games.drain(..) {
+            if let Some(archive) = &archive {
+                if let Err(err) = archive.insert(&game) {
+                    log::error!("Could not archive game: {err}");
+                }
+            }
+        }
+        if !selfplay.complete_replays.is_empty() {
+            REGISTRY.inc_counter(
+                "takzero_selfplay_games_total",
+                "Games finished in selfplay.",
+                selfplay.complete_replays.len() as f64,
+            );
+            save_replays(&replay_writer, &mut selfplay.complete_replays);
+            #[cfg(feature = "exploration")]
+            save_replays(&exploration_writer, &mut selfplay.exploration_replays);
+        }
+
+        if let Some(path) = &args.resume {
+            if steps % STEPS_BETWEEN_STATE_SAVES == 0 {
+                if let Err(err) = selfplay.save_state(path) {
+                    log::error!("Could not save unfinished games: {err}");
+                }
+            }
+        }
+    }
+}
+
+/// Record the temperature for opening moves in the audit manifest if it
+/// changed since it was last recorded.
+fn record_temperature(
+    audit: &mut Audit,
+    step: usize,
+    temperature: Option<f32>,
+    recorded: &mut Option<f32>,
+) {
+    if temperature == *recorded {
+        return;
+    }
+    *recorded = temperature;
+    if let Err(err) = audit.record(&Record::Temperature { step, temperature }) {
+        log::error!("Could not write to the audit manifest: {err}");
+    }
+}
+
+/// Record a step and the games which finished in it in the audit manifest.
+fn record_step(
+    audit: &mut Audit,
+    step: usize,
+    word_pos: u128,
+    active: usize,
+    finished: &[Replay<Env>],
+) {
+    let step_record = Record::Step {
+        step,
+        generation: logging::context().generation,
+        word_pos,
+        active,
+    };
+    let games = finished.iter().filter_map(|replay| replay.game_id);
+    if let Err(err) = std::iter::once(step_record)
+        .chain(games.map(|game_id| Record::Game { game_id, step }))
+        .try_for_each(|record| audit.record(&record))
+    {
+        log::error!("Could not write to the audit manifest: {err}");
+    }
+}
+
+/// Get the path to the model file (ending with ".ot")
+/// which has the highest number of steps (number after '_')
+/// in the given directory.
+#[allow(unused)]
+fn get_model_path_with_most_steps(directory: &PathBuf) -> Option<(u32, PathBuf)> {
+    read_dir(directory)
+        .unwrap()
+        .filter_map(|res| res.ok().map(|entry| entry.path()))
+        .filter(|p| p.extension().is_some_and(|ext| ext == "ot"))
+        .filter_map(|p| {
+            Some((
+                p.file_stem()?
+                    .to_str()?
+                    .split_once('_')?
+                    .1
+                    .parse::<u32>()
+                    .ok()?,
+                p,
+            ))
+        })
+        .max_by_key(|(s, _)| *s)
+}
+
+struct IncompleteTarget {
+    env: Env,
+    /// Progress towards the move limit of the variant, if it has one.
+    move_limit: Option<f32>,
+    /// Steps of the model which searched the position.
+    model_steps: Option<usize>,
+    policy: Box<[(Move, NotNan<f32>)]>,
+    root_ube_metric: NotNan<f32>,
+    root_value: f32,
+    stats: RootStats<Move>,
+}
+
+/// Save targets to a file. Drains the target Vec.
+fn save_targets_to_file(targets: &mut Vec<Target<Env>>, directory: &Path) {
+    let contents: String = targets.drain(..).map(|target| target.to_string()).collect();
+    let header = Header::new::<N, HALF_KOMI>(FileKind::Targets, logging::context().generation);
+    if let Err(err) = header::open_append(directory.join("targets-selfplay.txt"), &header)
+        .and_then(|mut file| file.write_all(contents.as_bytes()))
+    {
+        log::error!(
+            "Could not save targets to file [{err}], so here they are instead:\n{contents}"
+        );
+    }
+}
+
+/// Append replays to the newest shard. Drains the replays Vec.
+fn save_replays(writer: &ShardWriter, replays: &mut Vec<Replay<Env>>) {
+    match writer.append(replays, logging::context().generation) {
+        Ok(expired) => {
+            for path in expired {
+                log::info!("Expired replay shard {}.", path.display());
+            }
+        }
+        Err(err) => {
+            let contents: String = replays.iter().map(ToString::to_string).collect();
+            log::error!("Could not save replays [{err}], so here they are instead:
+{contents}");
+        }
+    }
+    replays.clear();
+}
+
+/// Upload this step's targets and replays as shards for `learn` and
+/// `reanalyze` to pick up.
+fn push_shards(
+    store: &dyn ObjectStore,
+    steps: usize,
+    targets: &[Target<Env>],
+    replays: &[Replay<Env>],
+) {
+    let worker = logging::context().worker.unwrap_or_default();
+    let shards: [(&str, String); 2] = [
+        ("targets-selfplay", targets.iter().map(ToString::to_string).collect()),
+        ("replays", replays.iter().map(ToString::to_string).collect()),
+    ];
+    for (prefix, contents) in shards {
+        if contents.is_empty() {
+            continue;
+        }
+        let key = format!("{prefix}/{worker}-{steps:0>7}.txt");
+        if let Err(err) = storage::push_shard(store, &key, contents.as_bytes()) {
+            log::error!("Could not push {key}: {err}");
+        }
+    }
+}
+
+/// Read the temperature for opening moves which the trainer was told to use,
+/// see `takzero::control`. It is `default` or missing otherwise.
+fn read_temperature(directory: &Path) -> Option<f32> {
+    std::fs::read_to_string(directory.join("selfplay_temperature.txt"))
+        .ok()?
+        .trim()
+        .parse()
+        .ok()
+}
+
+#[derive(Debug, Error)]
+enum ReadBufferLengthsError {
+    #[error("io: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("missing component")]
+    MissingComponent,
+    #[error("wrong checksum")]
+    WrongCheckSum,
+}
+
+fn read_buffer_lengths(directory: &Path) -> Result<(usize, usize), ReadBufferLengthsError> {
+    let buffer_lengths = std::fs::read_to_string(directory.join("buffer_lengths.txt"))?;
+    let mut nums = buffer_lengths.split(',').filter_map(|s| s.parse().ok());
+    let selfplay: usize = nums
+        .next()
+        .ok_or(ReadBufferLengthsError::MissingComponent)?;
+    let reanalyze: usize = nums
+        .next()
+        .ok_or(ReadBufferLengthsError::MissingComponent)?;
+    let checksum: usize = nums
+        .next()
+        .ok_or(ReadBufferLengthsError::MissingComponent)?;
+    if selfplay + reanalyze != checksum {
+        return Err(ReadBufferLengthsError::WrongCheckSum);
+    }
+    Ok((selfplay, reanalyze))
+}
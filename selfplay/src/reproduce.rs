@@ -0,0 +1,121 @@
+//! Playing an audited run again to reproduce one of its games, see
+//! [`takzero::audit`].
+//!
+//! The run is replayed from its seed, step by step, with the network
+//! generation, random stream position, number of active games, and
+//! temperature for opening moves which the manifest recorded, so waiting
+//! and timing in the original run do not matter. The games finished in
+//! every step are compared with the manifest, which finds the first step
+//! where the runs diverged.
+
+use std::path::Path;
+
+use takzero::{
+    audit::{read_manifest, ReadManifestError, Record},
+    logging,
+    network::checkpoint::{self, CheckpointError},
+    search::node::SearchError,
+    target::Replay,
+};
+use thiserror::Error;
+
+use crate::{
+    worker::{BuildSelfPlayError, SelfPlayBuilder},
+    Env,
+    Net,
+    DEVICE,
+};
+
+#[derive(Debug, Error)]
+pub enum ReproduceError {
+    #[error("{0}")]
+    Manifest(#[from] ReadManifestError),
+    #[error("game {0} did not finish in a fresh self-play run of the manifest")]
+    UnknownGame(u64),
+    #[error("{0}")]
+    Build(#[from] BuildSelfPlayError),
+    #[error("could not load the model of generation {generation}: {source}")]
+    Model {
+        generation: usize,
+        source: CheckpointError,
+    },
+    #[error("{0}")]
+    Search(#[from] SearchError),
+    #[error("the games finished in step {0} differ from the manifest")]
+    Diverged(usize),
+}
+
+/// Play the run in which the game finished again, and return the replay of
+/// the game. The builder should be configured like the original run was,
+/// and the directory should have the numbered models of the generations
+/// which the run used.
+///
+/// # Errors
+///
+/// Returns an error if the manifest cannot be read, the game is not in it,
+/// a model is missing, or the run diverges before the game finishes.
+pub fn reproduce_game(
+    manifest: &Path,
+    directory: &Path,
+    builder: SelfPlayBuilder,
+    game_id: u64,
+) -> Result<Replay<Env>, ReproduceError> {
+    let run = read_manifest(manifest)?
+        .into_iter()
+        .filter(|run| run.kind == "selfplay" && !run.resumed)
+        .find(|run| run.game_step(game_id).is_some())
+        .ok_or(ReproduceError::UnknownGame(game_id))?;
+    let mut selfplay = builder.seed(run.seed).build()?;
+
+    let mut loaded = None;
+    for record in &run.records {
+        let (step, generation, word_pos, active) = match *record {
+            Record::Step {
+                step,
+                generation,
+                word_pos,
+                active,
+            } => (step, generation, word_pos, active),
+            Record::Temperature { temperature, .. } => {
+                selfplay.temperature = temperature;
+                continue;
+            }
+            _ => continue,
+        };
+        if let Some(generation) = generation.filter(|g| loaded != Some(*g)) {
+            let path = directory.join(format!("model_{generation:0>7}.ot"));
+            selfplay.search.agent = checkpoint::load::<Net>(&path, DEVICE)
+                .map_err(|source| ReproduceError::Model { generation, source })?;
+            loaded = Some(generation);
+            // The replays of the original run have the generation too.
+            logging::set_generation(generation);
+        }
+
+        selfplay.search.rng.set_word_pos(word_pos);
+        let selected_actions = selfplay.select_actions()?;
+        selfplay.take_a_step(&selected_actions);
+        selfplay.search.mcts.set_active(active);
+        selfplay.restart_envs_and_complete_targets();
+
+        let mut expected: Vec<_> = run.finished_games(step).collect();
+        let mut finished: Vec<_> = selfplay
+            .complete_replays
+            .iter()
+            .filter_map(|replay| replay.game_id)
+            .collect();
+        expected.sort_unstable();
+        finished.sort_unstable();
+        if expected != finished {
+            return Err(ReproduceError::Diverged(step));
+        }
+        if let Some(replay) = selfplay
+            .complete_replays
+            .drain(..)
+            .find(|replay| replay.game_id == Some(game_id))
+        {
+            return Ok(replay);
+        }
+        selfplay.discard_finished();
+    }
+    Err(ReproduceError::UnknownGame(game_id))
+}
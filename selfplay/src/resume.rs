@@ -0,0 +1,164 @@
+//! Saving and restoring in-flight games so that a restarted worker can
+//! continue where it left off instead of throwing the games away.
+//!
+//! The state file looks like this:
+//! ```text
+//! {seed};{rng_word_pos};{next_game_id}
+//! game {game_id}
+//! {replay}
+//! {incomplete target}*
+//! game {game_id}
+//! ...
+//! ```
+//! Incomplete targets are written as [`Target`]s whose value is the root value
+//! (the real value is only known once the game ends).
+
+use std::{fmt::Write as _, num::ParseIntError, path::Path};
+
+use ordered_float::{FloatIsNan, NotNan};
+use rand_chacha::ChaCha12Rng;
+use takzero::target::{ParseReplayError, ParseTargetError, Replay, RootStats, Target};
+use thiserror::Error;
+
+use crate::{Env, IncompleteTarget, BATCH_SIZE};
+
+pub struct InFlightGame {
+    pub game_id: u64,
+    pub replay: Replay<Env>,
+    pub targets: Vec<IncompleteTarget>,
+}
+
+pub struct SelfplayState {
+    pub seed: u64,
+    pub word_pos: u128,
+    pub next_game_id: u64,
+    pub games: [InFlightGame; BATCH_SIZE],
+}
+
+#[derive(Debug, Error)]
+pub enum LoadStateError {
+    #[error("io: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("missing header")]
+    MissingHeader,
+    #[error("missing replay")]
+    MissingReplay,
+    #[error("expected a game, got `{0}`")]
+    ExpectedGame(String),
+    #[error("expected {BATCH_SIZE} games, got {0}")]
+    WrongNumberOfGames(usize),
+    #[error("number of targets does not match the replay")]
+    WrongNumberOfTargets,
+    #[error("{0}")]
+    Int(#[from] ParseIntError),
+    #[error("{0}")]
+    Replay(#[from] ParseReplayError),
+    #[error("{0}")]
+    Target(#[from] ParseTargetError),
+    #[error("{0}")]
+    Nan(#[from] FloatIsNan),
+}
+
+impl SelfplayState {
+    /// Load the state written by [`save`].
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the file cannot be read or is malformed.
+    pub fn load(path: &Path) -> Result<Self, LoadStateError> {
+        let contents = std::fs::read_to_string(path)?;
+        let mut lines = contents.lines().peekable();
+
+        let mut header = lines.next().ok_or(LoadStateError::MissingHeader)?.split(';');
+        let mut next = || header.next().ok_or(LoadStateError::MissingHeader);
+        let seed = next()?.parse()?;
+        let word_pos = next()?.parse()?;
+        let next_game_id = next()?.parse()?;
+
+        let mut games = Vec::with_capacity(BATCH_SIZE);
+        while let Some(line) = lines.next() {
+            let game_id = line
+                .strip_prefix("game ")
+                .ok_or_else(|| LoadStateError::ExpectedGame(line.to_string()))?
+                .parse()?;
+            let replay: Replay<Env> = lines.next().ok_or(LoadStateError::MissingReplay)?.parse()?;
+            let mut targets = Vec::with_capacity(replay.len());
+            for (i, env) in replay.states().enumerate() {
+                let line = lines
+                    .next_if(|line| !line.starts_with("game "))
+                    .ok_or(LoadStateError::WrongNumberOfTargets)?;
+                let target: Target<Env> = line.parse()?;
+                // Replays without statistics only keep the root value.
+                let stats = replay.stats.get(i).cloned().unwrap_or_else(|| RootStats {
+                    value: target.value,
+                    ube: target.ube,
+                    visits: Vec::new(),
+                });
+                targets.push(IncompleteTarget {
+                    env,
+                    move_limit: target.move_limit,
+                    model_steps: target.model_steps,
+                    policy: target.policy,
+                    root_ube_metric: NotNan::new(target.ube)?,
+                    root_value: target.value,
+                    stats,
+                });
+            }
+            if lines.peek().is_some_and(|line| !line.starts_with("game ")) {
+                return Err(LoadStateError::WrongNumberOfTargets);
+            }
+            games.push(InFlightGame {
+                game_id,
+                replay,
+                targets,
+            });
+        }
+
+        Ok(Self {
+            seed,
+            word_pos,
+            next_game_id,
+            games: games
+                .try_into()
+                .map_err(|games: Vec<_>| LoadStateError::WrongNumberOfGames(games.len()))?,
+        })
+    }
+}
+
+/// Save the in-flight games so that they can be resumed with
+/// [`SelfplayState::load`]. The file is replaced atomically.
+///
+/// # Errors
+///
+/// Returns an error if the file cannot be written.
+pub fn save(
+    path: &Path,
+    seed: u64,
+    rng: &ChaCha12Rng,
+    next_game_id: u64,
+    game_ids: &[u64],
+    replays: impl Iterator<Item = Replay<Env>>,
+    policy_targets: &[Vec<IncompleteTarget>],
+) -> std::io::Result<()> {
+    let mut contents = format!("{seed};{};{next_game_id}\n", rng.get_word_pos());
+    for ((game_id, replay), targets) in game_ids.iter().zip(replays).zip(policy_targets) {
+        let _ = writeln!(contents, "game {game_id}");
+        let _ = write!(contents, "{replay}");
+        for target in targets {
+            let target = Target {
+                env: target.env.clone(),
+                policy: target.policy.clone(),
+                value: target.root_value,
+                ube: target.root_ube_metric.into_inner(),
+                weight: None,
+                move_limit: target.move_limit,
+                model_steps: target.model_steps,
+            };
+            let _ = write!(contents, "{target}");
+        }
+    }
+
+    let temporary = path.with_extension("tmp");
+    std::fs::write(&temporary, contents)?;
+    std::fs::rename(temporary, path)
+}
@@ -0,0 +1,469 @@
+//! The state of a self-play worker: the search over a batch of games, and
+//! the targets, replays, and game ids which are collected along the way.
+
+use std::path::Path;
+
+use fast_tak::takparse::{Color, Move, Tps};
+use rand::prelude::*;
+use rand_chacha::ChaCha12Rng;
+use rayon::prelude::*;
+#[cfg(feature = "archive")]
+use takzero::archive::ArchivedGame;
+use takzero::{
+    logging,
+    metrics::REGISTRY,
+    network::Network,
+    ptn::Outcome,
+    search::{
+        builder::{BuildError, Search, SearchBuilder},
+        env::Environment,
+        eval::Eval,
+        node::SearchError,
+        DISCOUNT_FACTOR,
+    },
+    spectator::SPECTATOR,
+    target::{n_step_values, Replay, RootStats, Target},
+    variant::{Variant, VariantGame},
+};
+use tch::Device;
+use thiserror::Error;
+
+use crate::{
+    resume::{self, SelfplayState},
+    Env,
+    IncompleteTarget,
+    Net,
+    BATCH_SIZE,
+    HALF_KOMI,
+    N,
+    BETA,
+    DUPLICATE_TEMPERATURE,
+    REPLAY_TOP_MOVES,
+    SAMPLED_ACTIONS,
+    SEARCH_BUDGET,
+    WEIGHTED_RANDOM_PLIES,
+};
+
+/// Games are played under a variant, which may draw them at a move limit,
+/// and are stored as standard games.
+pub type Played = VariantGame<N, HALF_KOMI>;
+
+#[derive(Debug, Error)]
+pub enum BuildSelfPlayError {
+    #[error("{0}")]
+    Search(#[from] BuildError),
+    #[error("the discount should be in (0, 1], got {0}")]
+    Discount(f32),
+    #[error("the horizon should be at least one ply")]
+    Horizon,
+}
+
+pub struct SelfPlayBuilder {
+    device: Device,
+    seed: Option<u64>,
+    state: Option<SelfplayState>,
+    horizon: Option<usize>,
+    discount: f32,
+    mate_discount: f32,
+    variant: Variant,
+}
+
+impl SelfPlayBuilder {
+    /// Play with a new network on the device, until a checkpoint is loaded.
+    pub fn new(device: Device) -> Self {
+        Self {
+            device,
+            seed: None,
+            state: None,
+            horizon: None,
+            discount: DISCOUNT_FACTOR,
+            mate_discount: DISCOUNT_FACTOR,
+            variant: Variant::standard::<N>(),
+        }
+    }
+
+    /// Seed everything random, which is seeded from entropy by default.
+    /// The seed of resumed games takes precedence.
+    pub const fn seed(mut self, seed: u64) -> Self {
+        self.seed = Some(seed);
+        self
+    }
+
+    /// Resume the games of an earlier run, with its seed and game ids.
+    pub fn resume(mut self, state: SelfplayState) -> Self {
+        self.state = Some(state);
+        self
+    }
+
+    /// Bootstrap value targets from the root value of the search this many
+    /// plies ahead. The discounted game outcome is used by default.
+    pub const fn horizon(mut self, horizon: Option<usize>) -> Self {
+        self.horizon = horizon;
+        self
+    }
+
+    /// Discount per ply for value targets.
+    pub const fn discount(mut self, discount: f32) -> Self {
+        self.discount = discount;
+        self
+    }
+
+    /// Discount per ply for known results during search.
+    pub const fn mate_discount(mut self, mate_discount: f32) -> Self {
+        self.mate_discount = mate_discount;
+        self
+    }
+
+    /// Play under the variant, for example with a move limit. The standard
+    /// rules are played by default.
+    pub const fn variant(mut self, variant: Variant) -> Self {
+        self.variant = variant;
+        self
+    }
+
+    /// Check the configuration and set up the games.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the discount is not in (0, 1], the horizon is
+    /// zero, or the search parameters (including the mate discount) do not
+    /// fit together.
+    pub fn build(self) -> Result<SelfPlay, BuildSelfPlayError> {
+        if !(self.discount > 0.0 && self.discount <= 1.0) {
+            return Err(BuildSelfPlayError::Discount(self.discount));
+        }
+        if self.horizon == Some(0) {
+            return Err(BuildSelfPlayError::Horizon);
+        }
+
+        let seed = self
+            .state
+            .as_ref()
+            .map(|state| state.seed)
+            .or(self.seed)
+            .unwrap_or_else(|| rand::thread_rng().gen());
+        let mut rng = ChaCha12Rng::seed_from_u64(seed);
+        let net = Net::new(self.device, Some(rng.gen()));
+
+        let mut policy_targets: [_; BATCH_SIZE] = std::array::from_fn(|_| Vec::new());
+        let mut game_ids: [u64; BATCH_SIZE] = std::array::from_fn(|i| i as u64);
+        let mut next_game_id = BATCH_SIZE as u64;
+
+        let betas: [f32; BATCH_SIZE] = std::array::from_fn(|i| {
+            // Alternate, so that a smaller batch still explores with half of it.
+            if cfg!(feature = "exploration") && i % 2 == 0 {
+                BETA
+            } else {
+                0.0
+            }
+        });
+        let mut search = SearchBuilder::new(net)
+            .betas(betas)
+            .mate_discount(self.mate_discount)
+            .sampled_actions(SAMPLED_ACTIONS)
+            .search_budget(SEARCH_BUDGET);
+        if let Some(state) = self.state {
+            log::info!("Resuming unfinished games.");
+            let mut replays = Vec::with_capacity(BATCH_SIZE);
+            for ((game, game_id), policy_targets) in state
+                .games
+                .into_iter()
+                .zip(&mut game_ids)
+                .zip(&mut policy_targets)
+            {
+                *game_id = game.game_id;
+                *policy_targets = game.targets;
+                replays.push(self.variant.replay(game.replay));
+            }
+            next_game_id = state.next_game_id;
+            rng.set_word_pos(state.word_pos);
+            search = search.replays(
+                replays
+                    .try_into()
+                    .unwrap_or_else(|_| unreachable!("there is one replay per game")),
+            );
+        } else {
+            let mut actions = Vec::new();
+            search = search.envs(std::array::from_fn(|_| {
+                self.variant.opening(&mut rng, &mut actions)
+            }));
+        }
+
+        Ok(SelfPlay {
+            search: search.rng(rng).build()?,
+            seed,
+            policy_targets,
+            targets: Vec::new(),
+            complete_replays: Vec::new(),
+            #[cfg(feature = "exploration")]
+            exploration_replays: Vec::new(),
+            #[cfg(feature = "archive")]
+            archived_games: Vec::new(),
+            temperature: None,
+            game_ids,
+            next_game_id,
+            horizon: self.horizon,
+            discount: self.discount,
+        })
+    }
+}
+
+pub struct SelfPlay {
+    pub search: Search<BATCH_SIZE, Played, Net>,
+    seed: u64,
+    policy_targets: [Vec<IncompleteTarget>; BATCH_SIZE],
+    /// Targets of finished games which have not been saved yet.
+    pub targets: Vec<Target<Env>>,
+    /// Finished games which have not been saved yet.
+    pub complete_replays: Vec<Replay<Env>>,
+    #[cfg(feature = "exploration")]
+    pub exploration_replays: Vec<Replay<Env>>,
+    #[cfg(feature = "archive")]
+    pub archived_games: Vec<ArchivedGame<Env>>,
+    /// Temperature over visit counts for the opening moves, instead of
+    /// proportional sampling, as set through the control endpoint of the
+    /// trainer. Changes are recorded in the audit manifest.
+    pub temperature: Option<f32>,
+    game_ids: [u64; BATCH_SIZE],
+    next_game_id: u64,
+    horizon: Option<usize>,
+    discount: f32,
+}
+
+impl SelfPlay {
+    pub const fn seed(&self) -> u64 {
+        self.seed
+    }
+
+    /// Search all games and pick the actions to play, sampling early in the
+    /// game and making sure that identical games diverge.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the search fails, see [`Search::search`].
+    pub fn select_actions(&mut self) -> Result<[Move; BATCH_SIZE], SearchError> {
+        let mut selected_actions = self.search.search()?;
+        let temperature = self.temperature;
+        let Search { mcts, rng, .. } = &mut self.search;
+        selected_actions
+            .iter_mut()
+            .zip(mcts.nodes_and_envs())
+            .for_each(|(selected_action, (node, env))| {
+                if env.steps() < WEIGHTED_RANDOM_PLIES {
+                    *selected_action = temperature
+                        .and_then(|t| node.sample_action_with_temperature(t, &[], rng))
+                        .unwrap_or_else(|| node.select_selfplay_action(true, rng));
+                }
+            });
+        let diverged = mcts.diverge_duplicates(&mut selected_actions, DUPLICATE_TEMPERATURE, rng);
+        if diverged > 0 {
+            log::debug!("Resampled {diverged} actions to avoid duplicate games.");
+            REGISTRY.inc_counter(
+                "takzero_selfplay_diverged_duplicates_total",
+                "Actions resampled because another game was identical.",
+                diverged as f64,
+            );
+        }
+        Ok(selected_actions)
+    }
+
+    /// Take a step in each environment.
+    /// Generate target policy.
+    pub fn take_a_step(&mut self, selected_actions: &[Move; BATCH_SIZE]) {
+        let visitations = self.search.improved_policy_visitations() as f32;
+        let mate_discount = self.search.mate_discount();
+        let model_steps = logging::context().generation;
+        let mcts = &mut self.search.mcts;
+        let active: Vec<_> = mcts
+            .nodes_and_envs()
+            .zip(mcts.active())
+            .zip(&mut self.policy_targets)
+            .filter(|((_, active), _)| *active)
+            .map(|(((node, env), _), policy_targets)| (node, env, policy_targets))
+            .collect();
+        // The targets of each game are independent, so build them in parallel.
+        active.into_par_iter().for_each(|(node, env, policy_targets)| {
+            let root_ube_metric = node.ube_target(BETA, mate_discount);
+            policy_targets.push(IncompleteTarget {
+                env: env.game.clone(),
+                move_limit: env.move_limit(),
+                model_steps,
+                policy: node
+                    .improved_policy(visitations, mate_discount)
+                    .zip(node.children().iter())
+                    .map(|(p, (a, _))| (a, p))
+                    .collect(), // policy_target_from_proportional_visits(node),
+                root_ube_metric,
+                root_value: f32::from(node.evaluation()),
+                stats: RootStats::from_node(node, root_ube_metric.into_inner(), REPLAY_TOP_MOVES),
+            });
+        });
+        mcts.step(selected_actions);
+    }
+
+    /// Show the moves which were just played to spectators.
+    pub fn spectate(&self, selected_actions: &[Move; BATCH_SIZE]) {
+        if !SPECTATOR.is_enabled() {
+            return;
+        }
+        let mcts = &self.search.mcts;
+        mcts.nodes_and_envs()
+            .zip(mcts.active())
+            .zip(&self.policy_targets)
+            .zip(selected_actions.iter().zip(&self.game_ids))
+            .filter(|(((_, active), _), _)| *active)
+            .for_each(|((((_, env), _), policy_targets), (action, game_id))| {
+                let Some(target) = policy_targets.last() else {
+                    return;
+                };
+                let value = if target.env.to_move == Color::White {
+                    target.root_value
+                } else {
+                    -target.root_value
+                };
+                let tps = Tps::from(env.game.clone()).to_string();
+                SPECTATOR.play(*game_id, &action.to_string(), value, tps);
+            });
+    }
+
+    /// Restart any finished environments.
+    /// Complete targets of finished games using the game result.
+    pub fn restart_envs_and_complete_targets(&mut self) {
+        let betas = *self.search.betas();
+        let Search { mcts, rng, .. } = &mut self.search;
+        let (horizon, discount) = (self.horizon, self.discount);
+        #[allow(unused_variables)]
+        mcts.restart_terminal_envs(rng)
+            .zip(&mut self.policy_targets)
+            .zip(&betas)
+            .zip(&mut self.game_ids)
+            .for_each(|(((terminal_and_replay, policy_targets), beta), game_id)| {
+                if let Some((terminal, replay)) = terminal_and_replay {
+                    let mut replay = Replay::<Env>::from(replay);
+                    replay.generation = logging::context().generation;
+                    replay.game_id = Some(*game_id);
+                    // Positions of resumed games from before the restart have no targets.
+                    let offset = replay.len().saturating_sub(policy_targets.len());
+                    if offset == 0 {
+                        replay.stats = policy_targets.iter().map(|t| t.stats.clone()).collect();
+                    }
+                    #[cfg(feature = "exploration")]
+                    if *beta > 0.0 {
+                        // Keep enough actions to bootstrap the last target.
+                        let kept = usize::from(WEIGHTED_RANDOM_PLIES) + horizon.unwrap_or_default();
+                        self.exploration_replays.push(Replay {
+                            env: replay.env.clone(),
+                            actions: replay.actions.iter().copied().take(kept).collect(),
+                            adjudicated: None,
+                            generation: replay.generation,
+                            game_id: replay.game_id,
+                            stats: replay.stats.iter().take(kept).cloned().collect(),
+                        });
+                    }
+                    let plies = u16::try_from(replay.actions.len()).unwrap_or(u16::MAX);
+                    #[cfg(feature = "archive")]
+                    self.archived_games.push(ArchivedGame {
+                        replay: replay.clone(),
+                        generation: logging::context().generation.unwrap_or_default(),
+                        root_values: policy_targets.iter().map(|t| t.root_value).collect(),
+                    });
+                    let values = n_step_values(&replay, horizon, discount, |positions| {
+                        positions
+                            .iter()
+                            .map(|env| {
+                                usize::from(env.ply - replay.env.ply)
+                                    .checked_sub(offset)
+                                    .map_or(0.0, |i| policy_targets[i].root_value)
+                            })
+                            .collect()
+                    });
+                    self.complete_replays.push(replay);
+                    if let Some(last) = policy_targets.last() {
+                        // The terminal is for the player after the one who moved last.
+                        let to_move = match last.env.to_move {
+                            Color::White => Color::Black,
+                            Color::Black => Color::White,
+                        };
+                        let outcome = Outcome::from_terminal(terminal, to_move);
+                        SPECTATOR.finish(*game_id, outcome.result());
+                    }
+
+                    // Create targets.
+                    logging::with_game(*game_id, plies, || {
+                        log::debug!(
+                            "Game finished with value {}.",
+                            f32::from(Eval::from(terminal))
+                        );
+                    });
+                    *game_id = self.next_game_id;
+                    self.next_game_id += 1;
+                    for (
+                        IncompleteTarget {
+                            env,
+                            move_limit,
+                            model_steps,
+                            policy,
+                            root_ube_metric,
+                            ..
+                        },
+                        value,
+                    ) in policy_targets.drain(..).zip(&values[offset..]).rev()
+                    {
+                        // Only generate targets from non-exploratory episodes.
+                        // (Or after the initial exploration.)
+                        if *beta == 0.0 || env.ply > WEIGHTED_RANDOM_PLIES {
+                            self.targets.push(Target {
+                                env,
+                                value: *value,
+                                ube: root_ube_metric.into_inner(),
+                                policy,
+                                weight: None,
+                                move_limit,
+                                model_steps,
+                            });
+                        }
+                    }
+                }
+            });
+    }
+
+    /// Start new games in every environment, dropping the unfinished ones
+    /// and their targets, for example when searching them keeps failing.
+    pub fn abandon_games(&mut self) {
+        let Search { mcts, rng, .. } = &mut self.search;
+        mcts.restart_all_envs(rng);
+        for (game_id, policy_targets) in self.game_ids.iter_mut().zip(&mut self.policy_targets) {
+            policy_targets.clear();
+            *game_id = self.next_game_id;
+            self.next_game_id += 1;
+        }
+    }
+
+    /// Throw away the targets and replays of finished games instead of
+    /// saving them.
+    pub fn discard_finished(&mut self) {
+        self.targets.clear();
+        self.complete_replays.clear();
+        #[cfg(feature = "exploration")]
+        self.exploration_replays.clear();
+        #[cfg(feature = "archive")]
+        self.archived_games.clear();
+    }
+
+    /// Save the in-flight games, see [`resume::save`].
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the file cannot be written.
+    pub fn save_state(&self, path: &Path) -> std::io::Result<()> {
+        resume::save(
+            path,
+            self.seed,
+            &self.search.rng,
+            self.next_game_id,
+            &self.game_ids,
+            self.search.mcts.replays().cloned().map(Replay::from),
+            &self.policy_targets,
+        )
+    }
+}
@@ -0,0 +1,247 @@
+//! Split targets or replays into a training and a validation set by time
+//! instead of at random. Consecutive positions of a game are nearly the
+//! same, so a random split leaks them between the two sets and makes the
+//! validation loss look better than it is.
+//!
+//! Inputs are ordered by the generation and creation time in their headers,
+//! and the lines of a file are in the order they were written. Either the
+//! newest fraction of the lines (`--fraction 0.1`) or everything from some
+//! generation on (`--from-generation 1200`) becomes the validation set, and
+//! `--gap` drops the lines (or generations) just before it. The generation
+//! of a target is the steps of the model which produced it, and that of a
+//! replay is the one it records (or else the one of its file). Targets
+//! written before they recorded model steps are skipped when splitting by
+//! generation.
+
+use std::{
+    fs::File,
+    io::{self, BufRead, BufWriter, Write},
+    path::PathBuf,
+};
+
+use clap::Parser;
+use takzero::{
+    any::{AnyReplay, AnyTarget},
+    header::{read_any_header, FileKind, Header},
+    reader,
+};
+
+/// Half komi of replays without a header or `Komi` tag.
+const DEFAULT_HALF_KOMI: i8 = 4;
+
+#[derive(Parser, Debug)]
+struct Args {
+    /// Target or replay files to split, in any order
+    #[arg(required = true)]
+    input: Vec<PathBuf>,
+    /// File to write the training set to
+    #[arg(long)]
+    train: PathBuf,
+    /// File to write the validation set to
+    #[arg(long)]
+    validation: PathBuf,
+    /// Fraction of the newest lines which become the validation set
+    #[arg(long, default_value_t = 0.1)]
+    fraction: f64,
+    /// Use everything from this generation on as the validation set instead
+    #[arg(long)]
+    from_generation: Option<usize>,
+    /// Lines (or generations with `--from-generation`) just before the
+    /// validation set which are dropped
+    #[arg(long, default_value_t = 0)]
+    gap: usize,
+}
+
+/// Where a line goes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Part {
+    Train,
+    Validation,
+    Gap,
+}
+
+/// Start of the validation set.
+#[derive(Debug, Clone, Copy)]
+enum Boundary {
+    /// Index of the first line, counting lines of all inputs in order.
+    Line(usize),
+    Generation(usize),
+}
+
+impl Boundary {
+    /// Part of a line, or `None` if it needs a generation which is unknown.
+    fn part(self, line: usize, generation: Option<usize>, gap: usize) -> Option<Part> {
+        let (position, boundary) = match self {
+            Self::Line(boundary) => (line, boundary),
+            Self::Generation(boundary) => (generation?, boundary),
+        };
+        Some(if position >= boundary {
+            Part::Validation
+        } else if position + gap >= boundary {
+            Part::Gap
+        } else {
+            Part::Train
+        })
+    }
+}
+
+struct Input {
+    path: PathBuf,
+    header: Option<Header>,
+}
+
+impl Input {
+    /// The data lines of the input, without the header.
+    fn lines(&self) -> io::Result<impl Iterator<Item = io::Result<String>>> {
+        let mut reader = reader::open(&self.path)?;
+        read_any_header(&mut reader)?;
+        Ok(reader
+            .lines()
+            .filter(|line| !matches!(line, Ok(line) if line.trim().is_empty())))
+    }
+}
+
+fn main() {
+    takzero::logging::init();
+    let args = Args::parse();
+    if let Err(err) = split(&args) {
+        log::error!("{err}");
+        std::process::exit(1);
+    }
+}
+
+fn split(args: &Args) -> io::Result<()> {
+    let mut inputs = args
+        .input
+        .iter()
+        .map(|path| {
+            let header = read_any_header(&mut reader::open(path)?)?;
+            Ok(Input {
+                path: path.clone(),
+                header,
+            })
+        })
+        .collect::<io::Result<Vec<_>>>()?;
+    // Files without a header are from before headers existed.
+    inputs.sort_by_key(|input| {
+        let order = input.header.as_ref().map(|h| (h.generation, h.created));
+        (order, input.path.clone())
+    });
+    let headers: Vec<_> = inputs.iter().filter_map(|input| input.header.as_ref()).collect();
+    if headers.windows(2).any(|pair| {
+        (pair[0].kind, pair[0].size, pair[0].half_komi)
+            != (pair[1].kind, pair[1].size, pair[1].half_komi)
+    }) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "the inputs are for different kinds, sizes, or komis",
+        ));
+    }
+    let header = headers.last().map(|header| Header {
+        generation: None,
+        ..(*header).clone()
+    });
+
+    let boundary = if let Some(generation) = args.from_generation {
+        Boundary::Generation(generation)
+    } else {
+        let mut total = 0;
+        for input in &inputs {
+            total += input.lines()?.count();
+        }
+        #[allow(clippy::cast_sign_loss)]
+        let validation = (total as f64 * args.fraction.clamp(0.0, 1.0)).round() as usize;
+        Boundary::Line(total - validation)
+    };
+
+    let mut train = BufWriter::new(File::create(&args.train)?);
+    let mut validation = BufWriter::new(File::create(&args.validation)?);
+    if let Some(header) = &header {
+        write!(train, "{header}")?;
+        write!(validation, "{header}")?;
+    }
+    let (mut trained, mut validated, mut dropped, mut unknown) = (0, 0, 0, 0);
+    let mut index = 0;
+    for input in &inputs {
+        for line in input.lines()? {
+            let line = line?;
+            let generation = generation(&line, input.header.as_ref());
+            match boundary.part(index, generation, args.gap) {
+                Some(Part::Train) => {
+                    writeln!(train, "{}", line.trim_end())?;
+                    trained += 1;
+                }
+                Some(Part::Validation) => {
+                    writeln!(validation, "{}", line.trim_end())?;
+                    validated += 1;
+                }
+                Some(Part::Gap) => dropped += 1,
+                None => unknown += 1,
+            }
+            index += 1;
+        }
+    }
+    train.flush()?;
+    validation.flush()?;
+
+    log::info!(
+        "{trained} lines for training, {validated} for validation, {dropped} dropped in the gap"
+    );
+    if unknown > 0 {
+        log::warn!("skipped {unknown} lines without a known generation");
+    }
+    Ok(())
+}
+
+/// Generation of a line: the model steps of a target, or the generation of
+/// a replay or else of the file it is in.
+fn generation(line: &str, header: Option<&Header>) -> Option<usize> {
+    match header {
+        Some(header) if header.kind == FileKind::Targets => {
+            AnyTarget::parse(line, header.size, header.half_komi).ok()?.model_steps()
+        }
+        Some(header) => AnyReplay::parse(line, header.size, header.half_komi)
+            .ok()
+            .and_then(|replay| replay.generation())
+            .or(header.generation),
+        None if line.starts_with('[') => {
+            AnyReplay::detect(line, DEFAULT_HALF_KOMI).ok()?.generation()
+        }
+        None => AnyTarget::detect(line, DEFAULT_HALF_KOMI).ok()?.model_steps(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use takzero::header::{FileKind, Header};
+
+    use super::{generation, Boundary, Part};
+
+    #[test]
+    fn parts() {
+        let parts: Vec<_> = (0..6)
+            .map(|line| Boundary::Line(4).part(line, None, 1).unwrap())
+            .collect();
+        assert_eq!(parts, [
+            Part::Train,
+            Part::Train,
+            Part::Train,
+            Part::Gap,
+            Part::Validation,
+            Part::Validation,
+        ]);
+        assert_eq!(Boundary::Generation(10).part(0, None, 0), None);
+        assert_eq!(Boundary::Generation(10).part(0, Some(7), 2), Some(Part::Train));
+        assert_eq!(Boundary::Generation(10).part(0, Some(8), 2), Some(Part::Gap));
+        assert_eq!(Boundary::Generation(10).part(0, Some(12), 2), Some(Part::Validation));
+    }
+
+    #[test]
+    fn targets_use_their_model_steps() {
+        let target = "x3/x3/x3 1 1;0.5;0;a1:0.5,a2:0.5,a3:0,b1:0,b2:0,b3:0,c1:0,c2:0,c3:0";
+        let header = Header::new::<3, 0>(FileKind::Targets, Some(1000));
+        assert_eq!(generation(&format!("{target};;;1200"), Some(&header)), Some(1200));
+        assert_eq!(generation(&format!("{target};;;1200"), None), Some(1200));
+        assert_eq!(generation(target, Some(&header)), None);
+    }
+}
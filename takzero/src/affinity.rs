@@ -0,0 +1,187 @@
+//! Pinning of worker threads to cores.
+//!
+//! Self-play, target loading, and training share a machine, and when the
+//! scheduler moves their threads around freely they keep evicting each
+//! other from the same cores. A [`CoreSet`] names the cores one part of the
+//! pipeline may use, either as a list like `0-15,32-47` or as a NUMA node
+//! like `node:1`, and pins threads and thread pools to them.
+//!
+//! Pinning is only implemented on Linux. Elsewhere it fails with
+//! [`io::ErrorKind::Unsupported`], and the threads are left to the scheduler.
+
+use std::{fs, io, str::FromStr};
+
+use rayon::{ThreadPool, ThreadPoolBuildError, ThreadPoolBuilder};
+use thiserror::Error;
+
+/// A non-empty set of cores, in the order they were given.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CoreSet {
+    cores: Vec<usize>,
+}
+
+#[derive(Error, Debug)]
+pub enum ParseCoreSetError {
+    #[error("core set `{0}` is not a list of cores like `0-7,16` or a NUMA node like `node:0`")]
+    Format(String),
+    #[error("could not read the cores of NUMA node {node}: {source}")]
+    Numa { node: usize, source: io::Error },
+}
+
+impl FromStr for CoreSet {
+    type Err = ParseCoreSetError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(node) = s.trim().strip_prefix("node:") {
+            let node = node
+                .parse()
+                .map_err(|_| ParseCoreSetError::Format(s.to_string()))?;
+            return Self::numa_node(node);
+        }
+        parse_list(s).ok_or_else(|| ParseCoreSetError::Format(s.to_string()))
+    }
+}
+
+impl CoreSet {
+    /// The cores which belong to a NUMA node, as reported by the kernel.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the node does not exist or has no cores.
+    pub fn numa_node(node: usize) -> Result<Self, ParseCoreSetError> {
+        let path = format!("/sys/devices/system/node/node{node}/cpulist");
+        let list = fs::read_to_string(path)
+            .map_err(|source| ParseCoreSetError::Numa { node, source })?;
+        parse_list(&list).ok_or_else(|| ParseCoreSetError::Format(list.trim().to_string()))
+    }
+
+    #[must_use]
+    pub fn cores(&self) -> &[usize] {
+        &self.cores
+    }
+
+    /// Allow the current thread to run on any core of the set. Threads it
+    /// spawns afterwards (like the CPU threads of `LibTorch`) inherit this.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the affinity could not be set.
+    pub fn pin_current(&self) -> io::Result<()> {
+        set_affinity(&self.cores)
+    }
+
+    /// Build a thread pool with one thread per core of the set, each pinned
+    /// to its own core. Threads which cannot be pinned log a warning and
+    /// run unpinned.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the threads could not be spawned.
+    pub fn thread_pool(&self, name: &str) -> Result<ThreadPool, ThreadPoolBuildError> {
+        self.builder(name).build()
+    }
+
+    /// Like [`CoreSet::thread_pool`], but for the global rayon pool, which
+    /// the search uses. This also pins the current thread to the set.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the global pool was already initialized or the
+    /// threads could not be spawned.
+    pub fn install_global(&self, name: &str) -> Result<(), ThreadPoolBuildError> {
+        if let Err(err) = self.pin_current() {
+            log::warn!("Could not pin the {name} thread to cores {:?}: {err}", self.cores);
+        }
+        self.builder(name).build_global()
+    }
+
+    fn builder(&self, name: &str) -> ThreadPoolBuilder {
+        let name = name.to_string();
+        let cores = self.cores.clone();
+        ThreadPoolBuilder::new()
+            .num_threads(self.cores.len())
+            .thread_name({
+                let name = name.clone();
+                move |i| format!("{name}-{i}")
+            })
+            .start_handler(move |i| {
+                if let Err(err) = set_affinity(&cores[i..=i]) {
+                    log::warn!("Could not pin {name}-{i} to core {}: {err}", cores[i]);
+                }
+            })
+    }
+}
+
+/// Parse a list in the format the kernel uses, like `0-3,8,10-11`.
+fn parse_list(s: &str) -> Option<CoreSet> {
+    let mut cores = Vec::new();
+    for range in s.trim().split(',') {
+        let (start, end) = range.split_once('-').unwrap_or((range, range));
+        let (start, end): (usize, usize) = (start.trim().parse().ok()?, end.trim().parse().ok()?);
+        if start > end {
+            return None;
+        }
+        for core in start..=end {
+            if !cores.contains(&core) {
+                cores.push(core);
+            }
+        }
+    }
+    Some(CoreSet { cores })
+}
+
+#[cfg(target_os = "linux")]
+fn set_affinity(cores: &[usize]) -> io::Result<()> {
+    if cores.iter().any(|&core| core >= libc::CPU_SETSIZE as usize) {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "core index is too large"));
+    }
+    // SAFETY: An all-zero `cpu_set_t` is the empty set, the cores were
+    // checked to fit in it, and the size passed is the size of the set.
+    let result = unsafe {
+        let mut set: libc::cpu_set_t = std::mem::zeroed();
+        for &core in cores {
+            libc::CPU_SET(core, &mut set);
+        }
+        libc::sched_setaffinity(0, std::mem::size_of::<libc::cpu_set_t>(), std::ptr::addr_of!(set))
+    };
+    if result == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn set_affinity(_cores: &[usize]) -> io::Result<()> {
+    Err(io::ErrorKind::Unsupported.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{CoreSet, ParseCoreSetError};
+
+    #[test]
+    fn parse_lists() {
+        let set: CoreSet = "0-3,8, 10-11".parse().unwrap();
+        assert_eq!(set.cores(), [0, 1, 2, 3, 8, 10, 11]);
+        // Overlapping ranges do not repeat cores.
+        let set: CoreSet = "2-4,3,1".parse().unwrap();
+        assert_eq!(set.cores(), [2, 3, 4, 1]);
+
+        for invalid in ["", "3-1", "a", "1,,2", "node:x"] {
+            assert!(
+                matches!(invalid.parse::<CoreSet>(), Err(ParseCoreSetError::Format(_))),
+                "{invalid}"
+            );
+        }
+    }
+
+    #[test]
+    fn pool_has_a_thread_per_core() {
+        let set: CoreSet = "0".parse().unwrap();
+        let pool = set.thread_pool("test").unwrap();
+        assert_eq!(pool.current_num_threads(), 1);
+        let name = pool.install(|| std::thread::current().name().map(ToString::to_string));
+        assert_eq!(name.as_deref(), Some("test-0"));
+    }
+}
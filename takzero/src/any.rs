@@ -0,0 +1,350 @@
+//! Targets and replays of any supported board size and komi.
+//!
+//! [`Target`] and [`Replay`] are generic over the game, so tooling which
+//! handles files of several sizes would otherwise need one monomorph per
+//! size. [`AnyTarget`] and [`AnyReplay`] wrap every supported instantiation
+//! and pick one when parsing, from the file header or from the line itself.
+
+use std::{
+    fmt,
+    io::{self, BufRead},
+    path::Path,
+};
+
+use fast_tak::Game;
+use thiserror::Error;
+
+use crate::{
+    header::{read_any_header, FileKind},
+    ptn::{parse_half_komi, tag},
+    reader::{self, Categorize, ErrorCategory},
+    target::{ParseReplayError, ParseTargetError, Replay, Target},
+};
+
+#[derive(Error, Debug)]
+pub enum AnyParseError {
+    #[error("size {size} with half komi {half_komi} is not supported")]
+    Unsupported { size: usize, half_komi: i8 },
+    #[error("could not tell the board size")]
+    UnknownSize,
+    #[error("{0}")]
+    Target(#[from] ParseTargetError),
+    #[error("{0}")]
+    Replay(#[from] ParseReplayError),
+}
+
+impl Categorize for AnyParseError {
+    fn category(&self) -> ErrorCategory {
+        match self {
+            Self::Unsupported { .. } | Self::UnknownSize => ErrorCategory::Tps,
+            Self::Target(err) => err.category(),
+            Self::Replay(err) => err.category(),
+        }
+    }
+}
+
+macro_rules! any {
+    ($($variant:ident = ($n:literal, $half_komi:literal)),* $(,)?) => {
+        /// Board sizes and half komis which can be wrapped.
+        pub const SUPPORTED: &[(usize, i8)] = &[$(($n, $half_komi)),*];
+
+        /// A [`Target`] of any supported size and komi. Variants are named
+        /// after the size and half komi.
+        #[derive(Debug)]
+        pub enum AnyTarget {
+            $($variant(Target<Game<$n, $half_komi>>),)*
+        }
+
+        /// A [`Replay`] of any supported size and komi. Variants are named
+        /// after the size and half komi.
+        #[derive(Debug, Clone, PartialEq)]
+        pub enum AnyReplay {
+            $($variant(Replay<Game<$n, $half_komi>>),)*
+        }
+
+        impl AnyTarget {
+            /// Parse a target for the given size and komi.
+            ///
+            /// # Errors
+            ///
+            /// Returns an error if the size and komi are not supported or
+            /// the target does not parse.
+            pub fn parse(s: &str, size: usize, half_komi: i8) -> Result<Self, AnyParseError> {
+                match (size, half_komi) {
+                    $(($n, $half_komi) => Ok(Self::$variant(s.parse()?)),)*
+                    _ => Err(AnyParseError::Unsupported { size, half_komi }),
+                }
+            }
+
+            #[must_use]
+            pub const fn size(&self) -> usize {
+                match self {
+                    $(Self::$variant(_) => $n,)*
+                }
+            }
+
+            #[must_use]
+            pub const fn half_komi(&self) -> i8 {
+                match self {
+                    $(Self::$variant(_) => $half_komi,)*
+                }
+            }
+
+            /// Steps of the model which produced the target, if known.
+            #[must_use]
+            pub const fn model_steps(&self) -> Option<usize> {
+                match self {
+                    $(Self::$variant(target) => target.model_steps,)*
+                }
+            }
+        }
+
+        impl fmt::Display for AnyTarget {
+            fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                match self {
+                    $(Self::$variant(target) => target.fmt(f),)*
+                }
+            }
+        }
+
+        impl AnyReplay {
+            /// Parse a replay for the given size and komi.
+            ///
+            /// # Errors
+            ///
+            /// Returns an error if the size and komi are not supported or
+            /// the replay does not parse.
+            pub fn parse(s: &str, size: usize, half_komi: i8) -> Result<Self, AnyParseError> {
+                match (size, half_komi) {
+                    $(($n, $half_komi) => Ok(Self::$variant(s.parse()?)),)*
+                    _ => Err(AnyParseError::Unsupported { size, half_komi }),
+                }
+            }
+
+            #[must_use]
+            pub const fn size(&self) -> usize {
+                match self {
+                    $(Self::$variant(_) => $n,)*
+                }
+            }
+
+            #[must_use]
+            pub const fn half_komi(&self) -> i8 {
+                match self {
+                    $(Self::$variant(_) => $half_komi,)*
+                }
+            }
+
+            /// Number of actions in the replay.
+            #[must_use]
+            pub fn len(&self) -> usize {
+                match self {
+                    $(Self::$variant(replay) => replay.len(),)*
+                }
+            }
+
+            #[must_use]
+            pub fn is_empty(&self) -> bool {
+                self.len() == 0
+            }
+
+            /// Generation of the network which played the game, if known.
+            #[must_use]
+            pub const fn generation(&self) -> Option<usize> {
+                match self {
+                    $(Self::$variant(replay) => replay.generation,)*
+                }
+            }
+
+            /// Set the generation of a replay which does not record one.
+            pub fn fill_generation(&mut self, generation: usize) {
+                match self {
+                    $(Self::$variant(replay) => {
+                        replay.generation.get_or_insert(generation);
+                    })*
+                }
+            }
+        }
+
+        impl fmt::Display for AnyReplay {
+            fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                match self {
+                    $(Self::$variant(replay) => replay.fmt(f),)*
+                }
+            }
+        }
+    };
+}
+
+any!(
+    S3K0 = (3, 0),
+    S3K4 = (3, 4),
+    S4K0 = (4, 0),
+    S4K4 = (4, 4),
+    S5K0 = (5, 0),
+    S5K4 = (5, 4),
+    S6K0 = (6, 0),
+    S6K4 = (6, 4),
+    S7K0 = (7, 0),
+    S7K4 = (7, 4),
+    S8K0 = (8, 0),
+    S8K4 = (8, 4),
+);
+
+/// Board size of a TPS, counted from the rows of its board.
+fn tps_size(tps: &str) -> Option<usize> {
+    let board = tps.split_whitespace().next()?;
+    Some(board.split('/').count())
+}
+
+impl AnyTarget {
+    /// Parse a target, taking the size from its TPS.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the size cannot be told, the size and komi are
+    /// not supported, or the target does not parse.
+    pub fn detect(s: &str, half_komi: i8) -> Result<Self, AnyParseError> {
+        let tps = s.split(';').next().unwrap_or_default();
+        let size = tps_size(tps).ok_or(AnyParseError::UnknownSize)?;
+        Self::parse(s, size, half_komi)
+    }
+}
+
+impl AnyReplay {
+    /// Parse a replay, taking the size from its TPS and the komi from its
+    /// `Komi` tag, or `half_komi` if it has none.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the size cannot be told, the size and komi are
+    /// not supported, or the replay does not parse.
+    pub fn detect(s: &str, half_komi: i8) -> Result<Self, AnyParseError> {
+        let size = tag(s, "TPS")
+            .and_then(tps_size)
+            .ok_or(AnyParseError::UnknownSize)?;
+        let half_komi = match tag(s, "Komi") {
+            Some(komi) => parse_half_komi(komi)
+                .ok_or_else(|| ParseReplayError::WrongKomi(komi.to_string()))?,
+            None => half_komi,
+        };
+        Self::parse(s, size, half_komi)
+    }
+}
+
+/// Open a file of any supported size and komi and lazily parse its lines,
+/// which may be gzipped. The size and komi are taken from the header, or,
+/// for files without one, from each line and `half_komi`. Lines which do
+/// not parse are skipped.
+fn get_any<T>(
+    path: impl AsRef<Path>,
+    kind: FileKind,
+    half_komi: i8,
+    parse: fn(&str, usize, i8) -> Result<T, AnyParseError>,
+    detect: fn(&str, i8) -> Result<T, AnyParseError>,
+) -> io::Result<impl Iterator<Item = T>> {
+    let mut reader = reader::open(path)?;
+    let header = read_any_header(&mut reader)?;
+    if let Some(header) = &header {
+        header
+            .check_kind(kind)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+    }
+    Ok(reader.lines().map_while(Result::ok).filter_map(move |line| {
+        if line.trim().is_empty() {
+            return None;
+        }
+        header
+            .as_ref()
+            .map_or_else(
+                || detect(&line, half_komi),
+                |header| parse(&line, header.size, header.half_komi),
+            )
+            .ok()
+    }))
+}
+
+/// Open a replay file of any supported size and komi. Files without a
+/// header are read with the size of each replay and its komi, or
+/// `half_komi` for replays without a `Komi` tag.
+///
+/// # Errors
+///
+/// Returns an error if the file cannot be opened or its header is malformed
+/// or for targets.
+pub fn get_any_replays(
+    path: impl AsRef<Path>,
+    half_komi: i8,
+) -> io::Result<impl Iterator<Item = AnyReplay>> {
+    get_any(path, FileKind::Replays, half_komi, AnyReplay::parse, AnyReplay::detect)
+}
+
+/// Open a target file of any supported size and komi. Files without a
+/// header are read with the size of each target and `half_komi`.
+///
+/// # Errors
+///
+/// Returns an error if the file cannot be opened or its header is malformed
+/// or for replays.
+pub fn get_any_targets(
+    path: impl AsRef<Path>,
+    half_komi: i8,
+) -> io::Result<impl Iterator<Item = AnyTarget>> {
+    get_any(path, FileKind::Targets, half_komi, AnyTarget::parse, AnyTarget::detect)
+}
+
+#[cfg(test)]
+mod tests {
+    use fast_tak::Game;
+    use ordered_float::NotNan;
+
+    use super::{get_any_replays, AnyParseError, AnyReplay, AnyTarget};
+    use crate::{
+        header::{FileKind, Header, VERSION},
+        search::env::Environment,
+        target::{Replay, Target},
+    };
+
+    #[test]
+    fn dispatch_on_size_and_komi() {
+        let mut replay = Replay::new(Game::<5, 4>::default());
+        replay.push("a1".parse().unwrap());
+        let line = replay.to_string();
+
+        let any = AnyReplay::detect(&line, 0).unwrap();
+        assert!(matches!(any, AnyReplay::S5K4(_)));
+        assert_eq!((any.size(), any.half_komi(), any.len()), (5, 4, 1));
+        assert_eq!(any.to_string(), line);
+        assert!(matches!(AnyReplay::parse(&line, 9, 0), Err(AnyParseError::Unsupported { .. })));
+
+        let env = Game::<3, 0>::default();
+        let mut actions = Vec::new();
+        env.populate_actions(&mut actions);
+        let target = Target {
+            env,
+            policy: actions.into_iter().map(|a| (a, NotNan::new(0.1).unwrap())).collect(),
+            value: 0.5,
+            ube: 0.25,
+            weight: None,
+            move_limit: None,
+            model_steps: None,
+        };
+        let any = AnyTarget::detect(&target.to_string(), 0).unwrap();
+        assert!(matches!(&any, AnyTarget::S3K0(parsed) if *parsed == target));
+        assert!(AnyTarget::detect("", 0).is_err());
+
+        let path = std::env::temp_dir().join(format!("takzero-any-{}.txt", std::process::id()));
+        let header = Header::new::<5, 4>(FileKind::Replays, None);
+        std::fs::write(&path, format!("{header}{line}\nnot a replay\n")).unwrap();
+        assert_eq!(get_any_replays(&path, 0).unwrap().collect::<Vec<_>>(), [
+            AnyReplay::detect(&line, 0).unwrap()
+        ]);
+        let newer = Header {
+            version: VERSION + 1,
+            ..header
+        };
+        std::fs::write(&path, format!("{newer}{line}\n")).unwrap();
+        assert!(get_any_replays(&path, 0).is_err());
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,698 @@
+//! `SQLite` archive of finished games.
+//!
+//! Self-play can optionally record every finished game together with the
+//! network generation that played it and the root value before every move.
+//! This makes questions like "how does the draw rate change over training"
+//! a single query instead of a pass over the flat replay files.
+//!
+//! Every position of an archived game is also indexed by its Zobrist key and
+//! material signature, which answers "how often does the network reach this
+//! structure and how does it score?" by generation. Games are also grouped
+//! by their [`Opening`], to show how diverse the openings of each generation
+//! are.
+
+use std::path::Path;
+
+use fast_tak::{
+    takparse::{Color, GameResult, Piece, Tps},
+    Game,
+    Reserves,
+};
+use sqlite::{Connection, Row, State, Value};
+use thiserror::Error;
+
+use crate::{
+    opening::{Opening, OpeningTable},
+    quality::ValueQuality,
+    search::env::Environment,
+    target::{ParseReplayError, Replay},
+    zobrist::zobrist,
+};
+
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS games (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    size INTEGER NOT NULL,
+    start TEXT NOT NULL,
+    moves TEXT NOT NULL,
+    plies INTEGER NOT NULL,
+    result TEXT,
+    generation INTEGER NOT NULL,
+    root_values TEXT NOT NULL,
+    game_id INTEGER,
+    half_komi INTEGER
+);
+CREATE INDEX IF NOT EXISTS games_generation ON games (generation);
+CREATE TABLE IF NOT EXISTS positions (
+    key INTEGER NOT NULL,
+    material TEXT NOT NULL,
+    game INTEGER NOT NULL REFERENCES games (id),
+    ply INTEGER NOT NULL,
+    generation INTEGER NOT NULL,
+    root_value REAL
+);
+CREATE INDEX IF NOT EXISTS positions_key ON positions (key, generation);
+CREATE INDEX IF NOT EXISTS positions_material ON positions (material, generation);
+";
+
+/// Columns which were added to `games` later, which older archives get
+/// when they are opened. They are `NULL` for the games stored before.
+const ADDED_COLUMNS: [&str; 2] = ["game_id", "half_komi"];
+
+const DRAW: &str = "1/2-1/2";
+
+#[derive(Error, Debug)]
+pub enum ArchiveError {
+    #[error("sqlite: {0}")]
+    Sqlite(#[from] sqlite::Error),
+    #[error("replay: {0}")]
+    Replay(#[from] ParseReplayError),
+    #[error("root value: {0}")]
+    RootValue(#[from] std::num::ParseFloatError),
+}
+
+/// A finished game as stored in the archive.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ArchivedGame<E: Environment> {
+    pub replay: Replay<E>,
+    /// Training steps of the network which played the game.
+    pub generation: usize,
+    /// Root value before each move, from the perspective of the player to
+    /// move.
+    pub root_values: Vec<f32>,
+}
+
+/// How often one generation reached a position (or material signature).
+#[derive(Debug, Clone, PartialEq)]
+pub struct PositionStats {
+    pub generation: usize,
+    /// Number of times the position was reached.
+    pub occurrences: usize,
+    /// Number of distinct games which reached it.
+    pub games: usize,
+    /// Average root value from the perspective of the player to move,
+    /// or `None` if no root value was recorded.
+    pub average_value: Option<f64>,
+}
+
+/// A game which reached a position.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Occurrence {
+    /// Id of the game in self-play, if it was recorded.
+    pub game: Option<u64>,
+    pub half_komi: Option<i8>,
+    pub generation: usize,
+    /// Ply at which the position was reached.
+    pub ply: usize,
+    pub result: Option<String>,
+    pub start: String,
+    pub moves: String,
+}
+
+/// Material signature of the position: the number of flats, walls, and
+/// capstones on top of stacks for white and then black, like `5F1S0C/4F0S1C`.
+#[must_use]
+pub fn material<const N: usize, const HALF_KOMI: i8>(game: &Game<N, HALF_KOMI>) -> String
+where
+    Reserves<N>: Default,
+{
+    let mut counts = [[0; 3]; 2];
+    for row in game.board.iter() {
+        for stack in row {
+            if let Some((piece, color)) = stack.top() {
+                let piece = match piece {
+                    Piece::Flat => 0,
+                    Piece::Wall => 1,
+                    Piece::Cap => 2,
+                };
+                counts[usize::from(color == Color::Black)][piece] += 1;
+            }
+        }
+    }
+    let [[wf, ws, wc], [bf, bs, bc]] = counts;
+    format!("{wf}F{ws}S{wc}C/{bf}F{bs}S{bc}C")
+}
+
+/// Zobrist keys are stored as signed integers with the same bits.
+const fn key_value(key: u64) -> i64 {
+    i64::from_ne_bytes(key.to_ne_bytes())
+}
+
+fn game_id(row: &Row) -> Option<u64> {
+    row.read::<Option<i64>, _>("game_id")
+        .and_then(|id| id.try_into().ok())
+}
+
+fn parse_game<const N: usize, const HALF_KOMI: i8>(
+    row: &Row,
+    generation: usize,
+) -> Result<ArchivedGame<Game<N, HALF_KOMI>>, ArchiveError>
+where
+    Reserves<N>: Default,
+{
+    let mut replay = format!(
+        "[TPS \"{}\"] {}",
+        row.read::<&str, _>("start"),
+        row.read::<&str, _>("moves")
+    )
+    .parse::<Replay<Game<N, HALF_KOMI>>>()?;
+    replay.game_id = game_id(row);
+    let root_values = row
+        .read::<&str, _>("root_values")
+        .split(',')
+        .filter(|s| !s.is_empty())
+        .map(str::parse)
+        .collect::<Result<_, _>>()?;
+    Ok(ArchivedGame {
+        replay,
+        generation,
+        root_values,
+    })
+}
+
+pub struct GameArchive {
+    connection: Connection,
+}
+
+impl GameArchive {
+    /// Open the archive at the given path, creating it if it does not exist.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the database cannot be opened or initialized.
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, ArchiveError> {
+        let connection = sqlite::open(path)?;
+        connection.execute(SCHEMA)?;
+        let columns = connection
+            .prepare("SELECT name FROM pragma_table_info('games')")?
+            .into_iter()
+            .map(|row| Ok(row?.read::<&str, _>("name").to_string()))
+            .collect::<Result<Vec<_>, ArchiveError>>()?;
+        for column in ADDED_COLUMNS {
+            if !columns.iter().any(|name| name == column) {
+                connection.execute(format!("ALTER TABLE games ADD COLUMN {column} INTEGER"))?;
+            }
+        }
+        Ok(Self { connection })
+    }
+
+    /// Store a finished game and return its row in the archive, which is not
+    /// its id in self-play.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the game cannot be inserted.
+    pub fn insert<const N: usize, const HALF_KOMI: i8>(
+        &self,
+        game: &ArchivedGame<Game<N, HALF_KOMI>>,
+    ) -> Result<i64, ArchiveError>
+    where
+        Reserves<N>: Default,
+    {
+        let mut env = game.replay.env.clone();
+        for action in &game.replay.actions {
+            env.step(*action);
+        }
+        let result = GameResult::try_from(env.result())
+            .map_or(Value::Null, |result| result.to_string().into());
+        let moves = game
+            .replay
+            .actions
+            .iter()
+            .map(ToString::to_string)
+            .collect::<Vec<_>>()
+            .join(" ");
+        let root_values = game
+            .root_values
+            .iter()
+            .map(ToString::to_string)
+            .collect::<Vec<_>>()
+            .join(",");
+
+        let mut statement = self.connection.prepare(
+            "INSERT INTO games (size, start, moves, plies, result, generation, root_values,
+                game_id, half_komi)
+            VALUES (:size, :start, :moves, :plies, :result, :generation, :root_values,
+                :game_id, :half_komi)",
+        )?;
+        let game_id = game
+            .replay
+            .game_id
+            .and_then(|id| i64::try_from(id).ok())
+            .map_or(Value::Null, Value::from);
+        statement.bind::<&[(_, Value)]>(&[
+            (":size", (N as i64).into()),
+            (":start", Tps::from(game.replay.env.clone()).to_string().into()),
+            (":moves", moves.into()),
+            (":plies", (game.replay.len() as i64).into()),
+            (":result", result),
+            (":generation", (game.generation as i64).into()),
+            (":root_values", root_values.into()),
+            (":game_id", game_id),
+            (":half_komi", i64::from(HALF_KOMI).into()),
+        ])?;
+        while statement.next()? != State::Done {}
+
+        let mut statement = self.connection.prepare("SELECT last_insert_rowid()")?;
+        statement.next()?;
+        let id = statement.read::<i64, _>(0)?;
+        self.insert_positions(id, game)?;
+        Ok(id)
+    }
+
+    /// Index every position of a stored game, including the final one.
+    fn insert_positions<const N: usize, const HALF_KOMI: i8>(
+        &self,
+        id: i64,
+        game: &ArchivedGame<Game<N, HALF_KOMI>>,
+    ) -> Result<(), ArchiveError>
+    where
+        Reserves<N>: Default,
+    {
+        let mut statement = self.connection.prepare(
+            "INSERT INTO positions (key, material, game, ply, generation, root_value)
+            VALUES (:key, :material, :game, :ply, :generation, :root_value)",
+        )?;
+        let mut insert = || -> Result<(), ArchiveError> {
+            let mut env = game.replay.env.clone();
+            for ply in 0..=game.replay.len() {
+                let root_value = game
+                    .root_values
+                    .get(ply)
+                    .map_or(Value::Null, |value| f64::from(*value).into());
+                statement.reset()?;
+                statement.bind::<&[(_, Value)]>(&[
+                    (":key", key_value(zobrist(&env)).into()),
+                    (":material", material(&env).into()),
+                    (":game", id.into()),
+                    (":ply", (ply as i64).into()),
+                    (":generation", (game.generation as i64).into()),
+                    (":root_value", root_value),
+                ])?;
+                while statement.next()? != State::Done {}
+                if let Some(action) = game.replay.actions.get(ply) {
+                    env.step(*action);
+                }
+            }
+            Ok(())
+        };
+        // One transaction per game, since a transaction per row is very slow.
+        self.connection.execute("BEGIN")?;
+        let result = insert();
+        self.connection
+            .execute(if result.is_ok() { "COMMIT" } else { "ROLLBACK" })?;
+        result
+    }
+
+    /// Index the positions of stored games of this size which are not
+    /// indexed yet, for example games archived before positions were indexed.
+    /// Returns the number of games indexed.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the query fails or a stored game cannot be parsed.
+    pub fn index_positions<const N: usize, const HALF_KOMI: i8>(
+        &self,
+    ) -> Result<usize, ArchiveError>
+    where
+        Reserves<N>: Default,
+    {
+        let mut statement = self.connection.prepare(
+            "SELECT id, start, moves, generation, root_values, game_id FROM games
+            WHERE size = :size AND coalesce(half_komi, :half_komi) = :half_komi
+                AND id NOT IN (SELECT DISTINCT game FROM positions)
+            ORDER BY id ASC",
+        )?;
+        statement.bind::<&[(_, Value)]>(&[
+            (":size", (N as i64).into()),
+            (":half_komi", i64::from(HALF_KOMI).into()),
+        ])?;
+        let games = statement
+            .into_iter()
+            .map(|row| {
+                let row = row?;
+                let generation = row.read::<i64, _>("generation").try_into().unwrap_or_default();
+                Ok((row.read::<i64, _>("id"), parse_game(&row, generation)?))
+            })
+            .collect::<Result<Vec<(i64, ArchivedGame<Game<N, HALF_KOMI>>)>, ArchiveError>>()?;
+        for (id, game) in &games {
+            self.insert_positions(*id, game)?;
+        }
+        Ok(games.len())
+    }
+
+    /// Read all games played by the given generation. Games archived before
+    /// komi was recorded are read with any komi.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the query fails or a stored game cannot be parsed.
+    pub fn games<const N: usize, const HALF_KOMI: i8>(
+        &self,
+        generation: usize,
+    ) -> Result<Vec<ArchivedGame<Game<N, HALF_KOMI>>>, ArchiveError>
+    where
+        Reserves<N>: Default,
+    {
+        let mut statement = self.connection.prepare(
+            "SELECT start, moves, root_values, game_id FROM games
+            WHERE size = :size AND coalesce(half_komi, :half_komi) = :half_komi
+                AND generation = :generation
+            ORDER BY id ASC",
+        )?;
+        statement.bind::<&[(_, Value)]>(&[
+            (":size", (N as i64).into()),
+            (":half_komi", i64::from(HALF_KOMI).into()),
+            (":generation", (generation as i64).into()),
+        ])?;
+        statement
+            .into_iter()
+            .map(|row| parse_game(&row?, generation))
+            .collect()
+    }
+
+    /// Number of finished games stored in the archive.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the query fails.
+    pub fn count(&self) -> Result<usize, ArchiveError> {
+        let mut statement = self.connection.prepare("SELECT COUNT(*) FROM games")?;
+        statement.next()?;
+        Ok(statement.read::<i64, _>(0)?.try_into().unwrap_or_default())
+    }
+
+    /// Draw rate of finished games for each generation, in ascending order of
+    /// generation.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the query fails.
+    pub fn draw_rate_by_generation(&self) -> Result<Vec<(usize, f64)>, ArchiveError> {
+        self.per_generation(&format!("AVG(result = '{DRAW}')"))
+    }
+
+    /// Average game length in plies for each generation, in ascending order of
+    /// generation.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the query fails.
+    pub fn average_plies_by_generation(&self) -> Result<Vec<(usize, f64)>, ArchiveError> {
+        self.per_generation("AVG(plies)")
+    }
+
+    /// Quality of the value targets made from the root values with the
+    /// `horizon` and `discount` of self-play compared to the game outcomes,
+    /// for each generation in ascending order.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the query fails or a stored game cannot be parsed.
+    pub fn value_quality_by_generation<const N: usize, const HALF_KOMI: i8>(
+        &self,
+        horizon: Option<usize>,
+        discount: f32,
+    ) -> Result<Vec<(usize, ValueQuality)>, ArchiveError>
+    where
+        Reserves<N>: Default,
+    {
+        self.finished_generations(N, HALF_KOMI)?
+            .into_iter()
+            .map(|generation| {
+                let mut quality = ValueQuality::default();
+                for game in self.games::<N, HALF_KOMI>(generation)? {
+                    quality.add_game(&game.replay, &game.root_values, horizon, discount);
+                }
+                Ok((generation, quality))
+            })
+            .collect()
+    }
+
+    /// Results of the finished games of each opening, for each generation in
+    /// ascending order. Games which start after the opening swap are left
+    /// out.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the query fails or a stored game cannot be parsed.
+    pub fn opening_stats_by_generation<const N: usize, const HALF_KOMI: i8>(
+        &self,
+    ) -> Result<Vec<(usize, OpeningTable)>, ArchiveError>
+    where
+        Reserves<N>: Default,
+    {
+        self.finished_generations(N, HALF_KOMI)?
+            .into_iter()
+            .map(|generation| {
+                let mut openings = OpeningTable::new();
+                for game in self.games::<N, HALF_KOMI>(generation)? {
+                    let Some(opening) = Opening::of_replay(&game.replay) else {
+                        continue;
+                    };
+                    let mut env = game.replay.env.clone();
+                    for action in &game.replay.actions {
+                        env.step(*action);
+                    }
+                    let winner = match env.result() {
+                        fast_tak::GameResult::Winner { color, .. } => Some(color),
+                        fast_tak::GameResult::Draw { .. } => None,
+                        fast_tak::GameResult::Ongoing => continue,
+                    };
+                    openings.entry(opening).or_default().add(winner);
+                }
+                Ok((generation, openings))
+            })
+            .collect()
+    }
+
+    /// How often each generation reached the position with this Zobrist key,
+    /// in ascending order of generation.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the query fails.
+    pub fn position_stats(&self, key: u64) -> Result<Vec<PositionStats>, ArchiveError> {
+        self.positions_by_generation("key", key_value(key).into())
+    }
+
+    /// How often each generation reached positions with this material
+    /// signature (see [`material`]), in ascending order of generation.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the query fails.
+    pub fn material_stats(&self, material: &str) -> Result<Vec<PositionStats>, ArchiveError> {
+        self.positions_by_generation("material", material.into())
+    }
+
+    /// Games which reached the position with this Zobrist key, most recent
+    /// first.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the query fails.
+    pub fn occurrences(&self, key: u64, limit: usize) -> Result<Vec<Occurrence>, ArchiveError> {
+        let mut statement = self.connection.prepare(
+            "SELECT games.game_id, games.half_komi, games.generation, positions.ply,
+                games.result, games.start, games.moves
+            FROM positions JOIN games ON positions.game = games.id
+            WHERE positions.key = :key
+            ORDER BY games.id DESC
+            LIMIT :limit",
+        )?;
+        statement.bind::<&[(_, Value)]>(&[
+            (":key", key_value(key).into()),
+            (":limit", (limit as i64).into()),
+        ])?;
+        statement
+            .into_iter()
+            .map(|row| {
+                let row = row?;
+                Ok(Occurrence {
+                    game: game_id(&row),
+                    half_komi: row
+                        .read::<Option<i64>, _>("half_komi")
+                        .and_then(|half_komi| half_komi.try_into().ok()),
+                    generation: row.read::<i64, _>("generation").try_into().unwrap_or_default(),
+                    ply: row.read::<i64, _>("ply").try_into().unwrap_or_default(),
+                    result: row.read::<Option<&str>, _>("result").map(ToString::to_string),
+                    start: row.read::<&str, _>("start").to_string(),
+                    moves: row.read::<&str, _>("moves").to_string(),
+                })
+            })
+            .collect()
+    }
+
+    /// Generations with finished games of this size and komi, in ascending
+    /// order.
+    fn finished_generations(&self, size: usize, half_komi: i8) -> Result<Vec<usize>, ArchiveError> {
+        let mut statement = self.connection.prepare(
+            "SELECT DISTINCT generation FROM games
+            WHERE size = :size AND coalesce(half_komi, :half_komi) = :half_komi
+                AND result IS NOT NULL
+            ORDER BY generation ASC",
+        )?;
+        statement.bind::<&[(_, Value)]>(&[
+            (":size", (size as i64).into()),
+            (":half_komi", i64::from(half_komi).into()),
+        ])?;
+        statement
+            .into_iter()
+            .map(|row| {
+                Ok(row?
+                    .read::<i64, _>("generation")
+                    .try_into()
+                    .unwrap_or_default())
+            })
+            .collect()
+    }
+
+    fn positions_by_generation(
+        &self,
+        column: &str,
+        value: Value,
+    ) -> Result<Vec<PositionStats>, ArchiveError> {
+        let mut statement = self.connection.prepare(format!(
+            "SELECT generation, COUNT(*) AS occurrences, COUNT(DISTINCT game) AS games,
+                AVG(root_value) AS average_value
+            FROM positions
+            WHERE {column} = :value
+            GROUP BY generation
+            ORDER BY generation ASC"
+        ))?;
+        statement.bind::<&[(_, Value)]>(&[(":value", value)])?;
+        statement
+            .into_iter()
+            .map(|row| {
+                let row = row?;
+                let count = |column: &str| -> usize {
+                    row.read::<i64, _>(column).try_into().unwrap_or_default()
+                };
+                Ok(PositionStats {
+                    generation: count("generation"),
+                    occurrences: count("occurrences"),
+                    games: count("games"),
+                    average_value: row.read::<Option<f64>, _>("average_value"),
+                })
+            })
+            .collect()
+    }
+
+    fn per_generation(&self, aggregate: &str) -> Result<Vec<(usize, f64)>, ArchiveError> {
+        let statement = self.connection.prepare(format!(
+            "SELECT generation, {aggregate} AS value FROM games
+            WHERE result IS NOT NULL
+            GROUP BY generation
+            ORDER BY generation ASC"
+        ))?;
+        statement
+            .into_iter()
+            .map(|row| {
+                let row = row?;
+                Ok((
+                    row.read::<i64, _>("generation")
+                        .try_into()
+                        .unwrap_or_default(),
+                    row.read::<f64, _>("value"),
+                ))
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use fast_tak::Game;
+
+    use super::{material, ArchivedGame, GameArchive};
+    use crate::{
+        search::{env::Environment, DISCOUNT_FACTOR},
+        target::Replay,
+        zobrist::zobrist,
+    };
+
+    #[test]
+    fn archive_roundtrip() {
+        let archive = GameArchive::open(":memory:").unwrap();
+        let mut replay: Replay<Game<3, 0>> =
+            "[TPS \"x3/x3/x3 1 1\"] c3 a1 b1 b3 c1".parse().unwrap();
+        replay.game_id = Some(17);
+        let game = ArchivedGame {
+            replay,
+            generation: 100,
+            root_values: vec![0.0, -0.25, 0.5, -0.75, 1.0],
+        };
+        archive.insert(&game).unwrap();
+        archive
+            .insert(&ArchivedGame {
+                replay: Replay::new(Game::<3, 0>::default()),
+                generation: 200,
+                root_values: Vec::new(),
+            })
+            .unwrap();
+        archive
+            .insert(&ArchivedGame {
+                replay: Replay::new(Game::<3, 2>::default()),
+                generation: 100,
+                root_values: Vec::new(),
+            })
+            .unwrap();
+
+        assert_eq!(archive.count().unwrap(), 3);
+        assert_eq!(archive.games::<3, 0>(100).unwrap(), vec![game]);
+        assert_eq!(archive.games::<3, 2>(100).unwrap().len(), 1);
+        // Unfinished games do not count towards statistics.
+        assert_eq!(archive.draw_rate_by_generation().unwrap(), vec![(100, 0.0)]);
+        assert_eq!(archive.average_plies_by_generation().unwrap(), vec![(100, 5.0)]);
+        let quality = archive.value_quality_by_generation::<3, 0>(None, DISCOUNT_FACTOR).unwrap();
+        assert_eq!(quality.len(), 1);
+        assert_eq!(quality[0].1.positions, 5);
+
+        let openings = archive.opening_stats_by_generation::<3, 0>().unwrap();
+        assert_eq!(openings.len(), 1);
+        let (opening, stats) = openings[0].1.iter().next().unwrap();
+        assert_eq!(opening.to_string(), "corner/corner opposite");
+        assert_eq!((stats.games, stats.white_wins), (1, 1));
+    }
+
+    #[test]
+    fn position_index() {
+        let archive = GameArchive::open(":memory:").unwrap();
+        for (moves, generation, value) in [("a1 c3 b2", 100, 0.5), ("a1 c3 b3", 200, -0.25)] {
+            let mut replay: Replay<Game<3, 0>> =
+                format!("[TPS \"x3/x3/x3 1 1\"] {moves}").parse().unwrap();
+            replay.game_id = Some(generation as u64);
+            archive
+                .insert(&ArchivedGame {
+                    replay,
+                    generation,
+                    root_values: vec![0.0, 0.0, value],
+                })
+                .unwrap();
+        }
+
+        let mut env = Game::<3, 0>::default();
+        let replay: Replay<Game<3, 0>> = "[TPS \"x3/x3/x3 1 1\"] a1 c3".parse().unwrap();
+        for action in replay.actions {
+            env.step(action);
+        }
+        assert_eq!(material(&env), "1F0S0C/1F0S0C");
+        let stats = archive.position_stats(zobrist(&env)).unwrap();
+        assert_eq!(stats.len(), 2);
+        assert_eq!((stats[0].generation, stats[0].occurrences, stats[0].games), (100, 1, 1));
+        assert_eq!(stats[0].average_value, Some(0.5));
+        assert_eq!(stats[1].average_value, Some(-0.25));
+
+        // Both final positions have the same material but are different positions.
+        let stats = archive.material_stats("2F0S0C/1F0S0C").unwrap();
+        assert_eq!(stats.iter().map(|s| s.occurrences).sum::<usize>(), 2);
+        assert!(stats.iter().all(|s| s.average_value.is_none()));
+
+        let occurrences = archive.occurrences(zobrist(&env), 10).unwrap();
+        assert_eq!(occurrences.len(), 2);
+        assert_eq!((occurrences[0].generation, occurrences[0].ply), (200, 2));
+        assert_eq!((occurrences[0].game, occurrences[0].half_komi), (Some(200), Some(0)));
+        assert_eq!(archive.index_positions::<3, 0>().unwrap(), 0);
+    }
+}
@@ -0,0 +1,358 @@
+//! Audit manifests for reproducing runs.
+//!
+//! With `--audit`, `selfplay` and `learn` write down everything random or
+//! timing-dependent which decides what they do: the seed, the position of
+//! the random number stream at every step, the network generation which was
+//! searched with, how many games were active, the temperature which the
+//! opening moves were sampled with, and which targets were drawn into every
+//! training batch in which order. From a manifest, a self-play
+//! game or a training step can be executed again and compared.
+//!
+//! A manifest is a text file with one record per line:
+//! ```text
+//! run {kind} {seed} {fresh|resumed}
+//! step {step} {generation|-} {word_pos} {active}
+//! game {game_id} {step}
+//! batch {step} {loss} {key},{key},...
+//! temperature {step} {temperature|default}
+//! ```
+//! Every run of a process appends a `run` record, and the records after it
+//! belong to that run. A `step` record is written at the end of a self-play
+//! step with the stream position at which the search started and the number
+//! of environments kept active afterwards, and `game` records name the
+//! games which finished in it. A `batch` record has the loss of a training
+//! step and the [keys](crate::seen::target_key) of its targets. A
+//! `temperature` record is written when the temperature for opening moves
+//! is overridden through the control endpoint: by `learn` at the step it was
+//! told to, and by `selfplay` before the first step which uses it.
+
+use std::{
+    fmt,
+    fs::{File, OpenOptions},
+    io::{self, BufRead, BufReader, Write},
+    num::{ParseFloatError, ParseIntError},
+    path::Path,
+    str::FromStr,
+};
+
+use thiserror::Error;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Record {
+    /// The start of a run. Resumed runs cannot be reproduced from their
+    /// start, because the search trees were not saved.
+    Run {
+        kind: String,
+        seed: u64,
+        resumed: bool,
+    },
+    /// A self-play step.
+    Step {
+        step: usize,
+        generation: Option<usize>,
+        word_pos: u128,
+        active: usize,
+    },
+    /// A game which finished in a self-play step.
+    Game { game_id: u64, step: usize },
+    /// A training step.
+    Batch {
+        step: usize,
+        loss: f64,
+        keys: Vec<u64>,
+    },
+    /// The temperature for opening moves from this step on, or `None` for
+    /// the default sampling.
+    Temperature {
+        step: usize,
+        temperature: Option<f32>,
+    },
+}
+
+#[derive(Debug, Error)]
+pub enum ParseRecordError {
+    #[error("unknown audit record `{0}`")]
+    Unknown(String),
+    #[error("audit record is missing a field")]
+    MissingField,
+    #[error("{0}")]
+    Int(#[from] ParseIntError),
+    #[error("{0}")]
+    Float(#[from] ParseFloatError),
+}
+
+impl fmt::Display for Record {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Run {
+                kind,
+                seed,
+                resumed,
+            } => {
+                let start = if *resumed { "resumed" } else { "fresh" };
+                write!(f, "run {kind} {seed} {start}")
+            }
+            Self::Step {
+                step,
+                generation,
+                word_pos,
+                active,
+            } => {
+                let generation = generation.map_or_else(|| "-".into(), |g| g.to_string());
+                write!(f, "step {step} {generation} {word_pos} {active}")
+            }
+            Self::Game { game_id, step } => write!(f, "game {game_id} {step}"),
+            Self::Batch { step, loss, keys } => {
+                write!(f, "batch {step} {loss}")?;
+                for (i, key) in keys.iter().enumerate() {
+                    write!(f, "{}{key}", if i == 0 { " " } else { "," })?;
+                }
+                Ok(())
+            }
+            Self::Temperature { step, temperature } => match temperature {
+                Some(temperature) => write!(f, "temperature {step} {temperature}"),
+                None => write!(f, "temperature {step} default"),
+            },
+        }
+    }
+}
+
+impl FromStr for Record {
+    type Err = ParseRecordError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut fields = s.split_whitespace();
+        let mut next = || fields.next().ok_or(ParseRecordError::MissingField);
+        match next()? {
+            "run" => Ok(Self::Run {
+                kind: next()?.to_string(),
+                seed: next()?.parse()?,
+                resumed: match next()? {
+                    "fresh" => false,
+                    "resumed" => true,
+                    _ => return Err(ParseRecordError::Unknown(s.to_string())),
+                },
+            }),
+            "step" => Ok(Self::Step {
+                step: next()?.parse()?,
+                generation: match next()? {
+                    "-" => None,
+                    generation => Some(generation.parse()?),
+                },
+                word_pos: next()?.parse()?,
+                active: next()?.parse()?,
+            }),
+            "game" => Ok(Self::Game {
+                game_id: next()?.parse()?,
+                step: next()?.parse()?,
+            }),
+            "batch" => Ok(Self::Batch {
+                step: next()?.parse()?,
+                loss: next()?.parse()?,
+                keys: match next() {
+                    Ok(keys) => keys.split(',').map(str::parse).collect::<Result<_, _>>()?,
+                    Err(_) => Vec::new(),
+                },
+            }),
+            "temperature" => Ok(Self::Temperature {
+                step: next()?.parse()?,
+                temperature: match next()? {
+                    "default" => None,
+                    temperature => Some(temperature.parse()?),
+                },
+            }),
+            _ => Err(ParseRecordError::Unknown(s.to_string())),
+        }
+    }
+}
+
+/// Appends records to a manifest.
+pub struct Audit {
+    file: File,
+}
+
+impl Audit {
+    /// Open the manifest for appending and start a new run in it.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the manifest cannot be opened or written.
+    pub fn open(path: impl AsRef<Path>, kind: &str, seed: u64, resumed: bool) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let mut audit = Self { file };
+        audit.record(&Record::Run {
+            kind: kind.to_string(),
+            seed,
+            resumed,
+        })?;
+        Ok(audit)
+    }
+
+    /// Append a record. Records are written right away, so that the
+    /// manifest is complete up to a crash.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the manifest cannot be written.
+    pub fn record(&mut self, record: &Record) -> io::Result<()> {
+        writeln!(self.file, "{record}")
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum ReadManifestError {
+    #[error("io: {0}")]
+    Io(#[from] io::Error),
+    #[error("line {line}: {source}")]
+    Record {
+        line: usize,
+        source: ParseRecordError,
+    },
+}
+
+/// The records of one run.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Run {
+    pub kind: String,
+    pub seed: u64,
+    pub resumed: bool,
+    pub records: Vec<Record>,
+}
+
+impl Run {
+    /// The step in which a game finished.
+    #[must_use]
+    pub fn game_step(&self, game_id: u64) -> Option<usize> {
+        self.records.iter().find_map(|record| match record {
+            Record::Game { game_id: id, step } if *id == game_id => Some(*step),
+            _ => None,
+        })
+    }
+
+    /// The games which finished in a step.
+    pub fn finished_games(&self, step: usize) -> impl Iterator<Item = u64> + '_ {
+        self.records.iter().filter_map(move |record| match record {
+            Record::Game { game_id, step: s } if *s == step => Some(*game_id),
+            _ => None,
+        })
+    }
+}
+
+/// Read a manifest and split it into runs. Records before the first run
+/// are ignored.
+///
+/// # Errors
+///
+/// Returns an error if the manifest cannot be read or a line is malformed.
+pub fn read_manifest(path: impl AsRef<Path>) -> Result<Vec<Run>, ReadManifestError> {
+    let mut runs: Vec<Run> = Vec::new();
+    for (i, line) in BufReader::new(File::open(path)?).lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let record = line.parse().map_err(|source| ReadManifestError::Record {
+            line: i + 1,
+            source,
+        })?;
+        match (record, runs.last_mut()) {
+            (
+                Record::Run {
+                    kind,
+                    seed,
+                    resumed,
+                },
+                _,
+            ) => runs.push(Run {
+                kind,
+                seed,
+                resumed,
+                records: Vec::new(),
+            }),
+            (record, Some(run)) => run.records.push(record),
+            (_, None) => {}
+        }
+    }
+    Ok(runs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{read_manifest, Audit, Record};
+
+    #[test]
+    fn records_round_trip() {
+        let records = [
+            Record::Step {
+                step: 3,
+                generation: Some(1200),
+                word_pos: u128::from(u64::MAX) + 5,
+                active: 64,
+            },
+            Record::Step {
+                step: 4,
+                generation: None,
+                word_pos: 0,
+                active: 128,
+            },
+            Record::Game {
+                game_id: 130,
+                step: 4,
+            },
+            Record::Batch {
+                step: 101,
+                loss: 1.234_567_890_123_456,
+                keys: vec![1, u64::MAX, 0],
+            },
+            Record::Batch {
+                step: 102,
+                loss: 0.1,
+                keys: Vec::new(),
+            },
+            Record::Temperature {
+                step: 5,
+                temperature: Some(0.5),
+            },
+            Record::Temperature {
+                step: 6,
+                temperature: None,
+            },
+        ];
+        for record in records {
+            assert_eq!(record.to_string().parse::<Record>().unwrap(), record);
+        }
+        assert!("step 1 - 0".parse::<Record>().is_err());
+        assert!("seed 1".parse::<Record>().is_err());
+    }
+
+    #[test]
+    fn manifests_are_split_into_runs() {
+        let path = std::env::temp_dir().join(format!("audit-{}.txt", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let mut audit = Audit::open(&path, "selfplay", 7, false).unwrap();
+        audit.record(&Record::Game {
+            game_id: 3,
+            step: 10,
+        }).unwrap();
+        audit.record(&Record::Game {
+            game_id: 4,
+            step: 10,
+        }).unwrap();
+        drop(audit);
+        let mut audit = Audit::open(&path, "selfplay", 7, true).unwrap();
+        audit.record(&Record::Game {
+            game_id: 5,
+            step: 2,
+        }).unwrap();
+        drop(audit);
+
+        let runs = read_manifest(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(runs.len(), 2);
+        assert!(!runs[0].resumed && runs[1].resumed);
+        assert_eq!(runs[0].game_step(4), Some(10));
+        assert_eq!(runs[0].game_step(5), None);
+        assert_eq!(runs[0].finished_games(10).collect::<Vec<_>>(), [3, 4]);
+        assert_eq!(runs[1].records.len(), 1);
+    }
+}
@@ -0,0 +1,283 @@
+//! Adaptive number of concurrent environments.
+//!
+//! A bigger batch keeps the GPU busier but makes every search step slower.
+//! The controller grows the batch while the GPU is underutilized,
+//! shrinks it when steps get too slow, and otherwise hill-climbs on
+//! throughput (plies per second). Apart from slow steps, a change of
+//! direction has to be seen `patience` times in a row, and the batch holds
+//! still meanwhile, so one noisy step does not make it oscillate.
+//!
+//! The result is meant to be passed to
+//! [`BatchedMCTS::set_active`](crate::search::node::batched::BatchedMCTS::set_active),
+//! which only parks environments between games. `max` is the capacity of the
+//! batched search; start [below it](BatchSizeController::starting_at) to leave
+//! room for growth.
+
+use std::{
+    process::Command,
+    time::{Duration, Instant},
+};
+
+#[derive(Debug, Clone)]
+pub struct BatchSizeController {
+    pub min: usize,
+    pub max: usize,
+    /// How many environments to add or remove at once.
+    pub step: usize,
+    /// Steps slower than this always shrink the batch.
+    pub max_step_time: Duration,
+    /// GPU utilization (between 0 and 1) below which the batch grows.
+    pub target_utilization: f32,
+    /// Relative drop in throughput which reverses the search direction.
+    pub tolerance: f64,
+    /// Consecutive observations needed to change direction,
+    /// except for slow steps which shrink the batch right away.
+    pub patience: usize,
+    active: usize,
+    growing: bool,
+    /// Observations in a row which asked for a change of direction.
+    pending: usize,
+    previous_throughput: Option<f64>,
+}
+
+impl BatchSizeController {
+    /// # Panics
+    ///
+    /// Panics if `min` is zero or larger than `max`, or if `step` is zero.
+    #[must_use]
+    pub fn new(min: usize, max: usize, step: usize, max_step_time: Duration) -> Self {
+        assert!(0 < min && min <= max, "batch size bounds must satisfy 0 < min <= max");
+        assert!(step > 0, "step must be positive");
+        Self {
+            min,
+            max,
+            step,
+            max_step_time,
+            target_utilization: 0.9,
+            tolerance: 0.02,
+            patience: 3,
+            active: max,
+            growing: false,
+            pending: 0,
+            previous_throughput: None,
+        }
+    }
+
+    /// Start with `active` environments instead of `max`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `active` is not between `min` and `max`.
+    #[must_use]
+    pub fn starting_at(mut self, active: usize) -> Self {
+        assert!((self.min..=self.max).contains(&active), "active must be in min..=max");
+        self.active = active;
+        self
+    }
+
+    /// The number of environments which should currently be played.
+    #[must_use]
+    pub const fn active(&self) -> usize {
+        self.active
+    }
+
+    /// Observe a search step and return the new number of active
+    /// environments.
+    ///
+    /// `step_time` should only cover the search itself, `plies` is the
+    /// number of plies played during the step, and `utilization` the GPU
+    /// utilization if it is known.
+    pub fn observe(
+        &mut self,
+        step_time: Duration,
+        plies: usize,
+        utilization: Option<f32>,
+    ) -> usize {
+        let throughput = plies as f64 / step_time.as_secs_f64().max(f64::EPSILON);
+        if step_time > self.max_step_time {
+            self.growing = false;
+            self.pending = 0;
+        } else {
+            let reverse = if utilization.is_some_and(|u| u < self.target_utilization) {
+                !self.growing
+            } else {
+                // The last change made things worse, so go back.
+                self.previous_throughput
+                    .is_some_and(|previous| throughput < previous * (1.0 - self.tolerance))
+            };
+            if !reverse {
+                self.pending = 0;
+            } else if self.pending + 1 < self.patience {
+                // Hold still and keep comparing against the throughput
+                // from before the suspicious observation.
+                self.pending += 1;
+                return self.active;
+            } else {
+                self.growing = !self.growing;
+                self.pending = 0;
+            }
+        }
+        self.previous_throughput = Some(throughput);
+
+        self.active = if self.growing {
+            (self.active + self.step).min(self.max)
+        } else {
+            self.active.saturating_sub(self.step).max(self.min)
+        };
+        self.active
+    }
+}
+
+/// GPU utilization which is queried at most once per `interval`,
+/// because spawning `nvidia-smi` every step is not free.
+#[derive(Debug, Clone)]
+pub struct GpuMonitor {
+    pub index: usize,
+    pub interval: Duration,
+    last: Option<(Instant, Option<f32>)>,
+}
+
+impl GpuMonitor {
+    #[must_use]
+    pub const fn new(index: usize, interval: Duration) -> Self {
+        Self { index, interval, last: None }
+    }
+
+    /// The latest sample, taking a new one if the last is too old.
+    pub fn utilization(&mut self) -> Option<f32> {
+        match self.last {
+            Some((sampled, utilization)) if sampled.elapsed() < self.interval => utilization,
+            _ => {
+                let utilization = gpu_utilization(self.index);
+                self.last = Some((Instant::now(), utilization));
+                utilization
+            }
+        }
+    }
+}
+
+/// Query the utilization (between 0 and 1) of a GPU using `nvidia-smi`.
+/// Returns `None` if it is not available.
+#[must_use]
+pub fn gpu_utilization(index: usize) -> Option<f32> {
+    let output = Command::new("nvidia-smi")
+        .args([
+            "--query-gpu=utilization.gpu",
+            "--format=csv,noheader,nounits",
+            &format!("--id={index}"),
+        ])
+        .output()
+        .ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    let percent: f32 = String::from_utf8(output.stdout).ok()?.trim().parse().ok()?;
+    Some(percent / 100.0)
+}
+
+/// Query the GPU memory in bytes which a process holds, including its CUDA
+/// context, using `nvidia-smi`. Returns `None` if it is not available, for
+/// example when the process uses no GPU.
+#[must_use]
+pub fn gpu_memory_of(pid: u32) -> Option<u64> {
+    let output = Command::new("nvidia-smi")
+        .args(["--query-compute-apps=pid,used_memory", "--format=csv,noheader,nounits"])
+        .output()
+        .ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    memory_of(&String::from_utf8(output.stdout).ok()?, pid)
+}
+
+/// The memory of a process in the output of `nvidia-smi`, which lists every
+/// process once per GPU with its memory in MiB.
+fn memory_of(output: &str, pid: u32) -> Option<u64> {
+    let mebibytes = output
+        .lines()
+        .filter_map(|line| {
+            let (process, memory) = line.split_once(',')?;
+            if process.trim().parse() != Ok(pid) {
+                return None;
+            }
+            memory.trim().parse::<u64>().ok()
+        })
+        .reduce(|a, b| a + b)?;
+    Some(mebibytes << 20)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{memory_of, BatchSizeController, GpuMonitor};
+
+    #[test]
+    fn shrinks_when_slow() {
+        let mut controller = BatchSizeController::new(16, 64, 16, Duration::from_secs(1));
+        assert_eq!(controller.observe(Duration::from_secs(2), 64, Some(1.0)), 48);
+        assert_eq!(controller.observe(Duration::from_secs(2), 48, None), 32);
+        assert_eq!(controller.observe(Duration::from_secs(2), 32, None), 16);
+        assert_eq!(controller.observe(Duration::from_secs(2), 16, None), 16);
+    }
+
+    #[test]
+    fn grows_when_underutilized() {
+        let mut controller = BatchSizeController::new(16, 64, 16, Duration::from_secs(1));
+        controller.patience = 1;
+        controller.observe(Duration::from_secs(2), 64, None);
+        assert_eq!(controller.observe(Duration::from_millis(500), 48, Some(0.5)), 64);
+        assert_eq!(controller.observe(Duration::from_millis(500), 64, Some(0.5)), 64);
+    }
+
+    #[test]
+    fn reverses_when_throughput_drops() {
+        let mut controller = BatchSizeController::new(16, 64, 16, Duration::from_secs(10));
+        controller.patience = 1;
+        // Shrinking from 64 to 48 with full utilization.
+        assert_eq!(controller.observe(Duration::from_secs(1), 64, Some(1.0)), 48);
+        // Throughput dropped, so grow again.
+        assert_eq!(controller.observe(Duration::from_secs(1), 48, Some(1.0)), 64);
+        // Throughput recovered, keep going (but capped).
+        assert_eq!(controller.observe(Duration::from_secs(1), 64, Some(1.0)), 64);
+    }
+
+    #[test]
+    fn grows_beyond_the_starting_size() {
+        let mut controller =
+            BatchSizeController::new(16, 64, 16, Duration::from_secs(1)).starting_at(32);
+        controller.patience = 1;
+        assert_eq!(controller.observe(Duration::from_millis(500), 32, Some(0.5)), 48);
+        assert_eq!(controller.observe(Duration::from_millis(500), 48, Some(0.5)), 64);
+    }
+
+    #[test]
+    fn waits_for_patience_before_reversing() {
+        let mut controller = BatchSizeController::new(16, 64, 16, Duration::from_secs(10));
+        assert_eq!(controller.observe(Duration::from_secs(1), 64, Some(1.0)), 48);
+        // One noisy step holds still instead of reversing.
+        assert_eq!(controller.observe(Duration::from_secs(1), 48, Some(1.0)), 48);
+        // Throughput recovered, so keep shrinking.
+        assert_eq!(controller.observe(Duration::from_secs(1), 64, Some(1.0)), 32);
+        // Worse three times in a row, so grow again.
+        assert_eq!(controller.observe(Duration::from_secs(1), 32, Some(1.0)), 32);
+        assert_eq!(controller.observe(Duration::from_secs(1), 32, Some(1.0)), 32);
+        assert_eq!(controller.observe(Duration::from_secs(1), 32, Some(1.0)), 48);
+    }
+
+    #[test]
+    fn monitor_reuses_recent_samples() {
+        let mut monitor = GpuMonitor::new(0, Duration::MAX);
+        let first = monitor.utilization();
+        let sampled = monitor.last.map(|(sampled, _)| sampled);
+        assert_eq!(monitor.utilization(), first);
+        assert_eq!(monitor.last.map(|(sampled, _)| sampled), sampled);
+    }
+
+    #[test]
+    fn sums_process_memory() {
+        let output = "1234, 512\n99, 100\n1234, 256\n";
+        assert_eq!(memory_of(output, 1234), Some(768 << 20));
+        assert_eq!(memory_of(output, 5), None);
+    }
+}
@@ -0,0 +1,204 @@
+//! Checksums of replay and target shards.
+//!
+//! Next to a finished shard like `replays-00017.txt` lies a file
+//! `replays-00017.txt.xxh3` with a line like
+//! `xxh3=1b2e9c0f5a7d3e41 bytes=5242880`, the XXH3 hash and length of the
+//! shard. Loaders check it before parsing, so that a shard which was damaged
+//! on disk or in transfer is reported as corrupted instead of as lines which
+//! do not parse. Shards without a checksum, like the one still being
+//! written, are read unchecked.
+
+use std::{
+    ffi::OsString,
+    fmt,
+    fs::File,
+    io::{self, Read},
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use thiserror::Error;
+use xxhash_rust::xxh3::Xxh3;
+
+/// Extension of checksum files, added after the extension of the shard.
+pub const EXTENSION: &str = "xxh3";
+const CHUNK_LEN: usize = 1 << 16;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Checksum {
+    pub xxh3: u64,
+    pub bytes: u64,
+}
+
+impl Checksum {
+    /// Checksum of data in memory.
+    #[must_use]
+    pub fn of(data: &[u8]) -> Self {
+        Self {
+            xxh3: xxhash_rust::xxh3::xxh3_64(data),
+            bytes: data.len() as u64,
+        }
+    }
+
+    /// Checksum of everything the reader yields.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if reading fails.
+    pub fn read(mut reader: impl Read) -> io::Result<Self> {
+        let mut hasher = Xxh3::new();
+        let mut bytes = 0;
+        let mut chunk = vec![0; CHUNK_LEN];
+        loop {
+            let read = match reader.read(&mut chunk) {
+                Ok(0) => break,
+                Ok(read) => read,
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                Err(err) => return Err(err),
+            };
+            hasher.update(&chunk[..read]);
+            bytes += read as u64;
+        }
+        Ok(Self {
+            xxh3: hasher.digest(),
+            bytes,
+        })
+    }
+}
+
+impl fmt::Display for Checksum {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "xxh3={:016x} bytes={}", self.xxh3, self.bytes)
+    }
+}
+
+impl FromStr for Checksum {
+    type Err = ChecksumError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let malformed = || ChecksumError::Malformed(s.trim().to_string());
+        let (mut xxh3, mut bytes) = (None, None);
+        for field in s.split_whitespace() {
+            match field.split_once('=').ok_or_else(malformed)? {
+                ("xxh3", value) => {
+                    xxh3 = Some(u64::from_str_radix(value, 16).map_err(|_| malformed())?);
+                }
+                ("bytes", value) => bytes = Some(value.parse().map_err(|_| malformed())?),
+                _ => {}
+            }
+        }
+        Ok(Self {
+            xxh3: xxh3.ok_or_else(malformed)?,
+            bytes: bytes.ok_or_else(malformed)?,
+        })
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum ChecksumError {
+    #[error("{0}")]
+    Io(#[from] io::Error),
+    #[error("malformed checksum `{0}`")]
+    Malformed(String),
+    #[error("`{name}` is corrupted: expected {expected} but found {found}")]
+    Corrupted {
+        name: String,
+        expected: Checksum,
+        found: Checksum,
+    },
+}
+
+impl From<ChecksumError> for io::Error {
+    fn from(err: ChecksumError) -> Self {
+        match err {
+            ChecksumError::Io(err) => err,
+            err => Self::new(io::ErrorKind::InvalidData, err),
+        }
+    }
+}
+
+/// Path of the checksum file of a shard.
+#[must_use]
+pub fn sidecar_path(path: &Path) -> PathBuf {
+    let mut name = path.file_name().map(OsString::from).unwrap_or_default();
+    name.push(".");
+    name.push(EXTENSION);
+    path.with_file_name(name)
+}
+
+/// Compute the checksum of a finished shard and write it next to it.
+///
+/// # Errors
+///
+/// Returns an error if the shard cannot be read or the checksum cannot be
+/// written.
+pub fn write_sidecar(path: &Path) -> io::Result<Checksum> {
+    let checksum = Checksum::read(File::open(path)?)?;
+    std::fs::write(sidecar_path(path), format!("{checksum}\n"))?;
+    Ok(checksum)
+}
+
+/// Check the checksum of the data called `name` against the expected one.
+///
+/// # Errors
+///
+/// Returns [`ChecksumError::Corrupted`] if they do not match.
+pub fn check(name: &str, expected: Checksum, found: Checksum) -> Result<(), ChecksumError> {
+    if expected == found {
+        Ok(())
+    } else {
+        Err(ChecksumError::Corrupted {
+            name: name.to_string(),
+            expected,
+            found,
+        })
+    }
+}
+
+/// Check a shard against the checksum next to it. Returns whether there was
+/// a checksum to check.
+///
+/// # Errors
+///
+/// Returns [`ChecksumError::Corrupted`] if the shard does not match, and
+/// other errors if either file cannot be read or the checksum is malformed.
+pub fn verify(path: &Path) -> Result<bool, ChecksumError> {
+    let expected: Checksum = match std::fs::read_to_string(sidecar_path(path)) {
+        Ok(content) => content.parse()?,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(false),
+        Err(err) => return Err(err.into()),
+    };
+    let found = Checksum::read(File::open(path)?)?;
+    check(&path.display().to_string(), expected, found)?;
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::{sidecar_path, verify, write_sidecar, Checksum, ChecksumError};
+
+    #[test]
+    fn sidecar_round_trip_and_corruption() {
+        assert_eq!(
+            sidecar_path(Path::new("run/replays-00017.txt")),
+            Path::new("run/replays-00017.txt.xxh3")
+        );
+        let checksum = Checksum::of(b"x5/x5/x5/x5/x5 1 1;0;0;a1:1\n");
+        assert_eq!(checksum.to_string().parse::<Checksum>().unwrap(), checksum);
+        assert!(matches!("xxh3=zz bytes=1".parse::<Checksum>(), Err(ChecksumError::Malformed(_))));
+
+        let directory =
+            std::env::temp_dir().join(format!("takzero-checksum-{}", std::process::id()));
+        std::fs::create_dir_all(&directory).unwrap();
+        let path = directory.join("targets-00000.txt");
+        std::fs::write(&path, "line\n".repeat(100_000)).unwrap();
+        assert!(!verify(&path).unwrap());
+        write_sidecar(&path).unwrap();
+        assert!(verify(&path).unwrap());
+        std::fs::write(&path, "line\n".repeat(99_999) + "lime\n").unwrap();
+        assert!(matches!(verify(&path), Err(ChecksumError::Corrupted { .. })));
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+}
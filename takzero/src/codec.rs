@@ -0,0 +1,668 @@
+//! Compact binary encoding of targets, replays, and evaluations, for
+//! persistence and for sending them between workers.
+//!
+//! The format follows [postcard](https://postcard.jamesmunns.com/wire-format):
+//! integers are LEB128 varints, floats are 4 little-endian bytes, strings and
+//! sequences are prefixed with their length, options and enums start with a
+//! tag byte, and fields are written in declaration order without names.
+//! Actions are stored in PTN notation. Positions are packed: the ply, a
+//! bitboard of the occupied squares, the height and top piece of each stack,
+//! and one bit per stone for its color, which is a fraction of the size of
+//! TPS and is encoded and decoded without any formatting. Neither depends on
+//! the internal representation of the game.
+
+use std::collections::VecDeque;
+
+use fast_tak::{
+    takparse::{Color, Move, ParseMoveError, Piece, Square},
+    Board,
+    Colors,
+    Game,
+    PlayError,
+    Reserves,
+    Stack,
+};
+use ordered_float::NotNan;
+use thiserror::Error;
+
+use crate::{
+    search::{
+        env::Terminal,
+        eval::{Eval, Wdl},
+    },
+    target::{Replay, RootStats, Target},
+};
+
+#[derive(Error, Debug)]
+pub enum DecodeError {
+    #[error("unexpected end of input")]
+    UnexpectedEnd,
+    #[error("{0} bytes left over after decoding")]
+    TrailingBytes(usize),
+    #[error("varint does not fit")]
+    VarintOverflow,
+    #[error("invalid tag {0}")]
+    InvalidTag(u8),
+    #[error("invalid UTF-8")]
+    Utf8,
+    #[error("NaN where a number was expected")]
+    Nan,
+    #[error("occupied square outside of the board")]
+    Square,
+    #[error("more pieces than the reserves hold")]
+    Reserves,
+    #[error("{0}")]
+    Action(#[from] ParseMoveError),
+    #[error("invalid action")]
+    Invalid(#[from] PlayError),
+}
+
+pub trait Encode {
+    fn encode(&self, out: &mut Vec<u8>);
+}
+
+pub trait Decode: Sized {
+    /// Decode a value from the start of the input and advance past it.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the input does not start with a valid encoding.
+    fn decode(input: &mut &[u8]) -> Result<Self, DecodeError>;
+}
+
+#[must_use]
+pub fn to_bytes(value: &impl Encode) -> Vec<u8> {
+    let mut out = Vec::new();
+    value.encode(&mut out);
+    out
+}
+
+/// Decode a value which takes up all of the bytes.
+///
+/// # Errors
+///
+/// Returns an error if the bytes are not a valid encoding or if bytes are
+/// left over.
+pub fn from_bytes<T: Decode>(mut bytes: &[u8]) -> Result<T, DecodeError> {
+    let value = T::decode(&mut bytes)?;
+    if bytes.is_empty() {
+        Ok(value)
+    } else {
+        Err(DecodeError::TrailingBytes(bytes.len()))
+    }
+}
+
+fn take<'a>(input: &mut &'a [u8], len: usize) -> Result<&'a [u8], DecodeError> {
+    if input.len() < len {
+        return Err(DecodeError::UnexpectedEnd);
+    }
+    let (bytes, rest) = input.split_at(len);
+    *input = rest;
+    Ok(bytes)
+}
+
+fn encode_varint(mut x: u64, out: &mut Vec<u8>) {
+    while x >= 0x80 {
+        out.push((x as u8) | 0x80);
+        x >>= 7;
+    }
+    out.push(x as u8);
+}
+
+fn decode_varint(input: &mut &[u8]) -> Result<u64, DecodeError> {
+    let mut x = 0;
+    for shift in (0..64).step_by(7) {
+        let byte = take(input, 1)?[0];
+        x |= u64::from(byte & 0x7f) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(x);
+        }
+    }
+    Err(DecodeError::VarintOverflow)
+}
+
+fn decode_len(input: &mut &[u8]) -> Result<usize, DecodeError> {
+    usize::try_from(decode_varint(input)?).map_err(|_| DecodeError::VarintOverflow)
+}
+
+fn decode_tag(input: &mut &[u8]) -> Result<u8, DecodeError> {
+    Ok(take(input, 1)?[0])
+}
+
+impl Encode for u64 {
+    fn encode(&self, out: &mut Vec<u8>) {
+        encode_varint(*self, out);
+    }
+}
+
+impl Decode for u64 {
+    fn decode(input: &mut &[u8]) -> Result<Self, DecodeError> {
+        decode_varint(input)
+    }
+}
+
+impl Encode for u32 {
+    fn encode(&self, out: &mut Vec<u8>) {
+        encode_varint(u64::from(*self), out);
+    }
+}
+
+impl Decode for u32 {
+    fn decode(input: &mut &[u8]) -> Result<Self, DecodeError> {
+        Self::try_from(decode_varint(input)?).map_err(|_| DecodeError::VarintOverflow)
+    }
+}
+
+impl Encode for u16 {
+    fn encode(&self, out: &mut Vec<u8>) {
+        encode_varint(u64::from(*self), out);
+    }
+}
+
+impl Decode for u16 {
+    fn decode(input: &mut &[u8]) -> Result<Self, DecodeError> {
+        Self::try_from(decode_varint(input)?).map_err(|_| DecodeError::VarintOverflow)
+    }
+}
+
+impl Encode for usize {
+    fn encode(&self, out: &mut Vec<u8>) {
+        encode_varint(*self as u64, out);
+    }
+}
+
+impl Decode for usize {
+    fn decode(input: &mut &[u8]) -> Result<Self, DecodeError> {
+        decode_len(input)
+    }
+}
+
+impl Encode for f32 {
+    fn encode(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&self.to_le_bytes());
+    }
+}
+
+impl Decode for f32 {
+    fn decode(input: &mut &[u8]) -> Result<Self, DecodeError> {
+        let bytes = take(input, 4)?;
+        Ok(Self::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+}
+
+impl Encode for NotNan<f32> {
+    fn encode(&self, out: &mut Vec<u8>) {
+        self.into_inner().encode(out);
+    }
+}
+
+impl Decode for NotNan<f32> {
+    fn decode(input: &mut &[u8]) -> Result<Self, DecodeError> {
+        Self::new(f32::decode(input)?).map_err(|_| DecodeError::Nan)
+    }
+}
+
+impl Encode for str {
+    fn encode(&self, out: &mut Vec<u8>) {
+        self.len().encode(out);
+        out.extend_from_slice(self.as_bytes());
+    }
+}
+
+impl Decode for String {
+    fn decode(input: &mut &[u8]) -> Result<Self, DecodeError> {
+        let len = decode_len(input)?;
+        let bytes = take(input, len)?;
+        Ok(std::str::from_utf8(bytes)
+            .map_err(|_| DecodeError::Utf8)?
+            .to_string())
+    }
+}
+
+impl<T: Encode> Encode for Option<T> {
+    fn encode(&self, out: &mut Vec<u8>) {
+        match self {
+            None => out.push(0),
+            Some(value) => {
+                out.push(1);
+                value.encode(out);
+            }
+        }
+    }
+}
+
+impl<T: Decode> Decode for Option<T> {
+    fn decode(input: &mut &[u8]) -> Result<Self, DecodeError> {
+        match decode_tag(input)? {
+            0 => Ok(None),
+            1 => T::decode(input).map(Some),
+            tag => Err(DecodeError::InvalidTag(tag)),
+        }
+    }
+}
+
+impl Encode for Move {
+    fn encode(&self, out: &mut Vec<u8>) {
+        self.to_string().encode(out);
+    }
+}
+
+impl Decode for Move {
+    fn decode(input: &mut &[u8]) -> Result<Self, DecodeError> {
+        Ok(String::decode(input)?.parse()?)
+    }
+}
+
+/// The ply, then a bitboard of the occupied squares where square `N * y + x`
+/// is bit `N * y + x`, then for each occupied square its height minus one
+/// shifted left by two with the top piece in the low bits, then the colors
+/// of all stones from the bottom of each stack, 1 for black.
+impl<const N: usize, const HALF_KOMI: i8> Encode for Game<N, HALF_KOMI>
+where
+    Reserves<N>: Default,
+{
+    fn encode(&self, out: &mut Vec<u8>) {
+        self.ply.encode(out);
+        let mut occupied = 0u64;
+        let mut stacks = Vec::new();
+        let mut colors = Vec::new();
+        for (y, row) in self.board.iter().enumerate() {
+            for (x, stack) in row.enumerate() {
+                let Some((piece, _)) = stack.top() else {
+                    continue;
+                };
+                occupied |= 1 << (N * y + x);
+                let below = colors.len();
+                colors.extend(stack.colors().into_iter().map(|color| color == Color::Black));
+                let piece = match piece {
+                    Piece::Flat => 0,
+                    Piece::Wall => 1,
+                    Piece::Cap => 2,
+                };
+                stacks.push(((colors.len() - below - 1) << 2) | piece);
+            }
+        }
+        out.extend_from_slice(&occupied.to_le_bytes()[..(N * N).div_ceil(8)]);
+        for stack in stacks {
+            stack.encode(out);
+        }
+        for chunk in colors.chunks(8) {
+            out.push(
+                chunk
+                    .iter()
+                    .enumerate()
+                    .fold(0, |byte, (i, &black)| byte | (u8::from(black) << i)),
+            );
+        }
+    }
+}
+
+/// Decoding builds the board directly and checks that the pieces fit in the
+/// reserves.
+impl<const N: usize, const HALF_KOMI: i8> Decode for Game<N, HALF_KOMI>
+where
+    Reserves<N>: Default,
+{
+    fn decode(input: &mut &[u8]) -> Result<Self, DecodeError> {
+        let ply = u16::decode(input)?;
+        let mut occupied = [0; 8];
+        let len = (N * N).div_ceil(8);
+        occupied[..len].copy_from_slice(take(input, len)?);
+        let occupied = u64::from_le_bytes(occupied);
+        if occupied.checked_shr((N * N) as u32).unwrap_or_default() != 0 {
+            return Err(DecodeError::Square);
+        }
+
+        let mut stacks = Vec::new();
+        let mut stones = 0usize;
+        for _ in 0..occupied.count_ones() {
+            let stack = decode_len(input)?;
+            let height = (stack >> 2) + 1;
+            stones = stones.checked_add(height).ok_or(DecodeError::VarintOverflow)?;
+            let piece = match stack & 3 {
+                0 => Piece::Flat,
+                1 => Piece::Wall,
+                2 => Piece::Cap,
+                tag => return Err(DecodeError::InvalidTag(tag as u8)),
+            };
+            stacks.push((height, piece));
+        }
+        let colors = take(input, stones.div_ceil(8))?;
+
+        let reserves = Reserves::<N>::default();
+        // Pieces of each color, and capstones among them.
+        let mut pieces = [0usize; 2];
+        let mut caps = [0usize; 2];
+        let mut board = Board::<N>::default();
+        let mut stacks = stacks.into_iter();
+        let mut stone = 0;
+        for square in (0..N * N).filter(|square| occupied & (1 << square) != 0) {
+            let Some((height, piece)) = stacks.next() else {
+                break;
+            };
+            let stack: Colors = (stone..stone + height)
+                .map(|stone| {
+                    let black = (colors[stone / 8] >> (stone % 8)) & 1 == 1;
+                    pieces[usize::from(black)] += 1;
+                    if black { Color::Black } else { Color::White }
+                })
+                .collect();
+            stone += height;
+            let top = stack.top().ok_or(DecodeError::Reserves)?;
+            if piece == Piece::Cap {
+                caps[usize::from(top == Color::Black)] += 1;
+            }
+            let square = Square::new((square % N) as u8, (square / N) as u8);
+            *board.get_mut(square).ok_or(DecodeError::Square)? = Stack::exact(piece, stack);
+        }
+        let (total, total_caps) = (usize::from(reserves.stones), usize::from(reserves.caps));
+        if (0..2).any(|color| caps[color] > total_caps || pieces[color] > total + caps[color]) {
+            return Err(DecodeError::Reserves);
+        }
+
+        let to_move = if ply % 2 == 0 { Color::White } else { Color::Black };
+        Ok(Self::from_board_and_to_move(board, to_move, Some(ply)))
+    }
+}
+
+impl Encode for Terminal {
+    fn encode(&self, out: &mut Vec<u8>) {
+        out.push(match self {
+            Self::Win => 0,
+            Self::Loss => 1,
+            Self::Draw => 2,
+        });
+    }
+}
+
+impl Decode for Terminal {
+    fn decode(input: &mut &[u8]) -> Result<Self, DecodeError> {
+        match decode_tag(input)? {
+            0 => Ok(Self::Win),
+            1 => Ok(Self::Loss),
+            2 => Ok(Self::Draw),
+            tag => Err(DecodeError::InvalidTag(tag)),
+        }
+    }
+}
+
+impl Encode for Eval {
+    fn encode(&self, out: &mut Vec<u8>) {
+        match self {
+            Self::Value(value) => {
+                out.push(0);
+                value.encode(out);
+            }
+            Self::Wdl(wdl) => {
+                out.push(4);
+                wdl.win.encode(out);
+                wdl.draw.encode(out);
+                wdl.loss.encode(out);
+            }
+            Self::Win(ply) => {
+                out.push(1);
+                ply.encode(out);
+            }
+            Self::Loss(ply) => {
+                out.push(2);
+                ply.encode(out);
+            }
+            Self::Draw(ply) => {
+                out.push(3);
+                ply.encode(out);
+            }
+        }
+    }
+}
+
+impl Decode for Eval {
+    fn decode(input: &mut &[u8]) -> Result<Self, DecodeError> {
+        match decode_tag(input)? {
+            0 => NotNan::decode(input).map(Self::Value),
+            1 => u32::decode(input).map(Self::Win),
+            2 => u32::decode(input).map(Self::Loss),
+            3 => u32::decode(input).map(Self::Draw),
+            4 => Ok(Self::Wdl(Wdl {
+                win: NotNan::decode(input)?,
+                draw: NotNan::decode(input)?,
+                loss: NotNan::decode(input)?,
+            })),
+            tag => Err(DecodeError::InvalidTag(tag)),
+        }
+    }
+}
+
+impl Encode for RootStats<Move> {
+    fn encode(&self, out: &mut Vec<u8>) {
+        self.value.encode(out);
+        self.ube.encode(out);
+        self.visits.len().encode(out);
+        for (action, visit_count) in &self.visits {
+            action.encode(out);
+            visit_count.encode(out);
+        }
+    }
+}
+
+impl Decode for RootStats<Move> {
+    fn decode(input: &mut &[u8]) -> Result<Self, DecodeError> {
+        let value = f32::decode(input)?;
+        let ube = f32::decode(input)?;
+        let len = decode_len(input)?;
+        let visits = (0..len)
+            .map(|_| Ok((Move::decode(input)?, u32::decode(input)?)))
+            .collect::<Result<_, DecodeError>>()?;
+        Ok(Self { value, ube, visits })
+    }
+}
+
+impl<const N: usize, const HALF_KOMI: i8> Encode for Target<Game<N, HALF_KOMI>>
+where
+    Reserves<N>: Default,
+{
+    fn encode(&self, out: &mut Vec<u8>) {
+        self.env.encode(out);
+        self.policy.len().encode(out);
+        for (action, p) in &*self.policy {
+            action.encode(out);
+            p.encode(out);
+        }
+        self.value.encode(out);
+        self.ube.encode(out);
+        self.weight.encode(out);
+        self.move_limit.encode(out);
+        self.model_steps.encode(out);
+    }
+}
+
+/// Unlike parsing the text format, decoding does not check that the policy
+/// contains exactly the legal actions. Targets which were encoded before they
+/// had a move limit end after the weight, and those from before they had
+/// model steps after the move limit.
+impl<const N: usize, const HALF_KOMI: i8> Decode for Target<Game<N, HALF_KOMI>>
+where
+    Reserves<N>: Default,
+{
+    fn decode(input: &mut &[u8]) -> Result<Self, DecodeError> {
+        let env = Game::decode(input)?;
+        let len = decode_len(input)?;
+        let policy = (0..len)
+            .map(|_| Ok((Move::decode(input)?, NotNan::decode(input)?)))
+            .collect::<Result<_, DecodeError>>()?;
+        Ok(Self {
+            env,
+            policy,
+            value: f32::decode(input)?,
+            ube: f32::decode(input)?,
+            weight: Option::decode(input)?,
+            move_limit: if input.is_empty() {
+                None
+            } else {
+                Option::decode(input)?
+            },
+            model_steps: if input.is_empty() {
+                None
+            } else {
+                Option::decode(input)?
+            },
+        })
+    }
+}
+
+impl<const N: usize, const HALF_KOMI: i8> Encode for Replay<Game<N, HALF_KOMI>>
+where
+    Reserves<N>: Default,
+{
+    fn encode(&self, out: &mut Vec<u8>) {
+        self.env.encode(out);
+        self.actions.len().encode(out);
+        for action in &self.actions {
+            action.encode(out);
+        }
+        self.adjudicated.encode(out);
+        self.generation.encode(out);
+        self.game_id.encode(out);
+        self.stats.len().encode(out);
+        for stats in &self.stats {
+            stats.encode(out);
+        }
+    }
+}
+
+impl<const N: usize, const HALF_KOMI: i8> Decode for Replay<Game<N, HALF_KOMI>>
+where
+    Reserves<N>: Default,
+{
+    fn decode(input: &mut &[u8]) -> Result<Self, DecodeError> {
+        let env: Game<N, HALF_KOMI> = Game::decode(input)?;
+        let len = decode_len(input)?;
+        let mut actions = VecDeque::new();
+        // Verify that the actions are valid.
+        let mut end = env.clone();
+        for _ in 0..len {
+            let action = Move::decode(input)?;
+            end.play(action)?;
+            actions.push_back(action);
+        }
+        Ok(Self {
+            env,
+            actions,
+            adjudicated: Option::decode(input)?,
+            generation: Option::decode(input)?,
+            game_id: Option::decode(input)?,
+            stats: (0..decode_len(input)?)
+                .map(|_| RootStats::decode(input))
+                .collect::<Result<_, _>>()?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use fast_tak::{takparse::Tps, Game};
+    use ordered_float::NotNan;
+    use rand::{rngs::StdRng, seq::IteratorRandom, SeedableRng};
+
+    use super::{from_bytes, to_bytes, DecodeError};
+    use crate::{
+        search::{
+            env::{Environment, Terminal},
+            eval::Eval,
+        },
+        target::{Replay, RootStats, Target},
+    };
+
+    #[test]
+    fn round_trip() {
+        let mut replay: Replay<Game<5, 4>> =
+            "[TPS \"x5/x5/x5/x5/x5 1 1\"] a1 e5 c3".parse().unwrap();
+        replay.adjudicated = Some(Terminal::Loss);
+        replay.generation = Some(1200);
+        replay.game_id = Some(u64::MAX);
+        replay.stats = replay
+            .actions
+            .iter()
+            .map(|&action| RootStats {
+                value: 0.5,
+                ube: 0.125,
+                visits: vec![(action, 700)],
+            })
+            .collect();
+        let bytes = to_bytes(&replay);
+        assert!(bytes.len() < replay.to_string().len());
+        assert_eq!(from_bytes::<Replay<Game<5, 4>>>(&bytes).unwrap(), replay);
+
+        let target: Target<Game<5, 4>> = Target {
+            env: replay.env,
+            policy: [
+                ("a1".parse().unwrap(), NotNan::new(0.75).unwrap()),
+                ("e5".parse().unwrap(), NotNan::new(0.25).unwrap()),
+            ]
+            .into(),
+            value: 0.25,
+            ube: -1.0,
+            weight: Some(0.5),
+            move_limit: Some(0.25),
+            model_steps: Some(1200),
+        };
+        let bytes = to_bytes(&target);
+        assert_eq!(from_bytes::<Target<Game<5, 4>>>(&bytes).unwrap(), target);
+        let old = &bytes[..bytes.len() - to_bytes(&Some(1200_usize)).len()];
+        let target = Target { model_steps: None, ..target };
+        assert_eq!(from_bytes::<Target<Game<5, 4>>>(old).unwrap(), target);
+        let old = &old[..old.len() - 5];
+        let target = Target { move_limit: None, ..target };
+        assert_eq!(from_bytes::<Target<Game<5, 4>>>(old).unwrap(), target);
+
+        for eval in [
+            Eval::new_value(-0.5).unwrap(),
+            Eval::new_wdl(0.25, 0.5, 0.25).unwrap(),
+            Eval::Win(3),
+            Eval::Loss(0),
+            Eval::Draw(200),
+        ] {
+            assert_eq!(from_bytes::<Eval>(&to_bytes(&eval)).unwrap(), eval);
+        }
+        assert_eq!(to_bytes(&Eval::Draw(200)), [3, 0xc8, 0x01]);
+
+        assert!(matches!(
+            from_bytes::<Eval>(&[1, 2, 0]),
+            Err(DecodeError::TrailingBytes(1))
+        ));
+        assert!(matches!(from_bytes::<Eval>(&[1, 0x80]), Err(DecodeError::UnexpectedEnd)));
+        assert!(matches!(from_bytes::<Eval>(&[5]), Err(DecodeError::InvalidTag(5))));
+    }
+
+    #[test]
+    fn packed_positions() {
+        let mut rng = StdRng::seed_from_u64(0);
+        let mut actions = Vec::new();
+        let mut game: Game<6, 4> = Game::default();
+        assert_eq!(to_bytes(&game).len(), 6);
+        while game.terminal().is_none() {
+            let bytes = to_bytes(&game);
+            let tps = Tps::from(game.clone()).to_string();
+            assert!(bytes.len() < tps.len(), "{tps}");
+            let decoded: Game<6, 4> = from_bytes(&bytes).unwrap();
+            assert_eq!(
+                (decoded.white_reserves, decoded.black_reserves),
+                (game.white_reserves, game.black_reserves)
+            );
+            assert_eq!(Tps::from(decoded).to_string(), tps);
+
+            game.populate_actions(&mut actions);
+            game.step(actions.drain(..).choose(&mut rng).unwrap());
+        }
+
+        // A stack on a square outside of a 3x3 board.
+        assert!(matches!(
+            from_bytes::<Game<3, 0>>(&[0, 0, 2, 0, 0]),
+            Err(DecodeError::Square)
+        ));
+        // Eleven white flats on a 3x3 board, which only has ten.
+        assert!(matches!(
+            from_bytes::<Game<3, 0>>(&[0, 1, 0, 40, 0, 0]),
+            Err(DecodeError::Reserves)
+        ));
+    }
+}
@@ -0,0 +1,167 @@
+//! Configuration files for the `takzero` command.
+//!
+//! A configuration file keeps the options of a run in one place, so that
+//! the workers and the trainer of a run agree on them. Options are written
+//! like long command line options without the dashes, one per line, and
+//! grouped into sections named after subcommands. Options before the first
+//! section are shared by every subcommand which has them:
+//! ```text
+//! # Shared by selfplay, train, and reanalyze.
+//! directory = runs/main
+//! storage = s3://bucket/main
+//!
+//! [selfplay]
+//! cores = 0-31
+//! horizon = 5
+//!
+//! [train]
+//! cores = 32-63
+//! audit = runs/main/train-audit.txt
+//!
+//! [evaluate]
+//! symmetric = true
+//! ```
+//! A value of `true` passes the option as a flag, and `false` leaves it out.
+//! An option given on the command line replaces the value in the file, but
+//! a flag set to `true` in the file cannot be turned off from the command
+//! line; remove it from the file instead.
+
+use std::{fs, io, path::Path, str::FromStr};
+
+use thiserror::Error;
+
+/// The options of a configuration file, in the order they were written.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Config {
+    shared: Vec<(String, String)>,
+    sections: Vec<(String, Vec<(String, String)>)>,
+}
+
+#[derive(Debug, Error)]
+pub enum ConfigError {
+    #[error("could not read the configuration: {0}")]
+    Io(#[from] io::Error),
+    #[error("line {line} of the configuration is not `key = value` or `[section]`: {content}")]
+    Line { line: usize, content: String },
+}
+
+impl FromStr for Config {
+    type Err = ConfigError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut config = Self::default();
+        for (i, line) in s.lines().enumerate() {
+            let line = line.split_once('#').map_or(line, |(line, _)| line).trim();
+            if line.is_empty() {
+                continue;
+            }
+            let malformed = || ConfigError::Line {
+                line: i + 1,
+                content: line.to_string(),
+            };
+            if let Some(section) = line.strip_prefix('[') {
+                let section = section.strip_suffix(']').ok_or_else(malformed)?.trim();
+                if section.is_empty() {
+                    return Err(malformed());
+                }
+                config.sections.push((section.to_string(), Vec::new()));
+                continue;
+            }
+            let (key, value) = line.split_once('=').ok_or_else(malformed)?;
+            let (key, value) = (key.trim(), value.trim());
+            if key.is_empty() || key.starts_with('-') {
+                return Err(malformed());
+            }
+            let options = match config.sections.last_mut() {
+                Some((_, options)) => options,
+                None => &mut config.shared,
+            };
+            options.push((key.to_string(), value.to_string()));
+        }
+        Ok(config)
+    }
+}
+
+impl Config {
+    /// Read a configuration file.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the file cannot be read or a line is malformed.
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, ConfigError> {
+        fs::read_to_string(path)?.parse()
+    }
+
+    /// The options of a subcommand as command line arguments. Shared
+    /// options are only included if `has_option` accepts their name, and
+    /// come first, so that the options of the section take precedence.
+    pub fn arguments(&self, subcommand: &str, has_option: impl Fn(&str) -> bool) -> Vec<String> {
+        let shared = self.shared.iter().filter(|(key, _)| has_option(key));
+        let section = self
+            .sections
+            .iter()
+            .filter(|(name, _)| name == subcommand)
+            .flat_map(|(_, options)| options);
+        let mut arguments = Vec::new();
+        for (key, value) in shared.chain(section) {
+            match value.as_str() {
+                "true" => arguments.push(format!("--{key}")),
+                "false" => {}
+                value => {
+                    arguments.push(format!("--{key}"));
+                    arguments.push(value.to_string());
+                }
+            }
+        }
+        arguments
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Config, ConfigError};
+
+    #[test]
+    fn sections_become_arguments() {
+        let config: Config = "
+            # Shared.
+            directory = runs/main
+            seed = 3 # trailing comment
+
+            [selfplay]
+            cores = 0-31
+            audit = true
+            resume = false
+
+            [train]
+            directory = runs/other
+        "
+        .parse()
+        .unwrap();
+        let has_option = |key: &str| key != "seed";
+        assert_eq!(config.arguments("selfplay", has_option), [
+            "--directory",
+            "runs/main",
+            "--cores",
+            "0-31",
+            "--audit"
+        ]);
+        assert_eq!(config.arguments("train", |_| true), [
+            "--directory",
+            "runs/main",
+            "--seed",
+            "3",
+            "--directory",
+            "runs/other"
+        ]);
+        assert!(config.arguments("tei", |_| false).is_empty());
+    }
+
+    #[test]
+    fn malformed_lines_are_reported() {
+        for (config, line) in [("[selfplay", 1), ("a = 1\nflag", 2), ("[]", 1), ("--a = 1", 1)] {
+            let err = config.parse::<Config>().unwrap_err();
+            assert!(matches!(err, ConfigError::Line { line: l, .. } if l == line), "{config}");
+        }
+    }
+}
@@ -0,0 +1,319 @@
+//! Runtime control of a long training run.
+//!
+//! [`serve`] answers small HTTP requests which steer a running trainer
+//! without restarting it, for example `curl -X POST host:8002/pause`:
+//! - `POST /pause` and `POST /resume` stop and continue training,
+//! - `POST /save` publishes the model after the current step,
+//! - `POST /learning-rate?value=5e-5` changes the learning rate,
+//! - `POST /temperature?value=1.5` samples the opening moves of self-play
+//!   with this temperature over visit counts (`value=default` goes back to
+//!   proportional sampling),
+//! - `POST /log-filter?value=info,takzero=debug` replaces the log filter,
+//! - `GET /status` returns the settings which were changed as JSON.
+//!
+//! Commands are queued in the global [`CONTROL`] until the trainer takes
+//! them, except for the log filter, which is replaced right away. Changes
+//! are not remembered across restarts. Anyone who can reach the address can
+//! steer the run, so it should be bound to a private interface.
+
+use std::{
+    fmt::{self, Write as _},
+    io::Write,
+    net::{TcpListener, TcpStream, ToSocketAddrs},
+    str::FromStr,
+    sync::Mutex,
+    thread::JoinHandle,
+};
+
+use thiserror::Error;
+
+use crate::{logging, metrics::read_request_line, search::node::export::write_string};
+
+pub static CONTROL: Control = Control::new();
+
+/// A command for the trainer.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Command {
+    Pause,
+    Resume,
+    Save,
+    LearningRate(f64),
+    /// Temperature for the opening moves of self-play, or `None` for the
+    /// default proportional sampling.
+    Temperature(Option<f32>),
+    LogFilter(String),
+}
+
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum ParseCommandError {
+    #[error("unknown command `{0}`")]
+    Unknown(String),
+    #[error("command `{0}` needs a `?value=`")]
+    MissingValue(String),
+    #[error("invalid value `{value}` for `{command}`")]
+    InvalidValue { command: String, value: String },
+}
+
+impl FromStr for Command {
+    type Err = ParseCommandError;
+
+    /// Parse the path of a request, like `/learning-rate?value=5e-5`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (path, query) = s.split_once('?').unwrap_or((s, ""));
+        let command = path.trim_start_matches('/');
+        let value = query.strip_prefix("value=");
+        let invalid = |value: &str| ParseCommandError::InvalidValue {
+            command: command.to_string(),
+            value: value.to_string(),
+        };
+        let value = || value.ok_or_else(|| ParseCommandError::MissingValue(command.to_string()));
+        match command {
+            "pause" => Ok(Self::Pause),
+            "resume" => Ok(Self::Resume),
+            "save" => Ok(Self::Save),
+            "learning-rate" => {
+                let value = value()?;
+                value
+                    .parse()
+                    .ok()
+                    .filter(|x: &f64| x.is_finite() && *x > 0.0)
+                    .map(Self::LearningRate)
+                    .ok_or_else(|| invalid(value))
+            }
+            "temperature" => match value()? {
+                "default" => Ok(Self::Temperature(None)),
+                value => value
+                    .parse()
+                    .ok()
+                    .filter(|x: &f32| x.is_finite() && *x > 0.0)
+                    .map(|x| Self::Temperature(Some(x)))
+                    .ok_or_else(|| invalid(value)),
+            },
+            "log-filter" => match value()? {
+                "" => Err(invalid("")),
+                value => Ok(Self::LogFilter(value.to_string())),
+            },
+            _ => Err(ParseCommandError::Unknown(command.to_string())),
+        }
+    }
+}
+
+/// The settings which were changed through the control endpoint.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Settings {
+    pub paused: bool,
+    pub learning_rate: Option<f64>,
+    pub temperature: Option<f32>,
+    pub log_filter: Option<String>,
+}
+
+#[derive(Debug)]
+struct State {
+    settings: Settings,
+    commands: Vec<Command>,
+}
+
+#[derive(Debug)]
+pub struct Control {
+    state: Mutex<State>,
+}
+
+impl Default for Control {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Control {
+    #[must_use]
+    pub const fn new() -> Self {
+        Self {
+            state: Mutex::new(State {
+                settings: Settings {
+                    paused: false,
+                    learning_rate: None,
+                    temperature: None,
+                    log_filter: None,
+                },
+                commands: Vec::new(),
+            }),
+        }
+    }
+
+    fn state(&self) -> std::sync::MutexGuard<'_, State> {
+        self.state.lock().expect("control lock should not be poisoned")
+    }
+
+    /// Accept a command. The log filter is replaced right away, everything
+    /// else waits for [`Control::take_commands`].
+    ///
+    /// # Panics
+    ///
+    /// Panics if the lock is poisoned.
+    pub fn send(&self, command: Command) {
+        let mut state = self.state();
+        let settings = &mut state.settings;
+        match &command {
+            Command::Pause => settings.paused = true,
+            Command::Resume => settings.paused = false,
+            Command::Save => {}
+            Command::LearningRate(learning_rate) => settings.learning_rate = Some(*learning_rate),
+            Command::Temperature(temperature) => settings.temperature = *temperature,
+            Command::LogFilter(filter) => {
+                settings.log_filter = Some(filter.clone());
+                logging::set_filter(filter);
+                return;
+            }
+        }
+        state.commands.push(command);
+    }
+
+    /// Take the commands which were sent since the last call, oldest first.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the lock is poisoned.
+    #[must_use]
+    pub fn take_commands(&self) -> Vec<Command> {
+        std::mem::take(&mut self.state().commands)
+    }
+
+    /// The settings which were changed so far.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the lock is poisoned.
+    #[must_use]
+    pub fn settings(&self) -> Settings {
+        self.state().settings.clone()
+    }
+}
+
+impl Settings {
+    /// Render the settings as JSON, with `null` for unchanged ones.
+    #[must_use]
+    pub fn render_json(&self) -> String {
+        let mut out = String::new();
+        // Writing to a string does not fail.
+        let _ = self.write_json(&mut out);
+        out
+    }
+
+    fn write_json(&self, out: &mut String) -> fmt::Result {
+        write!(out, "{{\"paused\":{}", self.paused)?;
+        match self.learning_rate {
+            Some(learning_rate) => write!(out, ",\"learning_rate\":{learning_rate}")?,
+            None => out.push_str(",\"learning_rate\":null"),
+        }
+        match self.temperature {
+            Some(temperature) => write!(out, ",\"temperature\":{temperature}")?,
+            None => out.push_str(",\"temperature\":null"),
+        }
+        out.push_str(",\"log_filter\":");
+        match &self.log_filter {
+            Some(filter) => write_string(out, filter)?,
+            None => out.push_str("null"),
+        }
+        out.push('}');
+        Ok(())
+    }
+}
+
+/// Serve the global control endpoint on a background thread.
+///
+/// # Errors
+///
+/// Returns an error if the address cannot be bound.
+pub fn serve(address: impl ToSocketAddrs) -> std::io::Result<JoinHandle<()>> {
+    let listener = TcpListener::bind(address)?;
+    log::info!("Serving control endpoint on http://{}", listener.local_addr()?);
+    Ok(std::thread::spawn(move || {
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    if let Err(err) = respond(stream) {
+                        log::warn!("Could not respond to control request: {err}");
+                    }
+                }
+                Err(err) => log::warn!("Could not accept control connection: {err}"),
+            }
+        }
+    }))
+}
+
+fn respond(mut stream: TcpStream) -> std::io::Result<()> {
+    let request_line = read_request_line(&stream)?;
+    let (status, body) = match request_line.split_whitespace().take(2).collect::<Vec<_>>()[..] {
+        ["GET", "/status"] => ("200 OK", CONTROL.settings().render_json()),
+        ["POST", target] => match target.parse() {
+            Ok(command) => {
+                log::info!("Received control command {command:?}");
+                CONTROL.send(command);
+                ("200 OK", CONTROL.settings().render_json())
+            }
+            Err(ParseCommandError::Unknown(_)) => ("404 Not Found", String::new()),
+            Err(err) => ("400 Bad Request", err.to_string()),
+        },
+        _ => ("404 Not Found", String::new()),
+    };
+    write!(
+        stream,
+        "HTTP/1.1 {status}\r\nContent-Type: application/json\r\nContent-Length: \
+         {}\r\nConnection: close\r\n\r\n{body}",
+        body.len()
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Command, Control, ParseCommandError, Settings};
+
+    #[test]
+    fn parse_commands() {
+        assert_eq!("/pause".parse(), Ok(Command::Pause));
+        assert_eq!("/learning-rate?value=5e-5".parse(), Ok(Command::LearningRate(5e-5)));
+        assert_eq!("/temperature?value=default".parse(), Ok(Command::Temperature(None)));
+        assert_eq!("/temperature?value=1.5".parse(), Ok(Command::Temperature(Some(1.5))));
+        assert_eq!(
+            "/log-filter?value=info,takzero=debug".parse(),
+            Ok(Command::LogFilter("info,takzero=debug".into()))
+        );
+        assert_eq!(
+            "/learning-rate".parse::<Command>(),
+            Err(ParseCommandError::MissingValue("learning-rate".into()))
+        );
+        assert!(matches!(
+            "/learning-rate?value=-1".parse::<Command>(),
+            Err(ParseCommandError::InvalidValue { .. })
+        ));
+        assert!(matches!(
+            "/temperature?value=0".parse::<Command>(),
+            Err(ParseCommandError::InvalidValue { .. })
+        ));
+        assert_eq!("/stop".parse::<Command>(), Err(ParseCommandError::Unknown("stop".into())));
+    }
+
+    #[test]
+    fn commands_are_queued() {
+        let control = Control::new();
+        control.send(Command::Pause);
+        control.send(Command::LearningRate(1e-5));
+        control.send(Command::LogFilter("warn".into()));
+        assert_eq!(control.settings(), Settings {
+            paused: true,
+            learning_rate: Some(1e-5),
+            temperature: None,
+            log_filter: Some("warn".into()),
+        });
+        assert_eq!(control.take_commands(), [Command::Pause, Command::LearningRate(1e-5)]);
+        assert!(control.take_commands().is_empty());
+
+        control.send(Command::Resume);
+        control.send(Command::Temperature(Some(0.5)));
+        assert_eq!(
+            control.settings().render_json(),
+            "{\"paused\":false,\"learning_rate\":0.00001,\"temperature\":0.5,\
+             \"log_filter\":\"warn\"}"
+        );
+    }
+}
@@ -0,0 +1,262 @@
+//! Board-size curriculum.
+//!
+//! Self-play starts on the smallest board size and moves on to the next
+//! one once the Elo on the current size stops improving. When advancing,
+//! the previous size is not dropped immediately but mixed with the new one
+//! for a few observations so the network does not forget it abruptly.
+//!
+//! The state is stored as a single line of text so that it can be shared
+//! between processes through a file (like `buffer_lengths.txt`).
+
+use std::{
+    fmt,
+    num::{ParseFloatError, ParseIntError},
+    path::Path,
+    str::FromStr,
+};
+
+use thiserror::Error;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Curriculum {
+    /// Board sizes in the order they should be trained on.
+    pub board_sizes: Vec<usize>,
+    /// Number of Elo observations used to detect a plateau.
+    pub window: usize,
+    /// Minimum Elo gained over `window` observations to not be a plateau.
+    pub min_gain: f64,
+    /// Number of observations over which the previous board size is mixed
+    /// in after advancing.
+    pub mix_observations: usize,
+    stage: usize,
+    since_advance: usize,
+    history: Vec<f64>,
+}
+
+impl Curriculum {
+    /// # Panics
+    ///
+    /// Panics if there are no board sizes or the window is zero.
+    #[must_use]
+    pub fn new(
+        board_sizes: Vec<usize>,
+        window: usize,
+        min_gain: f64,
+        mix_observations: usize,
+    ) -> Self {
+        assert!(!board_sizes.is_empty(), "curriculum needs a board size");
+        assert!(window > 0, "plateau window must be positive");
+        Self {
+            board_sizes,
+            window,
+            min_gain,
+            mix_observations,
+            stage: 0,
+            since_advance: 0,
+            history: Vec::new(),
+        }
+    }
+
+    /// The board size of the current stage.
+    #[must_use]
+    pub fn current_board_size(&self) -> usize {
+        self.board_sizes[self.stage]
+    }
+
+    #[must_use]
+    pub fn is_last_stage(&self) -> bool {
+        self.stage + 1 == self.board_sizes.len()
+    }
+
+    /// The most recently observed Elo on the current board size.
+    #[must_use]
+    pub fn latest_elo(&self) -> f64 {
+        self.history.last().copied().unwrap_or_default()
+    }
+
+    /// The observed Elo on the current board size, oldest first.
+    #[must_use]
+    pub fn history(&self) -> &[f64] {
+        &self.history
+    }
+
+    /// Record the Elo of the latest network on the current board size.
+    /// Returns `true` if the curriculum advanced to the next board size.
+    pub fn observe(&mut self, elo: f64) -> bool {
+        self.since_advance += 1;
+        self.history.push(elo);
+        if self.is_last_stage() || !self.plateaued() {
+            return false;
+        }
+        self.stage += 1;
+        self.since_advance = 0;
+        self.history.clear();
+        true
+    }
+
+    fn plateaued(&self) -> bool {
+        self.history.len() > self.window
+            && self.history[self.history.len() - 1]
+                - self.history[self.history.len() - 1 - self.window]
+                < self.min_gain
+    }
+
+    /// Fraction of self-play which should be done on the given board size.
+    #[must_use]
+    pub fn share(&self, board_size: usize) -> f32 {
+        let current = self.current_board_size();
+        let mixing = self.stage > 0 && self.since_advance < self.mix_observations;
+        let new_share = if mixing {
+            (self.since_advance + 1) as f32 / (self.mix_observations + 1) as f32
+        } else {
+            1.0
+        };
+        if board_size == current {
+            new_share
+        } else if mixing && board_size == self.board_sizes[self.stage - 1] {
+            1.0 - new_share
+        } else {
+            0.0
+        }
+    }
+
+    /// Load the curriculum state from a file.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the file cannot be read or parsed.
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, LoadCurriculumError> {
+        Ok(std::fs::read_to_string(path)?.parse()?)
+    }
+
+    /// Save the curriculum state to a file, replacing the previous contents.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the file cannot be written.
+    pub fn save(&self, path: impl AsRef<Path>) -> std::io::Result<()> {
+        std::fs::write(path, self.to_string())
+    }
+}
+
+impl fmt::Display for Curriculum {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let join = |v: Vec<String>| v.join(",");
+        writeln!(
+            f,
+            "{};{};{};{};{};{};{}",
+            join(self.board_sizes.iter().map(ToString::to_string).collect()),
+            self.window,
+            self.min_gain,
+            self.mix_observations,
+            self.stage,
+            self.since_advance,
+            join(self.history.iter().map(ToString::to_string).collect()),
+        )
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum ParseCurriculumError {
+    #[error("missing field")]
+    MissingField,
+    #[error("stage is out of range")]
+    StageOutOfRange,
+    #[error("{0}")]
+    Int(#[from] ParseIntError),
+    #[error("{0}")]
+    Float(#[from] ParseFloatError),
+}
+
+#[derive(Error, Debug)]
+pub enum LoadCurriculumError {
+    #[error("io: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("parse: {0}")]
+    Parse(#[from] ParseCurriculumError),
+}
+
+impl FromStr for Curriculum {
+    type Err = ParseCurriculumError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        //{board_sizes};{window};{min_gain};{mix_observations};{stage};{since_advance};{history}
+        let mut iter = s.trim().split(';');
+        let mut next = || iter.next().ok_or(ParseCurriculumError::MissingField);
+        let board_sizes = next()?
+            .split(',')
+            .map(str::parse)
+            .collect::<Result<Vec<usize>, _>>()?;
+        let window = next()?.parse()?;
+        let min_gain = next()?.parse()?;
+        let mix_observations = next()?.parse()?;
+        let stage = next()?.parse()?;
+        let since_advance = next()?.parse()?;
+        let history = next()?
+            .split(',')
+            .filter(|s| !s.is_empty())
+            .map(str::parse)
+            .collect::<Result<_, _>>()?;
+        if stage >= board_sizes.len() {
+            return Err(ParseCurriculumError::StageOutOfRange);
+        }
+        Ok(Self {
+            board_sizes,
+            window,
+            min_gain,
+            mix_observations,
+            stage,
+            since_advance,
+            history,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Curriculum;
+
+    #[test]
+    fn advances_on_plateau() {
+        let mut curriculum = Curriculum::new(vec![4, 5, 6], 2, 10.0, 3);
+        assert!(!curriculum.observe(0.0));
+        assert!(!curriculum.observe(50.0));
+        assert!(!curriculum.observe(100.0));
+        assert!((curriculum.share(4) - 1.0).abs() < f32::EPSILON);
+        assert!(curriculum.share(5).abs() < f32::EPSILON);
+
+        assert!(!curriculum.observe(105.0));
+        assert!(curriculum.observe(106.0));
+        assert_eq!(curriculum.current_board_size(), 5);
+
+        // The previous size is slowly phased out.
+        assert!((curriculum.share(4) + curriculum.share(5) - 1.0).abs() < f32::EPSILON);
+        assert!(curriculum.share(4) > curriculum.share(5));
+        for elo in [200.0, 300.0, 400.0] {
+            assert!(!curriculum.observe(elo));
+        }
+        assert!(curriculum.share(4).abs() < f32::EPSILON);
+        assert!((curriculum.share(5) - 1.0).abs() < f32::EPSILON);
+    }
+
+    #[test]
+    fn last_stage_is_final() {
+        let mut curriculum = Curriculum::new(vec![4], 1, 10.0, 0);
+        for _ in 0..10 {
+            assert!(!curriculum.observe(0.0));
+        }
+        assert_eq!(curriculum.current_board_size(), 4);
+    }
+
+    #[test]
+    fn curriculum_consistency() {
+        let mut curriculum = Curriculum::new(vec![3, 4, 5], 3, 25.0, 4);
+        for elo in [0.0, 10.0, 12.5, 13.0, 14.0, 20.0] {
+            curriculum.observe(elo);
+        }
+        let string = curriculum.to_string();
+        let recovered: Curriculum = string.parse().unwrap();
+        assert_eq!(curriculum, recovered);
+        assert_eq!(string, recovered.to_string());
+    }
+}
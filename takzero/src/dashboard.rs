@@ -0,0 +1,301 @@
+//! Training dashboard.
+//!
+//! The trainer records losses, buffer sizes, the Elo history, and recent
+//! games into the global [`DASHBOARD`], and [`serve`] answers `GET /` with a
+//! self-contained page which polls `GET /data` (JSON) and draws them. This
+//! way a run can be monitored without a separate `TensorBoard` or Grafana stack.
+
+use std::{
+    fmt::{self, Write as _},
+    io::Write,
+    net::{TcpListener, TcpStream, ToSocketAddrs},
+    sync::Mutex,
+    thread::JoinHandle,
+};
+
+use crate::{metrics::read_request_line, search::node::export::write_string};
+
+pub static DASHBOARD: Dashboard = Dashboard::new();
+
+/// Points kept per series. Older points are thinned out beyond this.
+const MAX_POINTS: usize = 1000;
+/// Number of recent games which can be viewed.
+const MAX_GAMES: usize = 10;
+const PAGE: &str = include_str!("dashboard.html");
+
+/// A game which can be viewed move by move.
+#[derive(Debug, Clone, Default)]
+pub struct Game {
+    pub moves: Vec<String>,
+    /// TPS of every position, including the one after the last move.
+    pub positions: Vec<String>,
+}
+
+#[derive(Debug)]
+struct Series {
+    name: &'static str,
+    points: Vec<(usize, f64)>,
+    /// Only every `stride`-th value is kept.
+    stride: usize,
+    skipped: usize,
+}
+
+impl Series {
+    const fn new(name: &'static str) -> Self {
+        Self {
+            name,
+            points: Vec::new(),
+            stride: 1,
+            skipped: 0,
+        }
+    }
+
+    fn push(&mut self, step: usize, value: f64) {
+        self.skipped += 1;
+        if self.skipped < self.stride {
+            return;
+        }
+        self.skipped = 0;
+        self.points.push((step, value));
+        if self.points.len() > MAX_POINTS {
+            // Halve the resolution of the whole series.
+            let mut i = 0;
+            self.points.retain(|_| {
+                i += 1;
+                i % 2 == 1
+            });
+            self.stride *= 2;
+        }
+    }
+}
+
+#[derive(Debug)]
+struct State {
+    step: usize,
+    losses: Vec<Series>,
+    buffers: Vec<Series>,
+    elo: Vec<f64>,
+    games: Vec<Game>,
+}
+
+#[derive(Debug)]
+pub struct Dashboard {
+    state: Mutex<State>,
+}
+
+impl Default for Dashboard {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn record(series: &mut Vec<Series>, name: &'static str, step: usize, value: f64) {
+    let index = series.iter().position(|s| s.name == name).unwrap_or_else(|| {
+        series.push(Series::new(name));
+        series.len() - 1
+    });
+    series[index].push(step, value);
+}
+
+impl Dashboard {
+    #[must_use]
+    pub const fn new() -> Self {
+        Self {
+            state: Mutex::new(State {
+                step: 0,
+                losses: Vec::new(),
+                buffers: Vec::new(),
+                elo: Vec::new(),
+                games: Vec::new(),
+            }),
+        }
+    }
+
+    fn state(&self) -> std::sync::MutexGuard<'_, State> {
+        self.state.lock().expect("dashboard lock should not be poisoned")
+    }
+
+    /// Set the training step which following records belong to.
+    pub fn set_step(&self, step: usize) {
+        self.state().step = step;
+    }
+
+    /// Record a training loss.
+    pub fn record_loss(&self, name: &'static str, value: f64) {
+        let mut state = self.state();
+        let step = state.step;
+        record(&mut state.losses, name, step, value);
+    }
+
+    /// Record the number of targets in a buffer.
+    pub fn record_buffer(&self, name: &'static str, len: usize) {
+        let mut state = self.state();
+        let step = state.step;
+        record(&mut state.buffers, name, step, len as f64);
+    }
+
+    /// Replace the Elo history of the evaluated checkpoints.
+    pub fn set_elo(&self, history: &[f64]) {
+        self.state().elo = history.to_vec();
+    }
+
+    /// Replace the recent games, oldest first. Only the last few are kept.
+    pub fn set_games(&self, mut games: Vec<Game>) {
+        games.drain(..games.len().saturating_sub(MAX_GAMES));
+        self.state().games = games;
+    }
+
+    /// Render everything as JSON for the page.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the lock is poisoned.
+    #[must_use]
+    pub fn render_json(&self) -> String {
+        let mut out = String::new();
+        self.state()
+            .write_json(&mut out)
+            .expect("writing to a string should not fail");
+        out
+    }
+}
+
+fn write_number(out: &mut String, x: f64) -> fmt::Result {
+    if x.is_finite() {
+        write!(out, "{x}")
+    } else {
+        write!(out, "null")
+    }
+}
+
+fn write_strings(out: &mut String, strings: &[String]) -> fmt::Result {
+    out.push('[');
+    for (i, s) in strings.iter().enumerate() {
+        if i > 0 {
+            out.push(',');
+        }
+        write_string(out, s)?;
+    }
+    out.push(']');
+    Ok(())
+}
+
+fn write_series(out: &mut String, series: &[Series]) -> fmt::Result {
+    out.push('{');
+    for (i, s) in series.iter().enumerate() {
+        if i > 0 {
+            out.push(',');
+        }
+        write_string(out, s.name)?;
+        out.push_str(":[");
+        for (j, &(step, value)) in s.points.iter().enumerate() {
+            if j > 0 {
+                out.push(',');
+            }
+            write!(out, "[{step},")?;
+            write_number(out, value)?;
+            out.push(']');
+        }
+        out.push(']');
+    }
+    out.push('}');
+    Ok(())
+}
+
+impl State {
+    fn write_json(&self, out: &mut String) -> fmt::Result {
+        write!(out, "{{\"step\":{},\"losses\":", self.step)?;
+        write_series(out, &self.losses)?;
+        out.push_str(",\"buffers\":");
+        write_series(out, &self.buffers)?;
+        out.push_str(",\"elo\":[");
+        for (i, &elo) in self.elo.iter().enumerate() {
+            if i > 0 {
+                out.push(',');
+            }
+            write_number(out, elo)?;
+        }
+        out.push_str("],\"games\":[");
+        for (i, game) in self.games.iter().enumerate() {
+            if i > 0 {
+                out.push(',');
+            }
+            out.push_str("{\"moves\":");
+            write_strings(out, &game.moves)?;
+            out.push_str(",\"positions\":");
+            write_strings(out, &game.positions)?;
+            out.push('}');
+        }
+        out.push_str("]}");
+        Ok(())
+    }
+}
+
+/// Serve the global dashboard on a background thread.
+///
+/// # Errors
+///
+/// Returns an error if the address cannot be bound.
+pub fn serve(address: impl ToSocketAddrs) -> std::io::Result<JoinHandle<()>> {
+    let listener = TcpListener::bind(address)?;
+    log::info!("Serving dashboard on http://{}", listener.local_addr()?);
+    Ok(std::thread::spawn(move || {
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    if let Err(err) = respond(stream) {
+                        log::warn!("Could not respond to dashboard request: {err}");
+                    }
+                }
+                Err(err) => log::warn!("Could not accept dashboard connection: {err}"),
+            }
+        }
+    }))
+}
+
+fn respond(mut stream: TcpStream) -> std::io::Result<()> {
+    let request_line = read_request_line(&stream)?;
+    let (status, content_type, body) =
+        match request_line.split_whitespace().take(2).collect::<Vec<_>>()[..] {
+            ["GET", "/"] => ("200 OK", "text/html; charset=utf-8", PAGE.to_string()),
+            ["GET", "/data"] => ("200 OK", "application/json", DASHBOARD.render_json()),
+            _ => ("404 Not Found", "text/plain", String::new()),
+        };
+    write!(
+        stream,
+        "HTTP/1.1 {status}\r\nContent-Type: {content_type}\r\nContent-Length: \
+         {}\r\nConnection: close\r\n\r\n{body}",
+        body.len()
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Dashboard, Game, MAX_POINTS};
+
+    #[test]
+    fn render_and_thin_out() {
+        let dashboard = Dashboard::new();
+        for step in 0..=MAX_POINTS {
+            dashboard.set_step(step);
+            dashboard.record_loss("value", 0.5);
+        }
+        dashboard.record_buffer("selfplay", 10);
+        dashboard.set_elo(&[0.0, 12.5]);
+        dashboard.set_games(vec![Game {
+            moves: vec!["a1".into()],
+            positions: vec!["x3/x3/x3 1 1".into(), "x3/x3/2,x2 2 1".into()],
+        }]);
+
+        let state = dashboard.state();
+        assert_eq!(state.losses[0].points.len(), MAX_POINTS / 2 + 1);
+        assert_eq!(state.losses[0].stride, 2);
+        drop(state);
+        let json = dashboard.render_json();
+        assert!(json.starts_with("{\"step\":1000,\"losses\":{\"value\":[[0,0.5],[2,0.5],"));
+        assert!(json.ends_with(
+            ",\"buffers\":{\"selfplay\":[[1000,10]]},\"elo\":[0,12.5],\"games\":[{\"moves\":[\"a1\"],\
+             \"positions\":[\"x3/x3/x3 1 1\",\"x3/x3/2,x2 2 1\"]}]}"
+        ));
+    }
+}
@@ -0,0 +1,377 @@
+//! Single-file archives of target shards, for moving datasets between
+//! machines.
+//!
+//! An archive starts with the line `#takzero-dataset version=1`, followed by
+//! every shard as its own gzip member, then a manifest with one line per
+//! shard like `targets-00017.txt bytes=1048576 crc32=8a9136aa offset=27
+//! compressed=212345`, and ends with a line `#manifest offset=1234567` which
+//! says where the manifest starts. The CRC is of the uncompressed shard, so
+//! reading a shard back checks that it is exactly what was packed.
+
+use std::{
+    fmt,
+    fs::File,
+    io::{self, BufRead, BufReader, BufWriter, Read, Seek, SeekFrom, Write},
+    path::Path,
+    str::FromStr,
+};
+
+use flate2::{read::GzDecoder, write::GzEncoder, Compression, Crc};
+use thiserror::Error;
+
+pub const MAGIC: &str = "#takzero-dataset";
+pub const VERSION: u32 = 1;
+const TRAILER: &str = "#manifest";
+/// Enough to hold the trailer line.
+const TRAILER_LEN: u64 = 64;
+const CHUNK_LEN: usize = 1 << 16;
+
+#[derive(Error, Debug)]
+pub enum DatasetError {
+    #[error("{0}")]
+    Io(#[from] io::Error),
+    #[error("not a dataset archive")]
+    NotADataset,
+    #[error("format version {0} is newer than the supported version {VERSION}")]
+    NewerVersion(u32),
+    #[error("invalid manifest line `{0}`")]
+    InvalidManifest(String),
+    #[error("there is no shard `{0}`")]
+    MissingShard(String),
+    #[error("shard `{name}` should have {expected} bytes but has {found}")]
+    WrongLength {
+        name: String,
+        expected: u64,
+        found: u64,
+    },
+    #[error("shard `{name}` should have CRC {expected:08x} but has {found:08x}")]
+    WrongChecksum {
+        name: String,
+        expected: u32,
+        found: u32,
+    },
+}
+
+/// Shard names are plain file names, so that unpacking an archive cannot
+/// write outside of the target directory.
+fn is_valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && name != "."
+        && name != ".."
+        && !name.contains(|c: char| c.is_whitespace() || c == '/' || c == '\\')
+}
+
+/// A shard in the manifest.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Entry {
+    pub name: String,
+    /// Uncompressed length.
+    pub bytes: u64,
+    /// CRC-32 of the uncompressed shard.
+    pub crc32: u32,
+    /// Start of the gzip member in the archive.
+    pub offset: u64,
+    /// Length of the gzip member.
+    pub compressed: u64,
+}
+
+impl fmt::Display for Entry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "{} bytes={} crc32={:08x} offset={} compressed={}",
+            self.name, self.bytes, self.crc32, self.offset, self.compressed
+        )
+    }
+}
+
+impl FromStr for Entry {
+    type Err = DatasetError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || DatasetError::InvalidManifest(s.to_string());
+        let mut fields = s.split_whitespace();
+        let name = fields
+            .next()
+            .filter(|name| is_valid_name(name))
+            .ok_or_else(invalid)?
+            .to_string();
+        let mut field = |key: &str| {
+            fields
+                .next()
+                .and_then(|field| field.strip_prefix(key)?.strip_prefix('='))
+                .ok_or_else(invalid)
+        };
+        let bytes = field("bytes")?.parse().map_err(|_| invalid())?;
+        let crc32 = u32::from_str_radix(field("crc32")?, 16).map_err(|_| invalid())?;
+        let offset = field("offset")?.parse().map_err(|_| invalid())?;
+        let compressed = field("compressed")?.parse().map_err(|_| invalid())?;
+        Ok(Self {
+            name,
+            bytes,
+            crc32,
+            offset,
+            compressed,
+        })
+    }
+}
+
+pub struct DatasetWriter {
+    file: BufWriter<File>,
+    entries: Vec<Entry>,
+}
+
+impl DatasetWriter {
+    /// Create a new archive, replacing any existing file.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the file cannot be created or written.
+    pub fn create(path: impl AsRef<Path>) -> io::Result<Self> {
+        let mut file = BufWriter::new(File::create(path)?);
+        writeln!(file, "{MAGIC} version={VERSION}")?;
+        Ok(Self {
+            file,
+            entries: Vec::new(),
+        })
+    }
+
+    /// Compress a shard into the archive under the given name.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the name is not a plain file name without
+    /// whitespace or if reading or writing fails.
+    pub fn add(&mut self, name: &str, mut shard: impl Read) -> io::Result<&Entry> {
+        if !is_valid_name(name) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("invalid shard name `{name}`"),
+            ));
+        }
+        let offset = self.file.stream_position()?;
+        let mut crc = Crc::new();
+        let mut bytes = 0;
+        let mut encoder = GzEncoder::new(&mut self.file, Compression::default());
+        let mut buffer = vec![0; CHUNK_LEN];
+        loop {
+            let read = shard.read(&mut buffer)?;
+            if read == 0 {
+                break;
+            }
+            crc.update(&buffer[..read]);
+            bytes += read as u64;
+            encoder.write_all(&buffer[..read])?;
+        }
+        encoder.finish()?;
+        let end = self.file.stream_position()?;
+        self.entries.push(Entry {
+            name: name.to_string(),
+            bytes,
+            crc32: crc.sum(),
+            offset,
+            compressed: end - offset,
+        });
+        Ok(&self.entries[self.entries.len() - 1])
+    }
+
+    /// Add a shard file under its file name. Gzipped shards are stored
+    /// decompressed, so that their CRC is of the content.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the file cannot be read or the archive cannot be
+    /// written.
+    pub fn add_file(&mut self, path: impl AsRef<Path>) -> io::Result<&Entry> {
+        let path = path.as_ref();
+        let name = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .map(|name| name.strip_suffix(".gz").unwrap_or(name).to_string())
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "invalid file name"))?;
+        let shard = crate::reader::open(path)?;
+        self.add(&name, shard)
+    }
+
+    /// Write the manifest and flush the archive.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if writing fails.
+    pub fn finish(mut self) -> io::Result<Vec<Entry>> {
+        let manifest = self.file.stream_position()?;
+        for entry in &self.entries {
+            write!(self.file, "{entry}")?;
+        }
+        writeln!(self.file, "{TRAILER} offset={manifest}")?;
+        self.file.flush()?;
+        Ok(self.entries)
+    }
+}
+
+pub struct DatasetReader {
+    file: File,
+    entries: Vec<Entry>,
+}
+
+impl DatasetReader {
+    /// Open an archive and read its manifest. Shards are only checked when
+    /// they are read.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the file is not a dataset archive of a supported
+    /// version or its manifest is malformed.
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, DatasetError> {
+        let mut file = File::open(path)?;
+        let mut first = String::new();
+        BufReader::new(&mut file).read_line(&mut first)?;
+        let version = first
+            .strip_prefix(MAGIC)
+            .and_then(|rest| rest.trim().strip_prefix("version="))
+            .and_then(|version| version.parse().ok())
+            .ok_or(DatasetError::NotADataset)?;
+        if version > VERSION {
+            return Err(DatasetError::NewerVersion(version));
+        }
+
+        let len = file.metadata()?.len();
+        file.seek(SeekFrom::Start(len.saturating_sub(TRAILER_LEN)))?;
+        let mut tail = Vec::new();
+        file.read_to_end(&mut tail)?;
+        let manifest: u64 = String::from_utf8_lossy(&tail)
+            .lines()
+            .last()
+            .and_then(|line| line.strip_prefix(TRAILER))
+            .and_then(|rest| rest.trim().strip_prefix("offset="))
+            .and_then(|offset| offset.parse().ok())
+            .ok_or(DatasetError::NotADataset)?;
+
+        file.seek(SeekFrom::Start(manifest))?;
+        let entries = BufReader::new(&mut file)
+            .lines()
+            .take_while(|line| !matches!(line, Ok(line) if line.starts_with(TRAILER)))
+            .map(|line| line?.parse())
+            .collect::<Result<_, DatasetError>>()?;
+        Ok(Self { file, entries })
+    }
+
+    #[must_use]
+    pub fn entries(&self) -> &[Entry] {
+        &self.entries
+    }
+
+    /// Decompress a shard into `out`, checking its length and CRC.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if there is no such shard, if reading or writing
+    /// fails, or if the shard is corrupted. Some of a corrupted shard may
+    /// already have been written.
+    pub fn extract(&self, name: &str, out: &mut impl Write) -> Result<(), DatasetError> {
+        let entry = self
+            .entries
+            .iter()
+            .find(|entry| entry.name == name)
+            .ok_or_else(|| DatasetError::MissingShard(name.to_string()))?;
+        let mut file = self.file.try_clone()?;
+        file.seek(SeekFrom::Start(entry.offset))?;
+        let mut decoder = GzDecoder::new(file.take(entry.compressed));
+        let mut crc = Crc::new();
+        let mut bytes = 0;
+        let mut buffer = vec![0; CHUNK_LEN];
+        loop {
+            let read = decoder.read(&mut buffer)?;
+            if read == 0 {
+                break;
+            }
+            crc.update(&buffer[..read]);
+            bytes += read as u64;
+            out.write_all(&buffer[..read])?;
+        }
+        if bytes != entry.bytes {
+            return Err(DatasetError::WrongLength {
+                name: entry.name.clone(),
+                expected: entry.bytes,
+                found: bytes,
+            });
+        }
+        if crc.sum() != entry.crc32 {
+            return Err(DatasetError::WrongChecksum {
+                name: entry.name.clone(),
+                expected: entry.crc32,
+                found: crc.sum(),
+            });
+        }
+        Ok(())
+    }
+
+    /// Check every shard.
+    ///
+    /// # Errors
+    ///
+    /// Returns the error of the first shard which is corrupted.
+    pub fn verify(&self) -> Result<(), DatasetError> {
+        for entry in &self.entries {
+            self.extract(&entry.name, &mut io::sink())?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Seek, SeekFrom, Write};
+
+    use super::{DatasetError, DatasetReader, DatasetWriter};
+
+    #[test]
+    fn pack_verify_and_corrupt() {
+        let directory =
+            std::env::temp_dir().join(format!("takzero-dataset-{}", std::process::id()));
+        std::fs::create_dir_all(&directory).unwrap();
+        let shard = directory.join("targets-00000.txt");
+        let content = "x3/x3/x3 1 1;0.5;0;a1:1\n".repeat(1000);
+        std::fs::write(&shard, &content).unwrap();
+
+        let path = directory.join("dataset.tzd");
+        let mut writer = DatasetWriter::create(&path).unwrap();
+        writer.add_file(&shard).unwrap();
+        writer.add("empty.txt", std::io::empty()).unwrap();
+        assert!(writer.add("with space", std::io::empty()).is_err());
+        assert!(writer.add("../escape.txt", std::io::empty()).is_err());
+        let entries = writer.finish().unwrap();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].name, "targets-00000.txt");
+        assert_eq!(entries[0].bytes, content.len() as u64);
+        assert!(entries[0].compressed < entries[0].bytes);
+
+        let reader = DatasetReader::open(&path).unwrap();
+        assert_eq!(reader.entries(), entries);
+        reader.verify().unwrap();
+        let mut extracted = Vec::new();
+        reader.extract("targets-00000.txt", &mut extracted).unwrap();
+        assert_eq!(extracted, content.as_bytes());
+        assert!(matches!(
+            reader.extract("missing.txt", &mut extracted),
+            Err(DatasetError::MissingShard(_))
+        ));
+
+        // Flip the bits of a byte in the middle of the first shard.
+        let mut file = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(&path)
+            .unwrap();
+        let middle = SeekFrom::Start(entries[0].offset + entries[0].compressed / 2);
+        let mut byte = [0];
+        file.seek(middle).unwrap();
+        file.read_exact(&mut byte).unwrap();
+        file.seek(middle).unwrap();
+        file.write_all(&[!byte[0]]).unwrap();
+        drop(file);
+        assert!(DatasetReader::open(&path).unwrap().verify().is_err());
+
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+}
@@ -0,0 +1,166 @@
+//! Interpretable features of a position, for analytics and adjudication (see
+//! [`Environment::heuristic_value`]).
+
+use fast_tak::{
+    takparse::{Color, Move, MoveKind, Piece, Square},
+    Game,
+    Reserves,
+};
+
+use crate::search::env::{Environment, TerminalReason};
+
+/// Features of a position. Pairs are for white, then black.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Features {
+    /// Flats on top for white minus those for black, with komi.
+    pub flat_diff: f32,
+    /// Empty squares where a flat would complete a road.
+    pub road_threats: [usize; 2],
+    /// Height of the tallest stack.
+    pub max_height: usize,
+    /// Average height of the occupied squares.
+    pub mean_height: f32,
+    /// Spreads of the stacks topped by a capstone.
+    pub cap_mobility: [usize; 2],
+}
+
+impl Features {
+    /// Names of the values of [`Features::to_vec`].
+    pub const NAMES: [&'static str; 7] = [
+        "flat_diff",
+        "white_road_threats",
+        "black_road_threats",
+        "max_height",
+        "mean_height",
+        "white_cap_mobility",
+        "black_cap_mobility",
+    ];
+
+    #[must_use]
+    pub fn new<const N: usize, const HALF_KOMI: i8>(game: &Game<N, HALF_KOMI>) -> Self
+    where
+        Reserves<N>: Default,
+    {
+        let heights: Vec<usize> = game
+            .board
+            .iter()
+            .flatten()
+            .map(|stack| stack.size() as usize)
+            .filter(|height| *height > 0)
+            .collect();
+        Self {
+            flat_diff: f32::from(game.board.flat_diff()) - f32::from(HALF_KOMI) / 2.0,
+            road_threats: [Color::White, Color::Black].map(|color| road_threats(game, color)),
+            max_height: heights.iter().copied().max().unwrap_or_default(),
+            mean_height: heights.iter().sum::<usize>() as f32 / heights.len().max(1) as f32,
+            cap_mobility: [Color::White, Color::Black].map(|color| cap_mobility(game, color)),
+        }
+    }
+
+    /// The features in the order of [`Features::NAMES`].
+    #[must_use]
+    pub fn to_vec(&self) -> Vec<f32> {
+        vec![
+            self.flat_diff,
+            self.road_threats[0] as f32,
+            self.road_threats[1] as f32,
+            self.max_height as f32,
+            self.mean_height,
+            self.cap_mobility[0] as f32,
+            self.cap_mobility[1] as f32,
+        ]
+    }
+}
+
+/// Empty squares where a flat of the color would complete a road.
+fn road_threats<const N: usize, const HALF_KOMI: i8>(
+    game: &Game<N, HALF_KOMI>,
+    color: Color,
+) -> usize
+where
+    Reserves<N>: Default,
+{
+    // Placements in the opening swap are flats of the opponent.
+    if game.opening_swap() || game.terminal().is_some() {
+        return 0;
+    }
+    let mut threats = 0;
+    for (row, stacks) in game.board.iter().enumerate() {
+        for (column, stack) in stacks.enumerate() {
+            if stack.top().is_some() {
+                continue;
+            }
+            let mut after = game.clone();
+            after.to_move = color;
+            let square = Square::new(column as u8, row as u8);
+            // Only the player who placed the flat can have a road after it.
+            let placed = after.play(Move::new(square, MoveKind::Place(Piece::Flat)));
+            if placed.is_ok() && after.terminal_reason() == Some(TerminalReason::Road) {
+                threats += 1;
+            }
+        }
+    }
+    threats
+}
+
+/// Spreads of the stacks of the color which are topped by a capstone.
+fn cap_mobility<const N: usize, const HALF_KOMI: i8>(
+    game: &Game<N, HALF_KOMI>,
+    color: Color,
+) -> usize
+where
+    Reserves<N>: Default,
+{
+    let mut caps = Vec::new();
+    for (row, stacks) in game.board.iter().enumerate() {
+        for (column, stack) in stacks.enumerate() {
+            if stack.top() == Some((Piece::Cap, color)) {
+                caps.push(Square::new(column as u8, row as u8));
+            }
+        }
+    }
+    if caps.is_empty() || game.opening_swap() || game.terminal().is_some() {
+        return 0;
+    }
+    let mut position = game.clone();
+    position.to_move = color;
+    let mut actions = Vec::new();
+    position.populate_actions(&mut actions);
+    actions
+        .iter()
+        .filter(|action| {
+            matches!(action.kind(), MoveKind::Spread(..)) && caps.contains(&action.square())
+        })
+        .count()
+}
+
+#[cfg(test)]
+mod tests {
+    use fast_tak::{
+        takparse::Tps,
+        Game,
+    };
+
+    use super::Features;
+
+    #[test]
+    fn features_of_positions() {
+        let game: Game<5, 4> = "x5/x5/x5/x5/x5 1 1".parse::<Tps>().unwrap().into();
+        let features = Features::new(&game);
+        assert_eq!(features.road_threats, [0, 0]);
+        assert_eq!(features.max_height, 0);
+        assert!((features.flat_diff + 2.0).abs() < 1e-6);
+        assert_eq!(features.to_vec().len(), Features::NAMES.len());
+
+        // White can complete a road at e5 and has a capstone on a stack of 3.
+        let game: Game<5, 0> =
+            "1,1,1,1,x/x5/2,x,2,x2/x,2,x,121C,x/x5 2 6".parse::<Tps>().unwrap().into();
+        let features = Features::new(&game);
+        assert!((features.flat_diff - 1.0).abs() < 1e-6);
+        assert_eq!(features.road_threats, [1, 0]);
+        assert_eq!(features.max_height, 3);
+        assert!((features.mean_height - 10.0 / 8.0).abs() < 1e-6);
+        assert!(features.cap_mobility[0] > 0);
+        assert_eq!(features.cap_mobility[1], 0);
+    }
+}
@@ -0,0 +1,308 @@
+//! Header line of replay and target files.
+//!
+//! Writers put a line like
+//! `#takzero kind=replays version=3 size=6 half_komi=4 generation=1200 created=1700000000`
+//! at the top of new files, and loaders check it before parsing the rest,
+//! so that data written for another board size or by a newer format is
+//! rejected instead of silently misparsed. Files without a header are from
+//! before headers existed and are read as version 0, which has the same line
+//! format as version 1. Version 2 added the komi, generation, game id, and
+//! adjudicated result to replays, and version 3 added their search
+//! statistics. Unknown fields are ignored, so fields can be added without a
+//! new version.
+
+use std::{
+    fmt,
+    fs::{File, OpenOptions},
+    io::{self, BufRead, Write},
+    path::Path,
+    str::FromStr,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use thiserror::Error;
+
+pub const MAGIC: &str = "#takzero";
+/// Format version written by this build. Files with a newer version are
+/// rejected.
+pub const VERSION: u32 = 3;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileKind {
+    Replays,
+    Targets,
+}
+
+impl FileKind {
+    const fn as_str(self) -> &'static str {
+        match self {
+            Self::Replays => "replays",
+            Self::Targets => "targets",
+        }
+    }
+}
+
+impl fmt::Display for FileKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Header {
+    pub kind: FileKind,
+    pub version: u32,
+    pub size: usize,
+    pub half_komi: i8,
+    /// Generation of the network which produced the data, if any.
+    pub generation: Option<usize>,
+    /// Creation time in seconds since the Unix epoch.
+    pub created: u64,
+}
+
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum HeaderError {
+    #[error("missing `{MAGIC}`")]
+    MissingMagic,
+    #[error("missing field `{0}`")]
+    MissingField(&'static str),
+    #[error("invalid field `{0}`")]
+    InvalidField(String),
+    #[error("expected a {expected} file but found a {found} file")]
+    WrongKind { expected: FileKind, found: FileKind },
+    #[error("format version {0} is newer than the supported version {VERSION}")]
+    NewerVersion(u32),
+    #[error("the file is for size {0}")]
+    WrongSize(usize),
+    #[error("the file has a half komi of {0}")]
+    WrongKomi(i8),
+}
+
+impl Header {
+    /// Header for a new file of the current version, created now.
+    #[must_use]
+    pub fn new<const N: usize, const HALF_KOMI: i8>(
+        kind: FileKind,
+        generation: Option<usize>,
+    ) -> Self {
+        Self {
+            kind,
+            version: VERSION,
+            size: N,
+            half_komi: HALF_KOMI,
+            generation,
+            created: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |duration| duration.as_secs()),
+        }
+    }
+
+    /// Check that a file with this header can be read as `kind` for the
+    /// given size and komi.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if anything does not match or the version is newer
+    /// than [`VERSION`].
+    pub fn check<const N: usize, const HALF_KOMI: i8>(
+        &self,
+        kind: FileKind,
+    ) -> Result<(), HeaderError> {
+        self.check_kind(kind)?;
+        if self.size != N {
+            return Err(HeaderError::WrongSize(self.size));
+        }
+        if self.half_komi != HALF_KOMI {
+            return Err(HeaderError::WrongKomi(self.half_komi));
+        }
+        Ok(())
+    }
+
+    /// Check that a file with this header can be read as `kind`, whatever
+    /// its size and komi.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the kind does not match or the version is newer
+    /// than [`VERSION`].
+    pub fn check_kind(&self, kind: FileKind) -> Result<(), HeaderError> {
+        if self.kind != kind {
+            return Err(HeaderError::WrongKind {
+                expected: kind,
+                found: self.kind,
+            });
+        }
+        if self.version > VERSION {
+            return Err(HeaderError::NewerVersion(self.version));
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Header {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{MAGIC} kind={} version={} size={} half_komi={}",
+            self.kind, self.version, self.size, self.half_komi
+        )?;
+        if let Some(generation) = self.generation {
+            write!(f, " generation={generation}")?;
+        }
+        writeln!(f, " created={}", self.created)
+    }
+}
+
+impl FromStr for Header {
+    type Err = HeaderError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut fields = s.split_whitespace();
+        if fields.next() != Some(MAGIC) {
+            return Err(HeaderError::MissingMagic);
+        }
+        let (mut kind, mut version, mut size, mut half_komi, mut generation, mut created) =
+            (None, None, None, None, None, None);
+        for field in fields {
+            let invalid = || HeaderError::InvalidField(field.to_string());
+            let (key, value) = field.split_once('=').ok_or_else(invalid)?;
+            match key {
+                "kind" => {
+                    kind = Some(match value {
+                        "replays" => FileKind::Replays,
+                        "targets" => FileKind::Targets,
+                        _ => return Err(invalid()),
+                    });
+                }
+                "version" => version = Some(value.parse().map_err(|_| invalid())?),
+                "size" => size = Some(value.parse().map_err(|_| invalid())?),
+                "half_komi" => half_komi = Some(value.parse().map_err(|_| invalid())?),
+                "generation" => generation = Some(value.parse().map_err(|_| invalid())?),
+                "created" => created = Some(value.parse().map_err(|_| invalid())?),
+                _ => {}
+            }
+        }
+        Ok(Self {
+            kind: kind.ok_or(HeaderError::MissingField("kind"))?,
+            version: version.ok_or(HeaderError::MissingField("version"))?,
+            size: size.ok_or(HeaderError::MissingField("size"))?,
+            half_komi: half_komi.ok_or(HeaderError::MissingField("half_komi"))?,
+            generation,
+            created: created.unwrap_or_default(),
+        })
+    }
+}
+
+/// Open a file for appending, writing the header first if the file is new
+/// or empty.
+///
+/// # Errors
+///
+/// Returns an error if the file cannot be opened or written.
+pub fn open_append(path: impl AsRef<Path>, header: &Header) -> io::Result<File> {
+    let mut file = OpenOptions::new().append(true).create(true).open(path)?;
+    if file.metadata()?.len() == 0 {
+        write!(file, "{header}")?;
+    }
+    Ok(file)
+}
+
+/// Read the header at the start of a file without checking it, leaving the
+/// reader at the first line of data. Returns `None` for files without a
+/// header.
+///
+/// # Errors
+///
+/// Returns an error with [`io::ErrorKind::InvalidData`] if the header is
+/// malformed, or if reading fails.
+pub fn read_any_header(reader: &mut impl BufRead) -> io::Result<Option<Header>> {
+    if !reader.fill_buf()?.starts_with(MAGIC.as_bytes()) {
+        return Ok(None);
+    }
+    let mut line = String::new();
+    reader.read_line(&mut line)?;
+    line.parse()
+        .map(Some)
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+}
+
+/// Read the header at the start of a file and check it, leaving the reader
+/// at the first line of data. Returns `None` for files without a header.
+///
+/// # Errors
+///
+/// Returns an error with [`io::ErrorKind::InvalidData`] if the header is
+/// malformed or does not fit, or if reading fails.
+pub fn read_header<const N: usize, const HALF_KOMI: i8>(
+    reader: &mut impl BufRead,
+    kind: FileKind,
+) -> io::Result<Option<Header>> {
+    let header = read_any_header(reader)?;
+    if let Some(header) = &header {
+        header
+            .check::<N, HALF_KOMI>(kind)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+    }
+    Ok(header)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{BufRead, Cursor};
+
+    use super::{read_header, FileKind, Header, HeaderError, VERSION};
+
+    #[test]
+    fn round_trip_and_check() {
+        let header = Header {
+            kind: FileKind::Targets,
+            version: VERSION,
+            size: 6,
+            half_komi: 4,
+            generation: Some(1200),
+            created: 1_700_000_000,
+        };
+        let line = header.to_string();
+        assert_eq!(
+            line,
+            format!(
+                "#takzero kind=targets version={VERSION} size=6 half_komi=4 generation=1200 \
+                 created=1700000000\n"
+            )
+        );
+        assert_eq!(line.parse(), Ok(header.clone()));
+        assert_eq!(
+            "#takzero kind=replays version=1 size=6 half_komi=4 extra=1".parse::<Header>(),
+            Ok(Header {
+                kind: FileKind::Replays,
+                version: 1,
+                generation: None,
+                created: 0,
+                ..header
+            })
+        );
+        assert_eq!("x6/x6 1 1".parse::<Header>(), Err(HeaderError::MissingMagic));
+
+        assert_eq!(header.check::<6, 4>(FileKind::Targets), Ok(()));
+        assert_eq!(header.check::<5, 4>(FileKind::Targets), Err(HeaderError::WrongSize(6)));
+        assert_eq!(header.check::<6, 0>(FileKind::Targets), Err(HeaderError::WrongKomi(4)));
+        assert!(header.check::<6, 4>(FileKind::Replays).is_err());
+        let newer = Header {
+            version: VERSION + 1,
+            ..header
+        };
+        assert_eq!(
+            newer.check::<6, 4>(FileKind::Targets),
+            Err(HeaderError::NewerVersion(VERSION + 1))
+        );
+
+        let mut reader = Cursor::new(format!("{header}data\n"));
+        assert_eq!(read_header::<6, 4>(&mut reader, FileKind::Targets).unwrap(), Some(header));
+        assert_eq!(reader.lines().next().unwrap().unwrap(), "data");
+        let mut reader = Cursor::new("data\n");
+        assert_eq!(read_header::<6, 4>(&mut reader, FileKind::Targets).unwrap(), None);
+        assert_eq!(reader.lines().next().unwrap().unwrap(), "data");
+        let mut reader = Cursor::new(newer.to_string());
+        assert!(read_header::<6, 4>(&mut reader, FileKind::Targets).is_err());
+    }
+}
@@ -0,0 +1,287 @@
+//! Importing data from other Tak software.
+//!
+//! - Games from the `PlayTak` database, where the moves are written in server
+//!   notation (`P A1 C`, `M A1 A3 1 2`) separated by commas, komi is given in
+//!   half flats, and results are written like in PTN (`R-0`, `0-F`, `1-0`).
+//! - Positions analysed by Taktician, one per line with the TPS, the best move
+//!   in PTN, and the score from the perspective of the player to move,
+//!   separated by tabs. Taktician has no notion of our value range, so scores
+//!   are squashed into values between -1 and 1.
+//!
+//! Both become [`Target`]s with a one-hot policy, so they can be mixed into
+//! the supervised pretraining corpus next to games imported from PTN.
+
+use std::{cmp::Ordering, num::ParseIntError};
+
+use fast_tak::{
+    takparse::{Move, ParseMoveError, ParseTpsError, Square, Tps},
+    Game,
+    PlayError,
+    Reserves,
+};
+use thiserror::Error;
+
+use crate::{
+    ptn::{one_hot, Outcome, PtnGame},
+    search::env::{Environment, Terminal},
+    target::{Replay, Target},
+};
+
+/// Taktician scores beyond this are forced wins (its `WinThreshold`).
+pub const TAKTICIAN_WIN_THRESHOLD: i64 = 1 << 29;
+
+#[derive(Error, Debug)]
+pub enum ParsePlayTakMoveError {
+    #[error("move `{0}` is missing a field")]
+    MissingField(String),
+    #[error("invalid square `{0}`")]
+    Square(String),
+    #[error("invalid drop count: {0}")]
+    DropCount(#[from] ParseIntError),
+    #[error("{0}")]
+    Move(#[from] ParseMoveError),
+}
+
+#[derive(Error, Debug)]
+pub enum ImportError {
+    #[error("could not parse move: {0}")]
+    PlayTakMove(#[from] ParsePlayTakMoveError),
+    #[error("could not parse move: {0}")]
+    Move(#[from] ParseMoveError),
+    #[error("{0}")]
+    Tps(#[from] ParseTpsError),
+    #[error("the game is for size {0}")]
+    WrongSize(usize),
+    #[error("the game has a half komi of {0}")]
+    WrongKomi(i8),
+    #[error("the game uses {0} stones and {1} capstones")]
+    WrongReserves(u8, u8),
+    #[error("invalid action")]
+    Invalid(#[from] PlayError),
+    #[error("line `{0}` is missing a field")]
+    MissingField(String),
+    #[error("invalid score `{0}`")]
+    InvalidScore(String),
+}
+
+fn parse_square(s: &str) -> Result<Square, ParsePlayTakMoveError> {
+    let invalid = || ParsePlayTakMoveError::Square(s.to_string());
+    let mut chars = s.chars();
+    let column = chars.next().ok_or_else(invalid)?.to_ascii_lowercase();
+    let row = chars.next().and_then(|c| c.to_digit(10)).ok_or_else(invalid)?;
+    if !column.is_ascii_lowercase() || row == 0 || chars.next().is_some() {
+        return Err(invalid());
+    }
+    Ok(Square::new(column as u8 - b'a', row as u8 - 1))
+}
+
+/// Parse a move in `PlayTak` server notation like `P A1 C` or `M A1 A3 1 2`.
+///
+/// # Errors
+///
+/// Returns an error if the move is malformed.
+pub fn parse_playtak_move(s: &str) -> Result<Move, ParsePlayTakMoveError> {
+    let missing = || ParsePlayTakMoveError::MissingField(s.to_string());
+    let mut words = s.split_whitespace();
+    let kind = words.next().ok_or_else(missing)?;
+    let from = parse_square(words.next().ok_or_else(missing)?)?;
+    // Build PTN and let takparse do the validation.
+    let ptn = if kind == "P" {
+        let piece = match words.next() {
+            Some("W") => "S",
+            Some("C") => "C",
+            _ => "",
+        };
+        format!("{piece}{from}")
+    } else {
+        let to = parse_square(words.next().ok_or_else(missing)?)?;
+        let direction = match (to.column().cmp(&from.column()), to.row().cmp(&from.row())) {
+            (Ordering::Greater, _) => '>',
+            (Ordering::Less, _) => '<',
+            (_, Ordering::Greater) => '+',
+            _ => '-',
+        };
+        let drops: Vec<u32> = words.map(str::parse).collect::<Result<_, _>>()?;
+        let count: u32 = drops.iter().sum();
+        // Write canonical PTN, leaving out the defaults.
+        let count = if count > 1 {
+            count.to_string()
+        } else {
+            String::new()
+        };
+        let drops = if drops.len() > 1 {
+            drops.iter().map(ToString::to_string).collect()
+        } else {
+            String::new()
+        };
+        format!("{count}{from}{direction}{drops}")
+    };
+    Ok(ptn.parse()?)
+}
+
+/// A game as stored in the `PlayTak` database.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PlayTakGame {
+    pub size: usize,
+    /// Komi in half flats, as `PlayTak` stores it.
+    pub half_komi: i8,
+    /// Stones and capstones per player, or `None` for the defaults.
+    pub reserves: Option<(u8, u8)>,
+    /// Moves in server notation, separated by commas.
+    pub notation: String,
+    pub result: String,
+}
+
+impl PlayTakGame {
+    /// Replay the game. The outcome is taken from the final position if it is
+    /// terminal and from the result otherwise (resignation, time, or
+    /// abandonment).
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the game has a different size, komi, or number of
+    /// pieces, or if it contains an invalid move.
+    pub fn to_ptn_game<const N: usize, const HALF_KOMI: i8>(
+        &self,
+    ) -> Result<PtnGame<N, HALF_KOMI>, ImportError>
+    where
+        Reserves<N>: Default,
+    {
+        if self.size != N {
+            return Err(ImportError::WrongSize(self.size));
+        }
+        if self.half_komi != HALF_KOMI {
+            return Err(ImportError::WrongKomi(self.half_komi));
+        }
+        if let Some((stones, caps)) = self.reserves {
+            let Reserves {
+                stones: default_stones,
+                caps: default_caps,
+            } = Reserves::<N>::default();
+            if (stones, caps) != (default_stones, default_caps) {
+                return Err(ImportError::WrongReserves(stones, caps));
+            }
+        }
+
+        let mut replay = Replay::new(Game::default());
+        let mut end = Game::<N, HALF_KOMI>::default();
+        for notation in self.notation.split(',').filter(|s| !s.trim().is_empty()) {
+            let action = parse_playtak_move(notation)?;
+            end.play(action)?;
+            replay.push(action);
+        }
+        let outcome = match end.terminal() {
+            Some(terminal) => Some(Outcome::from_terminal(terminal, end.to_move)),
+            None => Outcome::from_result(self.result.trim()),
+        };
+        Ok(PtnGame { replay, outcome })
+    }
+}
+
+/// Squash a Taktician score into a value between -1 and 1. Forced wins and
+/// losses become exactly 1 and -1.
+#[must_use]
+pub fn taktician_value(score: i64, scale: f32) -> f32 {
+    if score >= TAKTICIAN_WIN_THRESHOLD {
+        f32::from(Terminal::Win)
+    } else if score <= -TAKTICIAN_WIN_THRESHOLD {
+        f32::from(Terminal::Loss)
+    } else {
+        (score as f32 / scale).tanh()
+    }
+}
+
+/// Turn a line of a Taktician analysis dump (`TPS<TAB>move<TAB>score`) into a
+/// target with the policy one-hot on the best move.
+///
+/// Taktician does not know about komi, so the analysis should have been done
+/// with the same rules.
+///
+/// # Errors
+///
+/// Returns an error if the line is malformed, the position has a different
+/// size, or the move is not legal.
+pub fn taktician_target<const N: usize, const HALF_KOMI: i8>(
+    line: &str,
+    scale: f32,
+) -> Result<Target<Game<N, HALF_KOMI>>, ImportError>
+where
+    Reserves<N>: Default,
+{
+    let missing = || ImportError::MissingField(line.to_string());
+    let mut fields = line.trim().split('\t');
+    let tps: Tps = fields.next().ok_or_else(missing)?.trim().parse()?;
+    let best: Move = fields.next().ok_or_else(missing)?.trim().parse()?;
+    let score = fields.next().ok_or_else(missing)?.trim();
+    let score: i64 = score
+        .parse()
+        .map_err(|_| ImportError::InvalidScore(score.to_string()))?;
+    if tps.size() != N {
+        return Err(ImportError::WrongSize(tps.size()));
+    }
+
+    let env: Game<N, HALF_KOMI> = tps.into();
+    env.clone().play(best)?;
+    Ok(Target {
+        policy: one_hot(&env, &best, &mut Vec::new()),
+        env,
+        value: taktician_value(score, scale),
+        ube: 0.0,
+        weight: None,
+        move_limit: None,
+        model_steps: None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use fast_tak::takparse::Move;
+
+    use super::{parse_playtak_move, taktician_target, taktician_value, PlayTakGame};
+    use crate::ptn::Outcome;
+
+    #[test]
+    fn playtak_game() {
+        for (playtak, ptn) in [
+            ("P A1", "a1"),
+            ("P C3 W", "Sc3"),
+            ("M C3 A3 2 1", "3c3<21"),
+            ("M A1 B1 3", "3a1>"),
+        ] {
+            assert_eq!(parse_playtak_move(playtak).unwrap(), ptn.parse::<Move>().unwrap());
+        }
+
+        let mut game = PlayTakGame {
+            size: 3,
+            half_komi: 0,
+            reserves: None,
+            notation: "P C3,P A1,P B1,P B3".to_string(),
+            result: "0-R".to_string(),
+        };
+        let imported = game.to_ptn_game::<3, 0>().unwrap();
+        assert_eq!(imported.replay.len(), 4);
+        assert_eq!(imported.outcome, Some(Outcome::BlackWin));
+        assert!(game.to_ptn_game::<3, 4>().is_err());
+
+        // The road is on the board, whatever the result says.
+        game.notation.push_str(",P C1");
+        let imported = game.to_ptn_game::<3, 0>().unwrap();
+        assert_eq!(imported.outcome, Some(Outcome::WhiteWin));
+
+        game.reserves = Some((9, 1));
+        assert!(game.to_ptn_game::<3, 0>().is_err());
+    }
+
+    #[test]
+    fn taktician_line() {
+        let target = taktician_target::<3, 0>("x3/x3/x3 1 1\ta1\t-250", 1000.0).unwrap();
+        assert!((target.value - (-0.25_f32).tanh()).abs() < 1e-6);
+        let total: f32 = target.policy.iter().map(|(_, p)| p.into_inner()).sum();
+        assert!((total - 1.0).abs() < f32::EPSILON);
+
+        assert!((taktician_value(1 << 30, 1000.0) - 1.0).abs() < f32::EPSILON);
+        assert!(taktician_target::<3, 0>("x3/x3/x3 1 1\tCa1\t0", 1000.0).is_err());
+        assert!(taktician_target::<4, 0>("x3/x3/x3 1 1\ta1\t0", 1000.0).is_err());
+        assert!(taktician_target::<3, 0>("x3/x3/x3 1 1\ta1", 1000.0).is_err());
+    }
+}
@@ -1,3 +1,35 @@
+pub mod affinity;
+pub mod any;
+#[cfg(feature = "archive")]
+pub mod archive;
+pub mod audit;
+pub mod batch_size;
+pub mod checksum;
+pub mod codec;
+pub mod config;
+pub mod control;
+pub mod curriculum;
+pub mod dashboard;
+pub mod dataset;
+pub mod features;
+pub mod header;
+pub mod import;
+pub mod logging;
+pub mod metrics;
 pub mod network;
+pub mod opening;
+pub mod phase;
+pub mod positions;
+pub mod ptn;
+pub mod quality;
+pub mod reader;
 pub mod search;
+pub mod seen;
+pub mod shards;
+pub mod spectator;
+pub mod storage;
 pub mod target;
+pub mod time_manager;
+pub mod variant;
+pub mod winrate;
+pub mod zobrist;
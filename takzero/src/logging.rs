@@ -0,0 +1,307 @@
+//! Structured logging.
+//!
+//! Every log line is tagged with the run context of the thread that emitted
+//! it (worker, network generation, game, and ply), so that a single worker
+//! or game can be found in weeks of logs. Set `TAKZERO_LOG_FORMAT=json` to
+//! get one JSON object per line instead of plain text. The log level is
+//! still configured with `RUST_LOG`. The `takzero` command takes both as
+//! `--log-format` and `--log-filter` instead, see [`init_with`], and the
+//! filter can be replaced while running with [`set_filter`].
+
+use std::{
+    cell::RefCell,
+    fmt::{self, Write as _},
+    io::Write,
+    str::FromStr,
+    sync::{OnceLock, RwLock},
+};
+
+use thiserror::Error;
+
+thread_local! {
+    static CONTEXT: RefCell<RunContext> = RefCell::new(RunContext::default());
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct RunContext {
+    pub worker: Option<String>,
+    pub generation: Option<usize>,
+    pub game: Option<u64>,
+    pub ply: Option<u16>,
+}
+
+impl fmt::Display for RunContext {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(worker) = &self.worker {
+            write!(f, " worker={worker}")?;
+        }
+        if let Some(generation) = self.generation {
+            write!(f, " generation={generation}")?;
+        }
+        if let Some(game) = self.game {
+            write!(f, " game={game}")?;
+        }
+        if let Some(ply) = self.ply {
+            write!(f, " ply={ply}")?;
+        }
+        Ok(())
+    }
+}
+
+impl RunContext {
+    fn to_json(&self, time: &str, level: log::Level, target: &str, message: &str) -> String {
+        let mut json = format!(
+            "{{\"time\":\"{time}\",\"level\":\"{level}\",\"target\":{}",
+            json_string(target)
+        );
+        if let Some(worker) = &self.worker {
+            let _ = write!(json, ",\"worker\":{}", json_string(worker));
+        }
+        if let Some(generation) = self.generation {
+            let _ = write!(json, ",\"generation\":{generation}");
+        }
+        if let Some(game) = self.game {
+            let _ = write!(json, ",\"game\":{game}");
+        }
+        if let Some(ply) = self.ply {
+            let _ = write!(json, ",\"ply\":{ply}");
+        }
+        let _ = write!(json, ",\"message\":{}}}", json_string(message));
+        json
+    }
+}
+
+/// Quote and escape a string for use in JSON.
+fn json_string(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len() + 2);
+    escaped.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\t' => escaped.push_str("\\t"),
+            c if c.is_control() => {
+                let _ = write!(escaped, "\\u{:04x}", u32::from(c));
+            }
+            c => escaped.push(c),
+        }
+    }
+    escaped.push('"');
+    escaped
+}
+
+/// How log lines are written.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Format {
+    #[default]
+    Text,
+    Json,
+}
+
+#[derive(Debug, Error)]
+#[error("unknown log format `{0}`, expected `text` or `json`")]
+pub struct ParseFormatError(String);
+
+impl FromStr for Format {
+    type Err = ParseFormatError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(Self::Text),
+            "json" => Ok(Self::Json),
+            _ => Err(ParseFormatError(s.to_string())),
+        }
+    }
+}
+
+/// The global logger, whose filter can be replaced while it runs.
+struct Reloadable {
+    format: Format,
+    logger: RwLock<env_logger::Logger>,
+}
+
+static LOGGER: OnceLock<Reloadable> = OnceLock::new();
+
+impl log::Log for Reloadable {
+    fn enabled(&self, metadata: &log::Metadata<'_>) -> bool {
+        self.logger().enabled(metadata)
+    }
+
+    fn log(&self, record: &log::Record<'_>) {
+        self.logger().log(record);
+    }
+
+    fn flush(&self) {
+        self.logger().flush();
+    }
+}
+
+impl Reloadable {
+    fn logger(&self) -> std::sync::RwLockReadGuard<'_, env_logger::Logger> {
+        self.logger.read().expect("logger lock should not be poisoned")
+    }
+}
+
+/// Initialize the global logger. Use this instead of `env_logger::init()`.
+pub fn init() {
+    init_with(None, None);
+}
+
+/// Initialize the global logger with the given format instead of
+/// `TAKZERO_LOG_FORMAT`, and the given filter (like `info,takzero=debug`)
+/// instead of `RUST_LOG`.
+///
+/// # Panics
+///
+/// Panics if the global logger was already initialized.
+pub fn init_with(format: Option<Format>, filter: Option<&str>) {
+    let format = format.unwrap_or_else(|| {
+        std::env::var("TAKZERO_LOG_FORMAT")
+            .ok()
+            .and_then(|format| format.parse().ok())
+            .unwrap_or_default()
+    });
+    let logger = build(format, filter);
+    let max_level = logger.filter();
+    let reloadable = LOGGER.get_or_init(|| Reloadable {
+        format,
+        logger: RwLock::new(logger),
+    });
+    log::set_logger(reloadable).expect("the logger should only be initialized once");
+    log::set_max_level(max_level);
+}
+
+/// Replace the filter of the global logger, like `info,takzero=debug`, so
+/// that a long run can log more or less without a restart. Does nothing if
+/// the global logger was not initialized.
+///
+/// # Panics
+///
+/// Panics if another thread panicked while replacing the filter.
+pub fn set_filter(filter: &str) {
+    let Some(reloadable) = LOGGER.get() else {
+        return;
+    };
+    let logger = build(reloadable.format, Some(filter));
+    let max_level = logger.filter();
+    *reloadable
+        .logger
+        .write()
+        .expect("logger lock should not be poisoned") = logger;
+    log::set_max_level(max_level);
+}
+
+fn build(format: Format, filter: Option<&str>) -> env_logger::Logger {
+    let json = format == Format::Json;
+    let mut builder = env_logger::Builder::from_default_env();
+    if let Some(filter) = filter {
+        builder.parse_filters(filter);
+    }
+    builder
+        .format(move |buf, record| {
+            let time = buf.timestamp().to_string();
+            let message = record.args().to_string();
+            CONTEXT.with_borrow(|context| {
+                if json {
+                    writeln!(
+                        buf,
+                        "{}",
+                        context.to_json(&time, record.level(), record.target(), &message)
+                    )
+                } else {
+                    writeln!(
+                        buf,
+                        "[{time} {} {}{context}] {message}",
+                        record.level(),
+                        record.target()
+                    )
+                }
+            })
+        })
+        .build()
+}
+
+/// Get a copy of the run context of this thread.
+#[must_use]
+pub fn context() -> RunContext {
+    CONTEXT.with_borrow(Clone::clone)
+}
+
+/// Replace the run context of this thread.
+pub fn set_context(context: RunContext) {
+    CONTEXT.set(context);
+}
+
+pub fn set_worker(worker: impl Into<String>) {
+    CONTEXT.with_borrow_mut(|context| context.worker = Some(worker.into()));
+}
+
+/// Name the worker of this thread, or call it `{role}-{process id}` if it
+/// has no name.
+pub fn set_worker_or_default(worker: Option<&str>, role: &str) {
+    match worker {
+        Some(worker) => set_worker(worker),
+        None => set_worker(format!("{role}-{}", std::process::id())),
+    }
+}
+
+pub fn set_generation(generation: usize) {
+    CONTEXT.with_borrow_mut(|context| context.generation = Some(generation));
+}
+
+/// Run a closure with the game and ply set in the run context.
+/// The previous game and ply are restored afterwards.
+pub fn with_game<T>(game: u64, ply: u16, f: impl FnOnce() -> T) -> T {
+    let previous =
+        CONTEXT.with_borrow_mut(|context| (context.game.replace(game), context.ply.replace(ply)));
+    let output = f();
+    CONTEXT.with_borrow_mut(|context| (context.game, context.ply) = previous);
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{json_string, with_game, RunContext};
+
+    #[test]
+    fn json_escaping() {
+        assert_eq!(json_string("plain"), "\"plain\"");
+        assert_eq!(
+            json_string("a \"quote\"\\\nnew line\u{1}"),
+            "\"a \\\"quote\\\"\\\\\\nnew line\\u0001\""
+        );
+    }
+
+    #[test]
+    fn context_formats() {
+        let context = RunContext {
+            worker: Some("selfplay-3".to_string()),
+            generation: Some(1200),
+            game: Some(17),
+            ply: None,
+        };
+        assert_eq!(
+            context.to_string(),
+            " worker=selfplay-3 generation=1200 game=17"
+        );
+        assert_eq!(
+            context.to_json("now", log::Level::Info, "selfplay", "hello"),
+            "{\"time\":\"now\",\"level\":\"INFO\",\"target\":\"selfplay\",\"worker\":\"selfplay-3\"\
+             ,\"generation\":1200,\"game\":17,\"message\":\"hello\"}"
+        );
+    }
+
+    #[test]
+    fn with_game_restores() {
+        super::set_worker("1");
+        let inner = with_game(5, 10, super::context);
+        assert_eq!(inner.game, Some(5));
+        assert_eq!(inner.ply, Some(10));
+        let outer = super::context();
+        assert_eq!(outer.game, None);
+        assert_eq!(outer.ply, None);
+        assert_eq!(outer.worker.as_deref(), Some("1"));
+    }
+}
@@ -0,0 +1,168 @@
+//! Prometheus metrics.
+//!
+//! Metrics are recorded into the global [`REGISTRY`] and can be exposed
+//! over HTTP with [`serve`], which answers `GET /metrics` with the
+//! [text exposition format](https://prometheus.io/docs/instrumenting/exposition_formats/).
+//! Recording is cheap enough to leave on even when nothing is serving.
+
+use std::{
+    collections::BTreeMap,
+    fmt::Write as _,
+    io::{BufRead, BufReader, Write},
+    net::{TcpListener, TcpStream, ToSocketAddrs},
+    sync::Mutex,
+    thread::JoinHandle,
+};
+
+pub static REGISTRY: Registry = Registry::new();
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Kind {
+    Counter,
+    Gauge,
+}
+
+impl Kind {
+    const fn as_str(self) -> &'static str {
+        match self {
+            Self::Counter => "counter",
+            Self::Gauge => "gauge",
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Metric {
+    help: &'static str,
+    kind: Kind,
+    value: f64,
+}
+
+#[derive(Debug, Default)]
+pub struct Registry {
+    metrics: Mutex<BTreeMap<&'static str, Metric>>,
+}
+
+impl Registry {
+    #[must_use]
+    pub const fn new() -> Self {
+        Self {
+            metrics: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    fn update(
+        &self,
+        name: &'static str,
+        help: &'static str,
+        kind: Kind,
+        f: impl FnOnce(&mut f64),
+    ) {
+        let mut metrics = self.metrics.lock().expect("metrics lock should not be poisoned");
+        let metric = metrics.entry(name).or_insert(Metric {
+            help,
+            kind,
+            value: 0.0,
+        });
+        debug_assert_eq!(metric.kind, kind, "metric {name} used with two kinds");
+        f(&mut metric.value);
+        drop(metrics);
+    }
+
+    /// Set a gauge to a value.
+    pub fn set_gauge(&self, name: &'static str, help: &'static str, value: f64) {
+        self.update(name, help, Kind::Gauge, |x| *x = value);
+    }
+
+    /// Increase a counter by some amount.
+    pub fn inc_counter(&self, name: &'static str, help: &'static str, by: f64) {
+        debug_assert!(by >= 0.0, "counters can only go up");
+        self.update(name, help, Kind::Counter, |x| *x += by);
+    }
+
+    /// Render all metrics in the Prometheus text format.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the lock is poisoned.
+    #[must_use]
+    pub fn render(&self) -> String {
+        let metrics = self.metrics.lock().expect("metrics lock should not be poisoned");
+        metrics
+            .iter()
+            .fold(String::new(), |mut s, (name, metric)| {
+                let _ = writeln!(s, "# HELP {name} {}", metric.help);
+                let _ = writeln!(s, "# TYPE {name} {}", metric.kind.as_str());
+                let _ = writeln!(s, "{name} {}", metric.value);
+                s
+            })
+    }
+}
+
+/// Serve the global registry at `GET /metrics` on a background thread.
+///
+/// # Errors
+///
+/// Returns an error if the address cannot be bound.
+pub fn serve(address: impl ToSocketAddrs) -> std::io::Result<JoinHandle<()>> {
+    let listener = TcpListener::bind(address)?;
+    log::info!("Serving metrics on {}", listener.local_addr()?);
+    Ok(std::thread::spawn(move || {
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    if let Err(err) = respond(stream) {
+                        log::warn!("Could not respond to metrics request: {err}");
+                    }
+                }
+                Err(err) => log::warn!("Could not accept metrics connection: {err}"),
+            }
+        }
+    }))
+}
+
+/// Read the request line of an HTTP request, skipping the headers.
+pub(crate) fn read_request_line(stream: &TcpStream) -> std::io::Result<String> {
+    let mut reader = BufReader::new(stream);
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    // Skip the headers.
+    let mut header = String::new();
+    while reader.read_line(&mut header)? > 2 {
+        header.clear();
+    }
+    Ok(request_line)
+}
+
+fn respond(mut stream: TcpStream) -> std::io::Result<()> {
+    let request_line = read_request_line(&stream)?;
+    let (status, body) = match request_line.split_whitespace().take(2).collect::<Vec<_>>()[..] {
+        ["GET", "/metrics"] => ("200 OK", REGISTRY.render()),
+        _ => ("404 Not Found", String::new()),
+    };
+    write!(
+        stream,
+        "HTTP/1.1 {status}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: \
+         {}\r\nConnection: close\r\n\r\n{body}",
+        body.len()
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Registry;
+
+    #[test]
+    fn render_format() {
+        let registry = Registry::new();
+        registry.inc_counter("games_total", "Finished games.", 2.0);
+        registry.inc_counter("games_total", "Finished games.", 3.0);
+        registry.set_gauge("buffer_size", "Targets in the buffer.", 10.0);
+        registry.set_gauge("buffer_size", "Targets in the buffer.", 7.5);
+        assert_eq!(
+            registry.render(),
+            "# HELP buffer_size Targets in the buffer.\n# TYPE buffer_size gauge\nbuffer_size \
+             7.5\n# HELP games_total Finished games.\n# TYPE games_total counter\ngames_total 5\n"
+        );
+    }
+}
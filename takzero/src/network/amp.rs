@@ -0,0 +1,396 @@
+//! Mixed precision training.
+//!
+//! [`MixedPrecision`] keeps a copy of a network in half precision (`fp16`)
+//! or `bfloat16`, which computes the forward and the backward pass, while
+//! the optimizer steps the weights of the original network, which stay in
+//! single precision. The copy is refreshed from them after every step.
+//!
+//! The range of `fp16` is small, so the loss is multiplied by a scale before
+//! the backward pass, which keeps small gradients from flushing to zero, and
+//! the gradients are divided by it again before the step. [`LossScaler`]
+//! adapts the scale: when a gradient overflows, the step is skipped and the
+//! scale halves, and after a long run of finite gradients it doubles.
+//! `bfloat16` has the range of single precision, so its loss is not scaled,
+//! but steps with gradients which are not finite are skipped all the same.
+//!
+//! Batch normalization layers, which the networks name `batch_norm`, stay
+//! in single precision in the copy, like the variables which are not trained
+//! (for example the simhash projection). Their statistics are updated by the
+//! copy during the forward pass and copied back into the network exactly
+//! after every step. State besides variables, like the hash counts, is only
+//! kept up to date in the network, so the copy is only for training.
+
+use std::{fmt, str::FromStr};
+
+use tch::{nn::Optimizer, Kind, Tensor};
+use thiserror::Error;
+
+use super::Network;
+
+/// Scale of the loss at the start of training in `fp16`.
+const INITIAL_SCALE: f64 = 65536.0;
+/// Consecutive steps with finite gradients after which the scale doubles.
+const GROWTH_INTERVAL: u32 = 2000;
+
+/// The precision of the forward and backward pass.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Precision {
+    #[default]
+    Fp32,
+    Fp16,
+    Bf16,
+}
+
+#[derive(Debug, Error, PartialEq, Eq)]
+#[error("unknown precision `{0}`, expected `fp32`, `fp16`, or `bf16`")]
+pub struct ParsePrecisionError(String);
+
+impl FromStr for Precision {
+    type Err = ParsePrecisionError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "fp32" => Ok(Self::Fp32),
+            "fp16" => Ok(Self::Fp16),
+            "bf16" => Ok(Self::Bf16),
+            _ => Err(ParsePrecisionError(s.to_string())),
+        }
+    }
+}
+
+impl fmt::Display for Precision {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Fp32 => "fp32",
+            Self::Fp16 => "fp16",
+            Self::Bf16 => "bf16",
+        })
+    }
+}
+
+/// Dynamic loss scaling.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LossScaler {
+    scale: f64,
+    /// Whether the scale adapts to overflows, or stays fixed.
+    dynamic: bool,
+    finite_steps: u32,
+}
+
+impl Default for LossScaler {
+    fn default() -> Self {
+        Self {
+            scale: INITIAL_SCALE,
+            dynamic: true,
+            finite_steps: 0,
+        }
+    }
+}
+
+impl LossScaler {
+    /// A scaler which never changes the scale.
+    #[must_use]
+    pub const fn fixed(scale: f64) -> Self {
+        Self {
+            scale,
+            dynamic: false,
+            finite_steps: 0,
+        }
+    }
+
+    #[must_use]
+    pub const fn scale(&self) -> f64 {
+        self.scale
+    }
+
+    /// Record whether the gradients of a step were finite, and adapt the
+    /// scale. Returns whether the step should be taken.
+    pub fn update(&mut self, finite: bool) -> bool {
+        if !finite {
+            self.finite_steps = 0;
+            if self.dynamic {
+                self.scale /= 2.0;
+            }
+            return false;
+        }
+        self.finite_steps += 1;
+        if self.dynamic && self.finite_steps >= GROWTH_INTERVAL {
+            self.finite_steps = 0;
+            self.scale *= 2.0;
+        }
+        true
+    }
+}
+
+/// A network trained in reduced precision, see the module documentation.
+#[derive(Debug)]
+pub struct MixedPrecision<NET> {
+    /// The copy of the network in reduced precision.
+    copy: NET,
+    kind: Kind,
+    scaler: LossScaler,
+    /// Trainable variables of the network and of the copy.
+    trainable: Vec<(Tensor, Tensor)>,
+    /// Variables which are not trained, like batch normalization statistics.
+    buffers: Vec<(Tensor, Tensor)>,
+}
+
+impl<NET: Network> MixedPrecision<NET> {
+    /// Make a copy of the network in the precision, or return `None` for
+    /// single precision. The copy is made with [`Network::clone`], so
+    /// anything the network keeps besides its variables is duplicated.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the copy does not have the variables of the network.
+    #[must_use]
+    pub fn new(net: &NET, precision: Precision) -> Option<Self> {
+        let (kind, scaler) = match precision {
+            Precision::Fp32 => return None,
+            Precision::Fp16 => (Kind::Half, LossScaler::default()),
+            Precision::Bf16 => (Kind::BFloat16, LossScaler::fixed(1.0)),
+        };
+        let copy = net.clone(net.vs().device());
+
+        let mut variables = net.vs().variables();
+        let mut trainable = Vec::new();
+        let mut buffers = Vec::new();
+        for (name, mut copied) in copy.vs().variables() {
+            let variable = variables
+                .remove(&name)
+                .expect("variables in both VarStores should have identical names");
+            if !variable.requires_grad() {
+                buffers.push((variable, copied));
+                continue;
+            }
+            if !name.contains("batch_norm") {
+                copied.set_data(&copied.to_kind(kind));
+            }
+            trainable.push((variable, copied));
+        }
+        Some(Self {
+            copy,
+            kind,
+            scaler,
+            trainable,
+            buffers,
+        })
+    }
+
+    /// The copy of the network, which computes the forward pass on inputs
+    /// converted with [`MixedPrecision::input`].
+    #[must_use]
+    pub const fn network(&self) -> &NET {
+        &self.copy
+    }
+
+    /// Convert an input to the precision of the copy.
+    #[must_use]
+    pub fn input(&self, xs: &Tensor) -> Tensor {
+        xs.to_kind(self.kind)
+    }
+
+    #[must_use]
+    pub const fn scaler(&self) -> &LossScaler {
+        &self.scaler
+    }
+
+    /// Compute the gradients of a loss of the copy, and step the network
+    /// with them unless they are not finite. Returns whether the step was
+    /// taken.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the gradients cannot be read.
+    pub fn backward_step(&mut self, opt: &mut Optimizer, loss: &Tensor) -> bool {
+        self.backward_step_with(opt, loss, |_, loss| loss.backward())
+    }
+
+    /// Like [`MixedPrecision::backward_step`], but the backward pass of the
+    /// scaled loss is left to `backward`, which gets the copy, for example to
+    /// finish it with [`super::residual::Checkpoints::backward`].
+    ///
+    /// # Panics
+    ///
+    /// Panics if the gradients cannot be read.
+    pub fn backward_step_with(
+        &mut self,
+        opt: &mut Optimizer,
+        loss: &Tensor,
+        backward: impl FnOnce(&NET, &Tensor),
+    ) -> bool {
+        let scale = self.scaler.scale();
+        for (_, copied) in &mut self.trainable {
+            copied.zero_grad();
+        }
+        backward(&self.copy, &(loss * scale));
+
+        // A sum is finite only if every gradient is.
+        let sums: Vec<_> = self
+            .trainable
+            .iter()
+            .map(|(_, copied)| copied.grad())
+            .filter(Tensor::defined)
+            .map(|gradient| gradient.sum(Kind::Float))
+            .collect();
+        let finite = sums.is_empty()
+            || f64::try_from(Tensor::stack(&sums, 0).sum(Kind::Float)).unwrap().is_finite();
+
+        let step = self.scaler.update(finite);
+        if step {
+            define_gradients(&self.trainable);
+            // Variables which the loss does not depend on get a gradient of
+            // zero.
+            tch::no_grad(|| {
+                for (variable, copied) in &self.trainable {
+                    let mut gradient = variable.grad();
+                    let copied = copied.grad();
+                    if copied.defined() {
+                        gradient.copy_(&copied);
+                        gradient /= scale;
+                    } else {
+                        let _ = gradient.zero_();
+                    }
+                }
+            });
+            opt.step();
+            for (variable, copied) in &mut self.trainable {
+                tch::no_grad(|| copied.copy_(variable));
+            }
+        } else {
+            log::warn!(
+                "Skipped a step with gradients which are not finite, loss scale is now {}.",
+                self.scaler.scale()
+            );
+        }
+        for (variable, copied) in &mut self.buffers {
+            tch::no_grad(|| variable.copy_(copied));
+        }
+        step
+    }
+}
+
+/// Give the variables of the network gradients for the gradients of the
+/// copy to be copied into. A variable only gets one in a backward pass, so
+/// this runs one through nothing but the variables which have none, which is
+/// only needed before the first step, or after the optimizer cleared them.
+fn define_gradients(trainable: &[(Tensor, Tensor)]) {
+    let zeros: Vec<_> = trainable
+        .iter()
+        .filter(|(variable, _)| !variable.grad().defined())
+        .map(|(variable, _)| variable.sum(Kind::Float) * 0.0)
+        .collect();
+    if !zeros.is_empty() {
+        Tensor::stack(&zeros, 0).sum(Kind::Float).backward();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tch::{
+        nn::{Adam, OptimizerConfig},
+        Device,
+        Kind,
+        Tensor,
+    };
+
+    use super::{LossScaler, MixedPrecision, Precision, GROWTH_INTERVAL, INITIAL_SCALE};
+    use crate::{
+        network::{connect4::Net, net6_simhash, repr::input_channels, HashNetwork, Network},
+        search::env::connect4::INPUT_SIZE,
+    };
+
+    #[test]
+    #[allow(clippy::float_cmp)]
+    fn scale_adapts() {
+        let mut scaler = LossScaler::default();
+        assert!(!scaler.update(false));
+        assert_eq!(scaler.scale(), INITIAL_SCALE / 2.0);
+        for _ in 0..GROWTH_INTERVAL {
+            assert!(scaler.update(true));
+        }
+        assert_eq!(scaler.scale(), INITIAL_SCALE);
+
+        let mut fixed = LossScaler::fixed(1.0);
+        assert!(!fixed.update(false));
+        assert!(fixed.update(true));
+        assert_eq!(fixed.scale(), 1.0);
+
+        assert_eq!("bf16".parse(), Ok(Precision::Bf16));
+        assert!("fp8".parse::<Precision>().is_err());
+    }
+
+    #[test]
+    fn train_in_bfloat16() {
+        let mut net = Net::new(Device::Cpu, Some(0));
+        assert!(MixedPrecision::new(&net, Precision::Fp32).is_none());
+        let mut amp = MixedPrecision::new(&net, Precision::Bf16).unwrap();
+        let mut opt = Adam::default().build(net.vs_mut(), 1e-2).unwrap();
+
+        let input = Tensor::rand([16, INPUT_SIZE as i64], (Kind::Float, Device::Cpu));
+        let target = Tensor::full([16, 1], 0.5, (Kind::Float, Device::Cpu));
+        let loss = |amp: &MixedPrecision<Net>| {
+            let (_, value) = amp.network().forward_t(&amp.input(&input), true);
+            (value.to_kind(Kind::Float) - &target).square().mean(Kind::Float)
+        };
+        let before = f64::try_from(loss(&amp)).unwrap();
+        for _ in 0..20 {
+            assert!(amp.backward_step(&mut opt, &loss(&amp)));
+        }
+        let after = f64::try_from(loss(&amp)).unwrap();
+        assert!(after < before, "loss went from {before} to {after}");
+        assert!(net
+            .vs()
+            .trainable_variables()
+            .iter()
+            .all(|variable| variable.kind() == Kind::Float));
+
+        // A step with gradients which are not finite is skipped.
+        let weights = net.vs().trainable_variables()[0].copy();
+        assert!(!amp.backward_step(&mut opt, &(loss(&amp) * f64::NAN)));
+        assert!(net.vs().trainable_variables()[0].equal(&weights));
+    }
+
+    #[test]
+    fn train_net6_in_reduced_precision() {
+        use net6_simhash::{Net, N};
+
+        let precisions = [(Precision::Bf16, Kind::BFloat16), (Precision::Fp16, Kind::Half)];
+        for (precision, kind) in precisions {
+            let mut net = Net::new(Device::Cpu, Some(0));
+            let mut amp = MixedPrecision::new(&net, precision).unwrap();
+            let mut opt = Adam::default().build(net.vs_mut(), 1e-3).unwrap();
+            let simhash_matrix = net.vs().variables()["simhash_matrix"].copy();
+            let weights = net.vs().variables()["policy.conv2d.weight"].copy();
+
+            let shape = [4, input_channels::<N>() as i64, N as i64, N as i64];
+            let input = Tensor::rand(shape, (Kind::Float, Device::Cpu)).round();
+            let target = Tensor::full([4, 1], 0.5, (Kind::Float, Device::Cpu));
+            // The loss scale of `fp16` starts high enough to overflow.
+            let steps = (0..20)
+                .filter(|_| {
+                    let (_, value, _) = amp.network().forward_t(&amp.input(&input), true);
+                    let loss = (value.to_kind(Kind::Float) - &target).square().mean(Kind::Float);
+                    amp.backward_step(&mut opt, &loss)
+                })
+                .count();
+            assert!(steps > 0, "no step was taken in {precision}");
+            assert!(!net.vs().variables()["policy.conv2d.weight"].equal(&weights));
+
+            // Only the weights outside of batch normalization are reduced,
+            // so the statistics come back exactly, and the projection does
+            // not change.
+            let copied = amp.network().vs().variables();
+            assert_eq!(copied["policy.conv2d.weight"].kind(), kind);
+            for (name, variable) in net.vs().variables() {
+                if name.contains("batch_norm") {
+                    assert_eq!(copied[&name].kind(), Kind::Float, "{name}");
+                    if !variable.requires_grad() {
+                        assert!(variable.equal(&copied[&name]), "{name} in {precision}");
+                    }
+                }
+            }
+            assert!(net.vs().variables()["simhash_matrix"].equal(&simhash_matrix));
+        }
+    }
+}
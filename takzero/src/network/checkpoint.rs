@@ -0,0 +1,435 @@
+//! Integrity of published checkpoints.
+//!
+//! The trainer writes a checkpoint under a temporary name, loads it back,
+//! and smoke tests it before it replaces the published one, with an XXH3
+//! checksum next to it (see [`crate::checksum`]). Workers check the checksum
+//! and smoke test what they load as well, because a file can still be
+//! damaged in transfer or on disk.
+//!
+//! A checkpoint which fails is never used. The trainer keeps the previous
+//! good checkpoint published, and also keeps a copy of it next to the latest
+//! one (see [`previous_path`]), which is pushed to object storage with it.
+//! Workers which find a corrupted checkpoint keep the network they have, or
+//! fall back to the previous checkpoint if they have not loaded one yet (see
+//! [`load_previous`]). Every failure is logged as an error and
+//! counted in `takzero_checkpoint_failures_total`, which alerts can watch.
+//!
+//! Checkpoints also record the input encoding they were trained with (see
+//! [`InputRepr`]) in a file next to them, and refuse to load into a process
+//! which encodes positions differently. Checkpoints without one were
+//! trained with the default encoding.
+
+use std::{
+    ffi::OsString,
+    fs,
+    io,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use tch::{Device, TchError};
+use thiserror::Error;
+
+use super::{
+    repr::{self, InputRepr, ParseInputReprError},
+    Network,
+};
+use crate::{
+    checksum::{self, Checksum, ChecksumError},
+    logging,
+    metrics::REGISTRY,
+    search::{agent::Agent, env::Environment},
+};
+
+/// How long to wait before checking a checkpoint again which does not match
+/// its checksum, because it may have been caught while being published.
+const PUBLISH_WINDOW: Duration = Duration::from_millis(500);
+
+#[derive(Debug, Error)]
+pub enum CheckpointError {
+    #[error("{0}")]
+    Checksum(#[from] ChecksumError),
+    #[error("{0}")]
+    Io(#[from] io::Error),
+    #[error("{0}")]
+    Torch(#[from] TchError),
+    #[error("parameter `{0}` is not finite")]
+    NonFinite(String),
+    #[error("the {0} predicted for the start position is not finite")]
+    Prediction(&'static str),
+    #[error("the checkpoint was trained with input `{found}`, but this uses `{expected}`")]
+    InputRepr {
+        expected: InputRepr,
+        found: InputRepr,
+    },
+    #[error("the recorded input encoding is malformed: {0}")]
+    ParseInputRepr(#[from] ParseInputReprError),
+}
+
+impl CheckpointError {
+    /// Whether the checkpoint itself is bad, rather than missing or
+    /// unreadable for now.
+    #[must_use]
+    pub const fn is_corrupted(&self) -> bool {
+        matches!(
+            self,
+            Self::Checksum(ChecksumError::Corrupted { .. } | ChecksumError::Malformed(_))
+                | Self::NonFinite(_)
+                | Self::Prediction(_)
+        )
+    }
+}
+
+/// Path of the copy of the previous good checkpoint.
+#[must_use]
+pub fn previous_path(path: &Path) -> PathBuf {
+    let mut name = path.file_name().map(OsString::from).unwrap_or_default();
+    name.push(".previous");
+    path.with_file_name(name)
+}
+
+/// Path of the file which records the input encoding of a checkpoint.
+#[must_use]
+pub fn repr_path(path: &Path) -> PathBuf {
+    let mut name = path.file_name().map(OsString::from).unwrap_or_default();
+    name.push(".repr");
+    path.with_file_name(name)
+}
+
+/// The input encoding which a checkpoint was trained with.
+///
+/// # Errors
+///
+/// Returns an error if the record cannot be read or is malformed.
+pub fn recorded_repr(path: &Path) -> Result<InputRepr, CheckpointError> {
+    match fs::read_to_string(repr_path(path)) {
+        Ok(repr) => Ok(repr.parse()?),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(InputRepr::default()),
+        Err(err) => Err(err.into()),
+    }
+}
+
+/// Configure this process with the input encoding of a checkpoint, for
+/// tools which only use one model and take their encoding from it.
+///
+/// # Errors
+///
+/// Returns an error if the record cannot be read, or if the process
+/// already uses another encoding.
+pub fn configure_repr(path: &Path) -> Result<(), CheckpointError> {
+    let found = recorded_repr(path)?;
+    repr::configure(found).map_err(|expected| CheckpointError::InputRepr { expected, found })
+}
+
+/// Save a checkpoint and record the input encoding of this process next to
+/// it.
+///
+/// # Errors
+///
+/// Returns an error if the checkpoint or the record cannot be written.
+pub fn save<NET: Network>(net: &NET, path: &Path) -> Result<(), CheckpointError> {
+    net.save(path)?;
+    fs::write(repr_path(path), format!("{}\n", repr::input_repr()))?;
+    Ok(())
+}
+
+/// Load a checkpoint if it was trained with the input encoding of this
+/// process.
+///
+/// # Errors
+///
+/// Returns an error if the encodings differ or the checkpoint cannot be
+/// loaded.
+pub fn load<NET: Network>(path: &Path, device: Device) -> Result<NET, CheckpointError> {
+    let (expected, found) = (repr::input_repr(), recorded_repr(path)?);
+    if expected != found {
+        return Err(CheckpointError::InputRepr { expected, found });
+    }
+    Ok(NET::load(path, device)?)
+}
+
+/// The numbered checkpoint with the most training steps in a directory,
+/// like `model_0012000.ot`, and its number of steps.
+///
+/// # Errors
+///
+/// Returns an error if the directory cannot be read.
+pub fn latest_numbered(directory: &Path) -> io::Result<Option<(usize, PathBuf)>> {
+    Ok(fs::read_dir(directory)?
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| path.extension().is_some_and(|ext| ext == "ot"))
+        .filter_map(|path| {
+            let (_, steps) = path.file_stem()?.to_str()?.split_once('_')?;
+            Some((steps.parse().ok()?, path))
+        })
+        .max_by_key(|(steps, _)| *steps))
+}
+
+/// The checkpoint which a model path names. A directory names its
+/// published `model_latest.ot` if it has one, and otherwise its numbered
+/// checkpoint with the most training steps.
+///
+/// # Errors
+///
+/// Returns an error if the path is a directory without checkpoints, or it
+/// cannot be read.
+pub fn resolve(path: &Path) -> io::Result<PathBuf> {
+    if !path.is_dir() {
+        return Ok(path.to_path_buf());
+    }
+    let latest = path.join("model_latest.ot");
+    if latest.exists() {
+        return Ok(latest);
+    }
+    latest_numbered(path)?.map(|(_, path)| path).ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("{} has no checkpoints", path.display()),
+        )
+    })
+}
+
+/// Check that every parameter is finite and that the network predicts a
+/// finite value and uncertainty for the start position.
+///
+/// # Errors
+///
+/// Returns an error for the first problem found.
+pub fn smoke_test<E: Environment, NET: Network + Agent<E>>(
+    net: &NET,
+) -> Result<(), CheckpointError> {
+    for (name, parameter) in net.vs().variables() {
+        if parameter.isfinite().all().f_int64_value(&[])? == 0 {
+            return Err(CheckpointError::NonFinite(name));
+        }
+    }
+    let env = E::default();
+    let mut actions = Vec::new();
+    env.populate_actions(&mut actions);
+    for (_, value, uncertainty) in net.policy_value_uncertainty(&[env], &[actions]) {
+        if !value.is_finite() {
+            return Err(CheckpointError::Prediction("value"));
+        }
+        if !uncertainty.is_finite() {
+            return Err(CheckpointError::Prediction("uncertainty"));
+        }
+    }
+    Ok(())
+}
+
+/// Load a checkpoint, checking it against its checksum if it has one, and
+/// smoke test it. A checkpoint which does not match its checksum is checked
+/// once more after a moment, in case it was being published.
+///
+/// # Errors
+///
+/// Returns an error if the checkpoint cannot be loaded or fails a check.
+pub fn load_verified<E: Environment, NET: Network + Agent<E>>(
+    path: &Path,
+    device: Device,
+) -> Result<NET, CheckpointError> {
+    if let Err(ChecksumError::Corrupted { .. }) = checksum::verify(path) {
+        std::thread::sleep(PUBLISH_WINDOW);
+    }
+    checksum::verify(path)?;
+    let net = load(path, device)?;
+    smoke_test::<E, NET>(&net)?;
+    Ok(net)
+}
+
+/// Save the network to `path` if it passes the checks, with its checksum.
+/// The checkpoint it replaces is kept at [`previous_path`] if it was good.
+/// If the new checkpoint fails, the published one is left alone.
+///
+/// The checksum is written beforehand and renamed into place right after
+/// the checkpoint, and [`load_verified`] checks a mismatch again, so that
+/// workers do not mistake a checkpoint being published for a corrupted one.
+///
+/// # Errors
+///
+/// Returns an error if the checkpoint cannot be written or fails a check.
+pub fn publish<E: Environment, NET: Network + Agent<E>>(
+    net: &NET,
+    path: &Path,
+) -> Result<Checksum, CheckpointError> {
+    let mut temporary = path.as_os_str().to_owned();
+    temporary.push(".tmp");
+    let temporary = PathBuf::from(temporary);
+    save(net, &temporary)?;
+    let checksum = Checksum::read(fs::File::open(&temporary)?)?;
+    // Loading the file back finds saves which were cut short.
+    if let Err(err) = NET::load(&temporary, net.vs().device())
+        .map_err(CheckpointError::from)
+        .and_then(|saved| smoke_test::<E, NET>(&saved))
+    {
+        let _ = fs::remove_file(&temporary);
+        let _ = fs::remove_file(repr_path(&temporary));
+        return Err(err);
+    }
+
+    // Checkpoints from before checksums were written count as good.
+    if path.exists() && checksum::verify(path).is_ok() {
+        let previous = previous_path(path);
+        fs::copy(path, &previous)?;
+        checksum::write_sidecar(&previous)?;
+        match fs::copy(repr_path(path), repr_path(&previous)) {
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                let _ = fs::remove_file(repr_path(&previous));
+            }
+            result => {
+                result?;
+            }
+        }
+    }
+    let sidecar = checksum::sidecar_path(&temporary);
+    fs::write(&sidecar, format!("{checksum}\n"))?;
+    fs::rename(repr_path(&temporary), repr_path(path))?;
+    fs::rename(&temporary, path)?;
+    fs::rename(sidecar, checksum::sidecar_path(path))?;
+    Ok(checksum)
+}
+
+/// Load the previous good checkpoint after the one at `path` turned out to
+/// be corrupted, unless a model was loaded already, which is then kept.
+/// The generation of the previous checkpoint is not known, so the logs keep
+/// the generation they had.
+pub fn load_previous<E: Environment, NET: Network + Agent<E>>(
+    path: &Path,
+    device: Device,
+) -> Option<NET> {
+    if logging::context().generation.is_some() {
+        log::warn!("Keeping the current model.");
+        return None;
+    }
+    let previous = previous_path(path);
+    load_verified::<E, NET>(&previous, device)
+        .inspect(|_| log::warn!("Falling back to {}.", previous.display()))
+        .inspect_err(|err| log::error!("Cannot fall back to {}: {err}", previous.display()))
+        .ok()
+}
+
+/// Read the number of training steps of `model_latest.ot` in the directory,
+/// which learn writes to `model_latest_steps.txt` when it publishes it.
+#[must_use]
+pub fn read_model_steps(directory: &Path) -> Option<usize> {
+    fs::read_to_string(directory.join("model_latest_steps.txt"))
+        .ok()?
+        .trim()
+        .parse()
+        .ok()
+}
+
+/// Log a failed checkpoint as an error and count it, for alerts.
+pub fn report_failure(path: &Path, err: &CheckpointError) {
+    log::error!("Checkpoint {} failed verification: {err}", path.display());
+    REGISTRY.inc_counter(
+        "takzero_checkpoint_failures_total",
+        "Checkpoints which were corrupted or failed the smoke test.",
+        1.0,
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use tch::{Device, Tensor};
+
+    use super::{
+        latest_numbered,
+        load_verified,
+        previous_path,
+        publish,
+        recorded_repr,
+        repr_path,
+        resolve,
+        CheckpointError,
+    };
+    use crate::{
+        checksum,
+        network::{
+            connect4::Net as Connect4Net,
+            repr::{self, InputRepr},
+            Network,
+        },
+        search::env::connect4::Connect4,
+    };
+
+    #[test]
+    fn corrupted_checkpoints_fall_back() {
+        let directory =
+            std::env::temp_dir().join(format!("takzero-checkpoint-{}", std::process::id()));
+        std::fs::create_dir_all(&directory).unwrap();
+        let path = directory.join("model_latest.ot");
+        assert_eq!(previous_path(&path), directory.join("model_latest.ot.previous"));
+        assert!(resolve(&directory).is_err());
+
+        let first = Connect4Net::new(Device::Cpu, Some(1));
+        publish::<Connect4, _>(&first, &path).unwrap();
+        assert!(checksum::verify(&path).unwrap());
+        assert!(!previous_path(&path).exists());
+        let second = Connect4Net::new(Device::Cpu, Some(2));
+        publish::<Connect4, _>(&second, &path).unwrap();
+        assert!(checksum::verify(&previous_path(&path)).unwrap());
+        second.save(directory.join("model_0000100.ot")).unwrap();
+        second.save(directory.join("model_0000020.ot")).unwrap();
+        assert_eq!(
+            latest_numbered(&directory).unwrap(),
+            Some((100, directory.join("model_0000100.ot")))
+        );
+        assert_eq!(resolve(&directory).unwrap(), path);
+
+        // A network with NaN parameters is not published.
+        let broken = Connect4Net::new(Device::Cpu, Some(3));
+        tch::no_grad(|| {
+            for (_, mut parameter) in broken.vs().variables() {
+                parameter.copy_(&Tensor::full_like(&parameter, f64::NAN));
+            }
+        });
+        let err = publish::<Connect4, _>(&broken, &path).unwrap_err();
+        assert!(matches!(err, CheckpointError::NonFinite(_)), "{err}");
+        load_verified::<Connect4, Connect4Net>(&path, Device::Cpu).unwrap();
+
+        // A damaged file is caught by its checksum.
+        let mut bytes = std::fs::read(&path).unwrap();
+        let middle = bytes.len() / 2;
+        bytes[middle] ^= 0xff;
+        std::fs::write(&path, bytes).unwrap();
+        let err = load_verified::<Connect4, Connect4Net>(&path, Device::Cpu).unwrap_err();
+        assert!(err.is_corrupted(), "{err}");
+        load_verified::<Connect4, Connect4Net>(&previous_path(&path), Device::Cpu).unwrap();
+
+        // A checkpoint whose checksum is replaced a moment later, like one
+        // being published, is not corrupted.
+        publish::<Connect4, _>(&second, &path).unwrap();
+        let sidecar = checksum::sidecar_path(&path);
+        let published = std::fs::read(&sidecar).unwrap();
+        std::fs::copy(checksum::sidecar_path(&previous_path(&path)), &sidecar).unwrap();
+        let replace = std::thread::spawn({
+            let sidecar = sidecar.clone();
+            move || {
+                std::thread::sleep(std::time::Duration::from_millis(50));
+                std::fs::write(sidecar, published).unwrap();
+            }
+        });
+        load_verified::<Connect4, Connect4Net>(&path, Device::Cpu).unwrap();
+        replace.join().unwrap();
+
+        // A missing checkpoint is not corrupted.
+        let missing = Path::new("does-not-exist.ot");
+        let err = load_verified::<Connect4, Connect4Net>(missing, Device::Cpu).unwrap_err();
+        assert!(!err.is_corrupted(), "{err}");
+
+        // Checkpoints record their input encoding, and older ones without a
+        // record were trained with the default one.
+        let previous = previous_path(&path);
+        assert_eq!(recorded_repr(&previous).unwrap(), repr::input_repr());
+        std::fs::remove_file(repr_path(&previous)).unwrap();
+        assert_eq!(recorded_repr(&previous).unwrap(), InputRepr::default());
+        std::fs::write(repr_path(&previous), "stack-depth=1\n").unwrap();
+        let err = load_verified::<Connect4, Connect4Net>(&previous, Device::Cpu).unwrap_err();
+        assert!(matches!(err, CheckpointError::InputRepr { .. }), "{err}");
+        assert!(!err.is_corrupted(), "{err}");
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+}
@@ -0,0 +1,198 @@
+//! A tiny network for [`Connect4`], which trains in seconds on a CPU, for
+//! end-to-end tests of search and training outside of Tak.
+
+use ordered_float::NotNan;
+use tch::{
+    nn::{self, ModuleT},
+    Device,
+    Tensor,
+};
+
+use super::{repr::gather_policy, Network};
+use crate::search::{
+    agent::Agent,
+    env::{
+        connect4::{Connect4, COLUMNS, INPUT_SIZE},
+        Environment,
+    },
+};
+
+const HIDDEN: i64 = 128;
+
+#[derive(Debug)]
+pub struct Net {
+    vs: nn::VarStore,
+    core: nn::SequentialT,
+    policy_net: nn::SequentialT,
+    value_net: nn::SequentialT,
+}
+
+fn core(path: &nn::Path) -> nn::SequentialT {
+    nn::seq_t()
+        .add(nn::linear(
+            path / "input_linear",
+            INPUT_SIZE as i64,
+            HIDDEN,
+            nn::LinearConfig::default(),
+        ))
+        .add_fn(Tensor::relu)
+        .add(nn::linear(
+            path / "hidden_linear",
+            HIDDEN,
+            HIDDEN,
+            nn::LinearConfig::default(),
+        ))
+        .add_fn(Tensor::relu)
+}
+
+fn policy_net(path: &nn::Path) -> nn::SequentialT {
+    nn::seq_t().add(nn::linear(
+        path / "linear",
+        HIDDEN,
+        COLUMNS as i64,
+        nn::LinearConfig::default(),
+    ))
+}
+
+fn value_net(path: &nn::Path) -> nn::SequentialT {
+    nn::seq_t()
+        .add(nn::linear(path / "linear", HIDDEN, 1, nn::LinearConfig::default()))
+        .add_fn(Tensor::tanh)
+}
+
+impl Network for Net {
+    fn new(device: Device, seed: Option<i64>) -> Self {
+        if let Some(seed) = seed {
+            tch::manual_seed(seed);
+        }
+
+        let vs = nn::VarStore::new(device);
+        let root = vs.root();
+        Self {
+            core: core(&(&root / "core")),
+            policy_net: policy_net(&(&root / "policy")),
+            value_net: value_net(&(&root / "value")),
+            vs,
+        }
+    }
+
+    fn vs(&self) -> &nn::VarStore {
+        &self.vs
+    }
+
+    fn vs_mut(&mut self) -> &mut nn::VarStore {
+        &mut self.vs
+    }
+}
+
+impl Net {
+    /// Policy logits and values for a batch of inputs from
+    /// [`Connect4::to_input`].
+    #[must_use]
+    pub fn forward_t(&self, xs: &Tensor, train: bool) -> (Tensor, Tensor) {
+        let core = self.core.forward_t(xs, train);
+        let policy = self.policy_net.forward_t(&core, train);
+        let value = self.value_net.forward_t(&core, train);
+        (policy, value)
+    }
+
+    /// Inputs of the positions as a batch.
+    #[must_use]
+    pub fn input(&self, env_batch: &[Connect4]) -> Tensor {
+        let input: Vec<f32> = env_batch.iter().flat_map(Connect4::to_input).collect();
+        Tensor::from_slice(&input)
+            .view([-1, INPUT_SIZE as i64])
+            .to(self.vs.device())
+    }
+}
+
+/// The network has no uncertainty head, so the uncertainty is always 0.
+impl Agent<Connect4> for Net {
+    fn policy_value_uncertainty(
+        &self,
+        env_batch: &[Connect4],
+        actions_batch: &[Vec<<Connect4 as Environment>::Action>],
+    ) -> impl Iterator<Item = (Vec<(u8, NotNan<f32>)>, f32, f32)> {
+        assert_eq!(env_batch.len(), actions_batch.len());
+        assert!(!env_batch.is_empty());
+        let (policy, values) = self.forward_t(&self.input(env_batch), false);
+        let values: Vec<f32> = values.view([-1]).try_into().unwrap();
+        gather_policy::<Connect4>(&policy, actions_batch)
+            .into_iter()
+            .zip(values)
+            .map(|(p, v)| (p, v, 0.0))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tch::{
+        nn::{Adam, OptimizerConfig},
+        Device,
+        Kind,
+        Tensor,
+    };
+
+    use super::Net;
+    use crate::{
+        network::Network,
+        search::{
+            agent::Agent,
+            env::{connect4::Connect4, Environment},
+            node::Node,
+            DISCOUNT_FACTOR,
+        },
+    };
+
+    #[test]
+    fn self_play_and_train() {
+        let mut net = Net::new(Device::Cpu, Some(0));
+
+        // Play a game with search and collect visit counts as policy targets.
+        let mut env = Connect4::default();
+        let mut positions = Vec::new();
+        let mut targets = Vec::new();
+        while env.terminal().is_none() {
+            let mut root = Node::default();
+            for _ in 0..64 {
+                root.simulate_simple(&net, env, 0.0, DISCOUNT_FACTOR);
+            }
+            let mut target = [0.0; 7];
+            let visit_count = root.visit_count() as f32;
+            for (column, child) in root.children() {
+                target[usize::from(column)] = child.visit_count() as f32 / visit_count;
+            }
+            positions.push(env);
+            targets.extend(target);
+            env.step(root.select_best_action());
+        }
+        assert!(positions.len() >= 7);
+
+        let mut actions = vec![Vec::new(); positions.len()];
+        for (env, actions) in positions.iter().zip(&mut actions) {
+            env.populate_actions(actions);
+        }
+        let policies: Vec<_> = net.policy_value_uncertainty(&positions, &actions).collect();
+        assert!(policies
+            .iter()
+            .zip(&actions)
+            .all(|((policy, ..), actions)| policy.len() == actions.len()));
+
+        // Fitting the targets should reduce the loss.
+        let input = net.input(&positions);
+        let target = Tensor::from_slice(&targets).view([-1, 7]);
+        let mut opt = Adam::default().build(net.vs_mut(), 1e-2).unwrap();
+        let loss = |net: &Net| {
+            let (policy, _) = net.forward_t(&input, true);
+            -(policy.log_softmax(1, Kind::Float) * &target)
+                .sum_dim_intlist(1, false, None)
+                .mean(Kind::Float)
+        };
+        let before = f64::try_from(loss(&net)).unwrap();
+        for _ in 0..50 {
+            opt.backward_step(&loss(&net));
+        }
+        let after = f64::try_from(loss(&net)).unwrap();
+        assert!(after < before, "loss went from {before} to {after}");
+    }
+}
@@ -1,12 +1,28 @@
+#[cfg(feature = "tch")]
+pub mod amp;
+#[cfg(feature = "tch")]
+pub mod checkpoint;
+#[cfg(feature = "tch")]
+pub mod connect4;
+#[cfg(feature = "tch")]
 pub mod net4_ensemble;
+#[cfg(feature = "tch")]
 pub mod net4_lcghash;
+#[cfg(feature = "tch")]
 pub mod net4_rnd;
+#[cfg(feature = "tch")]
 pub mod net4_simhash;
+#[cfg(feature = "tch")]
 pub mod net5;
+#[cfg(feature = "tch")]
 pub mod net6_simhash;
 pub mod repr;
+#[cfg(feature = "tch")]
 pub mod residual;
+#[cfg(feature = "tch")]
+pub mod staging;
 
+#[cfg(feature = "tch")]
 pub trait Network: Sized {
     fn new(device: tch::Device, seed: Option<i64>) -> Self;
     fn vs(&self) -> &tch::nn::VarStore;
@@ -44,6 +60,7 @@ pub trait Network: Sized {
     }
 }
 
+#[cfg(feature = "tch")]
 pub trait RndNetwork: Network {
     fn forward_t(&self, xs: &tch::Tensor, train: bool) -> (tch::Tensor, tch::Tensor, tch::Tensor);
 
@@ -52,6 +69,7 @@ pub trait RndNetwork: Network {
     fn update_rnd_normalization(&mut self, min: &tch::Tensor, max: &tch::Tensor);
 }
 
+#[cfg(feature = "tch")]
 pub trait EnsembleNetwork: Network {
     fn forward_t(
         &self,
@@ -63,6 +81,31 @@ pub trait EnsembleNetwork: Network {
     fn forward_core_and_ensemble(&self, xs: &tch::Tensor, train: bool) -> tch::Tensor;
 }
 
+/// A network whose residual blocks can recompute their activations in the
+/// backward pass instead of keeping them (see
+/// [`residual::forward_checkpointed`]).
+#[cfg(feature = "tch")]
+pub trait CheckpointNetwork: Network {
+    /// Segments to recompute the residual blocks in when training this
+    /// architecture, or `None` to keep every activation. Training can choose
+    /// another number.
+    const CHECKPOINT_SEGMENTS: Option<usize>;
+
+    /// Like `forward_t` in training, but the residual blocks recompute their
+    /// activations in the backward pass, in `segments` segments. After the
+    /// backward pass of the loss, [`CheckpointNetwork::backward_checkpointed`]
+    /// finishes it.
+    fn forward_checkpointed(
+        &self,
+        xs: &tch::Tensor,
+        segments: usize,
+    ) -> ((tch::Tensor, tch::Tensor, tch::Tensor), residual::Checkpoints);
+
+    /// Continue the backward pass into the residual blocks.
+    fn backward_checkpointed(&self, checkpoints: residual::Checkpoints);
+}
+
+#[cfg(feature = "tch")]
 pub trait HashNetwork<E: crate::search::env::Environment>: Network {
     fn forward_t(&self, xs: &tch::Tensor, train: bool) -> (tch::Tensor, tch::Tensor, tch::Tensor);
 
@@ -7,12 +7,12 @@ use tch::{
 };
 
 use super::{
-    repr::{game_to_tensor, input_channels, move_index, output_channels},
+    repr::{game_to_tensor, gather_policy, input_channels, output_channels},
     residual::ResidualBlock,
     EnsembleNetwork,
     Network,
 };
-use crate::{network::repr::output_size, search::agent::Agent};
+use crate::search::agent::Agent;
 
 pub const N: usize = 4;
 pub const HALF_KOMI: i8 = 4;
@@ -114,6 +114,17 @@ fn ube_net(path: &nn::Path) -> nn::SequentialT {
         ))
 }
 
+impl Net {
+    /// Disagreement of the ensemble heads about the value of each position,
+    /// as their variance, clamped to [`MAXIMUM_VARIANCE`].
+    #[must_use]
+    pub fn ensemble_disagreement(&self, xs: &Tensor) -> Tensor {
+        self.forward_core_and_ensemble(xs, false)
+            .var_dim(1i64, false, false)
+            .clamp(0.0, MAXIMUM_VARIANCE)
+    }
+}
+
 impl Network for Net {
     fn new(device: Device, seed: Option<i64>) -> Self {
         if let Some(seed) = seed {
@@ -190,36 +201,7 @@ impl Agent<Env> for Net {
         )
         .to(device);
         let (policy, values, ube_uncertainties, ensemble) = self.forward_t(&xs, false);
-        let policy = policy.view([-1, output_size::<N>() as i64]);
-        let max_actions = actions_batch.iter().map(Vec::len).max().unwrap_or_default();
-        let index = Tensor::from_slice2(
-            &actions_batch
-                .iter()
-                .map(|actions| {
-                    actions
-                        .iter()
-                        .map(|a| move_index::<N>(a) as i64)
-                        .chain(std::iter::repeat(0))
-                        .take(max_actions)
-                        .collect::<Vec<_>>()
-                })
-                .collect::<Vec<_>>(),
-        )
-        .to(device);
-
-        let indexed_policy = actions_batch
-            .iter()
-            .zip(
-                Vec::<Vec<_>>::try_from(policy.gather(1, &index, false))
-                    .expect("tensor should have two dimensions"),
-            )
-            .map(|(actions, p)| {
-                actions
-                    .iter()
-                    .zip(p)
-                    .map(|(a, p)| (*a, NotNan::new(p).expect("logit should not be NaN")))
-                    .collect()
-            });
+        let indexed_policy = gather_policy::<Env>(&policy, actions_batch);
         let values: Vec<_> = values.view([-1]).try_into().unwrap();
 
         // Uncertainty.
@@ -233,6 +215,7 @@ impl Agent<Env> for Net {
             .unwrap();
 
         indexed_policy
+            .into_iter()
             .zip(values)
             .zip(uncertainties)
             .map(|((p, v), u)| (p, v, u))
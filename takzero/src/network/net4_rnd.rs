@@ -7,15 +7,12 @@ use tch::{
 };
 
 use super::{
-    repr::{game_to_tensor, input_channels, move_index, output_channels},
+    repr::{game_to_tensor, gather_policy, input_channels, output_channels},
     residual::ResidualBlock,
     Network,
     RndNetwork,
 };
-use crate::{
-    network::{repr::output_size, residual::SmallBlock},
-    search::agent::Agent,
-};
+use crate::{network::residual::SmallBlock, search::agent::Agent};
 
 pub const N: usize = 4;
 pub const HALF_KOMI: i8 = 4;
@@ -254,36 +251,7 @@ impl Agent<Env> for Net {
             0,
         );
         let (policy, values, ube_uncertainties) = self.forward_t(&xs, false);
-        let policy = policy.view([-1, output_size::<N>() as i64]);
-        let max_actions = actions_batch.iter().map(Vec::len).max().unwrap_or_default();
-        let index = Tensor::from_slice2(
-            &actions_batch
-                .iter()
-                .map(|actions| {
-                    actions
-                        .iter()
-                        .map(|a| move_index::<N>(a) as i64)
-                        .chain(std::iter::repeat(0))
-                        .take(max_actions)
-                        .collect::<Vec<_>>()
-                })
-                .collect::<Vec<_>>(),
-        )
-        .to(device);
-
-        let indexed_policy = actions_batch
-            .iter()
-            .zip(
-                Vec::<Vec<_>>::try_from(policy.gather(1, &index, false))
-                    .expect("tensor should have two dimensions"),
-            )
-            .map(|(actions, p)| {
-                actions
-                    .iter()
-                    .zip(p)
-                    .map(|(a, p)| (*a, NotNan::new(p).expect("logit should not be NaN")))
-                    .collect()
-            });
+        let indexed_policy = gather_policy::<Env>(&policy, actions_batch);
         let values: Vec<_> = values.view([-1]).try_into().unwrap();
 
         // Uncertainty.
@@ -297,6 +265,7 @@ impl Agent<Env> for Net {
             .unwrap();
 
         indexed_policy
+            .into_iter()
             .zip(values)
             .zip(uncertainties)
             .map(|((p, v), u)| (p, v, u))
@@ -7,12 +7,13 @@ use tch::{
 };
 
 use super::{
-    repr::{game_to_tensor, input_channels, input_size, move_index, output_channels},
-    residual::ResidualBlock,
+    repr::{game_to_tensor, gather_policy, input_channels, input_size, output_channels},
+    residual::{forward_checkpointed, Checkpoints, ResidualBlock},
+    CheckpointNetwork,
     Network,
     RndNetwork,
 };
-use crate::{network::repr::output_size, search::agent::Agent};
+use crate::search::agent::Agent;
 
 pub const N: usize = 5;
 pub const HALF_KOMI: i8 = 4;
@@ -25,7 +26,8 @@ pub const MAXIMUM_VARIANCE: f64 = 4.0;
 #[derive(Debug)]
 pub struct Net {
     vs: nn::VarStore,
-    core: nn::SequentialT,
+    stem: nn::SequentialT,
+    res_blocks: Vec<ResidualBlock>,
     policy_net: nn::SequentialT,
     value_net: nn::SequentialT,
     ube_net: nn::SequentialT,
@@ -41,9 +43,8 @@ struct Rnd {
     max: Tensor,
 }
 
-fn core(path: &nn::Path) -> nn::SequentialT {
-    const CORE_RES_BLOCKS: u32 = 20;
-    let mut core = nn::seq_t()
+fn stem(path: &nn::Path) -> nn::SequentialT {
+    nn::seq_t()
         .add(nn::conv2d(
             path / "input_conv2d",
             input_channels::<N>() as i64,
@@ -61,15 +62,14 @@ fn core(path: &nn::Path) -> nn::SequentialT {
             FILTERS,
             nn::BatchNormConfig::default(),
         ))
-        .add_fn(Tensor::relu);
-    for n in 0..CORE_RES_BLOCKS {
-        core = core.add(ResidualBlock::new(
-            &(path / format!("res_block_{n}")),
-            FILTERS,
-            FILTERS,
-        ));
-    }
-    core
+        .add_fn(Tensor::relu)
+}
+
+fn res_blocks(path: &nn::Path) -> Vec<ResidualBlock> {
+    const CORE_RES_BLOCKS: u32 = 20;
+    (0..CORE_RES_BLOCKS)
+        .map(|n| ResidualBlock::new(&(path / format!("res_block_{n}")), FILTERS, FILTERS))
+        .collect()
 }
 
 fn policy_net(path: &nn::Path) -> nn::SequentialT {
@@ -156,7 +156,8 @@ impl Network for Net {
         let vs = nn::VarStore::new(device);
         let root = vs.root();
         Self {
-            core: core(&(&root / "core")),
+            stem: stem(&(&root / "core")),
+            res_blocks: res_blocks(&(&root / "core")),
             policy_net: policy_net(&(&root / "policy")),
             value_net: value_net(&(&root / "value")),
             ube_net: ube_net(&(&root / "ube")),
@@ -180,15 +181,42 @@ impl Network for Net {
     }
 }
 
-impl RndNetwork for Net {
-    fn forward_t(&self, xs: &Tensor, train: bool) -> (Tensor, Tensor, Tensor) {
-        let core = self.core.forward_t(xs, train);
-        let policy = self.policy_net.forward_t(&core, train);
-        let value = self.value_net.forward_t(&core, train);
+impl Net {
+    fn heads(&self, core: &Tensor, train: bool) -> (Tensor, Tensor, Tensor) {
+        let policy = self.policy_net.forward_t(core, train);
+        let value = self.value_net.forward_t(core, train);
         // Detached UBE so it does not mess with baseline
         let ube = self.ube_net.forward_t(&core.detach(), train);
         (policy, value, ube)
     }
+}
+
+impl CheckpointNetwork for Net {
+    const CHECKPOINT_SEGMENTS: Option<usize> = None;
+
+    fn forward_checkpointed(
+        &self,
+        xs: &Tensor,
+        segments: usize,
+    ) -> ((Tensor, Tensor, Tensor), Checkpoints) {
+        let (core, checkpoints) =
+            forward_checkpointed(&self.res_blocks, &self.stem.forward_t(xs, true), segments, true);
+        (self.heads(&core, true), checkpoints)
+    }
+
+    fn backward_checkpointed(&self, checkpoints: Checkpoints) {
+        checkpoints.backward(&self.res_blocks);
+    }
+}
+
+impl RndNetwork for Net {
+    fn forward_t(&self, xs: &Tensor, train: bool) -> (Tensor, Tensor, Tensor) {
+        let core = self
+            .res_blocks
+            .iter()
+            .fold(self.stem.forward_t(xs, train), |x, block| block.forward_t(&x, train));
+        self.heads(&core, train)
+    }
 
     fn forward_rnd(&self, xs: &Tensor, train: bool) -> Tensor {
         let learning = self
@@ -235,36 +263,7 @@ impl Agent<Env> for Net {
             0,
         );
         let (policy, values, ube_uncertainties) = self.forward_t(&xs, false);
-        let policy = policy.view([-1, output_size::<N>() as i64]);
-        let max_actions = actions_batch.iter().map(Vec::len).max().unwrap_or_default();
-        let index = Tensor::from_slice2(
-            &actions_batch
-                .iter()
-                .map(|actions| {
-                    actions
-                        .iter()
-                        .map(|a| move_index::<N>(a) as i64)
-                        .chain(std::iter::repeat(0))
-                        .take(max_actions)
-                        .collect::<Vec<_>>()
-                })
-                .collect::<Vec<_>>(),
-        )
-        .to(device);
-
-        let indexed_policy = actions_batch
-            .iter()
-            .zip(
-                Vec::<Vec<_>>::try_from(policy.gather(1, &index, false))
-                    .expect("tensor should have two dimensions"),
-            )
-            .map(|(actions, p)| {
-                actions
-                    .iter()
-                    .zip(p)
-                    .map(|(a, p)| (*a, NotNan::new(p).expect("logit should not be NaN")))
-                    .collect()
-            });
+        let indexed_policy = gather_policy::<Env>(&policy, actions_batch);
         let values: Vec<_> = values.view([-1]).try_into().unwrap();
 
         // Uncertainty.
@@ -278,6 +277,7 @@ impl Agent<Env> for Net {
             .unwrap();
 
         indexed_policy
+            .into_iter()
             .zip(values)
             .zip(uncertainties)
             .map(|((p, v), u)| (p, v, u))
@@ -1,3 +1,5 @@
+use std::sync::Mutex;
+
 use bitvec::prelude::*;
 use fast_tak::{takparse::Move, Game};
 use ordered_float::NotNan;
@@ -10,14 +12,17 @@ use tch::{
 };
 
 use super::{
-    repr::{game_to_tensor, input_channels, move_index, output_channels},
-    residual::ResidualBlock,
+    repr::{gather_policy, input_channels, output_channels},
+    residual::{forward_checkpointed, Checkpoints, ResidualBlock},
+    staging::Staging,
+    CheckpointNetwork,
     HashNetwork,
     Network,
 };
 use crate::{
-    network::repr::{input_size, output_size},
+    network::repr::{input_size, to_move_channel},
     search::agent::Agent,
+    variant::VariantGame,
 };
 
 pub const N: usize = 6;
@@ -32,17 +37,18 @@ pub const MAXIMUM_VARIANCE: f64 = 4.0;
 #[derive(Debug)]
 pub struct Net {
     vs: nn::VarStore,
-    core: nn::SequentialT,
+    stem: nn::SequentialT,
+    res_blocks: Vec<ResidualBlock>,
     policy_net: nn::SequentialT,
     value_net: nn::SequentialT,
     ube_net: nn::SequentialT,
     simhash_matrix: Tensor,
     simhash_set: BitBox,
+    staging: Mutex<Staging>,
 }
 
-fn core(path: &nn::Path) -> nn::SequentialT {
-    const CORE_RES_BLOCKS: u32 = 16;
-    let mut core = nn::seq_t()
+fn stem(path: &nn::Path) -> nn::SequentialT {
+    nn::seq_t()
         .add(nn::conv2d(
             path / "input_conv2d",
             input_channels::<N>() as i64,
@@ -60,15 +66,14 @@ fn core(path: &nn::Path) -> nn::SequentialT {
             FILTERS,
             nn::BatchNormConfig::default(),
         ))
-        .add_fn(Tensor::relu);
-    for n in 0..CORE_RES_BLOCKS {
-        core = core.add(ResidualBlock::new(
-            &(path / format!("res_block_{n}")),
-            FILTERS,
-            FILTERS,
-        ));
-    }
-    core
+        .add_fn(Tensor::relu)
+}
+
+fn res_blocks(path: &nn::Path) -> Vec<ResidualBlock> {
+    const CORE_RES_BLOCKS: u32 = 16;
+    (0..CORE_RES_BLOCKS)
+        .map(|n| ResidualBlock::new(&(path / format!("res_block_{n}")), FILTERS, FILTERS))
+        .collect()
 }
 
 fn policy_net(path: &nn::Path) -> nn::SequentialT {
@@ -127,15 +132,19 @@ impl Network for Net {
         let vs = nn::VarStore::new(device);
         let root = vs.root();
         Self {
-            core: core(&(&root / "core")),
+            stem: stem(&(&root / "core")),
+            res_blocks: res_blocks(&(&root / "core")),
             policy_net: policy_net(&(&root / "policy")),
             value_net: value_net(&(&root / "value")),
             ube_net: ube_net(&(&root / "ube")),
-            simhash_matrix: root.randn_standard("simhash_matrix", &[
-                input_size::<N>() as i64,
-                HASH_BITS as i64,
-            ]),
+            // A random projection, which is not trained.
+            simhash_matrix: root.add(
+                "simhash_matrix",
+                Tensor::randn([input_size::<N>() as i64, HASH_BITS as i64], (Kind::Float, device)),
+                false,
+            ),
             simhash_set: bitbox![0; 1 << HASH_BITS],
+            staging: Mutex::new(Staging::new(device)),
             vs,
         }
     }
@@ -190,15 +199,42 @@ impl Network for Net {
     }
 }
 
-impl HashNetwork<Env> for Net {
-    fn forward_t(&self, xs: &Tensor, train: bool) -> (Tensor, Tensor, Tensor) {
-        let core = self.core.forward_t(xs, train);
-        let policy = self.policy_net.forward_t(&core, train);
-        let value = self.value_net.forward_t(&core, train);
+impl Net {
+    fn heads(&self, core: &Tensor, train: bool) -> (Tensor, Tensor, Tensor) {
+        let policy = self.policy_net.forward_t(core, train);
+        let value = self.value_net.forward_t(core, train);
         // Detached UBE so it does not mess with baseline
         let ube = self.ube_net.forward_t(&core.detach(), train);
         (policy, value, ube)
     }
+}
+
+impl CheckpointNetwork for Net {
+    const CHECKPOINT_SEGMENTS: Option<usize> = None;
+
+    fn forward_checkpointed(
+        &self,
+        xs: &Tensor,
+        segments: usize,
+    ) -> ((Tensor, Tensor, Tensor), Checkpoints) {
+        let (core, checkpoints) =
+            forward_checkpointed(&self.res_blocks, &self.stem.forward_t(xs, true), segments, true);
+        (self.heads(&core, true), checkpoints)
+    }
+
+    fn backward_checkpointed(&self, checkpoints: Checkpoints) {
+        checkpoints.backward(&self.res_blocks);
+    }
+}
+
+impl HashNetwork<Env> for Net {
+    fn forward_t(&self, xs: &Tensor, train: bool) -> (Tensor, Tensor, Tensor) {
+        let core = self
+            .res_blocks
+            .iter()
+            .fold(self.stem.forward_t(xs, train), |x, block| block.forward_t(&x, train));
+        self.heads(&core, train)
+    }
 
     fn get_indices(&self, xs: &Tensor) -> Vec<usize> {
         let options = (Kind::Int64, self.vs().device());
@@ -211,7 +247,7 @@ impl HashNetwork<Env> for Net {
             &[
                 None,
                 Some(
-                    Tensor::from_slice(&[input_channels::<N>() as i64 - 2]).to(self.vs().device()),
+                    Tensor::from_slice(&[to_move_channel::<N>() as i64]).to(self.vs().device()),
                 ),
             ],
             &Tensor::zeros(
@@ -256,58 +292,19 @@ impl HashNetwork<Env> for Net {
     }
 }
 
-impl Agent<Env> for Net {
-    fn policy_value_uncertainty(
+impl Net {
+    /// Policy, value, and uncertainty of a batch of encoded inputs.
+    fn evaluate(
         &self,
-        env_batch: &[Env],
-        actions_batch: &[Vec<<Env as crate::search::env::Environment>::Action>],
+        xs: &Tensor,
+        actions_batch: &[Vec<Move>],
     ) -> impl Iterator<Item = (Vec<(Move, NotNan<f32>)>, f32, f32)> {
-        assert_eq!(env_batch.len(), actions_batch.len());
-        assert!(!env_batch.is_empty());
-        let device = self.vs.device();
-
-        let xs = Tensor::cat(
-            &env_batch
-                .iter()
-                .map(|env| game_to_tensor(env, device))
-                .collect::<Vec<_>>(),
-            0,
-        );
-        let (policy, values, ube_uncertainties) = self.forward_t(&xs, false);
-        let policy = policy.view([-1, output_size::<N>() as i64]);
-        let max_actions = actions_batch.iter().map(Vec::len).max().unwrap_or_default();
-        let index = Tensor::from_slice2(
-            &actions_batch
-                .iter()
-                .map(|actions| {
-                    actions
-                        .iter()
-                        .map(|a| move_index::<N>(a) as i64)
-                        .chain(std::iter::repeat(0))
-                        .take(max_actions)
-                        .collect::<Vec<_>>()
-                })
-                .collect::<Vec<_>>(),
-        )
-        .to(device);
-
-        let indexed_policy = actions_batch
-            .iter()
-            .zip(
-                Vec::<Vec<_>>::try_from(policy.gather(1, &index, false))
-                    .expect("tensor should have two dimensions"),
-            )
-            .map(|(actions, p)| {
-                actions
-                    .iter()
-                    .zip(p)
-                    .map(|(a, p)| (*a, NotNan::new(p).expect("logit should not be NaN")))
-                    .collect()
-            });
+        let (policy, values, ube_uncertainties) = self.forward_t(xs, false);
+        let indexed_policy = gather_policy::<Env>(&policy, actions_batch);
         let values: Vec<_> = values.view([-1]).try_into().unwrap();
 
         // Uncertainty.
-        let local_uncertainties = self.forward_hash(&xs);
+        let local_uncertainties = self.forward_hash(xs);
         let uncertainties: Vec<_> = ube_uncertainties
             .exp() // Exponent because UBE prediction is log(variance)
             .maximum(&local_uncertainties)
@@ -317,12 +314,58 @@ impl Agent<Env> for Net {
             .unwrap();
 
         indexed_policy
+            .into_iter()
             .zip(values)
             .zip(uncertainties)
             .map(|((p, v), u)| (p, v, u))
     }
 }
 
+impl Agent<Env> for Net {
+    fn policy_value_uncertainty(
+        &self,
+        env_batch: &[Env],
+        actions_batch: &[Vec<<Env as crate::search::env::Environment>::Action>],
+    ) -> impl Iterator<Item = (Vec<(Move, NotNan<f32>)>, f32, f32)> {
+        assert_eq!(env_batch.len(), actions_batch.len());
+        assert!(!env_batch.is_empty());
+
+        let xs = self
+            .staging
+            .lock()
+            .expect("staging lock should not be poisoned")
+            .upload(env_batch);
+
+        let outputs = self.evaluate(&xs, actions_batch);
+        // The outputs were read, so the input is no longer being copied.
+        drop(xs);
+        outputs
+    }
+}
+
+/// Variant games are encoded with their progress towards the move limit.
+impl Agent<VariantGame<N, HALF_KOMI>> for Net {
+    fn policy_value_uncertainty(
+        &self,
+        env_batch: &[VariantGame<N, HALF_KOMI>],
+        actions_batch: &[Vec<Move>],
+    ) -> impl Iterator<Item = (Vec<(Move, NotNan<f32>)>, f32, f32)> {
+        assert_eq!(env_batch.len(), actions_batch.len());
+        assert!(!env_batch.is_empty());
+
+        let xs = self
+            .staging
+            .lock()
+            .expect("staging lock should not be poisoned")
+            .upload_variants(env_batch);
+
+        let outputs = self.evaluate(&xs, actions_batch);
+        // The outputs were read, so the input is no longer being copied.
+        drop(xs);
+        outputs
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use std::array;
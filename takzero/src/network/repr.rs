@@ -1,10 +1,20 @@
+use std::{fmt, str::FromStr, sync::OnceLock};
+
 use fast_tak::{
-    takparse::{Color, Direction, Move, MoveKind, Piece},
+    takparse::{Color, Direction, Move, MoveKind, Pattern, Piece, Square},
     Game,
     Reserves,
+    Symmetry,
 };
 use ordered_float::NotNan;
+use rayon::prelude::*;
+#[cfg(feature = "tch")]
 use tch::{Device, Tensor};
+use thiserror::Error;
+
+#[cfg(feature = "tch")]
+use crate::search::env::{inverse_symmetry, ActionIndex};
+use crate::search::env::{Environment, SymmetryIndex};
 
 /// Get the number of possible moves for a given board size.
 ///
@@ -70,8 +80,94 @@ pub fn move_index<const N: usize>(m: &Move) -> usize {
     channel * N * N + row * N + column
 }
 
+/// The move with the given index, the inverse of [`move_index`].
+/// Returns `None` for indices past the output.
+#[must_use]
+pub fn index_move<const N: usize>(index: usize) -> Option<Move> {
+    if index >= output_size::<N>() {
+        return None;
+    }
+    let (channel, square) = (index / (N * N), index % (N * N));
+    let square = Square::new((square % N) as u8, (square / N) as u8);
+    let kind = match channel {
+        0 => MoveKind::Place(Piece::Flat),
+        1 => MoveKind::Place(Piece::Wall),
+        2 => MoveKind::Place(Piece::Cap),
+        _ => {
+            // The inverse of the offsets in `move_index`.
+            let offset = channel - 3;
+            let direction = match offset / possible_patterns::<N>() {
+                0 => Direction::Up,
+                1 => Direction::Right,
+                2 => Direction::Down,
+                _ => Direction::Left,
+            };
+            let pattern = offset % possible_patterns::<N>() + 1;
+            let mask = u8::try_from(pattern << (8 - N)).ok()?;
+            MoveKind::Spread(direction, Pattern::from_mask(mask))
+        }
+    };
+    Some(Move::new(square, kind))
+}
+
+/// Policy indices of every move under each symmetry.
+type Permutations = [Box<[usize]>; 8];
+
+/// Tables of [`policy_permutation`], by board size.
+static POLICY_PERMUTATIONS: [OnceLock<Permutations>; 9] = [const { OnceLock::new() }; 9];
+
+/// Where the policy indices go under a symmetry, in the order of
+/// [`Symmetry::symmetries`].
+///
+/// The move with index `i` becomes the move with index `permutation[i]`.
+/// The table is computed the first time it is asked for and then shared.
+///
+/// # Panics
+///
+/// Panics for board sizes above 8 and symmetries above 7.
+#[must_use]
+pub fn policy_permutation<const N: usize>(symmetry: SymmetryIndex) -> &'static [usize] {
+    let tables = POLICY_PERMUTATIONS[N].get_or_init(|| {
+        let mut tables: [Vec<usize>; 8] = Default::default();
+        for channel in 0..output_channels::<N>() {
+            // The kind of move does not depend on the square.
+            let kind = index_move::<N>(channel * N * N)
+                .expect("every channel should have moves")
+                .kind();
+            for row in 0..N {
+                for column in 0..N {
+                    let action = Move::new(Square::new(column as u8, row as u8), kind);
+                    let images = Symmetry::<N>::symmetries(&action);
+                    for (table, image) in tables.iter_mut().zip(images) {
+                        table.push(move_index::<N>(&image));
+                    }
+                }
+            }
+        }
+        tables.map(Vec::into_boxed_slice)
+    });
+    &tables[symmetry]
+}
+
+/// Indices which pick the policy of the position after a symmetry out of the
+/// policy of the original position with `index_select`, for augmenting and
+/// averaging batches of policies on the device.
+#[cfg(feature = "tch")]
+#[must_use]
+pub fn policy_permutation_tensor<const N: usize>(
+    symmetry: SymmetryIndex,
+    device: Device,
+) -> Tensor {
+    let indices: Vec<i64> = policy_permutation::<N>(inverse_symmetry::<N>(symmetry))
+        .iter()
+        .map(|&index| index as i64)
+        .collect();
+    Tensor::from_slice(&indices).to(device)
+}
+
 /// Create a mask for all the impossible moves.
 /// Possible moves are false, impossible are true.
+#[cfg(feature = "tch")]
 pub fn move_mask<const N: usize>(moves: &[Move], device: Device) -> Tensor {
     let mut mask = vec![true; output_size::<N>()];
     for mov in moves {
@@ -85,6 +181,7 @@ pub fn move_mask<const N: usize>(moves: &[Move], device: Device) -> Tensor {
 }
 
 /// Create a tensor containing the given policy.
+#[cfg(feature = "tch")]
 pub fn policy_tensor<const N: usize>(policy: &[(Move, NotNan<f32>)], device: Device) -> Tensor {
     let mut data = vec![0.0; output_size::<N>()];
     for (mov, p) in policy {
@@ -96,6 +193,50 @@ pub fn policy_tensor<const N: usize>(policy: &[(Move, NotNan<f32>)], device: Dev
         .to(device)
 }
 
+/// Pick the logits of the actions out of a batch of policy outputs, which
+/// are flattened to one index per action.
+/// A position with a NaN logit gets an empty policy, which the search
+/// reports as [`SearchError::Nan`](crate::search::node::SearchError::Nan).
+#[cfg(feature = "tch")]
+#[must_use]
+pub fn gather_policy<E: ActionIndex>(
+    policy: &Tensor,
+    actions_batch: &[Vec<E::Action>],
+) -> Vec<Vec<(E::Action, NotNan<f32>)>> {
+    let policy = policy.view([-1, E::ACTIONS as i64]);
+    let max_actions = actions_batch.iter().map(Vec::len).max().unwrap_or_default();
+    let index = Tensor::from_slice2(
+        &actions_batch
+            .iter()
+            .map(|actions| {
+                actions
+                    .iter()
+                    .map(|a| E::action_index(a) as i64)
+                    .chain(std::iter::repeat(0))
+                    .take(max_actions)
+                    .collect::<Vec<_>>()
+            })
+            .collect::<Vec<_>>(),
+    )
+    .to(policy.device());
+
+    actions_batch
+        .iter()
+        .zip(
+            Vec::<Vec<_>>::try_from(policy.gather(1, &index, false))
+                .expect("tensor should have two dimensions"),
+        )
+        .map(|(actions, p)| {
+            actions
+                .iter()
+                .zip(p)
+                .map(|(a, p)| Some((a.clone(), NotNan::new(p).ok()?)))
+                .collect::<Option<_>>()
+                .unwrap_or_default()
+        })
+        .collect()
+}
+
 /// Get the number of channels needed to encode each move type.
 /// This is used by the newer networks.
 #[inline]
@@ -115,28 +256,220 @@ pub const fn output_size<const N: usize>() -> usize {
     N * N * output_channels::<N>()
 }
 
+/// How positions are encoded as network inputs.
+///
+/// The encoding is part of the architecture of a network, so a checkpoint
+/// only works with the encoding it was trained with. Runs pick it with
+/// command line options (see [`configure`]), and checkpoints record it in a
+/// file next to them (see `network::checkpoint`).
+///
+/// It is written as `key=value` pairs separated by spaces, like
+/// `stack-depth=6 planes=extended`, and pairs which are left out keep their
+/// default.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct InputRepr {
+    /// Number of pieces below the top of each stack which are encoded, or
+    /// `None` for the pieces which can be carried with the top and `N + 1`
+    /// more below them.
+    pub stack_depth: Option<usize>,
+    pub planes: Planes,
+}
+
+/// The planes after the stacks, reserves, side to move, and flat count
+/// differential. Planes are only ever appended, so that the channels of
+/// older checkpoints keep their meaning.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Planes {
+    /// No further planes, like the first checkpoints.
+    #[default]
+    Base,
+    /// Whether the game is in the opening swap, then the progress towards
+    /// the move limit of a variant.
+    Extended,
+}
+
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum ParseInputReprError {
+    #[error("`{0}` is not `key=value`")]
+    Pair(String),
+    #[error("unknown key `{0}`")]
+    Key(String),
+    #[error("`{0}` is not a stack depth")]
+    StackDepth(String),
+    #[error("unknown planes `{0}`, expected `base` or `extended`")]
+    Planes(String),
+}
+
+impl FromStr for InputRepr {
+    type Err = ParseInputReprError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut repr = Self::default();
+        for pair in s.split_whitespace() {
+            let (key, value) = pair
+                .split_once('=')
+                .ok_or_else(|| ParseInputReprError::Pair(pair.to_string()))?;
+            match key {
+                "stack-depth" => {
+                    repr.stack_depth = match value {
+                        "default" => None,
+                        depth => Some(depth.parse().map_err(|_| {
+                            ParseInputReprError::StackDepth(depth.to_string())
+                        })?),
+                    }
+                }
+                "planes" => {
+                    repr.planes = match value {
+                        "base" => Planes::Base,
+                        "extended" => Planes::Extended,
+                        planes => return Err(ParseInputReprError::Planes(planes.to_string())),
+                    }
+                }
+                key => return Err(ParseInputReprError::Key(key.to_string())),
+            }
+        }
+        Ok(repr)
+    }
+}
+
+impl fmt::Display for InputRepr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.stack_depth {
+            Some(depth) => write!(f, "stack-depth={depth}")?,
+            None => f.write_str("stack-depth=default")?,
+        }
+        match self.planes {
+            Planes::Base => f.write_str(" planes=base"),
+            Planes::Extended => f.write_str(" planes=extended"),
+        }
+    }
+}
+
+/// The encoding which was set by [`configure`], or the default.
+static INPUT_REPR: OnceLock<InputRepr> = OnceLock::new();
+
+/// Set the encoding of positions for the rest of the process. This has to
+/// happen before any input is encoded or any network is created.
+///
+/// # Errors
+///
+/// Returns the encoding which is already in use if it is another one.
+pub fn configure(repr: InputRepr) -> Result<(), InputRepr> {
+    let current = *INPUT_REPR.get_or_init(|| repr);
+    if current == repr {
+        Ok(())
+    } else {
+        Err(current)
+    }
+}
+
+/// The encoding of positions of this process, see [`InputRepr`].
+#[must_use]
+pub fn input_repr() -> InputRepr {
+    *INPUT_REPR.get_or_init(InputRepr::default)
+}
+
+impl InputRepr {
+    /// Number of pieces below the top of each stack which are encoded on a
+    /// board of size `N`.
+    #[must_use]
+    pub fn depth<const N: usize>(&self) -> usize {
+        self.stack_depth.unwrap_or(2 * N)
+    }
+
+    /// Channels of the input on a board of size `N`.
+    #[must_use]
+    pub fn channels<const N: usize>(&self) -> usize {
+        input_channels_for(self.depth::<N>(), self.planes)
+    }
+
+    /// Channel with the progress towards the move limit of a variant, if
+    /// the input has one.
+    #[must_use]
+    pub fn move_limit_channel<const N: usize>(&self) -> Option<usize> {
+        (self.planes == Planes::Extended).then(|| 2 * (3 + self.depth::<N>()) + 7)
+    }
+
+    /// Write the input of the game into a buffer of the right size, which
+    /// is filled with zeroes.
+    pub fn encode<const N: usize, const HALF_KOMI: i8>(
+        &self,
+        buffer: &mut [f32],
+        game: &Game<N, HALF_KOMI>,
+    ) where
+        Reserves<N>: Default,
+    {
+        game_repr(buffer, game, self.depth::<N>(), self.planes);
+    }
+
+    /// Fill the move limit plane of an input, if there is one, with how much
+    /// of the move limit is used up.
+    pub fn encode_move_limit<const N: usize>(&self, buffer: &mut [f32], progress: f32) {
+        if let Some(channel) = self.move_limit_channel::<N>() {
+            buffer[N * N * channel..N * N * (channel + 1)].fill(progress);
+        }
+    }
+}
+
+/// Number of pieces below the top of each stack which the input encodes,
+/// see [`InputRepr::stack_depth`].
+#[must_use]
+pub fn stack_depth<const N: usize>() -> usize {
+    input_repr().depth::<N>()
+}
+
+/// Channels for each player: the type of the top piece, then the color of
+/// the stones below it.
 #[inline]
 #[must_use]
-pub const fn stack_size<const N: usize>() -> usize {
+pub fn stack_size<const N: usize>() -> usize {
     let piece_type = 3;
-    let carry = piece_type + (N - 1);
-    let below_carry = N + 1;
-    carry + below_carry
+    piece_type + stack_depth::<N>()
 }
 
 #[inline]
 #[must_use]
-pub const fn board_size<const N: usize>() -> usize {
+pub fn board_size<const N: usize>() -> usize {
     stack_size::<N>() * N * N
 }
 
+/// Channels of the input when `depth` pieces below the top of each stack are
+/// encoded, followed by the planes.
 #[inline]
 #[must_use]
-pub fn input_channels<const N: usize>() -> usize {
+pub const fn input_channels_for(depth: usize, planes: Planes) -> usize {
+    const PIECE_TYPE: usize = 3;
     const RESERVES: usize = 2; // stones + caps
     const TO_MOVE: usize = 1;
     const FCD: usize = 1;
-    2 * (stack_size::<N>() + RESERVES) + TO_MOVE + FCD
+    const SWAP: usize = 1;
+    const MOVE_LIMIT: usize = 1;
+    let base = 2 * (PIECE_TYPE + depth + RESERVES) + TO_MOVE + FCD;
+    match planes {
+        Planes::Base => base,
+        Planes::Extended => base + SWAP + MOVE_LIMIT,
+    }
+}
+
+#[inline]
+#[must_use]
+pub fn input_channels<const N: usize>() -> usize {
+    input_repr().channels::<N>()
+}
+
+/// Channel which is 1 when black is to move.
+#[inline]
+#[must_use]
+pub fn to_move_channel<const N: usize>() -> usize {
+    2 * stack_size::<N>() + 4
+}
+
+/// Channel with the progress towards the move limit of a variant, if the
+/// input has one.
+#[inline]
+#[must_use]
+pub fn move_limit_channel<const N: usize>() -> Option<usize> {
+    input_repr().move_limit_channel::<N>()
 }
 
 #[inline]
@@ -154,27 +487,71 @@ pub fn reserves_ratio<const N: usize>(reserves: Reserves<N>) -> (NotNan<f32>, No
 where
     Reserves<N>: Default,
 {
-    let Reserves {
-        stones: default_stones,
-        caps: default_caps,
-    } = Reserves::default();
+    ratio_of(reserves, Reserves::default())
+}
+
+/// Fraction of the starting stones and caps which are left.
+fn ratio_of<const N: usize>(
+    reserves: Reserves<N>,
+    start: Reserves<N>,
+) -> (NotNan<f32>, NotNan<f32>) {
     (
-        NotNan::new(f32::from(reserves.stones) / f32::from(default_stones)).unwrap_or_default(),
-        NotNan::new(f32::from(reserves.caps) / f32::from(default_caps)).unwrap_or_default(),
+        NotNan::new(f32::from(reserves.stones) / f32::from(start.stones)).unwrap_or_default(),
+        NotNan::new(f32::from(reserves.caps) / f32::from(start.caps)).unwrap_or_default(),
     )
 }
 
-/// Write 1s into the passed buffer to represent the game.
-/// Assumes the buffer is of correct size and filled with zeroes.
-fn game_repr<const N: usize, const HALF_KOMI: i8>(buffer: &mut [f32], game: &Game<N, HALF_KOMI>)
+/// Reserves of white and black at the start of the game: what is left plus
+/// what was placed, since pieces never leave the board.
+///
+/// This is the default for standard games, and the reserves of the variant
+/// for games with other reserve counts, so that those are encoded as a
+/// fraction of their own start.
+#[must_use]
+pub fn starting_reserves<const N: usize, const HALF_KOMI: i8>(
+    game: &Game<N, HALF_KOMI>,
+) -> (Reserves<N>, Reserves<N>)
 where
     Reserves<N>: Default,
 {
-    debug_assert_eq!(buffer.len(), input_size::<N>());
+    let (mut white, mut black) = (game.white_reserves, game.black_reserves);
+    for row in game.board.iter() {
+        for stack in row {
+            if stack.is_empty() {
+                continue;
+            }
+            for color in stack.colors() {
+                let reserves = if color == Color::White { &mut white } else { &mut black };
+                reserves.stones += 1;
+            }
+            if let Some((Piece::Cap, color)) = stack.top() {
+                let reserves = if color == Color::White { &mut white } else { &mut black };
+                reserves.stones -= 1;
+                reserves.caps += 1;
+            }
+        }
+    }
+    (white, black)
+}
+
+/// Write 1s into the passed buffer to represent the game, with `depth`
+/// pieces below the top of each stack, followed by the planes.
+/// Assumes the buffer is of correct size and filled with zeroes.
+fn game_repr<const N: usize, const HALF_KOMI: i8>(
+    buffer: &mut [f32],
+    game: &Game<N, HALF_KOMI>,
+    depth: usize,
+    planes: Planes,
+) where
+    Reserves<N>: Default,
+{
+    debug_assert_eq!(buffer.len(), input_channels_for(depth, planes) * N * N);
     debug_assert!(buffer.iter().all(|x| x.abs() <= f32::EPSILON));
 
+    let stack_size = 3 + depth;
+    let board_size = stack_size * N * N;
     let index = |row, column, channel| N * N * channel + N * row + column;
-    let offset = |color| usize::from(color != game.to_move) * stack_size::<N>();
+    let offset = |color| usize::from(color != game.to_move) * stack_size;
 
     for (y, row) in game.board.iter().enumerate() {
         for (x, stack) in row.enumerate() {
@@ -190,7 +567,7 @@ where
                 .reverse()
                 .into_iter()
                 .skip(1)
-                .take(stack_size::<N>() - 3)
+                .take(depth)
                 .enumerate()
             {
                 buffer[index(y, x, 3 + offset(color) + i)] = 1.0;
@@ -198,36 +575,74 @@ where
         }
     }
 
+    let (white_start, black_start) = starting_reserves(game);
     let (mine, other) = match game.to_move {
-        Color::White => (game.white_reserves, game.black_reserves),
-        Color::Black => (game.black_reserves, game.white_reserves),
+        Color::White => ((game.white_reserves, white_start), (game.black_reserves, black_start)),
+        Color::Black => ((game.black_reserves, black_start), (game.white_reserves, white_start)),
     };
-    let (stones, caps) = reserves_ratio(mine);
+    let (stones, caps) = ratio_of(mine.0, mine.1);
     for i in 0..N * N {
-        buffer[2 * board_size::<N>() + i] = stones.into();
-        buffer[2 * board_size::<N>() + N * N + i] = caps.into();
+        buffer[2 * board_size + i] = stones.into();
+        buffer[2 * board_size + N * N + i] = caps.into();
     }
 
-    let (stones, caps) = reserves_ratio(other);
+    let (stones, caps) = ratio_of(other.0, other.1);
     for i in 0..N * N {
-        buffer[2 * board_size::<N>() + 2 * N * N + i] = stones.into();
-        buffer[2 * board_size::<N>() + 3 * N * N + i] = caps.into();
+        buffer[2 * board_size + 2 * N * N + i] = stones.into();
+        buffer[2 * board_size + 3 * N * N + i] = caps.into();
     }
 
     if game.to_move == Color::Black {
         for i in 0..N * N {
-            buffer[2 * board_size::<N>() + 4 * N * N + i] = 1.0;
+            buffer[2 * board_size + 4 * N * N + i] = 1.0;
         }
     }
 
     let fcd = f32::from(game.board.flat_diff()) - f32::from(HALF_KOMI) / 2.0;
     let fcd_per_square = fcd / (N * N) as f32;
     for i in 0..N * N {
-        buffer[2 * board_size::<N>() + 5 * N * N + i] = fcd_per_square;
+        buffer[2 * board_size + 5 * N * N + i] = fcd_per_square;
     }
+
+    // In the opening swap, placements are of the opponent's pieces.
+    if planes == Planes::Extended && game.opening_swap() {
+        for i in 0..N * N {
+            buffer[2 * board_size + 6 * N * N + i] = 1.0;
+        }
+    }
+}
+
+/// Encode the game as the flat network input, with channels first.
+/// This is what [`game_to_tensor`] uses, for agents which do not go through
+/// `tch` (for example a network running in the browser).
+#[must_use]
+pub fn game_to_input<const N: usize, const HALF_KOMI: i8>(game: &Game<N, HALF_KOMI>) -> Vec<f32>
+where
+    Reserves<N>: Default,
+{
+    let mut buffer = vec![0.0; input_size::<N>()];
+    input_repr().encode(&mut buffer, game);
+    buffer
+}
+
+/// Encode a batch of games as one flat network input, in parallel.
+#[must_use]
+pub fn games_to_input<const N: usize, const HALF_KOMI: i8>(games: &[Game<N, HALF_KOMI>]) -> Vec<f32>
+where
+    Reserves<N>: Default,
+{
+    let size = input_size::<N>();
+    let repr = input_repr();
+    let mut buffer = vec![0.0; games.len() * size];
+    buffer
+        .par_chunks_mut(size)
+        .zip(games)
+        .for_each(|(buffer, game)| repr.encode(buffer, game));
+    buffer
 }
 
 /// Create a CUDA tensor which represent the game.
+#[cfg(feature = "tch")]
 pub fn game_to_tensor<const N: usize, const HALF_KOMI: i8>(
     game: &Game<N, HALF_KOMI>,
     device: Device,
@@ -235,8 +650,7 @@ pub fn game_to_tensor<const N: usize, const HALF_KOMI: i8>(
 where
     Reserves<N>: Default,
 {
-    let mut buffer = vec![0.0; input_size::<N>()];
-    game_repr(&mut buffer, game);
+    let buffer = game_to_input(game);
     // FIXME: Can we prevent this copy?
     Tensor::from_slice(&buffer)
         .reshape([1, input_channels::<N>() as i64, N as i64, N as i64])
@@ -245,18 +659,72 @@ where
 
 #[cfg(test)]
 mod tests {
-    use fast_tak::{takparse::Tps, Game};
+    use fast_tak::{takparse::Tps, Game, Reserves, Symmetry};
+    use rand::{rngs::StdRng, seq::IteratorRandom, SeedableRng};
+    #[cfg(feature = "tch")]
     use tch::Device;
 
-    use super::{game_repr, input_size};
+    use super::{
+        game_repr,
+        index_move,
+        input_channels_for,
+        move_index,
+        output_size,
+        policy_permutation,
+        InputRepr,
+        ParseInputReprError,
+        Planes,
+    };
+    use crate::search::env::Environment;
+    #[cfg(feature = "tch")]
     use crate::{
-        network::repr::{output_size, policy_tensor},
-        search::{
-            agent::{simple::Simple, Agent},
-            env::Environment,
-        },
+        network::repr::policy_tensor,
+        search::agent::{simple::Simple, Agent},
     };
 
+    fn check_policy_permutations<const N: usize, const HALF_KOMI: i8>()
+    where
+        Reserves<N>: Default,
+    {
+        for symmetry in 0..8 {
+            let permutation = policy_permutation::<N>(symmetry);
+            let mut sorted = permutation.to_vec();
+            sorted.sort_unstable();
+            assert!(sorted.into_iter().eq(0..output_size::<N>()), "{N}x{N}");
+        }
+        for index in 0..output_size::<N>() {
+            let inverse = index_move::<N>(index).map(|action| move_index::<N>(&action));
+            assert_eq!(inverse, Some(index), "{N}x{N}");
+        }
+        assert_eq!(index_move::<N>(output_size::<N>()), None);
+
+        let mut rng = StdRng::seed_from_u64(N as u64);
+        let mut game = Game::<N, HALF_KOMI>::default();
+        let mut actions = Vec::new();
+        while game.terminal().is_none() && game.ply < 60 {
+            game.populate_actions(&mut actions);
+            for action in &actions {
+                assert_eq!(index_move::<N>(move_index::<N>(action)).as_ref(), Some(action));
+                let images = Symmetry::<N>::symmetries(action);
+                for (symmetry, image) in images.iter().enumerate() {
+                    let index = policy_permutation::<N>(symmetry)[move_index::<N>(action)];
+                    assert_eq!(index, move_index::<N>(image), "{action} under {symmetry}");
+                }
+            }
+            game.step(actions.drain(..).choose(&mut rng).unwrap());
+        }
+    }
+
+    #[test]
+    fn policy_permutations() {
+        check_policy_permutations::<3, 0>();
+        check_policy_permutations::<4, 0>();
+        check_policy_permutations::<5, 4>();
+        check_policy_permutations::<6, 4>();
+        check_policy_permutations::<7, 4>();
+        check_policy_permutations::<8, 4>();
+    }
+
     #[test]
     fn starting_position() {
         let x = 1.0;
@@ -293,11 +761,20 @@ mod tests {
             o, o, o, o, o, o, o, o, o,
             // no komi, no pieces
             o, o, o, o, o, o, o, o, o,
+            // opening swap
+            x, x, x, x, x, x, x, x, x,
+            // no move limit
+            o, o, o, o, o, o, o, o, o,
         ];
-        assert_eq!(handmade.len(), input_size::<3>());
-        let mut buffer = vec![0.0; input_size::<3>()];
-        game_repr(&mut buffer, &Game::<3, 0>::default());
+        assert_eq!(handmade.len(), input_channels_for(6, Planes::Extended) * 9);
+        let mut buffer = vec![0.0; handmade.len()];
+        game_repr(&mut buffer, &Game::<3, 0>::default(), 6, Planes::Extended);
         assert_eq!(buffer, handmade);
+
+        // The base planes come first, so they mean the same without the others.
+        let mut base = vec![0.0; input_channels_for(6, Planes::Base) * 9];
+        game_repr(&mut base, &Game::<3, 0>::default(), 6, Planes::Base);
+        assert_eq!(base, handmade[..base.len()]);
     }
 
     #[test]
@@ -348,17 +825,39 @@ mod tests {
             x, x, x, x, x, x, x, x, x, x, x, x, x, x, x, x, x, x, x, x, x, x, x, x, x,
             // -3 fcd split over 25 squares
             d, d, d, d, d, d, d, d, d, d, d, d, d, d, d, d, d, d, d, d, d, d, d, d, d,
+            // no swap
+            o, o, o, o, o, o, o, o, o, o, o, o, o, o, o, o, o, o, o, o, o, o, o, o, o,
+            // no move limit
+            o, o, o, o, o, o, o, o, o, o, o, o, o, o, o, o, o, o, o, o, o, o, o, o, o,
         ];
-        assert_eq!(handmade.len(), input_size::<5>());
+        assert_eq!(handmade.len(), input_channels_for(10, Planes::Extended) * 25);
         let tps: Tps = "x2,1221,x,1S/2,2C,2,1,x/x,212,21C,2S,2/2211S,2,21,1,1/x2,221S,2,x 2 23"
             .parse()
             .unwrap();
         let game: Game<5, 4> = tps.into();
-        let mut buffer = vec![0.0; input_size::<5>()];
-        game_repr(&mut buffer, &game);
+        let mut buffer = vec![0.0; handmade.len()];
+        game_repr(&mut buffer, &game, 10, Planes::Extended);
         assert_eq!(buffer, handmade);
     }
 
+    #[test]
+    fn input_repr_round_trips() {
+        for repr in [InputRepr::default(), InputRepr {
+            stack_depth: Some(6),
+            planes: Planes::Extended,
+        }] {
+            assert_eq!(repr.to_string().parse(), Ok(repr));
+        }
+        assert_eq!("".parse(), Ok(InputRepr::default()));
+        assert_eq!("stack-depth=default".parse(), Ok(InputRepr::default()));
+        assert_eq!(
+            "stack-depth=six".parse::<InputRepr>(),
+            Err(ParseInputReprError::StackDepth("six".to_string()))
+        );
+        assert!("depth=6".parse::<InputRepr>().is_err());
+        assert!("planes=all".parse::<InputRepr>().is_err());
+    }
+
     #[test]
     #[allow(clippy::many_single_char_names)]
     fn tall_stack() {
@@ -399,16 +898,30 @@ mod tests {
             o, o, o, o, o, o, o, o, o,
             // +0.5 fcd split over 9
             d, d, d, d, d, d, d, d, d,
+            // no swap
+            o, o, o, o, o, o, o, o, o,
+            // no move limit
+            o, o, o, o, o, o, o, o, o,
         ];
-        assert_eq!(handmade.len(), input_size::<3>());
+        assert_eq!(handmade.len(), input_channels_for(6, Planes::Extended) * 9);
         let tps: Tps = "x3/x,21212112212S,x/x3 1 12".parse().unwrap();
         let game: Game<3, -1> = tps.into();
-        let mut buffer = vec![0.0; input_size::<3>()];
-        game_repr(&mut buffer, &game);
+        let mut buffer = vec![0.0; handmade.len()];
+        game_repr(&mut buffer, &game, 6, Planes::Extended);
         assert_eq!(buffer, handmade);
+
+        // With one piece below the top, the other layers are left out.
+        let mut shallow = vec![0.0; input_channels_for(1, Planes::Extended) * 9];
+        game_repr(&mut shallow, &game, 1, Planes::Extended);
+        let channels = (0..4).chain(9..13).chain(18..input_channels_for(6, Planes::Extended));
+        let expected: Vec<f32> = channels
+            .flat_map(|channel| handmade[9 * channel..9 * channel + 9].iter().copied())
+            .collect();
+        assert_eq!(shallow, expected);
     }
 
     #[test]
+    #[cfg(feature = "tch")]
     #[allow(clippy::many_single_char_names)]
     fn policy() {
         let tps: Tps = "2,1,x/1S,221,x/x,2S,2 1 6".parse().unwrap();
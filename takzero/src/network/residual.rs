@@ -1,6 +1,10 @@
-use std::ops::Add;
+use std::ops::{Add, Range};
 
-use tch::{nn, Tensor};
+use tch::{
+    nn::{self, ModuleT},
+    Kind,
+    Tensor,
+};
 
 // <https://medium.com/@bentou.pub/
 // alphazero-from-scratch-in-pytorch-for-the-game-of-chain-reaction-part-3-c3fbf0d6f986>
@@ -61,3 +65,152 @@ impl nn::ModuleT for ResidualBlock {
         self.model.forward_t(xs, train).add(xs).relu()
     }
 }
+
+/// What [`forward_checkpointed`] keeps of a stack of residual blocks for
+/// the backward pass: only the input of every segment of blocks, instead
+/// of every activation inside them.
+#[derive(Debug)]
+pub struct Checkpoints {
+    /// The input of every segment and its blocks, first segment first.
+    segments: Vec<(Tensor, Range<usize>)>,
+    /// The output of the last segment, which the rest of the network
+    /// starts from.
+    output: Tensor,
+    train: bool,
+}
+
+/// Run a stack of residual blocks without keeping the activations inside
+/// it, in `segments` segments of about the same number of blocks. Returns
+/// the output, and the checkpoints which [`Checkpoints::backward`] recomputes
+/// the activations of each segment from in the backward pass.
+///
+/// This trades compute for memory: the forward pass of the blocks runs
+/// twice, but only the inputs of the segments stay in memory between the
+/// forward and the backward pass. The recomputation also updates the
+/// running statistics of batch normalization in training, so they move
+/// twice per step.
+///
+/// # Panics
+///
+/// Panics if there are no blocks.
+#[must_use]
+pub fn forward_checkpointed(
+    blocks: &[ResidualBlock],
+    xs: &Tensor,
+    segments: usize,
+    train: bool,
+) -> (Tensor, Checkpoints) {
+    let segment_len = blocks.len().div_ceil(segments.clamp(1, blocks.len()));
+    let mut input = xs.shallow_clone();
+    let segments = (0..blocks.len())
+        .step_by(segment_len)
+        .map(|start| {
+            let range = start..blocks.len().min(start + segment_len);
+            // The first segment recomputes from the input itself, so that
+            // its gradient reaches the layers before the blocks.
+            let checkpoint = if start == 0 {
+                input.shallow_clone()
+            } else {
+                input.detach().set_requires_grad(true)
+            };
+            input = tch::no_grad(|| {
+                blocks[range.clone()]
+                    .iter()
+                    .fold(input.shallow_clone(), |x, block| block.forward_t(&x, train))
+            });
+            (checkpoint, range)
+        })
+        .collect();
+    let output = input.detach().set_requires_grad(true);
+    (output.shallow_clone(), Checkpoints {
+        segments,
+        output,
+        train,
+    })
+}
+
+impl Checkpoints {
+    /// Continue the backward pass from the output into the blocks, after
+    /// the backward pass of the loss reached the output. The activations of
+    /// every segment are recomputed, last segment first.
+    pub fn backward(self, blocks: &[ResidualBlock]) {
+        let mut gradient = self.output.grad();
+        for (input, range) in self.segments.into_iter().rev() {
+            if !gradient.defined() {
+                // The loss does not depend on the blocks.
+                return;
+            }
+            let output = blocks[range.clone()]
+                .iter()
+                .fold(input.shallow_clone(), |x, block| block.forward_t(&x, self.train));
+            // The gradient of this sum for every variable is the gradient
+            // of the loss through the output.
+            (output * &gradient).sum(Kind::Float).backward();
+            if range.start > 0 {
+                gradient = input.grad();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tch::{
+        nn::{self, ModuleT},
+        Device,
+        Kind,
+        Tensor,
+    };
+
+    use super::{forward_checkpointed, ResidualBlock};
+
+    #[test]
+    fn checkpointed_gradients_match() {
+        tch::manual_seed(0);
+        let vs = nn::VarStore::new(Device::Cpu);
+        let stem = nn::conv2d(vs.root() / "stem", 2, 8, 3, nn::ConvConfig {
+            padding: 1,
+            ..Default::default()
+        });
+        let blocks: Vec<_> = (0..5)
+            .map(|n| ResidualBlock::new(&(vs.root() / format!("res_block_{n}")), 8, 8))
+            .collect();
+        let xs = Tensor::randn([4, 2, 5, 5], (Kind::Float, Device::Cpu));
+
+        let gradients = |segments: Option<usize>| {
+            for mut variable in vs.trainable_variables() {
+                variable.zero_grad();
+            }
+            let x = stem.forward_t(&xs, true);
+            match segments {
+                Some(segments) => {
+                    let (output, checkpoints) = forward_checkpointed(&blocks, &x, segments, true);
+                    output.square().sum(Kind::Float).backward();
+                    checkpoints.backward(&blocks);
+                }
+                None => blocks
+                    .iter()
+                    .fold(x, |x, block| block.forward_t(&x, true))
+                    .square()
+                    .sum(Kind::Float)
+                    .backward(),
+            }
+            vs.trainable_variables()
+                .iter()
+                .map(|variable| variable.grad().copy())
+                .collect::<Vec<_>>()
+        };
+
+        let expected = gradients(None);
+        for segments in [1, 2, 5, 9] {
+            let actual = gradients(Some(segments));
+            assert!(
+                expected
+                    .iter()
+                    .zip(&actual)
+                    .all(|(a, b)| a.allclose(b, 1e-4, 1e-5, false)),
+                "{segments} segments"
+            );
+        }
+    }
+}
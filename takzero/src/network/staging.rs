@@ -0,0 +1,238 @@
+//! Staging of network inputs in pinned host memory.
+//!
+//! Positions are encoded in parallel straight into page-locked (pinned)
+//! buffers, and sent to the GPU with non-blocking copies, so the transfer is
+//! queued behind the work already on the device instead of stalling the host.
+//! Other than the transfer itself nothing is copied.
+//!
+//! `tch` does not expose CUDA streams, so the copies go on the current stream
+//! rather than a side stream: the copy of a batch waits for the inference of
+//! the batch before it. What overlaps is the encoding of a batch on the host
+//! with the transfer and inference of the one before, when several threads
+//! share the network. `bench` compares staged uploads with plain ones.
+//!
+//! There are two buffers which are used in turns. An upload returns a
+//! [`Staged`] input, which keeps its buffer from being written again until it
+//! is dropped, so it must live until the outputs of the batch were read. When
+//! both buffers are still in use, the input is copied from ordinary memory
+//! instead, which blocks until the copy is done.
+
+use std::{
+    ops::Deref,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
+
+use fast_tak::{Game, Reserves};
+use rayon::prelude::*;
+use tch::{Device, Kind, Tensor};
+
+use super::repr::{input_channels, input_repr};
+use crate::variant::VariantGame;
+
+#[derive(Debug)]
+pub struct Staging {
+    device: Device,
+    buffers: [Option<Tensor>; 2],
+    in_use: [Arc<AtomicBool>; 2],
+    next: usize,
+}
+
+/// An input on the device. While it lives, the pinned buffer which it is
+/// being copied from is not written again.
+#[derive(Debug)]
+pub struct Staged {
+    input: Tensor,
+    lease: Option<Arc<AtomicBool>>,
+}
+
+impl Deref for Staged {
+    type Target = Tensor;
+
+    fn deref(&self) -> &Self::Target {
+        &self.input
+    }
+}
+
+impl Drop for Staged {
+    fn drop(&mut self) {
+        if let Some(lease) = &self.lease {
+            lease.store(false, Ordering::Release);
+        }
+    }
+}
+
+impl Staging {
+    #[must_use]
+    pub fn new(device: Device) -> Self {
+        Self {
+            device,
+            buffers: [None, None],
+            in_use: [Arc::default(), Arc::default()],
+            next: 0,
+        }
+    }
+
+    /// Encode the games and start copying them to the device. Keep the
+    /// result until the outputs of the batch were read.
+    /// On the CPU the input is returned directly.
+    #[must_use]
+    pub fn upload<const N: usize, const HALF_KOMI: i8>(
+        &mut self,
+        games: &[Game<N, HALF_KOMI>],
+    ) -> Staged
+    where
+        Reserves<N>: Default,
+    {
+        let repr = input_repr();
+        self.upload_with::<N, _>(games, |buffer, game| repr.encode(buffer, game))
+    }
+
+    /// Like [`Staging::upload`], for games of a variant, which are encoded
+    /// with their progress towards the move limit.
+    #[must_use]
+    pub fn upload_variants<const N: usize, const HALF_KOMI: i8>(
+        &mut self,
+        games: &[VariantGame<N, HALF_KOMI>],
+    ) -> Staged
+    where
+        Reserves<N>: Default,
+    {
+        let repr = input_repr();
+        self.upload_with::<N, _>(games, |buffer, game| game.encode(repr, buffer))
+    }
+
+    /// Encode each item into its part of the input, which is filled with
+    /// zeroes, and start copying the input to the device.
+    fn upload_with<const N: usize, T: Sync>(
+        &mut self,
+        items: &[T],
+        encode: impl Fn(&mut [f32], &T) + Sync,
+    ) -> Staged {
+        let channels = input_channels::<N>();
+        let size = channels * N * N;
+        let shape = [items.len() as i64, channels as i64, N as i64, N as i64];
+        let encode_all = |input: &mut [f32]| {
+            input
+                .par_chunks_mut(size)
+                .zip(items)
+                .for_each(|(buffer, item)| encode(buffer, item));
+        };
+        let blocking = || {
+            let mut input = vec![0.0; items.len() * size];
+            encode_all(&mut input);
+            Staged {
+                input: Tensor::from_slice(&input).view(shape).to(self.device),
+                lease: None,
+            }
+        };
+        if !self.device.is_cuda() {
+            return blocking();
+        }
+        // Take a buffer which no copy is reading from, starting with the next.
+        let free = [self.next, 1 - self.next].into_iter().find(|&index| {
+            self.in_use[index]
+                .compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
+                .is_ok()
+        });
+        let Some(index) = free else {
+            return blocking();
+        };
+
+        let buffer = &mut self.buffers[index];
+        self.next = 1 - index;
+        // Pinned memory is slow to allocate, so buffers only grow.
+        let fits = buffer
+            .as_ref()
+            .is_some_and(|buffer| buffer.size()[0] >= shape[0] && buffer.size()[1..] == shape[1..]);
+        if !fits {
+            let pinned = Tensor::empty(shape, (Kind::Float, Device::Cpu)).pin_memory(self.device);
+            *buffer = Some(pinned);
+        }
+        let staged = buffer
+            .as_ref()
+            .expect("the buffer was just allocated")
+            .narrow(0, 0, shape[0]);
+        // SAFETY: The buffer is a contiguous float tensor on the host, which
+        // starts with room for the whole batch. It is not in use, so no copy
+        // is reading from it, and nothing else reads or writes it until the
+        // copy below.
+        let input = unsafe {
+            std::slice::from_raw_parts_mut(staged.data_ptr().cast::<f32>(), items.len() * size)
+        };
+        input.fill(0.0);
+        encode_all(input);
+        Staged {
+            input: staged.to_device_(self.device, Kind::Float, true, false),
+            lease: Some(self.in_use[index].clone()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use fast_tak::Game;
+    use tch::{Device, Tensor};
+
+    use super::Staging;
+    use crate::{
+        network::repr::{game_to_tensor, input_channels},
+        search::env::Environment,
+        variant::{variant_games_to_input, Variant, VariantGame},
+    };
+
+    #[test]
+    fn staged_input_matches_single_games() {
+        let device = Device::cuda_if_available();
+        let mut games = [Game::<5, 4>::default(), Game::default(), Game::default()];
+        games[1].play("a1".parse().unwrap()).unwrap();
+        games[2].play("e5".parse().unwrap()).unwrap();
+        let expected = Tensor::cat(
+            &games.iter().map(|game| game_to_tensor(game, device)).collect::<Vec<_>>(),
+            0,
+        );
+
+        let mut staging = Staging::new(device);
+        // Smaller batches reuse the buffers.
+        for batch in [3, 3, 2, 3] {
+            let staged = staging.upload(&games[..batch]);
+            assert!(staged.equal(&expected.narrow(0, 0, batch as i64)));
+        }
+    }
+
+    #[test]
+    fn outstanding_inputs_are_not_overwritten() {
+        let device = Device::cuda_if_available();
+        let mut games = [Game::<5, 4>::default(), Game::default(), Game::default()];
+        games[1].play("a1".parse().unwrap()).unwrap();
+        games[2].play("e5".parse().unwrap()).unwrap();
+
+        // Both buffers are in use by the time of the third upload.
+        let mut staging = Staging::new(device);
+        let staged: Vec<_> = games
+            .iter()
+            .map(|game| staging.upload(std::slice::from_ref(game)))
+            .collect();
+        for (staged, game) in staged.iter().zip(&games) {
+            assert!(staged.equal(&game_to_tensor(game, device)));
+        }
+    }
+
+    #[test]
+    fn staged_variants_match_their_input() {
+        let device = Device::cuda_if_available();
+        let variant = Variant {
+            max_plies: Some(20),
+            ..Variant::standard::<5>()
+        };
+        let mut games = [VariantGame::<5, 4>::new(variant), VariantGame::new(variant)];
+        games[1].step("a1".parse().unwrap());
+        let expected = Tensor::from_slice(&variant_games_to_input(&games))
+            .view([2, input_channels::<5>() as i64, 5, 5])
+            .to(device);
+
+        assert!(Staging::new(device).upload_variants(&games).equal(&expected));
+    }
+}
@@ -0,0 +1,258 @@
+//! Classification of openings, for statistics on how diverse the openings
+//! of self-play are.
+//!
+//! In the opening swap each player places a flat of the opponent, so the
+//! opening is named after those two placements: the region of each square
+//! (corner, edge, or center) and how they relate to each other, like
+//! `corner/corner opposite`. Openings which are symmetric to each other get
+//! the same name.
+
+use std::{collections::BTreeMap, fmt};
+
+use fast_tak::{
+    takparse::{Color, Piece},
+    Game,
+    Reserves,
+};
+
+use crate::{search::env::Environment, target::Replay};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Region {
+    Corner,
+    Edge,
+    Center,
+}
+
+impl Region {
+    /// Region of the square in column `x` and row `y`.
+    #[must_use]
+    pub const fn of<const N: usize>(x: usize, y: usize) -> Self {
+        let x_edge = x == 0 || x == N - 1;
+        let y_edge = y == 0 || y == N - 1;
+        match (x_edge, y_edge) {
+            (true, true) => Self::Corner,
+            (true, false) | (false, true) => Self::Edge,
+            (false, false) => Self::Center,
+        }
+    }
+}
+
+impl fmt::Display for Region {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Corner => "corner",
+            Self::Edge => "edge",
+            Self::Center => "center",
+        })
+    }
+}
+
+/// How the two placements of the opening swap relate to each other.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Relation {
+    /// Next to each other on a row or column.
+    Adjacent,
+    /// Next to each other on a diagonal.
+    Diagonal,
+    /// Mirrored through the center of the board.
+    Opposite,
+    /// On the same row or column, but not next to each other.
+    Aligned,
+    Apart,
+}
+
+impl Relation {
+    #[must_use]
+    pub const fn of<const N: usize>(first: (usize, usize), second: (usize, usize)) -> Self {
+        let dx = first.0.abs_diff(second.0);
+        let dy = first.1.abs_diff(second.1);
+        if first.0 + second.0 == N - 1 && first.1 + second.1 == N - 1 {
+            Self::Opposite
+        } else if dx + dy == 1 {
+            Self::Adjacent
+        } else if dx == 1 && dy == 1 {
+            Self::Diagonal
+        } else if dx == 0 || dy == 0 {
+            Self::Aligned
+        } else {
+            Self::Apart
+        }
+    }
+}
+
+impl fmt::Display for Relation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Adjacent => "adjacent",
+            Self::Diagonal => "diagonal",
+            Self::Opposite => "opposite",
+            Self::Aligned => "aligned",
+            Self::Apart => "apart",
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Opening {
+    /// Region of the flat which white placed for black.
+    pub first: Region,
+    /// Region of the flat which black placed for white.
+    pub second: Region,
+    pub relation: Relation,
+}
+
+impl Opening {
+    /// Classify the position right after the opening swap, or `None` for
+    /// any other ply.
+    #[must_use]
+    pub fn classify<const N: usize, const HALF_KOMI: i8>(game: &Game<N, HALF_KOMI>) -> Option<Self>
+    where
+        Reserves<N>: Default,
+    {
+        if game.ply != 2 {
+            return None;
+        }
+        let (mut first, mut second) = (None, None);
+        for (y, row) in game.board.iter().enumerate() {
+            for (x, stack) in row.enumerate() {
+                match stack.top() {
+                    Some((Piece::Flat, Color::Black)) => first = Some((x, y)),
+                    Some((Piece::Flat, Color::White)) => second = Some((x, y)),
+                    _ => {}
+                }
+            }
+        }
+        let (first, second) = (first?, second?);
+        Some(Self {
+            first: Region::of::<N>(first.0, first.1),
+            second: Region::of::<N>(second.0, second.1),
+            relation: Relation::of::<N>(first, second),
+        })
+    }
+
+    /// Classify the opening of a replay, or `None` if it starts after the
+    /// opening swap or ends before it.
+    #[must_use]
+    pub fn of_replay<const N: usize, const HALF_KOMI: i8>(
+        replay: &Replay<Game<N, HALF_KOMI>>,
+    ) -> Option<Self>
+    where
+        Reserves<N>: Default,
+    {
+        let mut env = replay.env.clone();
+        let mut actions = replay.actions.iter();
+        while env.ply < 2 {
+            env.step(*actions.next()?);
+        }
+        Self::classify(&env)
+    }
+}
+
+impl fmt::Display for Opening {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{} {}", self.first, self.second, self.relation)
+    }
+}
+
+/// Results of the finished games with one opening.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct OpeningStats {
+    pub games: usize,
+    pub white_wins: usize,
+    pub black_wins: usize,
+    pub draws: usize,
+}
+
+impl OpeningStats {
+    /// Count a finished game, with the winner or `None` for a draw.
+    pub fn add(&mut self, winner: Option<Color>) {
+        self.games += 1;
+        match winner {
+            Some(Color::White) => self.white_wins += 1,
+            Some(Color::Black) => self.black_wins += 1,
+            None => self.draws += 1,
+        }
+    }
+
+    /// Score of white (wins + draws / 2 over games).
+    #[must_use]
+    pub fn white_score(&self) -> f64 {
+        (self.white_wins as f64 + self.draws as f64 / 2.0) / self.games.max(1) as f64
+    }
+}
+
+/// Results of the games of each opening.
+pub type OpeningTable = BTreeMap<Opening, OpeningStats>;
+
+/// Effective number of openings: the exponential of the entropy of how
+/// often each opening was played.
+///
+/// It is the number of openings when all are played equally often, and
+/// lower when a few dominate. It is 0 without any games.
+#[must_use]
+pub fn diversity(counts: impl IntoIterator<Item = usize>) -> f64 {
+    let counts: Vec<_> = counts.into_iter().filter(|count| *count > 0).collect();
+    if counts.is_empty() {
+        return 0.0;
+    }
+    let total = counts.iter().sum::<usize>() as f64;
+    let entropy: f64 = counts
+        .iter()
+        .map(|count| {
+            let p = *count as f64 / total;
+            -p * p.ln()
+        })
+        .sum();
+    entropy.exp()
+}
+
+#[cfg(test)]
+mod tests {
+    use fast_tak::{takparse::Color, Game};
+
+    use super::{diversity, Opening, OpeningStats, Region, Relation};
+    use crate::target::Replay;
+
+    fn opening(moves: &str) -> Option<Opening> {
+        let replay: Replay<Game<5, 4>> =
+            format!("[TPS \"x5/x5/x5/x5/x5 1 1\"] {moves}").parse().unwrap();
+        Opening::of_replay(&replay)
+    }
+
+    #[test]
+    fn classification() {
+        let corners = opening("a1 e5 c3").unwrap();
+        assert_eq!(corners.to_string(), "corner/corner opposite");
+        // Symmetric openings get the same name.
+        assert_eq!(opening("e1 a5"), Some(corners));
+        assert_eq!(opening("a1 a5").map(|opening| opening.relation), Some(Relation::Aligned));
+        assert_eq!(opening("a1 b1").unwrap().to_string(), "corner/edge adjacent");
+        assert_eq!(opening("c3 d4").unwrap().to_string(), "center/center diagonal");
+        assert_eq!(opening("b1 d2").unwrap().to_string(), "edge/center apart");
+        assert_eq!(opening("a1"), None);
+        assert_eq!(Region::of::<5>(4, 2), Region::Edge);
+
+        // Games which start right after the opening swap are classified by
+        // the position, but not those which start later.
+        let replay: Replay<Game<5, 4>> = "[TPS \"1,x4/x5/x5/x5/x4,2 1 2\"] c3".parse().unwrap();
+        assert_eq!(Opening::of_replay(&replay), Some(corners));
+        let replay: Replay<Game<5, 4>> = "[TPS \"1,x4/x5/x5/x5/x4,2 2 2\"] c3".parse().unwrap();
+        assert_eq!(Opening::of_replay(&replay), None);
+    }
+
+    #[test]
+    fn statistics() {
+        let mut stats = OpeningStats::default();
+        for winner in [Some(Color::White), None, Some(Color::Black), Some(Color::White)] {
+            stats.add(winner);
+        }
+        assert_eq!(stats.games, 4);
+        assert!((stats.white_score() - 0.625).abs() < 1e-9);
+
+        assert!((diversity([5, 5, 5, 0]) - 3.0).abs() < 1e-9);
+        assert!(diversity([100, 1, 1]) < 1.2);
+        assert!((diversity([7]) - 1.0).abs() < 1e-9);
+        assert!(diversity([]).abs() < 1e-9);
+    }
+}
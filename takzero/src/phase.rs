@@ -0,0 +1,317 @@
+//! Sampling targets stratified by game phase.
+//!
+//! Every game has an opening but many end early, so a buffer of targets
+//! holds far more openings than endgames. [`PhaseSampler`] draws a batch with
+//! fixed [`Proportions`] of openings, middlegames, and endgames instead, where
+//! the phase of a position is decided by a [`PhaseSplit`].
+
+use std::{num::ParseFloatError, str::FromStr};
+
+use fast_tak::{Game, Reserves};
+use rand::Rng;
+use thiserror::Error;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Phase {
+    Opening = 0,
+    Middlegame = 1,
+    Endgame = 2,
+}
+
+impl Phase {
+    pub const ALL: [Self; 3] = [Self::Opening, Self::Middlegame, Self::Endgame];
+}
+
+/// Where the middlegame and the endgame start.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PhaseSplit {
+    /// By the number of plies played.
+    Ply { middlegame: u16, endgame: u16 },
+    /// By the fraction of stones left in the smaller of the two reserves,
+    /// from 1 at the start of the game down to 0.
+    Reserves { middlegame: f32, endgame: f32 },
+}
+
+impl PhaseSplit {
+    #[must_use]
+    pub fn phase<const N: usize, const HALF_KOMI: i8>(&self, game: &Game<N, HALF_KOMI>) -> Phase
+    where
+        Reserves<N>: Default,
+    {
+        match *self {
+            Self::Ply { middlegame, endgame } => {
+                if game.ply >= endgame {
+                    Phase::Endgame
+                } else if game.ply >= middlegame {
+                    Phase::Middlegame
+                } else {
+                    Phase::Opening
+                }
+            }
+            Self::Reserves { middlegame, endgame } => {
+                let stones = game.white_reserves.stones.min(game.black_reserves.stones);
+                let left = f32::from(stones) / f32::from(Reserves::<N>::default().stones);
+                if left <= endgame {
+                    Phase::Endgame
+                } else if left <= middlegame {
+                    Phase::Middlegame
+                } else {
+                    Phase::Opening
+                }
+            }
+        }
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum ParsePhaseError {
+    #[error("phase split `{0}` is not in the format `ply:10,40` or `reserves:0.8,0.3`")]
+    Split(String),
+    #[error("proportions `{0}` are not three non-negative numbers like `0.3,0.4,0.3`")]
+    Proportions(String),
+    #[error("{0}")]
+    Float(#[from] ParseFloatError),
+}
+
+impl FromStr for PhaseSplit {
+    type Err = ParsePhaseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let format = || ParsePhaseError::Split(s.to_string());
+        let (kind, bounds) = s.trim().split_once(':').ok_or_else(format)?;
+        let (middlegame, endgame) = bounds.split_once(',').ok_or_else(format)?;
+        let (middlegame, endgame) = (middlegame.trim(), endgame.trim());
+        match kind {
+            "ply" => Ok(Self::Ply {
+                middlegame: middlegame.parse().map_err(|_| format())?,
+                endgame: endgame.parse().map_err(|_| format())?,
+            }),
+            "reserves" => Ok(Self::Reserves {
+                middlegame: middlegame.parse()?,
+                endgame: endgame.parse()?,
+            }),
+            _ => Err(format()),
+        }
+    }
+}
+
+/// Relative weights of openings, middlegames, and endgames in a draw.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Proportions(pub [f64; 3]);
+
+impl FromStr for Proportions {
+    type Err = ParsePhaseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let weights = s
+            .split(',')
+            .map(|weight| weight.trim().parse())
+            .collect::<Result<Vec<f64>, _>>()?;
+        match weights[..] {
+            [opening, middlegame, endgame]
+                if weights.iter().all(|w| *w >= 0.0) && weights.iter().sum::<f64>() > 0.0 =>
+            {
+                Ok(Self([opening, middlegame, endgame]))
+            }
+            _ => Err(ParsePhaseError::Proportions(s.to_string())),
+        }
+    }
+}
+
+impl Proportions {
+    /// How many of `n` draws go to each phase, given how many items of each
+    /// phase are available. Draws a phase cannot fill go to the other phases
+    /// in proportion, and only when no phase with a weight has items left do
+    /// phases without a weight get draws, so that a draw is never short if
+    /// there are enough items.
+    #[must_use]
+    pub fn quotas(&self, n: usize, available: [usize; 3]) -> [usize; 3] {
+        let mut quotas = [0; 3];
+        let mut remaining = n.min(available.iter().sum());
+        while remaining > 0 {
+            let open = |i: usize| quotas[i] < available[i];
+            let weighted = (0..3).any(|i| open(i) && self.0[i] > 0.0);
+            let weights: [f64; 3] = std::array::from_fn(|i| match (open(i), weighted) {
+                (false, _) => 0.0,
+                (true, true) => self.0[i],
+                (true, false) => 1.0,
+            });
+            let total: f64 = weights.iter().sum();
+            let mut handed = 0;
+            for (i, weight) in weights.iter().enumerate() {
+                #[allow(clippy::cast_sign_loss)]
+                let share = (remaining as f64 * weight / total).floor() as usize;
+                let share = share.min(available[i] - quotas[i]);
+                quotas[i] += share;
+                handed += share;
+            }
+            if handed == 0 {
+                // Fewer draws remain than phases share them, so the one with
+                // the largest weight gets the next one.
+                let i = (0..3)
+                    .max_by(|&a, &b| weights[a].total_cmp(&weights[b]))
+                    .unwrap_or_default();
+                quotas[i] += 1;
+                handed = 1;
+            }
+            remaining -= handed;
+        }
+        quotas
+    }
+}
+
+/// Where the items of each phase are in a buffer, so that a draw does not
+/// have to go over the whole buffer.
+#[derive(Debug, Clone, Default)]
+struct PhaseIndex {
+    /// Indices into the buffer of the items of each phase.
+    indices: [Vec<usize>; 3],
+    /// Phase of every item in the buffer, and its place in `indices`.
+    slots: Vec<(Phase, usize)>,
+}
+
+impl PhaseIndex {
+    /// Index the items appended to the buffer since the last update, or all
+    /// of them again if the buffer shrank.
+    fn update<T>(&mut self, items: &[T], phase: impl Fn(&T) -> Phase) {
+        if items.len() < self.slots.len() {
+            *self = Self::default();
+        }
+        for (i, item) in items.iter().enumerate().skip(self.slots.len()) {
+            let phase = phase(item);
+            let indices = &mut self.indices[phase as usize];
+            self.slots.push((phase, indices.len()));
+            indices.push(i);
+        }
+    }
+
+    fn available(&self) -> [usize; 3] {
+        std::array::from_fn(|i| self.indices[i].len())
+    }
+
+    /// Remove a random item of the phase, which must have one, from the
+    /// buffer. The last item of the buffer takes its place.
+    fn remove<T>(&mut self, items: &mut Vec<T>, phase: Phase, rng: &mut impl Rng) -> T {
+        let indices = &mut self.indices[phase as usize];
+        let place = rng.gen_range(0..indices.len());
+        let index = indices.swap_remove(place);
+        if let Some(&moved) = indices.get(place) {
+            self.slots[moved].1 = place;
+        }
+        self.slots.swap_remove(index);
+        if let Some(&(phase, place)) = self.slots.get(index) {
+            self.indices[phase as usize][place] = index;
+        }
+        items.swap_remove(index)
+    }
+}
+
+/// Draws batches of positions with fixed proportions of each phase from one
+/// buffer.
+///
+/// The sampler keeps the phase of every item of the buffer. Between draws
+/// the buffer must only be appended to or shrunk, which makes the sampler
+/// index it again; reordering it (for example by shuffling, which random
+/// draws do not need) goes unnoticed.
+#[derive(Debug, Clone)]
+pub struct PhaseSampler {
+    pub split: PhaseSplit,
+    pub proportions: Proportions,
+    index: PhaseIndex,
+}
+
+impl PhaseSampler {
+    #[must_use]
+    pub fn new(split: PhaseSplit, proportions: Proportions) -> Self {
+        Self {
+            split,
+            proportions,
+            index: PhaseIndex::default(),
+        }
+    }
+
+    /// Remove `n` random items (or all of them, if there are fewer) from the
+    /// buffer, with phases in the proportions of the sampler, where the
+    /// phase of an item is that of its position.
+    pub fn draw<T, const N: usize, const HALF_KOMI: i8>(
+        &mut self,
+        items: &mut Vec<T>,
+        n: usize,
+        rng: &mut impl Rng,
+        env: impl Fn(&T) -> &Game<N, HALF_KOMI>,
+    ) -> Vec<T>
+    where
+        Reserves<N>: Default,
+    {
+        let split = self.split;
+        self.index.update(items, |item| split.phase(env(item)));
+        let quotas = self.proportions.quotas(n, self.index.available());
+        let mut drawn = Vec::with_capacity(quotas.iter().sum());
+        for (phase, quota) in Phase::ALL.into_iter().zip(quotas) {
+            for _ in 0..quota {
+                drawn.push(self.index.remove(items, phase, rng));
+            }
+        }
+        drawn
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use fast_tak::Game;
+    use rand::{rngs::StdRng, SeedableRng};
+
+    use super::{Phase, PhaseSampler, PhaseSplit, Proportions};
+    use crate::search::env::Environment;
+
+    #[test]
+    fn stratified_draws() {
+        let split: PhaseSplit = "ply:2,4".parse().unwrap();
+        let mut games = Vec::new();
+        let mut game = Game::<3, 0>::default();
+        let mut actions = Vec::new();
+        for _ in 0..6 {
+            games.push(game.clone());
+            game.populate_actions(&mut actions);
+            game.step(actions.drain(..).next().unwrap());
+        }
+        let phases: Vec<_> = games.iter().map(|game| split.phase(game)).collect();
+        assert_eq!(phases, [
+            Phase::Opening,
+            Phase::Opening,
+            Phase::Middlegame,
+            Phase::Middlegame,
+            Phase::Endgame,
+            Phase::Endgame,
+        ]);
+        let reserves: PhaseSplit = "reserves:0.9,0.5".parse().unwrap();
+        assert_eq!(reserves.phase(&games[0]), Phase::Opening);
+        let reserves: PhaseSplit = "reserves:1,0.5".parse().unwrap();
+        assert_eq!(reserves.phase(&games[0]), Phase::Middlegame);
+        assert!("moves:1,2".parse::<PhaseSplit>().is_err());
+
+        let proportions: Proportions = "1,1,2".parse().unwrap();
+        assert_eq!(proportions.quotas(8, [10, 10, 10]), [2, 2, 4]);
+        assert_eq!(proportions.quotas(8, [10, 10, 1]), [3, 4, 1]);
+        assert_eq!(proportions.quotas(8, [2, 2, 2]), [2, 2, 2]);
+        assert_eq!(Proportions([0.0, 0.0, 1.0]).quotas(3, [5, 5, 1]).iter().sum::<usize>(), 3);
+        assert!("1,2".parse::<Proportions>().is_err());
+        assert!("0,0,0".parse::<Proportions>().is_err());
+
+        let mut rng = StdRng::seed_from_u64(0);
+        let mut sampler = PhaseSampler::new(split, Proportions([0.0, 1.0, 1.0]));
+        let mut drawn = sampler.draw(&mut games, 4, &mut rng, |game| game);
+        drawn.sort_by_key(|game| game.ply);
+        assert_eq!(drawn.iter().map(|game| game.ply).collect::<Vec<_>>(), [2, 3, 4, 5]);
+        assert_eq!(games.iter().map(|game| game.ply).collect::<Vec<_>>(), [0, 1]);
+
+        // Items appended after a draw are indexed by the next one.
+        games.extend(drawn);
+        let drawn = sampler.draw(&mut games, 2, &mut rng, |game| game);
+        assert!(drawn.iter().all(|game| game.ply >= 2));
+        let mut plies: Vec<_> = games.iter().map(|game| game.ply).collect();
+        plies.sort_unstable();
+        assert_eq!(plies[..2], [0, 1]);
+        assert_eq!(plies.len(), 4);
+    }
+}
@@ -0,0 +1,130 @@
+//! Random but plausible positions, for sanity tests of networks, benchmark
+//! suites, and calibration of the uncertainty.
+//!
+//! Positions are found by playing random moves from a random opening and
+//! rejecting those outside of the [`Constraints`]. A short search with the
+//! heuristic [`Simple`] agent rejects positions where one side is clearly
+//! winning, which random moves produce often.
+
+use std::ops::RangeInclusive;
+
+use fast_tak::{Game, Reserves};
+use rand::{seq::IteratorRandom, Rng};
+
+use crate::search::{agent::simple::Simple, env::Environment, node::Node, DISCOUNT_FACTOR};
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Constraints {
+    /// Plies from the start of the game.
+    pub plies: RangeInclusive<u16>,
+    /// Pieces on the board, counting both players.
+    pub pieces: RangeInclusive<usize>,
+    /// Largest absolute value of the quick search for the player to move.
+    pub max_imbalance: f32,
+    /// Visits of the quick search, or 0 to skip it.
+    pub visits: u32,
+}
+
+impl Default for Constraints {
+    fn default() -> Self {
+        Self {
+            plies: 4..=40,
+            pieces: 0..=usize::MAX,
+            max_imbalance: 0.5,
+            visits: 64,
+        }
+    }
+}
+
+impl Constraints {
+    /// Whether the position is within the constraints.
+    #[must_use]
+    pub fn allow<const N: usize, const HALF_KOMI: i8>(&self, game: &Game<N, HALF_KOMI>) -> bool
+    where
+        Reserves<N>: Default,
+    {
+        if game.terminal().is_some()
+            || !self.plies.contains(&game.ply)
+            || !self.pieces.contains(&pieces_on_board(game))
+        {
+            return false;
+        }
+        if self.visits == 0 {
+            return true;
+        }
+        let mut root = Node::default();
+        let mut game = game.clone();
+        for _ in 0..self.visits {
+            root.simulate_in_place(&Simple, &mut game, 0.0, DISCOUNT_FACTOR);
+        }
+        let evaluation = root.evaluation();
+        !evaluation.is_known() && f32::from(evaluation).abs() <= self.max_imbalance
+    }
+}
+
+/// Pieces which both players have placed.
+fn pieces_on_board<const N: usize, const HALF_KOMI: i8>(game: &Game<N, HALF_KOMI>) -> usize
+where
+    Reserves<N>: Default,
+{
+    let Reserves { stones, caps } = Reserves::<N>::default();
+    [game.white_reserves, game.black_reserves]
+        .into_iter()
+        .map(|reserves| usize::from(stones + caps - reserves.stones - reserves.caps))
+        .sum()
+}
+
+/// A random position within the constraints, or `None` if none was found
+/// in this many attempts.
+pub fn random_position<const N: usize, const HALF_KOMI: i8>(
+    rng: &mut impl Rng,
+    constraints: &Constraints,
+    attempts: usize,
+) -> Option<Game<N, HALF_KOMI>>
+where
+    Reserves<N>: Default,
+{
+    let mut actions = Vec::new();
+    (0..attempts).find_map(|_| {
+        let target = constraints.plies.clone().choose(rng)?;
+        let mut game = Game::new_opening(rng, &mut actions);
+        while game.ply < target && game.terminal().is_none() {
+            game.populate_actions(&mut actions);
+            let action = actions.drain(..).choose(rng)?;
+            game.step(action);
+        }
+        constraints.allow(&game).then_some(game)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::{rngs::StdRng, SeedableRng};
+
+    use super::{pieces_on_board, random_position, Constraints};
+    use crate::search::env::Environment;
+
+    #[test]
+    fn positions_within_constraints() {
+        let mut rng = StdRng::seed_from_u64(0);
+        let constraints = Constraints {
+            plies: 10..=20,
+            pieces: 8..=20,
+            ..Constraints::default()
+        };
+        for _ in 0..10 {
+            let game = random_position::<5, 4>(&mut rng, &constraints, 100)
+                .expect("a position should be found");
+            assert!(game.terminal().is_none());
+            assert!(constraints.plies.contains(&game.ply));
+            assert!(constraints.pieces.contains(&pieces_on_board(&game)));
+        }
+
+        let impossible = Constraints {
+            plies: 4..=6,
+            pieces: 20..=30,
+            ..Constraints::default()
+        };
+        assert!(random_position::<5, 4>(&mut rng, &impossible, 10).is_none());
+    }
+}
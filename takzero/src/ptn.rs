@@ -0,0 +1,435 @@
+//! Importing games from PTN files.
+//!
+//! Unlike [`Replay`]'s `FromStr`, which only reads the single-line format
+//! written by self-play, this accepts ordinary PTN as exported by `PlayTak`
+//! or written by hand: an optional TPS start position, a komi tag, and the
+//! game result (including results by resignation or time, which cannot be
+//! derived from the moves). Games can be turned into supervised [`Target`]s
+//! with a one-hot policy on the played move and the discounted outcome as
+//! the value. Replays can also be written back as PTN and shared as
+//! [ptn.ninja](https://ptn.ninja) links.
+
+use std::fmt::Write;
+
+use fast_tak::{
+    takparse::{Color, ParsePtnError, Ptn, Tps},
+    Game,
+    PlayError,
+    Reserves,
+};
+use ordered_float::NotNan;
+use thiserror::Error;
+
+use crate::{
+    search::{
+        env::{Environment, Terminal, TerminalReason},
+        eval::Eval,
+    },
+    target::{Replay, Target},
+};
+
+/// Outcome of a game, from white's perspective.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Outcome {
+    WhiteWin,
+    BlackWin,
+    Draw,
+}
+
+impl Outcome {
+    /// Parse a PTN result like `R-0`, `0-F`, `1-0`, or `1/2-1/2`.
+    /// Returns `None` for unfinished games (`0-0`) and unknown results.
+    #[must_use]
+    pub fn from_result(result: &str) -> Option<Self> {
+        match result {
+            "R-0" | "F-0" | "1-0" => Some(Self::WhiteWin),
+            "0-R" | "0-F" | "0-1" => Some(Self::BlackWin),
+            "1/2-1/2" => Some(Self::Draw),
+            _ => None,
+        }
+    }
+
+    /// The outcome of a finished game, given the terminal state for the
+    /// player to move.
+    #[must_use]
+    pub const fn from_terminal(terminal: Terminal, to_move: Color) -> Self {
+        match (terminal, to_move) {
+            (Terminal::Win, Color::White) | (Terminal::Loss, Color::Black) => Self::WhiteWin,
+            (Terminal::Win, Color::Black) | (Terminal::Loss, Color::White) => Self::BlackWin,
+            (Terminal::Draw, _) => Self::Draw,
+        }
+    }
+
+    /// The result as written in PTN.
+    #[must_use]
+    pub const fn result(self) -> &'static str {
+        match self {
+            Self::WhiteWin => "1-0",
+            Self::BlackWin => "0-1",
+            Self::Draw => "1/2-1/2",
+        }
+    }
+
+    /// The result as written in PTN, with `R` or `F` for wins by road or by
+    /// flats when the reason is known.
+    #[must_use]
+    pub const fn result_with_reason(self, reason: Option<TerminalReason>) -> &'static str {
+        match (self, reason) {
+            (Self::WhiteWin, Some(TerminalReason::Road)) => "R-0",
+            (Self::BlackWin, Some(TerminalReason::Road)) => "0-R",
+            (Self::WhiteWin, Some(TerminalReason::Flats | TerminalReason::OutOfPieces)) => "F-0",
+            (Self::BlackWin, Some(TerminalReason::Flats | TerminalReason::OutOfPieces)) => "0-F",
+            (outcome, _) => outcome.result(),
+        }
+    }
+
+    /// The outcome from the perspective of the given player.
+    #[must_use]
+    pub const fn terminal(self, color: Color) -> Terminal {
+        match (self, color) {
+            (Self::WhiteWin, Color::White) | (Self::BlackWin, Color::Black) => Terminal::Win,
+            (Self::WhiteWin, Color::Black) | (Self::BlackWin, Color::White) => Terminal::Loss,
+            (Self::Draw, _) => Terminal::Draw,
+        }
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum ImportPtnError {
+    #[error("{0}")]
+    Ptn(#[from] ParsePtnError),
+    #[error("the game is for size {0}")]
+    WrongSize(usize),
+    #[error("the game has a half komi of {0}")]
+    WrongKomi(i8),
+    #[error("invalid komi `{0}`")]
+    InvalidKomi(String),
+    #[error("invalid action")]
+    Invalid(#[from] PlayError),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct PtnGame<const N: usize, const HALF_KOMI: i8>
+where
+    Reserves<N>: Default,
+{
+    pub replay: Replay<Game<N, HALF_KOMI>>,
+    /// The final result, taken from the position if it is terminal and from
+    /// the result tag or move list otherwise.
+    pub outcome: Option<Outcome>,
+}
+
+/// Find the value of a tag like `[Komi "2"]`, whether tags are on lines of
+/// their own as in PTN or all on one line as in replays.
+pub(crate) fn tag<'a>(s: &'a str, name: &str) -> Option<&'a str> {
+    s.split('[').skip(1).find_map(|tag| {
+        tag.split_once(']')?
+            .0
+            .trim()
+            .strip_prefix(name)?
+            .trim()
+            .strip_prefix('"')?
+            .strip_suffix('"')
+    })
+}
+
+/// Parse a komi like `2` or `0.5` into half komi.
+pub(crate) fn parse_half_komi(komi: &str) -> Option<i8> {
+    komi.parse::<f32>()
+        .ok()
+        .map(|komi| komi * 2.0)
+        .filter(|half_komi| half_komi.fract() == 0.0 && half_komi.abs() <= 127.0)
+        .map(|half_komi| half_komi as i8)
+}
+
+/// The result at the end of the move list, if it is given there.
+fn trailing_result(s: &str) -> Option<&str> {
+    s.lines()
+        .rev()
+        .find(|line| !line.trim().is_empty() && !line.trim().starts_with('['))?
+        .split_whitespace()
+        .last()
+}
+
+impl<const N: usize, const HALF_KOMI: i8> std::str::FromStr for PtnGame<N, HALF_KOMI>
+where
+    Reserves<N>: Default,
+{
+    type Err = ImportPtnError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(size) = tag(s, "Size").and_then(|size| size.parse().ok()) {
+            if size != N {
+                return Err(ImportPtnError::WrongSize(size));
+            }
+        }
+        // A missing komi tag means no komi.
+        let komi = tag(s, "Komi").unwrap_or("0");
+        let half_komi =
+            parse_half_komi(komi).ok_or_else(|| ImportPtnError::InvalidKomi(komi.to_string()))?;
+        if half_komi != HALF_KOMI {
+            return Err(ImportPtnError::WrongKomi(half_komi));
+        }
+
+        let ptn: Ptn = s.parse()?;
+        let env: Game<N, HALF_KOMI> = ptn.tps().map_or_else(Game::default, Into::into);
+        let mut replay = Replay::new(env.clone());
+        let mut end = env;
+        for &action in ptn.moves() {
+            end.play(action)?;
+            replay.push(action);
+        }
+
+        let terminal = end.terminal();
+        let outcome = terminal
+            .map(|terminal| Outcome::from_terminal(terminal, end.to_move))
+            .or_else(|| {
+                tag(s, "Result")
+                    .or_else(|| trailing_result(s))
+                    .and_then(Outcome::from_result)
+            });
+        if terminal.is_none() {
+            replay.adjudicated = outcome.map(|outcome| outcome.terminal(end.to_move));
+        }
+
+        Ok(Self { replay, outcome })
+    }
+}
+
+impl<const N: usize, const HALF_KOMI: i8> PtnGame<N, HALF_KOMI>
+where
+    Reserves<N>: Default,
+{
+    /// Create supervised targets for every position in the game: the policy
+    /// is one-hot on the played move and the value is the discounted outcome.
+    /// Returns `None` if the outcome of the game is not known.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the replay contains an illegal action.
+    #[must_use]
+    pub fn targets(&self) -> Option<Vec<Target<Game<N, HALF_KOMI>>>> {
+        let positions: Vec<_> = self.replay.positions().collect();
+        let mut last = self.replay.env.clone();
+        for &action in &self.replay.actions {
+            last.step(action);
+        }
+        let mut value = Eval::from(self.outcome?.terminal(last.to_move));
+
+        let mut targets = Vec::with_capacity(positions.len());
+        let mut actions = Vec::new();
+        for (env, played) in positions.into_iter().rev() {
+            value = value.negate();
+            targets.push(Target {
+                policy: one_hot(&env, played, &mut actions),
+                env,
+                value: f32::from(value),
+                ube: 0.0,
+                weight: None,
+                move_limit: None,
+                model_steps: None,
+            });
+        }
+        targets.reverse();
+        Some(targets)
+    }
+}
+
+/// Policy which is one-hot on the played action.
+pub(crate) fn one_hot<E: Environment>(
+    env: &E,
+    played: &E::Action,
+    actions: &mut Vec<E::Action>,
+) -> Box<[(E::Action, NotNan<f32>)]> {
+    env.populate_actions(actions);
+    actions
+        .drain(..)
+        .map(|action| {
+            let p = if action == *played { 1.0 } else { 0.0 };
+            (action, NotNan::new(p).expect("policy should not be NaN"))
+        })
+        .collect()
+}
+
+/// Write a replay as PTN with the given extra tags. The outcome is taken
+/// from the final position if the game is over, then from the adjudicated
+/// result of the replay, and finally from `outcome`.
+///
+/// # Panics
+///
+/// Panics if the replay contains an illegal action.
+#[must_use]
+pub fn to_ptn<const N: usize, const HALF_KOMI: i8>(
+    replay: &Replay<Game<N, HALF_KOMI>>,
+    tags: &[(&str, &str)],
+    outcome: Option<Outcome>,
+) -> String
+where
+    Reserves<N>: Default,
+{
+    let mut out = String::new();
+    for (name, value) in tags {
+        writeln!(out, "[{name} \"{value}\"]").unwrap();
+    }
+    writeln!(out, "[Size \"{N}\"]").unwrap();
+    writeln!(out, "[Komi \"{}\"]", f32::from(HALF_KOMI) / 2.0).unwrap();
+    if replay.env != Game::default() {
+        writeln!(out, "[TPS \"{}\"]", Tps::from(replay.env.clone())).unwrap();
+    }
+    let mut end = replay.env.clone();
+    for &action in &replay.actions {
+        end.step(action);
+    }
+    let outcome = end
+        .terminal()
+        .or(replay.adjudicated)
+        .map(|terminal| Outcome::from_terminal(terminal, end.to_move))
+        .or(outcome);
+    let result = outcome.map(|outcome| outcome.result_with_reason(end.terminal_reason()));
+    if let Some(result) = result {
+        writeln!(out, "[Result \"{result}\"]").unwrap();
+    }
+    writeln!(out).unwrap();
+
+    let mut color = replay.env.to_move;
+    let mut move_number = usize::from(replay.env.ply) / 2 + 1;
+    if color == Color::Black {
+        write!(out, "{move_number}. --").unwrap();
+    }
+    for (i, action) in replay.actions.iter().enumerate() {
+        if color == Color::White {
+            if i > 0 {
+                writeln!(out).unwrap();
+            }
+            write!(out, "{move_number}. {action}").unwrap();
+            color = Color::Black;
+        } else {
+            write!(out, " {action}").unwrap();
+            move_number += 1;
+            color = Color::White;
+        }
+    }
+    if let Some(result) = result {
+        write!(out, " {result}").unwrap();
+    }
+    writeln!(out).unwrap();
+    out
+}
+
+/// Link to view a PTN game on ptn.ninja (the PTN is compressed into the URL).
+#[must_use]
+pub fn ninja_url(ptn: &str) -> String {
+    format!("https://ptn.ninja/{}", lz_str::compress_to_encoded_uri_component(ptn))
+}
+
+/// Split a file with several PTN games into the individual games.
+/// A new game starts at a tag which follows moves.
+pub fn split_games(s: &str) -> impl Iterator<Item = &str> {
+    let mut starts = vec![0];
+    let mut in_moves = false;
+    let mut offset = 0;
+    for line in s.split_inclusive('\n') {
+        let trimmed = line.trim();
+        if trimmed.starts_with('[') {
+            if in_moves {
+                starts.push(offset);
+                in_moves = false;
+            }
+        } else if !trimmed.is_empty() {
+            in_moves = true;
+        }
+        offset += line.len();
+    }
+    let ends = starts.clone().into_iter().skip(1).chain([s.len()]);
+    starts
+        .into_iter()
+        .zip(ends)
+        .map(|(start, end)| &s[start..end])
+        .filter(|game| !game.trim().is_empty())
+}
+
+#[cfg(test)]
+mod tests {
+    use fast_tak::Game;
+
+    use super::{ninja_url, split_games, tag, to_ptn, Outcome, PtnGame};
+    use crate::search::DISCOUNT_FACTOR;
+
+    const GAME: &str = r#"[Site "PlayTak.com"]
+[Player1 "alice"]
+[Player2 "bob"]
+[Size "3"]
+[Komi "0"]
+[Result "R-0"]
+
+1. c3 a1
+2. b1 b3
+3. c1 R-0
+"#;
+
+    #[test]
+    fn import_game() {
+        let game: PtnGame<3, 0> = GAME.parse().unwrap();
+        assert_eq!(game.replay.len(), 5);
+        assert_eq!(game.outcome, Some(Outcome::WhiteWin));
+
+        let targets = game.targets().unwrap();
+        assert_eq!(targets.len(), 5);
+        // White played the last move and won.
+        assert!((targets[4].value - DISCOUNT_FACTOR).abs() < 1e-6);
+        let loss = -DISCOUNT_FACTOR.powi(2);
+        assert!((targets[3].value - loss).abs() < 1e-6);
+        for (target, played) in targets.iter().zip(&game.replay.actions) {
+            let total: f32 = target.policy.iter().map(|(_, p)| p.into_inner()).sum();
+            assert!((total - 1.0).abs() < f32::EPSILON);
+            assert!(target.policy.iter().any(|(a, p)| a == played && p.into_inner() > 0.5));
+        }
+
+        assert!(GAME.parse::<PtnGame<3, 4>>().is_err());
+        assert!(GAME.parse::<PtnGame<4, 0>>().is_err());
+    }
+
+    #[test]
+    fn resignation_and_splitting() {
+        let resigned = "[Size \"3\"]\n[Result \"0-1\"]\n1. a1 c3\n";
+        let file = format!("{GAME}\n{resigned}");
+        let games: Vec<_> = split_games(&file)
+            .map(|game| game.parse::<PtnGame<3, 0>>().unwrap())
+            .collect();
+        assert_eq!(games.len(), 2);
+        assert_eq!(games[1].outcome, Some(Outcome::BlackWin));
+        assert_eq!(games[1].replay.env, Game::default());
+        let targets = games[1].targets().unwrap();
+        // White resigned, so black's last move was winning.
+        assert!(targets[1].value > 0.0);
+        assert!(targets[0].value < 0.0);
+    }
+
+    #[test]
+    fn export_and_link() {
+        let game: PtnGame<3, 0> = GAME.parse().unwrap();
+        let ptn = to_ptn(&game.replay, &[("Player1", "alice")], None);
+        assert_eq!(
+            ptn,
+            "[Player1 \"alice\"]\n[Size \"3\"]\n[Komi \"0\"]\n[Result \"R-0\"]\n\n1. c3 a1\n2. b1 \
+             b3\n3. c1 R-0\n"
+        );
+        let exported: PtnGame<3, 0> = ptn.parse().unwrap();
+        assert_eq!(exported, game);
+
+        let url = ninja_url(&ptn);
+        let compressed = url.strip_prefix("https://ptn.ninja/").unwrap();
+        let decompressed = lz_str::decompress_from_encoded_uri_component(compressed).unwrap();
+        assert_eq!(String::from_utf16(&decompressed).unwrap(), ptn);
+    }
+
+    #[test]
+    fn tags_on_their_own_lines_or_one_line() {
+        assert_eq!(tag(GAME, "Komi"), Some("0"));
+        assert_eq!(tag(GAME, "Player2"), Some("bob"));
+        let replay = "[TPS \"x3/x3/x3 1 1\"] [Komi \"2\"] a1 c3";
+        assert_eq!(tag(replay, "TPS"), Some("x3/x3/x3 1 1"));
+        assert_eq!(tag(replay, "Komi"), Some("2"));
+        assert_eq!(tag(replay, "Size"), None);
+    }
+}
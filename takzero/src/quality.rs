@@ -0,0 +1,184 @@
+//! Quality of value targets.
+//!
+//! Remakes the value targets of finished self-play games from the root
+//! values recorded during self-play, like self-play does with a horizon,
+//! and compares them with the eventual, discounted game outcome. A sudden
+//! jump in the error or a badly calibrated curve usually means that
+//! something in target generation broke.
+
+use std::fmt;
+
+use crate::{
+    search::env::Environment,
+    target::{n_step_values, Replay},
+};
+
+/// Number of equally wide bins over `[-1, 1]` used for calibration.
+pub const CALIBRATION_BINS: usize = 10;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct CalibrationBin {
+    pub count: usize,
+    sum_predicted: f64,
+    sum_outcome: f64,
+}
+
+impl CalibrationBin {
+    #[must_use]
+    pub fn mean_predicted(&self) -> f64 {
+        self.sum_predicted / self.count as f64
+    }
+
+    #[must_use]
+    pub fn mean_outcome(&self) -> f64 {
+        self.sum_outcome / self.count as f64
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ValueQuality {
+    pub positions: usize,
+    sum_error: f64,
+    sum_squared_error: f64,
+    pub bins: [CalibrationBin; CALIBRATION_BINS],
+}
+
+impl ValueQuality {
+    /// Add a single prediction and the outcome it should have predicted.
+    #[allow(clippy::cast_sign_loss)]
+    pub fn add(&mut self, predicted: f32, outcome: f32) {
+        let (predicted, outcome) = (f64::from(predicted), f64::from(outcome));
+        let error = predicted - outcome;
+        self.positions += 1;
+        self.sum_error += error;
+        self.sum_squared_error += error * error;
+
+        let bin = ((predicted + 1.0) / 2.0 * CALIBRATION_BINS as f64).max(0.0) as usize;
+        let bin = &mut self.bins[bin.min(CALIBRATION_BINS - 1)];
+        bin.count += 1;
+        bin.sum_predicted += predicted;
+        bin.sum_outcome += outcome;
+    }
+
+    /// Add the value targets of a finished game, bootstrapped from the root
+    /// value before every move with the `horizon` and `discount` of
+    /// self-play (see [`n_step_values`]).
+    /// Returns `false` (and adds nothing) if the game is not finished or the
+    /// number of values does not match the number of moves. Adjudicated
+    /// games count as finished.
+    pub fn add_game<E: Environment>(
+        &mut self,
+        replay: &Replay<E>,
+        root_values: &[f32],
+        horizon: Option<usize>,
+        discount: f32,
+    ) -> bool {
+        if root_values.len() != replay.len() || replay.outcome().is_none() {
+            return false;
+        }
+
+        let targets = n_step_values(replay, horizon, discount, |positions| {
+            // The bootstrapped positions start `horizon` plies in.
+            let start = horizon.unwrap_or_default();
+            root_values[start..start + positions.len()].to_vec()
+        });
+        let outcomes = n_step_values(replay, None, discount, |_| unreachable!());
+        for (target, outcome) in targets.into_iter().zip(outcomes) {
+            self.add(target, outcome);
+        }
+        true
+    }
+
+    /// Mean of predicted minus outcome. Positive means too optimistic.
+    #[must_use]
+    pub fn bias(&self) -> f64 {
+        self.sum_error / self.positions as f64
+    }
+
+    #[must_use]
+    pub fn mean_squared_error(&self) -> f64 {
+        self.sum_squared_error / self.positions as f64
+    }
+
+    /// Average distance between the mean prediction and the mean outcome of
+    /// each bin, weighted by the number of positions in the bin.
+    #[must_use]
+    pub fn calibration_error(&self) -> f64 {
+        self.bins
+            .iter()
+            .filter(|bin| bin.count > 0)
+            .map(|bin| bin.count as f64 * (bin.mean_predicted() - bin.mean_outcome()).abs())
+            .sum::<f64>()
+            / self.positions as f64
+    }
+}
+
+impl fmt::Display for ValueQuality {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "positions={} bias={:.4} mse={:.4} calibration_error={:.4}",
+            self.positions,
+            self.bias(),
+            self.mean_squared_error(),
+            self.calibration_error()
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use fast_tak::Game;
+
+    use super::ValueQuality;
+    use crate::{search::DISCOUNT_FACTOR, target::Replay};
+
+    #[test]
+    fn perfect_predictions() {
+        // White builds a road on the last move.
+        let replay: Replay<Game<3, 0>> = "[TPS \"x3/x3/x3 1 1\"] c3 a1 b1 b3 c1".parse().unwrap();
+        let values: Vec<_> = (0..5)
+            .map(|i| {
+                let sign = if i % 2 == 0 { 1.0 } else { -1.0 };
+                sign * DISCOUNT_FACTOR.powi(5 - i)
+            })
+            .collect();
+        let mut quality = ValueQuality::default();
+        assert!(quality.add_game(&replay, &values, Some(2), DISCOUNT_FACTOR));
+        assert_eq!(quality.positions, 5);
+        assert!(quality.mean_squared_error() < 1e-6);
+        assert!(quality.calibration_error() < 1e-6);
+    }
+
+    #[test]
+    fn bootstrapped_targets() {
+        let replay: Replay<Game<3, 0>> = "[TPS \"x3/x3/x3 1 1\"] c3 a1 b1 b3 c1".parse().unwrap();
+        // Only the first three targets are bootstrapped with a horizon of 2,
+        // from the values of the positions 2 plies later.
+        let values = [0.0, 0.0, 0.0, 0.0, 0.0];
+        let mut quality = ValueQuality::default();
+        assert!(quality.add_game(&replay, &values, Some(2), 1.0));
+        assert_eq!(quality.positions, 5);
+        assert!((quality.mean_squared_error() - 3.0 / 5.0).abs() < 1e-6);
+
+        // Without a horizon the targets are the outcome, whatever the values.
+        let mut quality = ValueQuality::default();
+        assert!(quality.add_game(&replay, &values, None, 1.0));
+        assert!(quality.mean_squared_error() < 1e-6);
+    }
+
+    #[test]
+    fn biased_predictions() {
+        let mut quality = ValueQuality::default();
+        quality.add(0.5, 0.0);
+        quality.add(0.5, 1.0);
+        quality.add(-0.5, -1.0);
+        assert!((quality.bias() - 1.0 / 6.0).abs() < 1e-6);
+        assert!((quality.mean_squared_error() - 0.25).abs() < 1e-6);
+        assert!((quality.calibration_error() - 0.5 / 3.0).abs() < 1e-6);
+
+        let unfinished: Replay<Game<3, 0>> = "[TPS \"x3/x3/x3 1 1\"] c3 a1".parse().unwrap();
+        assert!(!quality.add_game(&unfinished, &[0.0, 0.0], None, DISCOUNT_FACTOR));
+        assert_eq!(quality.positions, 3);
+    }
+}
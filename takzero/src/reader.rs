@@ -0,0 +1,253 @@
+//! Lazy readers for files with one item per line.
+//!
+//! Files are read through one reused line buffer, so memory stays bounded by
+//! the longest line no matter how large the file is. Gzip-compressed files
+//! (for example `targets-selfplay.txt.gz`) are recognized by their magic
+//! bytes and decompressed on the fly.
+//!
+//! Lines which do not parse are skipped by default. [`LineReader::lossy`]
+//! keeps a [`LineError`] with the line number, the offending line, and an
+//! [`ErrorCategory`] for each of them, and [`LineReader::read_strict`] stops at
+//! the first one instead.
+
+use std::{
+    fmt,
+    fs::File,
+    io::{self, BufRead, BufReader},
+    marker::PhantomData,
+    num::ParseIntError,
+    path::Path,
+    str::FromStr,
+};
+
+use flate2::bufread::MultiGzDecoder;
+use thiserror::Error;
+
+/// First bytes of a gzip-compressed file.
+pub const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+/// Lines are cut to this many characters in errors.
+const FRAGMENT_LEN: usize = 80;
+
+/// Which part of a line failed to parse.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorCategory {
+    /// A tag like `[Komi "2"]`.
+    Tag,
+    Tps,
+    Action,
+    Number,
+    Policy,
+    /// Search statistics like `{v=0.5,u=0.1,a1=100}`.
+    Stats,
+}
+
+impl fmt::Display for ErrorCategory {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Tag => "tag",
+            Self::Tps => "TPS",
+            Self::Action => "action",
+            Self::Number => "number",
+            Self::Policy => "policy",
+            Self::Stats => "search statistics",
+        })
+    }
+}
+
+/// Parse errors which can say which part of the input was wrong.
+pub trait Categorize {
+    fn category(&self) -> ErrorCategory;
+}
+
+impl Categorize for ParseIntError {
+    fn category(&self) -> ErrorCategory {
+        ErrorCategory::Number
+    }
+}
+
+/// A line which did not parse.
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+#[error("line {line}: {category} error: {message} in `{fragment}`")]
+pub struct LineError {
+    /// Line number in the file, starting at 1 and counting the header.
+    pub line: usize,
+    /// The start of the line.
+    pub fragment: String,
+    pub category: ErrorCategory,
+    pub message: String,
+}
+
+/// Open a file for reading lines, decompressing it if it is gzipped.
+///
+/// # Errors
+///
+/// Returns an error if the file cannot be opened or read.
+pub fn open(path: impl AsRef<Path>) -> io::Result<Box<dyn BufRead + Send>> {
+    let mut reader = BufReader::new(File::open(path)?);
+    if reader.fill_buf()?.starts_with(&GZIP_MAGIC) {
+        Ok(Box::new(BufReader::new(MultiGzDecoder::new(reader))))
+    } else {
+        Ok(Box::new(reader))
+    }
+}
+
+/// Iterator which parses one item per line as it goes.
+/// Lines which do not parse are skipped and counted.
+pub struct LineReader<T> {
+    reader: Box<dyn BufRead + Send>,
+    line: String,
+    line_number: usize,
+    skipped: usize,
+    /// Errors of skipped lines, if they are kept.
+    errors: Option<Vec<LineError>>,
+    item: PhantomData<fn() -> T>,
+}
+
+impl<T> LineReader<T> {
+    /// Read lines from the reader. `first_line` is the number of the next
+    /// line, which is 2 if a header was already read.
+    #[must_use]
+    pub fn new(reader: Box<dyn BufRead + Send>, first_line: usize) -> Self {
+        Self {
+            reader,
+            line: String::new(),
+            line_number: first_line.saturating_sub(1),
+            skipped: 0,
+            errors: None,
+            item: PhantomData,
+        }
+    }
+
+    /// Keep the errors of skipped lines, see [`Self::errors`].
+    #[must_use]
+    pub fn lossy(mut self) -> Self {
+        self.errors.get_or_insert_with(Vec::new);
+        self
+    }
+
+    /// Number of lines which were skipped so far because they did not parse.
+    #[must_use]
+    pub const fn skipped(&self) -> usize {
+        self.skipped
+    }
+
+    /// Errors of the lines which were skipped so far. Always empty unless the
+    /// reader is [lossy](Self::lossy).
+    #[must_use]
+    pub fn errors(&self) -> &[LineError] {
+        self.errors.as_deref().unwrap_or_default()
+    }
+}
+
+impl<T: FromStr> LineReader<T>
+where
+    T::Err: Categorize + fmt::Display,
+{
+    /// Parse the next non-empty line, returning its error if it does not
+    /// parse. Reading stops with a warning if the file cannot be read.
+    pub fn next_checked(&mut self) -> Option<Result<T, LineError>> {
+        loop {
+            self.line.clear();
+            match self.reader.read_line(&mut self.line) {
+                Ok(0) => return None,
+                Ok(_) => {}
+                Err(err) => {
+                    log::warn!("Stopped reading early: {err}");
+                    return None;
+                }
+            }
+            self.line_number += 1;
+            let line = self.line.trim_end();
+            if line.is_empty() {
+                continue;
+            }
+            return Some(line.parse().map_err(|err: T::Err| LineError {
+                line: self.line_number,
+                fragment: line.chars().take(FRAGMENT_LEN).collect(),
+                category: err.category(),
+                message: err.to_string(),
+            }));
+        }
+    }
+
+    /// Parse all remaining lines.
+    ///
+    /// # Errors
+    ///
+    /// Returns the error of the first line which does not parse.
+    pub fn read_strict(mut self) -> Result<Vec<T>, LineError> {
+        std::iter::from_fn(|| self.next_checked()).collect()
+    }
+}
+
+impl<T: FromStr> Iterator for LineReader<T>
+where
+    T::Err: Categorize + fmt::Display,
+{
+    type Item = T;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            match self.next_checked()? {
+                Ok(item) => return Some(item),
+                Err(err) => {
+                    self.skipped += 1;
+                    if let Some(errors) = &mut self.errors {
+                        errors.push(err);
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Cursor, Write};
+
+    use flate2::{write::GzEncoder, Compression};
+
+    use super::{open, ErrorCategory, LineError, LineReader};
+
+    #[test]
+    fn plain_and_gzipped() {
+        let directory = std::env::temp_dir().join(format!("takzero-reader-{}", std::process::id()));
+        std::fs::create_dir_all(&directory).unwrap();
+        let content = "1\n2\nnot a number\n\n3\n";
+
+        let plain = directory.join("numbers.txt");
+        std::fs::write(&plain, content).unwrap();
+        let gzipped = directory.join("numbers.txt.gz");
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(content.as_bytes()).unwrap();
+        std::fs::write(&gzipped, encoder.finish().unwrap()).unwrap();
+
+        for path in [plain, gzipped] {
+            let mut reader = LineReader::<u32>::new(open(path).unwrap(), 1);
+            assert_eq!(reader.by_ref().collect::<Vec<_>>(), [1, 2, 3]);
+            assert_eq!(reader.skipped(), 1);
+        }
+        std::fs::remove_dir_all(directory).unwrap();
+
+        let reader = LineReader::<u32>::new(Box::new(Cursor::new("4\n5")), 1);
+        assert_eq!(reader.collect::<Vec<_>>(), [4, 5]);
+    }
+
+    #[test]
+    fn lossy_and_strict() {
+        let content = "1\n\nx\n2\n";
+        let mut reader = LineReader::<u32>::new(Box::new(Cursor::new(content)), 2).lossy();
+        assert_eq!(reader.by_ref().collect::<Vec<_>>(), [1, 2]);
+        assert_eq!(reader.errors(), [LineError {
+            line: 4,
+            fragment: "x".into(),
+            category: ErrorCategory::Number,
+            message: "invalid digit found in string".into(),
+        }]);
+
+        let reader = LineReader::<u32>::new(Box::new(Cursor::new(content)), 1);
+        assert_eq!(reader.read_strict().unwrap_err().line, 3);
+        let reader = LineReader::<u32>::new(Box::new(Cursor::new("1\n2\n")), 1);
+        assert_eq!(reader.read_strict(), Ok(vec![1, 2]));
+    }
+}
@@ -1,10 +1,34 @@
+use std::{
+    future::Future,
+    pin::pin,
+    sync::Arc,
+    task::{Context, Poll, Wake, Waker},
+    thread::{self, Thread},
+};
+
 use ordered_float::NotNan;
+use thiserror::Error;
 
 use super::env::Environment;
 
+pub mod batching;
+pub mod symmetric;
+
+/// The policy (as logits), value, and uncertainty predicted for a position.
+pub type Prediction<E> = (Vec<(<E as Environment>::Action, NotNan<f32>)>, f32, f32);
+
+/// Why an asynchronous agent could not evaluate a batch.
+#[derive(Debug, Error, Clone, Copy, PartialEq, Eq)]
+pub enum AgentError {
+    #[error("the agent is no longer running")]
+    Disconnected,
+}
+
 pub trait Agent<E: Environment> {
     /// Always batched.
     /// The policy does not have to be normalized (returning logits).
+    /// An empty policy for a position with actions means that it could not
+    /// be predicted, for example because a logit was NaN.
     fn policy_value_uncertainty(
         &self,
         env_batch: &[E],
@@ -12,11 +36,84 @@ pub trait Agent<E: Environment> {
     ) -> impl Iterator<Item = (Vec<(E::Action, NotNan<f32>)>, f32, f32)>;
 }
 
+impl<E: Environment, A: Agent<E>> Agent<E> for &A {
+    fn policy_value_uncertainty(
+        &self,
+        env_batch: &[E],
+        actions_batch: &[Vec<E::Action>],
+    ) -> impl Iterator<Item = (Vec<(E::Action, NotNan<f32>)>, f32, f32)> {
+        (**self).policy_value_uncertainty(env_batch, actions_batch)
+    }
+}
+
+/// An agent whose predictions arrive later, for example from a thread which
+/// batches requests from many searches (see [`batching::Batcher`]).
+///
+/// The search waits for the future instead of blocking a thread while the
+/// batch is evaluated. A client of the remote inference server would
+/// implement this too, but none exists yet.
+///
+/// Synchronous agents are used through [`Immediate`].
+pub trait AsyncAgent<E: Environment> {
+    /// Like [`Agent::policy_value_uncertainty`], but resolved later.
+    /// Returning fewer predictions than there are positions is allowed,
+    /// and cancels the simulations of the remaining positions.
+    fn evaluate(
+        &self,
+        env_batch: &[E],
+        actions_batch: &[Vec<E::Action>],
+    ) -> impl Future<Output = Result<Vec<Prediction<E>>, AgentError>> + Send;
+}
+
+/// A synchronous agent whose futures are ready immediately.
+pub struct Immediate<A>(pub A);
+
+impl<E: Environment, A: Agent<E>> AsyncAgent<E> for Immediate<A> {
+    fn evaluate(
+        &self,
+        env_batch: &[E],
+        actions_batch: &[Vec<E::Action>],
+    ) -> impl Future<Output = Result<Vec<Prediction<E>>, AgentError>> + Send {
+        let predictions = self.0.policy_value_uncertainty(env_batch, actions_batch).collect();
+        std::future::ready(Ok(predictions))
+    }
+}
+
+/// Run a future to completion on the current thread, which is parked while
+/// the future is waiting. This is how the synchronous searches drive the
+/// asynchronous ones.
+pub fn block_on<F: Future>(future: F) -> F::Output {
+    struct Unpark(Thread);
+
+    impl Wake for Unpark {
+        fn wake(self: Arc<Self>) {
+            self.0.unpark();
+        }
+
+        fn wake_by_ref(self: &Arc<Self>) {
+            self.0.unpark();
+        }
+    }
+
+    let waker = Waker::from(Arc::new(Unpark(thread::current())));
+    let mut context = Context::from_waker(&waker);
+    let mut future = pin!(future);
+    loop {
+        match future.as_mut().poll(&mut context) {
+            Poll::Ready(output) => return output,
+            Poll::Pending => thread::park(),
+        }
+    }
+}
+
 pub mod dummy {
     use ordered_float::NotNan;
 
     use super::{super::env::Environment, Agent};
 
+    /// An agent without a network, which values positions with
+    /// [`Environment::heuristic_value`] and has no preference between
+    /// actions. It plays when no network is loaded.
     pub struct Dummy;
 
     impl<E: Environment> Agent<E> for Dummy {
@@ -26,13 +123,13 @@ pub mod dummy {
             actions_batch: &[Vec<<E as Environment>::Action>],
         ) -> impl Iterator<Item = (Vec<(E::Action, NotNan<f32>)>, f32, f32)> {
             debug_assert_eq!(env_batch.len(), actions_batch.len());
-            actions_batch.iter().map(|actions| {
+            env_batch.iter().zip(actions_batch).map(|(env, actions)| {
                 (
                     actions
                         .iter()
                         .map(|a| (a.clone(), NotNan::new(1.0).unwrap()))
                         .collect(),
-                    0.0,
+                    env.heuristic_value(),
                     0.0,
                 )
             })
@@ -0,0 +1,260 @@
+//! Batching of evaluations requested by many searches.
+//!
+//! A [`Batcher`] is an [`AsyncAgent`] which forwards its requests to a
+//! thread that owns the real agent. The thread gathers the requests which
+//! are waiting when it becomes free into one batch, so searches with a few
+//! leaves each can share the network, and a search waiting for its
+//! predictions does not hold up a thread of its own.
+
+use std::{
+    future::Future,
+    io,
+    pin::Pin,
+    sync::{
+        mpsc::{self, Receiver, Sender},
+        Arc,
+        Mutex,
+    },
+    task::{Context, Poll, Waker},
+    thread,
+};
+
+use super::{Agent, AgentError, AsyncAgent, Prediction};
+use crate::search::env::Environment;
+
+pub struct Batcher<E: Environment> {
+    sender: Mutex<Sender<Request<E>>>,
+}
+
+struct Request<E: Environment> {
+    env_batch: Vec<E>,
+    actions_batch: Vec<Vec<E::Action>>,
+    respond: Respond<Vec<Prediction<E>>>,
+}
+
+impl<E: Environment + 'static> Batcher<E> {
+    /// Move the agent to a new thread which evaluates batches of up to
+    /// `max_batch_size` positions. A request is never split, so a single
+    /// bigger request is evaluated on its own.
+    /// The thread stops when the batcher is dropped.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the thread could not be spawned.
+    pub fn spawn<A: Agent<E> + Send + 'static>(
+        agent: A,
+        max_batch_size: usize,
+    ) -> io::Result<Self> {
+        let (sender, receiver) = mpsc::channel();
+        thread::Builder::new()
+            .name("batcher".into())
+            .spawn(move || serve(&agent, &receiver, max_batch_size))?;
+        Ok(Self {
+            sender: Mutex::new(sender),
+        })
+    }
+}
+
+impl<E: Environment> AsyncAgent<E> for Batcher<E> {
+    fn evaluate(
+        &self,
+        env_batch: &[E],
+        actions_batch: &[Vec<E::Action>],
+    ) -> impl Future<Output = Result<Vec<Prediction<E>>, AgentError>> + Send {
+        let slot = Arc::new(Mutex::new(Slot {
+            value: None,
+            waker: None,
+            closed: false,
+        }));
+        let request = Request {
+            env_batch: env_batch.to_vec(),
+            actions_batch: actions_batch.to_vec(),
+            respond: Respond(slot.clone()),
+        };
+        // If the thread is gone the request is dropped along with its
+        // sender, which resolves the response as disconnected.
+        let _ = self
+            .sender
+            .lock()
+            .expect("batcher lock should not be poisoned")
+            .send(request);
+        Response(slot)
+    }
+}
+
+fn serve<E: Environment, A: Agent<E>>(
+    agent: &A,
+    receiver: &Receiver<Request<E>>,
+    max_batch_size: usize,
+) {
+    while let Ok(first) = receiver.recv() {
+        let mut size = first.env_batch.len();
+        let mut requests = vec![first];
+        // Take the requests which are already waiting, without waiting for more.
+        while size < max_batch_size {
+            let Ok(request) = receiver.try_recv() else {
+                break;
+            };
+            size += request.env_batch.len();
+            requests.push(request);
+        }
+
+        let mut env_batch = Vec::with_capacity(size);
+        let mut actions_batch = Vec::with_capacity(size);
+        let ends: Vec<_> = requests
+            .iter_mut()
+            .map(|request| {
+                env_batch.append(&mut request.env_batch);
+                actions_batch.append(&mut request.actions_batch);
+                env_batch.len()
+            })
+            .collect();
+        let mut predictions = agent.policy_value_uncertainty(&env_batch, &actions_batch);
+        let mut start = 0;
+        for (request, end) in requests.into_iter().zip(ends) {
+            // If the agent returned too few predictions, the last requests
+            // get short responses, and the searches cancel those leaves.
+            request.respond.send(predictions.by_ref().take(end - start).collect());
+            start = end;
+        }
+    }
+}
+
+/// The state shared by the two ends of a response.
+struct Slot<T> {
+    value: Option<T>,
+    waker: Option<Waker>,
+    closed: bool,
+}
+
+/// The sending end of a response, which is kept by the batching thread.
+struct Respond<T>(Arc<Mutex<Slot<T>>>);
+
+impl<T> Respond<T> {
+    fn send(self, value: T) {
+        self.0.lock().expect("slot lock should not be poisoned").value = Some(value);
+        // Dropping the sender wakes the waiting search.
+    }
+}
+
+impl<T> Drop for Respond<T> {
+    fn drop(&mut self) {
+        let mut slot = self.0.lock().expect("slot lock should not be poisoned");
+        slot.closed = true;
+        if let Some(waker) = slot.waker.take() {
+            waker.wake();
+        }
+    }
+}
+
+/// The receiving end of a response, which the search waits for.
+struct Response<T>(Arc<Mutex<Slot<T>>>);
+
+impl<T> Future for Response<T> {
+    type Output = Result<T, AgentError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let mut slot = self.0.lock().expect("slot lock should not be poisoned");
+        match slot.value.take() {
+            Some(value) => Poll::Ready(Ok(value)),
+            None if slot.closed => Poll::Ready(Err(AgentError::Disconnected)),
+            None => {
+                slot.waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use fast_tak::Game;
+    use rand::SeedableRng;
+    use rand_chacha::ChaCha8Rng;
+
+    use super::Batcher;
+    use crate::search::{
+        agent::{
+            block_on,
+            dummy::Dummy,
+            simple::Simple,
+            Agent,
+            AgentError,
+            AsyncAgent,
+            Immediate,
+            Prediction,
+        },
+        env::Environment,
+        node::batched::BatchedMCTS,
+        DISCOUNT_FACTOR,
+    };
+
+    type Env = Game<4, 0>;
+
+    #[test]
+    fn batched_searches_match_direct_searches() {
+        let batcher = Batcher::spawn(Simple, 16).unwrap();
+        let searches: Vec<_> = thread::scope(|scope| {
+            let handles: Vec<_> = (0..4)
+                .map(|seed| {
+                    let batcher = &batcher;
+                    scope.spawn(move || {
+                        let mut rng = ChaCha8Rng::seed_from_u64(seed);
+                        let mut actions = Vec::new();
+                        let envs: [Env; 4] =
+                            std::array::from_fn(|_| Env::new_opening(&mut rng, &mut actions));
+                        let mut direct = BatchedMCTS::from_envs(envs.clone());
+                        let mut queued = BatchedMCTS::from_envs(envs);
+                        for _ in 0..32 {
+                            direct.simulate(&Simple, &[0.0; 4], DISCOUNT_FACTOR);
+                            let simulated =
+                                queued.simulate_async(batcher, &[0.0; 4], DISCOUNT_FACTOR);
+                            block_on(simulated).unwrap();
+                        }
+                        (direct, queued)
+                    })
+                })
+                .collect();
+            handles.into_iter().map(|handle| handle.join().unwrap()).collect()
+        });
+
+        for (direct, queued) in &searches {
+            for ((a, _), (b, _)) in direct.nodes_and_envs().zip(queued.nodes_and_envs()) {
+                assert_eq!(a.visit_count(), b.visit_count());
+                assert_eq!(a.evaluation(), b.evaluation());
+                assert_eq!(a.node_count(), b.node_count());
+            }
+        }
+    }
+
+    #[test]
+    fn stopped_batcher_is_disconnected() {
+        struct Panicking;
+
+        impl Agent<Env> for Panicking {
+            fn policy_value_uncertainty(
+                &self,
+                _: &[Env],
+                _: &[Vec<<Env as Environment>::Action>],
+            ) -> impl Iterator<Item = Prediction<Env>> {
+                std::iter::from_fn(|| -> Option<Prediction<Env>> { panic!("the agent crashed") })
+            }
+        }
+
+        let batcher = Batcher::spawn(Panicking, 16).unwrap();
+        let mut actions = Vec::new();
+        let env = Env::default();
+        env.populate_actions(&mut actions);
+        let first = block_on(batcher.evaluate(std::slice::from_ref(&env), &[actions.clone()]));
+        assert_eq!(first.err(), Some(AgentError::Disconnected));
+        // Later requests find the thread gone.
+        let second = block_on(batcher.evaluate(&[env], &[actions]));
+        assert_eq!(second.err(), Some(AgentError::Disconnected));
+
+        // A synchronous agent is always ready.
+        let dummy = block_on(Immediate(Dummy).evaluate(&[Env::default()], &[Vec::new()])).unwrap();
+        assert_eq!(dummy.len(), 1);
+    }
+}
@@ -0,0 +1,214 @@
+//! Averaging predictions over the symmetries of a position.
+//!
+//! A network is trained on positions under random symmetries, but it is
+//! not exactly invariant under them. [`Symmetric`] asks the agent about
+//! every position under all of its symmetries (8 for Tak) in one batch,
+//! maps the policies back to the position, and averages the policies as
+//! probabilities, and the values and uncertainties. This costs a batch 8
+//! times as large for every evaluation, for steadier predictions in
+//! evaluation and match play.
+
+use ordered_float::NotNan;
+
+use super::{
+    super::env::{Environment, SymmetryIndex},
+    Agent,
+};
+
+/// An agent whose predictions are averaged over the symmetries of each
+/// position, see the module documentation.
+pub struct Symmetric<A>(pub A);
+
+impl<E: Environment, A: Agent<E>> Agent<E> for Symmetric<A> {
+    fn policy_value_uncertainty(
+        &self,
+        env_batch: &[E],
+        actions_batch: &[Vec<E::Action>],
+    ) -> impl Iterator<Item = (Vec<(E::Action, NotNan<f32>)>, f32, f32)> {
+        debug_assert_eq!(env_batch.len(), actions_batch.len());
+        let mut copies = Vec::new();
+        let mut copied_actions = Vec::new();
+        let mut counts = Vec::with_capacity(env_batch.len());
+        for (env, actions) in env_batch.iter().zip(actions_batch) {
+            let symmetric = env.symmetric_copies();
+            counts.push(symmetric.len());
+            for (symmetry, copy) in symmetric.into_iter().enumerate() {
+                copied_actions.push(
+                    actions
+                        .iter()
+                        .map(|action| env.transform_action(action, symmetry))
+                        .collect::<Vec<_>>(),
+                );
+                copies.push(copy);
+            }
+        }
+
+        let mut predictions = self.0.policy_value_uncertainty(&copies, &copied_actions);
+        env_batch
+            .iter()
+            .zip(actions_batch)
+            .zip(counts)
+            .map(|((env, actions), count)| {
+                let mut probabilities = vec![0.0; actions.len()];
+                let (mut value, mut uncertainty) = (0.0, 0.0);
+                let mut unpredicted = false;
+                for (symmetry, (policy, v, u)) in predictions.by_ref().take(count).enumerate() {
+                    unpredicted |= policy.is_empty() && !actions.is_empty();
+                    add_probabilities(env, actions, symmetry, &policy, &mut probabilities);
+                    value += v;
+                    uncertainty += u;
+                }
+                let count = count as f32;
+                // A policy which could not be predicted under any symmetry,
+                // or a NaN logit, leaves the policy empty like in the agent,
+                // so that the search reports it.
+                let policy = actions
+                    .iter()
+                    .cloned()
+                    .zip(probabilities)
+                    .map(|(action, probability)| {
+                        let logit = (probability / count).ln();
+                        // Clamped like masked logits.
+                        (!unpredicted && !logit.is_nan()).then(|| {
+                            (action, NotNan::new(logit.max(f32::MIN)).expect("logit is not NaN"))
+                        })
+                    })
+                    .collect::<Option<Vec<_>>>()
+                    .unwrap_or_default();
+                (policy, value / count, uncertainty / count)
+            })
+            .collect::<Vec<_>>()
+            .into_iter()
+    }
+}
+
+/// Add the probabilities of a policy of the position under the symmetry to
+/// the probabilities of the actions of the position.
+fn add_probabilities<E: Environment>(
+    env: &E,
+    actions: &[E::Action],
+    symmetry: SymmetryIndex,
+    policy: &[(E::Action, NotNan<f32>)],
+    probabilities: &mut [f32],
+) {
+    let max = policy
+        .iter()
+        .map(|(_, logit)| logit.into_inner())
+        .fold(f32::NEG_INFINITY, f32::max);
+    let total: f32 = policy
+        .iter()
+        .map(|(_, logit)| (logit.into_inner() - max).exp())
+        .sum();
+    for (i, (action, logit)) in policy.iter().enumerate() {
+        let action = env.restore_action(action, symmetry);
+        // Agents usually keep the order of the actions.
+        let index = if actions.get(i) == Some(&action) {
+            Some(i)
+        } else {
+            actions.iter().position(|a| *a == action)
+        };
+        if let Some(index) = index {
+            probabilities[index] += (logit.into_inner() - max).exp() / total;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use fast_tak::{
+        takparse::{Move, Tps},
+        Game,
+    };
+    use ordered_float::NotNan;
+
+    use super::Symmetric;
+    use crate::search::{agent::Agent, env::Environment};
+
+    type Env = Game<4, 0>;
+
+    /// An agent which prefers some squares and values positions by their
+    /// key, so it changes its mind under symmetries.
+    struct Lopsided;
+
+    impl Agent<Env> for Lopsided {
+        fn policy_value_uncertainty(
+            &self,
+            env_batch: &[Env],
+            actions_batch: &[Vec<Move>],
+        ) -> impl Iterator<Item = (Vec<(Move, NotNan<f32>)>, f32, f32)> {
+            env_batch.iter().zip(actions_batch).map(|(env, actions)| {
+                let policy = actions
+                    .iter()
+                    .map(|a| {
+                        let square = a.square();
+                        let logit = f32::from(square.column() + 2 * square.row());
+                        (*a, NotNan::new(logit).unwrap())
+                    })
+                    .collect();
+                let value = (env.hash() % 100) as f32 / 100.0;
+                (policy, value, value / 2.0)
+            })
+        }
+    }
+
+    #[test]
+    fn predictions_are_invariant() {
+        let game: Env = "2,x3/x,1,x2/x2,2S,x/1,x3 1 4".parse::<Tps>().unwrap().into();
+        let copies = game.symmetric_copies();
+        assert_eq!(copies.len(), 8);
+        let actions: Vec<_> = copies
+            .iter()
+            .map(|copy| {
+                let mut actions = Vec::new();
+                copy.populate_actions(&mut actions);
+                actions
+            })
+            .collect();
+        let predictions: Vec<_> = Symmetric(Lopsided)
+            .policy_value_uncertainty(&copies, &actions)
+            .collect();
+
+        let (policy, value, uncertainty) = &predictions[0];
+        let total: f32 = policy.iter().map(|(_, logit)| logit.exp()).sum();
+        assert!((total - 1.0).abs() < 1e-4);
+        for (symmetry, (copy_policy, copy_value, copy_uncertainty)) in
+            predictions.iter().enumerate()
+        {
+            assert!((value - copy_value).abs() < 1e-6);
+            assert!((uncertainty - copy_uncertainty).abs() < 1e-6);
+            for (action, logit) in policy {
+                let image = game.transform_action(action, symmetry);
+                let (_, copy_logit) = copy_policy.iter().find(|(a, _)| *a == image).unwrap();
+                assert!(
+                    (logit.into_inner() - copy_logit.into_inner()).abs() < 1e-4,
+                    "{action} under {symmetry}"
+                );
+            }
+        }
+    }
+
+    /// An agent whose logits were NaN.
+    struct Broken;
+
+    impl Agent<Env> for Broken {
+        fn policy_value_uncertainty(
+            &self,
+            env_batch: &[Env],
+            _actions_batch: &[Vec<Move>],
+        ) -> impl Iterator<Item = (Vec<(Move, NotNan<f32>)>, f32, f32)> {
+            env_batch.iter().map(|_| (Vec::new(), 0.0, 0.0))
+        }
+    }
+
+    #[test]
+    fn unpredicted_policy_stays_empty() {
+        let game = Env::default();
+        let mut actions = Vec::new();
+        game.populate_actions(&mut actions);
+        let (policy, _, _) = Symmetric(Broken)
+            .policy_value_uncertainty(&[game], &[actions])
+            .next()
+            .unwrap();
+        assert!(policy.is_empty());
+    }
+}
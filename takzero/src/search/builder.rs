@@ -0,0 +1,328 @@
+//! Assembling a batched Gumbel search from its parts.
+//!
+//! [`SearchBuilder`] collects the agent, the games, the exploration betas,
+//! the mate discount, the Gumbel parameters, and the seed of the random number generator, and
+//! checks that they fit together before any search is run.
+//!
+//! ```ignore
+//! let mut search = SearchBuilder::<128, Env, _>::new(net)
+//!     .seed(seed)
+//!     .beta(0.25)
+//!     .sampled_actions(64)
+//!     .search_budget(768)
+//!     .build()?;
+//! let selected_actions = search.search()?;
+//! ```
+
+use rand::SeedableRng;
+use rand_chacha::ChaCha12Rng;
+use thiserror::Error;
+
+use super::{
+    agent::Agent,
+    env::{ActionIndex, Environment},
+    node::{
+        batched::{check_budget, BatchedMCTS},
+        SearchError,
+    },
+    DISCOUNT_FACTOR,
+};
+use crate::target::Replay;
+
+pub const DEFAULT_SAMPLED_ACTIONS: usize = 64;
+pub const DEFAULT_SEARCH_BUDGET: u32 = 768;
+
+#[derive(Debug, Error, Clone, PartialEq)]
+pub enum BuildError {
+    #[error("the batch is empty")]
+    EmptyBatch,
+    #[error("expected {expected} betas, one per game, got {found}")]
+    Betas { expected: usize, found: usize },
+    #[error("beta should be finite and not negative, got {0}")]
+    Beta(f32),
+    #[error("the mate discount should be in (0, 1], got {0}")]
+    MateDiscount(f32),
+    #[error("{0}")]
+    Search(#[from] SearchError),
+}
+
+pub struct SearchBuilder<const BATCH_SIZE: usize, E: Environment, A> {
+    agent: A,
+    mcts: Option<BatchedMCTS<BATCH_SIZE, E>>,
+    beta: f32,
+    betas: Option<Vec<f32>>,
+    mate_discount: f32,
+    sampled_actions: usize,
+    search_budget: u32,
+    rng: Option<ChaCha12Rng>,
+}
+
+impl<const BATCH_SIZE: usize, E: ActionIndex, A> SearchBuilder<BATCH_SIZE, E, A> {
+    /// Start a search with the agent. A borrowed agent (`&A`) works too.
+    #[must_use]
+    pub const fn new(agent: A) -> Self {
+        Self {
+            agent,
+            mcts: None,
+            beta: 0.0,
+            betas: None,
+            mate_discount: DISCOUNT_FACTOR,
+            sampled_actions: DEFAULT_SAMPLED_ACTIONS,
+            search_budget: DEFAULT_SEARCH_BUDGET,
+            rng: None,
+        }
+    }
+
+    /// Search these games. New openings are generated by default.
+    #[must_use]
+    pub fn envs(mut self, envs: [E; BATCH_SIZE]) -> Self {
+        self.mcts = Some(BatchedMCTS::from_envs(envs));
+        self
+    }
+
+    /// Resume unfinished games from their replays.
+    #[must_use]
+    pub fn replays(mut self, replays: [Replay<E>; BATCH_SIZE]) -> Self {
+        self.mcts = Some(BatchedMCTS::from_replays(replays));
+        self
+    }
+
+    /// Exploration beta of every game. Defaults to 0.
+    #[must_use]
+    pub const fn beta(mut self, beta: f32) -> Self {
+        self.beta = beta;
+        self
+    }
+
+    /// Exploration beta of each game, which overrides [`Self::beta`].
+    #[must_use]
+    pub fn betas(mut self, betas: impl Into<Vec<f32>>) -> Self {
+        self.betas = Some(betas.into());
+        self
+    }
+
+    /// Factor by which known results are discounted for every ply until the
+    /// end of the game. Defaults to [`DISCOUNT_FACTOR`].
+    #[must_use]
+    pub const fn mate_discount(mut self, mate_discount: f32) -> Self {
+        self.mate_discount = mate_discount;
+        self
+    }
+
+    /// Number of actions sampled at the root (k). Defaults to
+    /// [`DEFAULT_SAMPLED_ACTIONS`].
+    #[must_use]
+    pub const fn sampled_actions(mut self, sampled_actions: usize) -> Self {
+        self.sampled_actions = sampled_actions;
+        self
+    }
+
+    /// Simulations per search, which must be a multiple of k*log2(k).
+    /// Defaults to [`DEFAULT_SEARCH_BUDGET`].
+    #[must_use]
+    pub const fn search_budget(mut self, search_budget: u32) -> Self {
+        self.search_budget = search_budget;
+        self
+    }
+
+    /// Seed the random number generator, which is seeded from entropy by
+    /// default.
+    #[must_use]
+    pub fn seed(mut self, seed: u64) -> Self {
+        self.rng = Some(ChaCha12Rng::seed_from_u64(seed));
+        self
+    }
+
+    /// Use a random number generator which was already seeded, for example
+    /// one restored to where an earlier run stopped.
+    #[must_use]
+    pub fn rng(mut self, rng: ChaCha12Rng) -> Self {
+        self.rng = Some(rng);
+        self
+    }
+
+    /// Check the parts and assemble the search.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the batch is empty, if the number of betas does
+    /// not match the batch, if a beta is negative or not finite, if the mate
+    /// discount is not in (0, 1], or if the search budget does not split
+    /// evenly over sequential halving.
+    pub fn build(self) -> Result<Search<BATCH_SIZE, E, A>, BuildError> {
+        if BATCH_SIZE == 0 {
+            return Err(BuildError::EmptyBatch);
+        }
+        let betas = self.betas.unwrap_or_else(|| vec![self.beta; BATCH_SIZE]);
+        let betas: [f32; BATCH_SIZE] =
+            betas.try_into().map_err(|betas: Vec<_>| BuildError::Betas {
+                expected: BATCH_SIZE,
+                found: betas.len(),
+            })?;
+        if let Some(&beta) = betas.iter().find(|beta| !beta.is_finite() || **beta < 0.0) {
+            return Err(BuildError::Beta(beta));
+        }
+        if !(self.mate_discount > 0.0 && self.mate_discount <= 1.0) {
+            return Err(BuildError::MateDiscount(self.mate_discount));
+        }
+        check_budget(self.sampled_actions, self.search_budget)?;
+
+        let mut rng = self.rng.unwrap_or_else(ChaCha12Rng::from_entropy);
+        let mcts = self.mcts.unwrap_or_else(|| BatchedMCTS::new(&mut rng));
+        Ok(Search {
+            agent: self.agent,
+            mcts,
+            rng,
+            betas,
+            mate_discount: self.mate_discount,
+            sampled_actions: self.sampled_actions,
+            budget: self.search_budget,
+        })
+    }
+}
+
+/// A batched Gumbel search whose parameters were checked by
+/// [`SearchBuilder`]. The agent, the trees, and the random number generator
+/// can be used and replaced freely.
+pub struct Search<const BATCH_SIZE: usize, E: Environment, A> {
+    pub agent: A,
+    pub mcts: BatchedMCTS<BATCH_SIZE, E>,
+    pub rng: ChaCha12Rng,
+    betas: [f32; BATCH_SIZE],
+    mate_discount: f32,
+    sampled_actions: usize,
+    budget: u32,
+}
+
+impl<const BATCH_SIZE: usize, E: ActionIndex, A: Agent<E>> Search<BATCH_SIZE, E, A> {
+    /// Search every game with Gumbel sequential halving and return the
+    /// selected actions.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if a root has no children or the agent returns a
+    /// NaN prediction or too few predictions, see
+    /// [`BatchedMCTS::try_gumbel_sequential_halving`].
+    pub fn search(&mut self) -> Result<[E::Action; BATCH_SIZE], SearchError> {
+        self.mcts.try_gumbel_sequential_halving(
+            &self.agent,
+            &self.betas,
+            self.mate_discount,
+            self.sampled_actions,
+            self.budget,
+            &mut self.rng,
+        )
+    }
+
+    /// Do a single batched simulation step.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the agent returns a NaN prediction or too few
+    /// predictions.
+    pub fn simulate(&mut self) -> Result<(), SearchError> {
+        self.mcts.try_simulate(&self.agent, &self.betas, self.mate_discount)
+    }
+}
+
+impl<const BATCH_SIZE: usize, E: Environment, A> Search<BATCH_SIZE, E, A> {
+    #[must_use]
+    pub const fn betas(&self) -> &[f32; BATCH_SIZE] {
+        &self.betas
+    }
+
+    #[must_use]
+    pub const fn mate_discount(&self) -> f32 {
+        self.mate_discount
+    }
+
+    #[must_use]
+    pub const fn sampled_actions(&self) -> usize {
+        self.sampled_actions
+    }
+
+    #[must_use]
+    pub const fn search_budget(&self) -> u32 {
+        self.budget
+    }
+
+    /// Number of visits which each of the remaining actions gets over the
+    /// whole of sequential halving, which scales the completed Q-values of
+    /// the improved policy.
+    #[must_use]
+    pub const fn improved_policy_visitations(&self) -> u32 {
+        let log_sampled = self.sampled_actions.ilog2();
+        let per_step_per_action = self.budget / log_sampled / self.sampled_actions as u32;
+        let power_series = 2u32.pow(log_sampled) - 1;
+        per_step_per_action * power_series
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{BuildError, SearchBuilder};
+    use crate::search::{
+        agent::dummy::Dummy,
+        env::{connect4::Connect4, Environment},
+        node::SearchError,
+    };
+
+    #[test]
+    fn validation() {
+        let build = |builder: SearchBuilder<2, Connect4, Dummy>| builder.build().err();
+        let betas = BuildError::Betas {
+            expected: 2,
+            found: 1,
+        };
+        assert_eq!(build(SearchBuilder::new(Dummy).betas([0.0])), Some(betas));
+        assert_eq!(build(SearchBuilder::new(Dummy).beta(-1.0)), Some(BuildError::Beta(-1.0)));
+        assert_eq!(
+            build(SearchBuilder::new(Dummy).mate_discount(0.0)),
+            Some(BuildError::MateDiscount(0.0))
+        );
+        assert!(build(SearchBuilder::new(Dummy).mate_discount(f32::NAN)).is_some());
+        let budget = BuildError::Search(SearchError::Budget {
+            sampled_actions: 4,
+            search_budget: 12,
+        });
+        assert_eq!(
+            build(SearchBuilder::new(Dummy).sampled_actions(4).search_budget(12)),
+            Some(budget)
+        );
+        // One sampled action leaves nothing to halve.
+        assert!(build(SearchBuilder::new(Dummy).sampled_actions(1)).is_some());
+        assert_eq!(
+            SearchBuilder::<0, Connect4, Dummy>::new(Dummy).build().err(),
+            Some(BuildError::EmptyBatch)
+        );
+    }
+
+    #[test]
+    fn seeded_searches_agree() {
+        let search = || {
+            let mut search = SearchBuilder::<2, Connect4, _>::new(Dummy)
+                .envs([Connect4::default(), Connect4::from_columns(&[3, 3])])
+                .betas([0.0, 0.25])
+                .sampled_actions(4)
+                .search_budget(16)
+                .seed(7)
+                .build()
+                .unwrap();
+            assert_eq!(search.improved_policy_visitations(), 6);
+            let actions = search.search().unwrap();
+            // The children of the roots were searched directly.
+            for (root, _) in search.mcts.nodes_and_envs() {
+                let children = root.children();
+                let below: usize = children.iter().map(|(_, child)| child.node_count()).sum();
+                assert_eq!(root.node_count(), 1 + below);
+            }
+            actions
+        };
+        let actions = search();
+        assert_eq!(actions, search());
+
+        let mut legal = Vec::new();
+        Connect4::default().populate_actions(&mut legal);
+        assert!(legal.contains(&actions[0]));
+    }
+}
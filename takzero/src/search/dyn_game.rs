@@ -0,0 +1,288 @@
+//! A game whose board size and komi are picked at runtime.
+//!
+//! [`Game`] takes its size and komi as const generics, so a binary which
+//! should play any size would be built once per size. [`DynGame`] wraps every
+//! supported instantiation and implements [`Environment`] by dispatching to
+//! it, so one build can pick the size and komi from its arguments or the
+//! position it is given.
+
+use std::fmt;
+
+use fast_tak::{
+    takparse::{Move, ParseTpsError, Tps},
+    Game,
+};
+use ordered_float::NotNan;
+use rand::Rng;
+use thiserror::Error;
+
+use super::{
+    agent::{simple::Simple, Agent},
+    env::{Environment, IllegalMove, SymmetryIndex, Terminal, TerminalReason},
+};
+
+#[derive(Error, Debug)]
+pub enum DynGameError {
+    #[error("size {size} with half komi {half_komi} is not supported")]
+    Unsupported { size: usize, half_komi: i8 },
+    #[error("{0}")]
+    Tps(#[from] ParseTpsError),
+}
+
+macro_rules! dyn_game {
+    ($($variant:ident = ($n:literal, $half_komi:literal)),* $(,)?) => {
+        /// A [`Game`] of any supported size and komi. Variants are named
+        /// after the size and half komi.
+        #[derive(Debug, Clone, PartialEq, Eq)]
+        pub enum DynGame {
+            $($variant(Game<$n, $half_komi>),)*
+        }
+
+        impl DynGame {
+            /// Board sizes and half komis which can be played.
+            pub const SUPPORTED: &'static [(usize, i8)] = &[$(($n, $half_komi)),*];
+
+            /// The starting position of a game.
+            ///
+            /// # Errors
+            ///
+            /// Returns an error if the size and komi are not supported.
+            pub fn new(size: usize, half_komi: i8) -> Result<Self, DynGameError> {
+                match (size, half_komi) {
+                    $(($n, $half_komi) => Ok(Self::$variant(Game::default())),)*
+                    _ => Err(DynGameError::Unsupported { size, half_komi }),
+                }
+            }
+
+            /// A position given as TPS, with the size of its board.
+            ///
+            /// # Errors
+            ///
+            /// Returns an error if the TPS does not parse or its size and the
+            /// komi are not supported.
+            pub fn from_tps(tps: &str, half_komi: i8) -> Result<Self, DynGameError> {
+                let tps: Tps = tps.parse()?;
+                match (tps.size(), half_komi) {
+                    $(($n, $half_komi) => Ok(Self::$variant(tps.into())),)*
+                    (size, _) => Err(DynGameError::Unsupported { size, half_komi }),
+                }
+            }
+
+            #[must_use]
+            pub const fn size(&self) -> usize {
+                match self {
+                    $(Self::$variant(_) => $n,)*
+                }
+            }
+
+            #[must_use]
+            pub const fn half_komi(&self) -> i8 {
+                match self {
+                    $(Self::$variant(_) => $half_komi,)*
+                }
+            }
+        }
+
+        impl fmt::Display for DynGame {
+            fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                match self {
+                    $(Self::$variant(game) => Tps::from(game.clone()).fmt(f),)*
+                }
+            }
+        }
+
+        impl Environment for DynGame {
+            type Action = Move;
+
+            fn populate_actions(&self, actions: &mut Vec<Move>) {
+                match self {
+                    $(Self::$variant(game) => game.populate_actions(actions),)*
+                }
+            }
+
+            fn step(&mut self, action: Move) {
+                match self {
+                    $(Self::$variant(game) => game.step(action),)*
+                }
+            }
+
+            fn check(&self, action: &Move) -> Result<(), IllegalMove> {
+                match self {
+                    $(Self::$variant(game) => game.check(action),)*
+                }
+            }
+
+            fn terminal(&self) -> Option<Terminal> {
+                match self {
+                    $(Self::$variant(game) => game.terminal(),)*
+                }
+            }
+
+            fn terminal_reason(&self) -> Option<TerminalReason> {
+                match self {
+                    $(Self::$variant(game) => game.terminal_reason(),)*
+                }
+            }
+
+            fn steps(&self) -> u16 {
+                match self {
+                    $(Self::$variant(game) => game.steps(),)*
+                }
+            }
+
+            fn heuristic_value(&self) -> f32 {
+                match self {
+                    $(Self::$variant(game) => game.heuristic_value(),)*
+                }
+            }
+
+            fn opening_swap(&self) -> bool {
+                match self {
+                    $(Self::$variant(game) => game.opening_swap(),)*
+                }
+            }
+
+            fn hash(&self) -> u64 {
+                match self {
+                    $(Self::$variant(game) => game.hash(),)*
+                }
+            }
+
+            /// Keys of games of different sizes are computed from scratch.
+            fn hash_after(&self, hash: u64, action: &Move, next: &Self) -> u64 {
+                match (self, next) {
+                    $((Self::$variant(game), Self::$variant(next)) => {
+                        game.hash_after(hash, action, next)
+                    })*
+                    _ => next.hash(),
+                }
+            }
+
+            fn canonical(&self) -> (Self, SymmetryIndex) {
+                match self {
+                    $(Self::$variant(game) => {
+                        let (game, symmetry) = Environment::canonical(game);
+                        (Self::$variant(game), symmetry)
+                    })*
+                }
+            }
+
+            fn symmetric_copies(&self) -> Vec<Self> {
+                match self {
+                    $(Self::$variant(game) => Environment::symmetric_copies(game)
+                        .into_iter()
+                        .map(Self::$variant)
+                        .collect(),)*
+                }
+            }
+
+            fn transform_action(&self, action: &Move, symmetry: SymmetryIndex) -> Move {
+                match self {
+                    $(Self::$variant(game) => game.transform_action(action, symmetry),)*
+                }
+            }
+
+            fn restore_action(&self, action: &Move, symmetry: SymmetryIndex) -> Move {
+                match self {
+                    $(Self::$variant(game) => game.restore_action(action, symmetry),)*
+                }
+            }
+
+            /// An opening of the default size and komi.
+            fn new_opening(rng: &mut impl Rng, actions: &mut Vec<Move>) -> Self {
+                Self::S6K4(Game::new_opening(rng, actions))
+            }
+
+            /// An opening of the default size and komi.
+            fn new_opening_with_random_steps(
+                rng: &mut impl Rng,
+                actions: &mut Vec<Move>,
+                steps: usize,
+            ) -> Self {
+                Self::S6K4(Game::new_opening_with_random_steps(rng, actions, steps))
+            }
+        }
+
+        impl Agent<DynGame> for Simple {
+            fn policy_value_uncertainty(
+                &self,
+                env_batch: &[DynGame],
+                actions_batch: &[Vec<Move>],
+            ) -> impl Iterator<Item = (Vec<(Move, NotNan<f32>)>, f32, f32)> {
+                debug_assert_eq!(env_batch.len(), actions_batch.len());
+                env_batch.iter().zip(actions_batch).filter_map(|(env, actions)| match env {
+                    $(DynGame::$variant(game) => self
+                        .policy_value_uncertainty(
+                            std::slice::from_ref(game),
+                            std::slice::from_ref(actions),
+                        )
+                        .next(),)*
+                })
+            }
+        }
+    };
+}
+
+dyn_game!(
+    S3K0 = (3, 0),
+    S3K4 = (3, 4),
+    S4K0 = (4, 0),
+    S4K4 = (4, 4),
+    S5K0 = (5, 0),
+    S5K4 = (5, 4),
+    S6K0 = (6, 0),
+    S6K4 = (6, 4),
+    S7K0 = (7, 0),
+    S7K4 = (7, 4),
+    S8K0 = (8, 0),
+    S8K4 = (8, 4),
+);
+
+/// 6x6 with a komi of 2, which most networks are trained for.
+impl Default for DynGame {
+    fn default() -> Self {
+        Self::S6K4(Game::default())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use fast_tak::Game;
+
+    use super::{DynGame, DynGameError};
+    use crate::search::{
+        agent::{simple::Simple, Agent},
+        env::Environment,
+    };
+
+    #[test]
+    fn dispatch_at_runtime() {
+        let mut game = DynGame::new(5, 4).unwrap();
+        assert_eq!((game.size(), game.half_komi()), (5, 4));
+        assert!(matches!(DynGame::new(9, 4), Err(DynGameError::Unsupported { .. })));
+
+        let mut actions = Vec::new();
+        let mut reference = Game::<5, 4>::default();
+        for _ in 0..6 {
+            game.populate_actions(&mut actions);
+            let action = actions[actions.len() / 2];
+            let hash = game.hash();
+            let before = game.clone();
+            game.step(action);
+            reference.step(action);
+            assert_eq!(before.hash_after(hash, &action, &game), game.hash());
+            actions.clear();
+        }
+        assert_eq!(game, DynGame::S5K4(reference));
+        assert_eq!(game.steps(), 6);
+        assert_eq!(DynGame::from_tps(&game.to_string(), 4).unwrap(), game);
+        assert!(DynGame::from_tps(&game.to_string(), 2).is_err());
+
+        game.populate_actions(&mut actions);
+        let (policy, ..) = Simple
+            .policy_value_uncertainty(&[game], &[actions.clone()])
+            .next()
+            .unwrap();
+        assert_eq!(policy.len(), actions.len());
+    }
+}
@@ -1,22 +1,112 @@
 use std::fmt;
 
 use fast_tak::{
-    takparse::{Move, MoveKind, Piece, Square},
+    takparse::{Color, Direction, Move, MoveKind, Piece, Square, WinReason},
     Game,
     Reserves,
+    Stack,
     Symmetry,
 };
+use arrayvec::ArrayVec;
 use rand::{seq::IteratorRandom, Rng};
+use rayon::prelude::*;
+use thiserror::Error;
+
+use crate::{
+    features::Features,
+    network::repr::{index_move, move_index, output_size},
+    variant::carried,
+    zobrist::{touched_squares, zobrist, zobrist_after},
+};
+
+pub mod connect4;
+pub mod perft;
+
+/// Index of one of the symmetries of a position, where 0 is the identity.
+pub type SymmetryIndex = usize;
 
 pub trait Environment: Send + Sync + Clone + Default {
     type Action: Send + Sync + Clone + PartialEq + fmt::Debug;
 
     fn populate_actions(&self, actions: &mut Vec<Self::Action>);
     fn step(&mut self, action: Self::Action);
+    /// Check whether the action can be played, for actions which come from
+    /// users or files, since [`Environment::step`] may panic on illegal ones.
+    ///
+    /// # Errors
+    ///
+    /// Returns why the action is illegal.
+    fn check(&self, action: &Self::Action) -> Result<(), IllegalMove> {
+        if self.terminal().is_some() {
+            return Err(IllegalMove::GameOver);
+        }
+        let mut actions = Vec::new();
+        self.populate_actions(&mut actions);
+        if actions.contains(action) {
+            Ok(())
+        } else {
+            Err(IllegalMove::NotLegal)
+        }
+    }
     fn terminal(&self) -> Option<Terminal>;
+    /// Why the game ended, or `None` if it has not ended or the environment
+    /// does not tell.
+    fn terminal_reason(&self) -> Option<TerminalReason> {
+        None
+    }
     fn steps(&self) -> u16;
+    /// Value of the position for the player to move, between -1 and 1, from
+    /// hand-written rules. Used by agents and adjudication which do without a
+    /// network. Environments without a heuristic call every position even.
+    fn heuristic_value(&self) -> f32 {
+        0.0
+    }
+    /// Whether the player to move places a piece of their opponent instead
+    /// of their own, like in the first turn of Tak.
+    fn opening_swap(&self) -> bool {
+        false
+    }
+
+    /// Key of the position for transposition tables, caches, and
+    /// deduplication. Equal positions have equal keys.
+    fn hash(&self) -> u64;
+    /// Key of `next`, the position after playing `action` here, given the
+    /// key of this position. Environments which can update the key
+    /// incrementally should override this.
+    fn hash_after(&self, _hash: u64, _action: &Self::Action, next: &Self) -> u64 {
+        next.hash()
+    }
+
+    /// The position in an orientation which all of its symmetric copies
+    /// share, and the symmetry which turns this position into it.
+    /// Environments without symmetries are their own canonical form.
+    fn canonical(&self) -> (Self, SymmetryIndex) {
+        (self.clone(), 0)
+    }
+    /// The position under every symmetry, indexed by [`SymmetryIndex`], so
+    /// the first one is the position itself. Environments without
+    /// symmetries only have themselves.
+    fn symmetric_copies(&self) -> Vec<Self> {
+        vec![self.clone()]
+    }
+    /// The action under the symmetry, for example to play an action of this
+    /// position in its canonical form.
+    fn transform_action(&self, action: &Self::Action, _symmetry: SymmetryIndex) -> Self::Action {
+        action.clone()
+    }
+    /// Undo [`Self::transform_action`], for example to map an action chosen
+    /// in the canonical form back to this position.
+    fn restore_action(&self, action: &Self::Action, _symmetry: SymmetryIndex) -> Self::Action {
+        action.clone()
+    }
 
     fn new_opening(rng: &mut impl Rng, actions: &mut Vec<Self::Action>) -> Self;
+    /// An opening for the game after this one, under the same rules.
+    /// Environments whose rules are all in their type start a new opening.
+    #[must_use]
+    fn next_opening(&self, rng: &mut impl Rng, actions: &mut Vec<Self::Action>) -> Self {
+        Self::new_opening(rng, actions)
+    }
     fn new_opening_with_random_steps(
         rng: &mut impl Rng,
         actions: &mut Vec<Move>,
@@ -24,12 +114,79 @@ pub trait Environment: Send + Sync + Clone + Default {
     ) -> Self;
 }
 
+/// Environments which can take back steps, so that search can step and undo
+/// one environment instead of cloning it at every node.
+pub trait Undo: Environment {
+    /// What a step changed beyond what the action tells, which
+    /// [`Undo::undo`] needs to take it back.
+    type Delta;
+
+    /// Like [`Environment::step`], but returns what the step changed.
+    fn step_with_delta(&mut self, action: Self::Action) -> Self::Delta;
+    /// Take back `action`, which must have been the last step, with the delta
+    /// which [`Undo::step_with_delta`] returned for it.
+    fn undo(&mut self, action: Self::Action, delta: Self::Delta);
+}
+
+/// Environments whose actions are numbered densely from 0, like the outputs
+/// of a policy head. Network glue which only needs these numbers works for
+/// any such environment.
+pub trait ActionIndex: Environment {
+    /// Number of indices, so every index is smaller.
+    const ACTIONS: usize;
+
+    fn action_index(action: &Self::Action) -> usize;
+    /// The action with the index, or `None` if there is none.
+    fn index_action(index: usize) -> Option<Self::Action>;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Terminal {
     Win,
     Loss,
     Draw,
 }
 
+/// Why an action cannot be played.
+#[derive(Debug, Error, Clone, Copy, PartialEq, Eq)]
+pub enum IllegalMove {
+    #[error("the game is over")]
+    GameOver,
+    #[error("the square is occupied")]
+    Occupied,
+    #[error("only flats of the opponent can be placed in the opening swap")]
+    OpeningSwap,
+    #[error("there are no {0} left in the reserves")]
+    NoPieces(&'static str),
+    #[error("there is no stack to move")]
+    Empty,
+    #[error("the stack is controlled by the opponent")]
+    NotOwned,
+    #[error("{carried} pieces are carried, but the carry limit is {limit}")]
+    CarryLimit { carried: usize, limit: usize },
+    #[error("{carried} pieces are carried, but the stack has {height}")]
+    NotEnoughPieces { carried: usize, height: usize },
+    #[error("the spread leaves the board")]
+    OffBoard,
+    #[error("the spread runs into a wall or capstone")]
+    Blocked,
+    #[error("the action is not legal in this position")]
+    NotLegal,
+}
+
+/// Why a game of Tak ended.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum TerminalReason {
+    /// A player built a road.
+    Road,
+    /// The board filled up and flats were counted.
+    Flats,
+    /// A player placed their last piece and flats were counted.
+    OutOfPieces,
+    /// The game reached the move limit of its variant and was drawn.
+    MoveLimit,
+}
+
 impl<const N: usize, const HALF_KOMI: i8> Environment for Game<N, HALF_KOMI>
 where
     Reserves<N>: Default,
@@ -44,6 +201,84 @@ where
         self.play(action).expect("Action should be valid");
     }
 
+    /// Checks the rules of Tak directly instead of generating all actions.
+    fn check(&self, action: &Move) -> Result<(), IllegalMove> {
+        if self.terminal().is_some() {
+            return Err(IllegalMove::GameOver);
+        }
+        // Top piece and height of square `N * y + x`.
+        let stacks: Vec<_> = self
+            .board
+            .iter()
+            .flatten()
+            .map(|stack| (stack.top(), stack.size() as usize))
+            .collect();
+        let square = action.square();
+        let (column, row) = (usize::from(square.column()), usize::from(square.row()));
+        let (top, height) = stacks[N * row + column];
+        match action.kind() {
+            MoveKind::Place(piece) => {
+                if top.is_some() {
+                    return Err(IllegalMove::Occupied);
+                }
+                let swap = self.opening_swap();
+                if swap && piece != Piece::Flat {
+                    return Err(IllegalMove::OpeningSwap);
+                }
+                let reserves = match (self.to_move, swap) {
+                    (Color::White, false) | (Color::Black, true) => self.white_reserves,
+                    (Color::Black, false) | (Color::White, true) => self.black_reserves,
+                };
+                match piece {
+                    Piece::Cap if reserves.caps == 0 => Err(IllegalMove::NoPieces("capstones")),
+                    Piece::Flat | Piece::Wall if reserves.stones == 0 => {
+                        Err(IllegalMove::NoPieces("stones"))
+                    }
+                    _ => Ok(()),
+                }
+            }
+            MoveKind::Spread(direction, pattern) => {
+                if self.opening_swap() {
+                    return Err(IllegalMove::OpeningSwap);
+                }
+                let Some((piece, color)) = top else {
+                    return Err(IllegalMove::Empty);
+                };
+                if color != self.to_move {
+                    return Err(IllegalMove::NotOwned);
+                }
+                let carried = carried(action);
+                if carried > N {
+                    return Err(IllegalMove::CarryLimit { carried, limit: N });
+                }
+                if carried > height {
+                    return Err(IllegalMove::NotEnoughPieces { carried, height });
+                }
+                let drops: Vec<u32> = pattern.into_iter().collect();
+                let (mut x, mut y) = (column, row);
+                for (i, &drop) in drops.iter().enumerate() {
+                    (x, y) = match direction {
+                        Direction::Up => (x, y + 1),
+                        Direction::Down => (x, y.wrapping_sub(1)),
+                        Direction::Right => (x + 1, y),
+                        Direction::Left => (x.wrapping_sub(1), y),
+                    };
+                    if x >= N || y >= N {
+                        return Err(IllegalMove::OffBoard);
+                    }
+                    // Only a capstone alone on the last square flattens a wall.
+                    let smash = piece == Piece::Cap && i + 1 == drops.len() && drop == 1;
+                    match stacks[N * y + x].0 {
+                        Some((Piece::Cap, _)) => return Err(IllegalMove::Blocked),
+                        Some((Piece::Wall, _)) if !smash => return Err(IllegalMove::Blocked),
+                        _ => {}
+                    }
+                }
+                Ok(())
+            }
+        }
+    }
+
     fn terminal(&self) -> Option<Terminal> {
         match self.result() {
             fast_tak::GameResult::Winner { color, .. } => {
@@ -58,10 +293,79 @@ where
         }
     }
 
+    fn terminal_reason(&self) -> Option<TerminalReason> {
+        let reason = match self.result() {
+            fast_tak::GameResult::Winner { reason, .. } | fast_tak::GameResult::Draw { reason } => {
+                reason
+            }
+            fast_tak::GameResult::Ongoing => return None,
+        };
+        // The reason itself is not exported, only what it means for PTN.
+        Some(match WinReason::from(reason) {
+            WinReason::Road => TerminalReason::Road,
+            WinReason::Flat
+                if self.white_reserves.depleted() || self.black_reserves.depleted() =>
+            {
+                TerminalReason::OutOfPieces
+            }
+            WinReason::Flat => TerminalReason::Flats,
+            // Too many plies without a placement.
+            WinReason::Other => TerminalReason::MoveLimit,
+        })
+    }
+
     fn steps(&self) -> u16 {
         self.ply
     }
 
+    /// The flat count differential with komi, where each road threat counts
+    /// as a row of flats.
+    fn heuristic_value(&self) -> f32 {
+        let features = Features::new(self);
+        let [white, black] = features.road_threats;
+        let threats = white as f32 - black as f32;
+        let value = (threats.mul_add(N as f32, features.flat_diff) / (N * N) as f32).clamp(-1.0, 1.0);
+        match self.to_move {
+            Color::White => value,
+            Color::Black => -value,
+        }
+    }
+
+    /// Each player places a flat of the opponent in their first turn.
+    fn opening_swap(&self) -> bool {
+        self.ply < 2
+    }
+
+    fn hash(&self) -> u64 {
+        zobrist(self)
+    }
+
+    fn hash_after(&self, hash: u64, action: &Self::Action, next: &Self) -> u64 {
+        zobrist_after(hash, self, *action, next)
+    }
+
+    /// The symmetry with the smallest Zobrist key, the first one on ties.
+    fn canonical(&self) -> (Self, SymmetryIndex) {
+        self.symmetries()
+            .into_iter()
+            .enumerate()
+            .min_by_key(|(_, env)| zobrist(env))
+            .map(|(symmetry, env)| (env, symmetry))
+            .expect("there should be 8 symmetries")
+    }
+
+    fn symmetric_copies(&self) -> Vec<Self> {
+        self.symmetries().into_iter().collect()
+    }
+
+    fn transform_action(&self, action: &Self::Action, symmetry: SymmetryIndex) -> Self::Action {
+        Symmetry::<N>::symmetries(action)[symmetry]
+    }
+
+    fn restore_action(&self, action: &Self::Action, symmetry: SymmetryIndex) -> Self::Action {
+        Symmetry::<N>::symmetries(action)[inverse_symmetry::<N>(symmetry)]
+    }
+
     fn new_opening(rng: &mut impl Rng, _actions: &mut Vec<Move>) -> Self {
         let mut env = Self::default();
         // Pick random symmetry.
@@ -95,6 +399,127 @@ where
     }
 }
 
+impl<const N: usize, const HALF_KOMI: i8> ActionIndex for Game<N, HALF_KOMI>
+where
+    Reserves<N>: Default,
+{
+    const ACTIONS: usize = output_size::<N>();
+
+    fn action_index(action: &Move) -> usize {
+        move_index::<N>(action)
+    }
+
+    fn index_action(index: usize) -> Option<Move> {
+        index_move::<N>(index)
+    }
+}
+
+/// Actions in the opening swap: a flat of the opponent on any empty square.
+/// Unlike [`Environment::populate_actions`], this ignores the ply, for tools
+/// which enumerate the swap of other positions.
+pub fn swap_actions<const N: usize, const HALF_KOMI: i8>(
+    game: &Game<N, HALF_KOMI>,
+    actions: &mut Vec<Move>,
+) {
+    for (row, stacks) in game.board.iter().enumerate() {
+        for (column, stack) in stacks.enumerate() {
+            if stack.top().is_none() {
+                let square = Square::new(column as u8, row as u8);
+                actions.push(Move::new(square, MoveKind::Place(Piece::Flat)));
+            }
+        }
+    }
+}
+
+/// Populate the legal actions of each environment and check whether it has
+/// ended, in parallel. Ended environments get no actions.
+///
+/// # Panics
+///
+/// Panics if there are not as many action lists as environments.
+pub fn populate_actions_batch<E: Environment>(
+    env_batch: &[E],
+    actions_batch: &mut [Vec<E::Action>],
+) -> Vec<Option<Terminal>> {
+    assert_eq!(env_batch.len(), actions_batch.len());
+    env_batch
+        .par_iter()
+        .zip(actions_batch)
+        .map(|(env, actions)| {
+            let terminal = env.terminal();
+            if terminal.is_none() {
+                env.populate_actions(actions);
+            }
+            terminal
+        })
+        .collect()
+}
+
+/// What a move changed in a [`Game`]: the stacks of the squares it touched
+/// and the reserves, player to move, and counters, as they were before.
+#[derive(Debug, Clone)]
+pub struct GameDelta<const N: usize> {
+    stacks: ArrayVec<(Square, Stack), 9>,
+    to_move: Color,
+    white_reserves: Reserves<N>,
+    black_reserves: Reserves<N>,
+    reversible_plies: u16,
+}
+
+impl<const N: usize, const HALF_KOMI: i8> Undo for Game<N, HALF_KOMI>
+where
+    Reserves<N>: Default,
+{
+    type Delta = GameDelta<N>;
+
+    fn step_with_delta(&mut self, action: Move) -> GameDelta<N> {
+        let touched = touched_squares::<N>(action);
+        let stacks = (0..N * N)
+            .filter(|square| touched & (1 << square) != 0)
+            .map(|square| {
+                let square = Square::new((square % N) as u8, (square / N) as u8);
+                let stack = self.board.get(square).expect("the square is on the board");
+                (square, *stack)
+            })
+            .collect();
+        let delta = GameDelta {
+            stacks,
+            to_move: self.to_move,
+            white_reserves: self.white_reserves,
+            black_reserves: self.black_reserves,
+            reversible_plies: self.reversible_plies,
+        };
+        self.step(action);
+        delta
+    }
+
+    fn undo(&mut self, _action: Move, delta: GameDelta<N>) {
+        for (square, stack) in delta.stacks {
+            *self.board.get_mut(square).expect("the square is on the board") = stack;
+        }
+        self.white_reserves = delta.white_reserves;
+        self.black_reserves = delta.black_reserves;
+        self.reversible_plies = delta.reversible_plies;
+        self.to_move = delta.to_move;
+        self.ply -= 1;
+    }
+}
+
+/// The symmetry which undoes the given one. `b1` has a different image under
+/// each symmetry, so the inverse is the one which brings its image back.
+///
+/// # Panics
+///
+/// Panics if the symmetry is not below 8.
+#[must_use]
+pub fn inverse_symmetry<const N: usize>(symmetry: SymmetryIndex) -> SymmetryIndex {
+    let square = Square::new(1, 0);
+    let image = Symmetry::<N>::symmetries(&square)[symmetry];
+    (0..8)
+        .find(|&inverse| Symmetry::<N>::symmetries(&image)[inverse] == square)
+        .expect("every symmetry should have an inverse")
+}
+
 impl From<Terminal> for f32 {
     fn from(value: Terminal) -> Self {
         match value {
@@ -109,7 +534,7 @@ impl From<Terminal> for f32 {
 pub mod safecrack {
     use ordered_float::NotNan;
 
-    use super::{Environment, Terminal};
+    use super::{ActionIndex, Environment, Terminal, Undo};
     use crate::search::agent::Agent;
 
     #[derive(Clone)]
@@ -172,6 +597,14 @@ pub mod safecrack {
             unimplemented!("not necessary for the test");
         }
 
+        fn hash(&self) -> u64 {
+            self.tried
+                .iter()
+                .fold(u64::from(self.active), |hash, digit| {
+                    hash.wrapping_mul(31).wrapping_add(u64::from(*digit))
+                })
+        }
+
         fn new_opening(_rng: &mut impl rand::prelude::Rng, _actions: &mut Vec<Option<u8>>) -> Self {
             unimplemented!("not necessary for the test");
         }
@@ -185,6 +618,40 @@ pub mod safecrack {
         }
     }
 
+    /// Digits are their own index and passing is 10.
+    impl Undo for SafeCrack {
+        type Delta = ();
+
+        fn step_with_delta(&mut self, action: Self::Action) {
+            self.step(action);
+        }
+
+        fn undo(&mut self, action: Self::Action, (): ()) {
+            self.active = !self.active;
+            if self.active {
+                assert_eq!(self.tried.pop(), action);
+            } else {
+                assert_eq!(action, None);
+            }
+        }
+    }
+
+    impl ActionIndex for SafeCrack {
+        const ACTIONS: usize = 11;
+
+        fn action_index(action: &Option<u8>) -> usize {
+            action.map_or(10, usize::from)
+        }
+
+        fn index_action(index: usize) -> Option<Option<u8>> {
+            match index {
+                0..=9 => Some(Some(index as u8)),
+                10 => Some(None),
+                _ => None,
+            }
+        }
+    }
+
     pub struct SafeCracker;
 
     impl Agent<SafeCrack> for SafeCracker {
@@ -207,3 +674,230 @@ pub mod safecrack {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use fast_tak::{
+        takparse::{Move, Tps},
+        Game,
+    };
+    use rand::{rngs::StdRng, seq::IteratorRandom, SeedableRng};
+
+    use super::{
+        populate_actions_batch,
+        safecrack::SafeCrack,
+        swap_actions,
+        ActionIndex,
+        Environment,
+        IllegalMove,
+        Terminal,
+        TerminalReason,
+    };
+    use crate::network::repr::{index_move, output_size};
+
+    fn round_trip<E: ActionIndex>(env: &E) {
+        let mut actions = Vec::new();
+        env.populate_actions(&mut actions);
+        assert!(!actions.is_empty());
+        for action in actions {
+            let index = E::action_index(&action);
+            assert!(index < E::ACTIONS);
+            assert_eq!(E::index_action(index), Some(action));
+        }
+        assert_eq!(E::index_action(E::ACTIONS), None);
+    }
+
+    #[test]
+    fn action_indices() {
+        round_trip(&SafeCrack::default());
+        let game: Game<5, 0> = "2,x4/x5/x2,1S,x2/x5/1,x3,1 2 3".parse::<Tps>().unwrap().into();
+        round_trip(&game);
+        let game: Game<3, 0> = "x3/x,21212112212S,x/x3 1 12".parse::<Tps>().unwrap().into();
+        round_trip(&game);
+        let all: Vec<_> = (0..Game::<4, 0>::ACTIONS).map(Game::<4, 0>::index_action).collect();
+        assert!(all.iter().all(Option::is_some));
+        for (index, action) in all.into_iter().enumerate() {
+            assert_eq!(Game::<4, 0>::action_index(&action.unwrap()), index);
+        }
+    }
+
+    #[test]
+    fn batched_actions() {
+        let envs: Vec<Game<5, 0>> = [
+            "x5/x5/x5/x5/x5 1 1",
+            "2,x4/x5/x2,1S,x2/x5/1,x3,1 2 3",
+            "1,1,1,1,1/x5/x5/x5/2,2,2,2,x 2 5",
+        ]
+        .into_iter()
+        .map(|tps| tps.parse::<Tps>().unwrap().into())
+        .collect();
+        let mut actions_batch = vec![Vec::new(); envs.len()];
+        let terminals = populate_actions_batch(&envs, &mut actions_batch);
+        assert_eq!(terminals, envs.iter().map(Environment::terminal).collect::<Vec<_>>());
+        assert_eq!(terminals[2], Some(Terminal::Loss));
+        for ((env, actions), terminal) in envs.iter().zip(&actions_batch).zip(&terminals) {
+            let mut expected = Vec::new();
+            if terminal.is_none() {
+                env.populate_actions(&mut expected);
+            }
+            assert_eq!(actions, &expected);
+        }
+    }
+
+    #[test]
+    fn opening_swap() {
+        let mut game = Game::<4, 0>::default();
+        let (mut actions, mut swap) = (Vec::new(), Vec::new());
+        for ply in 0..3 {
+            assert_eq!(game.opening_swap(), ply < 2);
+            game.populate_actions(&mut actions);
+            swap_actions(&game, &mut swap);
+            assert_eq!(actions.len() == swap.len(), game.opening_swap());
+            if game.opening_swap() {
+                actions.sort_by_key(ToString::to_string);
+                swap.sort_by_key(ToString::to_string);
+                assert_eq!(actions, swap);
+            }
+            game.step(swap[0]);
+            actions.clear();
+            swap.clear();
+        }
+    }
+
+    #[test]
+    fn heuristic_values() {
+        let value = |tps: &str| {
+            let game: Game<5, 0> = tps.parse::<Tps>().unwrap().into();
+            game.heuristic_value()
+        };
+        assert!(value("x5/x5/x5/x5/x5 1 1").abs() < 1e-6);
+        // White can complete a road at e5, and flats are even.
+        let threat = "1,1,1,1,x/x5/2,x,2,x2/x,2,x,2,x/x5";
+        assert!((value(&format!("{threat} 1 5")) - 0.2).abs() < 1e-6);
+        assert!((value(&format!("{threat} 2 5")) + 0.2).abs() < 1e-6);
+        assert!(value("1,1,1,x2/x5/x5/x5/2,x4 2 3") < 0.0);
+        assert!(SafeCrack::default().heuristic_value().abs() < 1e-6);
+    }
+
+    #[test]
+    fn terminal_reasons() {
+        let reason = |tps: &str| {
+            let game: Game<3, 0> = tps.parse::<Tps>().unwrap().into();
+            (game.terminal(), game.terminal_reason())
+        };
+        assert_eq!(reason("x3/x3/x3 1 2"), (None, None));
+        assert_eq!(
+            reason("1,1,1/2,2,x/x3 2 3"),
+            (Some(Terminal::Loss), Some(TerminalReason::Road))
+        );
+        assert_eq!(
+            reason("1,2,1/2,1,2/1,2,1 2 5"),
+            (Some(Terminal::Loss), Some(TerminalReason::Flats))
+        );
+        let mut game = Game::<3, 0>::default();
+        game.white_reserves.stones = 1;
+        // Black places white's last stone in the first turn.
+        for action in ["a1", "c3"] {
+            game.step(action.parse().unwrap());
+        }
+        assert_eq!(
+            (game.terminal(), game.terminal_reason()),
+            (Some(Terminal::Draw), Some(TerminalReason::OutOfPieces))
+        );
+    }
+
+    #[test]
+    fn canonical_forms_agree() {
+        let game: Game<5, 0> = "2,x4/x5/x2,1S,x2/x5/1,x3,1 2 3".parse::<Tps>().unwrap().into();
+        let (canonical, _) = Environment::canonical(&game);
+        for copy in game.symmetric_copies() {
+            let (copy_canonical, symmetry) = Environment::canonical(&copy);
+            assert_eq!(copy_canonical, canonical);
+
+            let mut actions = Vec::new();
+            copy.populate_actions(&mut actions);
+            for action in actions {
+                let transformed = copy.transform_action(&action, symmetry);
+                assert_eq!(copy.restore_action(&transformed, symmetry), action);
+                let mut played = copy.clone();
+                played.step(action);
+                let mut canonical_played = copy_canonical.clone();
+                canonical_played.step(transformed);
+                assert_eq!(
+                    Environment::canonical(&played).0,
+                    Environment::canonical(&canonical_played).0
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn illegal_moves() {
+        let check = |tps: &str, action: &str| {
+            let game: Game<5, 0> = tps.parse::<Tps>().unwrap().into();
+            game.check(&action.parse().unwrap())
+        };
+        let start = "x5/x5/x5/x5/x5 1 1";
+        assert_eq!(check(start, "a1"), Ok(()));
+        assert_eq!(check(start, "Sa1"), Err(IllegalMove::OpeningSwap));
+        assert_eq!(check(start, "a1+"), Err(IllegalMove::OpeningSwap));
+
+        let swapped = "x4,1/x5/x5/x5/2,x4 1 2";
+        assert_eq!(check(swapped, "a1"), Err(IllegalMove::Occupied));
+        assert_eq!(check(swapped, "a1+"), Err(IllegalMove::NotOwned));
+        assert_eq!(check(swapped, "b1+"), Err(IllegalMove::Empty));
+        assert_eq!(check(swapped, "e5-"), Ok(()));
+        assert_eq!(check(swapped, "e5+"), Err(IllegalMove::OffBoard));
+        assert_eq!(
+            check(swapped, "2e5-"),
+            Err(IllegalMove::NotEnoughPieces {
+                carried: 2,
+                height: 1
+            })
+        );
+
+        assert_eq!(check("x5/x5/x5/x5/1,1S,x3 1 3", "a1>"), Err(IllegalMove::Blocked));
+        assert_eq!(check("x5/x5/x5/x5/1C,1S,x3 1 3", "a1>"), Ok(()));
+        assert_eq!(check("x5/x5/x5/x5/21C,1S,x3 1 4", "2a1>"), Err(IllegalMove::Blocked));
+
+        let mut game = Game::<5, 0>::default();
+        for action in "e1 e2 a3 a5 b3 b5 c3 c5 d3 d5 e3".split_whitespace() {
+            game.step(action.parse().unwrap());
+        }
+        assert_eq!(game.check(&"a1".parse().unwrap()), Err(IllegalMove::GameOver));
+
+        let game: Game<3, 0> = "2121,x2/x3/x2,2 1 4".parse::<Tps>().unwrap().into();
+        assert_eq!(
+            game.check(&"4a3-".parse().unwrap()),
+            Err(IllegalMove::CarryLimit {
+                carried: 4,
+                limit: 3
+            })
+        );
+        assert_eq!(game.check(&"Cb2".parse().unwrap()), Err(IllegalMove::NoPieces("capstones")));
+    }
+
+    #[test]
+    fn check_agrees_with_actions() {
+        let mut rng = StdRng::seed_from_u64(1);
+        let moves: Vec<Move> = (0..output_size::<5>()).filter_map(index_move::<5>).collect();
+        let mut actions = Vec::new();
+        for _ in 0..3 {
+            let mut game = Game::<5, 4>::default();
+            while game.terminal().is_none() {
+                game.populate_actions(&mut actions);
+                for action in &moves {
+                    assert_eq!(
+                        game.check(action).is_ok(),
+                        actions.contains(action),
+                        "{action} in {}",
+                        Tps::from(game.clone())
+                    );
+                }
+                game.step(actions.drain(..).choose(&mut rng).unwrap());
+            }
+        }
+        assert_eq!(SafeCrack::default().check(&Some(0)), Ok(()));
+        assert_eq!(SafeCrack::default().check(&None), Err(IllegalMove::NotLegal));
+    }
+}
@@ -0,0 +1,366 @@
+//! Connect Four, a second game for checking that search and training do not
+//! depend on Tak. Games are short and the board is tiny, so it is also cheap
+//! enough for end-to-end tests.
+//!
+//! The board is a bitboard with one 7-bit group per column, where the lowest
+//! 6 bits are the rows from the bottom and the top bit stays empty so that
+//! lines do not wrap from one column into the next.
+
+use std::fmt;
+
+use fast_tak::takparse::Move;
+use rand::{seq::IteratorRandom, Rng};
+
+use super::{ActionIndex, Environment, SymmetryIndex, Terminal, Undo};
+
+pub const COLUMNS: usize = 7;
+pub const ROWS: usize = 6;
+/// Length of [`Connect4::to_input`]: a plane for the stones of the player to
+/// move and one for the opponent.
+pub const INPUT_SIZE: usize = 2 * ROWS * COLUMNS;
+
+const STRIDE: usize = ROWS + 1;
+const BOTTOM: u64 = {
+    let mut bottom = 0;
+    let mut column = 0;
+    while column < COLUMNS {
+        bottom |= 1 << (column * STRIDE);
+        column += 1;
+    }
+    bottom
+};
+
+/// A position of Connect Four. Actions are the column to drop a stone into.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub struct Connect4 {
+    /// Stones of the player to move.
+    current: u64,
+    /// Stones of both players.
+    mask: u64,
+    ply: u16,
+}
+
+impl Connect4 {
+    /// The position after dropping stones into these columns from the start.
+    ///
+    /// # Panics
+    ///
+    /// Panics if a column is full or out of range.
+    #[must_use]
+    pub fn from_columns(columns: &[u8]) -> Self {
+        let mut env = Self::default();
+        for &column in columns {
+            assert!(env.can_play(column), "column {column} cannot be played");
+            env.step(column);
+        }
+        env
+    }
+
+    #[must_use]
+    pub const fn can_play(&self, column: u8) -> bool {
+        (column as usize) < COLUMNS && self.mask & top(column) == 0
+    }
+
+    /// Stone of the player to move (1), of the opponent (-1), or none (0).
+    #[must_use]
+    pub const fn cell(&self, column: usize, row: usize) -> i8 {
+        let bit = 1 << (column * STRIDE + row);
+        if self.current & bit != 0 {
+            1
+        } else if self.mask & bit != 0 {
+            -1
+        } else {
+            0
+        }
+    }
+
+    /// Encode the position as network input, with channels first and rows
+    /// from the bottom.
+    #[must_use]
+    pub fn to_input(&self) -> Vec<f32> {
+        let mut input = vec![0.0; INPUT_SIZE];
+        for row in 0..ROWS {
+            for column in 0..COLUMNS {
+                let channel = match self.cell(column, row) {
+                    1 => 0,
+                    -1 => 1,
+                    _ => continue,
+                };
+                input[channel * ROWS * COLUMNS + row * COLUMNS + column] = 1.0;
+            }
+        }
+        input
+    }
+
+    const fn mirrored(&self) -> Self {
+        Self {
+            current: mirror(self.current),
+            mask: mirror(self.mask),
+            ply: self.ply,
+        }
+    }
+}
+
+const fn top(column: u8) -> u64 {
+    1 << (ROWS - 1 + column as usize * STRIDE)
+}
+
+/// Whether the stones contain four in a row in any direction.
+const fn four_in_a_row(stones: u64) -> bool {
+    // Vertical, horizontal, and both diagonals.
+    let shifts = [1, STRIDE, STRIDE - 1, STRIDE + 1];
+    let mut i = 0;
+    while i < shifts.len() {
+        let pairs = stones & (stones >> shifts[i]);
+        if pairs & (pairs >> (2 * shifts[i])) != 0 {
+            return true;
+        }
+        i += 1;
+    }
+    false
+}
+
+/// Flip the board from left to right.
+const fn mirror(stones: u64) -> u64 {
+    let column_bits = (1 << STRIDE) - 1;
+    let mut mirrored = 0;
+    let mut column = 0;
+    while column < COLUMNS {
+        let bits = (stones >> (column * STRIDE)) & column_bits;
+        mirrored |= bits << ((COLUMNS - 1 - column) * STRIDE);
+        column += 1;
+    }
+    mirrored
+}
+
+/// The first player is `x` and the second is `o`, with the top row first.
+impl fmt::Display for Connect4 {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (mine, theirs) = if self.ply % 2 == 0 { ('x', 'o') } else { ('o', 'x') };
+        for row in (0..ROWS).rev() {
+            for column in 0..COLUMNS {
+                let symbol = match self.cell(column, row) {
+                    1 => mine,
+                    -1 => theirs,
+                    _ => '.',
+                };
+                write!(f, "{symbol}")?;
+            }
+            writeln!(f)?;
+        }
+        Ok(())
+    }
+}
+
+impl Undo for Connect4 {
+    type Delta = ();
+
+    fn step_with_delta(&mut self, column: u8) {
+        self.step(column);
+    }
+
+    fn undo(&mut self, column: u8, (): ()) {
+        let column_bits = ((1 << ROWS) - 1) << (column as usize * STRIDE);
+        let stones = self.mask & column_bits;
+        debug_assert_ne!(stones, 0, "column {column} is empty");
+        // The highest stone of the column was placed last.
+        self.mask ^= 1 << stones.ilog2();
+        self.current ^= self.mask;
+        self.ply -= 1;
+    }
+}
+
+impl Environment for Connect4 {
+    type Action = u8;
+
+    fn populate_actions(&self, actions: &mut Vec<u8>) {
+        actions.extend((0..COLUMNS as u8).filter(|&column| self.can_play(column)));
+    }
+
+    fn step(&mut self, column: u8) {
+        debug_assert!(self.can_play(column));
+        // The opponent moves next, and the new stone is the lowest empty bit
+        // of the column.
+        self.current ^= self.mask;
+        self.mask |= self.mask + (1 << (column as usize * STRIDE));
+        self.ply += 1;
+    }
+
+    fn terminal(&self) -> Option<Terminal> {
+        if four_in_a_row(self.current ^ self.mask) {
+            Some(Terminal::Loss)
+        } else if usize::from(self.ply) == ROWS * COLUMNS {
+            Some(Terminal::Draw)
+        } else {
+            None
+        }
+    }
+
+    fn steps(&self) -> u16 {
+        self.ply
+    }
+
+    /// Adding the bottom row to the stones of both players turns the height
+    /// of each column into a bit above it, so with the stones of the player
+    /// to move this is unique for every position.
+    fn hash(&self) -> u64 {
+        self.current + self.mask + BOTTOM
+    }
+
+    /// The mirror image with the smaller key, or the position itself on ties.
+    fn canonical(&self) -> (Self, SymmetryIndex) {
+        let mirrored = self.mirrored();
+        if mirrored.hash() < self.hash() {
+            (mirrored, 1)
+        } else {
+            (*self, 0)
+        }
+    }
+
+    fn symmetric_copies(&self) -> Vec<Self> {
+        vec![*self, self.mirrored()]
+    }
+
+    fn transform_action(&self, column: &u8, symmetry: SymmetryIndex) -> u8 {
+        if symmetry == 0 {
+            *column
+        } else {
+            COLUMNS as u8 - 1 - column
+        }
+    }
+
+    /// Mirroring undoes itself.
+    fn restore_action(&self, column: &u8, symmetry: SymmetryIndex) -> u8 {
+        self.transform_action(column, symmetry)
+    }
+
+    fn new_opening(_rng: &mut impl Rng, _actions: &mut Vec<u8>) -> Self {
+        Self::default()
+    }
+
+    fn new_opening_with_random_steps(
+        rng: &mut impl Rng,
+        _actions: &mut Vec<Move>,
+        steps: usize,
+    ) -> Self {
+        let mut env = Self::default();
+        let mut actions = Vec::new();
+        for _ in 0..steps {
+            if env.terminal().is_some() {
+                break;
+            }
+            env.populate_actions(&mut actions);
+            let Some(column) = actions.drain(..).choose(rng) else {
+                break;
+            };
+            env.step(column);
+        }
+        env
+    }
+}
+
+impl ActionIndex for Connect4 {
+    const ACTIONS: usize = COLUMNS;
+
+    fn action_index(column: &u8) -> usize {
+        usize::from(*column)
+    }
+
+    fn index_action(index: usize) -> Option<u8> {
+        (index < COLUMNS).then_some(index as u8)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::{rngs::StdRng, SeedableRng};
+
+    use super::{Connect4, INPUT_SIZE};
+    use crate::search::{
+        agent::dummy::Dummy,
+        env::{Environment, Terminal, Undo},
+        node::Node,
+        DISCOUNT_FACTOR,
+    };
+
+    #[test]
+    fn rules() {
+        // Vertical, horizontal, and both diagonals.
+        for columns in [
+            &[3, 4, 3, 4, 3, 4, 3][..],
+            &[0, 0, 1, 1, 2, 2, 3],
+            &[0, 1, 1, 2, 2, 3, 2, 3, 3, 6, 3],
+            &[6, 5, 5, 4, 4, 3, 4, 3, 3, 0, 3],
+        ] {
+            let env = Connect4::from_columns(columns);
+            assert_eq!(env.terminal(), Some(Terminal::Loss), "{columns:?}\n{env}");
+            let before = Connect4::from_columns(&columns[..columns.len() - 1]);
+            assert_eq!(before.terminal(), None, "{columns:?}\n{before}");
+        }
+        // Lines do not wrap from the top of one column to the next.
+        let env = Connect4::from_columns(&[1, 0, 6, 0, 5, 0, 0, 6, 0, 5, 0]);
+        assert_eq!(env.terminal(), None, "\n{env}");
+
+        // A game which fills the board without four in a row.
+        let mut env = Connect4::default();
+        let mut actions = Vec::new();
+        for (i, column) in "545062455041104565311226266362030334314210".bytes().enumerate() {
+            assert_eq!(env.terminal(), None, "the game ended after {i} stones\n{env}");
+            env.step(column - b'0');
+        }
+        assert_eq!(env.terminal(), Some(Terminal::Draw), "\n{env}");
+        env.populate_actions(&mut actions);
+        assert!(actions.is_empty());
+
+        // Undoing the steps gives back each earlier position.
+        let columns = [3, 3, 2, 3, 4, 4];
+        let mut env = Connect4::from_columns(&columns);
+        for (i, &column) in columns.iter().enumerate().rev() {
+            env.undo(column, ());
+            assert_eq!(env, Connect4::from_columns(&columns[..i]));
+        }
+
+        let env = Connect4::from_columns(&[3, 3, 2]);
+        let input = env.to_input();
+        assert_eq!(input.len(), INPUT_SIZE);
+        assert!((input.iter().sum::<f32>() - 3.0).abs() < f32::EPSILON);
+        assert_eq!(env.cell(3, 1), 1);
+        assert_eq!(env.cell(2, 0), -1);
+    }
+
+    #[test]
+    fn mirror_symmetry() {
+        let env = Connect4::from_columns(&[0, 1, 1, 5]);
+        let mirrored = Connect4::from_columns(&[6, 5, 5, 1]);
+        assert_ne!(env.hash(), mirrored.hash());
+        let (canonical, symmetry) = env.canonical();
+        assert_eq!(canonical, mirrored.canonical().0);
+        let mut actions = Vec::new();
+        env.populate_actions(&mut actions);
+        for action in actions {
+            let mut played = env;
+            played.step(action);
+            let mut canonical_played = canonical;
+            canonical_played.step(env.transform_action(&action, symmetry));
+            assert_eq!(played.canonical().0, canonical_played.canonical().0);
+        }
+
+        let mut rng = StdRng::seed_from_u64(0);
+        let env = Connect4::new_opening_with_random_steps(&mut rng, &mut Vec::new(), 10);
+        assert!(env.steps() <= 10);
+    }
+
+    #[test]
+    fn search_finds_wins() {
+        // The player to move wins by completing the row.
+        let env = Connect4::from_columns(&[0, 0, 1, 1, 2, 2]);
+        let mut root = Node::default();
+        (0..1_000)
+            .find(|_| {
+                root.simulate_simple(&Dummy, env, 0.0, DISCOUNT_FACTOR);
+                root.evaluation().is_win()
+            })
+            .expect("a win in one should be found");
+        assert_eq!(root.select_best_action(), 3);
+    }
+}
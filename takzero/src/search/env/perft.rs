@@ -0,0 +1,204 @@
+//! Move generation checks for environments.
+//!
+//! These are perft counts, with reference counts for Tak, and invariants
+//! which should hold over random play. Running these after upgrading an
+//! environment catches bugs before they end up in training data.
+
+use std::collections::HashSet;
+
+use rand::{seq::IteratorRandom, Rng};
+use thiserror::Error;
+
+use super::{Environment, Undo};
+
+/// Positions reached from the start of Tak after 0, 1, 2, ... plies, by
+/// board size. Positions where the game has ended are not expanded.
+const REFERENCE_COUNTS: [(usize, &[u64]); 4] = [
+    (3, &[1, 9, 72, 1_200, 17_792, 271_812]),
+    (4, &[1, 16, 240, 7_440, 216_464]),
+    (5, &[1, 25, 600, 43_320, 2_999_784]),
+    (6, &[1, 36, 1_260, 132_720, 13_586_048]),
+];
+
+/// Reference perft counts from the start of Tak for the board size, indexed
+/// by depth.
+#[must_use]
+pub fn reference_counts(size: usize) -> Option<&'static [u64]> {
+    REFERENCE_COUNTS
+        .iter()
+        .find(|(n, _)| *n == size)
+        .map(|(_, counts)| *counts)
+}
+
+/// Number of positions reached after exactly `depth` steps. Positions where
+/// the game has ended are not expanded.
+pub fn perft<E: Environment>(env: &E, depth: usize) -> u64 {
+    if depth == 0 {
+        return 1;
+    }
+    if env.terminal().is_some() {
+        return 0;
+    }
+    let mut actions = Vec::new();
+    env.populate_actions(&mut actions);
+    if depth == 1 {
+        return actions.len() as u64;
+    }
+    actions
+        .into_iter()
+        .map(|action| {
+            let mut next = env.clone();
+            next.step(action);
+            perft(&next, depth - 1)
+        })
+        .sum()
+}
+
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum Inconsistency {
+    #[error("{action} is generated twice after {path}")]
+    DuplicateAction { path: String, action: String },
+    #[error("there are no actions after {path}, but the game has not ended")]
+    NoActions { path: String },
+    #[error("playing {action} after {path} does not count as one step")]
+    Steps { path: String, action: String },
+    #[error("the incremental hash after {path} differs from the hash of the position")]
+    Hash { path: String },
+    #[error("undoing {action} after {path} does not give back the position")]
+    Undo { path: String, action: String },
+}
+
+/// Play random games and check that move generation, steps, and hashes
+/// agree with each other. Returns the number of positions checked.
+///
+/// # Errors
+///
+/// Errors with the first inconsistency and the actions which led to it.
+pub fn check_random_play<E: Environment>(
+    rng: &mut impl Rng,
+    games: usize,
+    max_steps: usize,
+) -> Result<usize, Inconsistency> {
+    check_random_play_with::<E>(rng, games, max_steps, None)
+}
+
+/// Like [`check_random_play`], but also check that undoing every step gives
+/// back the position.
+///
+/// # Errors
+///
+/// Errors with the first inconsistency and the actions which led to it.
+pub fn check_random_play_and_undo<E: Undo>(
+    rng: &mut impl Rng,
+    games: usize,
+    max_steps: usize,
+) -> Result<usize, Inconsistency> {
+    check_random_play_with::<E>(rng, games, max_steps, Some(step_and_undo::<E>))
+}
+
+/// Step a copy of the environment and undo the step again.
+fn step_and_undo<E: Undo>(env: &E, action: &E::Action) -> E {
+    let mut undone = env.clone();
+    let delta = undone.step_with_delta(action.clone());
+    undone.undo(action.clone(), delta);
+    undone
+}
+
+fn check_random_play_with<E: Environment>(
+    rng: &mut impl Rng,
+    games: usize,
+    max_steps: usize,
+    step_and_undo: Option<fn(&E, &E::Action) -> E>,
+) -> Result<usize, Inconsistency> {
+    let mut positions = 0;
+    let mut actions = Vec::new();
+    for _ in 0..games {
+        let mut env = E::default();
+        let mut path = Vec::new();
+        while path.len() < max_steps && env.terminal().is_none() {
+            positions += 1;
+            let describe = |path: &[E::Action]| format!("{path:?}");
+
+            actions.clear();
+            env.populate_actions(&mut actions);
+            let mut seen = HashSet::new();
+            let duplicate = actions.iter().find(|action| !seen.insert(format!("{action:?}")));
+            if let Some(action) = duplicate {
+                return Err(Inconsistency::DuplicateAction {
+                    path: describe(&path),
+                    action: format!("{action:?}"),
+                });
+            }
+            let Some(action) = actions.drain(..).choose(rng) else {
+                return Err(Inconsistency::NoActions {
+                    path: describe(&path),
+                });
+            };
+
+            let mut next = env.clone();
+            next.step(action.clone());
+            if next.steps() != env.steps() + 1 {
+                return Err(Inconsistency::Steps {
+                    path: describe(&path),
+                    action: format!("{action:?}"),
+                });
+            }
+            if env.hash_after(env.hash(), &action, &next) != next.hash() {
+                return Err(Inconsistency::Hash {
+                    path: describe(&path),
+                });
+            }
+            if let Some(step_and_undo) = step_and_undo {
+                let undone = step_and_undo(&env, &action);
+                if undone.hash() != env.hash() || undone.steps() != env.steps() {
+                    return Err(Inconsistency::Undo {
+                        path: describe(&path),
+                        action: format!("{action:?}"),
+                    });
+                }
+            }
+
+            env = next;
+            path.push(action);
+        }
+    }
+    Ok(positions)
+}
+
+#[cfg(test)]
+mod tests {
+    use fast_tak::Game;
+    use rand::{rngs::StdRng, SeedableRng};
+
+    use super::{check_random_play, check_random_play_and_undo, perft, reference_counts};
+    use crate::search::env::connect4::Connect4;
+
+    #[test]
+    fn reference_counts_match() {
+        for (size, depth) in [(3, 4), (4, 3), (5, 3), (6, 2)] {
+            let counts = reference_counts(size).unwrap();
+            let found: Vec<_> = (0..=depth)
+                .map(|depth| match size {
+                    3 => perft(&Game::<3, 0>::default(), depth),
+                    4 => perft(&Game::<4, 0>::default(), depth),
+                    5 => perft(&Game::<5, 4>::default(), depth),
+                    _ => perft(&Game::<6, 4>::default(), depth),
+                })
+                .collect();
+            assert_eq!(found, counts[..=depth], "{size}x{size}");
+        }
+        assert_eq!(reference_counts(9), None);
+
+        // Connect Four has 7 columns until one fills up after 6 stones.
+        assert_eq!(perft(&Connect4::default(), 2), 49);
+        assert_eq!(perft(&Connect4::default(), 7), 823_536);
+    }
+
+    #[test]
+    fn random_play_is_consistent() {
+        let mut rng = StdRng::seed_from_u64(0);
+        assert!(check_random_play::<Game<5, 4>>(&mut rng, 10, 200).unwrap() > 10);
+        assert!(check_random_play_and_undo::<Game<6, 4>>(&mut rng, 10, 200).unwrap() > 10);
+        assert!(check_random_play_and_undo::<Connect4>(&mut rng, 20, 42).unwrap() > 20);
+    }
+}
@@ -7,15 +7,58 @@ use super::{env::Terminal, DISCOUNT_FACTOR};
 #[derive(Clone, Copy, PartialEq, Debug)]
 pub enum Eval {
     Value(NotNan<f32>),
+    /// Probabilities of each outcome, for networks with a WDL head.
+    Wdl(Wdl),
     Win(u32),
     Loss(u32),
     Draw(u32),
 }
 
+/// Probabilities of a win, a draw, and a loss for the player to move.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Wdl {
+    pub win: NotNan<f32>,
+    pub draw: NotNan<f32>,
+    pub loss: NotNan<f32>,
+}
+
+impl Wdl {
+    /// # Errors
+    ///
+    /// Errors if any probability is NaN.
+    pub fn new(win: f32, draw: f32, loss: f32) -> Result<Self, FloatIsNan> {
+        Ok(Self {
+            win: NotNan::new(win)?,
+            draw: NotNan::new(draw)?,
+            loss: NotNan::new(loss)?,
+        })
+    }
+
+    /// The same probabilities for the opponent.
+    #[must_use]
+    pub const fn negate(self) -> Self {
+        Self {
+            win: self.loss,
+            draw: self.draw,
+            loss: self.win,
+        }
+    }
+
+    /// Expected score where a win is 1, a loss is -1, and a draw is
+    /// `draw_score`.
+    #[must_use]
+    pub fn scalar(self, draw_score: f32) -> f32 {
+        self.draw
+            .into_inner()
+            .mul_add(draw_score, self.win.into_inner() - self.loss.into_inner())
+    }
+}
+
 impl fmt::Display for Eval {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             Self::Value(not_nan) => not_nan.into_inner().fmt(f),
+            Self::Wdl(wdl) => write!(f, "Wdl({}/{}/{})", wdl.win, wdl.draw, wdl.loss),
             Self::Win(ply) => write!(f, "Win({ply})"),
             Self::Loss(ply) => write!(f, "Loss({ply})"),
             Self::Draw(ply) => write!(f, "Draw({ply})"),
@@ -36,10 +79,18 @@ impl Eval {
         Self::Value(value)
     }
 
+    /// # Errors
+    ///
+    /// Errors if any probability is NaN.
+    pub fn new_wdl(win: f32, draw: f32, loss: f32) -> Result<Self, FloatIsNan> {
+        Wdl::new(win, draw, loss).map(Self::Wdl)
+    }
+
     #[must_use]
     pub fn negate(&self) -> Self {
         match *self {
             Self::Value(value) => Self::Value(-value),
+            Self::Wdl(wdl) => Self::Wdl(wdl.negate()),
             Self::Win(ply) => Self::Loss(ply + 1),
             Self::Draw(ply) => Self::Draw(ply + 1),
             Self::Loss(ply) => Self::Win(ply + 1),
@@ -69,11 +120,97 @@ impl Eval {
     #[must_use]
     pub const fn ply(&self) -> Option<u32> {
         match self {
-            Self::Value(_) => None,
+            Self::Value(_) | Self::Wdl(_) => None,
             Self::Win(ply) | Self::Draw(ply) | Self::Loss(ply) => Some(*ply),
         }
     }
 
+    /// Probabilities of each outcome, which are certain for known
+    /// evaluations. Plain values do not have them.
+    #[must_use]
+    pub fn wdl(&self) -> Option<Wdl> {
+        let certain = |win, draw, loss| Wdl::new(win, draw, loss).ok();
+        match self {
+            Self::Value(_) => None,
+            Self::Wdl(wdl) => Some(*wdl),
+            Self::Win(_) => certain(1.0, 0.0, 0.0),
+            Self::Draw(_) => certain(0.0, 1.0, 0.0),
+            Self::Loss(_) => certain(0.0, 0.0, 1.0),
+        }
+    }
+
+    /// The evaluation as a value, where a draw (certain or not) is worth
+    /// `draw_score`. Known results are discounted by their distance.
+    #[must_use]
+    pub fn scalar(self, draw_score: f32) -> f32 {
+        self.discounted(DISCOUNT_FACTOR, draw_score)
+    }
+
+    /// Like [`Eval::scalar`], but known results are discounted by `discount`
+    /// for every ply until the end of the game.
+    #[must_use]
+    pub fn discounted(self, discount: f32, draw_score: f32) -> f32 {
+        discount.powi(self.ply().unwrap_or_default() as i32)
+            * match self {
+                Self::Value(x) => x.into(),
+                Self::Wdl(wdl) => wdl.scalar(draw_score),
+                Self::Win(_) => 1.0,
+                Self::Loss(_) => -1.0,
+                Self::Draw(_) => draw_score,
+            }
+    }
+
+    /// The best evaluation for the parent of these children, which picks the
+    /// child that is worst for the opponent. `None` if there are no children.
+    #[must_use]
+    pub fn negamax(children: impl IntoIterator<Item = Self>) -> Option<Self> {
+        children.into_iter().min().map(|eval| eval.negate())
+    }
+
+    /// The worst evaluation for the parent of these children, which picks the
+    /// child that is best for the opponent. `None` if there are no children.
+    #[must_use]
+    pub fn negamin(children: impl IntoIterator<Item = Self>) -> Option<Self> {
+        children.into_iter().max().map(|eval| eval.negate())
+    }
+
+    /// The known result of the parent of these children, if the children
+    /// prove one. A parent wins if any child is a loss for the opponent, and
+    /// otherwise its result is only known once all children are known.
+    #[must_use]
+    pub fn solved<I>(children: I) -> Option<Self>
+    where
+        I: IntoIterator<Item = Self>,
+        I::IntoIter: Clone,
+    {
+        let children = children.into_iter();
+        if children.clone().any(|eval| eval.is_loss())
+            || children.clone().all(|eval| eval.is_known())
+        {
+            Self::negamax(children)
+        } else {
+            None
+        }
+    }
+
+    /// The value of the evaluation in the search, where draws are worth 0.
+    /// Known results are discounted by `mate_discount` for every ply, so
+    /// that faster wins and slower losses are preferred before they are
+    /// proven. A discount of 1 treats all wins alike.
+    ///
+    /// # Panics
+    ///
+    /// Panics if a known result gives NaN, which a discount in `(0, 1]`
+    /// never does.
+    #[must_use]
+    pub fn backup_value(self, mate_discount: f32) -> NotNan<f32> {
+        match self {
+            Self::Value(x) => x,
+            eval => NotNan::new(eval.discounted(mate_discount, 0.0))
+                .expect("known evaluations cannot give NaN"),
+        }
+    }
+
     /// # Panics
     ///
     /// Panics if the return value of the function is NaN.
@@ -92,15 +229,10 @@ impl Default for Eval {
     }
 }
 
+/// Draws are worth 0.
 impl From<Eval> for f32 {
     fn from(value: Eval) -> Self {
-        DISCOUNT_FACTOR.powi(value.ply().unwrap_or_default() as i32)
-            * match value {
-                Eval::Value(x) => x.into(),
-                Eval::Win(_) => 1.0,
-                Eval::Loss(_) => -1.0,
-                Eval::Draw(_) => 0.0,
-            }
+        value.scalar(0.0)
     }
 }
 
@@ -108,7 +240,7 @@ impl From<Eval> for NotNan<f32> {
     fn from(eval: Eval) -> Self {
         match eval {
             Eval::Value(x) => x,
-            Eval::Win(_) | Eval::Draw(_) | Eval::Loss(_) => {
+            Eval::Wdl(_) | Eval::Win(_) | Eval::Draw(_) | Eval::Loss(_) => {
                 Self::new(f32::from(eval)).expect("known evaluations cannot give NaN")
             }
         }
@@ -135,36 +267,39 @@ impl PartialOrd for Eval {
 
 impl Eq for Eval {}
 
+/// Probabilities of each outcome are ordered like the value they convert to,
+/// where draws are worth 0. Only equal evaluations are ordered as equal: a
+/// value of the same worth comes before probabilities, which come before a
+/// draw, and probabilities of the same worth are ordered by themselves.
 impl Ord for Eval {
     fn cmp(&self, other: &Self) -> Ordering {
-        match self {
-            Self::Value(left) => match other {
-                Self::Value(right) => left.cmp(right),
-                Self::Win(_) => Ordering::Less,
-                Self::Draw(_) => left.cmp(&CONTEMPT),
-                Self::Loss(_) => Ordering::Greater,
-            },
-            Self::Win(left) => match other {
-                Self::Win(right) => right.cmp(left),
-                _ => Ordering::Greater,
-            },
-            Self::Draw(left) => match other {
-                Self::Value(right) => CONTEMPT.cmp(right),
-                Self::Win(_) => Ordering::Less,
-                Self::Draw(right) => right.cmp(left),
-                Self::Loss(_) => Ordering::Greater,
-            },
-            Self::Loss(left) => match other {
-                Self::Loss(right) => left.cmp(right),
-                _ => Ordering::Less,
-            },
+        let value = |wdl: Wdl| Self::Value(NotNan::from(Self::Wdl(wdl)));
+        match (*self, *other) {
+            (Self::Wdl(left), Self::Wdl(right)) => value(left)
+                .cmp(&value(right))
+                .then_with(|| {
+                    (left.win, left.draw, left.loss).cmp(&(right.win, right.draw, right.loss))
+                }),
+            (Self::Wdl(wdl), _) => value(wdl).cmp(other).then(Ordering::Greater),
+            (_, Self::Wdl(wdl)) => self.cmp(&value(wdl)).then(Ordering::Less),
+            (Self::Value(left), Self::Value(right)) => left.cmp(&right),
+            (Self::Value(left), Self::Draw(_)) => left.cmp(&CONTEMPT).then(Ordering::Less),
+            (Self::Draw(_), Self::Value(right)) => CONTEMPT.cmp(&right).then(Ordering::Greater),
+            (Self::Win(left), Self::Win(right)) | (Self::Draw(left), Self::Draw(right)) => {
+                right.cmp(&left)
+            }
+            (Self::Loss(left), Self::Loss(right)) => left.cmp(&right),
+            (Self::Win(_), _) | (_, Self::Loss(_)) => Ordering::Greater,
+            (_, Self::Win(_)) | (Self::Loss(_), _) => Ordering::Less,
         }
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use super::{Eval, CONTEMPT};
+    use std::cmp::Ordering;
+
+    use super::{Eval, Wdl, CONTEMPT};
 
     #[test]
     fn eval_order() {
@@ -192,4 +327,62 @@ mod tests {
             Eval::Win(5),
         ]);
     }
+
+    #[test]
+    fn wdl_evals() {
+        let eval = Eval::new_wdl(0.5, 0.3, 0.2).unwrap();
+        assert_eq!(eval.negate(), Eval::new_wdl(0.2, 0.3, 0.5).unwrap());
+        assert!(!eval.is_known());
+        assert_eq!(eval.ply(), None);
+        assert!((f32::from(eval) - 0.3).abs() < 1e-6);
+        assert!((eval.scalar(0.5) - 0.45).abs() < 1e-6);
+        assert!((Eval::Draw(0).scalar(0.5) - 0.5).abs() < 1e-6);
+        assert_eq!(Eval::Win(3).wdl(), Some(Wdl::new(1.0, 0.0, 0.0).unwrap()));
+        assert_eq!(Eval::new_value(0.5).unwrap().wdl(), None);
+
+        let wdl = Eval::new_wdl(0.6, 0.4, 0.0).unwrap();
+        let mut evals = [Eval::Win(3), wdl, Eval::Draw(0), Eval::new_value(0.5).unwrap()];
+        evals.sort();
+        assert_eq!(evals, [Eval::Draw(0), Eval::new_value(0.5).unwrap(), wdl, Eval::Win(3)]);
+
+        // Ordered like their conversion, but only equal to themselves.
+        let even = Eval::new_wdl(0.25, 0.5, 0.25).unwrap();
+        assert!(f32::from(even).abs() < 1e-6);
+        assert!(Eval::new_value(-0.01).unwrap() < even && even < Eval::new_value(0.01).unwrap());
+        assert_ne!(even.cmp(&Eval::new_value(0.0).unwrap()), Ordering::Equal);
+        assert_ne!(even.cmp(&Eval::new_wdl(0.5, 0.0, 0.5).unwrap()), Ordering::Equal);
+        assert_eq!(even.cmp(&even), Ordering::Equal);
+        assert_ne!(Eval::Value(CONTEMPT).cmp(&Eval::Draw(0)), Ordering::Equal);
+    }
+
+    #[test]
+    fn combinators() {
+        let value = |x| Eval::new_value(x).unwrap();
+        let children = [value(0.5), Eval::Win(2), value(-0.2)];
+        assert_eq!(Eval::negamax(children), Some(value(0.2)));
+        assert_eq!(Eval::negamin(children), Some(Eval::Loss(3)));
+        assert_eq!(Eval::negamax([]), None);
+        assert_eq!(Eval::solved(children), None);
+
+        // Any loss for the opponent is a win, and the fastest one is picked.
+        assert_eq!(
+            Eval::solved([value(0.5), Eval::Loss(4), Eval::Loss(2)]),
+            Some(Eval::Win(3))
+        );
+        // Otherwise all children must be known, and a draw beats a loss.
+        assert_eq!(Eval::solved([Eval::Win(1), Eval::Draw(5)]), Some(Eval::Draw(6)));
+        assert_eq!(Eval::solved([Eval::Win(1), Eval::Win(3)]), Some(Eval::Loss(4)));
+        assert_eq!(Eval::solved([]), None);
+    }
+
+    #[test]
+    fn faster_wins_back_up_higher() {
+        let discount = 0.9;
+        assert!(Eval::Win(1).discounted(discount, 0.0) > Eval::Win(5).discounted(discount, 0.0));
+        assert!(Eval::Loss(1).discounted(discount, 0.0) < Eval::Loss(5).discounted(discount, 0.0));
+        assert!((Eval::Win(2).discounted(discount, 0.0) - 0.81).abs() < 1e-6);
+        assert!((Eval::Win(9).discounted(1.0, 0.0) - 1.0).abs() < 1e-6);
+        assert!((Eval::Win(3).backup_value(0.9).into_inner() - 0.729).abs() < 1e-6);
+        assert_eq!(Eval::new_value(0.5).unwrap().backup_value(0.9), 0.5);
+    }
 }
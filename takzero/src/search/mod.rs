@@ -1,7 +1,10 @@
 pub mod agent;
+pub mod builder;
+pub mod dyn_game;
 pub mod env;
 pub mod eval;
 pub mod node;
+pub mod solver;
 
 // Discount, also known as gamma.
 pub const DISCOUNT_FACTOR: f32 = 0.997;
@@ -3,23 +3,32 @@ use std::cmp::Reverse;
 use ordered_float::NotNan;
 use rand::Rng;
 use rand_distr::{Distribution, Gumbel};
+use rayon::{iter::Either, prelude::*};
 
-use super::Node;
+use super::{
+    arena::{NodeId, ROOT},
+    Node,
+    NodeRef,
+};
 use crate::{
+    metrics::REGISTRY,
     search::{
-        agent::Agent,
-        env::{Environment, Terminal},
+        agent::{block_on, Agent, AsyncAgent, Immediate},
+        env::{populate_actions_batch, ActionIndex, Environment, Terminal},
         eval::Eval,
         node::{
             mcts::{ActionPolicy, Forward},
             policy::{sigma_select, softmax},
+            SearchError,
         },
     },
     target::Replay,
 };
 
 // TODO: Use itertools to make the zips nicer.
-// TODO: Add rayon later.
+
+// The tree work of each environment (selection, backup, and bookkeeping) is
+// spread over the rayon pool, while network evaluations stay batched.
 
 pub struct BatchedMCTS<const BATCH_SIZE: usize, E: Environment> {
     nodes: [Node<E>; BATCH_SIZE],
@@ -27,9 +36,12 @@ pub struct BatchedMCTS<const BATCH_SIZE: usize, E: Environment> {
     actions: [Vec<E::Action>; BATCH_SIZE],
     trajectories: [Vec<usize>; BATCH_SIZE],
     replays: [Replay<E>; BATCH_SIZE],
+    /// Parked environments are not searched or stepped.
+    parked: [bool; BATCH_SIZE],
+    active: usize,
 }
 
-impl<const BATCH_SIZE: usize, E: Environment> BatchedMCTS<BATCH_SIZE, E> {
+impl<const BATCH_SIZE: usize, E: ActionIndex> BatchedMCTS<BATCH_SIZE, E> {
     pub fn new(rng: &mut impl Rng) -> Self {
         let mut actions = Vec::new();
         let envs = std::array::from_fn(|_| E::new_opening(rng, &mut actions));
@@ -43,9 +55,56 @@ impl<const BATCH_SIZE: usize, E: Environment> BatchedMCTS<BATCH_SIZE, E> {
             trajectories: std::array::from_fn(|_| Vec::new()),
             replays: std::array::from_fn(|i| Replay::new(envs[i].clone())),
             envs,
+            parked: [false; BATCH_SIZE],
+            active: BATCH_SIZE,
         }
     }
 
+    /// Resume unfinished games from their replays.
+    /// The search trees are not restored.
+    pub fn from_replays(replays: [Replay<E>; BATCH_SIZE]) -> Self {
+        Self {
+            nodes: std::array::from_fn(|_| Node::default()),
+            actions: std::array::from_fn(|_| Vec::new()),
+            trajectories: std::array::from_fn(|_| Vec::new()),
+            envs: std::array::from_fn(|i| {
+                let mut env = replays[i].env.clone();
+                for action in &replays[i].actions {
+                    env.step(action.clone());
+                }
+                env
+            }),
+            replays,
+            parked: [false; BATCH_SIZE],
+            active: BATCH_SIZE,
+        }
+    }
+
+    /// Change how many environments are played at once.
+    ///
+    /// Environments with an index below `active` are resumed immediately.
+    /// The others are parked once their current game ends,
+    /// so that no game is abandoned halfway.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `active` is zero or larger than `BATCH_SIZE`.
+    pub fn set_active(&mut self, active: usize) {
+        assert!((1..=BATCH_SIZE).contains(&active), "active must be in 1..=BATCH_SIZE");
+        self.active = active;
+        self.parked[..active].fill(false);
+    }
+
+    /// Whether each environment is currently being played.
+    pub fn active(&self) -> impl Iterator<Item = bool> + '_ {
+        self.parked.iter().map(|parked| !parked)
+    }
+
+    /// Replays of the games currently being played.
+    pub fn replays(&self) -> impl Iterator<Item = &Replay<E>> {
+        self.replays.iter()
+    }
+
     pub fn nodes_and_envs(&self) -> impl Iterator<Item = (&Node<E>, &E)> {
         self.nodes.iter().zip(&self.envs)
     }
@@ -54,77 +113,107 @@ impl<const BATCH_SIZE: usize, E: Environment> BatchedMCTS<BATCH_SIZE, E> {
         self.nodes.iter_mut().zip(&mut self.envs)
     }
 
-    /// Do a single batched simulation step.
+    /// Do a single batched simulation step. Known results are discounted by
+    /// `mate_discount` for every ply (see [`Eval::backup_value`]).
     ///
     /// # Panics
     ///
     /// Panics if the actions or trajectories are not empty.
-    /// Also panics if any logit is NaN.
-    pub fn simulate<A: Agent<E>>(&mut self, agent: &A, betas: &[f32]) {
+    /// Also panics if any prediction is NaN or missing.
+    pub fn simulate<A: Agent<E>>(&mut self, agent: &A, betas: &[f32], mate_discount: f32) {
+        self.try_simulate(agent, betas, mate_discount).expect("simulation should succeed");
+    }
+
+    /// Like [`BatchedMCTS::simulate`], but fallible. The simulations of
+    /// positions without a usable prediction are cancelled, and the others
+    /// are propagated as usual.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the agent returns a NaN prediction or too few
+    /// predictions.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the actions or trajectories are not empty.
+    pub fn try_simulate<A: Agent<E>>(
+        &mut self,
+        agent: &A,
+        betas: &[f32],
+        mate_discount: f32,
+    ) -> Result<(), SearchError> {
+        block_on(self.simulate_async(&Immediate(agent), betas, mate_discount))
+    }
+
+    /// Like [`BatchedMCTS::try_simulate`], but waits for the predictions of
+    /// an asynchronous agent instead of blocking the thread. The future is
+    /// [`Send`] when the agent is [`Sync`].
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the agent returns a NaN prediction or too few
+    /// predictions, or cannot evaluate the batch at all. In the last case all
+    /// simulations of the batch are cancelled.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the actions or trajectories are not empty.
+    #[allow(clippy::future_not_send)]
+    pub async fn simulate_async<A: AsyncAgent<E>>(
+        &mut self,
+        agent: &A,
+        betas: &[f32],
+        mate_discount: f32,
+    ) -> Result<(), SearchError> {
         assert!(self.actions.iter().all(Vec::is_empty));
         assert!(self.trajectories.iter().all(Vec::is_empty));
 
         // Forward pass.
-        let (batch, forward): (Vec<_>, Vec<_>) = self
+        let (leaves, errors): (Vec<_>, Vec<_>) = self
             .nodes
-            .iter_mut()
-            .zip(&self.envs)
-            .zip(&mut self.actions)
-            .zip(&mut self.trajectories)
-            .zip(betas)
-            .filter_map(|((((node, env), actions), trajectory), beta)| {
-                match node.forward(trajectory, env.clone(), *beta) {
-                    Forward::Known(eval) => {
+            .par_iter_mut()
+            .zip(self.envs.par_iter())
+            .zip(self.actions.par_iter_mut())
+            .zip(self.trajectories.par_iter_mut())
+            .zip(betas.par_iter())
+            .zip(self.parked.par_iter())
+            .filter_map(|(((((node, env), actions), trajectory), beta), parked)| {
+                // Parked roots are only initialized so that they have children.
+                if *parked && !node.needs_initialization() {
+                    return None;
+                }
+                match node.forward(trajectory, env.clone(), *beta, mate_discount) {
+                    Ok(Forward::Known(eval)) => {
                         // If the result is known just propagate it now.
-                        node.backward_known_eval(trajectory.drain(..), eval);
+                        node.backward_known_eval(trajectory.drain(..), eval, mate_discount);
                         None
                     }
-                    Forward::NeedsNetwork(env) => {
+                    Ok(Forward::NeedsNetwork(env)) => {
                         env.populate_actions(actions);
                         // We are taking the actions because we need owned Vecs.
-                        Some(((env, std::mem::take(actions)), (node, trajectory, actions)))
+                        Some(Either::Left((
+                            (env, std::mem::take(actions)),
+                            (node, ROOT, trajectory, actions),
+                        )))
                     }
+                    Err(err) => Some(Either::Right(err)),
                 }
             })
-            .unzip();
+            .partition_map(|leaf| leaf);
+        // Leaves which were reached are still evaluated before a failed
+        // forward pass is reported.
+        let forward_failed = errors.into_iter().next();
+        let (batch, forward): (Vec<_>, Vec<_>) = leaves.into_iter().unzip();
         if batch.is_empty() {
-            return;
+            return forward_failed.map_or(Ok(()), Err);
         }
+        record_batch_occupancy(batch.len(), BATCH_SIZE);
 
         // Backward pass.
         let (env_batch, actions_batch): (Vec<_>, Vec<_>) = batch.into_iter().unzip();
-        let output = agent.policy_value_uncertainty(&env_batch, &actions_batch);
-        forward
-            .into_iter()
-            .zip(
-                #[allow(clippy::needless_collect)]
-                output.collect::<Vec<_>>(),
-            )
-            .zip(actions_batch)
-            .for_each(|((forward, output), mut moved_actions)| {
-                let (node, trajectory, old_actions) = forward;
-                let (policy, value, uncertainty) = output;
-
-                // Calculate probabilities from logits.
-                let probabilities = softmax(policy.clone().into_iter().map(|(_, p)| p));
-                // Do backwards pass.
-                node.backward_network_eval(
-                    trajectory.drain(..),
-                    policy
-                        .into_iter()
-                        .zip(probabilities)
-                        .map(|((action, logit), probability)| ActionPolicy {
-                            action,
-                            logit,
-                            probability,
-                        }),
-                    value,
-                    uncertainty,
-                );
-                // Restore old actions.
-                moved_actions.clear();
-                let _ = std::mem::replace(old_actions, moved_actions);
-            });
+        let propagated =
+            backward_batch(agent, &env_batch, actions_batch, forward, mate_discount).await;
+        forward_failed.map_or(propagated, Err)
     }
 
     /// Takes a step in all environments and nodes.
@@ -134,12 +223,13 @@ impl<const BATCH_SIZE: usize, E: Environment> BatchedMCTS<BATCH_SIZE, E> {
     /// Panics if there are fewer or more actions than `BATCH_SIZE`.
     pub fn step(&mut self, actions: &[E::Action; BATCH_SIZE]) {
         self.nodes
-            .iter_mut()
-            .zip(&mut self.envs)
-            .zip(&mut self.replays)
-            .zip(actions)
-            .for_each(|(((node, env), replay), action)| {
-                if !node.is_terminal() {
+            .par_iter_mut()
+            .zip(self.envs.par_iter_mut())
+            .zip(self.replays.par_iter_mut())
+            .zip(actions.par_iter())
+            .zip(self.parked.par_iter())
+            .for_each(|((((node, env), replay), action), parked)| {
+                if !node.is_terminal() && !parked {
                     node.descend(action);
                     replay.push(action.clone());
                     env.step(action.clone());
@@ -147,6 +237,31 @@ impl<const BATCH_SIZE: usize, E: Environment> BatchedMCTS<BATCH_SIZE, E> {
             });
     }
 
+    /// Throw away the search trees and keep the games, so that the next
+    /// search starts from scratch, for example after a failed one.
+    pub fn reset_trees(&mut self) {
+        self.nodes.iter_mut().for_each(Node::clear);
+    }
+
+    /// Start a new game in every environment, abandoning the current games
+    /// and their search trees. Environments with an index of at least
+    /// `active` are parked.
+    pub fn restart_all_envs(&mut self, rng: &mut impl Rng) {
+        self.reset_trees();
+        for ((env, actions), replay) in self
+            .envs
+            .iter_mut()
+            .zip(&mut self.actions)
+            .zip(&mut self.replays)
+        {
+            *env = env.next_opening(rng, actions);
+            *replay = Replay::new(env.clone());
+        }
+        for (i, parked) in self.parked.iter_mut().enumerate() {
+            *parked = i >= self.active;
+        }
+    }
+
     pub fn apply_noise(&mut self, rng: &mut impl Rng, noise_alpha: f32, noise_ratio: f32) {
         self.nodes
             .iter_mut()
@@ -154,12 +269,43 @@ impl<const BATCH_SIZE: usize, E: Environment> BatchedMCTS<BATCH_SIZE, E> {
             .for_each(|(node, _)| node.apply_dirichlet(rng, noise_alpha, noise_ratio));
     }
 
+    /// Resample the selected actions of environments which would otherwise
+    /// keep playing exactly the same game as an earlier environment.
+    /// Actions chosen by the earlier duplicates are excluded, so the games
+    /// diverge whenever there is another action to play.
+    /// Returns the number of resampled actions.
+    pub fn diverge_duplicates(
+        &self,
+        selected_actions: &mut [E::Action; BATCH_SIZE],
+        temperature: f32,
+        rng: &mut impl Rng,
+    ) -> usize
+    where
+        E: PartialEq,
+    {
+        let mut resampled = 0;
+        for i in (0..BATCH_SIZE).filter(|i| !self.parked[*i]) {
+            let taken: Vec<_> = (0..i)
+                .filter(|j| !self.parked[*j] && self.replays[*j] == self.replays[i])
+                .map(|j| selected_actions[j].clone())
+                .collect();
+            if !taken.contains(&selected_actions[i]) {
+                continue;
+            }
+            let node = &self.nodes[i];
+            if let Some(action) = node.sample_action_with_temperature(temperature, &taken, rng) {
+                selected_actions[i] = action;
+                resampled += 1;
+            }
+        }
+        resampled
+    }
+
     #[allow(clippy::missing_panics_doc)]
     pub fn select_best_actions(&self) -> [E::Action; BATCH_SIZE] {
         self.nodes
-            .iter()
-            .zip(&self.envs)
-            .map(|(node, _)| node.select_best_action())
+            .par_iter()
+            .map(Node::select_best_action)
             .collect::<Vec<_>>()
             .try_into()
             .expect("the number of nodes and envs should be equal to BATCH_SIZE")
@@ -191,51 +337,122 @@ impl<const BATCH_SIZE: usize, E: Environment> BatchedMCTS<BATCH_SIZE, E> {
             .zip(&mut self.envs)
             .zip(&mut self.actions)
             .zip(&mut self.replays)
-            .map(|(((node, env), actions), replay)| {
+            .zip(&mut self.parked)
+            .enumerate()
+            .map(|(i, ((((node, env), actions), replay), parked))| {
                 let terminal = env.terminal();
                 if terminal.is_some() {
                     // Reset game.
-                    *env = E::new_opening(rng, actions);
-                    *node = Node::default();
+                    *env = env.next_opening(rng, actions);
+                    node.clear();
+                    *parked = i >= self.active;
                 }
                 terminal.map(|t| (t, std::mem::replace(replay, Replay::new(env.clone()))))
             })
     }
 
-    #[allow(clippy::too_many_lines)]
-    #[allow(clippy::missing_panics_doc)]
+    /// Search with Gumbel sequential halving and return the selected action
+    /// of every environment.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the search budget is not a multiple of k*log2(k) where k is
+    /// the number of sampled actions, if a root has no children, or if any
+    /// prediction is NaN or missing.
     pub fn gumbel_sequential_halving<A: Agent<E>>(
         &mut self,
         agent: &A,
         betas: &[f32],
+        mate_discount: f32,
         sampled_actions: usize,
         search_budget: u32,
         rng: &mut impl Rng,
     ) -> [E::Action; BATCH_SIZE] {
-        assert!(sampled_actions > 0, "At least one action must be sampled");
-        assert_eq!(
-            search_budget % (sampled_actions.ilog2() * sampled_actions as u32),
-            0,
-            "The search budget should be a multiple of k*log2(k) for clean visits"
-        );
+        self.try_gumbel_sequential_halving(
+            agent,
+            betas,
+            mate_discount,
+            sampled_actions,
+            search_budget,
+            rng,
+        )
+        .expect("sequential halving should succeed")
+    }
+
+    /// Like [`BatchedMCTS::gumbel_sequential_halving`], but fallible.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`SearchError::Budget`] if the search budget is not a
+    /// multiple of k*log2(k) for clean visits, [`SearchError::NoChildren`] if
+    /// a root has no children (for example because its game is over), or an
+    /// error if the agent returns a NaN prediction or too few predictions.
+    /// The search is stopped at the first failed batch, leaving the trees
+    /// searched as far as they got, so the batch can still be stepped or
+    /// restarted.
+    pub fn try_gumbel_sequential_halving<A: Agent<E>>(
+        &mut self,
+        agent: &A,
+        betas: &[f32],
+        mate_discount: f32,
+        sampled_actions: usize,
+        search_budget: u32,
+        rng: &mut impl Rng,
+    ) -> Result<[E::Action; BATCH_SIZE], SearchError> {
+        block_on(self.gumbel_sequential_halving_async(
+            &Immediate(agent),
+            betas,
+            mate_discount,
+            sampled_actions,
+            search_budget,
+            rng,
+        ))
+    }
+
+    /// Like [`BatchedMCTS::try_gumbel_sequential_halving`], but waits for
+    /// the predictions of an asynchronous agent instead of blocking the
+    /// thread. The future is [`Send`] when the agent is [`Sync`] and the
+    /// random number generator is [`Send`].
+    ///
+    /// # Errors
+    ///
+    /// The same as [`BatchedMCTS::try_gumbel_sequential_halving`], and
+    /// [`SearchError::Agent`] if the agent cannot evaluate a batch at all.
+    #[allow(clippy::too_many_lines)]
+    #[allow(clippy::missing_panics_doc)]
+    #[allow(clippy::future_not_send)]
+    pub async fn gumbel_sequential_halving_async<A: AsyncAgent<E>>(
+        &mut self,
+        agent: &A,
+        betas: &[f32],
+        mate_discount: f32,
+        sampled_actions: usize,
+        search_budget: u32,
+        rng: &mut impl Rng,
+    ) -> Result<[E::Action; BATCH_SIZE], SearchError> {
+        check_budget(sampled_actions, search_budget)?;
 
         // Do a single batched step to make sure all roots are initialized.
-        self.simulate(agent, betas);
+        self.simulate_async(agent, betas, mate_discount).await?;
+        if self.nodes.iter().any(|node| node.children().is_empty()) {
+            return Err(SearchError::NoChildren);
+        }
 
         // Generate Gumbel noise.
         let gumbel_distr = Gumbel::new(0.0, 1.0).unwrap();
         let mut gumbel_noise = gumbel_distr.sample_iter(rng);
 
-        // Sample actions based on logits + Gumbel noise.
+        // Sample actions based on logits + Gumbel noise. The children are
+        // kept by their index in the arena of their tree.
         let mut selected_sets: Vec<Vec<_>> = self
             .nodes
-            .iter_mut()
+            .iter()
             .map(|node| {
                 let mut selected_set: Vec<_> = node
-                    .children
-                    .iter_mut()
+                    .children()
+                    .iter()
                     .zip(gumbel_noise.by_ref())
-                    .map(|((a, child), gumbel_noise)| (child.logit + gumbel_noise, a, child))
+                    .map(|((a, child), gumbel_noise)| (child.logit() + gumbel_noise, a, child.id))
                     .collect();
                 selected_set.sort_by_key(|(x, ..)| Reverse(*x));
                 selected_set.truncate(sampled_actions);
@@ -248,18 +465,21 @@ impl<const BATCH_SIZE: usize, E: Environment> BatchedMCTS<BATCH_SIZE, E> {
         let mut visits_to_most_visited_action = 0;
         let mut remaining_actions = sampled_actions;
 
-        for _ in 0..steps {
+        let mut failed = None;
+        'halving: for _ in 0..steps {
             let visits_per_action = visits_per_step / remaining_actions as u32;
 
             for i in 0..remaining_actions {
-                let mut nodes_and_envs: Vec<_> = selected_sets
-                    .iter_mut()
-                    .zip(&self.envs)
-                    .map(|(set, env)| {
+                let mut nodes_and_envs: Vec<_> = self
+                    .nodes
+                    .par_iter_mut()
+                    .zip(selected_sets.par_iter())
+                    .zip(self.envs.par_iter())
+                    .map(|((node, set), env)| {
                         let mut env = env.clone();
                         let i: usize = i % set.len();
                         env.step(set[i].1.clone());
-                        (&mut *set[i].2, env)
+                        (node, set[i].2, env)
                     })
                     .collect();
                 for _ in 0..visits_per_action {
@@ -270,66 +490,69 @@ impl<const BATCH_SIZE: usize, E: Environment> BatchedMCTS<BATCH_SIZE, E> {
                     assert!(self.trajectories.iter().all(Vec::is_empty));
 
                     // Forward pass.
-                    let (batch, forward): (Vec<_>, Vec<_>) = nodes_and_envs
-                        .iter_mut()
-                        .zip(&mut self.actions)
-                        .zip(&mut self.trajectories)
-                        .zip(betas)
-                        .filter_map(|((((node, env), actions), trajectory), _beta)| {
-                            match node.forward(trajectory, env.clone(), 0.0 /* *beta */) {
-                                Forward::Known(eval) => {
+                    let (leaves, errors): (Vec<_>, Vec<_>) = nodes_and_envs
+                        .par_iter_mut()
+                        .zip(self.actions.par_iter_mut())
+                        .zip(self.trajectories.par_iter_mut())
+                        .zip(betas.par_iter())
+                        .zip(self.parked.par_iter())
+                        .filter(|(_, parked)| !**parked)
+                        .filter_map(|(((((node, child, env), actions), trajectory), _beta), _)| {
+                            match node.forward_from(
+                                *child,
+                                trajectory,
+                                env.clone(),
+                                0.0, /* *beta */
+                                mate_discount,
+                            ) {
+                                Ok(Forward::Known(eval)) => {
                                     // If the result is known just propagate it now.
-                                    node.backward_known_eval(trajectory.drain(..), eval);
+                                    node.backward_known_eval_from(
+                                        *child,
+                                        trajectory.drain(..),
+                                        eval,
+                                        mate_discount,
+                                    );
                                     None
                                 }
-                                Forward::NeedsNetwork(env) => {
-                                    env.populate_actions(actions);
-                                    // We are taking the actions because we need owned Vecs.
-                                    Some((
-                                        (env, std::mem::take(actions)),
-                                        (node, trajectory, actions),
-                                    ))
-                                }
+                                Ok(Forward::NeedsNetwork(env)) => Some(Either::Left((
+                                    env,
+                                    (&mut **node, *child, trajectory, actions),
+                                ))),
+                                Err(err) => Some(Either::Right(err)),
                             }
                         })
-                        .unzip();
-                    if batch.is_empty() {
+                        .partition_map(|leaf| leaf);
+                    let forward_failed = errors.into_iter().next();
+                    let (env_batch, mut forward): (Vec<_>, Vec<_>) = leaves.into_iter().unzip();
+                    if env_batch.is_empty() {
+                        if let Some(err) = forward_failed {
+                            failed = Some(err);
+                            break 'halving;
+                        }
                         continue;
                     }
+                    record_batch_occupancy(env_batch.len(), BATCH_SIZE);
+
+                    // Generate the actions of all leaves in parallel.
+                    // We are taking the actions because we need owned Vecs.
+                    let mut actions_batch: Vec<_> = forward
+                        .iter_mut()
+                        .map(|(.., actions)| std::mem::take(*actions))
+                        .collect();
+                    let terminals = populate_actions_batch(&env_batch, &mut actions_batch);
+                    assert!(
+                        terminals.iter().all(Option::is_none),
+                        "the forward pass should stop at terminal nodes instead of returning them"
+                    );
 
                     // Backward pass.
-                    let (env_batch, actions_batch): (Vec<_>, Vec<_>) = batch.into_iter().unzip();
-                    let output = agent.policy_value_uncertainty(&env_batch, &actions_batch);
-                    forward
-                        .into_iter()
-                        .zip(
-                            #[allow(clippy::needless_collect)]
-                            output.collect::<Vec<_>>(),
-                        )
-                        .zip(actions_batch)
-                        .for_each(|((forward, output), mut moved_actions)| {
-                            let (node, trajectory, old_actions) = forward;
-                            let (policy, value, uncertainty) = output;
-
-                            // Calculate probabilities from logits.
-                            let probabilities = softmax(policy.clone().into_iter().map(|(_, p)| p));
-                            // Do backwards pass.
-                            node.backward_network_eval(
-                                trajectory.drain(..),
-                                policy.into_iter().zip(probabilities).map(
-                                    |((action, logit), probability)| ActionPolicy {
-                                        action,
-                                        logit,
-                                        probability,
-                                    },
-                                ),
-                                value,
-                                uncertainty,
-                            );
-                            // Restore old actions.
-                            moved_actions.clear();
-                            let _ = std::mem::replace(old_actions, moved_actions);
-                        });
+                    let propagated =
+                        backward_batch(agent, &env_batch, actions_batch, forward, mate_discount);
+                    if let Some(err) = propagated.await.err().or(forward_failed) {
+                        failed = Some(err);
+                        break 'halving;
+                    }
 
                     // ============================================================================
                 }
@@ -339,72 +562,205 @@ impl<const BATCH_SIZE: usize, E: Environment> BatchedMCTS<BATCH_SIZE, E> {
             remaining_actions /= 2;
 
             // Halve the number of actions.
-            for (selected_set, &beta) in selected_sets.iter_mut().zip(betas) {
-                selected_set.sort_by_key(|(logits_plus_gumbel, _, child)| {
-                    Reverse(
-                        logits_plus_gumbel
-                            + sigma_select(
-                                child.evaluation.negate().into(),
-                                child.std_dev,
-                                beta,
-                                visits_to_most_visited_action as f32,
-                            ),
-                    )
+            selected_sets
+                .par_iter_mut()
+                .zip(self.nodes.par_iter())
+                .zip(betas.par_iter())
+                .for_each(|((selected_set, node), &beta)| {
+                    selected_set.sort_by_key(|&(logits_plus_gumbel, _, child)| {
+                        let child = NodeRef::new(&node.arena, child);
+                        Reverse(
+                            logits_plus_gumbel
+                                + sigma_select(
+                                    child.evaluation().negate().backup_value(mate_discount),
+                                    child.std_dev(),
+                                    beta,
+                                    visits_to_most_visited_action as f32,
+                                ),
+                        )
+                    });
+                    selected_set.truncate(remaining_actions);
                 });
-                selected_set.truncate(remaining_actions);
-            }
+        }
+
+        if let Some(err) = failed {
+            return Err(err);
         }
 
         let selected = selected_sets
             .into_iter()
             .map(|mut selected_set| {
-                assert_eq!(
+                debug_assert_eq!(
                     selected_set.len(),
                     1,
                     "After sequential halving, every set should have exactly 1 action left"
                 );
-                selected_set.pop().unwrap().1.clone()
+                selected_set.pop().map(|(_, action, _)| action)
             })
-            .collect::<Vec<_>>()
+            .collect::<Option<Vec<_>>>()
+            .ok_or(SearchError::NoChildren)?
             .try_into()
-            .unwrap();
+            .expect("the number of nodes should be equal to BATCH_SIZE");
 
         // Recompute root statistics.
-        self.nodes.iter_mut().for_each(|node| {
-            node.visit_count = node
-                .children
-                .iter()
-                .map(|(_, child)| child.visit_count)
-                .sum::<u32>()
-                + 1;
-
-            let evaluations = node.children.iter().map(|(_, child)| &child.evaluation);
-            if evaluations.clone().any(Eval::is_loss) || evaluations.clone().all(Eval::is_known) {
-                // Node is solved.
-                node.evaluation = evaluations.min().unwrap().negate();
-                node.std_dev = NotNan::default();
-            } else {
-                // Slightly different formula than in the Gumbel MuZero paper.
-                // Here we are ignoring the original network eval because we no longer have
-                // access to it.
-                let visited_children = node
-                    .children
+        self.nodes
+            .par_iter_mut()
+            .zip(self.parked.par_iter())
+            .for_each(|(node, parked)| {
+                if *parked {
+                    return;
+                }
+                let root = node.root();
+                let visit_count = root
+                    .children()
                     .iter()
-                    .map(|(_, child)| child)
-                    .filter(|child| child.visit_count > 0);
-                let sum_of_probabilities: NotNan<f32> = visited_children
-                    .clone()
-                    .map(|child| child.probability)
-                    .sum();
-                let weighted_q: NotNan<f32> = visited_children
-                    .map(|child| child.probability * f32::from(child.evaluation.negate()))
-                    .sum();
-                node.evaluation = Eval::new_not_nan_value(weighted_q / sum_of_probabilities);
-            }
+                    .map(|(_, child)| child.real_visit_count())
+                    .sum::<u32>()
+                    + 1;
+
+                let evaluations = root.children().iter().map(|(_, child)| child.evaluation());
+                let evaluation = if let Some(solved) = Eval::solved(evaluations) {
+                    *node.arena.std_dev_mut(ROOT) = NotNan::default();
+                    solved
+                } else {
+                    // Slightly different formula than in the Gumbel MuZero paper.
+                    // Here we are ignoring the original network eval because we no longer have
+                    // access to it.
+                    let visited_children = root
+                        .children()
+                        .iter()
+                        .map(|(_, child)| child)
+                        .filter(|child| child.real_visit_count() > 0);
+                    let sum_of_probabilities: NotNan<f32> = visited_children
+                        .clone()
+                        .map(NodeRef::probability)
+                        .sum();
+                    let weighted_q: NotNan<f32> = visited_children
+                        .map(|child| {
+                            child.probability()
+                                * child.evaluation().negate().backup_value(mate_discount)
+                        })
+                        .sum();
+                    Eval::new_not_nan_value(weighted_q / sum_of_probabilities)
+                };
+                *node.arena.visit_count_mut(ROOT) = visit_count;
+                node.arena.set_evaluation(ROOT, evaluation);
+
+                // FIXME: std_dev is not recomputed
+            });
+
+        Ok(selected)
+    }
+}
 
-            // FIXME: std_dev is not recomputed
+/// Check that the search budget is a positive multiple of k*log2(k), where k
+/// is the number of sampled actions, so that sequential halving gives every
+/// remaining action the same number of visits.
+///
+/// # Errors
+///
+/// Returns [`SearchError::Budget`] if it is not.
+pub fn check_budget(sampled_actions: usize, search_budget: u32) -> Result<(), SearchError> {
+    let unit = sampled_actions.checked_ilog2().unwrap_or_default() * sampled_actions as u32;
+    if unit == 0 || search_budget % unit != 0 {
+        return Err(SearchError::Budget {
+            sampled_actions,
+            search_budget,
         });
+    }
+    Ok(())
+}
 
-        selected
+/// A tree reached by a forward pass, with the node the pass started at, its
+/// trajectory from there, and the vector the actions of its leaf are moved
+/// into.
+type Leaf<'a, E> = (
+    &'a mut Node<E>,
+    NodeId,
+    &'a mut Vec<usize>,
+    &'a mut Vec<<E as Environment>::Action>,
+);
+
+/// Propagate the predictions of the agent for a batch of leaves which were
+/// reached by forward passes, and give the moved action vectors back.
+/// The forward passes of leaves without a usable prediction are cancelled,
+/// so the trajectories are empty afterwards either way.
+#[allow(clippy::future_not_send)]
+async fn backward_batch<E: ActionIndex, A: AsyncAgent<E>>(
+    agent: &A,
+    env_batch: &[E],
+    actions_batch: Vec<Vec<E::Action>>,
+    forward: Vec<Leaf<'_, E>>,
+    mate_discount: f32,
+) -> Result<(), SearchError> {
+    // Leaves without a prediction get `None`.
+    let evaluated = agent.evaluate(env_batch, &actions_batch).await;
+    let (mut output, unavailable): (Vec<_>, _) = match evaluated {
+        Ok(output) => (output.into_iter().map(Some).collect(), None),
+        Err(err) => (Vec::new(), Some(err)),
+    };
+    output.resize_with(forward.len(), || None);
+    let errors: Vec<_> = forward
+        .into_par_iter()
+        .zip(actions_batch)
+        .zip(output)
+        .map(|((forward, mut moved_actions), output)| {
+            let (node, start, trajectory, old_actions) = forward;
+            let propagated = match output {
+                // An empty policy for a position with actions means that the
+                // logits were NaN (see `gather_policy`).
+                Some((policy, ..)) if policy.is_empty() && !moved_actions.is_empty() => {
+                    Err(SearchError::Nan("policy"))
+                }
+                Some((policy, value, uncertainty)) => {
+                    // Calculate probabilities from logits.
+                    let probabilities = softmax(policy.clone().into_iter().map(|(_, p)| p));
+                    // Do backwards pass.
+                    node.try_backward_network_eval_from(
+                        start,
+                        trajectory.iter().copied(),
+                        policy
+                            .into_iter()
+                            .zip(probabilities)
+                            .map(|((action, _), probability)| ActionPolicy {
+                                action,
+                                probability,
+                            }),
+                        value,
+                        uncertainty,
+                        mate_discount,
+                    )
+                }
+                None => Err(SearchError::MissingPrediction),
+            };
+            if propagated.is_err() {
+                node.cancel_forward_from(start, trajectory);
+            }
+            trajectory.clear();
+            // Restore old actions.
+            moved_actions.clear();
+            *old_actions = moved_actions;
+            propagated.err()
+        })
+        .collect();
+    // Every leaf is handled before the first error is reported.
+    if let Some(err) = unavailable {
+        return Err(err.into());
     }
+    errors.into_iter().flatten().next().map_or(Ok(()), Err)
+}
+
+/// Count how many positions are sent to the network compared to the capacity
+/// of the batch, so that the occupancy can be computed as a ratio of rates.
+fn record_batch_occupancy(evaluations: usize, capacity: usize) {
+    REGISTRY.inc_counter(
+        "takzero_network_evaluations_total",
+        "Positions evaluated by the network during search.",
+        evaluations as f64,
+    );
+    REGISTRY.inc_counter(
+        "takzero_network_batch_capacity_total",
+        "Positions which could have been evaluated with full batches.",
+        capacity as f64,
+    );
 }
@@ -0,0 +1,165 @@
+//! The children of a node, borrowed from the arena of its tree.
+//!
+//! Children are a range of the arena (see [`super::arena`]), so this is a
+//! view which is cheap to copy. Iterating yields each action with the child
+//! it leads to.
+
+use std::{iter::FusedIterator, ops::Range};
+
+use super::{
+    arena::{Arena, NodeId},
+    NodeRef,
+};
+use crate::search::env::{ActionIndex, Environment};
+
+pub struct Children<'a, E: Environment> {
+    arena: &'a Arena<E>,
+    ids: Range<NodeId>,
+}
+
+impl<E: Environment> Clone for Children<'_, E> {
+    fn clone(&self) -> Self {
+        Self {
+            arena: self.arena,
+            ids: self.ids.clone(),
+        }
+    }
+}
+
+impl<'a, E: ActionIndex> Children<'a, E> {
+    #[inline]
+    pub(super) const fn new(arena: &'a Arena<E>, ids: Range<NodeId>) -> Self {
+        Self { arena, ids }
+    }
+
+    #[inline]
+    #[must_use]
+    pub fn len(&self) -> usize {
+        self.ids.len()
+    }
+
+    #[inline]
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.ids.is_empty()
+    }
+
+    /// The child with the given index among its siblings, and its action.
+    #[inline]
+    #[must_use]
+    pub fn get(&self, index: usize) -> Option<(E::Action, NodeRef<'a, E>)> {
+        self.ids.clone().nth(index).map(|id| self.child(id))
+    }
+
+    #[inline]
+    #[must_use]
+    pub fn iter(&self) -> Iter<'a, E> {
+        self.clone().into_iter()
+    }
+
+    #[inline]
+    fn child(&self, id: NodeId) -> (E::Action, NodeRef<'a, E>) {
+        (self.arena.action(id), NodeRef::new(self.arena, id))
+    }
+}
+
+impl<'a, E: ActionIndex> IntoIterator for Children<'a, E> {
+    type IntoIter = Iter<'a, E>;
+    type Item = (E::Action, NodeRef<'a, E>);
+
+    fn into_iter(self) -> Self::IntoIter {
+        Iter { children: self }
+    }
+}
+
+impl<'a, E: ActionIndex> IntoIterator for &Children<'a, E> {
+    type IntoIter = Iter<'a, E>;
+    type Item = (E::Action, NodeRef<'a, E>);
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
+
+/// Iterator over the children of a node, with their actions.
+pub struct Iter<'a, E: Environment> {
+    children: Children<'a, E>,
+}
+
+impl<E: Environment> Clone for Iter<'_, E> {
+    fn clone(&self) -> Self {
+        Self {
+            children: self.children.clone(),
+        }
+    }
+}
+
+impl<'a, E: ActionIndex> Iterator for Iter<'a, E> {
+    type Item = (E::Action, NodeRef<'a, E>);
+
+    #[inline]
+    fn next(&mut self) -> Option<Self::Item> {
+        let id = self.children.ids.next()?;
+        Some(self.children.child(id))
+    }
+
+    #[inline]
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.children.ids.size_hint()
+    }
+}
+
+impl<E: ActionIndex> DoubleEndedIterator for Iter<'_, E> {
+    #[inline]
+    fn next_back(&mut self) -> Option<Self::Item> {
+        let id = self.children.ids.next_back()?;
+        Some(self.children.child(id))
+    }
+}
+
+impl<E: ActionIndex> ExactSizeIterator for Iter<'_, E> {}
+
+impl<E: ActionIndex> FusedIterator for Iter<'_, E> {}
+
+#[cfg(test)]
+mod tests {
+    use fast_tak::Game;
+
+    use crate::search::{
+        agent::dummy::Dummy,
+        env::{connect4::Connect4, Environment},
+        node::Node,
+        DISCOUNT_FACTOR,
+    };
+
+    #[test]
+    fn children_are_in_order() {
+        let mut node = Node::default();
+        assert!(node.children().is_empty());
+        node.simulate_simple(&Dummy, Connect4::default(), 0.0, DISCOUNT_FACTOR);
+        let mut actions = Vec::new();
+        Connect4::default().populate_actions(&mut actions);
+        let children = node.children();
+        assert_eq!(children.len(), actions.len());
+        assert!(children.iter().map(|(action, _)| action).eq(actions.iter().copied()));
+        assert!(children.iter().all(|(_, child)| child.children().is_empty()));
+        let (action, _) = children.get(2).unwrap();
+        assert_eq!(action, actions[2]);
+        assert!(children.get(actions.len()).is_none());
+    }
+
+    #[test]
+    fn descending_keeps_the_subtree() {
+        let mut root = Node::default();
+        for _ in 0..256 {
+            root.simulate_simple(&Dummy, Game::<5, 4>::default(), 0.0, DISCOUNT_FACTOR);
+        }
+        let action = root.select_best_action();
+        let (_, child) = root.children().iter().find(|(a, _)| *a == action).unwrap();
+        let (visits, nodes) = (child.visit_count(), child.node_count());
+        let memory = root.approx_memory_bytes();
+        root.descend(&action);
+        assert_eq!((root.visit_count(), root.node_count()), (visits, nodes));
+        assert!(root.approx_memory_bytes() < memory);
+    }
+}
@@ -1,59 +1,283 @@
-use std::fmt;
+use std::{
+    cmp::Reverse,
+    fmt::{self, Write},
+};
 
 use ordered_float::NotNan;
 
 use super::{
-    super::{env::Environment, eval::Eval},
+    super::{env::ActionIndex, eval::Eval, DISCOUNT_FACTOR},
     policy::upper_confidence_bound_with_predictor,
     Node,
+    NodeRef,
 };
 
-impl<E: Environment> fmt::Display for Node<E>
+/// How to render the top of a search tree as a text table, see
+/// [`Node::render`].
+#[derive(Debug, Clone)]
+pub struct TreeRender {
+    /// Maximum depth below the root, which is at depth 0.
+    pub max_depth: usize,
+    /// Children shown per node after sorting, or all of them.
+    pub top_k: Option<usize>,
+    /// Children with fewer visits are left out.
+    pub min_visits: u32,
+    pub sort: SortKey,
+    pub columns: Vec<Column>,
+    /// Mark the nodes on the principal variation with `*`.
+    pub highlight_pv: bool,
+}
+
+impl Default for TreeRender {
+    /// The children of the root with all columns, most visited first.
+    fn default() -> Self {
+        Self {
+            max_depth: 1,
+            top_k: None,
+            min_visits: 0,
+            sort: SortKey::Visits,
+            columns: Column::ALL.to_vec(),
+            highlight_pv: true,
+        }
+    }
+}
+
+/// The order of the children of a node.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SortKey {
+    /// Most visited first.
+    Visits,
+    /// Best for the player to move at the parent first.
+    Value,
+    /// Highest prior probability first.
+    Prior,
+    /// Highest improved policy first.
+    ImprovedPolicy,
+}
+
+/// A column of the table. Columns which depend on the parent are empty for
+/// the root.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Column {
+    Visits,
+    /// The evaluation for the player to move after the action (or at the
+    /// root), with known results like `Win(3)`.
+    Eval,
+    /// The evaluation as a scalar.
+    Value,
+    StdDev,
+    Variance,
+    /// Prior probability from the network.
+    Prior,
+    Logit,
+    /// Improved policy at the parent.
+    ImprovedPolicy,
+    /// Exploration bonus at the parent.
+    Puct,
+}
+
+impl Column {
+    pub const ALL: [Self; 9] = [
+        Self::Visits,
+        Self::Eval,
+        Self::Value,
+        Self::StdDev,
+        Self::Variance,
+        Self::Prior,
+        Self::Logit,
+        Self::ImprovedPolicy,
+        Self::Puct,
+    ];
+
+    const fn header(self) -> &'static str {
+        match self {
+            Self::Visits => "visits",
+            Self::Eval => "eval",
+            Self::Value => "value",
+            Self::StdDev => "std_dev",
+            Self::Variance => "variance",
+            Self::Prior => "prior",
+            Self::Logit => "logit",
+            Self::ImprovedPolicy => "impol",
+            Self::Puct => "puct",
+        }
+    }
+}
+
+/// What a row shows of a child besides the child itself.
+#[derive(Clone, Copy)]
+struct ParentStats {
+    improved_policy: NotNan<f32>,
+    puct: f32,
+}
+
+struct Row {
+    depth: usize,
+    label: String,
+    on_pv: bool,
+    cells: Vec<String>,
+}
+
+impl<E: ActionIndex> fmt::Display for Node<E>
 where
     E::Action: fmt::Display,
 {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        let mut action_info = self.action_info();
-        action_info.sort_by_key(|a| a.visit_count);
-        if self.needs_initialization() {
-            writeln!(f, "--- This node still needs to be initialized! ---")?;
+        f.write_str(&self.render(&TreeRender::default()))
+    }
+}
+
+impl<E: ActionIndex> Node<E>
+where
+    E::Action: fmt::Display,
+{
+    /// Render the top of the search tree as a table with a header and a row
+    /// per node, indented by depth.
+    #[must_use]
+    pub fn render(&self, options: &TreeRender) -> String {
+        self.root().render(options)
+    }
+}
+
+impl<E: ActionIndex> NodeRef<'_, E>
+where
+    E::Action: fmt::Display,
+{
+    /// Render the tree below this node, see [`Node::render`].
+    #[must_use]
+    pub fn render(self, options: &TreeRender) -> String {
+        let mut rows = Vec::new();
+        self.render_rows(&mut rows, "root".into(), None, 0, options.highlight_pv, options);
+
+        let label_width = rows
+            .iter()
+            .map(|row| 2 * row.depth + row.label.chars().count())
+            .chain(std::iter::once("action".len()))
+            .max()
+            .unwrap_or_default();
+        let widths: Vec<_> = options
+            .columns
+            .iter()
+            .enumerate()
+            .map(|(i, column)| {
+                rows.iter()
+                    .map(|row| row.cells[i].chars().count())
+                    .chain(std::iter::once(column.header().len()))
+                    .max()
+                    .unwrap_or_default()
+            })
+            .collect();
+
+        let mut out = format!("  {:label_width$}", "action");
+        for (column, width) in options.columns.iter().zip(&widths) {
+            let _ = write!(out, "  {:>width$}", column.header());
+        }
+        out.push('\n');
+        for row in rows {
+            let marker = if row.on_pv && options.highlight_pv { '*' } else { ' ' };
+            let label = format!("{}{}", "  ".repeat(row.depth), row.label);
+            let _ = write!(out, "{marker} {label:label_width$}");
+            for (cell, width) in row.cells.iter().zip(&widths) {
+                let _ = write!(out, "  {cell:>width$}");
+            }
+            out.push('\n');
+        }
+        out
+    }
+
+    fn render_rows(
+        self,
+        rows: &mut Vec<Row>,
+        label: String,
+        parent: Option<ParentStats>,
+        depth: usize,
+        on_pv: bool,
+        options: &TreeRender,
+    ) {
+        rows.push(Row {
+            depth,
+            label,
+            on_pv,
+            cells: options
+                .columns
+                .iter()
+                .map(|column| self.cell(*column, parent))
+                .collect(),
+        });
+        if depth >= options.max_depth {
+            return;
+        }
+
+        let best_action = if on_pv {
+            self.try_select_best_action().ok()
         } else {
-            for a in action_info {
-                writeln!(f, "{a}")?;
+            None
+        };
+        let mut children: Vec<_> = self
+            .improved_policy(self.most_visited_count(), DISCOUNT_FACTOR)
+            .zip(self.children())
+            .filter(|(_, (_, child))| child.real_visit_count() >= options.min_visits)
+            .collect();
+        match options.sort {
+            SortKey::Visits => {
+                children.sort_by_key(|(_, (_, child))| Reverse(child.real_visit_count()));
             }
-            // Header for action info
-            writeln!(
-                f,
-                "[ action ] [ count ] [ logit ] [ proba ] [ impol ] [ puct ] [ stdev ] [ \
-                 evaluation ]"
-            )?;
+            SortKey::Value => children.sort_by_key(|(_, (_, child))| child.evaluation()),
+            SortKey::Prior => children.sort_by_key(|(_, (_, child))| Reverse(child.probability())),
+            SortKey::ImprovedPolicy => children.sort_by_key(|(policy, _)| Reverse(*policy)),
+        }
+        for (improved_policy, (action, child)) in children
+            .into_iter()
+            .take(options.top_k.unwrap_or(usize::MAX))
+        {
+            let parent = ParentStats {
+                improved_policy,
+                puct: upper_confidence_bound_with_predictor(
+                    self.real_visit_count() as f32,
+                    child.real_visit_count() as f32,
+                    child.probability().into_inner(),
+                ),
+            };
+            let on_pv = best_action.as_ref() == Some(&action);
+            child.render_rows(rows, action.to_string(), Some(parent), depth + 1, on_pv, options);
+        }
+    }
+
+    fn cell(self, column: Column, parent: Option<ParentStats>) -> String {
+        match (column, parent) {
+            (Column::Visits, _) => self.real_visit_count().to_string(),
+            (Column::Eval, _) => format!("{:+.4}", self.evaluation()),
+            (Column::Value, _) => format!("{:+.4}", f32::from(self.evaluation())),
+            (Column::StdDev, _) => format!("{:.4}", self.std_dev()),
+            (Column::Variance, _) => format!("{:.4}", self.std_dev().powi(2)),
+            (Column::Prior, _) => format!("{:.4}", self.probability()),
+            (Column::Logit, _) => format!("{:+.4}", self.logit()),
+            (Column::ImprovedPolicy, Some(parent)) => format!("{:.4}", parent.improved_policy),
+            (Column::Puct, Some(parent)) => format!("{:.4}", parent.puct),
+            (Column::ImprovedPolicy | Column::Puct, None) => String::new(),
         }
-        writeln!(
-            f,
-            "((node))  [count: {}]  [std_dev: {:.4}]  [eval: {:+.4}]",
-            self.visit_count, self.std_dev, self.evaluation
-        )
     }
 }
 
-impl<E: Environment> Node<E> {
+impl<E: ActionIndex> Node<E> {
     #[must_use]
     pub fn action_info(&self) -> Vec<ActionInfo<E::Action>> {
-        self.improved_policy(self.most_visited_count())
-            .zip(self.children.iter())
+        let root = self.root();
+        root.improved_policy(root.most_visited_count(), DISCOUNT_FACTOR)
+            .zip(root.children())
             .map(|(improved_policy, (action, child))| ActionInfo {
-                action: action.clone(),
-                visit_count: child.visit_count,
-                logit: child.logit,
-                probability: child.probability,
+                action,
+                visit_count: child.real_visit_count(),
+                logit: child.logit(),
+                probability: child.probability(),
                 improved_policy,
                 puct: upper_confidence_bound_with_predictor(
-                    self.visit_count as f32,
-                    child.visit_count as f32,
-                    child.probability.into_inner(),
+                    root.real_visit_count() as f32,
+                    child.real_visit_count() as f32,
+                    child.probability().into_inner(),
                 ),
-                eval: child.evaluation,
-                std_dev: child.std_dev,
+                eval: child.evaluation(),
+                std_dev: child.std_dev(),
             })
             .collect()
     }
@@ -86,3 +310,59 @@ impl<A: fmt::Display> fmt::Display for ActionInfo<A> {
         )
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use fast_tak::Game;
+
+    use super::{Column, SortKey, TreeRender};
+    use crate::search::{agent::dummy::Dummy, node::Node, DISCOUNT_FACTOR};
+
+    #[test]
+    fn render_respects_options() {
+        let env: Game<3, 0> = Game::default();
+        let mut node = Node::default();
+        for _ in 0..200 {
+            node.simulate_simple(&Dummy, env.clone(), 0.0, DISCOUNT_FACTOR);
+        }
+
+        let table = node.render(&TreeRender {
+            max_depth: 2,
+            top_k: Some(2),
+            min_visits: 1,
+            sort: SortKey::Visits,
+            columns: vec![Column::Visits, Column::Value],
+            highlight_pv: true,
+        });
+        let lines: Vec<_> = table.lines().collect();
+        assert_eq!(lines[0].split_whitespace().collect::<Vec<_>>(), [
+            "action", "visits", "value"
+        ]);
+        // The root, two children, and at most two grandchildren each.
+        assert!((4..=8).contains(&(lines.len() - 1)), "{table}");
+        assert!(lines[1].starts_with("* root"), "{table}");
+        // Children are sorted by visits.
+        let visits: Vec<u32> = lines[1..]
+            .iter()
+            .filter(|line| line[1..].starts_with("   ") && !line[1..].starts_with("     "))
+            .map(|line| line.split_whitespace().rev().nth(1).unwrap().parse().unwrap())
+            .collect();
+        assert_eq!(visits.len(), 2);
+        assert!(visits[0] >= visits[1]);
+
+        // The best action is on the principal variation.
+        let children = node.render(&TreeRender::default());
+        let best = node.select_best_action().to_string();
+        let marked: Vec<_> = children.lines().filter(|line| line.starts_with("*   ")).collect();
+        assert_eq!(marked.len(), 1, "{children}");
+        assert!(marked[0].starts_with(&format!("*   {best} ")), "{children}");
+
+        let root_only = node.render(&TreeRender {
+            max_depth: 0,
+            highlight_pv: false,
+            ..TreeRender::default()
+        });
+        assert_eq!(root_only.lines().count(), 2);
+        assert!(root_only.lines().nth(1).unwrap().starts_with("  root"));
+    }
+}
@@ -0,0 +1,152 @@
+use std::fmt::{self, Write};
+
+use super::{super::env::ActionIndex, Node, NodeRef};
+
+/// Which part of the tree to export.
+#[derive(Debug, Clone, Copy)]
+pub struct TreeExport {
+    /// Maximum depth below the root, which is at depth 0.
+    pub max_depth: usize,
+    /// Children with fewer visits are left out.
+    pub min_visits: u32,
+}
+
+impl Default for TreeExport {
+    fn default() -> Self {
+        Self {
+            max_depth: 3,
+            min_visits: 1,
+        }
+    }
+}
+
+/// Write a float, using `null` for values which JSON cannot represent.
+fn write_float(out: &mut String, x: f32) -> fmt::Result {
+    if x.is_finite() {
+        write!(out, "{x}")
+    } else {
+        write!(out, "null")
+    }
+}
+
+pub(crate) fn write_string(out: &mut String, s: &str) -> fmt::Result {
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            c if c.is_control() => write!(out, "\\u{:04x}", u32::from(c))?,
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    Ok(())
+}
+
+impl<E: ActionIndex> Node<E>
+where
+    E::Action: fmt::Display,
+{
+    /// Export the top of the search tree as JSON for visualizers.
+    ///
+    /// Every node has its `action` (`null` at the root), `visits`, `value`
+    /// (for the player who made the action, or to move at the root), `eval`,
+    /// `std_dev` and `variance`, `probability` from the network and its log
+    /// `logit`, and `children` ordered by visits.
+    ///
+    /// # Panics
+    ///
+    /// Panics if formatting an action fails.
+    #[must_use]
+    pub fn export_json(&self, options: TreeExport) -> String {
+        let mut out = String::new();
+        self.root()
+            .write_json(&mut out, None, 0, options)
+            .expect("writing to a string should not fail");
+        out
+    }
+}
+
+impl<E: ActionIndex> NodeRef<'_, E>
+where
+    E::Action: fmt::Display,
+{
+    fn write_json(
+        self,
+        out: &mut String,
+        action: Option<&E::Action>,
+        depth: usize,
+        options: TreeExport,
+    ) -> fmt::Result {
+        out.push_str("{\"action\":");
+        match action {
+            Some(action) => write_string(out, &action.to_string())?,
+            None => out.push_str("null"),
+        }
+        write!(out, ",\"visits\":{},\"value\":", self.real_visit_count())?;
+        write_float(out, f32::from(self.evaluation()))?;
+        out.push_str(",\"eval\":");
+        write_string(out, &self.evaluation().to_string())?;
+        out.push_str(",\"std_dev\":");
+        write_float(out, self.std_dev().into_inner())?;
+        out.push_str(",\"variance\":");
+        write_float(out, self.std_dev().into_inner().powi(2))?;
+        out.push_str(",\"probability\":");
+        write_float(out, self.probability().into_inner())?;
+        out.push_str(",\"logit\":");
+        write_float(out, self.logit().into_inner())?;
+
+        out.push_str(",\"children\":[");
+        if depth < options.max_depth {
+            let mut children: Vec<_> = self
+                .children()
+                .iter()
+                .filter(|(_, child)| child.real_visit_count() >= options.min_visits.max(1))
+                .collect();
+            children.sort_by_key(|(_, child)| std::cmp::Reverse(child.real_visit_count()));
+            for (i, (action, child)) in children.into_iter().enumerate() {
+                if i > 0 {
+                    out.push(',');
+                }
+                child.write_json(out, Some(&action), depth + 1, options)?;
+            }
+        }
+        out.push_str("]}");
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use fast_tak::Game;
+
+    use super::TreeExport;
+    use crate::search::{agent::dummy::Dummy, node::Node, DISCOUNT_FACTOR};
+
+    #[test]
+    fn export_respects_cutoffs() {
+        let env: Game<3, 0> = Game::default();
+        let mut node = Node::default();
+        for _ in 0..100 {
+            node.simulate_simple(&Dummy, env.clone(), 0.0, DISCOUNT_FACTOR);
+        }
+
+        let root_only = node.export_json(TreeExport {
+            max_depth: 0,
+            min_visits: 1,
+        });
+        assert!(root_only.starts_with("{\"action\":null,\"visits\":100,"));
+        assert!(root_only.ends_with("\"children\":[]}"));
+
+        let json = node.export_json(TreeExport::default());
+        let visited = node
+            .children()
+            .iter()
+            .filter(|(_, child)| child.visit_count() > 0)
+            .count();
+        // Root actions appear at depth 1, and deeper ones are nested inside.
+        assert!(json.matches("\"action\":\"").count() >= visited);
+        assert_eq!(json.matches('{').count(), json.matches('}').count());
+        assert_eq!(json.matches('[').count(), json.matches(']').count());
+    }
+}
@@ -9,7 +9,8 @@
 //!
 //! The search is implemented in two steps, `forward` and
 //! `backward_known_eval`+`backward_network_eval` to allow for batch
-//! evaluations.
+//! evaluations. Both take the mate discount of the search, by which known
+//! results are discounted for every ply (see [`Eval::backup_value`]).
 //!
 //! A node solver has been implemented as well. It deduces
 //! when a result (win, loss, or draw) is guaranteed.
@@ -20,9 +21,17 @@
 use ordered_float::NotNan;
 
 use super::{
-    super::{agent::Agent, env::Environment, eval::Eval, DISCOUNT_FACTOR},
+    super::{
+        agent::Agent,
+        env::{ActionIndex, Environment, Terminal, Undo},
+        eval::Eval,
+        DISCOUNT_FACTOR,
+    },
+    arena::{NodeId, ROOT},
     policy::softmax,
     Node,
+    NodeRef,
+    SearchError,
 };
 
 /// Return value from [`Node::forward`] indicating if the evaluation is known
@@ -40,63 +49,94 @@ pub struct Propagated {
 
 pub struct ActionPolicy<E: Environment> {
     pub action: E::Action,
-    pub logit: NotNan<f32>,
     pub probability: NotNan<f32>,
 }
 
-impl<E: Environment> Node<E> {
+impl<E: ActionIndex> Node<E> {
     #[inline]
-    fn update_mean_value(&mut self, value: f32) {
-        if let Eval::Value(mean_value) = &mut self.evaluation {
-            *mean_value += (-*mean_value + value) / (self.visit_count as f32);
-        };
+    fn update_mean_value(&mut self, id: NodeId, value: f32) {
+        if let Eval::Value(mut mean_value) = self.arena.evaluation(id) {
+            mean_value += (-mean_value + value) / (self.arena.visit_count(id) as f32);
+            self.arena.set_evaluation(id, Eval::Value(mean_value));
+        }
     }
 
     #[inline]
-    fn update_standard_deviation(&mut self, variance: NotNan<f32>) {
-        if self.evaluation.is_known() {
+    fn update_standard_deviation(&mut self, id: NodeId, variance: NotNan<f32>) {
+        if self.arena.evaluation(id).is_known() {
             return;
         }
-        self.std_dev += (-self.std_dev + variance.sqrt()) / (self.visit_count as f32);
+        let visit_count = self.arena.visit_count(id) as f32;
+        let std_dev = self.arena.std_dev_mut(id);
+        *std_dev += (-*std_dev + variance.sqrt()) / visit_count;
+    }
+
+    #[inline]
+    fn add_visit(&mut self, id: NodeId) {
+        *self.arena.visit_count_mut(id) += 1;
+        #[cfg(feature = "virtual")]
+        {
+            *self.arena.virtual_visits_mut(id) += 1;
+        }
+    }
+
+    #[inline]
+    fn remove_visit(&mut self, id: NodeId) {
+        *self.arena.visit_count_mut(id) -= 1;
+        #[cfg(feature = "virtual")]
+        {
+            *self.arena.virtual_visits_mut(id) -= 1;
+        }
+    }
+
+    /// Mark the node as terminal with the result of the environment.
+    fn set_terminal(&mut self, id: NodeId, terminal: Terminal) -> Eval {
+        let evaluation = terminal.into();
+        self.arena.set_evaluation(id, evaluation);
+        *self.arena.std_dev_mut(id) = NotNan::default();
+        evaluation
     }
 
     // TODO: Once pruning is added back, we can skip traversing all evaluations to
     // find min in the case of a loss because that is will be the first loss we
     // find.
-    fn node_solver(&mut self, child_eval: Eval) {
-        let evaluations = self.children.iter().map(|(_, node)| node.evaluation);
-
+    pub(super) fn node_solver(&mut self, id: NodeId) {
         // If we can choose a loss for the opponent, this position is a win.
         // If all moves are wins for the opponent, this node is a loss.
         // If all moves are wins or draws for the opponent, we choose to draw.
-        if child_eval.is_loss() || evaluations.clone().all(|e| e.is_known()) {
-            self.evaluation = evaluations.min().unwrap().negate();
-            self.std_dev = NotNan::default();
+        let children = self.arena.children(id);
+        if let Some(solved) = Eval::solved(children.map(|child| self.arena.evaluation(child))) {
+            self.arena.set_evaluation(id, solved);
+            *self.arena.std_dev_mut(id) = NotNan::default();
         }
     }
 
     fn propagate_child_eval(
         &mut self,
+        id: NodeId,
         child_eval: Eval,
         child_variance: NotNan<f32>,
+        mate_discount: f32,
     ) -> Propagated {
-        self.node_solver(child_eval);
+        self.node_solver(id);
 
         // If the position is solved, we just propagate the solved value instead.
-        if self.evaluation.is_known() {
+        let evaluation = self.arena.evaluation(id);
+        if evaluation.is_known() {
+            let std_dev = self.arena.std_dev(id);
             return Propagated {
-                eval: self.evaluation,
-                variance: self.std_dev * self.std_dev,
+                eval: evaluation,
+                variance: std_dev * std_dev,
             };
         }
         // Otherwise this position is not known and we just
         // back-propagate the child result.
-        let negated = child_eval.negate().into();
-        self.update_mean_value(negated);
-        self.update_standard_deviation(child_variance);
+        let negated = child_eval.negate().backup_value(mate_discount);
+        self.update_mean_value(id, negated.into_inner());
+        self.update_standard_deviation(id, child_variance);
 
         Propagated {
-            eval: Eval::new_value(negated * DISCOUNT_FACTOR).unwrap(),
+            eval: Eval::new_not_nan_value(negated * DISCOUNT_FACTOR),
             variance: child_variance * DISCOUNT_FACTOR * DISCOUNT_FACTOR,
         }
     }
@@ -104,55 +144,110 @@ impl<E: Environment> Node<E> {
     /// Run the forward part of MCTS.
     /// One of `backward_known_eval` and `backward_network_eval`
     /// must be called afterwards.
-    pub fn forward(&mut self, trajectory: &mut Vec<usize>, mut env: E, beta: f32) -> Forward<E> {
+    ///
+    /// # Errors
+    ///
+    /// Returns [`SearchError::NoChildren`] if an initialized node on the way
+    /// has no children to select. The visits are taken back and the
+    /// trajectory is cleared, so the search can go on.
+    pub fn forward(
+        &mut self,
+        trajectory: &mut Vec<usize>,
+        env: E,
+        beta: f32,
+        mate_discount: f32,
+    ) -> Result<Forward<E>, SearchError> {
+        self.forward_from(ROOT, trajectory, env, beta, mate_discount)
+    }
+
+    /// Like [`Node::forward`], but starts at a node below the root. The
+    /// trajectory is relative to that node, and so are the backward passes
+    /// which must follow.
+    pub(super) fn forward_from(
+        &mut self,
+        start: NodeId,
+        trajectory: &mut Vec<usize>,
+        mut env: E,
+        beta: f32,
+        mate_discount: f32,
+    ) -> Result<Forward<E>, SearchError> {
         debug_assert!(trajectory.is_empty());
-        let mut node = self;
+        let mut id = start;
 
-        loop {
-            node.visit_count += 1;
-            #[cfg(feature = "virtual")]
-            {
-                node.virtual_visits += 1;
-            }
+        let forward = loop {
+            self.add_visit(id);
+            let node = NodeRef::new(&self.arena, id);
             // TODO: Prune all known results earlier
             // once visit count is not used for policy target.
             // Or don't - searching can still help find slower losses.
             if node.is_terminal() {
-                break Forward::Known(node.evaluation);
+                break Ok(Forward::Known(node.evaluation()));
             }
             if node.needs_initialization() {
                 if let Some(terminal) = env.terminal() {
-                    node.evaluation = terminal.into();
-                    node.std_dev = NotNan::default();
-                    break Forward::Known(node.evaluation);
+                    break Ok(Forward::Known(self.set_terminal(id, terminal)));
                 }
-                break Forward::NeedsNetwork(env);
+                break Ok(Forward::NeedsNetwork(env));
             }
 
-            let index = node.select_with_puct(beta);
+            let index = match node.select_with_puct(beta, mate_discount) {
+                Ok(index) => index,
+                Err(err) => break Err(err),
+            };
             trajectory.push(index);
-            let (action, child) = &mut node.children[index];
-            env.step(action.clone());
-            node = child;
+            id = self.arena.child(id, index);
+            env.step(self.arena.action(id));
+        };
+        if forward.is_err() {
+            self.cancel_forward_from(start, trajectory);
+            trajectory.clear();
+        }
+        forward
+    }
+
+    /// Take back the visits which [`Node::forward`] added along the
+    /// trajectory, for when the leaf cannot be evaluated after all.
+    pub fn cancel_forward(&mut self, trajectory: &[usize]) {
+        self.cancel_forward_from(ROOT, trajectory);
+    }
+
+    pub(super) fn cancel_forward_from(&mut self, start: NodeId, trajectory: &[usize]) {
+        let mut id = start;
+        for &index in trajectory {
+            self.remove_visit(id);
+            id = self.arena.child(id, index);
         }
+        self.remove_visit(id);
     }
 
     /// Propagate a known eval through the tree.
     pub fn backward_known_eval(
         &mut self,
+        trajectory: impl Iterator<Item = usize>,
+        eval: Eval,
+        mate_discount: f32,
+    ) -> Propagated {
+        self.backward_known_eval_from(ROOT, trajectory, eval, mate_discount)
+    }
+
+    pub(super) fn backward_known_eval_from(
+        &mut self,
+        id: NodeId,
         mut trajectory: impl Iterator<Item = usize>,
         eval: Eval,
+        mate_discount: f32,
     ) -> Propagated {
         if let Some(index) = trajectory.next() {
+            let child = self.arena.child(id, index);
             let Propagated {
                 eval: child_eval,
                 variance: child_variance,
-            } = self.children[index].1.backward_known_eval(trajectory, eval);
+            } = self.backward_known_eval_from(child, trajectory, eval, mate_discount);
             #[cfg(feature = "virtual")]
             {
-                self.virtual_visits -= 1;
+                *self.arena.virtual_visits_mut(id) -= 1;
             }
-            self.propagate_child_eval(child_eval, child_variance)
+            self.propagate_child_eval(id, child_eval, child_variance, mate_discount)
         } else {
             // Leaf reached, time to propagate upwards.
             Propagated {
@@ -163,67 +258,118 @@ impl<E: Environment> Node<E> {
     }
 
     /// Initialize a leaf node and propagate a network evaluation
-    /// through the tree.
-    ///
-    /// # Panics
-    ///
-    /// Panics if any of the policies is NaN.
+    /// through the tree. Use [`Node::try_backward_network_eval`] for
+    /// predictions which have not been checked for NaN yet.
     pub fn backward_network_eval(
         &mut self,
+        trajectory: impl Iterator<Item = usize>,
+        policy: impl Iterator<Item = ActionPolicy<E>>,
+        value: NotNan<f32>,
+        variance: NotNan<f32>,
+        mate_discount: f32,
+    ) -> Propagated {
+        self.backward_network_eval_from(ROOT, trajectory, policy, value, variance, mate_discount)
+    }
+
+    pub(super) fn backward_network_eval_from(
+        &mut self,
+        id: NodeId,
         mut trajectory: impl Iterator<Item = usize>,
         policy: impl Iterator<Item = ActionPolicy<E>>,
-        value: f32,
-        variance: f32,
+        value: NotNan<f32>,
+        variance: NotNan<f32>,
+        mate_discount: f32,
     ) -> Propagated {
         if let Some(index) = trajectory.next() {
+            let child = self.arena.child(id, index);
             let Propagated {
                 eval: child_eval,
                 variance: child_variance,
-            } = self.children[index]
-                .1
-                .backward_network_eval(trajectory, policy, value, variance);
+            } = self.backward_network_eval_from(
+                child,
+                trajectory,
+                policy,
+                value,
+                variance,
+                mate_discount,
+            );
             #[cfg(feature = "virtual")]
             {
-                self.virtual_visits -= 1;
+                *self.arena.virtual_visits_mut(id) -= 1;
             }
-            self.propagate_child_eval(child_eval, child_variance)
+            self.propagate_child_eval(id, child_eval, child_variance, mate_discount)
         } else {
             // Update mean value and standard deviation.
             // Note that this is not the same as self.propagate_child_eval()
             // because we do not negate!
-            self.update_mean_value(value);
-            let variance = NotNan::new(variance).expect("uncertainty/variance should not be NaN");
-            self.update_standard_deviation(variance);
-
-            // Finish leaf initialization.
-            self.children = policy
-                .map(
-                    |ActionPolicy {
-                         action,
-                         logit,
-                         probability,
-                     }| {
-                        (
-                            action,
-                            Self::from_logit_and_probability_and_parent_value_and_std_dev(
-                                logit,
-                                probability,
-                                self.evaluation.into(),
-                                self.std_dev,
-                            ),
-                        )
-                    },
-                )
-                .collect();
+            self.update_mean_value(id, value.into_inner());
+            self.update_standard_deviation(id, variance);
+
+            // Finish leaf initialization. The children start from the value
+            // of this node, seen from the other side.
+            let evaluation = NotNan::from(self.arena.evaluation(id));
+            let std_dev = self.arena.std_dev(id);
+            self.arena.expand(
+                id,
+                policy.map(|ActionPolicy { action, probability }| (action, probability)),
+                Eval::new_not_nan_value(-evaluation),
+                std_dev,
+            );
 
             Propagated {
-                eval: Eval::new_value(value * DISCOUNT_FACTOR)
-                    .expect("value prediction should not be NaN"),
+                eval: Eval::new_not_nan_value(value * DISCOUNT_FACTOR),
                 variance: variance * DISCOUNT_FACTOR * DISCOUNT_FACTOR,
             }
         }
     }
 
+    /// Like [`Node::backward_network_eval`], but checks the prediction
+    /// before touching the tree.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`SearchError::Nan`] if the value or the uncertainty is NaN.
+    /// The tree is left as it was after [`Node::forward`], so the visits can
+    /// be taken back with [`Node::cancel_forward`].
+    pub fn try_backward_network_eval(
+        &mut self,
+        trajectory: impl Iterator<Item = usize>,
+        policy: impl Iterator<Item = ActionPolicy<E>>,
+        value: f32,
+        variance: f32,
+        mate_discount: f32,
+    ) -> Result<Propagated, SearchError> {
+        self.try_backward_network_eval_from(
+            ROOT,
+            trajectory,
+            policy,
+            value,
+            variance,
+            mate_discount,
+        )
+    }
+
+    pub(super) fn try_backward_network_eval_from(
+        &mut self,
+        start: NodeId,
+        trajectory: impl Iterator<Item = usize>,
+        policy: impl Iterator<Item = ActionPolicy<E>>,
+        value: f32,
+        variance: f32,
+        mate_discount: f32,
+    ) -> Result<Propagated, SearchError> {
+        let value = NotNan::new(value).map_err(|_| SearchError::Nan("value"))?;
+        let variance = NotNan::new(variance).map_err(|_| SearchError::Nan("uncertainty"))?;
+        Ok(self.backward_network_eval_from(
+            start,
+            trajectory,
+            policy,
+            value,
+            variance,
+            mate_discount,
+        ))
+    }
+
     /// A non-batched version of simulate that does both
     /// forward and backward steps of MCTS. This is mainly
     /// used for testing.
@@ -232,50 +378,219 @@ impl<E: Environment> Node<E> {
     ///
     /// Panics if the agent does not return a prediction
     /// when needed.
-    pub fn simulate_simple<A: Agent<E>>(&mut self, agent: &A, env: E, beta: f32) -> Propagated {
+    pub fn simulate_simple<A: Agent<E>>(
+        &mut self,
+        agent: &A,
+        env: E,
+        beta: f32,
+        mate_discount: f32,
+    ) -> Propagated {
+        self.try_simulate_simple(agent, env, beta, mate_discount)
+            .expect("simulation should succeed")
+    }
+
+    /// Like [`Node::simulate_simple`], but fallible.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if selection fails, or if the agent does not return
+    /// a prediction when needed or returns a NaN one. The visits of the
+    /// simulation are taken back, so the search can go on.
+    pub fn try_simulate_simple<A: Agent<E>>(
+        &mut self,
+        agent: &A,
+        env: E,
+        beta: f32,
+        mate_discount: f32,
+    ) -> Result<Propagated, SearchError> {
         let mut trajectory = Vec::new();
-        match self.forward(&mut trajectory, env, beta) {
-            Forward::Known(eval) => self.backward_known_eval(trajectory.into_iter(), eval),
+        match self.forward(&mut trajectory, env, beta, mate_discount)? {
+            Forward::Known(eval) => {
+                Ok(self.backward_known_eval(trajectory.into_iter(), eval, mate_discount))
+            }
             Forward::NeedsNetwork(env) => {
-                let mut actions = [Vec::new()];
-                env.populate_actions(&mut actions[0]);
-                let (policy, value, uncertainty) = agent
-                    .policy_value_uncertainty(&[env], &actions)
-                    .next()
-                    .expect("agent should return exactly one prediction");
-                // Calculate probabilities from logits.
-                let probabilities = softmax(policy.clone().into_iter().map(|(_, p)| p));
-                // Do backwards pass.
-                self.backward_network_eval(
-                    trajectory.into_iter(),
-                    policy
-                        .into_iter()
-                        .zip(probabilities)
-                        .map(|((action, logit), probability)| ActionPolicy {
-                            action,
-                            logit,
-                            probability,
-                        }),
-                    value,
-                    uncertainty,
-                )
+                self.backward_agent_eval(agent, &trajectory, &env, mate_discount)
             }
         }
     }
+
+    /// Evaluate the leaf at the end of the trajectory with the agent and
+    /// propagate the evaluation, or cancel the forward pass if that fails.
+    fn backward_agent_eval<A: Agent<E>>(
+        &mut self,
+        agent: &A,
+        trajectory: &[usize],
+        env: &E,
+        mate_discount: f32,
+    ) -> Result<Propagated, SearchError> {
+        let mut actions = [Vec::new()];
+        env.populate_actions(&mut actions[0]);
+        let Some((policy, value, uncertainty)) = agent
+            .policy_value_uncertainty(std::slice::from_ref(env), &actions)
+            .next()
+        else {
+            self.cancel_forward(trajectory);
+            return Err(SearchError::MissingPrediction);
+        };
+        // An empty policy for a position with actions means that the logits
+        // were NaN (see `gather_policy`).
+        if policy.is_empty() && !actions[0].is_empty() {
+            self.cancel_forward(trajectory);
+            return Err(SearchError::Nan("policy"));
+        }
+        // Calculate probabilities from logits.
+        let probabilities = softmax(policy.clone().into_iter().map(|(_, p)| p));
+        // Do backwards pass.
+        let propagated = self.try_backward_network_eval(
+            trajectory.iter().copied(),
+            policy
+                .into_iter()
+                .zip(probabilities)
+                .map(|((action, _), probability)| ActionPolicy {
+                    action,
+                    probability,
+                }),
+            value,
+            uncertainty,
+            mate_discount,
+        );
+        if propagated.is_err() {
+            self.cancel_forward(trajectory);
+        }
+        propagated
+    }
+}
+
+impl<E: Undo + ActionIndex> Node<E> {
+    /// Like [`Node::forward`], but steps `env` in place, keeping the delta of
+    /// every step. Returns the known eval, or `None` if `env` needs a network
+    /// evaluation. The steps must be taken back with
+    /// [`Node::undo_trajectory`] afterwards.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`SearchError::NoChildren`] if an initialized node on the way
+    /// has no children to select. The visits are taken back, but the steps
+    /// taken so far must still be undone with [`Node::undo_trajectory`].
+    pub fn forward_in_place(
+        &mut self,
+        trajectory: &mut Vec<usize>,
+        deltas: &mut Vec<E::Delta>,
+        env: &mut E,
+        beta: f32,
+        mate_discount: f32,
+    ) -> Result<Option<Eval>, SearchError> {
+        debug_assert!(trajectory.is_empty() && deltas.is_empty());
+        let mut id = ROOT;
+
+        let known = loop {
+            self.add_visit(id);
+            let node = NodeRef::new(&self.arena, id);
+            if node.is_terminal() {
+                break Ok(Some(node.evaluation()));
+            }
+            if node.needs_initialization() {
+                if let Some(terminal) = env.terminal() {
+                    break Ok(Some(self.set_terminal(id, terminal)));
+                }
+                break Ok(None);
+            }
+
+            let index = match node.select_with_puct(beta, mate_discount) {
+                Ok(index) => index,
+                Err(err) => break Err(err),
+            };
+            trajectory.push(index);
+            id = self.arena.child(id, index);
+            deltas.push(env.step_with_delta(self.arena.action(id)));
+        };
+        if known.is_err() {
+            self.cancel_forward(trajectory);
+        }
+        known
+    }
+
+    /// Take back the steps of [`Node::forward_in_place`] along the
+    /// trajectory, with their deltas.
+    pub fn undo_trajectory(&self, trajectory: &[usize], deltas: Vec<E::Delta>, env: &mut E) {
+        let mut actions = Vec::with_capacity(trajectory.len());
+        let mut id = ROOT;
+        for &index in trajectory {
+            id = self.arena.child(id, index);
+            actions.push(self.arena.action(id));
+        }
+        for (action, delta) in actions.into_iter().zip(deltas).rev() {
+            env.undo(action, delta);
+        }
+    }
+
+    /// Like [`Node::simulate_simple`], but steps and undoes `env` instead of
+    /// cloning it along the way. `env` is back at the root afterwards.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the agent does not return a prediction
+    /// when needed.
+    pub fn simulate_in_place<A: Agent<E>>(
+        &mut self,
+        agent: &A,
+        env: &mut E,
+        beta: f32,
+        mate_discount: f32,
+    ) -> Propagated {
+        self.try_simulate_in_place(agent, env, beta, mate_discount)
+            .expect("simulation should succeed")
+    }
+
+    /// Like [`Node::simulate_in_place`], but fallible. `env` is back at the
+    /// root even if it fails.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if selection fails, or if the agent does not return
+    /// a prediction when needed or returns a NaN one. The visits of the
+    /// simulation are taken back, so the search can go on.
+    pub fn try_simulate_in_place<A: Agent<E>>(
+        &mut self,
+        agent: &A,
+        env: &mut E,
+        beta: f32,
+        mate_discount: f32,
+    ) -> Result<Propagated, SearchError> {
+        let mut trajectory = Vec::new();
+        let mut deltas = Vec::new();
+        let known = self.forward_in_place(&mut trajectory, &mut deltas, env, beta, mate_discount);
+        let propagated = match known {
+            Ok(Some(eval)) => {
+                Ok(self.backward_known_eval(trajectory.iter().copied(), eval, mate_discount))
+            }
+            Ok(None) => self.backward_agent_eval(agent, &trajectory, env, mate_discount),
+            Err(err) => Err(err),
+        };
+        self.undo_trajectory(&trajectory, deltas, env);
+        propagated
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use fast_tak::Game;
+    use ordered_float::NotNan;
 
     use super::super::{
         super::{agent::dummy::Dummy, eval::Eval},
         Node,
+        NodeRef,
+        SearchError,
     };
     use crate::search::{
-        agent::simple::Simple,
-        env::safecrack::{SafeCrack, SafeCracker},
+        agent::{simple::Simple, Agent},
+        env::{
+            safecrack::{SafeCrack, SafeCracker},
+            Environment,
+        },
         node::mcts::Propagated,
+        DISCOUNT_FACTOR,
     };
 
     #[test]
@@ -291,7 +606,7 @@ mod tests {
         (0..MAX_VISITS)
             .find(|_| {
                 matches!(
-                    root.simulate_simple(&Dummy, game.clone(), 1.0),
+                    root.simulate_simple(&Dummy, game.clone(), 1.0, DISCOUNT_FACTOR),
                     Propagated {
                         eval: Eval::Win(_),
                         ..
@@ -302,9 +617,9 @@ mod tests {
 
         println!("{root}");
         assert_eq!(
-            root.children
+            root.children()
                 .iter()
-                .find(|(_, node)| node.evaluation.is_loss())
+                .find(|(_, node)| node.evaluation().is_loss())
                 .unwrap()
                 .0,
             "b1".parse().unwrap(),
@@ -327,7 +642,7 @@ mod tests {
                     println!("{root}");
                 }
                 matches!(
-                    root.simulate_simple(&Simple, game.clone(), 1.0),
+                    root.simulate_simple(&Simple, game.clone(), 1.0, DISCOUNT_FACTOR),
                     Propagated {
                         eval: Eval::Win(_),
                         ..
@@ -338,9 +653,9 @@ mod tests {
 
         println!("{root}");
         let winning_move = root
-            .children
+            .children()
             .iter()
-            .find(|(_, node)| node.evaluation.is_loss())
+            .find(|(_, node)| node.evaluation().is_loss())
             .unwrap()
             .0;
         assert!(winning_move == "b2".parse().unwrap() || winning_move == "c2".parse().unwrap());
@@ -353,23 +668,23 @@ mod tests {
         let env = SafeCrack::new(KEY.to_vec());
         let mut root = Node::default();
 
-        assert!(f32::from(root.evaluation) == 0.0);
+        assert!(f32::from(root.evaluation()) == 0.0);
         for _ in 0..VISITS {
-            root.simulate_simple(&SafeCracker, env.clone(), 0.0);
+            root.simulate_simple(&SafeCracker, env.clone(), 0.0, DISCOUNT_FACTOR);
         }
 
         for k in KEY {
-            println!("eval: {}", root.evaluation);
-            for (action, child) in &root.children {
-                println!("\t{}: eval: {}", action.unwrap(), child.evaluation);
+            println!("eval: {}", root.evaluation());
+            for (action, child) in root.children() {
+                println!("\t{}: eval: {}", action.unwrap(), child.evaluation());
             }
-            assert!(f32::from(root.evaluation) > 0.0);
+            assert!(f32::from(root.evaluation()) > 0.0);
 
-            for (action, child) in &root.children {
+            for (action, child) in root.children() {
                 if action.is_some_and(|x| x == k) {
-                    assert!(f32::from(child.evaluation) < 0.0);
+                    assert!(f32::from(child.evaluation()) < 0.0);
                 } else {
-                    assert!(f32::from(child.evaluation) == 0.0);
+                    assert!(f32::from(child.evaluation()) == 0.0);
                 }
             }
 
@@ -377,6 +692,118 @@ mod tests {
             root.descend(&None);
         }
 
-        assert!(f32::from(root.evaluation) > 0.0);
+        assert!(f32::from(root.evaluation()) > 0.0);
+    }
+
+    #[test]
+    fn in_place_matches_cloning() {
+        let env = SafeCrack::new(vec![3, 1, 4]);
+        let mut cloning = Node::default();
+        let mut in_place = Node::default();
+        let mut undone = env.clone();
+        for _ in 0..1_000 {
+            cloning.simulate_simple(&SafeCracker, env.clone(), 0.0, DISCOUNT_FACTOR);
+            in_place.simulate_in_place(&SafeCracker, &mut undone, 0.0, DISCOUNT_FACTOR);
+            assert_eq!(undone.hash(), env.hash());
+        }
+
+        assert_eq!(cloning.evaluation(), in_place.evaluation());
+        for ((action, a), (other_action, b)) in cloning.children().iter().zip(in_place.children()) {
+            assert_eq!(action, other_action);
+            assert_eq!(a.visit_count(), b.visit_count());
+            assert_eq!(a.evaluation(), b.evaluation());
+        }
+    }
+
+    #[test]
+    fn failed_simulations_are_cancelled() {
+        struct Broken;
+
+        impl Agent<SafeCrack> for Broken {
+            fn policy_value_uncertainty(
+                &self,
+                _env_batch: &[SafeCrack],
+                actions_batch: &[Vec<Option<u8>>],
+            ) -> impl Iterator<Item = (Vec<(Option<u8>, NotNan<f32>)>, f32, f32)> {
+                actions_batch.iter().map(|actions| {
+                    let policy = actions.iter().map(|a| (*a, NotNan::default())).collect();
+                    (policy, f32::NAN, 0.0)
+                })
+            }
+        }
+
+        let env = SafeCrack::new(vec![2, 7]);
+        let mut root = Node::default();
+        assert_eq!(root.try_select_best_action(), Err(SearchError::NoChildren));
+        root.simulate_simple(&SafeCracker, env.clone(), 0.0, DISCOUNT_FACTOR);
+
+        assert!(matches!(
+            root.try_simulate_simple(&Broken, env.clone(), 0.0, DISCOUNT_FACTOR),
+            Err(SearchError::Nan("value"))
+        ));
+        assert_eq!(root.visit_count(), 1);
+        assert!(root.children().iter().all(|(_, child)| child.visit_count() == 0));
+
+        // The search goes on with an agent which works.
+        root.try_simulate_simple(&SafeCracker, env, 0.0, DISCOUNT_FACTOR).unwrap();
+        assert_eq!(root.visit_count(), 2);
+
+        assert_eq!(root.try_descend(&None), Err(SearchError::UnknownAction));
+        assert_eq!(root.visit_count(), 2);
+        let best = root.try_select_best_action().unwrap();
+        assert_eq!(root.try_descend(&best), Ok(()));
+        assert_eq!(root.visit_count(), 1);
+    }
+
+    #[test]
+    fn nan_policies_are_errors() {
+        // Networks give an empty policy when a logit is NaN.
+        struct NanPolicy;
+
+        impl Agent<SafeCrack> for NanPolicy {
+            fn policy_value_uncertainty(
+                &self,
+                _env_batch: &[SafeCrack],
+                actions_batch: &[Vec<Option<u8>>],
+            ) -> impl Iterator<Item = (Vec<(Option<u8>, NotNan<f32>)>, f32, f32)> {
+                actions_batch.iter().map(|_| (Vec::new(), 0.0, 0.0))
+            }
+        }
+
+        let mut env = SafeCrack::new(vec![2, 7]);
+        let mut root = Node::default();
+        root.simulate_simple(&SafeCracker, env.clone(), 0.0, DISCOUNT_FACTOR);
+
+        assert!(matches!(
+            root.try_simulate_simple(&NanPolicy, env.clone(), 0.0, DISCOUNT_FACTOR),
+            Err(SearchError::Nan("policy"))
+        ));
+        assert!(matches!(
+            root.try_simulate_in_place(&NanPolicy, &mut env, 0.0, DISCOUNT_FACTOR),
+            Err(SearchError::Nan("policy"))
+        ));
+        assert_eq!(root.visit_count(), 1);
+        assert!(root.children().iter().all(|(_, child)| child.visit_count() == 0));
+        assert_eq!(env.hash(), SafeCrack::new(vec![2, 7]).hash());
+    }
+
+    #[test]
+    fn node_count_is_maintained() {
+        fn traverse(node: NodeRef<SafeCrack>) -> usize {
+            1 + node.children().iter().map(|(_, child)| traverse(child)).sum::<usize>()
+        }
+
+        let env = SafeCrack::new(vec![5, 0, 5]);
+        let mut root = Node::default();
+        assert_eq!(root.node_count(), 1);
+        let empty = root.approx_memory_bytes();
+        for _ in 0..200 {
+            root.simulate_simple(&SafeCracker, env.clone(), 0.0, DISCOUNT_FACTOR);
+            assert_eq!(root.node_count(), traverse(root.root()));
+        }
+        assert!(root.approx_memory_bytes() > empty);
+
+        root.descend(&Some(5));
+        assert_eq!(root.node_count(), traverse(root.root()));
     }
 }
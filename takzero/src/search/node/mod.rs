@@ -1,140 +1,392 @@
 use ordered_float::NotNan;
 use rand::Rng;
 use rand_distr::{Distribution, WeightedIndex};
+use thiserror::Error;
 
-use super::{env::Environment, eval::Eval};
+use self::{
+    arena::{Arena, NodeId, ROOT},
+    children::Children,
+};
+use super::{
+    agent::AgentError,
+    env::{ActionIndex, Environment},
+    eval::Eval,
+};
 
+mod arena;
 pub mod batched;
+pub mod children;
 pub mod debug;
+pub mod export;
 // pub mod gumbel;
 pub mod mcts;
 pub mod noise;
 pub mod policy;
+pub mod progress;
 
-#[rustfmt::skip]
+/// Why a search could not continue. Returned by the fallible `try_` variants
+/// of the search functions, so that long-running services can drop the tree
+/// and recover instead of aborting.
+#[derive(Debug, Error, Clone, Copy, PartialEq, Eq)]
+pub enum SearchError {
+    #[error("the node has no children to choose from")]
+    NoChildren,
+    #[error("none of the children has enough visits to be sampled")]
+    Unvisited,
+    #[error("the action is not among the children of the node")]
+    UnknownAction,
+    #[error("the {0} predicted by the agent is NaN")]
+    Nan(&'static str),
+    #[error("the agent returned fewer predictions than there are positions")]
+    MissingPrediction,
+    #[error("the agent could not evaluate the batch: {0}")]
+    Agent(#[from] AgentError),
+    #[error(
+        "the search budget {search_budget} is not a positive multiple of k*log2(k) for \
+         {sampled_actions} sampled actions"
+    )]
+    Budget {
+        sampled_actions: usize,
+        search_budget: u32,
+    },
+}
+
+/// A search tree, which owns all of its nodes (see [`arena`]). It is used
+/// through its root, and the nodes below are reached with
+/// [`Node::children`].
 pub struct Node<E: Environment> {
-    pub evaluation: Eval,         // V(s_t) or Q(s_prev, a)
-    pub visit_count: u32,         // N(s_prev, a)
-    #[cfg(feature = "virtual")]
-    pub virtual_visits: u32,      // count number of unevaluated trajectories through this node
-    pub logit: NotNan<f32>,       // log(P(s_prev, a)) (network output)
-    pub probability: NotNan<f32>, // P(s_prev, a) (normalized)
-    pub std_dev: NotNan<f32>,     // average sqrt(clamp(max(UBE(s_t), geo_sum_discount * RND(s_t))))
-    pub children: Box<[(E::Action, Self)]>,
+    arena: Arena<E>,
 }
 
 impl<E: Environment> Default for Node<E> {
     fn default() -> Self {
         Self {
-            evaluation: Eval::default(),
-            visit_count: Default::default(),
-            #[cfg(feature = "virtual")]
-            virtual_visits: Default::default(),
-            logit: NotNan::default(),
-            probability: NotNan::default(),
-            std_dev: NotNan::default(),
-            children: Box::default(),
+            arena: Arena::default(),
         }
     }
 }
 
+/// A node of a search tree, borrowed from the tree.
+pub struct NodeRef<'a, E: Environment> {
+    arena: &'a Arena<E>,
+    id: NodeId,
+}
+
+// Not derived, because that would need the environment to be `Copy`.
+#[allow(clippy::expl_impl_clone_on_copy)]
+impl<E: Environment> Clone for NodeRef<'_, E> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<E: Environment> Copy for NodeRef<'_, E> {}
+
 struct PrincipalVariation<'a, E: Environment> {
-    node: &'a Node<E>,
+    node: NodeRef<'a, E>,
 }
 
-impl<'a, E: Environment> Iterator for PrincipalVariation<'a, E> {
+impl<E: ActionIndex> Iterator for PrincipalVariation<'_, E> {
     type Item = E::Action;
 
     fn next(&mut self) -> Option<Self::Item> {
         if self.node.needs_initialization() || self.node.is_terminal() {
             return None;
         }
-        let best_action = self.node.select_best_action();
+        let best_action = self.node.try_select_best_action().ok()?;
         let (_, best_child) = self
             .node
-            .children
+            .children()
             .iter()
-            .find(|(action, _)| *action == best_action)
-            .expect("Best action not found among node's children");
+            .find(|(action, _)| *action == best_action)?;
 
         self.node = best_child;
-        Some(best_action.clone())
+        Some(best_action)
     }
 }
 
-impl<E: Environment> Node<E> {
+impl<E: ActionIndex> Node<E> {
+    /// Memory taken by each node of the tree, without the room which the
+    /// arena keeps for more nodes.
+    pub const NODE_BYTES: usize = arena::NODE_BYTES;
+
+    /// The root of the tree.
+    #[inline]
     #[must_use]
-    pub fn from_logit_and_probability_and_parent_value_and_std_dev(
-        logit: NotNan<f32>,
-        probability: NotNan<f32>,
-        evaluation: NotNan<f32>,
-        std_dev: NotNan<f32>,
-    ) -> Self {
-        Self {
-            evaluation: Eval::new_not_nan_value(-evaluation),
-            logit,
-            probability,
-            std_dev,
-            ..Default::default()
-        }
+    pub const fn root(&self) -> NodeRef<'_, E> {
+        NodeRef::new(&self.arena, ROOT)
+    }
+
+    #[inline]
+    #[must_use]
+    pub fn evaluation(&self) -> Eval {
+        self.root().evaluation()
+    }
+
+    /// See [`NodeRef::visit_count`].
+    #[inline]
+    #[must_use]
+    pub fn visit_count(&self) -> u32 {
+        self.root().visit_count()
+    }
+
+    #[inline]
+    #[must_use]
+    pub fn std_dev(&self) -> NotNan<f32> {
+        self.root().std_dev()
+    }
+
+    #[inline]
+    #[must_use]
+    pub fn children(&self) -> Children<'_, E> {
+        self.root().children()
+    }
+
+    /// Number of nodes in the tree.
+    #[inline]
+    #[must_use]
+    pub const fn node_count(&self) -> usize {
+        self.arena.len()
+    }
+
+    /// Approximate memory used by the tree, counting the room which the
+    /// arena keeps for more nodes but not the overhead of the allocator.
+    #[inline]
+    #[must_use]
+    pub const fn approx_memory_bytes(&self) -> usize {
+        std::mem::size_of::<Self>() + self.arena.memory_bytes()
+    }
+
+    #[inline]
+    #[must_use]
+    pub fn needs_initialization(&self) -> bool {
+        self.root().needs_initialization()
     }
 
     #[inline]
     #[must_use]
-    pub const fn needs_initialization(&self) -> bool {
-        self.children.is_empty() && !self.evaluation.is_known()
+    pub fn is_terminal(&self) -> bool {
+        self.root().is_terminal()
     }
 
     /// Returns an iterator over the Principal Variation of the search tree
     pub fn principal_variation(&self) -> impl Iterator<Item = E::Action> + '_ {
-        PrincipalVariation { node: self }
+        self.root().principal_variation()
+    }
+
+    /// Throw the tree away, keeping its memory for the next search.
+    pub fn clear(&mut self) {
+        self.arena.clear();
     }
 
     /// Descend in the tree, replacing the root the sub-tree for a given action.
     /// This allows for tree reuse.
     /// If the action was not visited, the node will `Node::default()`.
+    ///
+    /// The kept sub-tree is copied into a new arena, and the rest of the
+    /// tree is freed at once with the old one.
     pub fn descend(&mut self, action: &E::Action) {
-        let mut me = std::mem::take(self);
-        let Some((_, child)) = me.children.iter_mut().find(|(a, _)| action == a) else {
-            return;
-        };
-        std::mem::swap(self, child);
-        // TODO: Maybe deallocate children on another thread.
+        let child = self.arena.children(ROOT).find(|&id| self.arena.action(id) == *action);
+        match child {
+            Some(child) => self.arena = self.arena.compact(child),
+            None => self.arena.clear(),
+        }
+    }
+
+    /// Like [`Node::descend`], but the action must be one of the children
+    /// once the node has been expanded. An action which is not, for example
+    /// one from a different position, would silently throw the tree away.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`SearchError::UnknownAction`] and leaves the node unchanged
+    /// if the node is expanded and the action is not among its children.
+    pub fn try_descend(&mut self, action: &E::Action) -> Result<(), SearchError> {
+        let children = self.children();
+        if !children.is_empty() && !children.iter().any(|(a, _)| *action == a) {
+            return Err(SearchError::UnknownAction);
+        }
+        self.descend(action);
+        Ok(())
+    }
+
+    /// See [`NodeRef::select_best_action`].
+    ///
+    /// # Panics
+    ///
+    /// Panics if there are no children.
+    #[must_use]
+    pub fn select_best_action(&self) -> E::Action {
+        self.root().select_best_action()
+    }
+
+    /// See [`NodeRef::try_select_best_action`].
+    ///
+    /// # Errors
+    ///
+    /// Returns [`SearchError::NoChildren`] if the node is not expanded or
+    /// terminal.
+    pub fn try_select_best_action(&self) -> Result<E::Action, SearchError> {
+        self.root().try_select_best_action()
+    }
+
+    /// See [`NodeRef::select_selfplay_action`].
+    ///
+    /// # Panics
+    ///
+    /// Panics if there are no children, or if proportional sampling is
+    /// requested and no child has enough visits.
+    pub fn select_selfplay_action(
+        &self,
+        proportional_sample: bool,
+        rng: &mut impl Rng,
+    ) -> E::Action {
+        self.root().select_selfplay_action(proportional_sample, rng)
+    }
+
+    /// See [`NodeRef::try_select_selfplay_action`].
+    ///
+    /// # Errors
+    ///
+    /// Returns [`SearchError::NoChildren`] if there are no children, or
+    /// [`SearchError::Unvisited`] if proportional sampling is requested and
+    /// no child has enough visits.
+    pub fn try_select_selfplay_action(
+        &self,
+        proportional_sample: bool,
+        rng: &mut impl Rng,
+    ) -> Result<E::Action, SearchError> {
+        self.root().try_select_selfplay_action(proportional_sample, rng)
+    }
+
+    /// See [`NodeRef::sample_action_with_temperature`].
+    pub fn sample_action_with_temperature(
+        &self,
+        temperature: f32,
+        excluded: &[E::Action],
+        rng: &mut impl Rng,
+    ) -> Option<E::Action> {
+        self.root().sample_action_with_temperature(temperature, excluded, rng)
+    }
+
+    /// See [`NodeRef::ube_target`].
+    ///
+    /// # Panics
+    ///
+    /// Panics if there are no children.
+    #[must_use]
+    pub fn ube_target(&self, beta: f32, mate_discount: f32) -> NotNan<f32> {
+        self.root().ube_target(beta, mate_discount)
+    }
+}
+
+impl<'a, E: ActionIndex> NodeRef<'a, E> {
+    #[inline]
+    const fn new(arena: &'a Arena<E>, id: NodeId) -> Self {
+        Self { arena, id }
     }
 
     #[inline]
     #[must_use]
-    pub fn is_terminal(&self) -> bool {
-        self.evaluation.ply().is_some_and(|ply| ply == 0)
+    pub fn evaluation(self) -> Eval {
+        self.arena.evaluation(self.id)
+    }
+
+    /// `P(s_prev, a)`, normalized over the siblings.
+    #[inline]
+    #[must_use]
+    pub fn probability(self) -> NotNan<f32> {
+        self.arena.probability(self.id)
+    }
+
+    #[inline]
+    #[must_use]
+    pub fn std_dev(self) -> NotNan<f32> {
+        self.arena.std_dev(self.id)
+    }
+
+    #[inline]
+    #[must_use]
+    pub fn children(self) -> Children<'a, E> {
+        Children::new(self.arena, self.arena.children(self.id))
+    }
+
+    /// Logit of the prior, `log(P(s_prev, a))`.
+    ///
+    /// It is not stored, because it only differs from the logit given by the
+    /// network by a constant which is the same for all siblings, and softmax
+    /// and comparisons between siblings do not depend on that constant.
+    /// Selection works with the probability directly, so the log is only
+    /// taken for the Gumbel root and for display.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the probability is NaN.
+    #[inline]
+    #[must_use]
+    pub fn logit(self) -> NotNan<f32> {
+        NotNan::new(self.probability().ln()).expect("the logit of a probability should not be NaN")
+    }
+
+    /// Number of nodes in the tree below and including this one. Unlike
+    /// [`Node::node_count`], it has to visit them.
+    #[must_use]
+    pub fn node_count(self) -> usize {
+        1 + self.children().iter().map(|(_, child)| child.node_count()).sum::<usize>()
+    }
+
+    #[inline]
+    #[must_use]
+    pub fn needs_initialization(self) -> bool {
+        self.arena.children(self.id).is_empty() && !self.evaluation().is_known()
+    }
+
+    /// Returns an iterator over the Principal Variation below this node.
+    pub fn principal_variation(self) -> impl Iterator<Item = E::Action> + 'a {
+        PrincipalVariation { node: self }
+    }
+
+    #[inline]
+    #[must_use]
+    pub fn is_terminal(self) -> bool {
+        self.evaluation().ply().is_some_and(|ply| ply == 0)
     }
 
     /// Returns the visit count, accounting for virtual visits
     /// if the feature is enabled.
     #[inline]
     #[must_use]
-    pub const fn visit_count(&self) -> u32 {
+    pub fn visit_count(self) -> u32 {
         #[cfg(feature = "virtual")]
         {
-            self.visit_count + self.virtual_visits
+            self.real_visit_count() + self.arena.virtual_visits(self.id)
         }
         #[cfg(not(feature = "virtual"))]
-        self.visit_count
+        self.real_visit_count()
+    }
+
+    /// Returns the visit count without virtual visits.
+    #[inline]
+    fn real_visit_count(self) -> u32 {
+        self.arena.visit_count(self.id)
     }
 
-    /// Returns the negated value of this node.
+    /// Returns the negated value of this node, with known results
+    /// discounted by `mate_discount` (see [`Eval::backup_value`]).
     /// When using virtual visits, they are counted as losses.
     #[inline]
     #[must_use]
-    pub fn q_value(&self) -> NotNan<f32> {
+    pub fn q_value(self, mate_discount: f32) -> NotNan<f32> {
         #[cfg(feature = "virtual")]
         {
-            let negated_eval: NotNan<f32> = self.evaluation.negate().into();
-            let multiplied_by_count = negated_eval * self.visit_count as f32;
-            let including_virtual_losses = multiplied_by_count + self.virtual_visits as f32;
+            let negated_eval = self.evaluation().negate().backup_value(mate_discount);
+            let multiplied_by_count = negated_eval * self.real_visit_count() as f32;
+            let including_virtual_losses =
+                multiplied_by_count + self.arena.virtual_visits(self.id) as f32;
             including_virtual_losses / self.visit_count() as f32
         }
         #[cfg(not(feature = "virtual"))]
-        self.evaluation.negate().into()
+        self.evaluation().negate().backup_value(mate_discount)
     }
 
     /// Return the best action after search.
@@ -143,59 +395,117 @@ impl<E: Environment> Node<E> {
     ///
     /// Panics if there are no children.
     #[must_use]
-    pub fn select_best_action(&self) -> E::Action {
+    pub fn select_best_action(self) -> E::Action {
+        self.try_select_best_action().expect("there should be at least one child")
+    }
+
+    /// Like [`NodeRef::select_best_action`], but fallible.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`SearchError::NoChildren`] if the node is not expanded or
+    /// terminal.
+    pub fn try_select_best_action(self) -> Result<E::Action, SearchError> {
         let best_eval = self
-            .children
+            .children()
             .iter()
-            .map(|(_, child)| child.evaluation)
+            .map(|(_, child)| child.evaluation())
             .min()
-            .expect("there should be at least one child");
-        self.children
+            .ok_or(SearchError::NoChildren)?;
+        self.children()
             .iter()
             // If the node is solved, filter for optimal actions.
-            .filter(|(_, child)| !self.evaluation.is_known() || child.evaluation == best_eval)
+            .filter(|(_, child)| !self.evaluation().is_known() || child.evaluation() == best_eval)
             // Select the action with the most visits.
-            .max_by_key(|(_, child)| child.visit_count)
-            .expect("there should be at least one child")
-            .0
-            .clone()
+            .max_by_key(|(_, child)| child.real_visit_count())
+            .map(|(action, _)| action)
+            .ok_or(SearchError::NoChildren)
     }
 
     /// Return an action to use in selfplay.
     ///
     /// # Panics
     ///
-    /// Panics if there are no children.
+    /// Panics if there are no children, or if proportional sampling is
+    /// requested and no child has enough visits.
     pub fn select_selfplay_action(
-        &self,
+        self,
         proportional_sample: bool,
         rng: &mut impl Rng,
     ) -> E::Action {
+        self.try_select_selfplay_action(proportional_sample, rng)
+            .expect("there should be at least one child with enough visits")
+    }
+
+    /// Like [`NodeRef::select_selfplay_action`], but fallible.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`SearchError::NoChildren`] if there are no children, or
+    /// [`SearchError::Unvisited`] if proportional sampling is requested and
+    /// no child has enough visits.
+    pub fn try_select_selfplay_action(
+        self,
+        proportional_sample: bool,
+        rng: &mut impl Rng,
+    ) -> Result<E::Action, SearchError> {
         const THRESHOLD_VISITS: u32 = 32;
 
-        if self.evaluation.is_known() {
+        let children = self.children();
+        if children.is_empty() {
+            Err(SearchError::NoChildren)
+        } else if self.evaluation().is_known() {
             // The node is solved, pick the best action.
-            self.select_best_action()
+            self.try_select_best_action()
         } else if proportional_sample {
             // Select an action randomly, proportional to visits.
-            let weighted_index = WeightedIndex::new(self.children.iter().map(|(_, child)| {
-                if child.visit_count < THRESHOLD_VISITS {
+            let weighted_index = WeightedIndex::new(children.iter().map(|(_, child)| {
+                if child.real_visit_count() < THRESHOLD_VISITS {
                     0
                 } else {
-                    child.visit_count
+                    child.real_visit_count()
                 }
             }))
-            .expect("there should be at least one child and visits cannot be negative");
-            self.children[weighted_index.sample(rng)].0.clone()
+            .map_err(|_| SearchError::Unvisited)?;
+            children
+                .get(weighted_index.sample(rng))
+                .map(|(action, _)| action)
+                .ok_or(SearchError::NoChildren)
         } else {
             // Select the action with the most visits.
-            self.children
+            children
                 .iter()
-                .max_by_key(|(_, child)| child.visit_count)
-                .expect("there should be at least one child")
-                .0
-                .clone()
+                .max_by_key(|(_, child)| child.real_visit_count())
+                .map(|(action, _)| action)
+                .ok_or(SearchError::NoChildren)
+        }
+    }
+
+    /// Sample an action with probability proportional to
+    /// `(visits + prior)^(1 / temperature)`, never picking an excluded action.
+    ///
+    /// Returns `None` if the node is solved (deviating could throw away a
+    /// proven result) or if there is nothing left to pick.
+    pub fn sample_action_with_temperature(
+        self,
+        temperature: f32,
+        excluded: &[E::Action],
+        rng: &mut impl Rng,
+    ) -> Option<E::Action> {
+        if self.evaluation().is_known() {
+            return None;
         }
+        let children = self.children();
+        let weighted_index = WeightedIndex::new(children.iter().map(|(action, child)| {
+            if excluded.contains(&action) {
+                0.0
+            } else {
+                let weight = child.real_visit_count() as f32 + child.probability().into_inner();
+                weight.powf(temperature.recip())
+            }
+        }))
+        .ok()?;
+        children.get(weighted_index.sample(rng)).map(|(action, _)| action)
     }
 
     /// Get the UBE target from the root after search.
@@ -204,19 +514,22 @@ impl<E: Environment> Node<E> {
     ///
     /// Panics if there are no children.
     #[must_use]
-    pub fn ube_target(&self, beta: f32) -> NotNan<f32> {
+    pub fn ube_target(self, beta: f32, mate_discount: f32) -> NotNan<f32> {
         // UBE target = 0.0 when node is solved.
-        if self.evaluation.is_known() || self.needs_initialization() {
+        if self.evaluation().is_known() || self.needs_initialization() {
             NotNan::default()
         } else {
             // Child with maximum value + beta * std_dev.
             let std_dev = self
-                .children
+                .children()
                 .iter()
                 .map(|(_, child)| child)
-                .max_by_key(|child| NotNan::from(child.evaluation.negate()) + child.std_dev * beta)
+                .max_by_key(|child| {
+                    child.evaluation().negate().backup_value(mate_discount)
+                        + child.std_dev() * beta
+                })
                 .expect("There should be at least one child")
-                .std_dev;
+                .std_dev();
             std_dev * std_dev
         }
     }
@@ -1,28 +1,24 @@
-use ordered_float::NotNan;
 use rand::Rng;
 use rand_distr::{Dirichlet, Distribution};
 
-use super::Node;
-use crate::search::env::Environment;
+use super::{arena::ROOT, Node};
+use crate::search::env::ActionIndex;
 
-impl<E: Environment> Node<E> {
+impl<E: ActionIndex> Node<E> {
     #[allow(clippy::missing_panics_doc)]
     pub fn apply_dirichlet(&mut self, rng: &mut impl Rng, alpha: f32, ratio: f32) {
         assert!(
             !self.needs_initialization(),
             "cannot apply dirichlet noise without initialized policy"
         );
-        let dirichlet = Dirichlet::new(&vec![alpha; self.children.len()]).unwrap();
+        let children = self.arena.children(ROOT);
+        let dirichlet = Dirichlet::new(&vec![alpha; children.len()]).unwrap();
         let samples = dirichlet.sample(rng);
 
-        self.children
-            .iter_mut()
-            .zip(samples)
-            .for_each(|((_, child), noise)| {
-                child.probability = child.probability * (1.0 - ratio) + noise * ratio;
-                child.logit = NotNan::new(child.probability.ln())
-                    .expect("Logit from probability should not be NaN");
-            });
+        for (child, noise) in children.zip(samples) {
+            let probability = self.arena.probability_mut(child);
+            *probability = *probability * (1.0 - ratio) + noise * ratio;
+        }
     }
 }
 
@@ -34,14 +30,15 @@ mod tests {
 
     use crate::search::{
         agent::dummy::Dummy,
-        env::Environment,
+        env::ActionIndex,
         node::{policy::softmax, Node},
+        DISCOUNT_FACTOR,
     };
 
-    fn sum_of_probabilities<E: Environment>(node: &Node<E>) -> NotNan<f32> {
-        node.children
+    fn sum_of_probabilities<E: ActionIndex>(node: &Node<E>) -> NotNan<f32> {
+        node.children()
             .iter()
-            .map(|(_, child)| child.probability)
+            .map(|(_, child)| child.probability())
             .sum::<NotNan<f32>>()
     }
 
@@ -50,7 +47,7 @@ mod tests {
         let mut rng = StdRng::seed_from_u64(123);
         let mut node = Node::default();
         let env = Game::<3, 0>::default();
-        node.simulate_simple(&Dummy, env, 0.0);
+        node.simulate_simple(&Dummy, env, 0.0, DISCOUNT_FACTOR);
 
         println!("{node}");
         // Sum of probabilities is 1 before noise.
@@ -61,8 +58,8 @@ mod tests {
         // Sum of probabilities is 1 after noise.
         assert!((sum_of_probabilities(&node) - 1.0).abs() < 1.1 * f32::EPSILON);
         // Softmax of new logits equals probabilities.
-        softmax(node.children.iter().map(|(_, child)| child.logit))
-            .zip(node.children.iter().map(|(_, child)| child.probability))
+        softmax(node.children().iter().map(|(_, child)| child.logit()))
+            .zip(node.children().iter().map(|(_, child)| child.probability()))
             .for_each(|(a, b)| assert!((a - b).abs() < f32::EPSILON));
     }
 }
@@ -1,6 +1,6 @@
 use ordered_float::NotNan;
 
-use super::{super::env::Environment, Node};
+use super::{super::env::ActionIndex, Node, NodeRef, SearchError};
 
 /// Perform the softmax on an iterator.
 ///
@@ -18,102 +18,143 @@ pub fn softmax(
     exp.map(move |x| x / sum)
 }
 
-impl<E: Environment> Node<E> {
+impl<'a, E: ActionIndex> NodeRef<'a, E> {
     #[must_use]
-    pub fn most_visited_count(&self) -> f32 {
-        self.children
+    pub fn most_visited_count(self) -> f32 {
+        self.children()
             .iter()
-            .map(|(_, node)| node.visit_count)
+            .map(|(_, node)| node.real_visit_count())
             .max()
             .unwrap_or_default() as f32
     }
 
-    /// Get the improved policy for this node.
+    /// Get the improved policy for this node, where known results are
+    /// discounted by `mate_discount`.
     ///
     /// # Panics
     ///
     /// Panics if the evaluation is NaN.
-    pub fn improved_policy(&self, visitations: f32) -> impl Iterator<Item = NotNan<f32>> + '_ {
-        let p = self.children.iter().map(move |(_, node)| -> NotNan<f32> {
+    pub fn improved_policy(
+        self,
+        visitations: f32,
+        mate_discount: f32,
+    ) -> impl Iterator<Item = NotNan<f32>> + 'a {
+        let children = self.children();
+        let sigma = children.iter().map(move |(_, node)| -> NotNan<f32> {
             let completed_value = if node.needs_initialization() {
-                self.evaluation
+                self.evaluation()
             } else {
-                node.evaluation.negate()
+                node.evaluation().negate()
             }
-            .into();
-            sigma_improve(completed_value, node.std_dev, 0.0, visitations) + node.logit
+            .backup_value(mate_discount);
+            sigma_improve(completed_value, node.std_dev(), 0.0, visitations)
         });
-
-        softmax(p)
+        // This is the softmax of logit + sigma, but exp(ln(p) + sigma) is
+        // p * exp(sigma), so the log of each prior is never taken.
+        let max = children
+            .iter()
+            .zip(sigma.clone())
+            .filter(|((_, node), _)| *node.probability() > 0.0)
+            .map(|(_, sigma)| sigma)
+            .max()
+            .unwrap_or_default();
+        let weights = children
+            .into_iter()
+            .zip(sigma)
+            .map(move |((_, node), sigma)| node.probability() * (sigma - max).exp());
+        let sum: NotNan<f32> = weights.clone().sum();
+        weights.map(move |weight| weight / sum)
     }
 
     /// Get index of child which maximizes the improved policy.
     /// Losing actions are pruned unless this node is a proven loss.
     ///
-    /// # Panics
+    /// # Errors
     ///
-    /// Panics if there are no children.
-    #[must_use]
-    pub fn select_with_improved_policy(&self) -> usize {
-        self.improved_policy(self.most_visited_count())
-            .zip(self.children.iter())
+    /// Returns [`SearchError::NoChildren`] if there are no children.
+    pub fn select_with_improved_policy(self, mate_discount: f32) -> Result<usize, SearchError> {
+        let visit_count = self.real_visit_count();
+        self.improved_policy(self.most_visited_count(), mate_discount)
+            .zip(self.children())
             .enumerate()
             // Prune only losing moves to preserve optimality.
-            .filter(|(_, (_, (_, child)))| self.evaluation.is_loss() || !child.evaluation.is_win())
+            .filter(|(_, (_, (_, child)))| {
+                self.evaluation().is_loss() || !child.evaluation().is_win()
+            })
             // Minimize mean-squared-error between visits and improved policy
             .max_by_key(|(_, (pi, (_, node)))| {
-                pi - node.visit_count as f32 / ((self.visit_count + 1) as f32)
+                pi - node.real_visit_count() as f32 / ((visit_count + 1) as f32)
             })
             .map(|(i, _)| i)
-            .expect("there should always be a child to simulate")
+            .ok_or(SearchError::NoChildren)
     }
 
     /// Get index of child which maximizes PUCT.
     /// Losing actions are pruned unless this node is a proven loss.
     ///
-    /// # Panics
+    /// # Errors
     ///
-    /// Panics if there are no children.
-    #[must_use]
-    pub fn select_with_puct(&self, beta: f32) -> usize {
-        let parent_visit_count = self.visit_count as f32;
-        self.children
+    /// Returns [`SearchError::NoChildren`] if there are no children.
+    pub fn select_with_puct(self, beta: f32, mate_discount: f32) -> Result<usize, SearchError> {
+        let parent_visit_count = self.real_visit_count() as f32;
+        self.children()
             .iter()
             .enumerate()
-            .filter(|(_, (_, child))| self.evaluation.is_loss() || !child.evaluation.is_win())
+            .filter(|(_, (_, child))| self.evaluation().is_loss() || !child.evaluation().is_win())
             .max_by_key(|(_, (_, child))| {
-                let q = child.q_value();
+                let q = child.q_value(mate_discount);
                 let puct = upper_confidence_bound_with_predictor(
                     parent_visit_count,
-                    child.visit_count as f32,
-                    child.probability.into_inner(),
+                    child.real_visit_count() as f32,
+                    child.probability().into_inner(),
                 );
-                q + puct + child.std_dev * beta
+                q + puct + child.std_dev() * beta
             })
             .map(|(i, _)| i)
-            .expect("there should always be a child to simulate")
+            .ok_or(SearchError::NoChildren)
     }
 
     /// Get index of child which maximizes UCT.
     /// Losing actions are pruned unless this node is a proven loss.
     ///
-    /// # Panics
+    /// # Errors
     ///
-    /// Panics if there are no children.
-    #[must_use]
-    pub fn select_with_uct(&self, beta: f32) -> usize {
-        let parent_visit_count = self.visit_count as f32;
-        self.children
+    /// Returns [`SearchError::NoChildren`] if there are no children.
+    pub fn select_with_uct(self, beta: f32, mate_discount: f32) -> Result<usize, SearchError> {
+        let parent_visit_count = self.real_visit_count() as f32;
+        self.children()
             .iter()
             .enumerate()
-            .filter(|(_, (_, child))| self.evaluation.is_loss() || !child.evaluation.is_win())
+            .filter(|(_, (_, child))| self.evaluation().is_loss() || !child.evaluation().is_win())
             .max_by_key(|(_, (_, child))| {
-                let q = child.q_value();
-                let uct = upper_confidence_bound(parent_visit_count, child.visit_count as f32);
-                q + uct + child.std_dev * beta
+                let q = child.q_value(mate_discount);
+                let visit_count = child.real_visit_count() as f32;
+                let uct = upper_confidence_bound(parent_visit_count, visit_count);
+                q + uct + child.std_dev() * beta
             })
             .map(|(i, _)| i)
-            .expect("there should always be a child to simulate")
+            .ok_or(SearchError::NoChildren)
+    }
+}
+
+impl<E: ActionIndex> Node<E> {
+    /// See [`NodeRef::most_visited_count`].
+    #[must_use]
+    pub fn most_visited_count(&self) -> f32 {
+        self.root().most_visited_count()
+    }
+
+    /// See [`NodeRef::improved_policy`].
+    ///
+    /// # Panics
+    ///
+    /// Panics if the evaluation is NaN.
+    pub fn improved_policy(
+        &self,
+        visitations: f32,
+        mate_discount: f32,
+    ) -> impl Iterator<Item = NotNan<f32>> + '_ {
+        self.root().improved_policy(visitations, mate_discount)
     }
 }
 
@@ -165,9 +206,11 @@ pub fn upper_confidence_bound(parent_visit_count: f32, visit_count: f32) -> f32
 
 #[cfg(test)]
 mod tests {
+    use fast_tak::Game;
     use ordered_float::NotNan;
 
-    use super::softmax;
+    use super::{sigma_improve, softmax};
+    use crate::search::{agent::simple::Simple, node::Node, DISCOUNT_FACTOR};
 
     #[test]
     fn softmax_works() {
@@ -185,4 +228,26 @@ mod tests {
             ])
             .for_each(|(a, b)| assert!((a - b).abs() < f32::EPSILON, "{a} should equal {b}"));
     }
+
+    #[test]
+    fn improved_policy_is_softmax_of_logits_plus_sigma() {
+        let mut node = Node::default();
+        for _ in 0..200 {
+            node.simulate_simple(&Simple, Game::<5, 4>::default(), 0.0, DISCOUNT_FACTOR);
+        }
+        let visitations = node.most_visited_count();
+        let expected = softmax(node.children().iter().map(|(_, child)| {
+            let completed_value = if child.needs_initialization() {
+                node.evaluation()
+            } else {
+                child.evaluation().negate()
+            }
+            .backup_value(DISCOUNT_FACTOR);
+            sigma_improve(completed_value, child.std_dev(), 0.0, visitations) + child.logit()
+        }));
+
+        node.improved_policy(visitations, DISCOUNT_FACTOR)
+            .zip(expected)
+            .for_each(|(a, b)| assert!((a - b).abs() < 1e-6, "{a} should equal {b}"));
+    }
 }
@@ -0,0 +1,140 @@
+use std::{fmt, time::Duration};
+
+use super::{
+    super::{env::ActionIndex, eval::Eval},
+    Node,
+};
+
+/// How often to report progress during a search.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReportInterval {
+    Simulations(u32),
+    Time(Duration),
+}
+
+/// Decides when progress should be reported during a search.
+#[derive(Debug, Clone)]
+pub struct ProgressReporter {
+    interval: ReportInterval,
+    last_simulations: u32,
+    last_time: Duration,
+}
+
+impl ProgressReporter {
+    #[must_use]
+    pub const fn new(interval: ReportInterval) -> Self {
+        Self {
+            interval,
+            last_simulations: 0,
+            last_time: Duration::ZERO,
+        }
+    }
+
+    /// Returns `true` if progress should be reported now,
+    /// given the simulations and time since the search started.
+    pub fn should_report(&mut self, simulations: u32, elapsed: Duration) -> bool {
+        let report = match self.interval {
+            ReportInterval::Simulations(interval) => {
+                simulations >= self.last_simulations.saturating_add(interval)
+            }
+            ReportInterval::Time(interval) => elapsed >= self.last_time + interval,
+        };
+        if report {
+            self.last_simulations = simulations;
+            self.last_time = elapsed;
+        }
+        report
+    }
+}
+
+/// Snapshot of a search in progress.
+#[derive(Debug, Clone)]
+pub struct SearchProgress<A> {
+    pub simulations: u32,
+    pub elapsed: Duration,
+    /// Length of the principal variation.
+    pub depth: usize,
+    pub evaluation: Eval,
+    pub principal_variation: Vec<A>,
+    /// Share of the root visits of each action, highest first.
+    pub visit_shares: Vec<(A, f32)>,
+    /// Nodes in the tree, see [`Node::node_count`].
+    pub tree_nodes: usize,
+    /// See [`Node::approx_memory_bytes`].
+    pub memory_bytes: usize,
+}
+
+impl<E: ActionIndex> Node<E> {
+    /// Take a snapshot of the search from this root.
+    #[must_use]
+    pub fn progress(&self, elapsed: Duration) -> SearchProgress<E::Action> {
+        let principal_variation: Vec<_> = self.principal_variation().collect();
+        let root = self.root();
+        let total = root.real_visit_count().saturating_sub(1).max(1) as f32;
+        let mut visit_shares: Vec<_> = root
+            .children()
+            .iter()
+            .filter(|(_, child)| child.real_visit_count() > 0)
+            .map(|(action, child)| (action, child.real_visit_count() as f32 / total))
+            .collect();
+        visit_shares.sort_by(|(_, a), (_, b)| b.total_cmp(a));
+        SearchProgress {
+            simulations: root.real_visit_count(),
+            elapsed,
+            depth: principal_variation.len(),
+            evaluation: root.evaluation(),
+            principal_variation,
+            visit_shares,
+            tree_nodes: self.node_count(),
+            memory_bytes: self.approx_memory_bytes(),
+        }
+    }
+}
+
+impl<A: fmt::Display> fmt::Display for SearchProgress<A> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "simulations {} time {:.1}s depth {} eval {:+.4} pv",
+            self.simulations,
+            self.elapsed.as_secs_f32(),
+            self.depth,
+            self.evaluation
+        )?;
+        for action in &self.principal_variation {
+            write!(f, " {action}")?;
+        }
+        write!(f, " visits")?;
+        for (action, share) in self.visit_shares.iter().take(5) {
+            write!(f, " {action}:{:.1}%", share * 100.0)?;
+        }
+        write!(
+            f,
+            " tree {} nodes {:.1}MiB",
+            self.tree_nodes,
+            self.memory_bytes as f64 / (1024.0 * 1024.0)
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{ProgressReporter, ReportInterval};
+
+    #[test]
+    fn report_intervals() {
+        let mut reporter = ProgressReporter::new(ReportInterval::Simulations(100));
+        assert!(!reporter.should_report(99, Duration::ZERO));
+        assert!(reporter.should_report(100, Duration::ZERO));
+        assert!(!reporter.should_report(150, Duration::ZERO));
+        assert!(reporter.should_report(200, Duration::ZERO));
+
+        let mut reporter = ProgressReporter::new(ReportInterval::Time(Duration::from_secs(1)));
+        assert!(!reporter.should_report(1000, Duration::from_millis(900)));
+        assert!(reporter.should_report(1000, Duration::from_millis(1100)));
+        assert!(!reporter.should_report(2000, Duration::from_millis(2000)));
+        assert!(reporter.should_report(2000, Duration::from_millis(2100)));
+    }
+}
@@ -0,0 +1,297 @@
+//! Exact solver for forced wins (tinue) using proof-number search.
+//!
+//! The search only proves or disproves that the player to move can force a
+//! win, so it does not need an agent. It runs with iterative deepening on
+//! the number of plies: shallow wins are found quickly and the line which is
+//! reported is close to the shortest one. Within an iteration, proof and
+//! disproof numbers prioritize the most promising part of the tree, which
+//! handles wide positions much better than the uniform proving in MCTS.
+
+use super::env::{Environment, Terminal};
+
+const INFINITY: u32 = u32::MAX;
+const WON: (u32, u32) = (0, INFINITY);
+const LOST: (u32, u32) = (INFINITY, 0);
+
+/// Result of solving a position, from the perspective of the player to move.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Solution<A> {
+    /// The player to move can force a win. Contains the winning line, where
+    /// the defender plays the longest resistance.
+    Win(Vec<A>),
+    /// The player to move cannot force a win.
+    NoWin,
+    /// There is no forced win within the given number of plies.
+    NoWinWithin(usize),
+    /// The node budget ran out.
+    Unknown,
+}
+
+/// Limits of the solver.
+#[derive(Debug, Clone, Copy)]
+pub struct Solver {
+    /// Maximum number of nodes over all iterations.
+    pub max_nodes: usize,
+    /// Maximum length of the winning line in plies.
+    pub max_depth: usize,
+}
+
+impl Default for Solver {
+    fn default() -> Self {
+        Self {
+            max_nodes: 1_000_000,
+            max_depth: 9,
+        }
+    }
+}
+
+impl Solver {
+    /// Try to prove a forced win for the player to move.
+    /// Returns the solution and the number of nodes that were created.
+    #[must_use]
+    pub fn solve<E: Environment>(&self, env: &E) -> (Solution<E::Action>, usize) {
+        match env.terminal() {
+            Some(Terminal::Win) => return (Solution::Win(Vec::new()), 0),
+            Some(Terminal::Loss | Terminal::Draw) => return (Solution::NoWin, 0),
+            None => {}
+        }
+
+        let mut nodes = 0;
+        let mut depth = 1;
+        while depth <= self.max_depth {
+            let mut search = Search::new(depth);
+            let proven = search.run(env, self.max_nodes.saturating_sub(nodes));
+            nodes += search.nodes.len();
+            log::debug!("depth {depth}: {} nodes, proven: {proven:?}", search.nodes.len());
+            match proven {
+                Some(true) => return (Solution::Win(search.line()), nodes),
+                // The disproof did not rely on the depth limit.
+                Some(false) if !search.hit_depth_limit => return (Solution::NoWin, nodes),
+                Some(false) => {}
+                None => return (Solution::Unknown, nodes),
+            }
+            // Wins happen after the attacker's moves, so only odd depths are
+            // interesting (although the defender can also lose on their move).
+            depth += 2;
+        }
+        // Nothing was searched if the maximum depth is 0.
+        (Solution::NoWinWithin(depth.saturating_sub(2)), nodes)
+    }
+}
+
+struct PnNode<A> {
+    proof: u32,
+    disproof: u32,
+    /// Whether the attacker is to move.
+    or: bool,
+    children: Vec<(A, usize)>,
+}
+
+/// One depth-limited proof-number search.
+struct Search<A> {
+    nodes: Vec<PnNode<A>>,
+    depth_limit: usize,
+    hit_depth_limit: bool,
+}
+
+impl<A: Clone> Search<A> {
+    fn new(depth_limit: usize) -> Self {
+        Self {
+            nodes: vec![PnNode {
+                proof: 1,
+                disproof: 1,
+                or: true,
+                children: Vec::new(),
+            }],
+            depth_limit,
+            hit_depth_limit: false,
+        }
+    }
+
+    /// Returns whether the root was proven, or `None` if the budget ran out.
+    fn run<E: Environment<Action = A>>(&mut self, root: &E, budget: usize) -> Option<bool> {
+        let mut actions = Vec::new();
+        let mut path = Vec::new();
+        while self.nodes[0].proof != 0 && self.nodes[0].disproof != 0 {
+            if self.nodes.len() >= budget {
+                return None;
+            }
+
+            // Select the most proving node.
+            let mut env = root.clone();
+            let mut index = 0;
+            path.clear();
+            path.push(0);
+            while !self.nodes[index].children.is_empty() {
+                let node = &self.nodes[index];
+                let (action, child) = if node.or {
+                    node.children
+                        .iter()
+                        .min_by_key(|(_, child)| self.nodes[*child].proof)
+                } else {
+                    node.children
+                        .iter()
+                        .min_by_key(|(_, child)| self.nodes[*child].disproof)
+                }
+                .expect("there should be children")
+                .clone();
+                env.step(action);
+                index = child;
+                path.push(child);
+            }
+
+            self.expand(index, &env, path.len() - 1, &mut actions);
+            for &index in path.iter().rev() {
+                self.update(index);
+            }
+        }
+        Some(self.nodes[0].proof == 0)
+    }
+
+    fn expand<E: Environment<Action = A>>(
+        &mut self,
+        index: usize,
+        env: &E,
+        depth: usize,
+        actions: &mut Vec<A>,
+    ) {
+        let or = self.nodes[index].or;
+        env.populate_actions(actions);
+        if actions.is_empty() {
+            (self.nodes[index].proof, self.nodes[index].disproof) = LOST;
+            return;
+        }
+        for action in actions.drain(..) {
+            let mut child_env = env.clone();
+            child_env.step(action.clone());
+            // The terminal is from the perspective of the player to move in the
+            // child, which is the defender if the attacker made this move.
+            let (proof, disproof) = match child_env.terminal() {
+                Some(Terminal::Win) if or => LOST,
+                Some(Terminal::Win) => WON,
+                Some(Terminal::Loss) if or => WON,
+                Some(Terminal::Loss | Terminal::Draw) => LOST,
+                None if depth + 1 >= self.depth_limit => {
+                    self.hit_depth_limit = true;
+                    LOST
+                }
+                None => (1, 1),
+            };
+            let child = self.nodes.len();
+            self.nodes.push(PnNode {
+                proof,
+                disproof,
+                or: !or,
+                children: Vec::new(),
+            });
+            self.nodes[index].children.push((action, child));
+        }
+    }
+
+    fn update(&mut self, index: usize) {
+        let node = &self.nodes[index];
+        if node.children.is_empty() {
+            return;
+        }
+        let children = || node.children.iter().map(|(_, child)| &self.nodes[*child]);
+        let (proof, disproof) = if node.or {
+            (
+                children().map(|child| child.proof).min().unwrap_or(INFINITY),
+                children().map(|child| child.disproof).fold(0, u32::saturating_add),
+            )
+        } else {
+            (
+                children().map(|child| child.proof).fold(0, u32::saturating_add),
+                children().map(|child| child.disproof).min().unwrap_or(INFINITY),
+            )
+        };
+        let node = &mut self.nodes[index];
+        node.proof = proof;
+        node.disproof = disproof;
+    }
+
+    /// Number of plies until the win in a proven node, with the attacker
+    /// choosing the fastest win and the defender the slowest loss.
+    fn win_length(&self, index: usize) -> usize {
+        let node = &self.nodes[index];
+        let lengths = node
+            .children
+            .iter()
+            .filter(|(_, child)| self.nodes[*child].proof == 0)
+            .map(|(_, child)| 1 + self.win_length(*child));
+        if node.or {
+            lengths.min()
+        } else {
+            lengths.max()
+        }
+        .unwrap_or_default()
+    }
+
+    /// The winning line from a proven root.
+    fn line(&self) -> Vec<A> {
+        let mut line = Vec::new();
+        let mut index = 0;
+        loop {
+            let node = &self.nodes[index];
+            let proven = node
+                .children
+                .iter()
+                .filter(|(_, child)| self.nodes[*child].proof == 0)
+                .map(|(action, child)| (action, *child, self.win_length(*child)));
+            let best = if node.or {
+                proven.min_by_key(|(_, _, length)| *length)
+            } else {
+                proven.max_by_key(|(_, _, length)| *length)
+            };
+            let Some((action, child, _)) = best else {
+                return line;
+            };
+            line.push(action.clone());
+            index = child;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use fast_tak::Game;
+
+    use super::{Solution, Solver};
+
+    #[test]
+    fn find_tinue_easy() {
+        // Same position as in the MCTS tests.
+        let game: Game<3, 0> = Game::from_ptn_moves(&["a3", "c1", "c2", "c3", "b3", "c3-"]);
+        let (solution, _) = Solver::default().solve(&game);
+        let Solution::Win(line) = solution else {
+            panic!("the position should be a win, got {solution:?}");
+        };
+        assert_eq!(line[0], "b1".parse().unwrap());
+    }
+
+    #[test]
+    fn find_tinue_deeper() {
+        let game: Game<3, 0> = Game::from_ptn_moves(&["a3", "a1", "b1", "c1"]);
+        let (solution, _) = Solver::default().solve(&game);
+        let Solution::Win(line) = solution else {
+            panic!("the position should be a win, got {solution:?}");
+        };
+        assert!(line[0] == "b2".parse().unwrap() || line[0] == "c2".parse().unwrap());
+    }
+
+    #[test]
+    fn no_immediate_win_from_start() {
+        let game: Game<3, 0> = Game::default();
+        let solver = Solver {
+            max_nodes: 100_000,
+            max_depth: 1,
+        };
+        assert_eq!(solver.solve(&game).0, Solution::NoWinWithin(1));
+
+        let solver = Solver {
+            max_depth: 0,
+            ..solver
+        };
+        assert_eq!(solver.solve(&game), (Solution::NoWinWithin(0), 0));
+    }
+}
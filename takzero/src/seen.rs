@@ -0,0 +1,182 @@
+//! Approximate counts of how often targets were trained on.
+//!
+//! [`SeenFilter`] is a counting Bloom filter keyed by [`target_key`], which
+//! combines the Zobrist key of the position with the policy. Counts may be
+//! too high because of collisions, but never too low, so a limit on them is
+//! never exceeded. The filter has a fixed size no matter how many targets
+//! are counted, and can be saved so that it persists across restarts.
+
+use std::{
+    fs::File,
+    io::{self, BufReader, BufWriter, Read, Write},
+    path::Path,
+};
+
+use fast_tak::{Game, Reserves};
+
+use crate::{search::env::Environment, target::Target, zobrist::mix};
+
+const MAGIC: &[u8; 8] = b"tzseen1\n";
+
+/// Key of a target: the position and its policy. Targets for the same
+/// position with another policy, for example from reanalysis, get another
+/// key. The order of the policy does not matter.
+#[must_use]
+pub fn target_key<const N: usize, const HALF_KOMI: i8>(
+    target: &Target<Game<N, HALF_KOMI>>,
+) -> u64
+where
+    Reserves<N>: Default,
+{
+    target.policy.iter().fold(target.env.hash(), |key, (action, p)| {
+        // FNV-1a, so that keys do not change between builds.
+        let action = action
+            .to_string()
+            .bytes()
+            .fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+                (hash ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3)
+            });
+        key ^ mix(action ^ (u64::from(p.to_bits()) << 16))
+    })
+}
+
+/// Counting Bloom filter with saturating 8-bit counters.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SeenFilter {
+    counters: Box<[u8]>,
+    hashes: u32,
+}
+
+impl SeenFilter {
+    /// Empty filter with the given number of counters, each key using
+    /// `hashes` of them.
+    ///
+    /// # Panics
+    ///
+    /// Panics if there are no counters or no hashes.
+    #[must_use]
+    pub fn new(counters: usize, hashes: u32) -> Self {
+        assert!(counters > 0 && hashes > 0);
+        Self {
+            counters: vec![0; counters].into_boxed_slice(),
+            hashes,
+        }
+    }
+
+    /// Counters of the key (double hashing).
+    fn indices(&self, key: u64) -> impl Iterator<Item = usize> {
+        let len = self.counters.len() as u64;
+        let step = mix(key) | 1;
+        (0..u64::from(self.hashes))
+            .map(move |i| (key.wrapping_add(i.wrapping_mul(step)) % len) as usize)
+    }
+
+    /// How often the key was inserted, possibly too high.
+    #[must_use]
+    pub fn count(&self, key: u64) -> u8 {
+        self.indices(key)
+            .map(|i| self.counters[i])
+            .min()
+            .unwrap_or_default()
+    }
+
+    /// Count the key once more and return its new count.
+    pub fn insert(&mut self, key: u64) -> u8 {
+        let count = self.count(key);
+        for i in self.indices(key) {
+            // Only the smallest counters are raised (conservative update),
+            // which keeps the counts of other keys from growing.
+            if self.counters[i] == count {
+                self.counters[i] = count.saturating_add(1);
+            }
+        }
+        count.saturating_add(1)
+    }
+
+    /// Save the filter to a file.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the file cannot be written.
+    pub fn save(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        writer.write_all(MAGIC)?;
+        writer.write_all(&self.hashes.to_le_bytes())?;
+        writer.write_all(&(self.counters.len() as u64).to_le_bytes())?;
+        writer.write_all(&self.counters)?;
+        writer.flush()
+    }
+
+    /// Load a filter which was saved with [`Self::save`].
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the file cannot be read, or with
+    /// [`io::ErrorKind::InvalidData`] if it is not a saved filter.
+    pub fn load(path: impl AsRef<Path>) -> io::Result<Self> {
+        let invalid = |message| io::Error::new(io::ErrorKind::InvalidData, message);
+        let mut reader = BufReader::new(File::open(path)?);
+        let mut magic = [0; 8];
+        reader.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(invalid("not a seen filter"));
+        }
+        let mut hashes = [0; 4];
+        reader.read_exact(&mut hashes)?;
+        let mut len = [0; 8];
+        reader.read_exact(&mut len)?;
+        let hashes = u32::from_le_bytes(hashes);
+        let len = usize::try_from(u64::from_le_bytes(len)).map_err(|_| invalid("too large"))?;
+        if hashes == 0 || len == 0 {
+            return Err(invalid("empty seen filter"));
+        }
+        let mut counters = vec![0; len].into_boxed_slice();
+        reader.read_exact(&mut counters)?;
+        Ok(Self { counters, hashes })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use fast_tak::Game;
+    use ordered_float::NotNan;
+
+    use super::{target_key, SeenFilter};
+    use crate::{search::env::Environment, target::Target};
+
+    #[test]
+    fn count_and_persist() {
+        let env = Game::<4, 0>::default();
+        let mut actions = Vec::new();
+        env.populate_actions(&mut actions);
+        let mut target = Target {
+            policy: actions.iter().map(|a| (*a, NotNan::new(0.5).unwrap())).collect(),
+            env,
+            value: 0.0,
+            ube: 0.0,
+            weight: None,
+            move_limit: None,
+            model_steps: None,
+        };
+        let key = target_key(&target);
+        target.policy.reverse();
+        assert_eq!(target_key(&target), key);
+        target.policy[0].1 = NotNan::new(0.25).unwrap();
+        let other = target_key(&target);
+        assert_ne!(other, key);
+
+        let mut filter = SeenFilter::new(1 << 10, 3);
+        assert_eq!(filter.count(key), 0);
+        assert_eq!(filter.insert(key), 1);
+        assert_eq!(filter.insert(key), 2);
+        assert_eq!(filter.count(key), 2);
+        assert!(filter.count(other) <= 2);
+
+        let path = std::env::temp_dir().join(format!("takzero-seen-{}", std::process::id()));
+        filter.save(&path).unwrap();
+        assert_eq!(SeenFilter::load(&path).unwrap(), filter);
+        std::fs::write(&path, b"something else").unwrap();
+        assert!(SeenFilter::load(&path).is_err());
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,508 @@
+//! Replay files split into shards.
+//!
+//! Instead of one ever-growing file, games are appended to numbered shards
+//! like `replays-00017.txt`, and a new shard is started once the current one
+//! holds enough games or bytes. The manifest `replays-manifest.txt` lists
+//! the shards, oldest first, with a line like
+//! `00017 games=10000 bytes=5242880 created=1700000000 closed=true`.
+//! Closed shards never change again, so they can be archived or expired,
+//! and readers follow the shards with a [`ShardCursor`]. A checksum is
+//! written next to each closed shard (see [`checksum`]) and checked when a
+//! reader starts on it.
+//!
+//! The manifest is only changed while holding `replays-manifest.lock`, so
+//! several workers can share a directory.
+
+use std::{
+    fmt,
+    fs::{File, OpenOptions},
+    io::{self, BufRead, BufReader, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use fast_tak::{Game, Reserves};
+
+use crate::{
+    checksum::{self, ChecksumError},
+    header::{self, FileKind, Header},
+    metrics::REGISTRY,
+    target::Replay,
+};
+
+/// Locks older than this are assumed to be left over from a crashed worker.
+const STALE_LOCK: Duration = Duration::from_secs(60);
+
+/// When to start a new shard.
+#[derive(Debug, Clone, Copy)]
+pub struct RotationPolicy {
+    pub max_games: usize,
+    pub max_bytes: u64,
+}
+
+impl Default for RotationPolicy {
+    fn default() -> Self {
+        Self {
+            max_games: 10_000,
+            max_bytes: 256 << 20,
+        }
+    }
+}
+
+/// What to do with old shards.
+#[derive(Debug, Clone, Default)]
+pub struct ExpiryPolicy {
+    /// Number of closed shards to keep. All are kept if `None`.
+    pub keep: Option<usize>,
+    /// Directory to move expired shards to. They are deleted if `None`.
+    pub archive: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Shard {
+    pub index: u32,
+    pub games: usize,
+    pub bytes: u64,
+    /// Creation time in seconds since the Unix epoch.
+    pub created: u64,
+    pub closed: bool,
+}
+
+impl Shard {
+    #[must_use]
+    pub fn file_name(&self, prefix: &str) -> String {
+        format!("{prefix}-{:05}.txt", self.index)
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Manifest {
+    /// Shards from oldest to newest.
+    pub shards: Vec<Shard>,
+}
+
+fn manifest_path(directory: &Path, prefix: &str) -> PathBuf {
+    directory.join(format!("{prefix}-manifest.txt"))
+}
+
+fn invalid(line: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("invalid manifest line `{line}`"))
+}
+
+impl Manifest {
+    /// Load the manifest of shards with the prefix. A missing manifest is
+    /// empty.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the manifest cannot be read or is malformed.
+    pub fn load(directory: &Path, prefix: &str) -> io::Result<Self> {
+        match std::fs::read_to_string(manifest_path(directory, prefix)) {
+            Ok(content) => content.parse(),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Save the manifest, replacing the old one atomically.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the manifest cannot be written.
+    pub fn save(&self, directory: &Path, prefix: &str) -> io::Result<()> {
+        let path = manifest_path(directory, prefix);
+        let temporary = path.with_extension("tmp");
+        std::fs::write(&temporary, self.to_string())?;
+        std::fs::rename(temporary, path)
+    }
+
+    /// Paths of the shards from oldest to newest.
+    pub fn paths<'a>(
+        &'a self,
+        directory: &'a Path,
+        prefix: &'a str,
+    ) -> impl Iterator<Item = PathBuf> + 'a {
+        self.shards
+            .iter()
+            .map(move |shard| directory.join(shard.file_name(prefix)))
+    }
+
+    /// Total number of games in all shards.
+    #[must_use]
+    pub fn games(&self) -> usize {
+        self.shards.iter().map(|shard| shard.games).sum()
+    }
+}
+
+impl fmt::Display for Manifest {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for shard in &self.shards {
+            writeln!(
+                f,
+                "{:05} games={} bytes={} created={} closed={}",
+                shard.index, shard.games, shard.bytes, shard.created, shard.closed
+            )?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for Manifest {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut shards = Vec::new();
+        for line in s.lines().filter(|line| !line.trim().is_empty()) {
+            let mut fields = line.split_whitespace();
+            let mut shard = Shard {
+                index: fields
+                    .next()
+                    .and_then(|index| index.parse().ok())
+                    .ok_or_else(|| invalid(line))?,
+                games: 0,
+                bytes: 0,
+                created: 0,
+                closed: false,
+            };
+            for field in fields {
+                let (key, value) = field.split_once('=').ok_or_else(|| invalid(line))?;
+                let parsed = match key {
+                    "games" => value.parse().map(|games| shard.games = games).is_ok(),
+                    "bytes" => value.parse().map(|bytes| shard.bytes = bytes).is_ok(),
+                    "created" => value.parse().map(|created| shard.created = created).is_ok(),
+                    "closed" => value.parse().map(|closed| shard.closed = closed).is_ok(),
+                    _ => true,
+                };
+                if !parsed {
+                    return Err(invalid(line));
+                }
+            }
+            shards.push(shard);
+        }
+        Ok(Self { shards })
+    }
+}
+
+/// Lock on the manifest which is released when dropped.
+struct ManifestLock(PathBuf);
+
+impl ManifestLock {
+    fn acquire(directory: &Path, prefix: &str) -> io::Result<Self> {
+        let path = directory.join(format!("{prefix}-manifest.lock"));
+        loop {
+            match OpenOptions::new().write(true).create_new(true).open(&path) {
+                Ok(_) => return Ok(Self(path)),
+                Err(err) if err.kind() == io::ErrorKind::AlreadyExists => {
+                    let stale = std::fs::metadata(&path)
+                        .and_then(|metadata| metadata.modified())
+                        .ok()
+                        .and_then(|modified| modified.elapsed().ok())
+                        .is_some_and(|age| age > STALE_LOCK);
+                    if stale {
+                        log::warn!("Removing stale lock {}", path.display());
+                        let _ = std::fs::remove_file(&path);
+                    } else {
+                        std::thread::sleep(Duration::from_millis(10));
+                    }
+                }
+                Err(err) => return Err(err),
+            }
+        }
+    }
+}
+
+impl Drop for ManifestLock {
+    fn drop(&mut self) {
+        if let Err(err) = std::fs::remove_file(&self.0) {
+            log::error!("Could not release {}: {err}", self.0.display());
+        }
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |duration| duration.as_secs())
+}
+
+/// Appends replays to the newest shard and rotates shards.
+#[derive(Debug, Clone)]
+pub struct ShardWriter {
+    directory: PathBuf,
+    prefix: String,
+    rotation: RotationPolicy,
+    expiry: ExpiryPolicy,
+}
+
+impl ShardWriter {
+    #[must_use]
+    pub fn new(
+        directory: impl Into<PathBuf>,
+        prefix: impl Into<String>,
+        rotation: RotationPolicy,
+        expiry: ExpiryPolicy,
+    ) -> Self {
+        Self {
+            directory: directory.into(),
+            prefix: prefix.into(),
+            rotation,
+            expiry,
+        }
+    }
+
+    /// Append replays to the newest shard, start a new shard if it is full,
+    /// and expire old shards. Returns the paths of the closed shards.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if a shard or the manifest cannot be written.
+    pub fn append<const N: usize, const HALF_KOMI: i8>(
+        &self,
+        replays: &[Replay<Game<N, HALF_KOMI>>],
+        generation: Option<usize>,
+    ) -> io::Result<Vec<PathBuf>>
+    where
+        Reserves<N>: Default,
+    {
+        if replays.is_empty() {
+            return Ok(Vec::new());
+        }
+        let _lock = ManifestLock::acquire(&self.directory, &self.prefix)?;
+        let mut manifest = Manifest::load(&self.directory, &self.prefix)?;
+        let new_shard = match manifest.shards.last() {
+            Some(shard) if !shard.closed => None,
+            Some(shard) => Some(shard.index + 1),
+            None => Some(0),
+        };
+        if let Some(index) = new_shard {
+            manifest.shards.push(Shard {
+                index,
+                games: 0,
+                bytes: 0,
+                created: now(),
+                closed: false,
+            });
+        }
+        // The last shard is open now.
+        let last = manifest.shards.len() - 1;
+        let shard = &mut manifest.shards[last];
+
+        let header = Header::new::<N, HALF_KOMI>(FileKind::Replays, generation);
+        let path = self.directory.join(shard.file_name(&self.prefix));
+        let mut file = header::open_append(&path, &header)?;
+        let contents: String = replays.iter().map(ToString::to_string).collect();
+        file.write_all(contents.as_bytes())?;
+        shard.games += replays.len();
+        shard.bytes = file.metadata()?.len();
+        shard.closed =
+            shard.games >= self.rotation.max_games || shard.bytes >= self.rotation.max_bytes;
+        if shard.closed {
+            checksum::write_sidecar(&path)?;
+            log::info!("Closed replay shard {} with {} games.", path.display(), shard.games);
+        }
+
+        let expired = self.expire(&mut manifest)?;
+        manifest.save(&self.directory, &self.prefix)?;
+        Ok(expired)
+    }
+
+    /// Remove the oldest closed shards beyond the number to keep from the
+    /// manifest and archive or delete them.
+    fn expire(&self, manifest: &mut Manifest) -> io::Result<Vec<PathBuf>> {
+        let Some(keep) = self.expiry.keep else {
+            return Ok(Vec::new());
+        };
+        let closed = manifest.shards.iter().filter(|shard| shard.closed).count();
+        let mut expired = Vec::new();
+        for shard in manifest
+            .shards
+            .iter()
+            .filter(|shard| shard.closed)
+            .take(closed.saturating_sub(keep))
+        {
+            let name = shard.file_name(&self.prefix);
+            let path = self.directory.join(&name);
+            let sidecar = checksum::sidecar_path(&path);
+            if let Some(archive) = &self.expiry.archive {
+                std::fs::create_dir_all(archive)?;
+                let destination = archive.join(&name);
+                // Renaming does not work across filesystems.
+                if std::fs::rename(&path, &destination).is_err() {
+                    std::fs::copy(&path, &destination)?;
+                    std::fs::remove_file(&path)?;
+                }
+                if sidecar.exists() {
+                    let destination = checksum::sidecar_path(&destination);
+                    if std::fs::rename(&sidecar, &destination).is_err() {
+                        std::fs::copy(&sidecar, &destination)?;
+                        std::fs::remove_file(&sidecar)?;
+                    }
+                }
+                expired.push(destination);
+            } else {
+                std::fs::remove_file(&path)?;
+                if sidecar.exists() {
+                    std::fs::remove_file(&sidecar)?;
+                }
+                expired.push(path);
+            }
+        }
+        let count = expired.len();
+        let mut closed_seen = 0;
+        manifest.shards.retain(|shard| {
+            if shard.closed && closed_seen < count {
+                closed_seen += 1;
+                false
+            } else {
+                true
+            }
+        });
+        Ok(expired)
+    }
+}
+
+/// Position of a reader in a sequence of shards.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ShardCursor {
+    pub index: u32,
+    pub offset: u64,
+}
+
+impl ShardCursor {
+    /// Call `f` with every complete line which was added to the shards since
+    /// the last call, skipping headers. Expired shards are skipped, and
+    /// closed shards are checked against their checksum before reading them.
+    /// Corrupted shards never heal, so they are logged and skipped too.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the manifest or a shard cannot be read.
+    pub fn read_new_lines(
+        &mut self,
+        directory: &Path,
+        prefix: &str,
+        mut f: impl FnMut(&str),
+    ) -> io::Result<()> {
+        let manifest = Manifest::load(directory, prefix)?;
+        let first = self.index;
+        let mut shards = manifest
+            .shards
+            .iter()
+            .filter(|shard| shard.index >= first)
+            .peekable();
+        while let Some(shard) = shards.next() {
+            if shard.index != self.index {
+                *self = Self {
+                    index: shard.index,
+                    offset: 0,
+                };
+            }
+            let path = directory.join(shard.file_name(prefix));
+            if shard.closed && self.offset == 0 {
+                match checksum::verify(&path) {
+                    Ok(_) => {}
+                    Err(err @ ChecksumError::Corrupted { .. }) => {
+                        log::error!("Skipping corrupted shard: {err}");
+                        REGISTRY.inc_counter(
+                            "takzero_corrupted_shards_total",
+                            "Closed shards which were skipped because they failed their checksum.",
+                            1.0,
+                        );
+                        self.index = shard.index + 1;
+                        continue;
+                    }
+                    Err(err) => return Err(err.into()),
+                }
+            }
+            let mut reader = BufReader::new(File::open(path)?);
+            reader.seek(SeekFrom::Start(self.offset))?;
+            let mut line = String::new();
+            loop {
+                line.clear();
+                let read = reader.read_line(&mut line)?;
+                // A line without a newline may still be being written.
+                if read == 0 || !line.ends_with('\n') {
+                    break;
+                }
+                self.offset += read as u64;
+                if !line.starts_with(header::MAGIC) {
+                    f(line.trim_end());
+                }
+            }
+            if !shard.closed || shards.peek().is_none() {
+                break;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use fast_tak::Game;
+
+    use super::{ExpiryPolicy, Manifest, RotationPolicy, ShardCursor, ShardWriter};
+    use crate::target::Replay;
+
+    #[test]
+    fn rotate_read_and_expire() {
+        let directory = std::env::temp_dir().join(format!("takzero-shards-{}", std::process::id()));
+        let archive = directory.join("archive");
+        std::fs::create_dir_all(&directory).unwrap();
+        let writer = ShardWriter::new(
+            &directory,
+            "replays",
+            RotationPolicy {
+                max_games: 3,
+                max_bytes: u64::MAX,
+            },
+            ExpiryPolicy {
+                keep: Some(1),
+                archive: Some(archive.clone()),
+            },
+        );
+        let replay: Replay<Game<3, 0>> = "[TPS \"x3/x3/x3 1 1\"] a1 c3".parse().unwrap();
+        let mut cursor = ShardCursor::default();
+        let mut lines = 0;
+
+        writer.append(&[replay.clone(), replay.clone()], None).unwrap();
+        cursor.read_new_lines(&directory, "replays", |_| lines += 1).unwrap();
+        assert_eq!(lines, 2);
+
+        // The first shard is closed after the third game.
+        writer.append(std::slice::from_ref(&replay), None).unwrap();
+        writer.append(&[replay.clone(), replay.clone(), replay.clone()], None).unwrap();
+        writer.append(std::slice::from_ref(&replay), None).unwrap();
+        let manifest = Manifest::load(&directory, "replays").unwrap();
+        assert_eq!(manifest.shards.len(), 2);
+        assert_eq!(manifest.shards[0].index, 1);
+        assert_eq!(manifest.games(), 4);
+        assert!(archive.join("replays-00000.txt").exists());
+        assert!(archive.join("replays-00000.txt.xxh3").exists());
+        assert_eq!(manifest.to_string().parse::<Manifest>().unwrap(), manifest);
+
+        // The cursor was in the expired shard, so it continues with the next one.
+        cursor
+            .read_new_lines(&directory, "replays", |line| {
+                assert_eq!(line.parse::<Replay<Game<3, 0>>>().unwrap(), replay);
+                lines += 1;
+            })
+            .unwrap();
+        assert_eq!(lines, 6);
+        assert_eq!(cursor.index, 2);
+
+        // A corrupted closed shard is skipped instead of read.
+        let shard = directory.join("replays-00001.txt");
+        let mut content = std::fs::read(&shard).unwrap();
+        *content.last_mut().unwrap() = b' ';
+        std::fs::write(&shard, content).unwrap();
+        let mut cursor = ShardCursor::default();
+        let mut lines = 0;
+        cursor.read_new_lines(&directory, "replays", |_| lines += 1).unwrap();
+        assert_eq!(lines, 1);
+        assert_eq!(cursor.index, 2);
+
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+}
@@ -0,0 +1,288 @@
+//! Live view of running games.
+//!
+//! Selfplay publishes every move together with the root evaluation into the
+//! global [`SPECTATOR`], and [`serve`] answers `GET /` with a page of boards
+//! and `GET /events` with a stream of
+//! [server-sent events](https://html.spec.whatwg.org/multipage/server-sent-events.html),
+//! so games can be watched in a browser while they are being played.
+//! Nothing is recorded until [`serve`] has been called.
+//!
+//! Every event is a JSON object with a `type`:
+//! - `game`: the `moves`, root `values`, and current `tps` of a game, sent
+//!   for every running game when a spectator connects,
+//! - `move`: a `move` was played, with the root `value` of the search from
+//!   white's perspective and the `tps` afterwards,
+//! - `end`: the game finished with a PTN `result`.
+//!
+//! All events have the `game` id.
+
+use std::{
+    collections::BTreeMap,
+    fmt::{self, Write as _},
+    io::Write,
+    net::{TcpListener, TcpStream, ToSocketAddrs},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::{self, Receiver, RecvTimeoutError, Sender},
+        Mutex,
+    },
+    thread::JoinHandle,
+    time::Duration,
+};
+
+use crate::{metrics::read_request_line, search::node::export::write_string};
+
+pub static SPECTATOR: Spectator = Spectator::new();
+
+/// Comments are sent this often, so that proxies do not close idle streams.
+const KEEP_ALIVE: Duration = Duration::from_secs(15);
+const PAGE: &str = include_str!("spectator.html");
+
+#[derive(Debug, Default)]
+struct LiveGame {
+    moves: Vec<String>,
+    values: Vec<f32>,
+    tps: String,
+}
+
+#[derive(Debug)]
+struct State {
+    games: BTreeMap<u64, LiveGame>,
+    subscribers: Vec<Sender<String>>,
+}
+
+impl State {
+    /// Send an event to every spectator, forgetting those which left.
+    fn broadcast(&mut self, event: &str) {
+        self.subscribers
+            .retain(|subscriber| subscriber.send(event.to_string()).is_ok());
+    }
+}
+
+#[derive(Debug)]
+pub struct Spectator {
+    enabled: AtomicBool,
+    state: Mutex<State>,
+}
+
+impl Default for Spectator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Spectator {
+    #[must_use]
+    pub const fn new() -> Self {
+        Self {
+            enabled: AtomicBool::new(false),
+            state: Mutex::new(State {
+                games: BTreeMap::new(),
+                subscribers: Vec::new(),
+            }),
+        }
+    }
+
+    fn state(&self) -> std::sync::MutexGuard<'_, State> {
+        self.state.lock().expect("spectator lock should not be poisoned")
+    }
+
+    /// Whether games are being recorded, so that callers can skip
+    /// formatting them otherwise.
+    #[must_use]
+    pub fn is_enabled(&self) -> bool {
+        self.enabled.load(Ordering::Relaxed)
+    }
+
+    /// Start recording games.
+    pub fn enable(&self) {
+        self.enabled.store(true, Ordering::Relaxed);
+    }
+
+    /// Record a move and the root value of the search which chose it, from
+    /// white's perspective. Games which are not known yet are started, so
+    /// games which were running before spectating was enabled show up too.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the lock is poisoned.
+    pub fn play(&self, game: u64, action: &str, value: f32, tps: String) {
+        if !self.is_enabled() {
+            return;
+        }
+        let mut state = self.state();
+        let live = state.games.entry(game).or_default();
+        live.moves.push(action.to_string());
+        live.values.push(value);
+        let ply = live.moves.len();
+        let mut event = String::new();
+        write_move(&mut event, game, ply, action, value, &tps)
+            .expect("writing to a string should not fail");
+        live.tps = tps;
+        state.broadcast(&event);
+    }
+
+    /// Record that a game finished with a PTN result.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the lock is poisoned.
+    pub fn finish(&self, game: u64, result: &str) {
+        if !self.is_enabled() {
+            return;
+        }
+        let mut state = self.state();
+        state.games.remove(&game);
+        let mut event = format!("{{\"type\":\"end\",\"game\":{game},\"result\":");
+        write_string(&mut event, result).expect("writing to a string should not fail");
+        event.push('}');
+        state.broadcast(&event);
+    }
+
+    /// Subscribe to events, starting with the state of every running game.
+    fn subscribe(&self) -> Receiver<String> {
+        let (sender, receiver) = mpsc::channel();
+        let mut state = self.state();
+        for (&id, game) in &state.games {
+            let mut event = String::new();
+            write_game(&mut event, id, game).expect("writing to a string should not fail");
+            // The receiver is still in scope, so this cannot fail.
+            let _ = sender.send(event);
+        }
+        state.subscribers.push(sender);
+        receiver
+    }
+}
+
+fn write_number(out: &mut String, x: f32) -> fmt::Result {
+    if x.is_finite() {
+        write!(out, "{x}")
+    } else {
+        write!(out, "null")
+    }
+}
+
+fn write_move(
+    out: &mut String,
+    game: u64,
+    ply: usize,
+    action: &str,
+    value: f32,
+    tps: &str,
+) -> fmt::Result {
+    write!(out, "{{\"type\":\"move\",\"game\":{game},\"ply\":{ply},\"move\":")?;
+    write_string(out, action)?;
+    out.push_str(",\"value\":");
+    write_number(out, value)?;
+    out.push_str(",\"tps\":");
+    write_string(out, tps)?;
+    out.push('}');
+    Ok(())
+}
+
+fn write_game(out: &mut String, id: u64, game: &LiveGame) -> fmt::Result {
+    write!(out, "{{\"type\":\"game\",\"game\":{id},\"moves\":[")?;
+    for (i, action) in game.moves.iter().enumerate() {
+        if i > 0 {
+            out.push(',');
+        }
+        write_string(out, action)?;
+    }
+    out.push_str("],\"values\":[");
+    for (i, &value) in game.values.iter().enumerate() {
+        if i > 0 {
+            out.push(',');
+        }
+        write_number(out, value)?;
+    }
+    out.push_str("],\"tps\":");
+    write_string(out, &game.tps)?;
+    out.push('}');
+    Ok(())
+}
+
+/// Enable the global spectator and serve it on a background thread.
+/// Every event stream gets its own thread.
+///
+/// # Errors
+///
+/// Returns an error if the address cannot be bound.
+pub fn serve(address: impl ToSocketAddrs) -> std::io::Result<JoinHandle<()>> {
+    let listener = TcpListener::bind(address)?;
+    log::info!("Serving live games on http://{}", listener.local_addr()?);
+    SPECTATOR.enable();
+    Ok(std::thread::spawn(move || {
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    std::thread::spawn(move || {
+                        if let Err(err) = respond(stream) {
+                            log::debug!("Spectator connection closed: {err}");
+                        }
+                    });
+                }
+                Err(err) => log::warn!("Could not accept spectator connection: {err}"),
+            }
+        }
+    }))
+}
+
+fn respond(mut stream: TcpStream) -> std::io::Result<()> {
+    let request_line = read_request_line(&stream)?;
+    match request_line.split_whitespace().take(2).collect::<Vec<_>>()[..] {
+        ["GET", "/"] => write!(
+            stream,
+            "HTTP/1.1 200 OK\r\nContent-Type: text/html; charset=utf-8\r\nContent-Length: \
+             {}\r\nConnection: close\r\n\r\n{PAGE}",
+            PAGE.len()
+        ),
+        ["GET", "/events"] => {
+            write!(
+                stream,
+                "HTTP/1.1 200 OK\r\nContent-Type: text/event-stream\r\nCache-Control: \
+                 no-cache\r\nConnection: keep-alive\r\n\r\n"
+            )?;
+            let events = SPECTATOR.subscribe();
+            loop {
+                match events.recv_timeout(KEEP_ALIVE) {
+                    Ok(event) => write!(stream, "data: {event}\n\n")?,
+                    Err(RecvTimeoutError::Timeout) => write!(stream, ": keep-alive\n\n")?,
+                    Err(RecvTimeoutError::Disconnected) => return Ok(()),
+                }
+                stream.flush()?;
+            }
+        }
+        _ => write!(
+            stream,
+            "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Spectator;
+
+    #[test]
+    fn events() {
+        let spectator = Spectator::new();
+        spectator.play(0, "a1", 0.5, "x3/x3/2,x2 2 1".into());
+        assert!(spectator.state().games.is_empty(), "disabled spectators record nothing");
+
+        spectator.enable();
+        spectator.play(3, "a1", 0.5, "x3/x3/2,x2 2 1".into());
+        let events = spectator.subscribe();
+        spectator.play(3, "c3", -0.25, "x2,1/x3/2,x2 1 2".into());
+        spectator.finish(3, "0-R");
+
+        let events: Vec<_> = events.try_iter().collect();
+        assert_eq!(events, [
+            "{\"type\":\"game\",\"game\":3,\"moves\":[\"a1\"],\"values\":[0.5],\"tps\":\"x3/x3/2,\
+             x2 2 1\"}",
+            "{\"type\":\"move\",\"game\":3,\"ply\":2,\"move\":\"c3\",\"value\":-0.25,\"tps\":\"x2,\
+             1/x3/2,x2 1 2\"}",
+            "{\"type\":\"end\",\"game\":3,\"result\":\"0-R\"}",
+        ]);
+        assert!(spectator.state().games.is_empty());
+    }
+}
@@ -0,0 +1,471 @@
+//! Object storage for replays and checkpoints.
+//!
+//! Distributed runs on machines without a shared filesystem push replay
+//! shards and checkpoints to an object store, and workers pull them from
+//! there. Two backends are provided: a local directory (useful for testing
+//! and for a shared filesystem) and S3-compatible storage, which goes through
+//! the `aws` command line tool so that credentials are configured the usual
+//! way. Set `TAKZERO_S3_ENDPOINT` to use a provider other than AWS.
+//!
+//! `learn` pushes [`PREVIOUS_MODEL_FILES`] and [`MODEL_FILES`] and pulls the target shards which
+//! `selfplay` and `reanalyze` push, and those pull the model when its steps
+//! change. `reanalyze` also pulls the replay shards which `selfplay` pushes.
+
+use std::{
+    collections::HashSet,
+    hash::BuildHasher,
+    io::{self, Write},
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+};
+
+use thiserror::Error;
+
+use crate::checksum::{self, Checksum, ChecksumError};
+
+/// Files which are pushed for every new model. The steps are pushed last, so
+/// a model whose steps were pulled is complete.
+pub const MODEL_FILES: [&str; 4] = [
+    "model_latest.ot",
+    "model_latest.ot.xxh3",
+    "model_latest.ot.repr",
+    "model_latest_steps.txt",
+];
+
+/// Files of the previous good model, which workers fall back to if the
+/// latest one is corrupted (see [`crate::network::checkpoint`]).
+///
+/// They are pushed before [`MODEL_FILES`] and pulled with them when there
+/// are any.
+pub const PREVIOUS_MODEL_FILES: [&str; 3] = [
+    "model_latest.ot.previous",
+    "model_latest.ot.previous.xxh3",
+    "model_latest.ot.previous.repr",
+];
+
+pub trait ObjectStore: Send + Sync {
+    /// Store an object, replacing it if it exists.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the object cannot be stored.
+    fn put(&self, key: &str, data: &[u8]) -> io::Result<()>;
+
+    /// Fetch an object.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the object does not exist or cannot be fetched.
+    fn get(&self, key: &str) -> io::Result<Vec<u8>>;
+
+    /// List the keys of objects which start with the prefix, in sorted order.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the objects cannot be listed.
+    fn list(&self, prefix: &str) -> io::Result<Vec<String>>;
+
+    /// Upload a local file.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the file cannot be read or stored.
+    fn put_file(&self, key: &str, path: &Path) -> io::Result<()> {
+        self.put(key, &std::fs::read(path)?)
+    }
+
+    /// Download an object into a local file. The file is replaced atomically
+    /// so that readers never see a partial download.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the object cannot be fetched or written.
+    fn get_file(&self, key: &str, path: &Path) -> io::Result<()> {
+        let data = self.get(key)?;
+        let temporary = path.with_extension("download");
+        std::fs::write(&temporary, data)?;
+        std::fs::rename(temporary, path)
+    }
+}
+
+/// Objects stored as files in a directory.
+#[derive(Debug, Clone)]
+pub struct LocalStore {
+    root: PathBuf,
+}
+
+impl LocalStore {
+    #[must_use]
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        Self { root: root.into() }
+    }
+}
+
+impl ObjectStore for LocalStore {
+    fn put(&self, key: &str, data: &[u8]) -> io::Result<()> {
+        let path = self.root.join(key);
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        let temporary = path.with_extension("upload");
+        std::fs::write(&temporary, data)?;
+        std::fs::rename(temporary, path)
+    }
+
+    fn get(&self, key: &str) -> io::Result<Vec<u8>> {
+        std::fs::read(self.root.join(key))
+    }
+
+    fn list(&self, prefix: &str) -> io::Result<Vec<String>> {
+        let mut keys = Vec::new();
+        let mut directories = vec![self.root.clone()];
+        while let Some(directory) = directories.pop() {
+            for entry in std::fs::read_dir(directory)? {
+                let path = entry?.path();
+                if path.is_dir() {
+                    directories.push(path);
+                } else if let Some(key) = path
+                    .strip_prefix(&self.root)
+                    .ok()
+                    .and_then(Path::to_str)
+                    .map(|key| key.replace(std::path::MAIN_SEPARATOR, "/"))
+                {
+                    if key.starts_with(prefix) {
+                        keys.push(key);
+                    }
+                }
+            }
+        }
+        keys.sort_unstable();
+        Ok(keys)
+    }
+}
+
+/// Objects stored in an S3-compatible bucket, accessed with the `aws` CLI.
+#[derive(Debug, Clone)]
+pub struct S3Store {
+    bucket: String,
+    prefix: String,
+    endpoint: Option<String>,
+}
+
+impl S3Store {
+    #[must_use]
+    pub fn new(bucket: impl Into<String>, prefix: impl Into<String>) -> Self {
+        Self {
+            bucket: bucket.into(),
+            prefix: prefix.into().trim_matches('/').to_string(),
+            endpoint: std::env::var("TAKZERO_S3_ENDPOINT").ok(),
+        }
+    }
+
+    fn full_key(&self, key: &str) -> String {
+        if self.prefix.is_empty() {
+            key.to_string()
+        } else {
+            format!("{}/{key}", self.prefix)
+        }
+    }
+
+    fn url(&self, key: &str) -> String {
+        format!("s3://{}/{}", self.bucket, self.full_key(key))
+    }
+
+    fn aws(&self) -> Command {
+        let mut command = Command::new("aws");
+        if let Some(endpoint) = &self.endpoint {
+            command.args(["--endpoint-url", endpoint]);
+        }
+        command
+    }
+}
+
+fn run(mut command: Command, stdin: Option<&[u8]>) -> io::Result<Vec<u8>> {
+    let mut child = command
+        .stdin(if stdin.is_some() {
+            Stdio::piped()
+        } else {
+            Stdio::null()
+        })
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+    if let (Some(data), Some(mut pipe)) = (stdin, child.stdin.take()) {
+        pipe.write_all(data)?;
+    }
+    let output = child.wait_with_output()?;
+    if output.status.success() {
+        Ok(output.stdout)
+    } else {
+        Err(io::Error::other(
+            String::from_utf8_lossy(&output.stderr).trim().to_string(),
+        ))
+    }
+}
+
+impl ObjectStore for S3Store {
+    fn put(&self, key: &str, data: &[u8]) -> io::Result<()> {
+        let mut command = self.aws();
+        command.args(["s3", "cp", "-", &self.url(key)]);
+        run(command, Some(data)).map(|_| ())
+    }
+
+    fn get(&self, key: &str) -> io::Result<Vec<u8>> {
+        let mut command = self.aws();
+        command.args(["s3", "cp", &self.url(key), "-"]);
+        run(command, None)
+    }
+
+    fn list(&self, prefix: &str) -> io::Result<Vec<String>> {
+        let mut command = self.aws();
+        // One key per line, so that keys may contain spaces.
+        command.args([
+            "s3api",
+            "list-objects-v2",
+            "--bucket",
+            &self.bucket,
+            "--prefix",
+            &self.full_key(prefix),
+            "--query",
+            "Contents[].[Key]",
+            "--output",
+            "text",
+        ]);
+        let output = String::from_utf8(run(command, None)?)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+        Ok(self.parse_listing(&output))
+    }
+}
+
+impl S3Store {
+    /// Keys relative to the prefix of the store in the text output of
+    /// `list-objects-v2`, which is `None` when nothing matches.
+    fn parse_listing(&self, output: &str) -> Vec<String> {
+        let mut keys: Vec<_> = output
+            .lines()
+            .filter(|line| !line.is_empty() && *line != "None")
+            .filter_map(|key| {
+                if self.prefix.is_empty() {
+                    Some(key)
+                } else {
+                    key.strip_prefix(&self.prefix)?.strip_prefix('/')
+                }
+            })
+            .map(ToString::to_string)
+            .collect();
+        keys.sort_unstable();
+        keys
+    }
+}
+
+/// Download files written by other processes into the directory. Files
+/// which cannot be pulled are kept as they were.
+pub fn pull_files(store: &dyn ObjectStore, directory: &Path, names: &[&str]) {
+    for name in names {
+        if let Err(err) = store.get_file(name, &directory.join(name)) {
+            log::debug!("Could not pull {name}: {err}");
+        }
+    }
+}
+
+/// Download [`MODEL_FILES`] if the steps of the pushed model differ from
+/// those in the directory, and [`PREVIOUS_MODEL_FILES`] if there are any.
+/// Returns whether a model was pulled.
+///
+/// # Errors
+///
+/// Returns an error if a file cannot be pulled. The steps are only written
+/// after the other files, so the pull is tried again next time.
+pub fn pull_model(store: &dyn ObjectStore, directory: &Path) -> io::Result<bool> {
+    let [model, checksum, repr, steps_name] = MODEL_FILES;
+    let steps = store.get(steps_name)?;
+    if std::fs::read(directory.join(steps_name)).is_ok_and(|local| local == steps) {
+        return Ok(false);
+    }
+    for name in [model, checksum, repr] {
+        store.get_file(name, &directory.join(name))?;
+    }
+    // The first model has no previous one.
+    pull_files(store, directory, &PREVIOUS_MODEL_FILES);
+    let path = directory.join(steps_name);
+    let temporary = path.with_extension("download");
+    std::fs::write(&temporary, steps)?;
+    std::fs::rename(temporary, path)?;
+    Ok(true)
+}
+
+/// Push a shard, with its checksum pushed first so that it exists whenever
+/// the shard does.
+///
+/// # Errors
+///
+/// Returns an error if the shard or its checksum cannot be stored.
+pub fn push_shard(store: &dyn ObjectStore, key: &str, contents: &[u8]) -> io::Result<()> {
+    let checksum = Checksum::of(contents).to_string();
+    store.put(&format!("{key}.{}", checksum::EXTENSION), checksum.as_bytes())?;
+    store.put(key, contents)
+}
+
+/// Hand the contents of the shards under `prefix/` which were not pulled yet
+/// to `sink`.
+///
+/// Shards are checked against their checksum if they have one. Shards which
+/// cannot be pulled are logged and tried again next time, except for
+/// corrupted ones, which are skipped.
+///
+/// # Errors
+///
+/// Returns an error if the shards cannot be listed.
+pub fn pull_new_shards<S: BuildHasher>(
+    store: &dyn ObjectStore,
+    prefix: &str,
+    pulled: &mut HashSet<String, S>,
+    mut sink: impl FnMut(&[u8]) -> io::Result<()>,
+) -> io::Result<()> {
+    let keys = store.list(&format!("{prefix}/"))?;
+    let sidecars: HashSet<_> = keys
+        .iter()
+        .filter(|key| key.ends_with(&format!(".{}", checksum::EXTENSION)))
+        .cloned()
+        .collect();
+    for key in keys {
+        if pulled.contains(&key) || sidecars.contains(&key) {
+            continue;
+        }
+        let sidecar = format!("{key}.{}", checksum::EXTENSION);
+        let data = match store.get(&key) {
+            Ok(data) => data,
+            Err(err) => {
+                log::error!("Could not pull {key}: {err}");
+                continue;
+            }
+        };
+        if sidecars.contains(&sidecar) {
+            let checked = store
+                .get(&sidecar)
+                .map_err(ChecksumError::from)
+                .and_then(|expected| String::from_utf8_lossy(&expected).parse::<Checksum>())
+                .and_then(|expected| checksum::check(&key, expected, Checksum::of(&data)));
+            match checked {
+                Ok(()) => {}
+                Err(err @ ChecksumError::Corrupted { .. }) => {
+                    log::error!("Skipping corrupted shard: {err}");
+                    pulled.insert(key);
+                    continue;
+                }
+                Err(err) => {
+                    log::error!("Could not check {key}: {err}");
+                    continue;
+                }
+            }
+        }
+        match sink(&data) {
+            Ok(()) => {
+                pulled.insert(key);
+            }
+            Err(err) => log::error!("Could not save {key}: {err}"),
+        }
+    }
+    Ok(())
+}
+
+#[derive(Error, Debug)]
+pub enum ParseStoreError {
+    #[error("missing bucket in `{0}`")]
+    MissingBucket(String),
+}
+
+/// Open a store from a URL: `s3://bucket/prefix` for S3-compatible storage,
+/// and `file:///path` or a plain path for a local directory.
+///
+/// # Errors
+///
+/// Returns an error if an S3 URL has no bucket.
+pub fn open(url: &str) -> Result<Box<dyn ObjectStore>, ParseStoreError> {
+    if let Some(rest) = url.strip_prefix("s3://") {
+        let (bucket, prefix) = rest.split_once('/').unwrap_or((rest, ""));
+        if bucket.is_empty() {
+            return Err(ParseStoreError::MissingBucket(url.to_string()));
+        }
+        Ok(Box::new(S3Store::new(bucket, prefix)))
+    } else {
+        Ok(Box::new(LocalStore::new(
+            url.strip_prefix("file://").unwrap_or(url),
+        )))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use super::{open, pull_model, pull_new_shards, push_shard, LocalStore, ObjectStore, S3Store};
+
+    #[test]
+    fn local_store_roundtrip() {
+        let root = std::env::temp_dir().join(format!("takzero-storage-{}", std::process::id()));
+        let store = LocalStore::new(&root);
+        store.put("replays/a.txt", b"first").unwrap();
+        store.put("replays/b.txt", b"second").unwrap();
+        store.put("model_latest.ot", b"weights").unwrap();
+        store.put("replays/a.txt", b"replaced").unwrap();
+
+        assert_eq!(store.get("replays/a.txt").unwrap(), b"replaced");
+        assert_eq!(store.list("replays/").unwrap(), vec!["replays/a.txt", "replays/b.txt"]);
+        assert!(store.get("missing").is_err());
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn s3_keys() {
+        let store = S3Store::new("bucket", "/run/1/");
+        assert_eq!(store.url("model_latest.ot"), "s3://bucket/run/1/model_latest.ot");
+        assert_eq!(S3Store::new("bucket", "").url("a"), "s3://bucket/a");
+        assert!(open("s3:///prefix").is_err());
+        assert!(open("s3://bucket").is_ok());
+
+        let listing = "run/1/replays/a b.txt\nrun/1/replays/c.txt\nrun/10/x.txt\n";
+        assert_eq!(store.parse_listing(listing), vec!["replays/a b.txt", "replays/c.txt"]);
+        assert!(store.parse_listing("None\n").is_empty());
+        assert_eq!(S3Store::new("bucket", "").parse_listing("a\n"), vec!["a"]);
+    }
+
+    #[test]
+    fn models_and_shards_are_pulled_once() {
+        let root = std::env::temp_dir().join(format!("takzero-pull-{}", std::process::id()));
+        let (store, directory) = (LocalStore::new(root.join("store")), root.join("local"));
+        std::fs::create_dir_all(&directory).unwrap();
+        store.put("model_latest.ot", b"weights").unwrap();
+        store.put("model_latest.ot.xxh3", b"checksum").unwrap();
+        store.put("model_latest.ot.repr", b"repr").unwrap();
+        store.put("model_latest_steps.txt", b"100").unwrap();
+        assert!(pull_model(&store, &directory).unwrap());
+        assert!(!pull_model(&store, &directory).unwrap());
+        assert!(!directory.join("model_latest.ot.previous").exists());
+        store.put("model_latest.ot.previous", b"older weights").unwrap();
+        store.put("model_latest_steps.txt", b"200").unwrap();
+        assert!(pull_model(&store, &directory).unwrap());
+        assert_eq!(
+            std::fs::read(directory.join("model_latest.ot.previous")).unwrap(),
+            b"older weights"
+        );
+        assert_eq!(std::fs::read(directory.join("model_latest_steps.txt")).unwrap(), b"200");
+
+        push_shard(&store, "replays/a.txt", b"first\n").unwrap();
+        push_shard(&store, "replays/b.txt", b"second\n").unwrap();
+        // A shard which does not match its checksum is skipped.
+        store.put("replays/b.txt", b"changed\n").unwrap();
+        let mut pulled = HashSet::new();
+        let mut contents = Vec::new();
+        for _ in 0..2 {
+            pull_new_shards(&store, "replays", &mut pulled, |data| {
+                contents.extend_from_slice(data);
+                Ok(())
+            })
+            .unwrap();
+        }
+        assert_eq!(contents, b"first\n");
+        assert_eq!(pulled.len(), 2);
+        std::fs::remove_dir_all(root).unwrap();
+    }
+}
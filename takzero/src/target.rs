@@ -1,17 +1,14 @@
 use std::{
-    collections::VecDeque,
+    collections::{HashMap, VecDeque},
     fmt,
-    fs::OpenOptions,
-    io::{BufRead, BufReader},
-    num::ParseFloatError,
+    num::{ParseFloatError, ParseIntError},
     path::Path,
     str::FromStr,
 };
 
 use fast_tak::{
-    takparse::{GameResult, ParseMoveError, ParsePtnError, ParseTpsError, Ptn, Tps},
+    takparse::{GameResult, Move, ParseMoveError, ParseTpsError, Tps},
     Game,
-    PlayError,
     Reserves,
     Symmetry,
 };
@@ -19,7 +16,16 @@ use ordered_float::{FloatIsNan, NotNan};
 use rand::prelude::*;
 use thiserror::Error;
 
-use crate::search::{env::Environment, node::Node};
+use crate::{
+    checksum,
+    header::{read_header, FileKind},
+    ptn::{one_hot, parse_half_komi, Outcome},
+    reader::{self, Categorize, ErrorCategory, LineReader},
+    search::{
+        env::{ActionIndex, Environment, IllegalMove, Terminal},
+        node::Node,
+    },
+};
 
 #[derive(Debug, PartialEq)]
 pub struct Target<E: Environment> {
@@ -27,6 +33,15 @@ pub struct Target<E: Environment> {
     pub policy: Box<[(E::Action, NotNan<f32>)]>, // \pi'(s_t)
     pub value: f32,                              // discounted N-step value
     pub ube: f32,                                // sum of RND + discounted N-step UBE
+    /// Weight of the target in the loss, 1 if not given. Used to weigh
+    /// merged duplicates, importance sampling, or imported human games.
+    pub weight: Option<f32>,
+    /// How much of the move limit was used up in the position, if the game
+    /// was played under one (see
+    /// [`Variant::move_limit_progress`](crate::variant::Variant::move_limit_progress)).
+    pub move_limit: Option<f32>,
+    /// Steps of the model whose search produced the target, if known.
+    pub model_steps: Option<usize>,
 }
 
 pub trait Augment {
@@ -39,11 +54,28 @@ where
     Reserves<N>: Default,
 {
     fn augment(&self, rng: &mut impl Rng) -> Self {
-        let index = rng.gen_range(0..8);
+        self.symmetry(rng.gen_range(0..8))
+    }
+}
+
+impl<const N: usize, const HALF_KOMI: i8> Target<Game<N, HALF_KOMI>>
+where
+    Reserves<N>: Default,
+{
+    /// The target under one of the 8 symmetries of the board.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the index is not below 8.
+    #[must_use]
+    pub fn symmetry(&self, index: usize) -> Self {
         Self {
             env: self.env.symmetries().into_iter().nth(index).unwrap(),
             value: self.value,
             ube: self.ube,
+            weight: self.weight,
+            move_limit: self.move_limit,
+            model_steps: self.model_steps,
             policy: self
                 .policy
                 .iter()
@@ -51,6 +83,73 @@ where
                 .collect(),
         }
     }
+
+    /// The target under all 8 symmetries of the board, starting with itself.
+    /// Symmetric positions appear more than once.
+    #[must_use]
+    pub fn symmetries(&self) -> Vec<Self> {
+        (0..8).map(|index| self.symmetry(index)).collect()
+    }
+
+    /// The symmetry of the target whose position is canonical (see
+    /// [`Environment::canonical`]), so that all symmetric copies of a
+    /// position map to the same target.
+    #[must_use]
+    pub fn canonical(&self) -> Self {
+        let (_, symmetry) = Environment::canonical(&self.env);
+        self.symmetry(symmetry)
+    }
+}
+
+/// Canonicalize the targets (see [`Target::canonical`]) and merge targets
+/// for the same position into one.
+///
+/// The merged target has the weighted average value, UBE, and policy, and
+/// the sum of the weights as its weight. Targets are returned in the order
+/// their positions first appear.
+#[must_use]
+pub fn dedup_targets<const N: usize, const HALF_KOMI: i8>(
+    targets: impl IntoIterator<Item = Target<Game<N, HALF_KOMI>>>,
+) -> Vec<Target<Game<N, HALF_KOMI>>>
+where
+    Reserves<N>: Default,
+{
+    let mut index = HashMap::new();
+    let mut merged: Vec<(Target<Game<N, HALF_KOMI>>, f32)> = Vec::new();
+    for target in targets {
+        let mut target = target.canonical();
+        let weight = target.weight.unwrap_or(1.0);
+        let key = Tps::from(target.env.clone()).to_string();
+        let Some(&i) = index.get(&key) else {
+            index.insert(key, merged.len());
+            target.value *= weight;
+            target.ube *= weight;
+            target.policy.iter_mut().for_each(|(_, p)| *p *= weight);
+            merged.push((target, weight));
+            continue;
+        };
+        let (sum, total) = &mut merged[i];
+        *total += weight;
+        sum.weight = Some(*total);
+        sum.value += weight * target.value;
+        sum.ube += weight * target.ube;
+        for (action, p) in &*target.policy {
+            if let Some((_, q)) = sum.policy.iter_mut().find(|(a, _)| a == action) {
+                *q += *p * weight;
+            }
+        }
+    }
+    merged
+        .into_iter()
+        .map(|(mut target, total)| {
+            if total > 0.0 {
+                target.value /= total;
+                target.ube /= total;
+                target.policy.iter_mut().for_each(|(_, p)| *p /= total);
+            }
+            target
+        })
+        .collect()
 }
 
 impl<const N: usize, const HALF_KOMI: i8> fmt::Display for Target<Game<N, HALF_KOMI>>
@@ -68,7 +167,23 @@ where
             .collect::<Vec<_>>()
             .join(",");
 
-        writeln!(f, "{tps};{value};{ube};{policy}")
+        write!(f, "{tps};{value};{ube};{policy}")?;
+        if self.weight.is_some() || self.move_limit.is_some() || self.model_steps.is_some() {
+            write!(f, ";")?;
+        }
+        if let Some(weight) = self.weight {
+            write!(f, "{weight}")?;
+        }
+        if self.move_limit.is_some() || self.model_steps.is_some() {
+            write!(f, ";")?;
+        }
+        if let Some(move_limit) = self.move_limit {
+            write!(f, "{move_limit}")?;
+        }
+        if let Some(model_steps) = self.model_steps {
+            write!(f, ";{model_steps}")?;
+        }
+        writeln!(f)
     }
 }
 
@@ -91,11 +206,29 @@ pub enum ParseTargetError {
     #[error("{0}")]
     Float(#[from] ParseFloatError),
     #[error("{0}")]
+    Int(#[from] ParseIntError),
+    #[error("{0}")]
     PolicyNan(#[from] FloatIsNan),
     #[error("the policy does not contain the right actions")]
     PolicyWrongActions,
 }
 
+impl Categorize for ParseTargetError {
+    fn category(&self) -> ErrorCategory {
+        match self {
+            Self::MissingTps | Self::Tps(_) => ErrorCategory::Tps,
+            Self::MissingValue | Self::MissingUbe | Self::Float(_) | Self::Int(_) => {
+                ErrorCategory::Number
+            }
+            Self::MissingPolicy
+            | Self::WrongPolicyFormat
+            | Self::PolicyNan(_)
+            | Self::PolicyWrongActions => ErrorCategory::Policy,
+            Self::Action(_) => ErrorCategory::Action,
+        }
+    }
+}
+
 impl<const N: usize, const HALF_KOMI: i8> FromStr for Target<Game<N, HALF_KOMI>>
 where
     Reserves<N>: Default,
@@ -103,7 +236,7 @@ where
     type Err = ParseTargetError;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        //{tps};{value};{ube};{policy}
+        //{tps};{value};{ube};{policy}[;[{weight}][;[{move_limit}][;{model_steps}]]]
         let mut iter = s.trim().split(';');
         let tps: Tps = iter.next().ok_or(ParseTargetError::MissingTps)?.parse()?;
         let value = iter.next().ok_or(ParseTargetError::MissingValue)?.parse()?;
@@ -118,6 +251,10 @@ where
                     .and_then(|(a, p)| Ok((a.parse()?, NotNan::new(p.parse()?)?)))
             })
             .collect::<Result<_, _>>()?;
+        let mut optional = || iter.next().filter(|s| !s.is_empty());
+        let weight = optional().map(str::parse).transpose()?;
+        let move_limit = optional().map(str::parse).transpose()?;
+        let model_steps = optional().map(str::parse).transpose()?;
         let env: Game<N, HALF_KOMI> = tps.into();
 
         // Check that all actions that should be in the policy are in the policy,
@@ -138,6 +275,9 @@ where
             policy,
             value,
             ube,
+            weight,
+            move_limit,
+            model_steps,
         })
     }
 }
@@ -148,25 +288,105 @@ where
 ///
 /// Panics if the target policy for any move is NaN.
 #[must_use]
-pub fn policy_target_from_proportional_visits<E: Environment>(
+pub fn policy_target_from_proportional_visits<E: ActionIndex>(
     node: &Node<E>,
 ) -> Box<[(E::Action, NotNan<f32>)]> {
-    node.children
+    node.children()
         .iter()
         .map(|(action, child)| {
             (
-                action.clone(),
-                NotNan::new(child.visit_count as f32 / node.visit_count as f32)
+                action,
+                NotNan::new(child.visit_count() as f32 / node.visit_count() as f32)
                     .expect("target policy should not be NaN"),
             )
         })
         .collect()
 }
 
+/// The opinion of the search at the root before a move of a replay.
+#[derive(Debug, PartialEq, Clone)]
+pub struct RootStats<A> {
+    /// Root value from the perspective of the player to move.
+    pub value: f32,
+    pub ube: f32,
+    /// Visit counts of the most visited actions, most visited first.
+    pub visits: Vec<(A, u32)>,
+}
+
+impl<A: Clone> RootStats<A> {
+    /// Statistics of a searched root, keeping the `top` most visited actions.
+    #[must_use]
+    pub fn from_node<E: ActionIndex<Action = A>>(node: &Node<E>, ube: f32, top: usize) -> Self {
+        let mut visits: Vec<_> = node
+            .children()
+            .iter()
+            .map(|(action, child)| (action, child.visit_count()))
+            .collect();
+        visits.sort_by_key(|(_, visit_count)| std::cmp::Reverse(*visit_count));
+        visits.truncate(top);
+        Self {
+            value: f32::from(node.evaluation()),
+            ube,
+            visits,
+        }
+    }
+}
+
+impl<A: fmt::Display> fmt::Display for RootStats<A> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{{v={},u={}", self.value, self.ube)?;
+        for (action, visit_count) in &self.visits {
+            write!(f, ",{action}={visit_count}")?;
+        }
+        write!(f, "}}")
+    }
+}
+
+impl<A: FromStr> FromStr for RootStats<A> {
+    type Err = ParseReplayError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || ParseReplayError::Stats(s.to_string());
+        let mut fields = s
+            .strip_prefix('{')
+            .and_then(|s| s.strip_suffix('}'))
+            .ok_or_else(invalid)?
+            .split(',')
+            .map(|field| field.split_once('=').ok_or_else(invalid));
+        let mut number = |key: &str| match fields.next() {
+            Some(Ok((k, value))) if k == key => value.parse().map_err(|_| invalid()),
+            _ => Err(invalid()),
+        };
+        let value = number("v")?;
+        let ube = number("u")?;
+        let visits = fields
+            .map(|field| {
+                let (action, visit_count) = field?;
+                Ok((
+                    action.parse().map_err(|_| invalid())?,
+                    visit_count.parse().map_err(|_| invalid())?,
+                ))
+            })
+            .collect::<Result<_, ParseReplayError>>()?;
+        Ok(Self { value, ube, visits })
+    }
+}
+
 #[derive(Debug, PartialEq, Clone)]
 pub struct Replay<E: Environment> {
     pub env: E,
     pub actions: VecDeque<E::Action>,
+    /// Result of a game which ended without reaching a terminal position,
+    /// for example by resignation, from the perspective of the player to
+    /// move in the final position.
+    pub adjudicated: Option<Terminal>,
+    /// Generation of the network which played the game, if known.
+    pub generation: Option<usize>,
+    /// Id of the game in the run which played it, if known.
+    pub game_id: Option<u64>,
+    /// Search statistics before each action, if they were recorded.
+    /// Either empty or as long as `actions`.
+    pub stats: VecDeque<RootStats<E::Action>>,
 }
 
 impl<E: Environment> Replay<E> {
@@ -175,6 +395,10 @@ impl<E: Environment> Replay<E> {
         Self {
             env,
             actions: VecDeque::new(),
+            adjudicated: None,
+            generation: None,
+            game_id: None,
+            stats: VecDeque::new(),
         }
     }
 
@@ -199,17 +423,42 @@ impl<E: Environment> Replay<E> {
     pub fn advance(&mut self, steps: usize) {
         for _ in 0..steps {
             self.env.step(self.actions.pop_front().unwrap());
+            self.stats.pop_front();
         }
     }
 
-    pub fn states(&self) -> impl Iterator<Item = E> + '_ {
+    /// Positions of the replay, each with the action played in it. They are
+    /// computed lazily by stepping one clone of the starting position, and
+    /// the final position is never reached.
+    pub fn positions(&self) -> impl Iterator<Item = (E, &E::Action)> + '_ {
         let mut env = self.env.clone();
-        self.actions.iter().map(move |a| {
-            let ret = env.clone();
-            env.step(a.clone());
-            ret
+        let mut previous: Option<&E::Action> = None;
+        self.actions.iter().map(move |action| {
+            if let Some(previous) = previous.replace(action) {
+                env.step(previous.clone());
+            }
+            (env.clone(), action)
         })
     }
+
+    pub fn states(&self) -> impl Iterator<Item = E> + '_ {
+        self.positions().map(|(env, _)| env)
+    }
+
+    /// Number of plies played before the replay starts.
+    pub fn start_ply(&self) -> u16 {
+        self.env.steps()
+    }
+
+    /// The result of the game from the perspective of the player to move in
+    /// the final position, or `None` if the game is not finished.
+    pub fn outcome(&self) -> Option<Terminal> {
+        let mut env = self.env.clone();
+        for action in &self.actions {
+            env.step(action.clone());
+        }
+        env.terminal().or(self.adjudicated)
+    }
 }
 
 impl<const N: usize, const HALF_KOMI: i8> fmt::Display for Replay<Game<N, HALF_KOMI>>
@@ -217,14 +466,31 @@ where
     Reserves<N>: Default,
 {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "[TPS \"{}\"]", Tps::from(self.env.clone()),)?;
+        write!(
+            f,
+            "[TPS \"{}\"] [Komi \"{}\"]",
+            Tps::from(self.env.clone()),
+            f32::from(HALF_KOMI) / 2.0
+        )?;
+        if let Some(generation) = self.generation {
+            write!(f, " [Generation \"{generation}\"]")?;
+        }
+        if let Some(game_id) = self.game_id {
+            write!(f, " [Game \"{game_id}\"]")?;
+        }
         let mut env = self.env.clone();
-        for action in &self.actions {
+        let with_stats = self.stats.len() == self.actions.len();
+        for (i, action) in self.actions.iter().enumerate() {
             write!(f, " {action}")?;
+            if with_stats {
+                write!(f, " {}", self.stats[i])?;
+            }
             env.step(*action);
         }
         if let Ok(result) = GameResult::try_from(env.result()) {
             writeln!(f, " {result}")
+        } else if let Some(terminal) = self.adjudicated {
+            writeln!(f, " {}", Outcome::from_terminal(terminal, env.to_move).result())
         } else {
             writeln!(f)
         }
@@ -233,16 +499,33 @@ where
 
 #[derive(Error, Debug)]
 pub enum ParseReplayError {
-    #[error("{0}")]
-    Ptn(#[from] ParsePtnError),
+    #[error("invalid tag `{0}`")]
+    Tag(String),
     #[error("missing TPS")]
     MissingTps,
+    #[error("the replay has a komi of {0}")]
+    WrongKomi(String),
     #[error("{0}")]
     Tps(#[from] ParseTpsError),
     #[error("{0}")]
     Action(#[from] ParseMoveError),
-    #[error("invalid action")]
-    Invalid(#[from] PlayError),
+    #[error("illegal action {action}: {reason}")]
+    Illegal { action: Move, reason: IllegalMove },
+    #[error("invalid search statistics `{0}`")]
+    Stats(String),
+    #[error("search statistics are missing for some actions")]
+    MissingStats,
+}
+
+impl Categorize for ParseReplayError {
+    fn category(&self) -> ErrorCategory {
+        match self {
+            Self::Tag(_) | Self::WrongKomi(_) => ErrorCategory::Tag,
+            Self::MissingTps | Self::Tps(_) => ErrorCategory::Tps,
+            Self::Action(_) | Self::Illegal { .. } => ErrorCategory::Action,
+            Self::Stats(_) | Self::MissingStats => ErrorCategory::Stats,
+        }
+    }
 }
 
 impl<const N: usize, const HALF_KOMI: i8> FromStr for Replay<Game<N, HALF_KOMI>>
@@ -252,51 +535,175 @@ where
     type Err = ParseReplayError;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        let ptn: Ptn = s.parse()?;
-        let env: Game<N, HALF_KOMI> = ptn.tps().ok_or(ParseReplayError::MissingTps)?.into();
-
-        let actions: VecDeque<_> = ptn.moves().iter().copied().collect();
+        let mut replay = Self::new(Game::default());
+        let mut tps = None;
+        let mut rest = s.trim();
+        while let Some(tag) = rest.strip_prefix('[') {
+            let invalid = || ParseReplayError::Tag(rest.to_string());
+            let (tag, after) = tag.split_once(']').ok_or_else(invalid)?;
+            let (name, value) = tag.trim().split_once(' ').ok_or_else(invalid)?;
+            let value = value
+                .trim()
+                .strip_prefix('"')
+                .and_then(|value| value.strip_suffix('"'))
+                .ok_or_else(invalid)?;
+            match name {
+                "TPS" => tps = Some(value.parse::<Tps>()?),
+                "Komi" if parse_half_komi(value) != Some(HALF_KOMI) => {
+                    return Err(ParseReplayError::WrongKomi(value.to_string()));
+                }
+                "Generation" => replay.generation = Some(value.parse().map_err(|_| invalid())?),
+                "Game" => replay.game_id = Some(value.parse().map_err(|_| invalid())?),
+                _ => {}
+            }
+            rest = after.trim_start();
+        }
+        replay.env = tps.ok_or(ParseReplayError::MissingTps)?.into();
 
         // Verify that the actions are valid.
-        let mut test_env = env.clone();
-        for &action in &actions {
-            test_env.play(action)?;
+        let mut end = replay.env.clone();
+        let mut result = None;
+        for token in rest.split_whitespace() {
+            if token == "0-0" {
+                continue;
+            }
+            if let Some(outcome) = Outcome::from_result(token) {
+                result = Some(outcome);
+                continue;
+            }
+            // Search statistics follow the action they were for.
+            if token.starts_with('{') {
+                if replay.stats.len() + 1 != replay.actions.len() {
+                    return Err(ParseReplayError::MissingStats);
+                }
+                replay.stats.push_back(token.parse()?);
+                continue;
+            }
+            let action: Move = token.parse()?;
+            end.check(&action).map_err(|reason| ParseReplayError::Illegal { action, reason })?;
+            end.step(action);
+            replay.push(action);
+        }
+        if !replay.stats.is_empty() && replay.stats.len() != replay.actions.len() {
+            return Err(ParseReplayError::MissingStats);
+        }
+        // Results of terminal positions follow from the moves.
+        if end.terminal().is_none() {
+            replay.adjudicated = result.map(|outcome| outcome.terminal(end.to_move));
         }
 
-        Ok(Self { env, actions })
+        Ok(replay)
     }
 }
 
-/// Open a file and parse all the replays (stored one per line).
+/// Value targets for the positions of a replay, from the perspective of the
+/// player to move, with `discount` applied per ply.
+///
+/// Without a `horizon` the value is the discounted outcome of the game.
+/// With a horizon `h`, positions more than `h` plies before the end are
+/// bootstrapped from the value of the position `h` plies later, which
+/// `bootstrap` returns for a batch of positions. Values are returned for the
+/// first positions for which they are known: all of them if the game is
+/// finished, and those at least `h` plies before the end otherwise.
+pub fn n_step_values<E: Environment>(
+    replay: &Replay<E>,
+    horizon: Option<usize>,
+    discount: f32,
+    bootstrap: impl FnOnce(&[E]) -> Vec<f32>,
+) -> Vec<f32> {
+    let mut states: Vec<_> = replay.states().collect();
+    let mut last = replay.env.clone();
+    for action in &replay.actions {
+        last.step(action.clone());
+    }
+    let terminal = last.terminal().or(replay.adjudicated);
+    states.push(last);
+
+    let plies = replay.len();
+    // Positions from here on are close enough to the end to use the outcome.
+    let from_outcome = horizon.map_or(0, |h| plies.saturating_sub(h));
+    // Discount and change of perspective after some plies.
+    let scale = |plies: usize| {
+        let sign = if plies % 2 == 0 { 1.0 } else { -1.0 };
+        sign * discount.powi(i32::try_from(plies).unwrap_or(i32::MAX))
+    };
+
+    let mut values: Vec<f32> = match horizon {
+        Some(h) if from_outcome > 0 => bootstrap(&states[h..h + from_outcome])
+            .into_iter()
+            .map(|value| scale(h) * value)
+            .collect(),
+        _ => Vec::new(),
+    };
+    if let Some(terminal) = terminal {
+        let outcome = f32::from(terminal);
+        values.extend((from_outcome..plies).map(|t| scale(plies - t) * outcome));
+    }
+    values
+}
+
+/// Targets for the first positions of a replay, one for each value (see
+/// [`n_step_values`]), with the policy one-hot on the played action.
+#[must_use]
+pub fn one_hot_targets<E: Environment>(replay: &Replay<E>, values: &[f32]) -> Vec<Target<E>> {
+    let mut actions = Vec::new();
+    replay
+        .positions()
+        .zip(values)
+        .map(|((env, played), value)| Target {
+            policy: one_hot(&env, played, &mut actions),
+            env,
+            value: *value,
+            ube: 0.0,
+            weight: None,
+            move_limit: None,
+            model_steps: replay.generation,
+        })
+        .collect()
+}
+
+/// Open a file and lazily parse the replays (stored one per line), which
+/// may be gzipped. Lines which do not parse are skipped, unless the reader
+/// is made lossy or strict (see [`LineReader`]).
 ///
 /// # Errors
 ///
-/// Returns an error if the file cannot be opened.
+/// Returns an error if the file cannot be opened, if its header is for
+/// another size, komi, or a newer format, or with
+/// [`std::io::ErrorKind::InvalidData`] if it does not match its checksum
+/// (see [`checksum`]).
 pub fn get_replays<const N: usize, const HALF_KOMI: i8>(
     path: impl AsRef<Path>,
-) -> Result<impl Iterator<Item = Replay<Game<N, HALF_KOMI>>>, std::io::Error>
+) -> Result<LineReader<Replay<Game<N, HALF_KOMI>>>, std::io::Error>
 where
     Reserves<N>: Default,
 {
-    Ok(BufReader::new(OpenOptions::new().read(true).open(path)?)
-        .lines()
-        .filter_map(|line| line.ok()?.parse::<Replay<Game<N, HALF_KOMI>>>().ok()))
+    checksum::verify(path.as_ref())?;
+    let mut reader = reader::open(path)?;
+    let header = read_header::<N, HALF_KOMI>(&mut reader, FileKind::Replays)?;
+    Ok(LineReader::new(reader, 1 + usize::from(header.is_some())))
 }
 
-/// Open a file and parse all the targets (stored one per line).
+/// Open a file and lazily parse the targets (stored one per line), which
+/// may be gzipped. Lines which do not parse are skipped, unless the reader
+/// is made lossy or strict (see [`LineReader`]).
 ///
 /// # Errors
 ///
-/// Returns an error if the file cannot be opened.
+/// Returns an error if the file cannot be opened, if its header is for
+/// another size, komi, or a newer format, or with
+/// [`std::io::ErrorKind::InvalidData`] if it does not match its checksum
+/// (see [`checksum`]).
 pub fn get_targets<const N: usize, const HALF_KOMI: i8>(
     path: impl AsRef<Path>,
-) -> Result<impl Iterator<Item = Target<Game<N, HALF_KOMI>>>, std::io::Error>
+) -> Result<LineReader<Target<Game<N, HALF_KOMI>>>, std::io::Error>
 where
     Reserves<N>: Default,
 {
-    Ok(BufReader::new(OpenOptions::new().read(true).open(path)?)
-        .lines()
-        .filter_map(|line| line.ok()?.parse::<Target<Game<N, HALF_KOMI>>>().ok()))
+    checksum::verify(path.as_ref())?;
+    let mut reader = reader::open(path)?;
+    let header = read_header::<N, HALF_KOMI>(&mut reader, FileKind::Targets)?;
+    Ok(LineReader::new(reader, 1 + usize::from(header.is_some())))
 }
 
 #[cfg(test)]
@@ -306,8 +713,8 @@ mod tests {
     use rand::{seq::IteratorRandom, Rng, SeedableRng};
 
     use crate::{
-        search::env::Environment,
-        target::{Replay, Target},
+        search::env::{Environment, Terminal},
+        target::{dedup_targets, n_step_values, ParseReplayError, Replay, Target},
     };
 
     #[test]
@@ -330,6 +737,9 @@ mod tests {
                     .collect(),
                 value: rng.gen(),
                 ube: rng.gen(),
+                weight: rng.gen_bool(0.5).then(|| rng.gen()),
+                move_limit: rng.gen_bool(0.5).then(|| rng.gen()),
+                model_steps: rng.gen_bool(0.5).then(|| rng.gen_range(0..10_000)),
             };
             let string = target.to_string();
             println!("{string}");
@@ -375,4 +785,121 @@ mod tests {
             }
         }
     }
+
+    #[test]
+    fn replay_metadata() {
+        let mut replay: Replay<Game<3, 4>> = "[TPS \"x3/x3/x3 1 1\"] c3 a1".parse().unwrap();
+        assert_eq!(replay.outcome(), None);
+        assert_eq!(replay.start_ply(), 0);
+        let positions: Vec<_> = replay.positions().map(|(env, a)| (env.steps(), *a)).collect();
+        assert_eq!(positions, [(0, "c3".parse().unwrap()), (1, "a1".parse().unwrap())]);
+        replay.generation = Some(1200);
+        replay.game_id = Some(17);
+        // White resigned.
+        replay.adjudicated = Some(Terminal::Loss);
+
+        let string = replay.to_string();
+        assert_eq!(
+            string,
+            "[TPS \"x3/x3/x3 1 1\"] [Komi \"2\"] [Generation \"1200\"] [Game \"17\"] c3 a1 0-1\n"
+        );
+        let recovered: Replay<Game<3, 4>> = string.parse().unwrap();
+        assert_eq!(recovered, replay);
+        assert_eq!(recovered.outcome(), Some(Terminal::Loss));
+        let values = n_step_values(&recovered, None, 0.9, |_| unreachable!());
+        assert_eq!(values.len(), 2);
+        assert!((values[0] + 0.81).abs() < 1e-6);
+        assert!((values[1] - 0.9).abs() < 1e-6);
+
+        assert!(matches!(
+            string.parse::<Replay<Game<3, 0>>>(),
+            Err(ParseReplayError::WrongKomi(_))
+        ));
+        assert!(matches!(
+            "[TPS \"x3/x3/x3 1 1\"] [Game 17] c3".parse::<Replay<Game<3, 4>>>(),
+            Err(ParseReplayError::Tag(_))
+        ));
+
+        let with_stats =
+            "[TPS \"x3/x3/x3 1 1\"] c3 {v=0.5,u=0.25,c3=90,a1=10} a1 {v=-0.5,u=0,b2=3}";
+        let mut replay: Replay<Game<3, 4>> = with_stats.parse().unwrap();
+        assert_eq!(replay.stats[0].visits[1], ("a1".parse().unwrap(), 10));
+        assert!(replay.to_string().ends_with(" a1 {v=-0.5,u=0,b2=3}\n"));
+        replay.advance(1);
+        assert_eq!(replay.stats.len(), 1);
+        assert!(matches!(
+            "[TPS \"x3/x3/x3 1 1\"] c3 {v=0.5,u=0.25} a1".parse::<Replay<Game<3, 4>>>(),
+            Err(ParseReplayError::MissingStats)
+        ));
+        assert!(matches!(
+            "[TPS \"x3/x3/x3 1 1\"] c3 {u=0.25,v=0.5}".parse::<Replay<Game<3, 4>>>(),
+            Err(ParseReplayError::Stats(_))
+        ));
+    }
+
+    #[test]
+    fn n_step_and_symmetries() {
+        let mut replay: Replay<Game<3, 0>> =
+            "[TPS \"x3/x3/x3 1 1\"] c3 a1 b1 b3 c1".parse().unwrap();
+        // White wins with the last move.
+        let values = n_step_values(&replay, None, 0.9, |_| unreachable!());
+        assert_eq!(values.len(), 5);
+        assert!((values[4] - 0.9).abs() < 1e-6);
+        assert!((values[3] + 0.81).abs() < 1e-6);
+
+        let values = n_step_values(&replay, Some(2), 0.9, |positions| {
+            assert_eq!(positions.len(), 3);
+            assert_eq!(positions[0].ply, 2);
+            vec![0.5; positions.len()]
+        });
+        assert_eq!(values.len(), 5);
+        assert!((values[0] - 0.405).abs() < 1e-6);
+        assert!((values[3] + 0.81).abs() < 1e-6);
+
+        // Without the last move the game is not finished.
+        replay.actions.pop_back();
+        assert!(n_step_values(&replay, None, 0.9, |_| unreachable!()).is_empty());
+        let values = n_step_values(&replay, Some(2), 0.9, |positions| vec![0.5; positions.len()]);
+        assert_eq!(values.len(), 2);
+
+        let mut actions = Vec::new();
+        replay.env.populate_actions(&mut actions);
+        let target = Target {
+            env: replay.env.clone(),
+            policy: actions.into_iter().map(|a| (a, NotNan::default())).collect(),
+            value: 0.5,
+            ube: 0.0,
+            weight: None,
+            move_limit: None,
+            model_steps: None,
+        };
+        let symmetries = target.symmetries();
+        assert_eq!(symmetries.len(), 8);
+        assert_eq!(symmetries[0], target);
+
+        // All symmetric copies of an opening are merged into one target.
+        let mut env = replay.env.clone();
+        env.step(replay.actions[0]);
+        let mut actions = Vec::new();
+        env.populate_actions(&mut actions);
+        let target = Target {
+            env,
+            policy: actions.into_iter().map(|a| (a, NotNan::default())).collect(),
+            value: 0.5,
+            ube: 0.0,
+            weight: None,
+            move_limit: None,
+            model_steps: None,
+        };
+        let canonical = target.canonical();
+        let mut copies = target.symmetries();
+        assert!(copies.iter().all(|copy| copy.canonical().env == canonical.env));
+        copies[1].value = 1.0;
+        copies[1].weight = Some(2.0);
+        let merged = dedup_targets(copies);
+        assert_eq!(merged.len(), 1);
+        assert!((merged[0].value - (3.5 + 2.0) / 9.0).abs() < 1e-6);
+        assert_eq!(merged[0].weight, Some(9.0));
+        assert_eq!(merged[0].env, canonical.env);
+    }
 }
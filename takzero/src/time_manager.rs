@@ -0,0 +1,195 @@
+//! Clock-based time management for match play.
+//!
+//! Converts the remaining clock time and increment into a budget for a
+//! single move. The search may stop at the soft limit, which is extended
+//! while the best move keeps changing, and must stop at the hard limit.
+//! Forced moves (a single legal move or a solved root) are played at once.
+
+use std::{
+    num::ParseFloatError,
+    str::FromStr,
+    time::{Duration, Instant},
+};
+
+use thiserror::Error;
+
+/// Time control of a game, like `300+5` (in seconds).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TimeControl {
+    pub initial: Duration,
+    pub increment: Duration,
+}
+
+#[derive(Error, Debug)]
+pub enum ParseTimeControlError {
+    #[error("time control `{0}` is not in the format `initial+increment`")]
+    Format(String),
+    #[error("{0}")]
+    Float(#[from] ParseFloatError),
+}
+
+impl FromStr for TimeControl {
+    type Err = ParseTimeControlError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (initial, increment) = s.split_once('+').unwrap_or((s, "0"));
+        let seconds = |x: &str| -> Result<Duration, Self::Err> {
+            let x: f64 = x.trim().parse()?;
+            Duration::try_from_secs_f64(x)
+                .map_err(|_| ParseTimeControlError::Format(s.to_string()))
+        };
+        Ok(Self {
+            initial: seconds(initial)?,
+            increment: seconds(increment)?,
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct TimeManager {
+    /// Fraction of the remaining time to use for a normal move.
+    pub time_fraction: f64,
+    /// Fraction of the increment to use for a normal move.
+    pub increment_fraction: f64,
+    /// How much the soft limit grows every time the best move changes.
+    pub extension_per_change: f64,
+    /// The soft limit is never extended past this multiple.
+    pub max_extension: f64,
+    /// Time kept in reserve for communication and overhead.
+    pub overhead: Duration,
+}
+
+impl Default for TimeManager {
+    fn default() -> Self {
+        Self {
+            time_fraction: 1.0 / 20.0,
+            increment_fraction: 0.75,
+            extension_per_change: 0.5,
+            max_extension: 3.0,
+            overhead: Duration::from_millis(50),
+        }
+    }
+}
+
+impl TimeManager {
+    /// Start timing a move.
+    #[must_use]
+    pub fn start<A: PartialEq>(
+        &self,
+        remaining: Duration,
+        increment: Duration,
+        forced: bool,
+    ) -> MoveTimer<A> {
+        let available = remaining.saturating_sub(self.overhead);
+        let soft = if forced {
+            Duration::ZERO
+        } else {
+            remaining
+                .mul_f64(self.time_fraction)
+                .saturating_add(increment.mul_f64(self.increment_fraction))
+                .min(available)
+        };
+        MoveTimer {
+            start: Instant::now(),
+            soft,
+            hard: soft.mul_f64(self.max_extension).min(available),
+            extension: 1.0,
+            extension_per_change: self.extension_per_change,
+            max_extension: self.max_extension,
+            best: None,
+        }
+    }
+}
+
+/// Keeps track of the time spent on a single move.
+#[derive(Debug, Clone)]
+pub struct MoveTimer<A> {
+    start: Instant,
+    soft: Duration,
+    hard: Duration,
+    extension: f64,
+    extension_per_change: f64,
+    max_extension: f64,
+    best: Option<A>,
+}
+
+impl<A: PartialEq> MoveTimer<A> {
+    /// Report the current best move. Extends the soft limit if it changed.
+    pub fn observe_best(&mut self, action: A) {
+        if self.best.as_ref().is_some_and(|best| *best != action) {
+            self.extension = (self.extension + self.extension_per_change).min(self.max_extension);
+        }
+        self.best = Some(action);
+    }
+
+    /// Time budget of the move, including extensions so far.
+    #[must_use]
+    pub fn budget(&self) -> Duration {
+        self.soft.mul_f64(self.extension).min(self.hard)
+    }
+
+    #[must_use]
+    pub fn elapsed(&self) -> Duration {
+        self.start.elapsed()
+    }
+
+    #[must_use]
+    pub fn should_stop(&self) -> bool {
+        self.should_stop_after(self.elapsed())
+    }
+
+    #[must_use]
+    pub fn should_stop_after(&self, elapsed: Duration) -> bool {
+        elapsed >= self.budget()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{TimeControl, TimeManager};
+
+    fn seconds(duration: Duration) -> f64 {
+        (duration.as_secs_f64() * 1000.0).round() / 1000.0
+    }
+
+    #[test]
+    fn extends_on_unstable_best_move() {
+        let manager = TimeManager::default();
+        let mut timer = manager.start(Duration::from_secs(60), Duration::from_secs(2), false);
+        // 60 / 20 + 2 * 0.75
+        assert!((seconds(timer.budget()) - 4.5).abs() < 1e-9);
+        timer.observe_best("a1");
+        timer.observe_best("a1");
+        assert!(timer.should_stop_after(Duration::from_secs(5)));
+        timer.observe_best("b1");
+        assert!((seconds(timer.budget()) - 6.75).abs() < 1e-9);
+        assert!(!timer.should_stop_after(Duration::from_secs(5)));
+        for action in ["a1", "b1", "a1", "b1", "a1"] {
+            timer.observe_best(action);
+        }
+        assert!((seconds(timer.budget()) - 13.5).abs() < 1e-9);
+        assert!(timer.should_stop_after(Duration::from_secs(14)));
+    }
+
+    #[test]
+    fn forced_moves_and_low_time() {
+        let manager = TimeManager::default();
+        let timer = manager.start::<()>(Duration::from_secs(60), Duration::ZERO, true);
+        assert!(timer.should_stop_after(Duration::ZERO));
+
+        let timer = manager.start::<()>(Duration::from_millis(100), Duration::from_secs(5), false);
+        assert_eq!(timer.budget(), Duration::from_millis(50));
+    }
+
+    #[test]
+    fn parse_time_control() {
+        let time_control: TimeControl = "300+2.5".parse().unwrap();
+        assert_eq!(time_control.initial, Duration::from_secs(300));
+        assert_eq!(time_control.increment, Duration::from_millis(2500));
+        assert_eq!("60".parse::<TimeControl>().unwrap().increment, Duration::ZERO);
+        assert!("a+b".parse::<TimeControl>().is_err());
+        assert!("-1+0".parse::<TimeControl>().is_err());
+    }
+}
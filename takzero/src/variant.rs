@@ -0,0 +1,588 @@
+//! House variants with other reserve counts or carry limits.
+//!
+//! A [`Variant`] gives the stones and capstones each player starts with and
+//! how many pieces a spread may pick up. [`VariantGame`] is an
+//! [`Environment`] which starts with the reserves of its variant and only
+//! offers spreads within its carry limit. The input representation encodes
+//! reserves as a fraction of what the player started with (see
+//! [`starting_reserves`](crate::network::repr::starting_reserves)), so
+//! networks see variant games like standard ones. [`WithVariant`] lets any
+//! agent for a [`Game`] play variant games.
+//!
+//! A variant may also draw games after a number of plies, or of plies
+//! without a placement, so that self-play cannot produce games of thousands
+//! of plies. [`VariantGame::to_input`] encodes how close the game is to that
+//! limit in the last plane of inputs with
+//! [`Planes::Extended`](crate::network::repr::Planes), which is what the
+//! networks see when they play variant games themselves, and targets record
+//! it so that they are trained on the same inputs.
+
+use fast_tak::{
+    takparse::{Move, MoveKind},
+    Game,
+    Reserves,
+};
+use ordered_float::NotNan;
+use rand::Rng;
+use rayon::prelude::*;
+
+use crate::{
+    network::repr::{input_repr, InputRepr},
+    search::{
+        agent::Agent,
+        env::{ActionIndex, Environment, IllegalMove, SymmetryIndex, Terminal, TerminalReason},
+    },
+    target::Replay,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Variant {
+    /// Stones each player starts with.
+    pub stones: u8,
+    /// Capstones each player starts with.
+    pub caps: u8,
+    /// Most pieces a spread may pick up. Limits above the board size have
+    /// no effect.
+    pub carry_limit: usize,
+    /// Plies after which the game is drawn.
+    pub max_plies: Option<u16>,
+    /// Plies in a row without a placement after which the game is drawn.
+    pub max_reversible_plies: Option<u16>,
+}
+
+impl Variant {
+    /// The standard rules for the board size.
+    #[must_use]
+    pub fn standard<const N: usize>() -> Self
+    where
+        Reserves<N>: Default,
+    {
+        let Reserves { stones, caps } = Reserves::<N>::default();
+        Self {
+            stones,
+            caps,
+            carry_limit: N,
+            max_plies: None,
+            max_reversible_plies: None,
+        }
+    }
+
+    /// The starting position of the variant.
+    #[must_use]
+    pub fn start<const N: usize, const HALF_KOMI: i8>(&self) -> Game<N, HALF_KOMI>
+    where
+        Reserves<N>: Default,
+    {
+        let mut game = Game::default();
+        let reserves = Reserves {
+            stones: self.stones,
+            caps: self.caps,
+        };
+        game.white_reserves = reserves;
+        game.black_reserves = reserves;
+        game
+    }
+
+    /// A random opening of the variant, which places the same pieces as
+    /// [`Environment::new_opening`] of a standard game.
+    #[must_use]
+    pub fn opening<const N: usize, const HALF_KOMI: i8>(
+        &self,
+        rng: &mut impl Rng,
+        actions: &mut Vec<Move>,
+    ) -> VariantGame<N, HALF_KOMI>
+    where
+        Reserves<N>: Default,
+    {
+        let mut game = Game::<N, HALF_KOMI>::new_opening(rng, actions);
+        let standard = Reserves::<N>::default();
+        for reserves in [&mut game.white_reserves, &mut game.black_reserves] {
+            reserves.stones = self.stones.saturating_sub(standard.stones - reserves.stones);
+            reserves.caps = self.caps.saturating_sub(standard.caps - reserves.caps);
+        }
+        VariantGame {
+            game,
+            variant: *self,
+            reversible_plies: 0,
+        }
+    }
+
+    /// The replay of a game of the variant, which is stored as a standard
+    /// game. Replays start from an opening, after which every ply so far was
+    /// a placement.
+    #[must_use]
+    pub fn replay<const N: usize, const HALF_KOMI: i8>(
+        &self,
+        replay: Replay<Game<N, HALF_KOMI>>,
+    ) -> Replay<VariantGame<N, HALF_KOMI>>
+    where
+        Reserves<N>: Default,
+    {
+        Replay {
+            env: VariantGame {
+                game: replay.env,
+                variant: *self,
+                reversible_plies: 0,
+            },
+            actions: replay.actions,
+            adjudicated: replay.adjudicated,
+            generation: replay.generation,
+            game_id: replay.game_id,
+            stats: replay.stats,
+        }
+    }
+
+    /// Whether the variant draws games after some plies.
+    #[must_use]
+    pub const fn has_move_limit(&self) -> bool {
+        self.max_plies.is_some() || self.max_reversible_plies.is_some()
+    }
+
+    /// Whether the action is within the carry limit.
+    #[must_use]
+    pub fn allows(&self, action: &Move) -> bool {
+        carried(action) <= self.carry_limit
+    }
+
+    /// How much of the move limit is used up, between 0 and 1, where 1 means
+    /// that the game is drawn. Always 0 without a move limit.
+    #[must_use]
+    pub fn move_limit_progress(&self, plies: u16, reversible_plies: u16) -> f32 {
+        let progress = |count: u16, limit: Option<u16>| {
+            limit.map_or(0.0, |limit| (f32::from(count) / f32::from(limit.max(1))).min(1.0))
+        };
+        progress(plies, self.max_plies).max(progress(reversible_plies, self.max_reversible_plies))
+    }
+}
+
+/// Pieces a move picks up, 0 for placements.
+#[must_use]
+pub fn carried(action: &Move) -> usize {
+    match action.kind() {
+        MoveKind::Place(_) => 0,
+        // The last piece which is carried ends the lowest drop.
+        MoveKind::Spread(_, pattern) => 8 - pattern.mask().trailing_zeros() as usize,
+    }
+}
+
+/// A game under the rules of a variant.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VariantGame<const N: usize, const HALF_KOMI: i8> {
+    pub game: Game<N, HALF_KOMI>,
+    pub variant: Variant,
+    /// Plies in a row without a placement.
+    pub reversible_plies: u16,
+}
+
+impl<const N: usize, const HALF_KOMI: i8> VariantGame<N, HALF_KOMI>
+where
+    Reserves<N>: Default,
+{
+    #[must_use]
+    pub fn new(variant: Variant) -> Self {
+        Self {
+            game: variant.start(),
+            variant,
+            reversible_plies: 0,
+        }
+    }
+
+    /// How much of the move limit of the variant is used up.
+    #[must_use]
+    pub fn move_limit_progress(&self) -> f32 {
+        self.variant.move_limit_progress(self.game.ply, self.reversible_plies)
+    }
+
+    /// The progress towards the move limit for targets, or `None` if the
+    /// variant has no move limit.
+    #[must_use]
+    pub fn move_limit(&self) -> Option<f32> {
+        self.variant.has_move_limit().then(|| self.move_limit_progress())
+    }
+
+    /// Key which is mixed into the hash of the game, so that positions which
+    /// are at different distances from the move limit differ. Without a move
+    /// limit it is 0, and the hash is the one of the game.
+    fn move_limit_key(&self) -> u64 {
+        let plies = self.variant.max_plies.map_or(0, |_| self.game.ply);
+        let reversible_plies = self
+            .variant
+            .max_reversible_plies
+            .map_or(0, |_| self.reversible_plies);
+        ((u64::from(plies) << 16) | u64::from(reversible_plies)).wrapping_mul(0x9e37_79b9_7f4a_7c15)
+    }
+
+    /// The network input of the game, with the progress towards the move
+    /// limit if the encoding of this process has a plane for it.
+    #[must_use]
+    pub fn to_input(&self) -> Vec<f32> {
+        self.to_input_with(input_repr())
+    }
+
+    /// The network input of the game under an encoding.
+    #[must_use]
+    pub fn to_input_with(&self, repr: InputRepr) -> Vec<f32> {
+        let mut input = vec![0.0; repr.channels::<N>() * N * N];
+        self.encode(repr, &mut input);
+        input
+    }
+
+    /// Write the input of the game into a buffer of the right size, which is
+    /// filled with zeroes.
+    pub fn encode(&self, repr: InputRepr, buffer: &mut [f32]) {
+        repr.encode(buffer, &self.game);
+        repr.encode_move_limit::<N>(buffer, self.move_limit_progress());
+    }
+}
+
+/// Encode a batch of variant games as one flat network input, in parallel.
+#[must_use]
+pub fn variant_games_to_input<const N: usize, const HALF_KOMI: i8>(
+    games: &[VariantGame<N, HALF_KOMI>],
+) -> Vec<f32>
+where
+    Reserves<N>: Default,
+{
+    let repr = input_repr();
+    let size = repr.channels::<N>() * N * N;
+    let mut buffer = vec![0.0; games.len() * size];
+    buffer
+        .par_chunks_mut(size)
+        .zip(games)
+        .for_each(|(buffer, game)| game.encode(repr, buffer));
+    buffer
+}
+
+/// Game replays do not know the move limit, so games which it drew are
+/// recorded as adjudicated draws.
+impl<const N: usize, const HALF_KOMI: i8> From<Replay<VariantGame<N, HALF_KOMI>>>
+    for Replay<Game<N, HALF_KOMI>>
+where
+    Reserves<N>: Default,
+{
+    fn from(replay: Replay<VariantGame<N, HALF_KOMI>>) -> Self {
+        let mut last = replay.env.clone();
+        for action in &replay.actions {
+            last.step(*action);
+        }
+        let adjudicated = replay.adjudicated.or_else(|| {
+            (last.terminal_reason() == Some(TerminalReason::MoveLimit)).then_some(Terminal::Draw)
+        });
+        Self {
+            env: replay.env.game,
+            actions: replay.actions,
+            adjudicated,
+            generation: replay.generation,
+            game_id: replay.game_id,
+            stats: replay.stats,
+        }
+    }
+}
+
+impl<const N: usize, const HALF_KOMI: i8> Default for VariantGame<N, HALF_KOMI>
+where
+    Reserves<N>: Default,
+{
+    fn default() -> Self {
+        Self::new(Variant::standard::<N>())
+    }
+}
+
+impl<const N: usize, const HALF_KOMI: i8> Environment for VariantGame<N, HALF_KOMI>
+where
+    Reserves<N>: Default,
+{
+    type Action = Move;
+
+    fn populate_actions(&self, actions: &mut Vec<Move>) {
+        self.game.populate_actions(actions);
+        actions.retain(|action| self.variant.allows(action));
+    }
+
+    fn step(&mut self, action: Move) {
+        debug_assert!(self.variant.allows(&action));
+        self.reversible_plies = match action.kind() {
+            MoveKind::Place(_) => 0,
+            MoveKind::Spread(..) => self.reversible_plies.saturating_add(1),
+        };
+        self.game.step(action);
+    }
+
+    fn check(&self, action: &Move) -> Result<(), IllegalMove> {
+        if self.terminal().is_some() {
+            return Err(IllegalMove::GameOver);
+        }
+        if !self.variant.allows(action) {
+            return Err(IllegalMove::CarryLimit {
+                carried: carried(action),
+                limit: self.variant.carry_limit,
+            });
+        }
+        self.game.check(action)
+    }
+
+    fn terminal(&self) -> Option<Terminal> {
+        self.game
+            .terminal()
+            .or_else(|| (self.move_limit_progress() >= 1.0).then_some(Terminal::Draw))
+    }
+
+    fn terminal_reason(&self) -> Option<TerminalReason> {
+        self.game
+            .terminal_reason()
+            .or_else(|| (self.move_limit_progress() >= 1.0).then_some(TerminalReason::MoveLimit))
+    }
+
+    fn steps(&self) -> u16 {
+        self.game.steps()
+    }
+
+    fn heuristic_value(&self) -> f32 {
+        self.game.heuristic_value()
+    }
+
+    fn opening_swap(&self) -> bool {
+        self.game.opening_swap()
+    }
+
+    fn hash(&self) -> u64 {
+        self.game.hash() ^ self.move_limit_key()
+    }
+
+    fn hash_after(&self, hash: u64, action: &Move, next: &Self) -> u64 {
+        self.game
+            .hash_after(hash ^ self.move_limit_key(), action, &next.game)
+            ^ next.move_limit_key()
+    }
+
+    fn canonical(&self) -> (Self, SymmetryIndex) {
+        let (game, symmetry) = Environment::canonical(&self.game);
+        (
+            Self {
+                game,
+                variant: self.variant,
+                reversible_plies: self.reversible_plies,
+            },
+            symmetry,
+        )
+    }
+
+    fn symmetric_copies(&self) -> Vec<Self> {
+        Environment::symmetric_copies(&self.game)
+            .into_iter()
+            .map(|game| Self {
+                game,
+                variant: self.variant,
+                reversible_plies: self.reversible_plies,
+            })
+            .collect()
+    }
+
+    fn transform_action(&self, action: &Move, symmetry: SymmetryIndex) -> Move {
+        self.game.transform_action(action, symmetry)
+    }
+
+    fn restore_action(&self, action: &Move, symmetry: SymmetryIndex) -> Move {
+        self.game.restore_action(action, symmetry)
+    }
+
+    /// An opening under the standard rules, see [`Variant::opening`] for
+    /// other variants.
+    fn new_opening(rng: &mut impl Rng, actions: &mut Vec<Move>) -> Self {
+        Self {
+            game: Game::new_opening(rng, actions),
+            variant: Variant::standard::<N>(),
+            reversible_plies: 0,
+        }
+    }
+
+    /// An opening under the same variant.
+    fn next_opening(&self, rng: &mut impl Rng, actions: &mut Vec<Move>) -> Self {
+        self.variant.opening(rng, actions)
+    }
+
+    /// An opening under the standard rules.
+    fn new_opening_with_random_steps(
+        rng: &mut impl Rng,
+        actions: &mut Vec<Move>,
+        steps: usize,
+    ) -> Self {
+        Self {
+            game: Game::new_opening_with_random_steps(rng, actions, steps),
+            variant: Variant::standard::<N>(),
+            reversible_plies: 0,
+        }
+    }
+}
+
+impl<const N: usize, const HALF_KOMI: i8> ActionIndex for VariantGame<N, HALF_KOMI>
+where
+    Reserves<N>: Default,
+{
+    const ACTIONS: usize = Game::<N, HALF_KOMI>::ACTIONS;
+
+    fn action_index(action: &Move) -> usize {
+        Game::<N, HALF_KOMI>::action_index(action)
+    }
+
+    fn index_action(index: usize) -> Option<Move> {
+        Game::<N, HALF_KOMI>::index_action(index)
+    }
+}
+
+/// An agent for [`Game`] which plays [`VariantGame`]s. Its policy is only
+/// asked about the actions which the variant allows. It sees the game
+/// without the move limit.
+pub struct WithVariant<A>(pub A);
+
+impl<A, const N: usize, const HALF_KOMI: i8> Agent<VariantGame<N, HALF_KOMI>> for WithVariant<A>
+where
+    A: Agent<Game<N, HALF_KOMI>>,
+    Reserves<N>: Default,
+{
+    fn policy_value_uncertainty(
+        &self,
+        env_batch: &[VariantGame<N, HALF_KOMI>],
+        actions_batch: &[Vec<Move>],
+    ) -> impl Iterator<Item = (Vec<(Move, NotNan<f32>)>, f32, f32)> {
+        let games: Vec<_> = env_batch.iter().map(|env| env.game.clone()).collect();
+        self.0
+            .policy_value_uncertainty(&games, actions_batch)
+            .collect::<Vec<_>>()
+            .into_iter()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use fast_tak::{takparse::Move, Game};
+
+    use rand::{rngs::StdRng, SeedableRng};
+
+    use super::{carried, Variant, VariantGame, WithVariant};
+    use crate::{
+        network::repr::{game_to_input, starting_reserves, InputRepr, Planes},
+        search::{
+            agent::{simple::Simple, Agent},
+            env::{Environment, Terminal, TerminalReason},
+        },
+        target::Replay,
+    };
+
+    #[test]
+    fn variant_rules() {
+        for (ptn, pieces) in [("c3", 0), ("a1+", 1), ("3c3<21", 3), ("5a1>122", 5)] {
+            assert_eq!(carried(&ptn.parse::<Move>().unwrap()), pieces, "{ptn}");
+        }
+
+        let variant = Variant {
+            stones: 12,
+            caps: 1,
+            carry_limit: 2,
+            max_plies: None,
+            max_reversible_plies: None,
+        };
+        let mut env = VariantGame::<5, 0>::new(variant);
+        assert_eq!(env.game.white_reserves.stones, 12);
+        let start = game_to_input(&env.game);
+        let mut actions = Vec::new();
+        for ptn in ["a1", "e5", "b1", "b2", "b1<", "a2", "e4", "a2-", "e3"] {
+            env.step(ptn.parse().unwrap());
+        }
+        assert_eq!(starting_reserves(&env.game).0.stones, 12);
+        env.populate_actions(&mut actions);
+        assert!(!actions.is_empty());
+        assert!(actions.iter().all(|action| carried(action) <= 2));
+        let mut standard = Vec::new();
+        env.game.populate_actions(&mut standard);
+        assert!(standard.iter().any(|action| carried(action) == 3));
+
+        // Reserves are encoded as a fraction of the variant's start.
+        assert_eq!(start, game_to_input(&Game::<5, 0>::default()));
+        assert_eq!(VariantGame::<5, 0>::default().variant, Variant::standard::<5>());
+
+        let (policy, ..) = WithVariant(Simple)
+            .policy_value_uncertainty(&[env], &[actions.clone()])
+            .next()
+            .unwrap();
+        assert_eq!(policy.len(), actions.len());
+    }
+
+    #[test]
+    fn move_limit() {
+        let variant = Variant {
+            max_plies: Some(20),
+            max_reversible_plies: Some(4),
+            ..Variant::standard::<5>()
+        };
+        let mut env = VariantGame::<5, 4>::new(variant);
+        for ptn in ["a1", "e5", "b1", "d5", "b1<", "d5>", "a1>", "e5<"] {
+            assert_eq!(env.terminal(), None);
+            env.step(ptn.parse().unwrap());
+        }
+        assert_eq!(env.reversible_plies, 4);
+        assert_eq!(env.terminal(), Some(Terminal::Draw));
+        assert_eq!(env.terminal_reason(), Some(TerminalReason::MoveLimit));
+
+        // The progress towards the limit is visible in the input.
+        let mut env = VariantGame::<5, 4>::new(variant);
+        for ptn in ["a1", "e5", "b1", "d5", "b1<"] {
+            env.step(ptn.parse().unwrap());
+        }
+        assert!((env.move_limit_progress() - 0.25).abs() < 1e-6);
+        let repr = InputRepr {
+            planes: Planes::Extended,
+            ..InputRepr::default()
+        };
+        let input = env.to_input_with(repr);
+        assert_eq!(input.len(), repr.channels::<5>() * 25);
+        let channel = 25 * repr.move_limit_channel::<5>().unwrap();
+        assert!(input[channel..channel + 25].iter().all(|x| (x - 0.25).abs() < 1e-6));
+        let base = game_to_input(&env.game);
+        assert_eq!(input[..base.len()], base);
+        assert_eq!(env.to_input_with(InputRepr::default()), base);
+
+        // Without a limit, games go on.
+        assert!(VariantGame::<5, 4>::default().move_limit_progress().abs() < 1e-6);
+        assert_eq!(VariantGame::<5, 4>::default().move_limit(), None);
+    }
+
+    #[test]
+    fn move_limit_in_hash_and_replays() {
+        let variant = Variant {
+            stones: 15,
+            max_reversible_plies: Some(2),
+            ..Variant::standard::<5>()
+        };
+        let mut rng = StdRng::seed_from_u64(0);
+        let opening: VariantGame<5, 4> = variant.opening(&mut rng, &mut Vec::new());
+        assert_eq!(opening.game.ply, 2);
+        assert_eq!(opening.game.white_reserves.stones, 14);
+        let next = opening.next_opening(&mut rng, &mut Vec::new());
+        assert_eq!(next.variant, variant);
+
+        // Positions which differ in the plies without a placement differ in
+        // their hash, which is updated incrementally like the one of games.
+        let mut replay = Replay::new(opening.clone());
+        let mut env = opening;
+        let mut actions = Vec::new();
+        for _ in 0..2 {
+            env.populate_actions(&mut actions);
+            let action = actions.drain(..).find(|action| carried(action) > 0).unwrap();
+            let before = env.clone();
+            env.step(action);
+            assert_eq!(before.hash_after(before.hash(), &action, &env), env.hash());
+            replay.push(action);
+        }
+        let mut placed_last = env.clone();
+        placed_last.reversible_plies = 0;
+        assert_ne!(placed_last.hash(), env.hash());
+
+        // The move limit drew the game, which standard games only know from
+        // the replay.
+        assert_eq!(env.terminal(), Some(Terminal::Draw));
+        let standard = Replay::<Game<5, 4>>::from(replay.clone());
+        assert_eq!(standard.adjudicated, Some(Terminal::Draw));
+        assert_eq!(variant.replay(standard).env, replay.env);
+    }
+}
@@ -0,0 +1,83 @@
+//! Conversions between the value of the network, the expected score, and
+//! an approximate Elo difference.
+//!
+//! A value is the expected outcome between -1 (loss) and 1 (win), where a
+//! draw is worth 0, so it maps linearly to the expected score between 0 and 1.
+//! Elo differences use the logistic curve, where a score of 0.64 is about 100
+//! Elo.
+
+/// Scores are clamped to this distance from 0 and 1 before converting them
+/// to Elo, so that certain results do not give infinities.
+const SCORE_MARGIN: f64 = 0.01;
+
+/// Expected score (wins + draws / 2) for a value.
+#[must_use]
+pub fn expected_score(value: f32) -> f32 {
+    (value.clamp(-1.0, 1.0) + 1.0) / 2.0
+}
+
+/// Value for an expected score.
+#[must_use]
+pub fn value_from_score(score: f32) -> f32 {
+    score.clamp(0.0, 1.0).mul_add(2.0, -1.0)
+}
+
+/// Estimate the Elo difference from a score (wins + draws / 2 over games).
+/// The score is clamped so that sweeps do not produce infinities.
+#[must_use]
+pub fn elo_from_score(score: f64) -> f64 {
+    let score = score.clamp(SCORE_MARGIN, 1.0 - SCORE_MARGIN);
+    400.0 * (score / (1.0 - score)).log10()
+}
+
+/// Expected score against an opponent who is this much weaker in Elo.
+#[must_use]
+pub fn score_from_elo(elo: f64) -> f64 {
+    1.0 / (1.0 + 10f64.powf(-elo / 400.0))
+}
+
+/// Approximate Elo advantage of the player to move for a value.
+#[must_use]
+pub fn elo_from_value(value: f32) -> f64 {
+    elo_from_score(f64::from(expected_score(value)))
+}
+
+/// Like [`elo_from_value`], but relative to `baseline`, the value of the
+/// starting position for the same player.
+///
+/// Komi is meant to make the start even, so this removes what is left of
+/// the first-move advantage (or what komi overcorrects) for the komi the
+/// game is played with.
+#[must_use]
+pub fn komi_adjusted_elo(value: f32, baseline: f32) -> f64 {
+    elo_from_value(value) - elo_from_value(baseline)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        elo_from_score,
+        elo_from_value,
+        expected_score,
+        komi_adjusted_elo,
+        score_from_elo,
+        value_from_score,
+    };
+
+    #[test]
+    fn conversions_agree() {
+        for value in [-1.0, -0.4, 0.0, 0.3, 1.0] {
+            assert!((value_from_score(expected_score(value)) - value).abs() < 1e-6);
+        }
+        assert!((expected_score(0.5) - 0.75).abs() < 1e-6);
+        assert!(elo_from_value(0.0).abs() < 1e-9);
+        assert!(elo_from_value(1.0).is_finite());
+        assert!(elo_from_value(0.28) > 95.0 && elo_from_value(0.28) < 105.0);
+        for elo in [-300.0, 0.0, 150.0] {
+            assert!((elo_from_score(score_from_elo(elo)) - elo).abs() < 1e-6);
+        }
+        assert!(elo_from_value(-0.2) < 0.0);
+        assert!(komi_adjusted_elo(0.1, 0.1).abs() < 1e-9);
+        assert!(komi_adjusted_elo(0.1, 0.2) < 0.0);
+    }
+}
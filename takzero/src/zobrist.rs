@@ -0,0 +1,183 @@
+//! Zobrist keys of positions.
+//!
+//! The key is the XOR of one pseudo-random number per feature of the position
+//! (a stone of some color at some height of some square, a wall or capstone
+//! on top of a square, and black to move). The numbers are derived from the
+//! feature itself instead of a table, so keys are stable between runs and
+//! builds and can be stored, for example in the game archive.
+//!
+//! After a move only the squares it touched and the player to move change,
+//! so [`zobrist_after`] updates a key instead of hashing the whole board.
+
+use fast_tak::{
+    takparse::{Color, Direction, Move, MoveKind, Piece},
+    Game,
+    Reserves,
+};
+
+/// Feature which is set when black is to move.
+const BLACK_TO_MOVE: u64 = u64::MAX;
+
+/// Mix a feature into a pseudo-random number (`SplitMix64`).
+pub(crate) const fn mix(feature: u64) -> u64 {
+    let mut z = feature.wrapping_add(0x9E37_79B9_7F4A_7C15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    z ^ (z >> 31)
+}
+
+/// Number identifying a feature of a square.
+/// `kind` is the color of a stone at `height`, or the kind of the top piece.
+const fn feature(square: usize, height: usize, kind: u64) -> u64 {
+    ((square as u64) << 32) | ((height as u64) << 8) | kind
+}
+
+/// XOR of the features of the squares in the set, where square `N * y + x`
+/// is bit `N * y + x` of `squares`.
+fn board_key<const N: usize, const HALF_KOMI: i8>(game: &Game<N, HALF_KOMI>, squares: u64) -> u64
+where
+    Reserves<N>: Default,
+{
+    let mut key = 0;
+    for (y, row) in game.board.iter().enumerate() {
+        for (x, stack) in row.enumerate() {
+            let square = N * y + x;
+            if squares & (1 << square) == 0 {
+                continue;
+            }
+            match stack.top() {
+                Some((Piece::Wall, _)) => key ^= mix(feature(square, 0, 2)),
+                Some((Piece::Cap, _)) => key ^= mix(feature(square, 0, 3)),
+                Some((Piece::Flat, _)) => {}
+                None => continue,
+            }
+            for (height, color) in stack.colors().into_iter().enumerate() {
+                key ^= mix(feature(square, height, u64::from(color == Color::Black)));
+            }
+        }
+    }
+    key
+}
+
+/// Zobrist key of the position, including the player to move.
+/// Reserves are not part of the key since they follow from the board.
+#[must_use]
+pub fn zobrist<const N: usize, const HALF_KOMI: i8>(game: &Game<N, HALF_KOMI>) -> u64
+where
+    Reserves<N>: Default,
+{
+    let mut key = board_key(game, u64::MAX);
+    if game.to_move == Color::Black {
+        key ^= mix(BLACK_TO_MOVE);
+    }
+    key
+}
+
+/// Squares a move changes: where it is placed, or where a spread starts
+/// and every square it drops pieces on.
+pub(crate) fn touched_squares<const N: usize>(action: Move) -> u64 {
+    let square = action.square();
+    let (mut y, mut x) = (usize::from(square.row()), usize::from(square.column()));
+    let mut squares: u64 = 1 << (N * y + x);
+    if let MoveKind::Spread(direction, pattern) = action.kind() {
+        // Every drop is on the next square.
+        for _ in 0..pattern.mask().count_ones() {
+            match direction {
+                Direction::Up => y += 1,
+                Direction::Down => y -= 1,
+                Direction::Right => x += 1,
+                Direction::Left => x -= 1,
+            }
+            squares |= 1 << (N * y + x);
+        }
+    }
+    squares
+}
+
+/// Zobrist key of `next`, the position after playing `action` in `game`,
+/// computed from the key of `game` by rehashing only the touched squares.
+#[must_use]
+pub fn zobrist_after<const N: usize, const HALF_KOMI: i8>(
+    key: u64,
+    game: &Game<N, HALF_KOMI>,
+    action: Move,
+    next: &Game<N, HALF_KOMI>,
+) -> u64
+where
+    Reserves<N>: Default,
+{
+    let squares = touched_squares::<N>(action);
+    key ^ board_key(game, squares) ^ board_key(next, squares) ^ mix(BLACK_TO_MOVE)
+}
+
+#[cfg(test)]
+mod tests {
+    use fast_tak::{
+        takparse::{Move, Tps},
+        Game,
+    };
+
+    use rand::{rngs::StdRng, seq::SliceRandom, SeedableRng};
+
+    use super::{zobrist, zobrist_after};
+    use crate::search::env::Environment;
+
+    fn play(moves: &str) -> Game<5, 0> {
+        let mut game = Game::default();
+        for m in moves.split_whitespace() {
+            game.play(m.parse::<Move>().unwrap()).unwrap();
+        }
+        game
+    }
+
+    fn game(tps: &str) -> Game<5, 0> {
+        tps.parse::<Tps>().unwrap().into()
+    }
+
+    #[test]
+    fn transpositions_share_a_key() {
+        assert_eq!(
+            zobrist(&play("a1 e5 c3 c4 b2 b3")),
+            zobrist(&play("a1 e5 b2 b3 c3 c4"))
+        );
+        assert_eq!(
+            zobrist(&play("a1 e5")),
+            zobrist(&game("x4,1/x5/x5/x5/2,x4 1 2"))
+        );
+
+        let keys = [
+            game("2,x4/x5/x5/x5/1,x3,1 1 3"),
+            game("2,x4/x5/x5/x5/1,x3,1 2 3"),
+            game("2,x4/x5/x5/x5/1,x3,1S 1 3"),
+            game("2,x4/x5/x5/x5/1,x3,1C 1 3"),
+            game("x5/x5/x5/x5/12,x3,1 1 3"),
+            game("x5/x5/x5/x5/21,x3,1 1 3"),
+        ]
+        .map(|game| zobrist(&game));
+        for (i, a) in keys.iter().enumerate() {
+            for b in &keys[i + 1..] {
+                assert_ne!(a, b);
+            }
+        }
+    }
+
+    #[test]
+    fn incremental_keys_match() {
+        let mut rng = StdRng::seed_from_u64(7);
+        let mut actions = Vec::new();
+        for _ in 0..20 {
+            let mut game = Game::<5, 0>::default();
+            let mut key = game.hash();
+            while game.terminal().is_none() {
+                game.populate_actions(&mut actions);
+                let action = *actions.choose(&mut rng).unwrap();
+                actions.clear();
+                let mut next = game.clone();
+                next.step(action);
+                key = zobrist_after(key, &game, action, &next);
+                assert_eq!(key, zobrist(&next), "after {action}");
+                game = next;
+            }
+        }
+    }
+}
@@ -0,0 +1,322 @@
+//! Python bindings for scripting experiments against takzero.
+//!
+//! Build with `maturin develop --release` inside this directory, then:
+//!
+//! ```python
+//! import takzero_py as tz
+//! game = tz.Game()
+//! game.play("a1")
+//! model = tz.Model("model.ot")
+//! result = model.search(game, visits=800)
+//! print(result.best_move, result.value)
+//! ```
+//!
+//! Like the other tools, the bindings use the 6x6 network and environment.
+
+use std::path::PathBuf;
+
+use fast_tak::takparse::{Move, Tps};
+use pyo3::{exceptions::PyValueError, prelude::*};
+use takzero::{
+    network::{
+        net6_simhash::{Env, Net, HALF_KOMI, N},
+        Network,
+    },
+    search::{
+        agent::Agent,
+        env::{Environment, Terminal},
+        node::{policy::softmax, Node},
+        DISCOUNT_FACTOR,
+    },
+    target::{get_replays, get_targets, Replay, Target},
+};
+use tch::Device;
+
+fn value_error(err: impl ToString) -> PyErr {
+    PyValueError::new_err(err.to_string())
+}
+
+fn parse_device(device: &str) -> PyResult<Device> {
+    match device {
+        "cpu" => Ok(Device::Cpu),
+        "cuda" => Ok(Device::Cuda(0)),
+        _ => device
+            .strip_prefix("cuda:")
+            .and_then(|index| index.parse().ok())
+            .map(Device::Cuda)
+            .ok_or_else(|| value_error(format!("unknown device `{device}`"))),
+    }
+}
+
+/// A game of Tak.
+#[pyclass(name = "Game")]
+#[derive(Clone)]
+struct PyGame {
+    env: Env,
+}
+
+#[pymethods]
+impl PyGame {
+    /// Start from the given TPS, or from the empty board.
+    #[new]
+    #[pyo3(signature = (tps = None))]
+    fn new(tps: Option<&str>) -> PyResult<Self> {
+        let Some(tps) = tps else {
+            return Ok(Self { env: Env::default() });
+        };
+        let tps: Tps = tps.parse().map_err(value_error)?;
+        if tps.size() != N {
+            return Err(value_error(format!("only size {N} is supported")));
+        }
+        Ok(Self { env: tps.into() })
+    }
+
+    fn legal_moves(&self) -> Vec<String> {
+        let mut actions = Vec::new();
+        self.env.populate_actions(&mut actions);
+        actions.iter().map(ToString::to_string).collect()
+    }
+
+    /// Play a move given in PTN notation.
+    fn play(&mut self, action: &str) -> PyResult<()> {
+        let action: Move = action.parse().map_err(value_error)?;
+        self.env.play(action).map_err(value_error)
+    }
+
+    /// `"win"`, `"loss"`, or `"draw"` for the player to move,
+    /// or `None` if the game is not over.
+    fn terminal(&self) -> Option<&'static str> {
+        self.env.terminal().map(|terminal| match terminal {
+            Terminal::Win => "win",
+            Terminal::Loss => "loss",
+            Terminal::Draw => "draw",
+        })
+    }
+
+    fn tps(&self) -> String {
+        Tps::from(self.env.clone()).to_string()
+    }
+
+    #[getter]
+    fn ply(&self) -> u16 {
+        self.env.steps()
+    }
+
+    fn copy(&self) -> Self {
+        self.clone()
+    }
+
+    fn __repr__(&self) -> String {
+        format!("Game(\"{}\")", self.tps())
+    }
+}
+
+/// Result of a search.
+#[pyclass(name = "SearchResult", get_all)]
+struct PySearchResult {
+    /// `None` if the game is over.
+    best_move: Option<String>,
+    principal_variation: Vec<String>,
+    /// Value for the player to move, between -1 and 1.
+    value: f32,
+    /// Visits of each move.
+    visits: Vec<(String, u32)>,
+}
+
+/// Policy as `(move, probability)` pairs, value, and uncertainty.
+type EvaluationTuple = (Vec<(String, f32)>, f32, f32);
+
+/// A network loaded from a checkpoint.
+#[pyclass(name = "Model", unsendable)]
+struct PyModel {
+    net: Net,
+}
+
+#[pymethods]
+impl PyModel {
+    #[new]
+    #[pyo3(signature = (path, device = "cuda"))]
+    fn new(path: PathBuf, device: &str) -> PyResult<Self> {
+        let net = Net::load_partial(path, parse_device(device)?).map_err(value_error)?;
+        Ok(Self { net })
+    }
+
+    /// Evaluate positions with the network alone.
+    /// Returns a list of `(policy, value, uncertainty)` where the policy is a
+    /// list of `(move, probability)`. Finished games are not allowed.
+    fn evaluate(&self, games: Vec<PyRef<PyGame>>) -> PyResult<Vec<EvaluationTuple>> {
+        if games.iter().any(|game| game.env.terminal().is_some()) {
+            return Err(value_error("cannot evaluate finished games"));
+        }
+        if games.is_empty() {
+            return Ok(Vec::new());
+        }
+        let envs: Vec<_> = games.iter().map(|game| game.env.clone()).collect();
+        let actions: Vec<_> = envs
+            .iter()
+            .map(|env| {
+                let mut actions = Vec::new();
+                env.populate_actions(&mut actions);
+                actions
+            })
+            .collect();
+        Ok(tch::no_grad(|| {
+            self.net
+                .policy_value_uncertainty(&envs, &actions)
+                .map(|(policy, value, uncertainty)| {
+                    let probabilities = softmax(policy.iter().map(|(_, logit)| *logit));
+                    let policy = policy
+                        .iter()
+                        .zip(probabilities)
+                        .map(|((action, _), p)| (action.to_string(), p.into_inner()))
+                        .collect();
+                    (policy, value, uncertainty)
+                })
+                .collect()
+        }))
+    }
+
+    /// Run MCTS from the given position.
+    #[pyo3(signature = (game, visits = 800, beta = 0.0))]
+    fn search(&self, game: &PyGame, visits: u32, beta: f32) -> PySearchResult {
+        let mut node = Node::default();
+        tch::no_grad(|| {
+            for _ in 0..visits {
+                node.simulate_simple(&self.net, game.env.clone(), beta, DISCOUNT_FACTOR);
+            }
+        });
+        PySearchResult {
+            best_move: (!node.children().is_empty() && node.visit_count() > 0)
+                .then(|| node.select_best_action().to_string()),
+            principal_variation: node.principal_variation().map(|a| a.to_string()).collect(),
+            value: f32::from(node.evaluation()),
+            visits: node
+                .children()
+                .iter()
+                .map(|(action, child)| (action.to_string(), child.visit_count()))
+                .collect(),
+        }
+    }
+}
+
+/// A self-play game.
+#[pyclass(name = "Replay", get_all)]
+struct PyReplay {
+    /// Starting position.
+    start: PyGame,
+    moves: Vec<String>,
+    /// Generation of the network which played the game, if known.
+    generation: Option<usize>,
+    /// Id of the game in its run, if known.
+    game_id: Option<u64>,
+}
+
+impl From<Replay<Env>> for PyReplay {
+    fn from(replay: Replay<Env>) -> Self {
+        Self {
+            start: PyGame { env: replay.env },
+            moves: replay.actions.iter().map(ToString::to_string).collect(),
+            generation: replay.generation,
+            game_id: replay.game_id,
+        }
+    }
+}
+
+#[pymethods]
+impl PyReplay {
+    /// All positions in the game, including the final one.
+    fn positions(&self) -> PyResult<Vec<PyGame>> {
+        let mut env = self.start.env.clone();
+        let mut positions = vec![PyGame { env: env.clone() }];
+        for action in &self.moves {
+            env.play(action.parse().map_err(value_error)?)
+                .map_err(value_error)?;
+            positions.push(PyGame { env: env.clone() });
+        }
+        Ok(positions)
+    }
+}
+
+/// A training target.
+#[pyclass(name = "Target", get_all)]
+struct PyTarget {
+    game: PyGame,
+    policy: Vec<(String, f32)>,
+    value: f32,
+    ube: f32,
+    weight: f32,
+}
+
+impl From<Target<Env>> for PyTarget {
+    fn from(target: Target<Env>) -> Self {
+        Self {
+            game: PyGame { env: target.env },
+            policy: target
+                .policy
+                .iter()
+                .map(|(action, p)| (action.to_string(), p.into_inner()))
+                .collect(),
+            value: target.value,
+            ube: target.ube,
+            weight: target.weight.unwrap_or(1.0),
+        }
+    }
+}
+
+/// Parse a single replay line.
+#[pyfunction]
+fn parse_replay(line: &str) -> PyResult<PyReplay> {
+    line.parse::<Replay<Env>>()
+        .map(Into::into)
+        .map_err(value_error)
+}
+
+/// Parse a single target line.
+#[pyfunction]
+fn parse_target(line: &str) -> PyResult<PyTarget> {
+    line.parse::<Target<Env>>()
+        .map(Into::into)
+        .map_err(value_error)
+}
+
+/// Read all replays from a file, skipping lines which fail to parse.
+/// With `strict`, the first such line raises a `ValueError` instead.
+#[pyfunction]
+#[pyo3(signature = (path, strict = false))]
+fn read_replays(path: PathBuf, strict: bool) -> PyResult<Vec<PyReplay>> {
+    let replays = get_replays::<N, HALF_KOMI>(path)?;
+    if strict {
+        let replays = replays.read_strict().map_err(value_error)?;
+        Ok(replays.into_iter().map(Into::into).collect())
+    } else {
+        Ok(replays.map(Into::into).collect())
+    }
+}
+
+/// Read all targets from a file, skipping lines which fail to parse.
+/// With `strict`, the first such line raises a `ValueError` instead.
+#[pyfunction]
+#[pyo3(signature = (path, strict = false))]
+fn read_targets(path: PathBuf, strict: bool) -> PyResult<Vec<PyTarget>> {
+    let targets = get_targets::<N, HALF_KOMI>(path)?;
+    if strict {
+        let targets = targets.read_strict().map_err(value_error)?;
+        Ok(targets.into_iter().map(Into::into).collect())
+    } else {
+        Ok(targets.map(Into::into).collect())
+    }
+}
+
+#[pymodule]
+fn takzero_py(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    m.add_class::<PyGame>()?;
+    m.add_class::<PyModel>()?;
+    m.add_class::<PySearchResult>()?;
+    m.add_class::<PyReplay>()?;
+    m.add_class::<PyTarget>()?;
+    m.add_function(wrap_pyfunction!(parse_replay, m)?)?;
+    m.add_function(wrap_pyfunction!(parse_target, m)?)?;
+    m.add_function(wrap_pyfunction!(read_replays, m)?)?;
+    m.add_function(wrap_pyfunction!(read_targets, m)?)?;
+    Ok(())
+}
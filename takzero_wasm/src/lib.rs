@@ -0,0 +1,214 @@
+//! takzero's search compiled to WebAssembly, for analysis boards in the
+//! browser.
+//!
+//! The network is not part of the build. Instead, the page passes an
+//! `evaluate` function which receives a batch of positions, each as
+//! `{ tps, moves, input }` where `input` is the `Float32Array` network input
+//! (see [`game_to_input`]), and returns one `{ logits, value, uncertainty }`
+//! per position, with a logit for each of the `moves`. The function has to be
+//! synchronous, so a model running through an asynchronous runtime should be
+//! driven from a worker.
+//!
+//! Build with `wasm-pack build takzero_wasm --target web`.
+
+use fast_tak::{
+    takparse::{Move, Tps},
+    Game,
+};
+use js_sys::{Array, Float32Array, Function, Object, Reflect};
+use ordered_float::NotNan;
+use takzero::{
+    network::repr::game_to_input,
+    search::{agent::Agent, env::Environment, node::Node, DISCOUNT_FACTOR},
+};
+use wasm_bindgen::prelude::*;
+
+const N: usize = 6;
+const HALF_KOMI: i8 = 4;
+type Env = Game<N, HALF_KOMI>;
+
+/// Agent which calls back into JavaScript.
+struct JsAgent {
+    evaluate: Function,
+}
+
+fn get(object: &JsValue, key: &str) -> JsValue {
+    Reflect::get(object, &key.into()).expect("evaluation should be an object")
+}
+
+impl Agent<Env> for JsAgent {
+    fn policy_value_uncertainty(
+        &self,
+        env_batch: &[Env],
+        actions_batch: &[Vec<Move>],
+    ) -> impl Iterator<Item = (Vec<(Move, NotNan<f32>)>, f32, f32)> {
+        let batch: Array = env_batch
+            .iter()
+            .zip(actions_batch)
+            .map(|(env, actions)| {
+                let position = Object::new();
+                let moves: Array = actions
+                    .iter()
+                    .map(|action| JsValue::from(action.to_string()))
+                    .collect();
+                let input = Float32Array::from(game_to_input(env).as_slice());
+                let tps = Tps::from(env.clone()).to_string();
+                Reflect::set(&position, &"tps".into(), &tps.into())
+                    .and_then(|_| Reflect::set(&position, &"moves".into(), &moves))
+                    .and_then(|_| Reflect::set(&position, &"input".into(), &input))
+                    .expect("setting properties of a new object should succeed");
+                JsValue::from(position)
+            })
+            .collect();
+
+        let evaluations = self
+            .evaluate
+            .call1(&JsValue::NULL, &batch)
+            .expect("the evaluate function should not throw");
+        let evaluations = Array::from(&evaluations);
+        assert_eq!(
+            evaluations.length() as usize,
+            env_batch.len(),
+            "there should be an evaluation for every position"
+        );
+
+        let outputs: Vec<_> = evaluations
+            .iter()
+            .zip(actions_batch)
+            .map(|(evaluation, actions)| {
+                let logits = Float32Array::new(&get(&evaluation, "logits")).to_vec();
+                assert_eq!(logits.len(), actions.len(), "there should be a logit for every move");
+                let policy = actions
+                    .iter()
+                    .zip(logits)
+                    .map(|(action, logit)| {
+                        (*action, NotNan::new(logit).expect("logits should not be NaN"))
+                    })
+                    .collect();
+                let value = get(&evaluation, "value").as_f64().unwrap_or_default() as f32;
+                let uncertainty =
+                    get(&evaluation, "uncertainty").as_f64().unwrap_or_default() as f32;
+                (policy, value, uncertainty)
+            })
+            .collect();
+        outputs.into_iter()
+    }
+}
+
+/// Search tree for a position, which can be extended a few simulations at a
+/// time so that the page stays responsive.
+#[wasm_bindgen]
+pub struct Analysis {
+    env: Env,
+    root: Node<Env>,
+    agent: JsAgent,
+    beta: f32,
+}
+
+#[wasm_bindgen]
+impl Analysis {
+    /// Start analysing the given position, or the empty board.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the TPS is invalid or for another board size.
+    #[wasm_bindgen(constructor)]
+    pub fn new(
+        tps: Option<String>,
+        evaluate: Function,
+        beta: Option<f32>,
+    ) -> Result<Self, JsError> {
+        let env = match tps {
+            Some(tps) => {
+                let tps: Tps = tps.parse()?;
+                if tps.size() != N {
+                    return Err(JsError::new(&format!("only size {N} is supported")));
+                }
+                tps.into()
+            }
+            None => Env::default(),
+        };
+        Ok(Self {
+            env,
+            root: Node::default(),
+            agent: JsAgent { evaluate },
+            beta: beta.unwrap_or_default(),
+        })
+    }
+
+    /// Run more simulations.
+    pub fn search(&mut self, simulations: u32) {
+        for _ in 0..simulations {
+            self.root.simulate_simple(&self.agent, self.env.clone(), self.beta, DISCOUNT_FACTOR);
+        }
+    }
+
+    /// Play a move, keeping the relevant part of the tree.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the move is invalid or illegal.
+    pub fn play(&mut self, action: &str) -> Result<(), JsError> {
+        let action: Move = action.parse()?;
+        self.env.play(action)?;
+        self.root.descend(&action);
+        Ok(())
+    }
+
+    #[must_use]
+    pub fn tps(&self) -> String {
+        Tps::from(self.env.clone()).to_string()
+    }
+
+    #[must_use]
+    pub fn legal_moves(&self) -> Vec<String> {
+        let mut actions = Vec::new();
+        self.env.populate_actions(&mut actions);
+        actions.iter().map(ToString::to_string).collect()
+    }
+
+    #[must_use]
+    pub fn simulations(&self) -> u32 {
+        self.root.visit_count()
+    }
+
+    /// Value for the player to move, between -1 and 1.
+    #[must_use]
+    pub fn value(&self) -> f32 {
+        f32::from(self.root.evaluation())
+    }
+
+    /// `undefined` until the first simulation or if the game is over.
+    #[must_use]
+    pub fn best_move(&self) -> Option<String> {
+        (!self.root.children().is_empty()).then(|| self.root.select_best_action().to_string())
+    }
+
+    #[must_use]
+    pub fn principal_variation(&self) -> Vec<String> {
+        self.root
+            .principal_variation()
+            .map(|action| action.to_string())
+            .collect()
+    }
+
+    /// Visits of every move, in the same order as [`Self::moves`].
+    #[must_use]
+    pub fn visits(&self) -> Vec<u32> {
+        self.root
+            .children()
+            .iter()
+            .map(|(_, child)| child.visit_count())
+            .collect()
+    }
+
+    /// Moves which have been expanded at the root.
+    #[must_use]
+    pub fn moves(&self) -> Vec<String> {
+        self.root
+            .children()
+            .iter()
+            .map(|(action, _)| action.to_string())
+            .collect()
+    }
+}
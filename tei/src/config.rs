@@ -0,0 +1,288 @@
+use std::{str::FromStr, time::Duration};
+
+use takzero::search::{node::progress::ReportInterval, DISCOUNT_FACTOR};
+use thiserror::Error;
+
+use crate::protocol::{Output, ValueType};
+
+/// Which search algorithm to use for `go`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SearchMode {
+    /// Plain MCTS, which can be stopped at any time.
+    Mcts,
+    /// Gumbel sequential halving, which needs the number of nodes up front.
+    Gumbel,
+}
+
+/// Engine options which can be changed with `setoption`.
+#[derive(Debug, Clone)]
+pub struct SearchConfig {
+    pub model_path: Option<String>,
+    pub search_mode: SearchMode,
+    /// Simulations to use when `go` has no stopping condition.
+    pub simulations: usize,
+    /// Number of actions considered at the root by Gumbel search.
+    pub sampled_actions: usize,
+    /// Exploration bonus given to uncertain nodes.
+    pub beta: f32,
+    /// Discount per ply for known results, so that faster wins are
+    /// preferred before they are proven.
+    pub mate_discount: f32,
+    /// Temperature used to pick the final move. Zero picks the best move.
+    pub temperature: f32,
+    /// Number of threads used by libtorch for CPU work.
+    pub threads: usize,
+    /// How often to print `info` lines during search.
+    pub info_interval: ReportInterval,
+    /// Most simulations per move, whatever `go` asks for, to compare
+    /// engines of different inference cost at equal nodes.
+    pub node_limit: Option<usize>,
+    /// Factor applied to the time of every `go`, to give time odds.
+    pub time_odds: f64,
+    /// Whether to average the predictions of the network over the 8
+    /// symmetries of every position.
+    pub symmetries: bool,
+}
+
+impl Default for SearchConfig {
+    fn default() -> Self {
+        Self {
+            model_path: None,
+            search_mode: SearchMode::Mcts,
+            simulations: 800,
+            sampled_actions: 16,
+            beta: 0.0,
+            mate_discount: DISCOUNT_FACTOR,
+            temperature: 0.0,
+            threads: 1,
+            info_interval: ReportInterval::Simulations(200),
+            node_limit: None,
+            time_odds: 1.0,
+            symmetries: false,
+        }
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum SetOptionError {
+    #[error("unknown option: {0}")]
+    Unknown(String),
+    #[error("invalid value `{value}` for option {name}")]
+    InvalidValue { name: String, value: String },
+}
+
+impl SearchConfig {
+    /// Describe all options to the GUI.
+    pub fn print_options() {
+        println!("{}", Output::Option {
+            name: "model",
+            value_type: ValueType::String,
+            default: Some("./path/to/model.ot"),
+            min: None,
+            max: None,
+            variables: &[]
+        });
+        println!("{}", Output::Option {
+            name: "Search",
+            value_type: ValueType::Combo,
+            default: Some("mcts"),
+            min: None,
+            max: None,
+            variables: &["mcts", "gumbel"]
+        });
+        println!("{}", Output::Option {
+            name: "Simulations",
+            value_type: ValueType::Spin,
+            default: Some("800"),
+            min: Some("1"),
+            max: Some("1000000"),
+            variables: &[]
+        });
+        println!("{}", Output::Option {
+            name: "SampledActions",
+            value_type: ValueType::Spin,
+            default: Some("16"),
+            min: Some("2"),
+            max: Some("256"),
+            variables: &[]
+        });
+        println!("{}", Output::Option {
+            name: "Beta",
+            value_type: ValueType::String,
+            default: Some("0.0"),
+            min: None,
+            max: None,
+            variables: &[]
+        });
+        println!("{}", Output::Option {
+            name: "MateDiscount",
+            value_type: ValueType::String,
+            default: Some("0.997"),
+            min: None,
+            max: None,
+            variables: &[]
+        });
+        println!("{}", Output::Option {
+            name: "Temperature",
+            value_type: ValueType::String,
+            default: Some("0.0"),
+            min: None,
+            max: None,
+            variables: &[]
+        });
+        println!("{}", Output::Option {
+            name: "Threads",
+            value_type: ValueType::Spin,
+            default: Some("1"),
+            min: Some("1"),
+            max: Some("256"),
+            variables: &[]
+        });
+        println!("{}", Output::Option {
+            name: "InfoNodes",
+            value_type: ValueType::Spin,
+            default: Some("200"),
+            min: Some("1"),
+            max: Some("1000000"),
+            variables: &[]
+        });
+        println!("{}", Output::Option {
+            name: "InfoMilliseconds",
+            value_type: ValueType::Spin,
+            default: None,
+            min: Some("1"),
+            max: Some("3600000"),
+            variables: &[]
+        });
+        println!("{}", Output::Option {
+            name: "NodeLimit",
+            value_type: ValueType::Spin,
+            default: Some("0"),
+            min: Some("0"),
+            max: Some("1000000"),
+            variables: &[]
+        });
+        println!("{}", Output::Option {
+            name: "TimeOdds",
+            value_type: ValueType::String,
+            default: Some("1.0"),
+            min: None,
+            max: None,
+            variables: &[]
+        });
+        println!("{}", Output::Option {
+            name: "Symmetries",
+            value_type: ValueType::Check,
+            default: Some("false"),
+            min: None,
+            max: None,
+            variables: &[]
+        });
+    }
+
+    /// Apply a `setoption` message.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the option is unknown or the value is invalid,
+    /// in which case the configuration is left unchanged.
+    pub fn set(&mut self, name: &str, value: &str) -> Result<(), SetOptionError> {
+        let invalid = || SetOptionError::InvalidValue {
+            name: name.to_string(),
+            value: value.to_string(),
+        };
+        match name {
+            "model" => self.model_path = Some(value.to_string()),
+            "Search" => {
+                self.search_mode = match value {
+                    "mcts" => SearchMode::Mcts,
+                    "gumbel" => SearchMode::Gumbel,
+                    _ => return Err(invalid()),
+                };
+            }
+            "Simulations" => {
+                self.simulations = parse_filtered(value, |&x| x >= 1).ok_or_else(invalid)?;
+            }
+            "SampledActions" => {
+                // Gumbel search needs log2(k) > 0.
+                self.sampled_actions = parse_filtered(value, |&x| x >= 2).ok_or_else(invalid)?;
+            }
+            "Beta" => {
+                self.beta = parse_filtered(value, |x: &f32| x.is_finite()).ok_or_else(invalid)?;
+            }
+            "MateDiscount" => {
+                self.mate_discount = parse_filtered(value, |x: &f32| *x > 0.0 && *x <= 1.0)
+                    .ok_or_else(invalid)?;
+            }
+            "Temperature" => {
+                self.temperature = parse_filtered(value, |x: &f32| x.is_finite() && *x >= 0.0)
+                    .ok_or_else(invalid)?;
+            }
+            "Threads" => {
+                self.threads = parse_filtered(value, |&x| x >= 1).ok_or_else(invalid)?;
+                tch::set_num_threads(i32::try_from(self.threads).unwrap_or(i32::MAX));
+            }
+            "InfoNodes" => {
+                let nodes = parse_filtered(value, |&x| x >= 1).ok_or_else(invalid)?;
+                self.info_interval = ReportInterval::Simulations(nodes);
+            }
+            "InfoMilliseconds" => {
+                let millis = parse_filtered(value, |&x| x >= 1).ok_or_else(invalid)?;
+                self.info_interval = ReportInterval::Time(Duration::from_millis(millis));
+            }
+            "NodeLimit" => {
+                // Zero lifts the limit.
+                let limit = parse_filtered(value, |_: &usize| true).ok_or_else(invalid)?;
+                self.node_limit = (limit > 0).then_some(limit);
+            }
+            "TimeOdds" => {
+                self.time_odds = parse_filtered(value, |x: &f64| x.is_finite() && *x > 0.0)
+                    .ok_or_else(invalid)?;
+            }
+            "Symmetries" => self.symmetries = value.parse().map_err(|_| invalid())?,
+            _ => return Err(SetOptionError::Unknown(name.to_string())),
+        }
+        Ok(())
+    }
+}
+
+fn parse_filtered<T: FromStr>(value: &str, filter: impl Fn(&T) -> bool) -> Option<T> {
+    value.parse().ok().filter(filter)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{SearchConfig, SearchMode};
+
+    #[test]
+    fn set_options() {
+        let mut config = SearchConfig::default();
+        config.set("Search", "gumbel").unwrap();
+        config.set("SampledActions", "8").unwrap();
+        config.set("Temperature", "0.5").unwrap();
+        config.set("model", "/path with spaces/model.ot").unwrap();
+        assert_eq!(config.search_mode, SearchMode::Gumbel);
+        assert_eq!(config.sampled_actions, 8);
+        assert!((config.temperature - 0.5).abs() < f32::EPSILON);
+        assert_eq!(config.model_path.as_deref(), Some("/path with spaces/model.ot"));
+
+        assert!(config.set("SampledActions", "1").is_err());
+        assert!(config.set("Temperature", "-1").is_err());
+        assert!(config.set("MateDiscount", "0").is_err());
+        assert!(config.set("MateDiscount", "NaN").is_err());
+        assert!(config.set("Unknown", "1").is_err());
+        assert_eq!(config.sampled_actions, 8);
+
+        config.set("NodeLimit", "400").unwrap();
+        config.set("TimeOdds", "0.5").unwrap();
+        assert_eq!(config.node_limit, Some(400));
+        assert!((config.time_odds - 0.5).abs() < f64::EPSILON);
+        assert!(config.set("TimeOdds", "0").is_err());
+        config.set("NodeLimit", "0").unwrap();
+        assert_eq!(config.node_limit, None);
+
+        config.set("Symmetries", "true").unwrap();
+        assert!(config.symmetries);
+        assert!(config.set("Symmetries", "yes").is_err());
+    }
+}
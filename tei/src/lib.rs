@@ -0,0 +1,360 @@
+use std::time::{Duration, Instant};
+
+use fast_tak::takparse::{Color, Move};
+use config::{SearchConfig, SearchMode};
+use protocol::{GoOption, Id, Input, Output, ParseInputError, Position, ValueType};
+use takzero::{
+    network::{
+        checkpoint,
+        net6_simhash::{Env, Net, HALF_KOMI, N},
+        Network,
+    },
+    search::{
+        agent::{dummy::Dummy, symmetric::Symmetric, Agent},
+        env::Environment,
+        node::{
+            batched::BatchedMCTS,
+            progress::{ProgressReporter, SearchProgress},
+            Node,
+            SearchError,
+        },
+    },
+    time_manager::TimeManager,
+};
+use thiserror::Error;
+
+mod config;
+mod protocol;
+
+const MAX_ERRORS_IN_A_ROW: usize = 5;
+
+/// Speak TEI on standard input and output until the GUI quits. The caller
+/// initializes logging.
+#[allow(clippy::too_many_lines)]
+pub fn run() {
+    let mut line = String::new();
+    let stdin = std::io::stdin();
+
+    // Wait for first `tei` message.
+    let Ok(Input::Tei) = get_input(&stdin, &mut line) else {
+        log::error!("first message received should be `tei`");
+        return;
+    };
+
+    // Print name and author.
+    println!("{}", Output::Id(Id::Name("TakZero")));
+    println!("{}", Output::Id(Id::Author("Viliam Vadocz (0x57696c6c)")));
+
+    // Describe engine options.
+    SearchConfig::print_options();
+    println!("{}", Output::Option {
+        name: "HalfKomi",
+        value_type: ValueType::Combo,
+        default: Some("4"),
+        min: None,
+        max: None,
+        variables: &["4"]
+    });
+    println!("{}", Output::Ok);
+
+    // Configure engine options.
+    let mut config = SearchConfig::default();
+    loop {
+        match get_input(&stdin, &mut line) {
+            Ok(Input::IsReady) => break,
+            Ok(Input::Option { name, value }) => match name.as_ref() {
+                "HalfKomi" => {
+                    let Ok(half_komi) = value.parse::<i8>() else {
+                        log::error!("could not parse half komi");
+                        return;
+                    };
+                    if half_komi != HALF_KOMI {
+                        log::error!(
+                            "half komi of {half_komi} was request, but only {HALF_KOMI} is \
+                             supported"
+                        );
+                        return;
+                    }
+                }
+                _ => {
+                    if let Err(err) = config.set(&name, &value) {
+                        log::warn!("{err}");
+                    }
+                }
+            },
+            Ok(_) => log::warn!("only expecting `isready` or `option` messages"),
+            Err(err) => log::error!("{err}"),
+        }
+    }
+
+    // Load engine / model. Without one, the engine still plays, but only
+    // with the heuristic value.
+    let net = config.model_path.clone().and_then(|model_path| {
+        if let Err(err) = checkpoint::configure_repr(std::path::Path::new(&model_path)) {
+            log::error!("failed to read the input encoding of the model: {err}");
+            return None;
+        }
+        Net::load_partial(model_path, tch::Device::Cuda(0))
+            .inspect_err(|err| log::error!("failed to load model: {err}"))
+            .ok()
+    });
+    if net.is_none() {
+        log::warn!("no model is loaded, searching with the heuristic value");
+    }
+    println!("{}", Output::ReadyOk);
+
+    let mut node = Node::default();
+    let mut env = Env::default();
+    if let Some(net) = &net {
+        let warm_up = node.try_simulate_simple(net, env.clone(), config.beta, config.mate_discount);
+        if let Err(err) = warm_up {
+            log::error!("warm-up search failed: {err}");
+            node = Node::default();
+        }
+    }
+
+    let mut errors_in_a_row = 0;
+    loop {
+        match get_input(&stdin, &mut line) {
+            Ok(Input::IsReady) => println!("{}", Output::ReadyOk),
+            Ok(Input::NewGame { size }) => {
+                if size != N {
+                    log::error!("the engine is compiled only for size {N}");
+                }
+                node = Node::default();
+                env = Env::default();
+            }
+            Ok(Input::Position { position, moves }) => {
+                node = Node::default();
+                env = match position {
+                    Position::StartPos => Env::default(),
+                    Position::Tps(tps) => tps.into(),
+                };
+                for my_move in moves {
+                    if let Err(err) = env.play(my_move) {
+                        log::error!("could not play move {my_move}: {err}");
+                        break;
+                    }
+                }
+            }
+            Ok(Input::Option { name, value }) => match name.as_ref() {
+                "model" | "HalfKomi" => log::warn!("{name} can only be set before `isready`"),
+                _ => {
+                    let symmetries = config.symmetries;
+                    if let Err(err) = config.set(&name, &value) {
+                        log::warn!("{err}");
+                    }
+                    // The tree was searched with other predictions.
+                    if config.symmetries != symmetries {
+                        node = Node::default();
+                    }
+                }
+            },
+            Ok(Input::Quit) => break,
+            Ok(Input::Go(go_options)) => {
+                let best_move = match &net {
+                    Some(net) if config.symmetries => {
+                        go(&Symmetric(net), &env, &mut node, go_options, &config)
+                    }
+                    Some(net) => go(net, &env, &mut node, go_options, &config),
+                    None => go(&Dummy, &env, &mut node, go_options, &config),
+                };
+                match best_move {
+                    Ok(best_move) => println!("{}", Output::BestMove(best_move)),
+                    Err(err) => {
+                        // Throw the tree away so that the next search starts over.
+                        log::error!("search failed: {err}");
+                        node = Node::default();
+                        if let Some(fallback) = any_move(&env) {
+                            println!("{}", Output::BestMove(fallback));
+                        }
+                    }
+                }
+            }
+
+            Ok(_) => log::warn!("unhandled message"),
+            Err(err) => {
+                log::error!("{err}");
+                errors_in_a_row += 1;
+                if errors_in_a_row >= MAX_ERRORS_IN_A_ROW {
+                    log::error!("there were {MAX_ERRORS_IN_A_ROW} errors in a row");
+                    return;
+                }
+                continue;
+            }
+        };
+        errors_in_a_row = 0;
+    }
+}
+
+fn go(
+    net: &impl Agent<Env>,
+    env: &Env,
+    node: &mut Node<Env>,
+    go_options: Vec<GoOption>,
+    config: &SearchConfig,
+) -> Result<Move, SearchError> {
+    let mut nodes = None;
+    let mut move_time = None;
+
+    let mut my_time = None;
+    let mut my_inc = None;
+
+    for option in go_options {
+        match option {
+            GoOption::Nodes(amount) => nodes = Some(amount),
+            GoOption::MoveTime(duration) => move_time = Some(duration),
+            GoOption::WhiteTime(duration) if env.to_move == Color::White => {
+                my_time = Some(duration);
+            }
+            GoOption::BlackTime(duration) if env.to_move == Color::Black => {
+                my_time = Some(duration);
+            }
+            GoOption::WhiteIncrement(duration) if env.to_move == Color::White => {
+                my_inc = Some(duration);
+            }
+            GoOption::BlackIncrement(duration) if env.to_move == Color::Black => {
+                my_inc = Some(duration);
+            }
+            _ => log::warn!("ignored `go` option {option:?}"),
+        }
+    }
+
+    if nodes.is_none() && move_time.is_none() && my_time.is_none() {
+        log::warn!("no understood stopping condition given, using {}", config.simulations);
+        nodes = Some(config.simulations);
+    }
+
+    // Handicaps apply on top of what the GUI asked for.
+    if let Some(limit) = config.node_limit {
+        nodes = Some(nodes.map_or(limit, |nodes| nodes.min(limit)));
+    }
+    let odds = |duration: Duration| duration.mul_f64(config.time_odds);
+    move_time = move_time.map(odds);
+    my_time = my_time.map(odds);
+    my_inc = my_inc.map(odds);
+
+    match (config.search_mode, nodes) {
+        (SearchMode::Gumbel, Some(nodes)) => return go_gumbel(net, env, nodes, config),
+        (SearchMode::Gumbel, None) => {
+            log::warn!("gumbel search needs `go nodes`, falling back to mcts");
+        }
+        (SearchMode::Mcts, _) => {}
+    }
+
+    let time_manager = TimeManager::default();
+    let mut timer = None;
+    let mut reporter = ProgressReporter::new(config.info_interval);
+
+    let start = Instant::now();
+    let mut in_place = env.clone();
+    for visits in 1.. {
+        node.try_simulate_in_place(net, &mut in_place, config.beta, config.mate_discount)?;
+
+        let elapsed = start.elapsed();
+
+        // Clock time is only used if there is no fixed time per move.
+        let out_of_time = match (move_time, my_time) {
+            (None, Some(my_time)) => {
+                // Created after the first simulation so that the root is expanded.
+                let timer = timer.get_or_insert_with(|| {
+                    let forced = node.children().len() == 1 || node.evaluation().is_known();
+                    time_manager.start(my_time, my_inc.unwrap_or_default(), forced)
+                });
+                timer.observe_best(node.try_select_best_action()?);
+                timer.should_stop() || node.evaluation().is_known()
+            }
+            _ => false,
+        };
+
+        if reporter.should_report(visits as u32, elapsed) {
+            report(node.progress(elapsed));
+        }
+
+        if out_of_time
+            || nodes.is_some_and(|amount| visits >= amount)
+            || move_time.is_some_and(|duration| elapsed >= duration)
+        {
+            report(node.progress(elapsed));
+            break;
+        }
+    }
+    choose_move(node, config.temperature)
+}
+
+/// Search with Gumbel sequential halving, like during selfplay
+/// (but without Dirichlet noise).
+fn go_gumbel(
+    net: &impl Agent<Env>,
+    env: &Env,
+    nodes: usize,
+    config: &SearchConfig,
+) -> Result<Move, SearchError> {
+    // The budget needs to be a multiple of k * log2(k).
+    let unit = config.sampled_actions * config.sampled_actions.ilog2() as usize;
+    let budget = (nodes / unit).max(1) * unit;
+
+    let start = Instant::now();
+    let mut batched_mcts = BatchedMCTS::<1, Env>::from_envs([env.clone()]);
+    let [best_move] = batched_mcts.try_gumbel_sequential_halving(
+        net,
+        &[config.beta],
+        config.mate_discount,
+        config.sampled_actions,
+        budget as u32,
+        &mut rand::thread_rng(),
+    )?;
+    if let Some((node, _)) = batched_mcts.nodes_and_envs().next() {
+        report(node.progress(start.elapsed()));
+        if config.temperature > 0.0 {
+            return choose_move(node, config.temperature);
+        }
+    }
+    Ok(best_move)
+}
+
+/// Print search progress, with the visit shares of the top moves as a string.
+fn report(progress: SearchProgress<Move>) {
+    let visit_shares: Vec<_> = progress
+        .visit_shares
+        .iter()
+        .take(5)
+        .map(|(action, share)| format!("{action}:{:.1}%", share * 100.0))
+        .collect();
+    println!("{}", Output::InfoString(format!("visits {}", visit_shares.join(" "))));
+    println!("{}", Output::Info(progress));
+}
+
+/// Pick the move to play after search, either the best one or one sampled
+/// with the configured temperature.
+fn choose_move(node: &Node<Env>, temperature: f32) -> Result<Move, SearchError> {
+    if temperature > 0.0 {
+        if let Some(action) =
+            node.sample_action_with_temperature(temperature, &[], &mut rand::thread_rng())
+        {
+            return Ok(action);
+        }
+    }
+    node.try_select_best_action()
+}
+
+/// Any legal move, so that the GUI still gets an answer when search fails.
+fn any_move(env: &Env) -> Option<Move> {
+    let mut actions = Vec::new();
+    env.populate_actions(&mut actions);
+    actions.into_iter().next()
+}
+
+#[derive(Debug, Error)]
+enum GetInputError {
+    #[error("reading from stdin failed: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("parse error: {0}")]
+    Parse(#[from] ParseInputError),
+}
+
+fn get_input(stdin: &std::io::Stdin, line: &mut String) -> Result<Input, GetInputError> {
+    line.clear();
+    stdin.read_line(line)?;
+    Ok(line.trim().parse()?)
+}
@@ -4,7 +4,7 @@
 use std::{fmt, num::ParseIntError, str::FromStr, time::Duration};
 
 use fast_tak::takparse::{Move, ParseMoveError, ParseTpsError, Tps};
-use takzero::search::eval::Eval;
+use takzero::search::node::progress::SearchProgress;
 use thiserror::Error;
 
 pub enum Input {
@@ -91,10 +91,11 @@ impl FromStr for Input {
                     .next()
                     .filter(|&word| word == "value")
                     .ok_or(ParseInputError::MissingValue)?;
-                let value = words
-                    .next()
-                    .ok_or(ParseInputError::MissingName)?
-                    .to_string();
+                // Values such as paths may contain spaces.
+                let value = words.collect::<Vec<_>>().join(" ");
+                if value.is_empty() {
+                    return Err(ParseInputError::MissingValue);
+                }
                 Ok(Self::Option { name, value })
             }
             "teinewgame" => {
@@ -171,12 +172,8 @@ pub enum Output {
     Ok,
     ReadyOk,
     BestMove(Move),
-    Info {
-        time: Duration,
-        nodes: usize,
-        score: Eval,
-        principal_variation: Vec<Move>,
-    },
+    Info(SearchProgress<Move>),
+    InfoString(String),
 }
 
 pub enum Id {
@@ -236,18 +233,16 @@ impl fmt::Display for Output {
             Self::Ok => write!(f, "teiok"),
             Self::ReadyOk => write!(f, "readyok"),
             Self::BestMove(the_move) => write!(f, "bestmove {the_move}"),
-            Self::Info {
-                time,
-                nodes,
-                score,
-                principal_variation,
-            } => {
-                let centipawns = (f32::from(*score) * 100.0) as i32;
+            Self::Info(progress) => {
+                let time = progress.elapsed.as_millis();
+                let nodes = progress.simulations;
+                let score = progress.evaluation;
+                let centipawns = (f32::from(score) * 100.0) as i32;
                 write!(
                     f,
-                    "info time {} nodes {nodes} nps {}",
-                    time.as_millis(),
-                    1000 * nodes / time.as_millis() as usize,
+                    "info depth {} time {time} nodes {nodes} nps {}",
+                    progress.depth,
+                    1000 * u128::from(nodes) / time.max(1),
                 )?;
                 if let Some(ply) = score.ply() {
                     write!(f, " score mate {ply}")?;
@@ -255,11 +250,12 @@ impl fmt::Display for Output {
                     write!(f, " score cp {centipawns}")?;
                 }
                 write!(f, " pv")?;
-                for mv in principal_variation {
+                for mv in &progress.principal_variation {
                     write!(f, " {mv}")?;
                 }
                 Ok(())
             }
+            Self::InfoString(string) => write!(f, "info string {string}"),
         }
     }
 }
@@ -0,0 +1,124 @@
+use std::time::Instant;
+
+use clap::{Parser, ValueEnum};
+use fast_tak::{
+    takparse::{Move, Tps},
+    Game,
+    Reserves,
+};
+use takzero::{
+    ptn::{ninja_url, to_ptn},
+    search::{
+        agent::simple::Simple,
+        node::Node,
+        solver::{Solution, Solver},
+        DISCOUNT_FACTOR,
+    },
+    target::Replay,
+};
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+enum Method {
+    /// Proof-number search with iterative deepening
+    Pns,
+    /// Exact win/loss propagation in MCTS with a heuristic agent
+    Mcts,
+}
+
+#[derive(Parser, Debug)]
+struct Args {
+    /// Position to solve, for example "x3/x,1,x/x3 2 1"
+    tps: String,
+    /// Half komi of the game
+    #[arg(long, default_value_t = 0)]
+    half_komi: i8,
+    /// Node budget (simulations for MCTS)
+    #[arg(long, default_value_t = 1_000_000)]
+    nodes: usize,
+    /// Maximum length of the winning line in plies (proof-number search only)
+    #[arg(long, default_value_t = 9)]
+    max_depth: usize,
+    #[arg(long, value_enum, default_value_t = Method::Pns)]
+    method: Method,
+}
+
+macro_rules! dispatch {
+    ($args:expr, $tps:expr; $($size:literal),*) => {
+        match ($tps.size(), $args.half_komi) {
+            $(
+                ($size, 0) => solve::<$size, 0>(&$args, $tps),
+                ($size, 4) => solve::<$size, 4>(&$args, $tps),
+            )*
+            (size, half_komi) => {
+                log::error!("size {size} with half komi {half_komi} is not supported");
+            }
+        }
+    };
+}
+
+fn main() {
+    takzero::logging::init();
+    let args = Args::parse();
+    let tps: Tps = match args.tps.parse() {
+        Ok(tps) => tps,
+        Err(err) => {
+            log::error!("invalid TPS: {err}");
+            return;
+        }
+    };
+    dispatch!(args, tps; 3, 4, 5, 6, 7, 8);
+}
+
+fn solve_with_mcts<const N: usize, const HALF_KOMI: i8>(
+    env: &Game<N, HALF_KOMI>,
+    simulations: usize,
+) -> (Solution<Move>, usize)
+where
+    Reserves<N>: Default,
+{
+    let mut root = Node::default();
+    let mut env = env.clone();
+    for simulation in 1..=simulations {
+        root.simulate_in_place(&Simple, &mut env, 0.0, DISCOUNT_FACTOR);
+        if root.evaluation().is_win() {
+            return (Solution::Win(root.principal_variation().collect()), simulation);
+        }
+        if root.evaluation().is_known() {
+            return (Solution::NoWin, simulation);
+        }
+    }
+    (Solution::Unknown, simulations)
+}
+
+fn solve<const N: usize, const HALF_KOMI: i8>(args: &Args, tps: Tps)
+where
+    Reserves<N>: Default,
+{
+    let env: Game<N, HALF_KOMI> = tps.into();
+    let start = Instant::now();
+    let (solution, nodes) = match args.method {
+        Method::Pns => Solver {
+            max_nodes: args.nodes,
+            max_depth: args.max_depth,
+        }
+        .solve(&env),
+        Method::Mcts => solve_with_mcts(&env, args.nodes),
+    };
+    let elapsed = start.elapsed();
+
+    match solution {
+        Solution::Win(line) => {
+            let moves: Vec<_> = line.iter().map(ToString::to_string).collect();
+            println!("win in {} plies: {}", line.len(), moves.join(" "));
+            let mut replay = Replay::new(env);
+            for action in line {
+                replay.push(action);
+            }
+            println!("{}", ninja_url(&to_ptn(&replay, &[], None)));
+        }
+        Solution::NoWin => println!("no forced win"),
+        Solution::NoWinWithin(depth) => println!("no forced win within {depth} plies"),
+        Solution::Unknown => println!("unknown (budget exhausted)"),
+    }
+    println!("{nodes} nodes in {:.2}s", elapsed.as_secs_f32());
+}
@@ -0,0 +1,236 @@
+//! Calibration of checkpoints against fixed external engines.
+//!
+//! Every checkpoint plays the same matches against the same anchors, so the
+//! ratings form a longitudinal strength curve which does not drift with the
+//! pool of checkpoints (unlike ratings from matches between checkpoints).
+
+use std::{fmt, io, path::Path, str::FromStr};
+
+use svg::{
+    node::element::{Circle, Line, Polyline, Text, Title},
+    Document,
+};
+
+use crate::rating::{ratings_with_error, GameResult};
+
+const CHART_WIDTH: f64 = 800.0;
+const CHART_HEIGHT: f64 = 400.0;
+const CHART_MARGIN: f64 = 50.0;
+const COLORS: [&str; 6] = ["#8142f5", "#f5427b", "#42b3f5", "#f5a742", "#42f58d", "#555555"];
+
+/// Result of one checkpoint against one anchor.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Calibration {
+    pub checkpoint: String,
+    pub anchor: String,
+    pub games: usize,
+    /// Score of the checkpoint between 0 and 1.
+    pub score: f64,
+    /// Elo of the checkpoint relative to the anchor.
+    pub elo: f64,
+    /// Half-width of the 95% confidence interval.
+    pub error: f64,
+}
+
+impl Calibration {
+    /// Rate a match where player 0 is the checkpoint and player 1 the anchor.
+    #[must_use]
+    pub fn from_results(checkpoint: String, anchor: String, results: &[GameResult]) -> Self {
+        let points: f64 = results
+            .iter()
+            .map(|game| {
+                if game.white == 0 {
+                    game.white_score
+                } else {
+                    1.0 - game.white_score
+                }
+            })
+            .sum();
+        let ratings = ratings_with_error(2, results);
+        Self {
+            checkpoint,
+            anchor,
+            games: results.len(),
+            score: points / results.len().max(1) as f64,
+            // With two players the ratings are symmetric around zero.
+            elo: ratings[0].elo - ratings[1].elo,
+            error: 2.0 * ratings[0].error,
+        }
+    }
+}
+
+impl fmt::Display for Calibration {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{},{},{},{:.4},{:.1},{:.1}",
+            self.checkpoint, self.anchor, self.games, self.score, self.elo, self.error
+        )
+    }
+}
+
+impl FromStr for Calibration {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let fields: Vec<_> = s.trim().split(',').collect();
+        let [checkpoint, anchor, games, score, elo, error] = fields.as_slice() else {
+            return Err(format!("expected 6 fields in `{s}`"));
+        };
+        let number = |field: &str| field.parse::<f64>().map_err(|err| format!("`{field}`: {err}"));
+        Ok(Self {
+            checkpoint: (*checkpoint).to_string(),
+            anchor: (*anchor).to_string(),
+            games: games.parse().map_err(|err| format!("`{games}`: {err}"))?,
+            score: number(score)?,
+            elo: number(elo)?,
+            error: number(error)?,
+        })
+    }
+}
+
+/// Read all results from a file, which may not exist yet.
+///
+/// # Errors
+///
+/// Returns an error if the file exists but cannot be read.
+pub fn load(path: &Path) -> io::Result<Vec<Calibration>> {
+    let content = match std::fs::read_to_string(path) {
+        Ok(content) => content,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(err) => return Err(err),
+    };
+    Ok(content
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .filter_map(|line| match line.parse() {
+            Ok(calibration) => Some(calibration),
+            Err(err) => {
+                log::warn!("skipping calibration line: {err}");
+                None
+            }
+        })
+        .collect())
+}
+
+/// Chart the Elo of the checkpoints (in order of their names) with one line
+/// per anchor.
+#[must_use]
+#[allow(clippy::suboptimal_flops)]
+pub fn chart(results: &[Calibration]) -> Document {
+    let mut checkpoints: Vec<_> = results.iter().map(|r| r.checkpoint.as_str()).collect();
+    checkpoints.sort_unstable();
+    checkpoints.dedup();
+    let mut anchors: Vec<_> = results.iter().map(|r| r.anchor.as_str()).collect();
+    anchors.sort_unstable();
+    anchors.dedup();
+
+    let (low, high) = results.iter().fold((0.0_f64, 0.0_f64), |(low, high), r| {
+        (low.min(r.elo - r.error), high.max(r.elo + r.error))
+    });
+    let range = (high - low).max(1.0);
+    let plot_width = CHART_WIDTH - 2.0 * CHART_MARGIN;
+    let plot_height = CHART_HEIGHT - 2.0 * CHART_MARGIN;
+    let x = |checkpoint: &str| {
+        let index = checkpoints.iter().position(|c| *c == checkpoint).unwrap_or_default();
+        CHART_MARGIN + plot_width * (index as f64 + 0.5) / checkpoints.len().max(1) as f64
+    };
+    let y = |elo: f64| CHART_MARGIN + plot_height * (high - elo) / range;
+
+    let mut document = Document::new()
+        .set("viewBox", (0, 0, CHART_WIDTH, CHART_HEIGHT))
+        .set("font-family", "sans-serif")
+        .set("font-size", 12)
+        .add(
+            Line::new()
+                .set("x1", CHART_MARGIN)
+                .set("y1", y(0.0))
+                .set("x2", CHART_WIDTH - CHART_MARGIN)
+                .set("y2", y(0.0))
+                .set("stroke", "#cccccc"),
+        )
+        .add(
+            Text::new(format!("{high:+.0}"))
+                .set("x", CHART_MARGIN - 5.0)
+                .set("y", y(high))
+                .set("text-anchor", "end"),
+        )
+        .add(
+            Text::new(format!("{low:+.0}"))
+                .set("x", CHART_MARGIN - 5.0)
+                .set("y", y(low))
+                .set("text-anchor", "end"),
+        );
+
+    for (i, anchor) in anchors.iter().enumerate() {
+        let color = COLORS[i % COLORS.len()];
+        let mut points: Vec<_> = results.iter().filter(|r| r.anchor == *anchor).collect();
+        points.sort_by(|a, b| a.checkpoint.cmp(&b.checkpoint));
+        let line: Vec<_> = points
+            .iter()
+            .map(|r| format!("{:.1},{:.1}", x(&r.checkpoint), y(r.elo)))
+            .collect();
+        document = document
+            .add(
+                Polyline::new()
+                    .set("points", line.join(" "))
+                    .set("fill", "none")
+                    .set("stroke", color),
+            )
+            .add(
+                Text::new(format!("vs {anchor}"))
+                    .set("x", CHART_MARGIN + 10.0)
+                    .set("y", 15.0 * (i + 1) as f64)
+                    .set("fill", color),
+            );
+        for r in points {
+            document = document
+                .add(
+                    Line::new()
+                        .set("x1", x(&r.checkpoint))
+                        .set("y1", y(r.elo - r.error))
+                        .set("x2", x(&r.checkpoint))
+                        .set("y2", y(r.elo + r.error))
+                        .set("stroke", color)
+                        .set("opacity", 0.5),
+                )
+                .add(
+                    Circle::new()
+                        .set("cx", x(&r.checkpoint))
+                        .set("cy", y(r.elo))
+                        .set("r", 3)
+                        .set("fill", color)
+                        .add(Title::new(r.to_string())),
+                );
+        }
+    }
+    document
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Calibration;
+    use crate::rating::GameResult;
+
+    #[test]
+    fn rate_and_round_trip() {
+        // The checkpoint (player 0) wins three of four games.
+        let results: Vec<_> = [(0, 1, 1.0), (1, 0, 0.0), (0, 1, 1.0), (1, 0, 1.0)]
+            .into_iter()
+            .map(|(white, black, white_score)| GameResult {
+                white,
+                black,
+                white_score,
+            })
+            .collect();
+        let calibration = Calibration::from_results("a".into(), "b".into(), &results);
+        assert_eq!(calibration.games, 4);
+        assert!((calibration.score - 0.75).abs() < 1e-9);
+        assert!(calibration.elo > 0.0);
+
+        let parsed: Calibration = calibration.to_string().parse().unwrap();
+        assert_eq!(parsed.checkpoint, "a");
+        assert_eq!(parsed.games, 4);
+        assert!((parsed.elo - calibration.elo).abs() < 0.1);
+    }
+}
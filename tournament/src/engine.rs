@@ -0,0 +1,295 @@
+//! Client side of the TEI protocol.
+
+use std::{
+    io::{self, BufRead, BufReader, Write},
+    process::{Child, ChildStdin, ChildStdout, Command, Stdio},
+    time::Duration,
+};
+
+use fast_tak::takparse::{Move, ParseMoveError};
+use thiserror::Error;
+
+const QUIT_POLLS: usize = 10;
+const QUIT_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+#[derive(Error, Debug)]
+pub enum EngineError {
+    #[error("engine `{0}` has no command")]
+    NoCommand(String),
+    #[error("engine `{name}`: {source}")]
+    Io { name: String, source: io::Error },
+    #[error("engine `{0}` exited unexpectedly")]
+    Exited(String),
+    #[error("engine `{name}` sent an invalid move `{line}`: {source}")]
+    InvalidMove {
+        name: String,
+        line: String,
+        source: ParseMoveError,
+    },
+}
+
+/// How long an engine may think about a move.
+#[derive(Debug, Clone, Copy)]
+pub enum Limit {
+    Nodes(u32),
+    MoveTime(Duration),
+    Clock {
+        white_time: Duration,
+        black_time: Duration,
+        white_increment: Duration,
+        black_increment: Duration,
+    },
+}
+
+/// Compute odds for one engine, to compare engines of very different
+/// inference cost. The runner applies them, so they work for any engine.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Handicap {
+    /// Nodes per move instead of the limit of the match. The engine does
+    /// not play on the clock then.
+    pub nodes: Option<u32>,
+    /// Factor applied to the thinking time and increment of the engine.
+    pub time_odds: f64,
+}
+
+impl Default for Handicap {
+    fn default() -> Self {
+        Self {
+            nodes: None,
+            time_odds: 1.0,
+        }
+    }
+}
+
+impl Handicap {
+    /// Apply a setting like `nodes=400` or `time=0.5`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the setting is unknown or the value is invalid.
+    pub fn set(&mut self, name: &str, value: &str) -> Result<(), String> {
+        let invalid = || format!("invalid value `{value}` for handicap `{name}`");
+        match name {
+            "nodes" => {
+                self.nodes = Some(value.parse().ok().filter(|&x| x > 0).ok_or_else(invalid)?);
+            }
+            "time" => {
+                self.time_odds = value
+                    .parse()
+                    .ok()
+                    .filter(|x: &f64| x.is_finite() && *x > 0.0)
+                    .ok_or_else(invalid)?;
+            }
+            _ => return Err(format!("unknown handicap `{name}`, expected `nodes` or `time`")),
+        }
+        Ok(())
+    }
+
+    /// The engine's share of an amount of time.
+    #[must_use]
+    pub fn time(self, time: Duration) -> Duration {
+        time.mul_f64(self.time_odds)
+    }
+
+    /// The limit for the engine instead of the limit of the match. Clock
+    /// times are handicapped by the runner, which keeps the clocks.
+    #[must_use]
+    pub fn limit(self, limit: Limit) -> Limit {
+        match (self.nodes, limit) {
+            (Some(nodes), _) => Limit::Nodes(nodes),
+            (None, Limit::MoveTime(time)) => Limit::MoveTime(self.time(time)),
+            (None, limit) => limit,
+        }
+    }
+}
+
+/// A running TEI engine.
+pub struct Engine {
+    pub name: String,
+    pub handicap: Handicap,
+    child: Child,
+    stdin: ChildStdin,
+    stdout: BufReader<ChildStdout>,
+    line: String,
+}
+
+impl Engine {
+    /// Start an engine and set its options.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the engine cannot be started or does not respond.
+    pub fn start(
+        name: String,
+        command: &str,
+        options: &[(String, String)],
+    ) -> Result<Self, EngineError> {
+        let mut words = command.split_whitespace();
+        let program = words
+            .next()
+            .ok_or_else(|| EngineError::NoCommand(name.clone()))?;
+        let mut child = Command::new(program)
+            .args(words)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .map_err(|source| EngineError::Io {
+                name: name.clone(),
+                source,
+            })?;
+        let stdin = child.stdin.take().expect("stdin should be piped");
+        let stdout = BufReader::new(child.stdout.take().expect("stdout should be piped"));
+        let mut engine = Self {
+            name,
+            handicap: Handicap::default(),
+            child,
+            stdin,
+            stdout,
+            line: String::new(),
+        };
+
+        engine.send("tei")?;
+        engine.wait_for("teiok")?;
+        for (option, value) in options {
+            engine.send(&format!("setoption name {option} value {value}"))?;
+        }
+        engine.send("isready")?;
+        engine.wait_for("readyok")?;
+        Ok(engine)
+    }
+
+    fn io_error(&self, source: io::Error) -> EngineError {
+        EngineError::Io {
+            name: self.name.clone(),
+            source,
+        }
+    }
+
+    fn send(&mut self, line: &str) -> Result<(), EngineError> {
+        log::trace!("{} <- {line}", self.name);
+        writeln!(self.stdin, "{line}")
+            .and_then(|()| self.stdin.flush())
+            .map_err(|err| self.io_error(err))
+    }
+
+    /// Read lines until one starts with the given prefix, and return it.
+    fn wait_for(&mut self, prefix: &str) -> Result<&str, EngineError> {
+        loop {
+            self.line.clear();
+            let read = self.stdout.read_line(&mut self.line);
+            match read {
+                Ok(0) => return Err(EngineError::Exited(self.name.clone())),
+                Ok(_) => {}
+                Err(err) => return Err(self.io_error(err)),
+            }
+            log::trace!("{} -> {}", self.name, self.line.trim_end());
+            if self.line.trim_start().starts_with(prefix) {
+                return Ok(self.line.trim());
+            }
+        }
+    }
+
+    /// Prepare for a new game.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the engine does not respond.
+    pub fn new_game(&mut self, size: usize) -> Result<(), EngineError> {
+        self.send(&format!("teinewgame {size}"))?;
+        self.send("isready")?;
+        self.wait_for("readyok")?;
+        Ok(())
+    }
+
+    /// Ask the engine for a move in the position reached by playing `moves`
+    /// from `tps`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the engine does not respond or sends an invalid
+    /// move. Legality is not checked.
+    pub fn go(&mut self, tps: &str, moves: &[Move], limit: Limit) -> Result<Move, EngineError> {
+        let mut position = format!("position tps {tps}");
+        if !moves.is_empty() {
+            position.push_str(" moves");
+            for action in moves {
+                position.push(' ');
+                position.push_str(&action.to_string());
+            }
+        }
+        self.send(&position)?;
+        self.send(&match limit {
+            Limit::Nodes(nodes) => format!("go nodes {nodes}"),
+            Limit::MoveTime(time) => format!("go movetime {}", time.as_millis()),
+            Limit::Clock {
+                white_time,
+                black_time,
+                white_increment,
+                black_increment,
+            } => format!(
+                "go wtime {} btime {} winc {} binc {}",
+                white_time.as_millis(),
+                black_time.as_millis(),
+                white_increment.as_millis(),
+                black_increment.as_millis()
+            ),
+        })?;
+
+        let name = self.name.clone();
+        let line = self.wait_for("bestmove")?;
+        let action = line.split_whitespace().nth(1).unwrap_or_default();
+        action.parse().map_err(|source| EngineError::InvalidMove {
+            name,
+            line: line.to_string(),
+            source,
+        })
+    }
+}
+
+impl Drop for Engine {
+    fn drop(&mut self) {
+        self.send("quit").ok();
+        // Give the engine a moment to exit on its own.
+        for _ in 0..QUIT_POLLS {
+            if let Ok(Some(_)) = self.child.try_wait() {
+                return;
+            }
+            std::thread::sleep(QUIT_POLL_INTERVAL);
+        }
+        self.child.kill().ok();
+        self.child.wait().ok();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{Handicap, Limit};
+
+    #[test]
+    fn handicaps_change_limits() {
+        let mut handicap = Handicap::default();
+        let second = Duration::from_secs(1);
+        assert!(matches!(
+            handicap.limit(Limit::MoveTime(second)),
+            Limit::MoveTime(t) if t == second
+        ));
+
+        handicap.set("time", "0.25").unwrap();
+        assert_eq!(handicap.time(second), Duration::from_millis(250));
+        assert!(matches!(
+            handicap.limit(Limit::MoveTime(second)),
+            Limit::MoveTime(t) if t == Duration::from_millis(250)
+        ));
+        assert!(matches!(handicap.limit(Limit::Nodes(800)), Limit::Nodes(800)));
+
+        handicap.set("nodes", "100").unwrap();
+        assert!(matches!(handicap.limit(Limit::MoveTime(second)), Limit::Nodes(100)));
+
+        for (name, value) in [("nodes", "0"), ("time", "-1"), ("time", "inf"), ("elo", "1")] {
+            assert!(handicap.set(name, value).is_err(), "{name}={value}");
+        }
+        assert_eq!(handicap.nodes, Some(100));
+    }
+}
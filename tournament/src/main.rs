@@ -0,0 +1,564 @@
+use std::{
+    fs::{File, OpenOptions},
+    io::Write as _,
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+
+use calibration::Calibration;
+use clap::{Parser, ValueEnum};
+use engine::{Engine, EngineError, Handicap, Limit};
+use fast_tak::{
+    takparse::{Color, Move, Tps},
+    Game,
+};
+use rand::SeedableRng;
+use rating::{ratings_with_error, GameResult};
+use takzero::{
+    ptn::{ninja_url, to_ptn, Outcome},
+    search::env::{Environment, Terminal, TerminalReason},
+    target::Replay,
+    time_manager::TimeControl,
+};
+
+mod calibration;
+mod engine;
+mod rating;
+
+const N: usize = 6;
+const HALF_KOMI: i8 = 4;
+type Env = Game<N, HALF_KOMI>;
+/// Nodes per move if no limit is given.
+const DEFAULT_NODES: u32 = 800;
+/// Name of the player for checkpoints when calibrating.
+const CHECKPOINT: &str = "checkpoint";
+const CALIBRATION_POLL_INTERVAL: Duration = Duration::from_secs(600);
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+enum Format {
+    /// Every engine plays every other engine.
+    RoundRobin,
+    /// The first engine plays every other engine.
+    Gauntlet,
+}
+
+#[derive(Parser, Debug)]
+struct Args {
+    /// Engines as `name=command`, for example `latest=./tei`
+    #[arg(long = "engine", required = true)]
+    engines: Vec<String>,
+    /// Engine options as `name:option=value`, for example `latest:model=net.ot`
+    #[arg(long = "option")]
+    options: Vec<String>,
+    /// Compute odds as `name:nodes=400` (nodes per move instead of the
+    /// limit of the match) or `name:time=0.5` (a share of the time), for
+    /// comparing engines of very different inference cost
+    #[arg(long = "handicap")]
+    handicaps: Vec<String>,
+    #[arg(long, value_enum, default_value_t = Format::RoundRobin)]
+    format: Format,
+    /// Number of times each pairing plays each opening (with both colors)
+    #[arg(long, default_value_t = 1)]
+    rounds: usize,
+    /// File with opening positions as TPS, one per line
+    #[arg(long)]
+    openings: Option<PathBuf>,
+    /// Number of random openings to generate without an opening file
+    #[arg(long, default_value_t = 50)]
+    random_openings: usize,
+    /// Random plies in generated openings
+    #[arg(long, default_value_t = 2)]
+    opening_plies: usize,
+    /// Seed for generated openings
+    #[arg(long, default_value_t = 0)]
+    seed: u64,
+    /// Time control like `60+0.5` (in seconds)
+    #[arg(long, conflicts_with_all = ["nodes", "move_time"])]
+    time_control: Option<TimeControl>,
+    /// Nodes per move
+    #[arg(long, conflicts_with = "move_time")]
+    nodes: Option<u32>,
+    /// Milliseconds per move
+    #[arg(long)]
+    move_time: Option<u64>,
+    /// Games longer than this are adjudicated as draws
+    #[arg(long, default_value_t = 400)]
+    max_plies: usize,
+    /// Adjudicate games at the move limit as wins for the side whose
+    /// heuristic value is above this margin, instead of as draws
+    #[arg(long)]
+    adjudication_margin: Option<f32>,
+    /// File to append the games to as PTN
+    #[arg(long)]
+    games: Option<PathBuf>,
+    /// Directory of checkpoints to play against the engines, which act as
+    /// fixed anchors (results go to `calibration.csv` and `calibration.svg`)
+    #[arg(long)]
+    calibrate: Option<PathBuf>,
+    /// TEI engine which plays the checkpoints with its `model` option set,
+    /// configured with `--option checkpoint:option=value`
+    #[arg(long, default_value = "./tei")]
+    checkpoint_engine: String,
+    /// Keep calibrating new checkpoints as they appear
+    #[arg(long, requires = "calibrate")]
+    watch: bool,
+}
+
+struct Player {
+    name: String,
+    command: String,
+    options: Vec<(String, String)>,
+    handicap: Handicap,
+}
+
+impl Player {
+    fn start(&self, name: String, options: &[(String, String)]) -> Result<Engine, EngineError> {
+        let mut engine = Engine::start(name, &self.command, options)?;
+        engine.handicap = self.handicap;
+        Ok(engine)
+    }
+}
+
+fn parse_players(args: &Args) -> Result<Vec<Player>, String> {
+    let mut players: Vec<_> = args
+        .engines
+        .iter()
+        .map(|engine| {
+            let (name, command) = engine
+                .split_once('=')
+                .ok_or_else(|| format!("engine `{engine}` is not in the format `name=command`"))?;
+            Ok(Player {
+                name: name.to_string(),
+                command: command.to_string(),
+                options: vec![("HalfKomi".to_string(), HALF_KOMI.to_string())],
+                handicap: Handicap::default(),
+            })
+        })
+        .collect::<Result<_, String>>()?;
+    if args.calibrate.is_some() {
+        players.insert(
+            0,
+            Player {
+                name: CHECKPOINT.to_string(),
+                command: args.checkpoint_engine.clone(),
+                options: vec![("HalfKomi".to_string(), HALF_KOMI.to_string())],
+                handicap: Handicap::default(),
+            },
+        );
+    }
+    for option in &args.options {
+        let (name, setting) = option
+            .split_once(':')
+            .and_then(|(name, setting)| Some((name, setting.split_once('=')?)))
+            .ok_or_else(|| format!("option `{option}` is not in the format `name:option=value`"))?;
+        let player = players
+            .iter_mut()
+            .find(|player| player.name == name)
+            .ok_or_else(|| format!("option `{option}` is for an unknown engine"))?;
+        player
+            .options
+            .push((setting.0.to_string(), setting.1.to_string()));
+    }
+    for handicap in &args.handicaps {
+        let (name, (setting, value)) = handicap
+            .split_once(':')
+            .and_then(|(name, setting)| Some((name, setting.split_once('=')?)))
+            .ok_or_else(|| format!("handicap `{handicap}` is not in the format `name:key=value`"))?;
+        let player = players
+            .iter_mut()
+            .find(|player| player.name == name)
+            .ok_or_else(|| format!("handicap `{handicap}` is for an unknown engine"))?;
+        player.handicap.set(setting, value)?;
+    }
+    Ok(players)
+}
+
+fn openings(args: &Args) -> std::io::Result<Vec<Env>> {
+    if let Some(path) = &args.openings {
+        return Ok(std::fs::read_to_string(path)?
+            .lines()
+            .filter(|line| !line.trim().is_empty())
+            .filter_map(|line| match line.parse::<Tps>() {
+                Ok(tps) if tps.size() == N => Some(tps.into()),
+                _ => {
+                    log::warn!("skipping opening `{line}`");
+                    None
+                }
+            })
+            .collect());
+    }
+    let mut rng = rand_chacha::ChaCha8Rng::seed_from_u64(args.seed);
+    let mut actions = Vec::new();
+    Ok((0..args.random_openings)
+        .map(|_| Env::new_opening_with_random_steps(&mut rng, &mut actions, args.opening_plies))
+        .collect())
+}
+
+/// Pairs of players that meet, depending on the format.
+fn pairings(format: Format, players: usize) -> Vec<(usize, usize)> {
+    match format {
+        Format::RoundRobin => (0..players)
+            .flat_map(|a| (a + 1..players).map(move |b| (a, b)))
+            .collect(),
+        Format::Gauntlet => (1..players).map(|b| (0, b)).collect(),
+    }
+}
+
+struct Record {
+    outcome: Outcome,
+    reason: &'static str,
+    moves: Vec<Move>,
+}
+
+/// Play one game between the engines. Illegal moves and running out of time
+/// lose the game. The handicaps of the engines adjust their limits and
+/// clocks.
+fn play_game(
+    white: &mut Engine,
+    black: &mut Engine,
+    opening: &Env,
+    limit: Option<Limit>,
+    time_control: Option<TimeControl>,
+    max_plies: usize,
+    adjudication_margin: Option<f32>,
+) -> Result<Record, EngineError> {
+    white.new_game(N)?;
+    black.new_game(N)?;
+    let tps = Tps::from(opening.clone()).to_string();
+    let mut env = opening.clone();
+    let mut moves = Vec::new();
+    // The remaining time and increment of each side.
+    let mut clocks = time_control.map(|time_control| {
+        [white.handicap, black.handicap].map(|handicap| {
+            (
+                handicap.time(time_control.initial),
+                handicap.time(time_control.increment),
+            )
+        })
+    });
+    let lose = |color: Color| match color {
+        Color::White => Outcome::BlackWin,
+        Color::Black => Outcome::WhiteWin,
+    };
+
+    loop {
+        if let Some(terminal) = env.terminal() {
+            return Ok(Record {
+                outcome: Outcome::from_terminal(terminal, env.to_move),
+                reason: match env.terminal_reason() {
+                    Some(TerminalReason::Road) => "road",
+                    Some(TerminalReason::Flats) => "flats",
+                    Some(TerminalReason::OutOfPieces) => "out of pieces",
+                    Some(TerminalReason::MoveLimit) => "move limit",
+                    None => "game over",
+                },
+                moves,
+            });
+        }
+        if moves.len() >= max_plies {
+            let value = env.heuristic_value();
+            let outcome = match adjudication_margin {
+                Some(margin) if value > margin => {
+                    Outcome::from_terminal(Terminal::Win, env.to_move)
+                }
+                Some(margin) if value < -margin => lose(env.to_move),
+                _ => Outcome::Draw,
+            };
+            return Ok(Record {
+                outcome,
+                reason: "adjudicated after the move limit",
+                moves,
+            });
+        }
+
+        let color = env.to_move;
+        let engine = match color {
+            Color::White => &mut *white,
+            Color::Black => &mut *black,
+        };
+        let handicap = engine.handicap;
+        let limit = match clocks {
+            Some([(white_time, white_increment), (black_time, black_increment)]) => Limit::Clock {
+                white_time,
+                black_time,
+                white_increment,
+                black_increment,
+            },
+            None => limit.unwrap_or(Limit::Nodes(DEFAULT_NODES)),
+        };
+        let start = Instant::now();
+        let action = engine.go(&tps, &moves, handicap.limit(limit))?;
+        let elapsed = start.elapsed();
+
+        // Engines handicapped to a number of nodes do not play on the clock.
+        if let (Some(clocks), None) = (&mut clocks, handicap.nodes) {
+            let (clock, increment) = &mut clocks[usize::from(color == Color::Black)];
+            let Some(remaining) = clock.checked_sub(elapsed) else {
+                return Ok(Record {
+                    outcome: lose(color),
+                    reason: "lost on time",
+                    moves,
+                });
+            };
+            *clock = remaining + *increment;
+        }
+        if env.play(action).is_err() {
+            log::warn!("{} played an illegal move {action}", engine.name);
+            return Ok(Record {
+                outcome: lose(color),
+                reason: "illegal move",
+                moves,
+            });
+        }
+        moves.push(action);
+    }
+}
+
+fn game_ptn(white: &str, black: &str, opening: &Env, record: &Record) -> String {
+    let mut replay = Replay::new(opening.clone());
+    for &action in &record.moves {
+        replay.push(action);
+    }
+    let tags = [
+        ("Player1", white),
+        ("Player2", black),
+        ("Termination", record.reason),
+    ];
+    to_ptn(&replay, &tags, Some(record.outcome))
+}
+
+fn main() {
+    takzero::logging::init();
+    let args = Args::parse();
+    if let Err(err) = run(&args) {
+        log::error!("{err}");
+    }
+}
+
+fn run(args: &Args) -> Result<(), Box<dyn std::error::Error>> {
+    let players = parse_players(args)?;
+    let openings = openings(args)?;
+    let limit = args
+        .nodes
+        .map(Limit::Nodes)
+        .or_else(|| args.move_time.map(|ms| Limit::MoveTime(Duration::from_millis(ms))));
+    let games_file = args
+        .games
+        .as_ref()
+        .map(|path| OpenOptions::new().append(true).create(true).open(path))
+        .transpose()?;
+    let mut runner = Runner {
+        args,
+        limit,
+        games_file,
+    };
+    if let Some(directory) = &args.calibrate {
+        return calibrate(&mut runner, directory, &players, &openings);
+    }
+
+    let mut engines = players
+        .iter()
+        .map(|player| player.start(player.name.clone(), &player.options))
+        .collect::<Result<Vec<_>, _>>()?;
+    let mut results = Vec::new();
+    for round in 0..args.rounds {
+        for (index, opening) in openings.iter().enumerate() {
+            for &(a, b) in &pairings(args.format, players.len()) {
+                let (engine_a, engine_b) = two_mut(&mut engines, a, b);
+                let label = format!("round {round} opening {index}");
+                results.extend(runner.play_pairing((a, engine_a), (b, engine_b), opening, &label)?);
+            }
+        }
+    }
+
+    print_table(&players, &results);
+    Ok(())
+}
+
+/// Settings shared by all games.
+struct Runner<'a> {
+    args: &'a Args,
+    limit: Option<Limit>,
+    games_file: Option<File>,
+}
+
+impl Runner<'_> {
+    /// Play an opening with both colors.
+    fn play_pairing(
+        &mut self,
+        (a, engine_a): (usize, &mut Engine),
+        (b, engine_b): (usize, &mut Engine),
+        opening: &Env,
+        label: &str,
+    ) -> Result<[GameResult; 2], Box<dyn std::error::Error>> {
+        Ok([
+            self.play((a, &mut *engine_a), (b, &mut *engine_b), opening, label)?,
+            self.play((b, engine_b), (a, engine_a), opening, label)?,
+        ])
+    }
+
+    fn play(
+        &mut self,
+        (white, white_engine): (usize, &mut Engine),
+        (black, black_engine): (usize, &mut Engine),
+        opening: &Env,
+        label: &str,
+    ) -> Result<GameResult, Box<dyn std::error::Error>> {
+        let record = play_game(
+            white_engine,
+            black_engine,
+            opening,
+            self.limit,
+            self.args.time_control,
+            self.args.max_plies,
+            self.args.adjudication_margin,
+        )?;
+        let white_score = match record.outcome {
+            Outcome::WhiteWin => 1.0,
+            Outcome::BlackWin => 0.0,
+            Outcome::Draw => 0.5,
+        };
+        log::info!(
+            "{label}: {} vs {} {white_score}-{} ({})",
+            white_engine.name,
+            black_engine.name,
+            1.0 - white_score,
+            record.reason
+        );
+        let ptn = game_ptn(&white_engine.name, &black_engine.name, opening, &record);
+        log::debug!("{}", ninja_url(&ptn));
+        if let Some(file) = &mut self.games_file {
+            writeln!(file, "{ptn}")?;
+        }
+        Ok(GameResult {
+            white,
+            black,
+            white_score,
+        })
+    }
+}
+
+/// Play every checkpoint in `directory` which has no results yet against all
+/// engines, and record the results in `calibration.csv` and a chart of them
+/// in `calibration.svg` in the same directory.
+fn calibrate(
+    runner: &mut Runner<'_>,
+    directory: &Path,
+    players: &[Player],
+    openings: &[Env],
+) -> Result<(), Box<dyn std::error::Error>> {
+    let (checkpoint, anchors) = players
+        .split_first()
+        .expect("the checkpoint player should exist");
+    let mut engines = anchors
+        .iter()
+        .map(|player| player.start(player.name.clone(), &player.options))
+        .collect::<Result<Vec<_>, _>>()?;
+    let csv = directory.join("calibration.csv");
+
+    loop {
+        let done = calibration::load(&csv)?;
+        let mut paths: Vec<_> = std::fs::read_dir(directory)?
+            .map(|entry| entry.map(|entry| entry.path()))
+            .collect::<Result<_, _>>()?;
+        paths.retain(|path| {
+            path.extension().is_some_and(|ext| ext == "ot")
+                && path.file_stem().is_some_and(|stem| stem != "model_latest")
+        });
+        paths.sort();
+
+        for path in paths {
+            let name = path
+                .file_stem()
+                .unwrap_or_default()
+                .to_string_lossy()
+                .to_string();
+            if done.iter().any(|calibration| calibration.checkpoint == name) {
+                continue;
+            }
+            let mut options = checkpoint.options.clone();
+            options.push(("model".to_string(), path.display().to_string()));
+            let mut engine = checkpoint.start(name.clone(), &options)?;
+            let mut file = OpenOptions::new().append(true).create(true).open(&csv)?;
+
+            for (anchor, anchor_engine) in anchors.iter().zip(&mut engines) {
+                let mut results = Vec::new();
+                for round in 0..runner.args.rounds {
+                    for (index, opening) in openings.iter().enumerate() {
+                        let label = format!("{name} round {round} opening {index}");
+                        results.extend(runner.play_pairing(
+                            (0, &mut engine),
+                            (1, &mut *anchor_engine),
+                            opening,
+                            &label,
+                        )?);
+                    }
+                }
+                let calibration =
+                    Calibration::from_results(name.clone(), anchor.name.clone(), &results);
+                log::info!(
+                    "{name} vs {}: {:.1}% ({:+.1} +/- {:.1} Elo)",
+                    anchor.name,
+                    100.0 * calibration.score,
+                    calibration.elo,
+                    calibration.error
+                );
+                writeln!(file, "{calibration}")?;
+            }
+            let chart = calibration::chart(&calibration::load(&csv)?);
+            svg::save(directory.join("calibration.svg"), &chart)?;
+        }
+
+        if !runner.args.watch {
+            return Ok(());
+        }
+        log::info!("Waiting {CALIBRATION_POLL_INTERVAL:?} for new checkpoints.");
+        std::thread::sleep(CALIBRATION_POLL_INTERVAL);
+    }
+}
+
+/// Borrow two distinct elements mutably.
+fn two_mut<T>(items: &mut [T], a: usize, b: usize) -> (&mut T, &mut T) {
+    assert_ne!(a, b, "the elements should be distinct");
+    if a < b {
+        let (left, right) = items.split_at_mut(b);
+        (&mut left[a], &mut right[0])
+    } else {
+        let (left, right) = items.split_at_mut(a);
+        (&mut right[0], &mut left[b])
+    }
+}
+
+fn print_table(players: &[Player], results: &[GameResult]) {
+    let ratings = ratings_with_error(players.len(), results);
+    let mut order: Vec<_> = (0..players.len()).collect();
+    order.sort_by(|&a, &b| ratings[b].elo.total_cmp(&ratings[a].elo));
+
+    println!(
+        "{:<4} {:<24} {:>8} {:>6} {:>6} {:>6}",
+        "rank", "name", "elo", "+/-", "games", "score"
+    );
+    for (rank, &i) in order.iter().enumerate() {
+        let (games, points) = results
+            .iter()
+            .filter_map(|game| {
+                if game.white == i {
+                    Some(game.white_score)
+                } else if game.black == i {
+                    Some(1.0 - game.white_score)
+                } else {
+                    None
+                }
+            })
+            .fold((0_u32, 0.0), |(games, points), score| (games + 1, points + score));
+        println!(
+            "{:<4} {:<24} {:>8.1} {:>6.1} {:>6} {:>5.1}%",
+            rank + 1,
+            players[i].name,
+            ratings[i].elo,
+            ratings[i].error,
+            games,
+            100.0 * points / f64::from(games.max(1))
+        );
+    }
+}
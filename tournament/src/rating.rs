@@ -0,0 +1,140 @@
+//! Rating estimation in the style of BayesElo.
+//!
+//! Ratings are the maximum likelihood Bradley-Terry strengths (a draw counts
+//! as half a win for both sides), found with the minorization-maximization
+//! algorithm. Every player also gets a prior of a few virtual draws against
+//! an average opponent, which keeps ratings finite after a sweep. The error
+//! bars come from bootstrapping over the games.
+
+use rand::{Rng, SeedableRng};
+
+/// Virtual draws against an average player added to every player.
+const PRIOR_DRAWS: f64 = 2.0;
+const ITERATIONS: usize = 10_000;
+const TOLERANCE: f64 = 1e-9;
+const BOOTSTRAP_SAMPLES: usize = 200;
+const BOOTSTRAP_SEED: u64 = 0x5EED;
+/// Two-sided 95% interval of a normal distribution.
+const Z_95: f64 = 1.96;
+
+/// Result of a single game.
+#[derive(Debug, Clone, Copy)]
+pub struct GameResult {
+    pub white: usize,
+    pub black: usize,
+    /// 1 for a white win, 0.5 for a draw, 0 for a black win.
+    pub white_score: f64,
+}
+
+/// Rating of a player, relative to the average of all players.
+#[derive(Debug, Clone, Copy)]
+pub struct Rating {
+    pub elo: f64,
+    /// Half-width of the 95% confidence interval.
+    pub error: f64,
+}
+
+/// Maximum likelihood Elo ratings with the average fixed at zero.
+#[must_use]
+pub fn elo_ratings(players: usize, games: &[GameResult]) -> Vec<f64> {
+    let mut points = vec![PRIOR_DRAWS / 2.0; players];
+    let mut played = vec![vec![0.0; players]; players];
+    for game in games {
+        points[game.white] += game.white_score;
+        points[game.black] += 1.0 - game.white_score;
+        played[game.white][game.black] += 1.0;
+        played[game.black][game.white] += 1.0;
+    }
+
+    let mut strength = vec![1.0; players];
+    for _ in 0..ITERATIONS {
+        let mut change: f64 = 0.0;
+        for (i, (points, played)) in points.iter().zip(&played).enumerate() {
+            // The virtual opponent has a strength of 1.
+            let denominator = PRIOR_DRAWS / (strength[i] + 1.0)
+                + played
+                    .iter()
+                    .zip(&strength)
+                    .filter(|(games, _)| **games > 0.0)
+                    .map(|(games, other)| games / (strength[i] + other))
+                    .sum::<f64>();
+            let updated = points / denominator;
+            change = change.max((updated - strength[i]).abs() / strength[i]);
+            strength[i] = updated;
+        }
+        if change < TOLERANCE {
+            break;
+        }
+    }
+
+    let elo: Vec<_> = strength.iter().map(|s| 400.0 * s.log10()).collect();
+    let mean = elo.iter().sum::<f64>() / players.max(1) as f64;
+    elo.into_iter().map(|elo| elo - mean).collect()
+}
+
+/// Ratings with error bars from resampling the games.
+#[must_use]
+pub fn ratings_with_error(players: usize, games: &[GameResult]) -> Vec<Rating> {
+    let elo = elo_ratings(players, games);
+    let mut rng = rand::rngs::StdRng::seed_from_u64(BOOTSTRAP_SEED);
+    let mut sum_of_squares = vec![0.0; players];
+    let mut resampled = Vec::with_capacity(games.len());
+    for _ in 0..BOOTSTRAP_SAMPLES {
+        resampled.clear();
+        resampled.extend((0..games.len()).map(|_| games[rng.gen_range(0..games.len())]));
+        for (sum, (sample, elo)) in sum_of_squares
+            .iter_mut()
+            .zip(elo_ratings(players, &resampled).into_iter().zip(&elo))
+        {
+            *sum += (sample - elo).powi(2);
+        }
+    }
+    elo.into_iter()
+        .zip(sum_of_squares)
+        .map(|(elo, sum)| Rating {
+            elo,
+            error: Z_95 * (sum / BOOTSTRAP_SAMPLES as f64).sqrt(),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{elo_ratings, ratings_with_error, GameResult};
+
+    fn games(
+        white: usize,
+        black: usize,
+        wins: usize,
+        draws: usize,
+        losses: usize,
+    ) -> Vec<GameResult> {
+        [(wins, 1.0), (draws, 0.5), (losses, 0.0)]
+            .into_iter()
+            .flat_map(|(count, white_score)| {
+                (0..count).map(move |_| GameResult {
+                    white,
+                    black,
+                    white_score,
+                })
+            })
+            .collect()
+    }
+
+    #[test]
+    fn stronger_player_is_rated_higher() {
+        // A 75% score is about 191 Elo, the prior pulls it in a little.
+        let results = games(0, 1, 300, 0, 100);
+        let elo = elo_ratings(2, &results);
+        let difference = elo[0] - elo[1];
+        assert!(difference > 180.0 && difference < 191.0, "{difference}");
+        assert!((elo[0] + elo[1]).abs() < 1e-6);
+
+        let mut results = games(0, 1, 10, 0, 0);
+        results.extend(games(1, 2, 5, 0, 5));
+        let ratings = ratings_with_error(3, &results);
+        assert!(ratings.iter().all(|rating| rating.elo.is_finite()));
+        assert!(ratings[0].elo > ratings[1].elo);
+        assert!(ratings[0].error > 0.0);
+    }
+}
@@ -0,0 +1,218 @@
+use std::path::PathBuf;
+
+use clap::Parser;
+use fast_tak::takparse::{MoveKind, Tps};
+use svg::{
+    node::element::{Rect, Text},
+    Document,
+};
+use takzero::{
+    network::{
+        net6_simhash::{Env, Net, N},
+        repr::game_to_tensor,
+        HashNetwork,
+        Network,
+    },
+    search::{agent::Agent, env::Environment, node::policy::softmax},
+};
+use tch::{Device, Kind};
+
+const COLOR: (u8, u8, u8) = (0x81, 0x42, 0xf5);
+const SQUARE_SIZE: usize = 60;
+const MARGIN: usize = 30;
+
+#[derive(Parser, Debug)]
+struct Args {
+    /// Path to model to load.
+    #[arg(long)]
+    model_path: PathBuf,
+    /// Position written as TPS (the start position by default)
+    #[arg(long)]
+    tps: Option<Tps>,
+    /// Write the heatmaps as SVG to this path instead of printing them
+    #[arg(long)]
+    svg: Option<PathBuf>,
+    /// Run the network on the CPU
+    #[arg(long)]
+    cpu: bool,
+}
+
+/// Per-square values indexed by row (rank 1 first) and column.
+type Squares = [[f32; N]; N];
+
+struct Heatmap {
+    title: &'static str,
+    squares: Squares,
+    /// Format the value of a square for its label.
+    label: fn(f32) -> String,
+}
+
+fn main() {
+    takzero::logging::init();
+    let args = Args::parse();
+    let device = if args.cpu { Device::Cpu } else { Device::cuda_if_available() };
+    let net = match Net::load_partial(&args.model_path, device) {
+        Ok(net) => net,
+        Err(err) => {
+            log::error!("could not load {}: {err}", args.model_path.display());
+            return;
+        }
+    };
+    let env: Env = args.tps.map(Env::from).unwrap_or_default();
+
+    let (policy, value) = tch::no_grad(|| placement_policy(&net, &env));
+    let heatmaps = [
+        Heatmap {
+            title: "placement policy",
+            squares: policy,
+            label: |p| format!("{:.1}", 100.0 * p),
+        },
+        Heatmap {
+            title: "value gradient",
+            squares: value_gradient(&net, &env),
+            label: |g| format!("{g:.3}"),
+        },
+    ];
+
+    println!("tps: {}", Tps::from(env));
+    println!("value: {value:+.3}");
+    if let Some(path) = args.svg {
+        if let Err(err) = svg::save(&path, &to_svg(&heatmaps)) {
+            log::error!("could not write {}: {err}", path.display());
+        }
+    } else {
+        for heatmap in &heatmaps {
+            println!("{}", to_terminal(heatmap));
+        }
+    }
+}
+
+/// Probability mass of all placements (flats, walls, and capstones) on each
+/// square, and the value of the position.
+fn placement_policy(net: &Net, env: &Env) -> (Squares, f32) {
+    let mut actions = Vec::new();
+    env.populate_actions(&mut actions);
+    let (policy, value, _) = net
+        .policy_value_uncertainty(std::slice::from_ref(env), &[actions])
+        .next()
+        .expect("there should be an output for the position");
+
+    let mut squares = [[0.0; N]; N];
+    let probabilities = softmax(policy.iter().map(|(_, logit)| *logit));
+    for ((action, _), probability) in policy.iter().zip(probabilities) {
+        if let MoveKind::Place(_) = action.kind() {
+            let square = action.square();
+            squares[square.row() as usize][square.column() as usize] += probability.into_inner();
+        }
+    }
+    (squares, value)
+}
+
+/// Magnitude of the gradient of the value with respect to the input,
+/// summed over the channels of each square.
+fn value_gradient(net: &Net, env: &Env) -> Squares {
+    let xs = game_to_tensor(env, net.vs().device()).set_requires_grad(true);
+    let (_, value, _) = net.forward_t(&xs, false);
+    value.sum(Kind::Float).backward();
+    let gradient: Vec<f32> = xs
+        .grad()
+        .abs()
+        .sum_dim_intlist(1, false, None)
+        .view([-1])
+        .try_into()
+        .expect("gradient should have the shape of the board");
+
+    let mut squares = [[0.0; N]; N];
+    for (i, g) in gradient.into_iter().enumerate() {
+        squares[i / N][i % N] = g;
+    }
+    squares
+}
+
+/// Color of a square with the given intensity between 0 and 1.
+fn shade(intensity: f32) -> (u8, u8, u8) {
+    let intensity = intensity.clamp(0.0, 1.0);
+    #[allow(clippy::cast_sign_loss)]
+    let mix = |channel: u8| (255.0 + (f32::from(channel) - 255.0) * intensity).round() as u8;
+    (mix(COLOR.0), mix(COLOR.1), mix(COLOR.2))
+}
+
+/// Intensities relative to the largest square, so that small maps are visible.
+fn normalized(squares: &Squares) -> Squares {
+    let max = squares.iter().flatten().copied().fold(0.0, f32::max);
+    if max <= 0.0 {
+        return *squares;
+    }
+    squares.map(|row| row.map(|x| x / max))
+}
+
+fn to_terminal(heatmap: &Heatmap) -> String {
+    let intensities = normalized(&heatmap.squares);
+    let mut out = format!("{}:\n", heatmap.title);
+    for row in (0..N).rev() {
+        out.push_str(&format!("{} ", row + 1));
+        for column in 0..N {
+            let (r, g, b) = shade(intensities[row][column]);
+            let foreground = if intensities[row][column] > 0.5 { 97 } else { 30 };
+            let label = (heatmap.label)(heatmap.squares[row][column]);
+            out.push_str(&format!("\x1b[{foreground};48;2;{r};{g};{b}m{label:^7}\x1b[0m"));
+        }
+        out.push('\n');
+    }
+    out.push_str("  ");
+    for file in (b'a'..).take(N) {
+        out.push_str(&format!("{:^7}", char::from(file)));
+    }
+    out
+}
+
+fn to_svg(heatmaps: &[Heatmap]) -> Document {
+    let board = N * SQUARE_SIZE;
+    let width = heatmaps.len() * (board + MARGIN) + MARGIN;
+    let height = board + 2 * MARGIN;
+    let mut document = Document::new()
+        .set("viewBox", (0, 0, width, height))
+        .set("font-family", "sans-serif");
+
+    for (i, heatmap) in heatmaps.iter().enumerate() {
+        let left = MARGIN + i * (board + MARGIN);
+        let intensities = normalized(&heatmap.squares);
+        document = document.add(
+            Text::new(heatmap.title)
+                .set("x", left + board / 2)
+                .set("y", MARGIN * 2 / 3)
+                .set("text-anchor", "middle"),
+        );
+        for row in 0..N {
+            for column in 0..N {
+                let x = left + column * SQUARE_SIZE;
+                // Rank 1 is at the bottom.
+                let y = MARGIN + (N - 1 - row) * SQUARE_SIZE;
+                let (r, g, b) = shade(intensities[row][column]);
+                document = document
+                    .add(
+                        Rect::new()
+                            .set("x", x)
+                            .set("y", y)
+                            .set("width", SQUARE_SIZE)
+                            .set("height", SQUARE_SIZE)
+                            .set("fill", format!("rgb({r},{g},{b})"))
+                            .set("stroke", "#cccccc"),
+                    )
+                    .add(
+                        Text::new((heatmap.label)(heatmap.squares[row][column]))
+                            .set("x", x + SQUARE_SIZE / 2)
+                            .set("y", y + SQUARE_SIZE / 2)
+                            .set("text-anchor", "middle")
+                            .set("dominant-baseline", "middle")
+                            .set("font-size", 12)
+                            .set(
+                                "fill",
+                                if intensities[row][column] > 0.5 { "white" } else { "black" },
+                            ),
+                    );
+            }
+        }
+    }
+    document
+}
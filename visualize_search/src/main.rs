@@ -11,7 +11,11 @@ use takzero::{
         net4_rnd::{Env, Net},
         Network,
     },
-    search::{env::Environment, node::Node},
+    search::{
+        env::Environment,
+        node::{export::TreeExport, Node, NodeRef},
+        DISCOUNT_FACTOR,
+    },
 };
 
 const VISITS: u32 = 1000;
@@ -36,16 +40,22 @@ fn visualize_search(net: &Net, env: &Env, beta: f32) {
     let mut node = Node::default();
 
     for _ in 0..VISITS {
-        node.simulate_simple(net, env.clone(), beta);
+        node.simulate_simple(net, env.clone(), beta, DISCOUNT_FACTOR);
     }
 
     let mut document = Document::new().set("viewBox", (-400, -400, 1000, 1000));
     // .set("style", "background:black");
 
-    document = draw_tree(document, &node, env, 0.0, 0.0, 0.0, 2.0 * PI);
+    document = draw_tree(document, node.root(), env, 0.0, 0.0, 0.0, 2.0 * PI);
     document = document.add(Script::new(include_str!("preview.js")));
 
     svg::save(format!("tree_with_beta={beta}.svg"), &document).unwrap();
+    // Also export the tree for interactive visualizers.
+    std::fs::write(
+        format!("tree_with_beta={beta}.json"),
+        node.export_json(TreeExport::default()),
+    )
+    .unwrap();
 }
 
 fn opacity(visits: u32) -> f32 {
@@ -55,7 +65,7 @@ fn opacity(visits: u32) -> f32 {
 #[allow(clippy::suboptimal_flops)]
 fn draw_tree(
     mut document: Document,
-    node: &Node<Env>,
+    node: NodeRef<Env>,
     env: &Env,
     x: f32,
     y: f32,
@@ -72,8 +82,8 @@ fn draw_tree(
             .set("tps", Tps::from(env.clone()).to_string()),
     );
 
-    let angle_step = (max_angle - min_angle) / node.children.len() as f32;
-    for (i, (action, child)) in node.children.iter().enumerate() {
+    let angle_step = (max_angle - min_angle) / node.children().len() as f32;
+    for (i, (action, child)) in node.children().iter().enumerate() {
         if child.visit_count() < 1 {
             continue;
         }
@@ -92,7 +102,7 @@ fn draw_tree(
                 .set("action", action.to_string()),
         );
         let mut clone = env.clone();
-        clone.step(*action);
+        clone.step(action);
         document = draw_tree(
             document,
             child,